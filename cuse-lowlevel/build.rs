@@ -10,10 +10,22 @@ extern crate bindgen;
 extern crate pkg_config;
 
 use std::env;
+use std::fs;
 use std::iter;
 use std::path::PathBuf;
 
-const FUSE_USE_VERSION: u32 = 314; //fuse version of ubuntu 24.04
+// Oldest and newest libfuse the bindings are known to work with:
+// 3.10 (Debian 11, Ubuntu 22.04) up to 3.17. Newer releases are bound with the API of 3.17.
+const MIN_FUSE_VERSION: (u32, u32) = (3, 10);
+const MAX_FUSE_VERSION: (u32, u32) = (3, 17);
+// fuse_loop_cfg_* and fuse_session_loop_mt_312 replaced the public struct fuse_loop_config
+const FUSE_LOOP_CFG_VERSION: (u32, u32) = (3, 12);
+
+/// "3.14.0" -> (3, 14)
+fn parse_version(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.split('.').map(|part| part.parse::<u32>().ok());
+    Some((parts.next()??, parts.next()??))
+}
 
 fn fuse_binding_filter(builder: bindgen::Builder) -> bindgen::Builder {
     let builder = builder
@@ -24,7 +36,8 @@ fn fuse_binding_filter(builder: bindgen::Builder) -> bindgen::Builder {
         .allowlist_var("(?i)^fuse.*")
         .blocklist_type("fuse_log_func_t")
         .blocklist_function("fuse_set_log_func")
-        .allowlist_type("^libfuse_version$");
+        // struct libfuse_version of 3.17 and the like
+        .allowlist_type("(?i)^libfuse.*");
     builder
 }
 
@@ -40,6 +53,7 @@ fn cuse_binding_filter(builder: bindgen::Builder) -> bindgen::Builder {
 fn generate_fuse_bindings(
     header: &str,
     fuse_lib: &pkg_config::Library,
+    fuse_use_version: u32,
     binding_filter: fn(bindgen::Builder) -> bindgen::Builder,
 ) {
     // Find header file
@@ -68,7 +82,7 @@ fn generate_fuse_bindings(
         .iter()
         .map(|dir| format!("-I{}", dir.display()));
     // API version definition
-    let api_define = iter::once(format!("-DFUSE_USE_VERSION={}", FUSE_USE_VERSION));
+    let api_define = iter::once(format!("-DFUSE_USE_VERSION={}", fuse_use_version));
     // Chain compile flags
     let compile_flags = defines.chain(includes).chain(api_define);
 
@@ -101,15 +115,69 @@ fn generate_fuse_bindings(
 
 fn main() {
     let mut pkgcfg = pkg_config::Config::new();
+    let link_static = env::var_os("CARGO_FEATURE_STATIC").is_some();
 
     // Find libfuse
     let fuse3_lib = pkgcfg
-        .cargo_metadata(true)
+        .cargo_metadata(!link_static)
+        .statik(link_static)
         .probe("fuse3")
         .expect("Failed to find pkg-config module fuse3");
+    if link_static {
+        // pkg-config leaves libraries in the system directories (e.g. /usr/lib) to the
+        // dynamic linker, even with --static. So the flags are emitted here.
+        for path in &fuse3_lib.link_paths {
+            println!("cargo:rustc-link-search=native={}", path.display());
+        }
+        for lib in &fuse3_lib.libs {
+            if lib == "fuse3" {
+                println!("cargo:rustc-link-lib=static=fuse3");
+            } else {
+                println!("cargo:rustc-link-lib={}", lib);
+            }
+        }
+        println!("cargo:rerun-if-env-changed=PKG_CONFIG_PATH");
+    }
+
+    let version = parse_version(&fuse3_lib.version)
+        .unwrap_or_else(|| panic!("Cannot parse the version {} of fuse3", fuse3_lib.version));
+    if version < MIN_FUSE_VERSION {
+        panic!(
+            "fuse3 {} is too old, at least {}.{} is required",
+            fuse3_lib.version, MIN_FUSE_VERSION.0, MIN_FUSE_VERSION.1
+        );
+    }
+    // the headers of libfuse 3 expect major * 100 + minor, e.g. 314
+    let api_version = version.min(MAX_FUSE_VERSION);
+    let fuse_use_version = api_version.0 * 100 + api_version.1;
+
+    println!("cargo:rustc-check-cfg=cfg(fuse_loop_cfg)");
+    if api_version >= FUSE_LOOP_CFG_VERSION {
+        println!("cargo:rustc-cfg=fuse_loop_cfg");
+    }
 
     // Generate lowlevel bindings
-    generate_fuse_bindings("fuse_lowlevel.h", &fuse3_lib, fuse_binding_filter);
+    generate_fuse_bindings(
+        "fuse_lowlevel.h",
+        &fuse3_lib,
+        fuse_use_version,
+        fuse_binding_filter,
+    );
     // Generate lowlevel cuse bindings
-    generate_fuse_bindings("cuse_lowlevel.h", &fuse3_lib, cuse_binding_filter);
+    generate_fuse_bindings(
+        "cuse_lowlevel.h",
+        &fuse3_lib,
+        fuse_use_version,
+        cuse_binding_filter,
+    );
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::write(
+        out_dir.join("fuse_version.rs"),
+        format!(
+            "pub const FUSE_BUILD_VERSION: &str = {:?};\npub const FUSE_USE_VERSION: u32 = {};\n",
+            fuse3_lib.version, fuse_use_version
+        ),
+    )
+    .expect("Failed to write fuse_version.rs");
 }
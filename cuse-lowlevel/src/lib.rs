@@ -28,3 +28,68 @@ pub mod cuse_lowlevel {
         fuse_args, fuse_conn_info, fuse_file_info, fuse_pollhandle, fuse_req_t, fuse_session,
     };
 }
+
+/// Differences between the libfuse releases the bindings may be generated from (3.10 to 3.17).
+/// build.rs picks FUSE_USE_VERSION from the installed headers and sets the cfgs.
+pub mod compat {
+    use super::fuse_lowlevel::*;
+    use super::*;
+    use std::ffi::CStr;
+
+    include!(concat!(env!("OUT_DIR"), "/fuse_version.rs"));
+
+    /// Version of the libfuse that has been loaded, e.g. "3.14.0". It may differ from
+    /// FUSE_BUILD_VERSION, if the library has been updated after the build.
+    pub fn fuse_runtime_version() -> String {
+        unsafe { CStr::from_ptr(fuse_pkgversion()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Symbols of a newer libfuse than the loaded one may be missing, the other way round
+    /// is fine.
+    pub fn runtime_older_than_build() -> bool {
+        fn major_minor(version: &str) -> Option<(u32, u32)> {
+            let mut parts = version.split('.').map(|part| part.parse::<u32>().ok());
+            Some((parts.next()??, parts.next()??))
+        }
+        match (
+            major_minor(&fuse_runtime_version()),
+            major_minor(FUSE_BUILD_VERSION),
+        ) {
+            (Some(runtime), Some(build)) => runtime < build,
+            _ => false,
+        }
+    }
+
+    /// Runs the session with a thread pool. libfuse 3.12 made struct fuse_loop_config opaque
+    /// and added the limit on the number of threads, before only idle threads are limited.
+    #[cfg(fuse_loop_cfg)]
+    pub unsafe fn session_loop_mt(
+        se: *mut fuse_session,
+        clone_fd: bool,
+        max_threads: c_uint,
+    ) -> c_int {
+        let config = fuse_loop_cfg_create();
+        fuse_loop_cfg_set_clone_fd(config, clone_fd as c_uint);
+        fuse_loop_cfg_set_max_threads(config, max_threads);
+        let result = fuse_session_loop_mt_312(se, config);
+        fuse_loop_cfg_destroy(config);
+        result
+    }
+
+    /// Runs the session with a thread pool. libfuse 3.12 made struct fuse_loop_config opaque
+    /// and added the limit on the number of threads, before only idle threads are limited.
+    #[cfg(not(fuse_loop_cfg))]
+    pub unsafe fn session_loop_mt(
+        se: *mut fuse_session,
+        clone_fd: bool,
+        max_threads: c_uint,
+    ) -> c_int {
+        let mut config = fuse_loop_config {
+            clone_fd: clone_fd as c_int,
+            max_idle_threads: max_threads,
+        };
+        fuse_session_loop_mt(se, &mut config)
+    }
+}
@@ -15,7 +15,7 @@ pub const UI_DEV_DESTROY: u64 = request_code_none!(b'U', 2);
 pub const UI_DEV_SETUP: u64 = request_code_write!(b'U', 3, ::std::mem::size_of::<uinput_setup>());
 pub const UI_ABS_SETUP: u64 =
     request_code_write!(b'U', 4, ::std::mem::size_of::<uinput_abs_setup>());
-//pub const UI_ABS_SETUP_WITHOUT_SIZE: u64 = request_code_write!(b'U', 4, 0);
+pub const UI_ABS_SETUP_WITHOUT_SIZE: u64 = request_code_write!(b'U', 4, 0);
 
 pub const UI_GET_SYSNAME_WITHOUT_SIZE: u64 = request_code_read!(b'U', 44, 0);
 //#define UI_GET_SYSNAME(len)	_IOC(_IOC_READ, UINPUT_IOCTL_BASE, 44, len)
@@ -42,6 +42,15 @@ pub const UI_BEGIN_FF_ERASE: u64 =
 pub const UI_END_FF_ERASE: u64 =
     request_code_write!(b'U', 203, ::std::mem::size_of::<uinput_ff_erase>());
 
+// The numbers a 32-bit process uses on a 64-bit kernel, where the argument contains a pointer
+// (see the *_COMPAT ioctls of uinput.c). struct uinput_ff_upload_compat has 96 bytes.
+pub const UINPUT_FF_UPLOAD_COMPAT_SIZE: usize = 96;
+pub const UI_SET_PHYS_COMPAT: u64 = request_code_write!(b'U', 108, 4);
+pub const UI_BEGIN_FF_UPLOAD_COMPAT: u64 =
+    request_code_readwrite!(b'U', 200, UINPUT_FF_UPLOAD_COMPAT_SIZE);
+pub const UI_END_FF_UPLOAD_COMPAT: u64 =
+    request_code_write!(b'U', 201, UINPUT_FF_UPLOAD_COMPAT_SIZE);
+
 ioctl_none!(ui_dev_create, b'U', 1);
 ioctl_none!(ui_dev_destroy, b'U', 2);
 ioctl_write_ptr! {ui_dev_setup, b'U', 3, uinput_setup}
@@ -0,0 +1,175 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// An absolute pointer as created by streaming clients (e.g. for the touch or pen input of a
+// tablet that shows the remote screen): ABS_X/ABS_Y in the coordinates of the client, mouse
+// buttons and a wheel. udev classifies it as ID_INPUT_MOUSE, libinput maps the axes to the
+// screen. It passes vuinputd --device-policy strict-tablet.
+//
+// Usage: tablet-absolute [/dev/uinput]
+
+use libc::{
+    c_int, close, input_absinfo, open, uinput_abs_setup, uinput_setup, write, O_NONBLOCK, O_WRONLY,
+};
+use std::ffi::{CStr, CString};
+use std::io;
+use std::mem::{size_of, zeroed};
+use std::os::raw::{c_char, c_void};
+use std::ptr;
+use std::thread::sleep;
+use std::time::Duration;
+pub use uinput_ioctls::*;
+
+// Constants (same numeric values as in linux headers)
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const EV_ABS: u16 = 0x03;
+const BTN_LEFT: u16 = 0x110;
+const BTN_RIGHT: u16 = 0x111;
+const BTN_MIDDLE: u16 = 0x112;
+const REL_WHEEL: u16 = 0x08;
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+const SYN_REPORT: u16 = 0;
+const INPUT_PROP_DIRECT: u16 = 0x01;
+const BUS_USB: u16 = 0x03;
+
+// The coordinate space of the client. libinput scales it to the screen, the resolution
+// (units per mm) only matters for tools that want the physical size.
+const MAX_X: i32 = 19200;
+const MAX_Y: i32 = 12000;
+const RESOLUTION: i32 = 28;
+
+fn emit(fd: c_int, type_: u16, code: u16, value: i32) -> io::Result<()> {
+    let mut ie: libc::input_event = unsafe { zeroed() };
+    ie.type_ = type_;
+    ie.code = code;
+    ie.value = value;
+
+    let bytes = size_of::<libc::input_event>();
+    let written = unsafe { write(fd, &ie as *const libc::input_event as *const c_void, bytes) };
+    if written as usize != bytes {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Moves the pointer to (x, y) in one frame
+fn move_to(fd: c_int, x: i32, y: i32) -> io::Result<()> {
+    emit(fd, EV_ABS, ABS_X, x)?;
+    emit(fd, EV_ABS, ABS_Y, y)?;
+    emit(fd, EV_SYN, SYN_REPORT, 0)
+}
+
+fn click(fd: c_int, button: u16) -> io::Result<()> {
+    emit(fd, EV_KEY, button, 1)?;
+    emit(fd, EV_SYN, SYN_REPORT, 0)?;
+    emit(fd, EV_KEY, button, 0)?;
+    emit(fd, EV_SYN, SYN_REPORT, 0)
+}
+
+fn or_exit<T>(result: nix::Result<T>, what: &str) -> T {
+    result.unwrap_or_else(|e| {
+        // EPERM: the device policy of vuinputd does not allow the capability
+        eprintln!("{} failed: {:?}", what, e);
+        std::process::exit(1);
+    })
+}
+
+unsafe fn setup_axis(fd: c_int, code: u16, maximum: i32) {
+    let setup = uinput_abs_setup {
+        code,
+        absinfo: input_absinfo {
+            value: 0,
+            minimum: 0,
+            maximum,
+            fuzz: 0,
+            flat: 0,
+            resolution: RESOLUTION,
+        },
+    };
+    or_exit(ui_abs_setup(fd, &setup), "ui_abs_setup");
+}
+
+fn main() -> io::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let device = match args.len() {
+        2 => args[1].clone(),
+        _ => "/dev/uinput".to_string(),
+    };
+
+    let path = CString::new(device).unwrap();
+    let fd = unsafe { open(path.as_ptr(), O_WRONLY | O_NONBLOCK) };
+    if fd < 0 {
+        eprintln!("error opening uinput");
+        return Err(io::Error::last_os_error());
+    }
+
+    unsafe {
+        // the axes map to the whole screen
+        or_exit(
+            ui_set_propbit(fd, INPUT_PROP_DIRECT.into()),
+            "ui_set_propbit(INPUT_PROP_DIRECT)",
+        );
+
+        or_exit(ui_set_evbit(fd, EV_KEY.into()), "ui_set_evbit(EV_KEY)");
+        for button in [BTN_LEFT, BTN_RIGHT, BTN_MIDDLE] {
+            or_exit(ui_set_keybit(fd, button.into()), "ui_set_keybit");
+        }
+
+        or_exit(ui_set_evbit(fd, EV_REL.into()), "ui_set_evbit(EV_REL)");
+        or_exit(
+            ui_set_relbit(fd, REL_WHEEL.into()),
+            "ui_set_relbit(REL_WHEEL)",
+        );
+
+        // UI_ABS_SETUP sets the absbit as well
+        or_exit(ui_set_evbit(fd, EV_ABS.into()), "ui_set_evbit(EV_ABS)");
+        setup_axis(fd, ABS_X, MAX_X);
+        setup_axis(fd, ABS_Y, MAX_Y);
+    }
+
+    let mut usetup: uinput_setup = unsafe { zeroed() };
+    usetup.id.bustype = BUS_USB;
+    usetup.id.vendor = 0xbeef;
+    usetup.id.product = 0xdead;
+    let name = CString::new("Example tablet (absolute)").unwrap();
+    unsafe {
+        let name_ptr = usetup.name.as_mut_ptr() as *mut c_char;
+        ptr::copy_nonoverlapping(name.as_ptr(), name_ptr, name.to_bytes_with_nul().len());
+    }
+
+    unsafe {
+        or_exit(
+            ui_dev_setup(fd, &mut usetup as *mut uinput_setup),
+            "ui_dev_setup",
+        );
+        or_exit(ui_dev_create(fd), "ui_dev_create");
+
+        let mut resultbuf: [c_char; 64] = [0; 64];
+        ui_get_sysname(fd, &mut resultbuf).unwrap();
+        let sysname = CStr::from_ptr(resultbuf.as_ptr()).to_string_lossy();
+        eprintln!("sysname: {}", sysname);
+    }
+
+    // allow userspace (libinput, the compositor) to pick up the device
+    sleep(Duration::from_secs(10));
+
+    // visit the corners of the screen clockwise, then click in the middle
+    for (x, y) in [(0, 0), (MAX_X, 0), (MAX_X, MAX_Y), (0, MAX_Y)] {
+        move_to(fd, x, y)?;
+        sleep(Duration::from_secs(1));
+    }
+    move_to(fd, MAX_X / 2, MAX_Y / 2)?;
+    click(fd, BTN_LEFT)?;
+
+    sleep(Duration::from_secs(10));
+
+    unsafe {
+        or_exit(ui_dev_destroy(fd), "ui_dev_destroy");
+        close(fd);
+    }
+    Ok(())
+}
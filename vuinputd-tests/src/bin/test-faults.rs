@@ -0,0 +1,92 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Runs in a container of the fault injection tests: creates a keyboard named "Fault Keyboard"
+// as often as asked and prints one line per attempt, after destroying the keyboard again:
+//
+//   failed <ioctl> <errno>                       the ioctl failed in the container
+//   created <syspath> <devnode> node|no-node     whether the node showed up in /dev/input
+//
+// It exits with 0 as long as every ioctl answered, whatever the answer was.
+
+use std::ffi::{CStr, CString};
+use std::mem::zeroed;
+use std::os::raw::c_char;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use libc::{close, open, uinput_setup, O_NONBLOCK, O_RDWR};
+use nix::errno::Errno;
+use uinput_ioctls::*;
+use vuinputd_tests::devices::device_base::{fetch_device_node, BUS_USB, EV_KEY, SYS_INPUT_DIR};
+
+const KEY_A: libc::c_ulong = 30;
+
+#[derive(Parser)]
+struct Args {
+    /// How often to create the keyboard
+    #[arg(long, default_value_t = 1)]
+    attempts: u32,
+}
+
+/// Sets up and creates the keyboard, returns its sysname
+unsafe fn create(fd: i32) -> Result<String, (&'static str, Errno)> {
+    ui_set_evbit(fd, EV_KEY.into()).map_err(|e| ("UI_SET_EVBIT", e))?;
+    ui_set_keybit(fd, KEY_A).map_err(|e| ("UI_SET_KEYBIT", e))?;
+    let mut usetup: uinput_setup = zeroed();
+    usetup.id.bustype = BUS_USB;
+    usetup.id.vendor = 0xbeef;
+    usetup.id.product = 0xfa17;
+    let name = CString::new("Fault Keyboard").unwrap();
+    std::ptr::copy_nonoverlapping(
+        name.as_ptr(),
+        usetup.name.as_mut_ptr() as *mut c_char,
+        name.to_bytes_with_nul().len(),
+    );
+    ui_dev_setup(fd, &usetup).map_err(|e| ("UI_DEV_SETUP", e))?;
+    ui_dev_create(fd).map_err(|e| ("UI_DEV_CREATE", e))?;
+    let mut sysname: [c_char; 64] = [0; 64];
+    ui_get_sysname(fd, sysname.as_mut_slice()).map_err(|e| ("UI_GET_SYSNAME", e))?;
+    Ok(CStr::from_ptr(sysname.as_ptr())
+        .to_string_lossy()
+        .into_owned())
+}
+
+/// Waits up to 3 seconds for the node. vuinputd answers UI_DEV_CREATE once it has placed the
+/// node, the wait is for placements that leave the node to others.
+fn wait_for_node(devnode: &str) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(3);
+    while !Path::new(devnode).exists() {
+        if Instant::now() > deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    true
+}
+
+fn main() {
+    let args = Args::parse();
+    let uinput = CString::new("/dev/uinput").unwrap();
+    for _ in 0..args.attempts {
+        let fd = unsafe { open(uinput.as_ptr(), O_RDWR | O_NONBLOCK) };
+        assert!(fd >= 0, "failed to open /dev/uinput");
+        match unsafe { create(fd) } {
+            Ok(sysname) => {
+                let syspath = format!("{}{}", SYS_INPUT_DIR, sysname);
+                let devnode = fetch_device_node(&syspath).unwrap_or_default();
+                let node = match wait_for_node(&devnode) {
+                    true => "node",
+                    false => "no-node",
+                };
+                println!("created {} {} {}", syspath, devnode, node);
+                unsafe { ui_dev_destroy(fd) }.expect("UI_DEV_DESTROY failed");
+            }
+            Err((ioctl, errno)) => println!("failed {} {}", ioctl, errno as i32),
+        }
+        unsafe { close(fd) };
+    }
+}
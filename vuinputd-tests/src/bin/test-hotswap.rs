@@ -0,0 +1,145 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Runs in a container of test_gamepad_hotswap and does what a streaming client does when it
+// reconnects over and over: creates an Xbox gamepad and destroys it right away, --cycles times
+// and as fast as it can. Afterwards it checks what the container got to see:
+//
+//   - an add and a remove event on its udev monitor for the event node of every cycle, with
+//     increasing SEQNUMs, i.e. vuinputd passed the events of the udev event store on in order
+//   - no node left in /dev/input
+//
+// It prints "cycles <n> in <ms> ms" and exits with 1 and the reason if a check fails.
+
+use std::fs;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use vuinputd_tests::devices::{Device, XboxGamepadDevice};
+use vuinputd_tests::udev_monitor;
+
+/// How long the events and the removal of the nodes may lag behind the last cycle
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Parser)]
+struct Args {
+    /// How often to create and destroy the gamepad
+    #[arg(long, default_value_t = 100)]
+    cycles: u32,
+}
+
+/// ACTION, SEQNUM and DEVNAME of an event of an event node
+struct NodeEvent {
+    action: String,
+    seqnum: u64,
+    devname: String,
+}
+
+/// The event nodes left in /dev/input
+fn event_nodes() -> Vec<String> {
+    fs::read_dir("/dev/input")
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .filter(|name| name.starts_with("event"))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Checks that every cycle has its add and remove, in the order of the SEQNUMs
+fn check_events(events: &[NodeEvent], cycles: u32) -> Result<(), String> {
+    for pair in events.windows(2) {
+        if pair[1].seqnum <= pair[0].seqnum {
+            return Err(format!(
+                "SEQNUM {} ({} {}) came after {} ({} {})",
+                pair[1].seqnum,
+                pair[1].action,
+                pair[1].devname,
+                pair[0].seqnum,
+                pair[0].action,
+                pair[0].devname
+            ));
+        }
+    }
+    let count = |action: &str| events.iter().filter(|e| e.action == action).count();
+    let (adds, removes) = (count("add"), count("remove"));
+    if adds != cycles as usize || removes != cycles as usize {
+        return Err(format!(
+            "{} cycles, but {} add and {} remove events",
+            cycles, adds, removes
+        ));
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = Args::parse();
+    let monitor = udev_monitor::open().expect("failed to listen to the udev monitor");
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || loop {
+        let Some(properties) = udev_monitor::recv_event(&monitor) else {
+            continue;
+        };
+        let Some(devname) = properties
+            .get("DEVNAME")
+            .filter(|name| name.starts_with("/dev/input/event"))
+        else {
+            continue;
+        };
+        let event = NodeEvent {
+            action: properties.get("ACTION").cloned().unwrap_or_default(),
+            seqnum: properties
+                .get("SEQNUM")
+                .and_then(|seqnum| seqnum.parse().ok())
+                .unwrap_or_default(),
+            devname: devname.clone(),
+        };
+        if sender.send(event).is_err() {
+            return;
+        }
+    });
+
+    let start = Instant::now();
+    for cycle in 0..args.cycles {
+        let gamepad = XboxGamepadDevice::create(Some("/dev/uinput"), "Hotswap Gamepad")
+            .unwrap_or_else(|e| {
+                eprintln!("cycle {}: failed to create the gamepad: {}", cycle, e);
+                std::process::exit(1);
+            });
+        gamepad.destroy();
+    }
+    println!(
+        "cycles {} in {} ms",
+        args.cycles,
+        start.elapsed().as_millis()
+    );
+
+    let deadline = Instant::now() + SETTLE_TIMEOUT;
+    let mut events = Vec::new();
+    while events.len() < 2 * args.cycles as usize {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match receiver.recv_timeout(left) {
+            Ok(event) => events.push(event),
+            Err(_) => break,
+        }
+    }
+    let mut stale = event_nodes();
+    while !stale.is_empty() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(100));
+        stale = event_nodes();
+    }
+
+    let result = check_events(&events, args.cycles).and_then(|()| match stale.as_slice() {
+        [] => Ok(()),
+        stale => Err(format!("stale nodes in /dev/input: {:?}", stale)),
+    });
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -2,26 +2,101 @@
 //
 // Author: Johannes Leupolz <dev@leupolz.eu>
 
-use core::panic;
+// Runs in a container and does what the test on the host asks for over IPC:
+//
+//   Ready                  answered with Ready, then the container exits
+//   CreateDevice           creates the device and answers DeviceCreated, then waits for Ready
+//                          from the host, emits a few events and answers with the EventLog
+//
+// Anything else is answered with Error.
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
 use std::time::Duration;
 
-use vuinputd_tests::ipc::SandboxChildIpc;
+use vuinputd_tests::devices::keyboard::KEY_A;
+use vuinputd_tests::devices::mouse::{BTN_LEFT, REL_X};
+use vuinputd_tests::devices::{Device, KeyboardDevice, MouseDevice, EV_KEY, EV_REL};
+use vuinputd_tests::ipc::{DeviceKind, Message, SandboxChildIpc};
+
+const TIMEOUT: Option<Duration> = Some(Duration::from_secs(10));
+
+/// Creates the device, lets the host check it and emits `events` once it is ready
+fn create_and_emit<D: Device>(
+    ipc: &SandboxChildIpc,
+    name: &str,
+    events: &[(u16, u16, i32)],
+) -> Result<(), String> {
+    let mut device = D::create(Some("/dev/uinput"), name).map_err(|e| e.to_string())?;
+    let devnode = device.state().event_device_node.clone();
+    let rdev = fs::metadata(&devnode)
+        .map_err(|e| format!("{}: {}", devnode, e))?
+        .rdev();
+    ipc.send_message(&Message::DeviceCreated {
+        syspath: device.sysname().to_string(),
+        devnode,
+        major: libc::major(rdev),
+        minor: libc::minor(rdev),
+    })
+    .map_err(|e| e.to_string())?;
+
+    match ipc.recv_message(TIMEOUT).map_err(|e| e.to_string())? {
+        Message::Ready => {}
+        other => return Err(format!("expected ready, got {:?}", other)),
+    }
+    for &(ev_type, code, value) in events {
+        device
+            .emit_read_and_log(ev_type, code, value)
+            .map_err(|e| e.to_string())?;
+    }
+    let events = device.event_log().to_vec();
+    device.destroy();
+    ipc.send_message(&Message::EventLog { events })
+        .map_err(|e| e.to_string())
+}
 
 fn main() {
     println!("starting test-ipc");
     let ipc = unsafe { SandboxChildIpc::from_fd() };
 
-    let incoming = ipc
-        .recv(Some(Duration::from_secs(5)))
-        .expect("error receiving input from ipc as child within 5 seconds");
-    let incoming_str =
-        str::from_utf8(&incoming).expect("message received from ipc is not encoded as utf8");
-    if incoming_str == "continue" {
-        println!("child received continue");
-        ipc.send(b"ok").unwrap();
-    } else {
-        ipc.send(b"nok").unwrap();
-        println!("child received {}", incoming_str);
-        panic!("expected ipc message to be 'continue'");
+    loop {
+        let message = ipc
+            .recv_message(TIMEOUT)
+            .expect("error receiving a message from ipc as child within 10 seconds");
+        println!("child received {:?}", message);
+        let result = match message {
+            Message::Ready => {
+                ipc.send_message(&Message::Ready).unwrap();
+                return;
+            }
+            Message::CreateDevice {
+                kind: DeviceKind::Keyboard,
+                name,
+            } => create_and_emit::<KeyboardDevice>(
+                &ipc,
+                &name,
+                &[(EV_KEY, KEY_A, 1), (EV_KEY, KEY_A, 0)],
+            ),
+            Message::CreateDevice {
+                kind: DeviceKind::Mouse,
+                name,
+            } => create_and_emit::<MouseDevice>(
+                &ipc,
+                &name,
+                &[
+                    (EV_REL, REL_X, 5),
+                    (EV_KEY, BTN_LEFT, 1),
+                    (EV_KEY, BTN_LEFT, 0),
+                ],
+            ),
+            other => Err(format!("unexpected message {:?}", other)),
+        };
+        if let Err(message) = result {
+            ipc.send_message(&Message::Error {
+                message: message.clone(),
+            })
+            .unwrap();
+            panic!("{}", message);
+        }
     }
 }
@@ -495,7 +495,7 @@ fn main() -> io::Result<()> {
         let eventlog = TestLog {
             events: vec![ev1, ev2],
         };
-        let serialized = serde_json::to_string(&eventlog).unwrap();
+        let serialized = serde_json::to_string(&eventlog.scrubbed()).unwrap();
         println!("Event log: {}", serialized);
 
         // Destroy device and close fd
@@ -0,0 +1,164 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Runs in a container with libinput-tools and checks that libinput accepts a device created on
+// /dev/uinput: `libinput debug-events` on the seat has to announce it with DEVICE_ADDED (via
+// the udev event vuinputd sends into the container), on the expected seat, and has to report
+// the emitted events. Exits with 1 and says why if any of that fails.
+
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::{Parser, ValueEnum};
+use vuinputd_tests::devices::keyboard::KEY_A;
+use vuinputd_tests::devices::mouse::{BTN_LEFT, REL_X};
+use vuinputd_tests::devices::{Device, KeyboardDevice, MouseDevice, EV_KEY, EV_REL};
+use vuinputd_tests::libinput::{parse_debug_event, DebugEvent};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Kind {
+    Keyboard,
+    Mouse,
+}
+
+#[derive(Debug, Parser)]
+#[command(author, version, about)]
+struct Args {
+    /// The device to create
+    #[arg(long, value_enum, default_value = "keyboard")]
+    kind: Kind,
+
+    /// The seat libinput is started on and the device has to be on
+    #[arg(long, default_value = "seat0")]
+    seat: String,
+
+    /// Device path (with /dev/)
+    #[arg(long, default_value = "/dev/uinput")]
+    dev_path: String,
+}
+
+/// `libinput debug-events` on `seat`, its events come through the receiver
+fn debug_events(seat: &str) -> Result<(Child, Receiver<DebugEvent>), String> {
+    let mut child = Command::new("libinput")
+        .args(["debug-events", "--udev", seat, "--show-keycodes"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("failed to start libinput debug-events: {}", e))?;
+    let stdout = child.stdout.take().unwrap();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            println!("libinput: {}", line);
+            if let Some(event) = parse_debug_event(&line) {
+                if sender.send(event).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    Ok((child, receiver))
+}
+
+/// The next event of `node`, skips those of other devices
+fn next_event(events: &Receiver<DebugEvent>, node: &str, deadline: Instant) -> Option<DebugEvent> {
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match events.recv_timeout(left) {
+            Ok(event) if event.node == node => return Some(event),
+            Ok(_) => continue,
+            Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => return None,
+        }
+    }
+}
+
+fn verify<D: Device>(
+    args: &Args,
+    events: &Receiver<DebugEvent>,
+    emitted: &[(u16, u16, i32)],
+) -> Result<(), String> {
+    let mut device = D::create(Some(&args.dev_path), D::name()).map_err(|e| e.to_string())?;
+    let devnode = device.state().event_device_node.clone();
+    let node = Path::new(&devnode)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    println!("created {} on {}", D::name(), devnode);
+
+    let result = (|| {
+        let deadline = Instant::now() + TIMEOUT;
+        let added = loop {
+            let event = next_event(events, &node, deadline)
+                .ok_or_else(|| format!("libinput did not enumerate {}", devnode))?;
+            if let Some(added) = event.added_device() {
+                break added;
+            }
+        };
+        if added.seat != args.seat {
+            return Err(format!(
+                "{} is on seat {}, expected {}",
+                devnode, added.seat, args.seat
+            ));
+        }
+        println!("libinput enumerated {:?}", added);
+
+        for &(ev_type, code, value) in emitted {
+            device
+                .emit_read_and_log(ev_type, code, value)
+                .map_err(|e| e.to_string())?;
+            let deadline = Instant::now() + TIMEOUT;
+            loop {
+                let event = next_event(events, &node, deadline).ok_or_else(|| {
+                    format!(
+                        "libinput did not report type {} code {} value {}",
+                        ev_type, code, value
+                    )
+                })?;
+                if event.yields(ev_type, code, value) {
+                    break;
+                }
+            }
+        }
+        println!("libinput reported all {} events", emitted.len());
+        Ok(())
+    })();
+    device.destroy();
+    result
+}
+
+fn main() {
+    let args = Args::parse();
+    let (mut libinput, events) = debug_events(&args.seat).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    // libinput has to listen on the udev monitor before the device is created
+    thread::sleep(Duration::from_millis(500));
+
+    let result = match args.kind {
+        Kind::Keyboard => {
+            verify::<KeyboardDevice>(&args, &events, &[(EV_KEY, KEY_A, 1), (EV_KEY, KEY_A, 0)])
+        }
+        Kind::Mouse => verify::<MouseDevice>(
+            &args,
+            &events,
+            &[
+                (EV_REL, REL_X, 5),
+                (EV_KEY, BTN_LEFT, 1),
+                (EV_KEY, BTN_LEFT, 0),
+            ],
+        ),
+    };
+    let _ = libinput.kill();
+    let _ = libinput.wait();
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -3,10 +3,11 @@
 // Author: Johannes Leupolz <dev@leupolz.eu>
 
 use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 use vuinputd_tests::scenarios::{
     basic_keyboard::BasicKeyboard, basic_mouse::BasicMouse, basic_ps4_gamepad::BasicPs4Gamepad,
     basic_xbox_gamepad::BasicXboxGamepad, ff_xbox_gamepad::FfXboxGamepad, BasicMouseAbsolute,
-    ScenarioArgs,
+    BasicTouchpad, ScenarioArgs,
 };
 
 #[derive(Parser)]
@@ -21,6 +22,10 @@ struct Cli {
     #[arg(short, long, default_value = "/dev/uinput")]
     dev_path: String,
 
+    /// Save evemu recordings (.desc and .events) of the created devices to this directory
+    #[arg(long, value_name = "DIR")]
+    evemu_dir: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -42,6 +47,9 @@ enum Commands {
     /// Basic Xbox gamepad test
     BasicXboxGamepad,
 
+    /// Basic touchpad (clickpad) test
+    BasicTouchpad,
+
     /// Force feedback / Vibration Xbox gamepad test
     FfXboxGamepad,
     /*
@@ -65,6 +73,7 @@ fn main() -> Result<(), std::io::Error> {
     let args = ScenarioArgs {
         ipc: cli.ipc,
         dev_path: Some(cli.dev_path),
+        evemu_dir: cli.evemu_dir,
     };
 
     match cli.command {
@@ -73,6 +82,7 @@ fn main() -> Result<(), std::io::Error> {
         Commands::BasicMouseAbsolute => BasicMouseAbsolute::run(&args),
         Commands::BasicPs4Gamepad => BasicPs4Gamepad::run(&args),
         Commands::BasicXboxGamepad => BasicXboxGamepad::run(&args),
+        Commands::BasicTouchpad => BasicTouchpad::run(&args),
         Commands::FfXboxGamepad => FfXboxGamepad::run(&args),
         /*
         Commands::ReuseKeyboard => ReuseKeyboard::run(&args),
@@ -0,0 +1,72 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Runs in a container of test_shutdown_cleans_up_containers: creates a keyboard, tells the host
+// about it (DeviceCreated) and keeps it until the udev monitor of the container announces its
+// removal, which vuinputd sends when it stops. Then it reports DeviceRemoved, or an Error if
+// nothing came within 30 seconds.
+
+use std::fs;
+use std::os::fd::OwnedFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use vuinputd_tests::devices::keyboard::KeyboardDevice;
+use vuinputd_tests::devices::Device;
+use vuinputd_tests::ipc::{Message, SandboxChildIpc};
+use vuinputd_tests::udev_monitor;
+
+/// Waits for a remove event of the node, e.g. DEVNAME=/dev/input/event7
+fn wait_for_remove(monitor: &OwnedFd, devnode: &str, timeout: Duration) -> bool {
+    let node = Path::new(devnode).file_name().unwrap().to_string_lossy();
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        let Some(properties) = udev_monitor::recv_event(monitor) else {
+            continue;
+        };
+        let is_remove = properties
+            .get("ACTION")
+            .is_some_and(|action| action == "remove");
+        let is_node = properties
+            .get("DEVNAME")
+            .is_some_and(|name| name.ends_with(node.as_ref()));
+        if is_remove && is_node {
+            return true;
+        }
+    }
+    false
+}
+
+fn main() {
+    let ipc = unsafe { SandboxChildIpc::from_fd() };
+    let monitor = udev_monitor::open().expect("failed to listen to the udev monitor");
+
+    let keyboard = KeyboardDevice::create(Some("/dev/uinput"), "Shutdown Keyboard")
+        .expect("failed to create the keyboard");
+    let devnode = keyboard.state().event_device_node.clone();
+    let rdev = fs::metadata(&devnode)
+        .expect("the node of the keyboard is missing")
+        .rdev();
+    let created = Message::DeviceCreated {
+        syspath: keyboard.sysname().to_string(),
+        devnode: devnode.clone(),
+        major: libc::major(rdev),
+        minor: libc::minor(rdev),
+    };
+    println!("{:?}", created);
+    ipc.send_message(&created).unwrap();
+
+    let answer = match wait_for_remove(&monitor, &devnode, Duration::from_secs(30)) {
+        true => Message::DeviceRemoved {
+            syspath: keyboard.sysname().to_string(),
+        },
+        false => Message::Error {
+            message: format!("no remove event of {} within 30 seconds", devnode),
+        },
+    };
+    ipc.send_message(&answer).unwrap();
+    // vuinputd is gone, there is nobody left to destroy the keyboard
+    std::mem::forget(keyboard);
+}
@@ -0,0 +1,87 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Runs in a container of test_writes_racing_a_destroy: creates a keyboard and lets several
+// threads write to it while the main thread destroys it, so that vuinputd gets writes and the
+// UI_DEV_DESTROY of one handle on several of its CUSE threads at once. Every write must either
+// reach the keyboard or, once it is gone, fail with EINVAL (a write without a device is a legacy
+// setup, which an input_event is not). After UI_DEV_DESTROY has returned, no write succeeds.
+//
+// It prints "writes <n> refused <n>" and exits with 1 and the reason if a check fails.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use uinput_ioctls::ui_dev_destroy;
+use vuinputd_tests::devices::device_base::{emit, Device, EV_KEY, EV_SYN, SYN_REPORT};
+use vuinputd_tests::devices::keyboard::KeyboardDevice;
+
+const KEY_A: u16 = 30;
+const WRITERS: usize = 4;
+
+/// Writes KEY_A until `stop`, returns the successful and the refused writes
+fn write_until(fd: i32, stop: &AtomicBool, unexpected: &Mutex<Vec<String>>) -> (u64, u64) {
+    let (mut written, mut refused) = (0, 0);
+    let mut value = 1;
+    while !stop.load(Ordering::Relaxed) {
+        let result = emit(fd, EV_KEY, KEY_A, value).and_then(|()| emit(fd, EV_SYN, SYN_REPORT, 0));
+        match result {
+            Ok(()) => written += 1,
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => refused += 1,
+            Err(e) => unexpected.lock().unwrap().push(e.to_string()),
+        }
+        value = 1 - value;
+    }
+    (written, refused)
+}
+
+fn main() {
+    let keyboard =
+        KeyboardDevice::create(Some("/dev/uinput"), "Race Keyboard").unwrap_or_else(|e| {
+            eprintln!("failed to create the keyboard: {}", e);
+            std::process::exit(1);
+        });
+    let fd = keyboard.uinput_fd();
+    let stop = Arc::new(AtomicBool::new(false));
+    let unexpected = Arc::new(Mutex::new(Vec::new()));
+    let writers: Vec<_> = (0..WRITERS)
+        .map(|_| {
+            let (stop, unexpected) = (stop.clone(), unexpected.clone());
+            thread::spawn(move || write_until(fd, &stop, &unexpected))
+        })
+        .collect();
+
+    thread::sleep(Duration::from_millis(200));
+    if let Err(e) = unsafe { ui_dev_destroy(fd) } {
+        eprintln!("UI_DEV_DESTROY failed: {}", e);
+        std::process::exit(1);
+    }
+    let after_destroy = emit(fd, EV_KEY, KEY_A, 1);
+    thread::sleep(Duration::from_millis(100));
+    stop.store(true, Ordering::Relaxed);
+    let (written, refused) = writers
+        .into_iter()
+        .map(|writer| writer.join().unwrap())
+        .fold((0, 0), |(w, r), (written, refused)| {
+            (w + written, r + refused)
+        });
+    keyboard.destroy();
+
+    println!("writes {} refused {}", written, refused);
+    let unexpected = unexpected.lock().unwrap();
+    if !unexpected.is_empty() {
+        eprintln!("writes failed unexpectedly: {:?}", unexpected);
+        std::process::exit(1);
+    }
+    if after_destroy.is_ok() {
+        eprintln!("a write after UI_DEV_DESTROY succeeded");
+        std::process::exit(1);
+    }
+    if written == 0 || refused == 0 {
+        eprintln!("the writes did not race the destroy");
+        std::process::exit(1);
+    }
+}
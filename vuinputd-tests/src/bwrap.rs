@@ -6,7 +6,7 @@ use std::io;
 use std::os::fd::{FromRawFd, IntoRawFd, OwnedFd};
 use std::os::unix::net::UnixDatagram;
 use std::os::unix::process::CommandExt;
-use std::process::{Command, Output};
+use std::process::{Child, Command, Output};
 
 use nix::errno::Errno;
 use nix::sys::socket::{socketpair, AddressFamily, SockFlag, SockType};
@@ -51,22 +51,71 @@ impl BwrapBuilder {
         self
     }
 
-    pub fn dev(mut self) -> Self {
+    pub fn unshare_user(mut self) -> Self {
+        self.args.push("--unshare-user".into());
+        self
+    }
+
+    /// Keeps the network of the host after unshare_all
+    pub fn share_net(mut self) -> Self {
+        self.args.push("--share-net".into());
+        self
+    }
+
+    /// The uid in the sandbox, the uid of the caller is mapped to it (with unshare_user)
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.args.push("--uid".into());
+        self.args.push(uid.to_string());
+        self
+    }
+
+    /// The gid in the sandbox, the gid of the caller is mapped to it (with unshare_user)
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.args.push("--gid".into());
+        self.args.push(gid.to_string());
+        self
+    }
+
+    /// e.g. "CAP_SYS_ADMIN" or "ALL", only for a bwrap that runs as root
+    pub fn cap_add(mut self, capability: &str) -> Self {
+        self.args.push("--cap-add".into());
+        self.args.push(capability.into());
+        self
+    }
+
+    pub fn cap_drop(mut self, capability: &str) -> Self {
+        self.args.push("--cap-drop".into());
+        self.args.push(capability.into());
+        self
+    }
+
+    pub fn dev(self) -> Self {
+        self.dev_of("vuinput-test")
+    }
+
+    /// /dev and /dev/input from the directories of the vuinputd instance `devname`
+    pub fn dev_of(mut self, devname: &str) -> Self {
         // for our tests, we cannot simply use the "--dev"-flag, because it creates a tmpfs with the nodev flag
         // see SETUP_MOUNT_DEV and PRIV_SEP_OP_TMPFS_MOUNT
         // in https://github.com/containers/bubblewrap/blob/v0.11.0/bubblewrap.c#L1370-L1376 .
         // So, we mount a temporary directory that does not have this restrictions.
         self.args.extend([
             "--dev-bind".into(),
-            "/run/vuinputd/vuinput-test/dev".into(),
+            format!("/run/vuinputd/{devname}/dev"),
             "/dev".into(),
             "--dev-bind".into(),
-            "/run/vuinputd/vuinput-test/dev-input".into(),
+            format!("/run/vuinputd/{devname}/dev-input"),
             "/dev/input".into(),
         ]);
         self
     }
 
+    pub fn setenv(mut self, key: &str, value: &str) -> Self {
+        self.args
+            .extend(["--setenv".into(), key.into(), value.into()]);
+        self
+    }
+
     pub fn tmpfs(mut self, path: &str) -> Self {
         self.args.push("--tmpfs".into());
         self.args.push(path.into());
@@ -128,6 +177,15 @@ impl BwrapBuilder {
     }
 
     pub fn run(mut self) -> io::Result<Output> {
+        self.build().output()
+    }
+
+    /// Starts the container without waiting for it, e.g. to talk to it over IPC while it runs.
+    pub fn spawn(mut self) -> io::Result<Child> {
+        self.build().spawn()
+    }
+
+    fn build(&mut self) -> Command {
         println!("Arguments for bwrap: {:?}", &self.args);
 
         let mut cmd = Command::new("bwrap");
@@ -150,15 +208,60 @@ impl BwrapBuilder {
             };
         }
 
-        cmd.args(&self.args).output()
+        cmd.args(&self.args);
+        cmd
     }
 }
 
-#[cfg(feature = "requires-bwrap")]
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn builds_the_arguments() {
+        let builder = BwrapBuilder::new()
+            .unshare_all()
+            .share_net()
+            .unshare_user()
+            .uid(0)
+            .gid(0)
+            .cap_add("CAP_MKNOD")
+            .dev_of("vuinput-a")
+            .dev_bind("/run/vuinputd/vuinput-a/udev", "/run/udev")
+            .setenv("LANG", "C")
+            .command("/test-ok", &["--quiet"]);
+        assert_eq!(
+            builder.args,
+            [
+                "--unshare-all",
+                "--share-net",
+                "--unshare-user",
+                "--uid",
+                "0",
+                "--gid",
+                "0",
+                "--cap-add",
+                "CAP_MKNOD",
+                "--dev-bind",
+                "/run/vuinputd/vuinput-a/dev",
+                "/dev",
+                "--dev-bind",
+                "/run/vuinputd/vuinput-a/dev-input",
+                "/dev/input",
+                "--dev-bind",
+                "/run/vuinputd/vuinput-a/udev",
+                "/run/udev",
+                "--setenv",
+                "LANG",
+                "C",
+                "--",
+                "/test-ok",
+                "--quiet",
+            ]
+        );
+    }
+
+    #[cfg(feature = "requires-bwrap")]
     #[test]
     fn bwrap_works() {
         if !bwrap_available() {
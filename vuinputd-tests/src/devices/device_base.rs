@@ -2,6 +2,7 @@
 //
 // Author: Johannes Leupolz <dev@leupolz.eu>
 
+use crate::evemu;
 use crate::test_log::LoggedInputEvent;
 use libc::{c_int, close, open, write, O_NONBLOCK, O_RDWR, O_WRONLY};
 use libc::{input_event, timespec, uinput_setup, CLOCK_MONOTONIC};
@@ -130,6 +131,11 @@ pub trait Device: Sized {
         &mut self.state_mut().events
     }
 
+    /// Describe the event device like evemu-describe
+    fn evemu_description(&self) -> io::Result<evemu::Description> {
+        evemu::Description::read(self.get_event_device()?)
+    }
+
     /// Setup the uinput device (calls ui_dev_setup and ui_get_sysname)
     fn setup_device(
         &self,
@@ -7,6 +7,7 @@ pub mod keyboard;
 pub mod mouse;
 pub mod mouse_absolute;
 pub mod ps4_gamepad;
+pub mod touchpad;
 pub mod utils;
 pub mod xbox_gamepad;
 
@@ -17,6 +18,7 @@ pub use keyboard::KeyboardDevice;
 pub use mouse::MouseDevice;
 pub use mouse_absolute::MouseAbsoluteDevice;
 pub use ps4_gamepad::Ps4GamepadDevice;
+pub use touchpad::TouchpadDevice;
 pub use xbox_gamepad::XboxGamepadDevice;
 
 // Re-export constants from device_base for backward compatibility
@@ -0,0 +1,153 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// A clickpad as offered by remote desktops: two multitouch slots, no physical buttons
+// besides the pad itself. INPUT_PROP_POINTER and INPUT_PROP_BUTTONPAD make udev classify it
+// as ID_INPUT_TOUCHPAD and libinput treat it as an indirect touchpad.
+
+use crate::devices::{
+    device_base::{fetch_device_node, open_uinput, Device, DeviceState, BUS_USB},
+    utils::{ABS_X, ABS_Y},
+};
+use libc::{
+    c_int, close, input_absinfo, open, uinput_abs_setup, INPUT_PROP_BUTTONPAD, INPUT_PROP_POINTER,
+};
+use std::io;
+use uinput_ioctls::*;
+
+// Touchpad codes
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_TOOL_FINGER: u16 = 0x145;
+pub const BTN_TOUCH: u16 = 0x14a;
+pub const BTN_TOOL_DOUBLETAP: u16 = 0x14d;
+pub const ABS_MT_SLOT: u16 = 0x2f;
+pub const ABS_MT_POSITION_X: u16 = 0x35;
+pub const ABS_MT_POSITION_Y: u16 = 0x36;
+pub const ABS_MT_TRACKING_ID: u16 = 0x39;
+
+// 100 x 62 mm at 10 units per mm
+pub const TOUCHPAD_MAX_X: i32 = 1000;
+pub const TOUCHPAD_MAX_Y: i32 = 620;
+const TOUCHPAD_RESOLUTION: i32 = 10;
+
+unsafe fn setup_axis(fd: c_int, code: u16, maximum: i32, resolution: i32) -> io::Result<()> {
+    let setup = uinput_abs_setup {
+        code,
+        absinfo: input_absinfo {
+            value: 0,
+            minimum: 0,
+            maximum,
+            fuzz: 0,
+            flat: 0,
+            resolution,
+        },
+    };
+    ui_abs_setup(fd, &setup)
+        .map_err(|e| io::Error::other(format!("ui_abs_setup {} failed: {:?}", code, e)))?;
+    Ok(())
+}
+
+/// Setup touchpad device
+unsafe fn setup_touchpad(fd: c_int) -> io::Result<()> {
+    ui_set_evbit(fd, super::EV_SYN.into())?;
+
+    ui_set_propbit(fd, INPUT_PROP_POINTER.into())?;
+    ui_set_propbit(fd, INPUT_PROP_BUTTONPAD.into())?;
+
+    // EV_KEY: the click of the pad and the number of fingers
+    ui_set_evbit(fd, super::EV_KEY.into())?;
+    for key in [BTN_LEFT, BTN_TOOL_FINGER, BTN_TOUCH, BTN_TOOL_DOUBLETAP] {
+        ui_set_keybit(fd, key.into())?;
+    }
+
+    // EV_ABS: UI_ABS_SETUP also sets the absbit
+    ui_set_evbit(fd, super::EV_ABS.into())?;
+    setup_axis(fd, ABS_X, TOUCHPAD_MAX_X, TOUCHPAD_RESOLUTION)?;
+    setup_axis(fd, ABS_Y, TOUCHPAD_MAX_Y, TOUCHPAD_RESOLUTION)?;
+    setup_axis(fd, ABS_MT_SLOT, 1, 0)?;
+    setup_axis(fd, ABS_MT_POSITION_X, TOUCHPAD_MAX_X, TOUCHPAD_RESOLUTION)?;
+    setup_axis(fd, ABS_MT_POSITION_Y, TOUCHPAD_MAX_Y, TOUCHPAD_RESOLUTION)?;
+    setup_axis(fd, ABS_MT_TRACKING_ID, 0xffff, 0)?;
+
+    Ok(())
+}
+
+pub struct TouchpadDevice {
+    state: DeviceState,
+}
+
+impl Device for TouchpadDevice {
+    fn name() -> &'static str {
+        "Touchpad"
+    }
+
+    fn state(&self) -> &DeviceState {
+        &self.state
+    }
+
+    fn state_mut(&mut self) -> &mut DeviceState {
+        &mut self.state
+    }
+
+    fn get_event_device(&self) -> Result<c_int, io::Error> {
+        Ok(self.state.event_device_fd)
+    }
+
+    fn create(device: Option<&str>, name: &str) -> Result<Self, io::Error> {
+        let fd = open_uinput(device)?;
+
+        unsafe { setup_touchpad(fd)? };
+
+        let temp_device = TouchpadDevice {
+            state: DeviceState {
+                uinput_fd: fd,
+                sysname: String::new(),
+                device_name: name.to_string(),
+                event_device_node: String::new(),
+                event_device_fd: -1,
+                events: Vec::new(),
+            },
+        };
+        temp_device.setup_device(name, 0xbeef, 0xdead, BUS_USB, 0)?;
+
+        unsafe {
+            ui_dev_create(fd).inspect_err(|e| eprintln!("ui_dev_create failed: {:?}", e))?;
+        }
+
+        let sysname = temp_device.get_sysname()?;
+
+        let event_device_node = fetch_device_node(&sysname)?;
+        let event_device_fd = unsafe {
+            open(
+                event_device_node.as_ptr() as *const i8,
+                libc::O_RDONLY | libc::O_NONBLOCK,
+            )
+        };
+        if event_device_fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(TouchpadDevice {
+            state: DeviceState {
+                uinput_fd: fd,
+                sysname,
+                device_name: name.to_string(),
+                event_device_node,
+                event_device_fd,
+                events: Vec::new(),
+            },
+        })
+    }
+
+    fn destroy(self) {
+        unsafe {
+            ui_dev_destroy(self.state.uinput_fd).unwrap_or_else(|e| {
+                eprintln!("ui_dev_destroy failed: {:?}", e);
+                std::process::exit(1);
+            });
+            close(self.state.uinput_fd);
+            close(self.state.event_device_fd);
+        }
+    }
+}
@@ -0,0 +1,242 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Recordings in the format of evemu (https://gitlab.freedesktop.org/libevdev/evemu): a .desc
+// file describes the device like evemu-describe does, a .events file holds the events like
+// evemu-record does. Both can be replayed with evemu-device and evemu-play, e.g. to compare a
+// device created through vuinputd with one created on /dev/uinput directly.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::mem::{size_of, zeroed};
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+
+use libc::{input_absinfo, input_id};
+use nix::sys::ioctl::ioctl_num_type;
+use nix::{ioctl_read, ioctl_read_buf, request_code_read};
+
+use crate::test_log::{LoggedInputEvent, TestLog};
+
+const EVEMU_VERSION: &str = "1.3";
+const EV_SYN: u16 = 0x00;
+const EV_ABS: u16 = 0x03;
+const EV_CNT: u16 = 0x20;
+const ABS_CNT: u16 = 0x40;
+/// Large enough for every bitmask of the kernel (KEY_CNT bits)
+const MAX_MASK_BYTES: usize = 0x300 / 8;
+const BYTES_PER_LINE: usize = 8;
+
+ioctl_read!(eviocgid, b'E', 0x02, input_id);
+ioctl_read_buf!(eviocgname, b'E', 0x06, u8);
+ioctl_read_buf!(eviocgprop, b'E', 0x09, u8);
+
+/// Capabilities of an evdev device, as far as evemu describes them
+#[derive(Debug, Clone)]
+pub struct Description {
+    pub name: String,
+    pub id: input_id,
+    pub properties: Vec<u8>,
+    /// Bitmask of the codes per event type, type 0 holds the supported event types
+    pub masks: Vec<(u16, Vec<u8>)>,
+    pub absinfo: Vec<(u16, input_absinfo)>,
+}
+
+impl Description {
+    /// Queries the capabilities of an opened evdev device (/dev/input/event*)
+    pub fn read(fd: c_int) -> io::Result<Description> {
+        let mut name = [0u8; 256];
+        let mut id: input_id = unsafe { zeroed() };
+        let mut properties = [0u8; BYTES_PER_LINE];
+        let properties_len = unsafe {
+            eviocgname(fd, &mut name)?;
+            eviocgid(fd, &mut id)?;
+            eviocgprop(fd, &mut properties)?
+        };
+        let name = name.split(|b| *b == 0).next().unwrap_or_default();
+
+        let mut masks = Vec::new();
+        for ev_type in 0..EV_CNT {
+            let mut mask = [0u8; MAX_MASK_BYTES];
+            let request = request_code_read!(b'E', 0x20 + ev_type, mask.len());
+            let len = unsafe { libc::ioctl(fd, request as ioctl_num_type, mask.as_mut_ptr()) };
+            // types without codes (e.g. EV_PWR) are refused with EINVAL
+            if len > 0 {
+                masks.push((ev_type, mask[..len as usize].to_vec()));
+            }
+        }
+
+        let mut absinfo = Vec::new();
+        for code in (0..ABS_CNT).filter(|code| has_code(&masks, EV_ABS, *code)) {
+            let mut info: input_absinfo = unsafe { zeroed() };
+            let request = request_code_read!(b'E', 0x40 + code, size_of::<input_absinfo>());
+            if unsafe { libc::ioctl(fd, request as ioctl_num_type, &mut info) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            absinfo.push((code, info));
+        }
+
+        Ok(Description {
+            name: String::from_utf8_lossy(name).into_owned(),
+            id,
+            properties: properties[..properties_len as usize].to_vec(),
+            masks,
+            absinfo,
+        })
+    }
+
+    /// Renders the description like evemu-describe
+    pub fn to_evemu(&self) -> String {
+        let mut out = format!("# EVEMU {}\n", EVEMU_VERSION);
+        let _ = writeln!(out, "# Input device name: \"{}\"", self.name);
+        let _ = writeln!(out, "N: {}", self.name);
+        let _ = writeln!(
+            out,
+            "I: {:04x} {:04x} {:04x} {:04x}",
+            self.id.bustype, self.id.vendor, self.id.product, self.id.version
+        );
+        write_mask(&mut out, "P:", &self.properties);
+        for (ev_type, mask) in &self.masks {
+            write_mask(&mut out, &format!("B: {:02x}", ev_type), mask);
+        }
+        for (code, info) in &self.absinfo {
+            let _ = writeln!(
+                out,
+                "A: {:02x} {} {} {} {} {}",
+                code, info.minimum, info.maximum, info.fuzz, info.flat, info.resolution
+            );
+        }
+        out
+    }
+}
+
+fn has_code(masks: &[(u16, Vec<u8>)], ev_type: u16, code: u16) -> bool {
+    masks
+        .iter()
+        .find(|(t, _)| *t == ev_type)
+        .and_then(|(_, mask)| mask.get(usize::from(code / 8)))
+        .is_some_and(|byte| byte & (1 << (code % 8)) != 0)
+}
+
+/// Writes the mask in lines of 8 bytes, padded with zeros
+fn write_mask(out: &mut String, prefix: &str, mask: &[u8]) {
+    let lines = mask.len().div_ceil(BYTES_PER_LINE).max(1);
+    for line in 0..lines {
+        out.push_str(prefix);
+        for i in 0..BYTES_PER_LINE {
+            let byte = mask.get(line * BYTES_PER_LINE + i).copied().unwrap_or(0);
+            let _ = write!(out, " {:02x}", byte);
+        }
+        out.push('\n');
+    }
+}
+
+impl TestLog {
+    /// Renders the events like evemu-record, with times relative to the first event. The log
+    /// leaves out the SYN_REPORT that ends each frame, so one is added after every event.
+    /// Keyboard keys are written as they are; scrub the log first if it may hold real input.
+    pub fn to_evemu(&self) -> String {
+        let mut out = format!("# EVEMU {}\n", EVEMU_VERSION);
+        let Some(first) = self.events.first() else {
+            return out;
+        };
+        let start = first.tv_sec * 1_000_000_000 + first.tv_nsec;
+        for event in &self.events {
+            let elapsed = (event.tv_sec * 1_000_000_000 + event.tv_nsec - start).max(0);
+            let time = format!(
+                "{}.{:06}",
+                elapsed / 1_000_000_000,
+                elapsed % 1_000_000_000 / 1000
+            );
+            write_event(&mut out, &time, event);
+            if event.type_ != EV_SYN {
+                let _ = writeln!(out, "E: {} 0000 0000 0", time);
+            }
+        }
+        out
+    }
+}
+
+fn write_event(out: &mut String, time: &str, event: &LoggedInputEvent) {
+    let _ = writeln!(
+        out,
+        "E: {} {:04x} {:04x} {}",
+        time, event.type_, event.code, event.value
+    );
+}
+
+/// Writes <dir>/<name>.desc and <dir>/<name>.events
+pub fn save(
+    dir: &Path,
+    name: &str,
+    description: &Description,
+    log: &TestLog,
+) -> io::Result<(PathBuf, PathBuf)> {
+    fs::create_dir_all(dir)?;
+    let desc = dir.join(format!("{}.desc", name));
+    let events = dir.join(format!("{}.events", name));
+    fs::write(&desc, description.to_evemu())?;
+    fs::write(&events, log.to_evemu())?;
+    Ok((desc, events))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logged(tv_sec: i64, tv_nsec: i64, type_: u16, code: u16, value: i32) -> LoggedInputEvent {
+        LoggedInputEvent {
+            tv_sec,
+            tv_nsec,
+            duration_usec: 0,
+            type_,
+            code,
+            value,
+            send_and_receive_match: true,
+        }
+    }
+
+    #[test]
+    fn renders_descriptions_and_events() {
+        let mut keys = vec![0u8; 96];
+        keys[0x110 / 8] = 0x07; // BTN_LEFT, BTN_RIGHT, BTN_MIDDLE
+        let description = Description {
+            name: "Example Mouse".to_string(),
+            id: input_id {
+                bustype: 0x03,
+                vendor: 0x1234,
+                product: 0x5678,
+                version: 1,
+            },
+            properties: vec![],
+            masks: vec![(0, vec![0x07, 0, 0, 0]), (1, keys), (2, vec![0x03, 0])],
+            absinfo: vec![],
+        };
+        let desc = description.to_evemu();
+        assert!(desc.starts_with("# EVEMU 1.3\n"));
+        assert!(desc.contains("N: Example Mouse\nI: 0003 1234 5678 0001\n"));
+        assert!(desc.contains("P: 00 00 00 00 00 00 00 00\nB: 00 07 00 00 00 00 00 00 00\n"));
+        assert_eq!(desc.matches("B: 01").count(), 12);
+        assert!(desc.contains("B: 01 00 00 07 00 00 00 00 00\n"));
+        assert!(desc.ends_with("B: 02 03 00 00 00 00 00 00 00\n"));
+        assert!(has_code(&description.masks, 1, 0x112));
+        assert!(!has_code(&description.masks, 3, 0));
+
+        let log = TestLog {
+            events: vec![
+                logged(10, 500_000, 1, 0x110, 1),
+                logged(11, 2_000, 1, 0x110, 0),
+            ],
+        };
+        assert_eq!(
+            log.to_evemu(),
+            "# EVEMU 1.3\n\
+             E: 0.000000 0001 0110 1\n\
+             E: 0.000000 0000 0000 0\n\
+             E: 0.999502 0001 0110 0\n\
+             E: 0.999502 0000 0000 0\n"
+        );
+    }
+}
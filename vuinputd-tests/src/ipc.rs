@@ -4,6 +4,19 @@
 
 // TODO: Use https://varlink.org/ which also supports bridges over ssh, which is nice
 
+// The test on the host and the binary in the container talk over a seqpacket socket, which
+// keeps the boundaries of the packets. A message is one packet of JSON, so a test can go
+// through several steps with the container, like
+//
+//   host                                  container (test-ipc)
+//   CreateDevice { kind, name }    ->
+//                                  <-     DeviceCreated { syspath, devnode, major, minor }
+//   (checks the device on the host)
+//   Ready                          ->
+//                                  <-     EventLog { events }
+//
+// Either side answers what it can't handle with Error. send and recv still pass raw bytes.
+
 use std::{
     io,
     os::{
@@ -13,6 +26,66 @@ use std::{
     time::Duration,
 };
 
+use serde::{Deserialize, Serialize};
+
+use crate::test_log::LoggedInputEvent;
+
+/// Largest message recv_message accepts, an event log of a few hundred events fits
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeviceKind {
+    Keyboard,
+    Mouse,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Message {
+    /// The sender is ready for the next step, e.g. the host has checked a device
+    Ready,
+    /// Asks the container to create a device
+    CreateDevice {
+        kind: DeviceKind,
+        name: String,
+    },
+    /// The container has created a device, major and minor are those of its event node
+    DeviceCreated {
+        syspath: String,
+        devnode: String,
+        major: u32,
+        minor: u32,
+    },
+    /// The container has seen the remove event of a device on its udev monitor
+    DeviceRemoved {
+        syspath: String,
+    },
+    /// The events the container has emitted and read back from the event node
+    EventLog {
+        events: Vec<LoggedInputEvent>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+fn send_message(sock: &UnixDatagram, message: &Message) -> io::Result<()> {
+    let packet = serde_json::to_vec(message)?;
+    sock.send(&packet)?;
+    Ok(())
+}
+
+fn recv_message(sock: &UnixDatagram, read_timeout: Option<Duration>) -> io::Result<Message> {
+    let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+    sock.set_read_timeout(read_timeout)?;
+    let n = sock.recv(&mut buf)?;
+    if n == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    serde_json::from_slice(&buf[..n]).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
 /// IPC handle kept by the parent.
 pub struct SandboxIpc {
     pub sock: UnixDatagram,
@@ -31,6 +104,15 @@ impl SandboxIpc {
         self.sock.send(data)?;
         Ok(())
     }
+
+    pub fn send_message(&self, message: &Message) -> io::Result<()> {
+        send_message(&self.sock, message)
+    }
+
+    /// Receives the next message, an empty packet (the container has exited) is an error
+    pub fn recv_message(&self, read_timeout: Option<Duration>) -> io::Result<Message> {
+        recv_message(&self.sock, read_timeout)
+    }
 }
 
 /// IPC handle inside the container.
@@ -61,4 +143,57 @@ impl SandboxChildIpc {
         buf.truncate(n);
         Ok(buf)
     }
+
+    pub fn send_message(&self, message: &Message) -> io::Result<()> {
+        send_message(&self.sock, message)
+    }
+
+    pub fn recv_message(&self, read_timeout: Option<Duration>) -> io::Result<Message> {
+        recv_message(&self.sock, read_timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_keep_their_boundaries() {
+        let (host, child) = UnixDatagram::pair().unwrap();
+        let created = Message::DeviceCreated {
+            syspath: "/sys/devices/virtual/input/input42".to_string(),
+            devnode: "/dev/input/event7".to_string(),
+            major: 13,
+            minor: 71,
+        };
+        let log = Message::EventLog {
+            events: vec![LoggedInputEvent {
+                tv_sec: 1,
+                tv_nsec: 2,
+                duration_usec: 3,
+                type_: 1,
+                code: 30,
+                value: 1,
+                send_and_receive_match: true,
+            }],
+        };
+        send_message(&child, &created).unwrap();
+        send_message(&child, &log).unwrap();
+        let timeout = Some(Duration::from_secs(1));
+        assert_eq!(recv_message(&host, timeout).unwrap(), created);
+        assert_eq!(recv_message(&host, timeout).unwrap(), log);
+
+        assert_eq!(
+            serde_json::to_string(&Message::CreateDevice {
+                kind: DeviceKind::Keyboard,
+                name: "IPC Keyboard".to_string()
+            })
+            .unwrap(),
+            r#"{"type":"create-device","kind":"keyboard","name":"IPC Keyboard"}"#
+        );
+
+        host.send(b"continue").unwrap();
+        let e = recv_message(&child, timeout).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
 }
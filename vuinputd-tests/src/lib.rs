@@ -4,8 +4,12 @@
 
 pub mod bwrap;
 pub mod devices;
+pub mod evemu;
 pub mod ipc;
+pub mod libinput;
+pub mod nspawn;
 pub mod podman;
 pub mod run_vuinputd;
 pub mod scenarios;
 pub mod test_log;
+pub mod udev_monitor;
@@ -0,0 +1,162 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Reads the output of `libinput debug-events`, which sees the devices the way a compositor
+// does: through the udev monitor and the udev database of the seat. A device that libinput
+// enumerates on the right seat and whose events come out of it is one a compositor accepts,
+// which is more than an existing node proves. Parsing the output of the tool keeps libinput a
+// runtime dependency of the test image only.
+//
+// The lines look like
+//
+//   -event5   DEVICE_ADDED            IPC Keyboard                      seat0 default group6  cap:k
+//    event5   KEYBOARD_KEY            +0.112s	KEY_A (30) pressed
+//    event6   POINTER_MOTION          +0.240s	  5.00/  0.00 ( +5.00/ +0.00)
+//    event6   POINTER_BUTTON          +0.241s	BTN_LEFT (272) pressed, seat count: 1
+
+use crate::devices::{EV_KEY, EV_REL};
+
+/// Codes of EV_KEY from BTN_MISC on are buttons, libinput reports them as POINTER_BUTTON
+const BTN_MISC: u16 = 0x100;
+
+/// One line of `libinput debug-events`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugEvent {
+    /// e.g. "event5", the name of the event node
+    pub node: String,
+    /// e.g. "KEYBOARD_KEY"
+    pub kind: String,
+    /// Everything after the kind
+    pub detail: String,
+}
+
+/// A device as DEVICE_ADDED shows it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddedDevice {
+    pub name: String,
+    /// The physical seat, the ID_SEAT of the udev database
+    pub seat: String,
+    /// The logical seat, "default" unless the device has a WL_SEAT
+    pub logical_seat: String,
+}
+
+/// Parses one line of `libinput debug-events`, None for lines without an event node
+pub fn parse_debug_event(line: &str) -> Option<DebugEvent> {
+    let line = line.trim_start_matches([' ', '-']);
+    let (node, rest) = line.split_once(char::is_whitespace)?;
+    if !node.starts_with("event") {
+        return None;
+    }
+    let rest = rest.trim_start();
+    let (kind, detail) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    Some(DebugEvent {
+        node: node.to_string(),
+        kind: kind.to_string(),
+        detail: detail.trim().to_string(),
+    })
+}
+
+impl DebugEvent {
+    /// Name and seats of a DEVICE_ADDED event, the name may contain spaces
+    pub fn added_device(&self) -> Option<AddedDevice> {
+        if self.kind != "DEVICE_ADDED" {
+            return None;
+        }
+        let words: Vec<&str> = self.detail.split_whitespace().collect();
+        let group = words.iter().position(|word| word.starts_with("group"))?;
+        if group < 3 {
+            return None;
+        }
+        Some(AddedDevice {
+            name: words[..group - 2].join(" "),
+            seat: words[group - 2].to_string(),
+            logical_seat: words[group - 1].to_string(),
+        })
+    }
+
+    /// Code and state of a KEYBOARD_KEY or POINTER_BUTTON event, needs --show-keycodes
+    fn key(&self) -> Option<(u16, bool)> {
+        let (_, after) = self.detail.split_once('(')?;
+        let (code, state) = after.split_once(')')?;
+        let pressed = match state.split([' ', ',']).find(|word| !word.is_empty())? {
+            "pressed" => true,
+            "released" => false,
+            _ => return None,
+        };
+        Some((code.trim().parse().ok()?, pressed))
+    }
+
+    /// Whether this is what libinput makes of the evdev event (`ev_type`, `code`, `value`).
+    /// Relative motion is only compared by its kind, libinput accelerates it.
+    pub fn yields(&self, ev_type: u16, code: u16, value: i32) -> bool {
+        match ev_type {
+            EV_KEY => {
+                let kind = if code < BTN_MISC {
+                    "KEYBOARD_KEY"
+                } else {
+                    "POINTER_BUTTON"
+                };
+                self.kind == kind && self.key() == Some((code, value != 0))
+            }
+            EV_REL => self.kind == "POINTER_MOTION",
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::keyboard::KEY_A;
+    use crate::devices::mouse::{BTN_LEFT, REL_X};
+
+    #[test]
+    fn understands_debug_events() {
+        let added = parse_debug_event(
+            "-event5   DEVICE_ADDED            IPC Keyboard                      seat_vuinput_4690 default group6  cap:k",
+        )
+        .unwrap();
+        assert_eq!(added.node, "event5");
+        assert_eq!(
+            added.added_device(),
+            Some(AddedDevice {
+                name: "IPC Keyboard".to_string(),
+                seat: "seat_vuinput_4690".to_string(),
+                logical_seat: "default".to_string(),
+            })
+        );
+
+        let key =
+            parse_debug_event(" event5   KEYBOARD_KEY            +0.112s\tKEY_A (30) pressed")
+                .unwrap();
+        assert_eq!(key.added_device(), None);
+        assert!(key.yields(EV_KEY, KEY_A, 1));
+        assert!(!key.yields(EV_KEY, KEY_A, 0));
+        // without --show-keycodes, the key is hidden
+        let hidden =
+            parse_debug_event(" event5   KEYBOARD_KEY            +0.112s\t*** (-1) pressed")
+                .unwrap();
+        assert!(!hidden.yields(EV_KEY, KEY_A, 1));
+
+        let button = parse_debug_event(
+            " event6   POINTER_BUTTON          +0.241s\tBTN_LEFT (272) released, seat count: 0",
+        )
+        .unwrap();
+        assert!(button.yields(EV_KEY, BTN_LEFT, 0));
+        assert!(!button.yields(EV_KEY, BTN_LEFT, 1));
+
+        let motion = parse_debug_event(
+            " event6   POINTER_MOTION          +0.240s\t  5.00/  0.00 ( +5.00/ +0.00)",
+        )
+        .unwrap();
+        assert!(motion.yields(EV_REL, REL_X, 5));
+        assert!(!motion.yields(EV_KEY, BTN_LEFT, 1));
+
+        assert_eq!(
+            parse_debug_event("Kernel:           /dev/input/event5"),
+            None
+        );
+        assert_eq!(parse_debug_event(""), None);
+    }
+}
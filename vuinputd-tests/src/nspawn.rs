@@ -0,0 +1,129 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// systemd-nspawn runs a directory tree as a container. The tests use the file system of the
+// podman image exported into a directory, see docs/TESTS.md.
+
+use std::io;
+use std::process::{Command, Output};
+
+/// Check if systemd-nspawn is available.
+pub fn nspawn_available() -> bool {
+    Command::new("systemd-nspawn")
+        .arg("--version")
+        .output()
+        .map(|o| o.status.success())
+        .unwrap_or(false)
+}
+
+/// Builder for systemd-nspawn invocations.
+pub struct NspawnBuilder {
+    args: Vec<String>,
+    command: Vec<String>,
+}
+
+impl Default for NspawnBuilder {
+    fn default() -> Self {
+        Self {
+            args: vec!["--quiet".into()],
+            command: Vec::new(),
+        }
+    }
+}
+
+impl NspawnBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The root of the container
+    pub fn directory(mut self, directory: &str) -> Self {
+        self.args.push(format!("--directory={directory}"));
+        self
+    }
+
+    /// "source:destination" or just the path
+    pub fn bind(mut self, spec: &str) -> Self {
+        self.args.push(format!("--bind={spec}"));
+        self
+    }
+
+    pub fn bind_ro(mut self, spec: &str) -> Self {
+        self.args.push(format!("--bind-ro={spec}"));
+        self
+    }
+
+    /// e.g. "pick" for a shifted user namespace, see systemd-nspawn(1)
+    pub fn private_users(mut self, mode: &str) -> Self {
+        self.args.push(format!("--private-users={mode}"));
+        self
+    }
+
+    /// A property of the scope unit of the container, e.g. "DeviceAllow=char-input rw"
+    pub fn property(mut self, property: &str) -> Self {
+        self.args.push(format!("--property={property}"));
+        self
+    }
+
+    pub fn allow_input_devices(self) -> Self {
+        self.property("DeviceAllow=char-input rw")
+    }
+
+    pub fn setenv(mut self, key: &str, value: &str) -> Self {
+        self.args.push(format!("--setenv={key}={value}"));
+        self
+    }
+
+    /// The command to run in the container
+    pub fn command(mut self, cmd: &[&str]) -> Self {
+        self.command = cmd.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    fn arguments(&self) -> Vec<String> {
+        let mut args = self.args.clone();
+        args.push("--".into());
+        args.extend(self.command.iter().cloned());
+        args
+    }
+
+    pub fn run(self) -> io::Result<Output> {
+        let args = self.arguments();
+        println!("Arguments for systemd-nspawn: {:?}", &args);
+        Command::new("systemd-nspawn").args(&args).output()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_the_arguments() {
+        let builder = NspawnBuilder::new()
+            .directory("/var/lib/machines/vuinputd-tests")
+            .bind("/dev/vuinput-test:/dev/uinput")
+            .bind_ro("/etc/resolv.conf")
+            .private_users("pick")
+            .allow_input_devices()
+            .setenv("RUST_BACKTRACE", "1")
+            .command(&["/test-libinput", "--kind", "mouse"]);
+        assert_eq!(
+            builder.arguments(),
+            [
+                "--quiet",
+                "--directory=/var/lib/machines/vuinputd-tests",
+                "--bind=/dev/vuinput-test:/dev/uinput",
+                "--bind-ro=/etc/resolv.conf",
+                "--private-users=pick",
+                "--property=DeviceAllow=char-input rw",
+                "--setenv=RUST_BACKTRACE=1",
+                "--",
+                "/test-libinput",
+                "--kind",
+                "mouse",
+            ]
+        );
+    }
+}
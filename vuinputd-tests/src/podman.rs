@@ -72,11 +72,17 @@ impl PodmanBuilder {
         self
     }
 
-    pub fn allow_input_devices(mut self) -> Self {
-        self.args.push("--device-cgroup-rule=\"c 13:* rwm\"".into());
+    /// e.g. "c 13:* rwm", podman gets the rule without any quoting of a shell
+    pub fn device_cgroup_rule(mut self, rule: &str) -> Self {
+        self.args.push("--device-cgroup-rule".into());
+        self.args.push(rule.into());
         self
     }
 
+    pub fn allow_input_devices(self) -> Self {
+        self.device_cgroup_rule("c 13:* rwm")
+    }
+
     pub fn volume(mut self, spec: &str) -> Self {
         self.args.push("-v".into());
         self.args.push(spec.into());
@@ -113,12 +119,56 @@ impl PodmanBuilder {
         self
     }
 
+    pub fn cap_add(mut self, capability: &str) -> Self {
+        self.args.push("--cap-add".into());
+        self.args.push(capability.into());
+        self
+    }
+
+    pub fn cap_drop(mut self, capability: &str) -> Self {
+        self.args.push("--cap-drop".into());
+        self.args.push(capability.into());
+        self
+    }
+
+    pub fn privileged(mut self) -> Self {
+        self.args.push("--privileged".into());
+        self
+    }
+
+    /// e.g. "host" to share the PID namespace of the host
+    pub fn pid(mut self, mode: &str) -> Self {
+        self.args.push("--pid".into());
+        self.args.push(mode.into());
+        self
+    }
+
+    pub fn network(mut self, mode: &str) -> Self {
+        self.args.push("--network".into());
+        self.args.push(mode.into());
+        self
+    }
+
+    /// "container_uid:from_uid:amount", see podman-run(1)
     pub fn uidmap(mut self, uidmap: &str) -> Self {
         self.args.push("--uidmap".into());
         self.args.push(uidmap.into());
         self
     }
 
+    /// "container_gid:from_gid:amount", see podman-run(1)
+    pub fn gidmap(mut self, gidmap: &str) -> Self {
+        self.args.push("--gidmap".into());
+        self.args.push(gidmap.into());
+        self
+    }
+
+    pub fn annotation(mut self, key: &str, value: &str) -> Self {
+        self.args.push("--annotation".into());
+        self.args.push(format!("{key}={value}"));
+        self
+    }
+
     /// Enable bidirectional IPC using a Unix seqpacket socketpair.
     pub fn with_ipc(mut self) -> io::Result<(Self, SandboxIpc)> {
         let (parent, child) = nix::sys::socket::socketpair(
@@ -179,11 +229,76 @@ impl PodmanBuilder {
     }
 }
 
-#[cfg(feature = "requires-podman")]
+/// Removes a detached container when dropped
+pub struct PodmanContainerGuard {
+    name: String,
+}
+
+impl PodmanContainerGuard {
+    pub fn new(name: &str) -> Self {
+        Self { name: name.into() }
+    }
+
+    pub fn logs(&self) -> io::Result<Output> {
+        Command::new("podman").args(["logs", &self.name]).output()
+    }
+}
+
+impl Drop for PodmanContainerGuard {
+    fn drop(&mut self) {
+        let _ = Command::new("podman")
+            .args(["rm", "--force", "--time", "5", &self.name])
+            .output();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn builds_the_arguments() {
+        let builder = PodmanBuilder::new()
+            .run_cmd()
+            .rm()
+            .userns("keep-id")
+            .uidmap("0:1:1000")
+            .gidmap("0:1:1000")
+            .cap_add("SYS_ADMIN")
+            .cap_drop("NET_RAW")
+            .network("none")
+            .allow_input_devices()
+            .annotation("org.vuinputd.enable", "true")
+            .image("localhost/vuinputd-tests:latest")
+            .command(&["/test-ok"]);
+        assert_eq!(
+            builder.args,
+            [
+                "run",
+                "--rm",
+                "--userns",
+                "keep-id",
+                "--uidmap",
+                "0:1:1000",
+                "--gidmap",
+                "0:1:1000",
+                "--cap-add",
+                "SYS_ADMIN",
+                "--cap-drop",
+                "NET_RAW",
+                "--network",
+                "none",
+                "--device-cgroup-rule",
+                "c 13:* rwm",
+                "--annotation",
+                "org.vuinputd.enable=true",
+                "localhost/vuinputd-tests:latest",
+                "/test-ok",
+            ]
+        );
+    }
+
+    #[cfg(feature = "requires-podman")]
     #[test]
     fn podman_builder_smoke() {
         if !podman_available() {
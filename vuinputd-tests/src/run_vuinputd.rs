@@ -3,11 +3,15 @@
 // Author: Johannes Leupolz <dev@leupolz.eu>
 
 use std::{
+    fs,
+    io::{self, Read, Write},
+    net::TcpStream,
     os::unix::process::CommandExt,
-    process::{Child, Command},
+    path::Path,
+    process::{Child, Command, ExitStatus},
     sync::Mutex,
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use nix::sys::signal::{self, Signal};
@@ -16,8 +20,17 @@ use nix::unistd::Pid;
 /// Global singleton
 static VUINPUTD_LOCK: Mutex<()> = Mutex::new(());
 
+/// The node of the --devname the tests use
+const CUSE_NODE: &str = "/dev/vuinput-test";
+
 pub fn ensure_vuinputd_running(args: &[&str]) -> VuinputdGuard {
-    VuinputdGuard::start(args)
+    VuinputdGuard::start(args, None)
+}
+
+/// Like ensure_vuinputd_running, but built with the fault-injection feature and `faults` in
+/// VUI_FAULTS, e.g. "UI_DEV_CREATE=EIO:1"
+pub fn ensure_vuinputd_running_with_faults(args: &[&str], faults: &str) -> VuinputdGuard {
+    VuinputdGuard::start(args, Some(faults))
 }
 
 pub struct VuinputdGuard {
@@ -25,14 +38,15 @@ pub struct VuinputdGuard {
 }
 
 impl VuinputdGuard {
-    fn start(args: &[&str]) -> Self {
+    fn start(args: &[&str], faults: Option<&str>) -> Self {
         println!("Acquiring lock to ensure only one vuinputd test instance is running");
         let _mutex = VUINPUTD_LOCK.lock().unwrap();
         println!("Executing vuinputd located via cargo run");
-        let mut concat_args = vec![
-            "run",
-            "-p",
-            "vuinputd",
+        let mut concat_args = vec!["run", "-p", "vuinputd"];
+        if faults.is_some() {
+            concat_args.extend(["--features", "fault-injection"]);
+        }
+        concat_args.extend([
             "--",
             "--major",
             "120",
@@ -40,10 +54,14 @@ impl VuinputdGuard {
             "414796",
             "--devname",
             "vuinput-test",
-        ];
+        ]);
         concat_args.extend(args);
+        let mut command = Command::new("cargo");
+        if let Some(faults) = faults {
+            command.env("VUI_FAULTS", faults);
+        }
         let child = unsafe {
-            Command::new("cargo")
+            command
                 .args(concat_args)
                 .pre_exec(|| {
                     // Last resort, if the parent just is killed.
@@ -56,13 +74,60 @@ impl VuinputdGuard {
 
         // Optional: give it time to create /dev/vuinput
         thread::sleep(Duration::from_millis(1000));
+        // with other features, cargo builds vuinputd first
+        let deadline = Instant::now() + Duration::from_secs(300);
+        while faults.is_some() && !Path::new(CUSE_NODE).exists() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(200));
+        }
 
         Self { child }
     }
+
+    /// The pid of vuinputd (cargo run replaces itself with it)
+    pub fn pid(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// The number of file descriptors vuinputd has open
+    pub fn open_fds(&self) -> io::Result<usize> {
+        Ok(fs::read_dir(format!("/proc/{}/fd", self.pid()))?.count())
+    }
+
+    /// The resident memory of vuinputd in KiB
+    pub fn rss_kib(&self) -> io::Result<u64> {
+        let status = fs::read_to_string(format!("/proc/{}/status", self.pid()))?;
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix("VmRSS:"))
+            .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+            .ok_or_else(|| io::Error::other("no VmRSS in the status of vuinputd"))
+    }
+
+    /// Whether vuinputd has not exited (or crashed) yet
+    pub fn is_running(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+
+    /// Sends SIGTERM and waits until vuinputd has cleaned up and exited. None if it is still
+    /// running after `timeout`.
+    pub fn terminate(&mut self, timeout: Duration) -> Option<ExitStatus> {
+        let _ = signal::kill(Pid::from_raw(self.pid() as i32), Signal::SIGTERM);
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if let Ok(Some(status)) = self.child.try_wait() {
+                return Some(status);
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        None
+    }
 }
 
 impl Drop for VuinputdGuard {
     fn drop(&mut self) {
+        if let Ok(Some(_)) = self.child.try_wait() {
+            return;
+        }
         let pid = Pid::from_raw(self.child.id() as i32);
 
         // First: SIGTERM
@@ -83,3 +148,40 @@ impl Drop for VuinputdGuard {
         println!("vuinputd for tests killed");
     }
 }
+
+/// The metrics of a vuinputd started with --metrics-listen `address`
+pub fn scrape_metrics(address: &str) -> io::Result<String> {
+    let mut stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    write!(stream, "GET /metrics HTTP/1.0\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+/// The value of the metric `name` (without labels) in the text format of Prometheus
+pub fn metric(metrics: &str, name: &str) -> Option<f64> {
+    metrics.lines().find_map(|line| {
+        let (metric, value) = line.split_once(' ')?;
+        (metric == name).then(|| value.trim().parse().ok())?
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_a_metric() {
+        let metrics = "HTTP/1.0 200 OK\r\n\r\n\
+            # HELP vuinputd_udev_event_store_entries Entries of the udev event store\n\
+            # TYPE vuinputd_udev_event_store_entries gauge\n\
+            vuinputd_udev_event_store_entries 12\n\
+            vuinputd_devices{container=\"games\"} 2\n";
+        assert_eq!(
+            metric(metrics, "vuinputd_udev_event_store_entries"),
+            Some(12.0)
+        );
+        assert_eq!(metric(metrics, "vuinputd_devices"), None);
+    }
+}
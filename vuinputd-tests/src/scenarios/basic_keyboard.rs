@@ -31,8 +31,9 @@ impl BasicKeyboard {
         let eventlog = TestLog {
             events: keyboard.event_log().to_vec(),
         };
-        let serialized = serde_json::to_string(&eventlog).unwrap();
+        let serialized = serde_json::to_string(&eventlog.scrubbed()).unwrap();
         println!("Event log: {}", serialized);
+        args.save_evemu("basic-keyboard", &keyboard, &eventlog)?;
 
         KeyboardDevice::destroy(keyboard);
         Ok(())
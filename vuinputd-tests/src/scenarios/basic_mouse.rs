@@ -31,8 +31,9 @@ impl BasicMouse {
         let eventlog = TestLog {
             events: mouse.event_log().to_vec(),
         };
-        let serialized = serde_json::to_string(&eventlog).unwrap();
+        let serialized = serde_json::to_string(&eventlog.scrubbed()).unwrap();
         println!("Event log: {}", serialized);
+        args.save_evemu("basic-mouse", &mouse, &eventlog)?;
 
         MouseDevice::destroy(mouse);
         Ok(())
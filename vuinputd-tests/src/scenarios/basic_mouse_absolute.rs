@@ -30,8 +30,9 @@ impl BasicMouseAbsolute {
         let eventlog = TestLog {
             events: mouse.event_log().to_vec(),
         };
-        let serialized = serde_json::to_string(&eventlog).unwrap();
+        let serialized = serde_json::to_string(&eventlog.scrubbed()).unwrap();
         println!("Event log: {}", serialized);
+        args.save_evemu("basic-mouse-absolute", &mouse, &eventlog)?;
 
         MouseAbsoluteDevice::destroy(mouse);
         Ok(())
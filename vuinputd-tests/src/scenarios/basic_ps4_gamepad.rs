@@ -31,8 +31,9 @@ impl BasicPs4Gamepad {
         let eventlog = TestLog {
             events: gamepad.event_log().to_vec(),
         };
-        let serialized = serde_json::to_string(&eventlog).unwrap();
+        let serialized = serde_json::to_string(&eventlog.scrubbed()).unwrap();
         println!("Event log: {}", serialized);
+        args.save_evemu("basic-ps4-gamepad", &gamepad, &eventlog)?;
 
         Ps4GamepadDevice::destroy(gamepad);
         Ok(())
@@ -0,0 +1,52 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+use std::thread;
+use std::time::Duration;
+
+use crate::devices::touchpad::{
+    TouchpadDevice, ABS_MT_POSITION_X, ABS_MT_TRACKING_ID, BTN_LEFT, BTN_TOOL_FINGER, BTN_TOUCH,
+};
+use crate::devices::{Device, EV_ABS, EV_KEY};
+use crate::scenarios::ScenarioArgs;
+use crate::test_log::TestLog;
+
+const ABS_X: u16 = 0x00;
+
+pub struct BasicTouchpad;
+
+impl BasicTouchpad {
+    pub fn run(args: &ScenarioArgs) -> Result<(), std::io::Error> {
+        let device = args
+            .dev_path
+            .clone()
+            .unwrap_or_else(|| "/dev/uinput".to_string());
+
+        let mut touchpad = TouchpadDevice::create(Some(&device), "Example Touchpad")?;
+        eprintln!("sysname: {}", touchpad.sysname());
+
+        thread::sleep(Duration::from_secs(1));
+
+        // one finger touches down, clicks the pad and is lifted again
+        touchpad.emit_read_and_log(EV_ABS, ABS_MT_TRACKING_ID, 1)?;
+        touchpad.emit_read_and_log(EV_ABS, ABS_MT_POSITION_X, 500)?;
+        touchpad.emit_read_and_log(EV_ABS, ABS_X, 500)?;
+        touchpad.emit_read_and_log(EV_KEY, BTN_TOUCH, 1)?;
+        touchpad.emit_read_and_log(EV_KEY, BTN_TOOL_FINGER, 1)?;
+        touchpad.emit_read_and_log(EV_KEY, BTN_LEFT, 1)?;
+        touchpad.emit_read_and_log(EV_KEY, BTN_LEFT, 0)?;
+        touchpad.emit_read_and_log(EV_KEY, BTN_TOOL_FINGER, 0)?;
+        touchpad.emit_read_and_log(EV_KEY, BTN_TOUCH, 0)?;
+        touchpad.emit_read_and_log(EV_ABS, ABS_MT_TRACKING_ID, -1)?;
+
+        let eventlog = TestLog {
+            events: touchpad.event_log().to_vec(),
+        };
+        let serialized = serde_json::to_string(&eventlog.scrubbed()).unwrap();
+        println!("Event log: {}", serialized);
+        args.save_evemu("basic-touchpad", &touchpad, &eventlog)?;
+
+        TouchpadDevice::destroy(touchpad);
+        Ok(())
+    }
+}
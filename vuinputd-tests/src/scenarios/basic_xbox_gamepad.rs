@@ -32,8 +32,9 @@ impl BasicXboxGamepad {
         let eventlog = TestLog {
             events: gamepad.event_log().to_vec(),
         };
-        let serialized = serde_json::to_string(&eventlog).unwrap();
+        let serialized = serde_json::to_string(&eventlog.scrubbed()).unwrap();
         println!("Event log: {}", serialized);
+        args.save_evemu("basic-xbox-gamepad", &gamepad, &eventlog)?;
 
         XboxGamepadDevice::destroy(gamepad);
         Ok(())
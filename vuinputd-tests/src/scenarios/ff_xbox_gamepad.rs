@@ -67,7 +67,7 @@ impl FfXboxGamepad {
         let eventlog = TestLog {
             events: gamepad.event_log().to_vec(),
         };
-        let serialized = serde_json::to_string(&eventlog).unwrap();
+        let serialized = serde_json::to_string(&eventlog.scrubbed()).unwrap();
         println!("Event log: {}", serialized);
 
         XboxGamepadDevice::destroy(gamepad);
@@ -2,10 +2,18 @@
 //
 // Author: Johannes Leupolz <dev@leupolz.eu>
 
+use std::io;
+use std::path::PathBuf;
+
+use crate::devices::Device;
+use crate::evemu;
+use crate::test_log::TestLog;
+
 pub mod basic_keyboard;
 pub mod basic_mouse;
 pub mod basic_mouse_absolute;
 pub mod basic_ps4_gamepad;
+pub mod basic_touchpad;
 pub mod basic_xbox_gamepad;
 pub mod ff_xbox_gamepad;
 /*
@@ -20,6 +28,7 @@ pub use basic_keyboard::BasicKeyboard;
 pub use basic_mouse::BasicMouse;
 pub use basic_mouse_absolute::BasicMouseAbsolute;
 pub use basic_ps4_gamepad::BasicPs4Gamepad;
+pub use basic_touchpad::BasicTouchpad;
 pub use basic_xbox_gamepad::BasicXboxGamepad;
 pub use ff_xbox_gamepad::FfXboxGamepad;
 /*
@@ -34,4 +43,19 @@ pub use stress_xbox_gamepad::StressXboxGamepad;
 pub struct ScenarioArgs {
     pub ipc: bool,
     pub dev_path: Option<String>,
+    /// Directory for evemu recordings of the created devices
+    pub evemu_dir: Option<PathBuf>,
+}
+
+impl ScenarioArgs {
+    /// Saves the device and the event log as <name>.desc and <name>.events, if --evemu-dir
+    /// is given. Must be called before the device is destroyed.
+    pub fn save_evemu(&self, name: &str, device: &impl Device, log: &TestLog) -> io::Result<()> {
+        let Some(dir) = &self.evemu_dir else {
+            return Ok(());
+        };
+        let (desc, events) = evemu::save(dir, name, &device.evemu_description()?, log)?;
+        eprintln!("evemu recording: {} {}", desc.display(), events.display());
+        Ok(())
+    }
 }
@@ -4,7 +4,7 @@
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct LoggedInputEvent {
     pub tv_sec: i64,
 
@@ -25,3 +25,32 @@ pub struct LoggedInputEvent {
 pub struct TestLog {
     pub events: Vec<LoggedInputEvent>,
 }
+
+const EV_KEY: u16 = 0x01;
+
+// Buttons of mice, joysticks, gamepads and digitizers; everything else with EV_KEY is a keyboard key
+fn is_keyboard_key(code: u16) -> bool {
+    !matches!(code, 0x100..=0x151 | 0x220..=0x2e7)
+}
+
+impl LoggedInputEvent {
+    /// Returns a copy with code and value of keyboard keys zeroed (same rule as the
+    /// keystroke privacy of vuinputd).
+    pub fn scrubbed(&self) -> LoggedInputEvent {
+        let mut event = self.clone();
+        if event.type_ == EV_KEY && is_keyboard_key(event.code) {
+            event.code = 0;
+            event.value = 0;
+        }
+        event
+    }
+}
+
+impl TestLog {
+    /// Returns a copy that is safe to print, i.e. without keyboard keys.
+    pub fn scrubbed(&self) -> TestLog {
+        TestLog {
+            events: self.events.iter().map(|e| e.scrubbed()).collect(),
+        }
+    }
+}
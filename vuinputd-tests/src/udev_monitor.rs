@@ -0,0 +1,87 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// The udev monitor of a container, like udevadm monitor --udev: the events vuinputd sends into
+// the container for its devices, in the format of libudev (a header, then the properties
+// separated by NUL).
+
+use std::collections::HashMap;
+use std::os::fd::{AsRawFd, OwnedFd};
+
+use nix::sys::socket::{
+    bind, recv, socket, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol, SockType,
+};
+
+/// Multicast group of the events udev sends after processing them, as udevadm monitor --udev
+const UDEV_MONITOR_GROUP: u32 = 2;
+
+/// Room for a few hundred events, for tests that create devices faster than they read
+const RECEIVE_BUFFER: libc::c_int = 1024 * 1024;
+
+fn set_option<T>(fd: &OwnedFd, option: libc::c_int, value: &T) {
+    unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            value as *const T as *const libc::c_void,
+            std::mem::size_of::<T>() as libc::socklen_t,
+        );
+    }
+}
+
+/// Listens to the udev monitor, a recv waits at most a second
+pub fn open() -> nix::Result<OwnedFd> {
+    let fd = socket(
+        AddressFamily::Netlink,
+        SockType::Raw,
+        SockFlag::SOCK_CLOEXEC,
+        SockProtocol::NetlinkKObjectUEvent,
+    )?;
+    bind(fd.as_raw_fd(), &NetlinkAddr::new(0, UDEV_MONITOR_GROUP))?;
+    let timeout = libc::timeval {
+        tv_sec: 1,
+        tv_usec: 0,
+    };
+    set_option(&fd, libc::SO_RCVTIMEO, &timeout);
+    set_option(&fd, libc::SO_RCVBUF, &RECEIVE_BUFFER);
+    Ok(fd)
+}
+
+/// The properties of a packet of the monitor, e.g. ACTION, DEVNAME and SEQNUM
+pub fn parse_properties(packet: &[u8]) -> HashMap<String, String> {
+    packet
+        .split(|byte| *byte == 0)
+        .filter_map(|property| {
+            let property = String::from_utf8_lossy(property);
+            let (key, value) = property.split_once('=')?;
+            Some((key.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// The properties of the next event, None if nothing came within a second
+pub fn recv_event(monitor: &OwnedFd) -> Option<HashMap<String, String>> {
+    let mut buf = vec![0u8; 8192];
+    let n = recv(monitor.as_raw_fd(), &mut buf, MsgFlags::empty()).ok()?;
+    Some(parse_properties(&buf[..n]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_properties_of_a_packet() {
+        // the header of libudev ("libudev\0", magic, sizes and offsets) yields no property,
+        // unless one of its bytes happens to be a '='
+        let mut packet = b"libudev\0\xfe\xed\xca\xfe\x28\0\0\0".to_vec();
+        packet.extend(b"ACTION=remove\0DEVNAME=/dev/input/event7\0SEQNUM=4711\0");
+        let properties = parse_properties(&packet);
+        assert_eq!(properties["ACTION"], "remove");
+        assert_eq!(properties["DEVNAME"], "/dev/input/event7");
+        assert_eq!(properties["SEQNUM"], "4711");
+        assert_eq!(properties.len(), 3);
+    }
+}
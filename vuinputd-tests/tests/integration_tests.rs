@@ -4,6 +4,7 @@
 
 use std::{process::Command, time::Duration};
 use vuinputd_tests::bwrap;
+use vuinputd_tests::ipc::{DeviceKind, Message};
 use vuinputd_tests::run_vuinputd;
 
 #[cfg(all(feature = "requires-privileges", feature = "requires-bwrap"))]
@@ -38,7 +39,7 @@ fn test_bwrap_ipc() {
 
     // Note that builder.run() will block. Thus, the send needs to happen before the child process blocks
     // the host process.
-    ipc.send("continue".as_bytes())
+    ipc.send_message(&Message::Ready)
         .unwrap_or_else(|e| panic!("failed to send data via ipc: {e}"));
 
     let out = builder
@@ -46,16 +47,101 @@ fn test_bwrap_ipc() {
         .run()
         .unwrap_or_else(|e| panic!("failed to run bwrap!: {e}"));
 
-    let result = ipc.recv(Some(Duration::from_secs(5)));
+    let result = ipc.recv_message(Some(Duration::from_secs(5)));
 
     println!("Output");
     println!("stdout: {}", str::from_utf8(&out.stdout).unwrap());
     println!("stderr: {}", str::from_utf8(&out.stderr).unwrap());
 
     let result = result.expect("error receiving input from ipc as host within 5 seconds");
-    let result_str =
-        str::from_utf8(&result).expect("message received from ipc is not encoded as utf8");
-    println!("host received {}", result_str);
+    println!("host received {:?}", result);
+    assert_eq!(result, Message::Ready);
+}
+
+// Writes and UI_DEV_DESTROY of one handle, served by several CUSE threads at once: a write either
+// reaches the keyboard or fails with EINVAL, none succeeds after the destroy has returned (checked
+// by test-write-destroy), and vuinputd keeps running.
+#[cfg(all(
+    feature = "requires-privileges",
+    feature = "requires-uinput",
+    feature = "requires-bwrap"
+))]
+#[test]
+fn test_writes_racing_a_destroy() {
+    let mut guard = run_vuinputd::ensure_vuinputd_running(&["--cuse-threads", "8"]);
+    let test_write_destroy = env!("CARGO_BIN_EXE_test-write-destroy");
+    for _ in 0..10 {
+        let out = bwrap::BwrapBuilder::new()
+            .unshare_net()
+            .ro_bind("/", "/")
+            .tmpfs("/tmp")
+            // dev needs to be writable for the new devices
+            .dev()
+            .tmpfs("/run")
+            .dev_bind("/dev/vuinput-test", "/dev/uinput")
+            .die_with_parent()
+            .command(test_write_destroy, &[])
+            .run()
+            .unwrap_or_else(|e| panic!("failed to run bwrap!: {e}"));
+        println!("stdout: {}", str::from_utf8(&out.stdout).unwrap());
+        println!("stderr: {}", str::from_utf8(&out.stderr).unwrap());
+        assert!(out.status.success());
+    }
+    assert!(guard.is_running(), "vuinputd did not survive the races");
+}
+
+#[cfg(all(
+    feature = "requires-privileges",
+    feature = "requires-uinput",
+    feature = "requires-bwrap"
+))]
+#[test]
+fn test_ipc_create_verify_emit() {
+    use std::path::Path;
+
+    let _guard = run_vuinputd::ensure_vuinputd_running(&[]);
+    let test_ipc = env!("CARGO_BIN_EXE_test-ipc");
+
+    let (builder, ipc) = bwrap::BwrapBuilder::new()
+        .unshare_net()
+        .ro_bind("/", "/")
+        .tmpfs("/tmp")
+        // dev needs to be writable for the new devices
+        .dev()
+        .tmpfs("/run")
+        .dev_bind("/dev/vuinput-test", "/dev/uinput")
+        .die_with_parent()
+        .with_ipc()
+        .expect("failed to create IPC");
+    let mut container = builder
+        .command(test_ipc, &[])
+        .spawn()
+        .unwrap_or_else(|e| panic!("failed to run bwrap!: {e}"));
+
+    let timeout = Some(Duration::from_secs(10));
+    ipc.send_message(&Message::CreateDevice {
+        kind: DeviceKind::Mouse,
+        name: "IPC Mouse".to_string(),
+    })
+    .unwrap();
+    let Message::DeviceCreated { syspath, .. } = ipc.recv_message(timeout).unwrap() else {
+        panic!("the container did not create the mouse");
+    };
+    // the device exists on the host before the container emits anything
+    let name = std::fs::read_to_string(Path::new(&syspath).join("name")).unwrap();
+    assert_eq!(name.trim(), "IPC Mouse");
+
+    ipc.send_message(&Message::Ready).unwrap();
+    let Message::EventLog { events } = ipc.recv_message(timeout).unwrap() else {
+        panic!("the container did not send its events");
+    };
+    assert_eq!(events.len(), 3);
+    assert!(events.iter().all(|event| event.send_and_receive_match));
+
+    ipc.send_message(&Message::Ready).unwrap();
+    assert_eq!(ipc.recv_message(timeout).unwrap(), Message::Ready);
+    assert!(container.wait().unwrap().success());
+    assert!(!Path::new(&syspath).exists(), "{} is still there", syspath);
 }
 
 #[cfg(all(feature = "requires-privileges", feature = "requires-bwrap"))]
@@ -244,3 +330,463 @@ fn test_mouse_absolute_in_container() {
 
     assert!(out.status.success());
 }
+
+#[cfg(all(
+    feature = "requires-privileges",
+    feature = "requires-uinput",
+    feature = "requires-bwrap"
+))]
+#[test]
+fn test_mouse_absolute_with_strict_tablet() {
+    let _guard: run_vuinputd::VuinputdGuard =
+        run_vuinputd::ensure_vuinputd_running(&["--device-policy", "strict-tablet"]);
+
+    let test_scenarios = env!("CARGO_BIN_EXE_test-scenarios");
+
+    let out = bwrap::BwrapBuilder::new()
+        .unshare_net()
+        .ro_bind("/", "/")
+        .tmpfs("/tmp")
+        // dev needs to be writable for the new devices
+        .dev()
+        // run needs to be writable for the udev devices
+        .tmpfs("/run")
+        .dev_bind("/dev/vuinput-test", "/dev/uinput")
+        .die_with_parent()
+        .command(test_scenarios, &["basic-mouse-absolute"])
+        .run()
+        .unwrap_or_else(|e| panic!("failed to run bwrap!: {e}"));
+
+    println!("Output");
+    println!("stdout: {}", str::from_utf8(&out.stdout).unwrap());
+    println!("stderr: {}", str::from_utf8(&out.stderr).unwrap());
+
+    assert!(out.status.success());
+}
+
+#[cfg(all(
+    feature = "requires-privileges",
+    feature = "requires-uinput",
+    feature = "requires-bwrap"
+))]
+#[test]
+fn test_touchpad_in_container() {
+    let _guard: run_vuinputd::VuinputdGuard =
+        run_vuinputd::ensure_vuinputd_running(&["--device-policy", "strict-touchpad"]);
+
+    let test_scenarios = env!("CARGO_BIN_EXE_test-scenarios");
+
+    let out = bwrap::BwrapBuilder::new()
+        .unshare_net()
+        .ro_bind("/", "/")
+        .tmpfs("/tmp")
+        // dev needs to be writable for the new devices
+        .dev()
+        // run needs to be writable for the udev devices
+        .tmpfs("/run")
+        .dev_bind("/dev/vuinput-test", "/dev/uinput")
+        .die_with_parent()
+        .command(test_scenarios, &["basic-touchpad"])
+        .run()
+        .unwrap_or_else(|e| panic!("failed to run bwrap!: {e}"));
+
+    println!("Output");
+    println!("stdout: {}", str::from_utf8(&out.stdout).unwrap());
+    println!("stderr: {}", str::from_utf8(&out.stderr).unwrap());
+
+    assert!(out.status.success());
+}
+
+#[cfg(all(
+    feature = "requires-privileges",
+    feature = "requires-uinput",
+    feature = "requires-bwrap"
+))]
+#[test]
+fn test_shutdown_cleans_up_containers() {
+    use std::path::Path;
+
+    // helpers that vuinputd leaves behind become children of the test when it exits
+    unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1) };
+    let mut guard = run_vuinputd::ensure_vuinputd_running(&[]);
+    let test_shutdown = env!("CARGO_BIN_EXE_test-shutdown");
+
+    let mut containers = Vec::new();
+    for index in 0..2 {
+        // /run of the container, to look at its udev database from the host
+        let run = std::env::temp_dir().join(format!(
+            "vuinputd-shutdown-{}-{}",
+            std::process::id(),
+            index
+        ));
+        std::fs::create_dir_all(&run).unwrap();
+        let (builder, ipc) = bwrap::BwrapBuilder::new()
+            .unshare_net()
+            .ro_bind("/", "/")
+            .tmpfs("/tmp")
+            // dev needs to be writable for the new devices
+            .dev()
+            .bind(run.to_str().unwrap(), "/run")
+            .dev_bind("/dev/vuinput-test", "/dev/uinput")
+            .die_with_parent()
+            .with_ipc()
+            .expect("failed to create IPC");
+        let container = builder
+            .command(test_shutdown, &[])
+            .spawn()
+            .unwrap_or_else(|e| panic!("failed to run bwrap!: {e}"));
+        containers.push((container, ipc, run));
+    }
+
+    let mut devices = Vec::new();
+    for (_, ipc, run) in &containers {
+        let created = ipc
+            .recv_message(Some(Duration::from_secs(10)))
+            .expect("the container did not create its keyboard within 10 seconds");
+        println!("container: {:?}", created);
+        let Message::DeviceCreated {
+            syspath,
+            devnode,
+            major,
+            minor,
+        } = created
+        else {
+            panic!("unexpected message {:?}", created);
+        };
+        let node = Path::new("/run/vuinputd/vuinput-test/dev-input")
+            .join(Path::new(&devnode).file_name().unwrap());
+        let runtime_data = run.join(format!("udev/data/c{}:{}", major, minor));
+        // the udev data is written by a job after UI_DEV_CREATE has been answered
+        for _ in 0..50 {
+            if runtime_data.exists() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        assert!(Path::new(&syspath).exists(), "{} is missing", syspath);
+        assert!(node.exists(), "{} is missing", node.display());
+        assert!(
+            runtime_data.exists(),
+            "{} is missing",
+            runtime_data.display()
+        );
+        devices.push((syspath, node, runtime_data));
+    }
+
+    let status = guard
+        .terminate(Duration::from_secs(15))
+        .expect("vuinputd did not stop within 15 seconds");
+    assert!(status.success(), "vuinputd exited with {}", status);
+
+    for (container, ipc, run) in &mut containers {
+        let removed = ipc
+            .recv_message(Some(Duration::from_secs(35)))
+            .expect("the container did not answer");
+        assert!(
+            matches!(removed, Message::DeviceRemoved { .. }),
+            "unexpected message {:?}",
+            removed
+        );
+        assert!(container.wait().unwrap().success());
+        let _ = std::fs::remove_dir_all(run);
+    }
+    for (syspath, node, runtime_data) in devices {
+        assert!(!Path::new(&syspath).exists(), "{} is still there", syspath);
+        assert!(!node.exists(), "{} is still there", node.display());
+        assert!(
+            !runtime_data.exists(),
+            "{} is still there",
+            runtime_data.display()
+        );
+    }
+
+    let helpers = orphaned_helpers();
+    unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 0) };
+    assert!(helpers.is_empty(), "helpers left behind: {:?}", helpers);
+}
+
+/// Processes named vuinputd whose parent is this process: helpers of a vuinputd that exited
+/// are handed to it as it is a subreaper, alive or as zombies
+#[cfg(all(
+    feature = "requires-privileges",
+    feature = "requires-uinput",
+    feature = "requires-bwrap"
+))]
+fn orphaned_helpers() -> Vec<String> {
+    let me = std::process::id().to_string();
+    std::fs::read_dir("/proc")
+        .unwrap()
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let status = std::fs::read_to_string(entry.path().join("status")).ok()?;
+            let field = |name: &str| {
+                status
+                    .lines()
+                    .find_map(|line| line.strip_prefix(name))
+                    .map(str::trim)
+            };
+            (field("PPid:")? == me && field("Name:")? == "vuinputd").then(|| {
+                format!(
+                    "{} ({})",
+                    entry.file_name().to_string_lossy(),
+                    field("State:").unwrap_or("?")
+                )
+            })
+        })
+        .collect()
+}
+
+/// Runs test-faults in a container of a vuinputd with `faults` injected and returns its lines.
+/// Checks that vuinputd survived and, once the container is gone, that it left no device behind.
+#[cfg(all(
+    feature = "requires-privileges",
+    feature = "requires-uinput",
+    feature = "requires-bwrap"
+))]
+fn run_with_faults(faults: &str, args: &[&str], attempts: u32) -> Vec<String> {
+    let mut guard = run_vuinputd::ensure_vuinputd_running_with_faults(args, faults);
+    let test_faults = env!("CARGO_BIN_EXE_test-faults");
+    let attempts = attempts.to_string();
+
+    let out = bwrap::BwrapBuilder::new()
+        .unshare_net()
+        .ro_bind("/", "/")
+        .tmpfs("/tmp")
+        // dev needs to be writable for the new devices
+        .dev()
+        // run needs to be writable for the udev devices
+        .tmpfs("/run")
+        .dev_bind("/dev/vuinput-test", "/dev/uinput")
+        .die_with_parent()
+        .command(test_faults, &["--attempts", &attempts])
+        .run()
+        .unwrap_or_else(|e| panic!("failed to run bwrap!: {e}"));
+
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    println!("stdout: {}", stdout);
+    println!("stderr: {}", str::from_utf8(&out.stderr).unwrap());
+    assert!(out.status.success());
+    assert!(guard.is_running(), "vuinputd did not survive {}", faults);
+
+    let mut left = host_devices_named("Fault Keyboard");
+    for _ in 0..30 {
+        if left.is_empty() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+        left = host_devices_named("Fault Keyboard");
+    }
+    assert!(left.is_empty(), "devices left behind: {:?}", left);
+
+    let status = guard
+        .terminate(Duration::from_secs(15))
+        .expect("vuinputd did not stop within 15 seconds");
+    assert!(status.success(), "vuinputd exited with {}", status);
+    stdout.lines().map(str::to_string).collect()
+}
+
+/// The input devices named `name` on the host, e.g. those of test-faults
+#[cfg(all(
+    feature = "requires-privileges",
+    feature = "requires-uinput",
+    feature = "requires-bwrap"
+))]
+fn host_devices_named(name: &str) -> Vec<String> {
+    std::fs::read_dir("/sys/devices/virtual/input")
+        .unwrap()
+        .flatten()
+        .filter(|entry| {
+            std::fs::read_to_string(entry.path().join("name"))
+                .is_ok_and(|device| device.trim() == name)
+        })
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect()
+}
+
+#[cfg(all(
+    feature = "requires-privileges",
+    feature = "requires-uinput",
+    feature = "requires-bwrap"
+))]
+#[test]
+fn test_faults_of_host_ioctls_reach_the_client() {
+    let lines = run_with_faults("UI_SET_KEYBIT=EINVAL:1,UI_DEV_CREATE=EIO:1", &[], 3);
+    assert_eq!(lines[0], format!("failed UI_SET_KEYBIT {}", libc::EINVAL));
+    assert_eq!(lines[1], format!("failed UI_DEV_CREATE {}", libc::EIO));
+    // the handle after the faults works as usual
+    assert!(lines[2].starts_with("created ") && lines[2].ends_with(" node"));
+}
+
+#[cfg(all(
+    feature = "requires-privileges",
+    feature = "requires-uinput",
+    feature = "requires-bwrap"
+))]
+#[test]
+fn test_transient_faults_of_ui_dev_create_are_retried() {
+    let config = std::env::temp_dir().join(format!("vuinputd-faults-{}.toml", std::process::id()));
+    std::fs::write(&config, "[limits]\ncreate-retries = 3\n").unwrap();
+    let lines = run_with_faults(
+        "UI_DEV_CREATE=EAGAIN:2",
+        &["--config", config.to_str().unwrap()],
+        1,
+    );
+    let _ = std::fs::remove_file(&config);
+    assert!(lines[0].starts_with("created ") && lines[0].ends_with(" node"));
+}
+
+#[cfg(all(
+    feature = "requires-privileges",
+    feature = "requires-uinput",
+    feature = "requires-bwrap"
+))]
+#[test]
+fn test_failed_mknod_fails_ui_dev_create() {
+    // UI_DEV_CREATE is answered by the mknod job, the device on the host is destroyed again
+    // (checked by run_with_faults)
+    let lines = run_with_faults("mknod=EACCES", &[], 2);
+    assert!(lines
+        .iter()
+        .all(|line| *line == format!("failed UI_DEV_CREATE {}", libc::EIO)));
+}
+
+#[cfg(all(
+    feature = "requires-privileges",
+    feature = "requires-uinput",
+    feature = "requires-bwrap"
+))]
+#[test]
+fn test_failed_netlink_keeps_the_node() {
+    let lines = run_with_faults("netlink=ENOBUFS", &[], 2);
+    assert!(lines
+        .iter()
+        .all(|line| line.starts_with("created ") && line.ends_with(" node")));
+}
+
+/// Runs test-hotswap in a container, which creates and destroys a gamepad `cycles` times
+#[cfg(all(
+    feature = "requires-privileges",
+    feature = "requires-uinput",
+    feature = "requires-bwrap"
+))]
+fn run_hotswap(cycles: u32) {
+    let test_hotswap = env!("CARGO_BIN_EXE_test-hotswap");
+    let cycles = cycles.to_string();
+    let out = bwrap::BwrapBuilder::new()
+        .unshare_net()
+        .ro_bind("/", "/")
+        .tmpfs("/tmp")
+        // dev needs to be writable for the new devices
+        .dev()
+        // run needs to be writable for the udev devices
+        .tmpfs("/run")
+        .dev_bind("/dev/vuinput-test", "/dev/uinput")
+        .die_with_parent()
+        .command(test_hotswap, &["--cycles", &cycles])
+        .run()
+        .unwrap_or_else(|e| panic!("failed to run bwrap!: {e}"));
+    println!("stdout: {}", str::from_utf8(&out.stdout).unwrap());
+    println!("stderr: {}", str::from_utf8(&out.stderr).unwrap());
+    assert!(out.status.success());
+}
+
+// Streaming clients reconnect often, each time with a new gamepad. 100 cycles of create and
+// destroy as fast as the container can must leave neither devices on the host nor nodes in the
+// container (checked by test-hotswap, with the order of the udev events), and must not let
+// vuinputd grow.
+#[cfg(all(
+    feature = "requires-privileges",
+    feature = "requires-uinput",
+    feature = "requires-bwrap"
+))]
+#[test]
+fn test_gamepad_hotswap() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    const CYCLES: u32 = 100;
+    const METRICS: &str = "127.0.0.1:9813";
+    const STORE_ENTRIES: &str = "vuinputd_udev_event_store_entries";
+    let mut guard = run_vuinputd::ensure_vuinputd_running(&["--metrics-listen", METRICS]);
+    let store_entries = || {
+        run_vuinputd::scrape_metrics(METRICS)
+            .ok()
+            .and_then(|metrics| run_vuinputd::metric(&metrics, STORE_ENTRIES))
+            .expect("no size of the udev event store in the metrics")
+    };
+
+    // the first devices allocate what vuinputd keeps anyway, e.g. the queue of a container
+    run_hotswap(5);
+    let fds_before = guard.open_fds().unwrap();
+    let rss_before = guard.rss_kib().unwrap();
+    let store_before = store_entries();
+
+    let done = Arc::new(AtomicBool::new(false));
+    let sampler = {
+        let done = done.clone();
+        std::thread::spawn(move || {
+            let mut samples = Vec::new();
+            while !done.load(Ordering::Relaxed) {
+                samples.push(store_entries());
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            samples
+        })
+    };
+    run_hotswap(CYCLES);
+    done.store(true, Ordering::Relaxed);
+    let samples = sampler.join().unwrap();
+
+    let mut left = host_devices_named("Hotswap Gamepad");
+    for _ in 0..30 {
+        if left.is_empty() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+        left = host_devices_named("Hotswap Gamepad");
+    }
+    assert!(left.is_empty(), "devices left behind: {:?}", left);
+
+    // an entry per syspath (the input device and its event node) until the TTL of the store,
+    // the remove event reuses the entry of the add event
+    let bound = store_before + f64::from(2 * CYCLES);
+    println!(
+        "udev event store: {} before, samples {:?}",
+        store_before, samples
+    );
+    assert!(
+        samples.iter().all(|entries| *entries <= bound),
+        "the udev event store grew beyond {} entries: {:?}",
+        bound,
+        samples
+    );
+    assert!(
+        store_entries() <= bound,
+        "the udev event store grew beyond {} entries",
+        bound
+    );
+
+    let fds_after = guard.open_fds().unwrap();
+    let rss_after = guard.rss_kib().unwrap();
+    println!(
+        "vuinputd: {} -> {} fds, {} -> {} KiB",
+        fds_before, fds_after, rss_before, rss_after
+    );
+    assert!(
+        fds_after <= fds_before + 2,
+        "vuinputd leaks file descriptors: {} before, {} after",
+        fds_before,
+        fds_after
+    );
+    assert!(
+        rss_after <= rss_before + 16 * 1024,
+        "vuinputd grew from {} KiB to {} KiB",
+        rss_before,
+        rss_after
+    );
+
+    let status = guard
+        .terminate(Duration::from_secs(15))
+        .expect("vuinputd did not stop within 15 seconds");
+    assert!(status.success(), "vuinputd exited with {}", status);
+}
@@ -0,0 +1,47 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+#![cfg(all(
+    feature = "requires-privileges",
+    feature = "requires-uinput",
+    feature = "requires-nspawn"
+))]
+
+use vuinputd_tests::nspawn;
+use vuinputd_tests::run_vuinputd;
+
+/// The file system of the podman image, exported as described in docs/TESTS.md
+const MACHINE_DIRECTORY: &str = "/var/lib/machines/vuinputd-tests";
+
+fn test_libinput_in_nspawn(private_users: &str) {
+    let _guard = run_vuinputd::ensure_vuinputd_running(&[]);
+
+    for kind in ["keyboard", "mouse"] {
+        let out = nspawn::NspawnBuilder::new()
+            .directory(MACHINE_DIRECTORY)
+            .private_users(private_users)
+            .bind("/dev/vuinput-test:/dev/uinput")
+            .allow_input_devices()
+            .command(&["/test-libinput", "--kind", kind, "--seat", "seat0"])
+            .run()
+            .unwrap_or_else(|e| panic!("failed to run systemd-nspawn!: {e}"));
+
+        println!("Output");
+        println!("stdout: {}", str::from_utf8(&out.stdout).unwrap());
+        println!("stderr: {}", str::from_utf8(&out.stderr).unwrap());
+
+        assert!(out.status.success(), "libinput did not accept the {kind}");
+    }
+}
+
+#[test]
+fn test_libinput_accepts_devices_in_nspawn() {
+    test_libinput_in_nspawn("no");
+}
+
+// The nodes have to belong to root of the container, or libinput can't open them
+#[test]
+fn test_libinput_accepts_devices_in_nspawn_with_private_users() {
+    test_libinput_in_nspawn("pick");
+}
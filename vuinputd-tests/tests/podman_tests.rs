@@ -3,6 +3,7 @@
 // Author: Johannes Leupolz <dev@leupolz.eu>
 
 use std::time::Duration;
+use vuinputd_tests::ipc::Message;
 use vuinputd_tests::podman;
 use vuinputd_tests::run_vuinputd;
 
@@ -40,23 +41,22 @@ fn test_podman_ipc() {
 
     // Note that builder.run() will block. Thus, the send needs to happen before the child process blocks
     // the host process.
-    ipc.send("continue".as_bytes())
+    ipc.send_message(&Message::Ready)
         .unwrap_or_else(|e| panic!("failed to send data via ipc: {e}"));
 
     let out = builder
         .run()
         .unwrap_or_else(|e| panic!("failed to run podman!: {e}"));
 
-    let result = ipc.recv(Some(Duration::from_secs(5)));
+    let result = ipc.recv_message(Some(Duration::from_secs(5)));
 
     println!("Output");
     println!("stdout: {}", str::from_utf8(&out.stdout).unwrap());
     println!("stderr: {}", str::from_utf8(&out.stderr).unwrap());
 
     let result = result.expect("error receiving input from ipc as host within 5 seconds");
-    let result_str =
-        str::from_utf8(&result).expect("message received from ipc is not encoded as utf8");
-    println!("host received {}", result_str);
+    println!("host received {:?}", result);
+    assert_eq!(result, Message::Ready);
 }
 
 #[cfg(all(
@@ -92,6 +92,78 @@ fn test_keyboard_in_container_with_vuinput() {
     assert!(out.status.success());
 }
 
+// vuinputd itself runs in a privileged container and serves a sibling container. The /proc,
+// /sys and /run/udev of the host are mounted below /host, the PID namespace is the one of the
+// host, so that the requests of the sibling container can be identified.
+#[cfg(all(
+    feature = "requires-privileges",
+    feature = "requires-uinput",
+    feature = "requires-podman"
+))]
+#[test]
+fn test_keyboard_in_container_with_containerized_vuinput() {
+    let name = "vuinputd-podman-tests-daemon";
+    let out = podman::PodmanBuilder::new()
+        .run_cmd()
+        .rm()
+        .detach()
+        .name(name)
+        .privileged()
+        .pid("host")
+        .network("host")
+        .volume("/dev:/dev")
+        .volume("/proc:/host/proc")
+        .volume("/sys:/host/sys:ro")
+        .volume("/run/udev:/host/run/udev:ro")
+        .volume("/run/vuinputd:/run/vuinputd:rshared")
+        .image("localhost/vuinputd-tests:latest")
+        .command(&[
+            "/vuinputd",
+            "--major",
+            "120",
+            "--minor",
+            "414796",
+            "--devname",
+            "vuinput-test",
+            "--host-root",
+            "/host",
+        ])
+        .run()
+        .unwrap_or_else(|e| panic!("failed to run podman!: {e}"));
+    assert!(
+        out.status.success(),
+        "failed to start the vuinputd container"
+    );
+    let daemon = podman::PodmanContainerGuard::new(name);
+
+    // give it time to create /dev/vuinput-test
+    std::thread::sleep(Duration::from_millis(2000));
+
+    let (builder, _ipc) = podman::PodmanBuilder::new()
+        .run_cmd()
+        .rm()
+        .with_ipc()
+        .expect("failed to create IPC");
+    let builder = builder
+        .device("/dev/vuinput-test:/dev/uinput")
+        .allow_input_devices()
+        .image("localhost/vuinputd-tests:latest")
+        .command(&["/test-keyboard"]);
+
+    let out = builder
+        .run()
+        .unwrap_or_else(|e| panic!("failed to run podman!: {e}"));
+
+    println!("Output");
+    println!("stdout: {}", str::from_utf8(&out.stdout).unwrap());
+    println!("stderr: {}", str::from_utf8(&out.stderr).unwrap());
+    if let Ok(logs) = daemon.logs() {
+        println!("vuinputd: {}", str::from_utf8(&logs.stderr).unwrap());
+    }
+
+    assert!(out.status.success());
+}
+
 #[cfg(all(
     feature = "requires-rootless",
     feature = "requires-uinput",
@@ -125,3 +197,33 @@ fn test_keyboard_in_container_with_vuinput_rootless_with_userns() {
 
     assert!(out.status.success());
 }
+
+// libinput in the container has to enumerate the devices on the default seat of the container
+// and report their events, like a compositor in there would.
+#[cfg(all(
+    feature = "requires-privileges",
+    feature = "requires-uinput",
+    feature = "requires-podman"
+))]
+#[test]
+fn test_libinput_accepts_devices_in_container() {
+    let _guard = run_vuinputd::ensure_vuinputd_running(&[]);
+
+    for kind in ["keyboard", "mouse"] {
+        let out = podman::PodmanBuilder::new()
+            .run_cmd()
+            .rm()
+            .device("/dev/vuinput-test:/dev/uinput")
+            .allow_input_devices()
+            .image("localhost/vuinputd-tests:latest")
+            .command(&["/test-libinput", "--kind", kind, "--seat", "seat0"])
+            .run()
+            .unwrap_or_else(|e| panic!("failed to run podman!: {e}"));
+
+        println!("Output");
+        println!("stdout: {}", str::from_utf8(&out.stdout).unwrap());
+        println!("stderr: {}", str::from_utf8(&out.stderr).unwrap());
+
+        assert!(out.status.success(), "libinput did not accept the {kind}");
+    }
+}
@@ -6,6 +6,8 @@ use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 
+use crate::input_realizer::input_device::NodeOwnership;
+
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "action")]
 pub enum Action {
@@ -14,6 +16,9 @@ pub enum Action {
         path: String,
         major: u64,
         minor: u64,
+        /// Owner, group and mode of the node, see node_owner
+        #[serde(default)]
+        ownership: NodeOwnership,
     },
 
     #[serde(rename = "write-udev-runtime-data")]
@@ -28,6 +33,12 @@ pub enum Action {
         netlink_message: HashMap<String, String>,
     },
 
+    /// Has the kernel send the event, for a udevd of the container, see container_udevd
+    #[serde(rename = "emit-kernel-uevent")]
+    EmitKernelUevent {
+        netlink_message: HashMap<String, String>,
+    },
+
     #[serde(rename = "remove-device")]
     RemoveDevice {
         path: String,
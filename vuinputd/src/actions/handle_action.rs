@@ -2,7 +2,10 @@
 //
 // Author: Johannes Leupolz <dev@leupolz.eu>
 
+use anyhow::anyhow;
+
 use super::action::Action;
+use crate::input_realizer::container_udevd;
 use crate::input_realizer::input_device;
 use crate::input_realizer::netlink_message;
 use crate::input_realizer::runtime_data;
@@ -17,8 +20,13 @@ pub fn handle_cli_action(json: String) -> i32 {
 
 fn handle_action(action: Action) -> anyhow::Result<()> {
     match action {
-        Action::MknodDevice { path, major, minor } => {
-            input_device::ensure_input_device(path, major.into(), minor.into())?;
+        Action::MknodDevice {
+            path,
+            major,
+            minor,
+            ownership,
+        } => {
+            input_device::ensure_input_device(path, major, minor, &ownership)?;
             Ok(())
         }
         Action::WriteUdevRuntimeData {
@@ -28,10 +36,8 @@ fn handle_action(action: Action) -> anyhow::Result<()> {
         } => {
             runtime_data::ensure_udev_structure()?;
             match runtime_data {
-                Some(data) => {
-                    runtime_data::write_udev_data("/run", &data, major.into(), minor.into())?
-                }
-                None => runtime_data::delete_udev_data("/run", major.into(), minor.into())?,
+                Some(data) => runtime_data::write_udev_data("/run", &data, major, minor)?,
+                None => runtime_data::delete_udev_data("/run", major, minor)?,
             }
             Ok(())
         }
@@ -39,8 +45,13 @@ fn handle_action(action: Action) -> anyhow::Result<()> {
             netlink_message::send_udev_monitor_message_with_properties(netlink_message);
             Ok(())
         }
+        Action::EmitKernelUevent { netlink_message } => {
+            let payload = container_udevd::encode_kernel_uevent(&netlink_message)
+                .ok_or_else(|| anyhow!("the event lacks ACTION or DEVPATH"))?;
+            netlink_message::send_kernel_uevent(&payload).map_err(|e| anyhow!(e))
+        }
         Action::RemoveDevice { path, major, minor } => {
-            input_device::remove_input_device(path, major.into(), minor.into())?;
+            input_device::remove_input_device(path, major, minor)?;
             Ok(())
         }
     }
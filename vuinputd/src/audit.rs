@@ -0,0 +1,308 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Audit trail of the security relevant decisions: devices created and destroyed, requests the
+// device policy or a limit refused, and handles revoked. With audit-log, each decision is
+// appended to the file as a JSON line; with audit-journal, it is sent to the systemd journal
+// with a MESSAGE_ID per action, so that `journalctl MESSAGE_ID=...` finds them. Either way
+// incident response can reconstruct what a container tried to inject.
+//
+// Unlike the events of the control socket, nothing is dropped: the records are written right
+// away by the thread that made the decision.
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde::Serialize;
+
+use crate::config_file::value_name;
+use crate::cuse_device::state::VuInputState;
+use crate::global_config::get_reloadable_config;
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    CreateDevice,
+    DestroyDevice,
+    /// A capability, event or device the policy or a limit refused
+    Reject,
+    Revoke,
+    /// vuinputctl label, the reason holds the new label
+    Label,
+}
+
+impl Action {
+    /// MESSAGE_ID of the journal entries
+    fn message_id(self) -> &'static str {
+        match self {
+            Action::CreateDevice => "5c1b0f6a8e7d4c2f9a3e1d7b6c4a2f01",
+            Action::DestroyDevice => "5c1b0f6a8e7d4c2f9a3e1d7b6c4a2f02",
+            Action::Reject => "5c1b0f6a8e7d4c2f9a3e1d7b6c4a2f03",
+            Action::Revoke => "5c1b0f6a8e7d4c2f9a3e1d7b6c4a2f04",
+            Action::Label => "5c1b0f6a8e7d4c2f9a3e1d7b6c4a2f05",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Verdict {
+    Allowed,
+    Denied,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditRecord {
+    /// Seconds since the epoch, with milliseconds
+    pub timestamp: f64,
+    pub action: Action,
+    pub verdict: Verdict,
+    pub reason: Option<String>,
+    pub fh: u64,
+    /// Process that opened /dev/uinput, host view
+    pub pid: u32,
+    /// Root pid of its container
+    pub container: u32,
+    /// Engine and name of the container, e.g. "docker steam (3f2a9c1b7d4e)", if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container_name: Option<String>,
+    /// Inodes of the namespaces of the process, e.g. "mnt": 4026532811
+    pub namespaces: BTreeMap<&'static str, u64>,
+    pub policy: String,
+    pub devnode: Option<String>,
+    /// Set with vuinputctl label
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new(
+        fh: u64,
+        vuinput_state: &VuInputState,
+        action: Action,
+        verdict: Verdict,
+        reason: Option<String>,
+        devnode: Option<&str>,
+    ) -> AuditRecord {
+        let process = &vuinput_state.requesting_process;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis() as f64 / 1000.0)
+            .unwrap_or(0.0);
+        AuditRecord {
+            timestamp,
+            action,
+            verdict,
+            reason,
+            fh,
+            pid: process.pid_requestor.as_raw(),
+            container: process.pid_requestor_root.as_raw(),
+            container_name: process
+                .identity
+                .as_ref()
+                .map(|identity| identity.to_string()),
+            namespaces: process
+                .namespaces
+                .entries()
+                .into_iter()
+                .filter_map(|(name, inode)| Some((name, inode?)))
+                .collect(),
+            policy: value_name(&vuinput_state.policy(&get_reloadable_config())),
+            devnode: devnode.map(str::to_string),
+            label: vuinput_state.label.clone(),
+        }
+    }
+
+    /// The fields of the journal entry, in the native protocol of systemd-journald
+    fn journal_fields(&self, json: &str) -> Vec<(&'static str, String)> {
+        let action = value_name_of(&self.action);
+        let verdict = value_name_of(&self.verdict);
+        let mut message = format!(
+            "fh {} (container {}): {} {}",
+            self.fh, self.container, action, verdict
+        );
+        if let Some(reason) = &self.reason {
+            message.push_str(": ");
+            message.push_str(reason);
+        }
+        let priority = match self.verdict {
+            Verdict::Allowed => "5",
+            Verdict::Denied => "4",
+        };
+        let mut fields = vec![
+            ("MESSAGE", message),
+            ("MESSAGE_ID", self.action.message_id().to_string()),
+            ("PRIORITY", priority.to_string()),
+            ("SYSLOG_IDENTIFIER", "vuinputd".to_string()),
+            ("VUINPUTD_ACTION", action),
+            ("VUINPUTD_VERDICT", verdict),
+            ("VUINPUTD_FH", self.fh.to_string()),
+            ("VUINPUTD_PID", self.pid.to_string()),
+            ("VUINPUTD_CONTAINER", self.container.to_string()),
+            ("VUINPUTD_POLICY", self.policy.clone()),
+            ("VUINPUTD_AUDIT", json.to_string()),
+        ];
+        if let Some(reason) = &self.reason {
+            fields.push(("VUINPUTD_REASON", reason.clone()));
+        }
+        if let Some(devnode) = &self.devnode {
+            fields.push(("VUINPUTD_DEVNODE", devnode.clone()));
+        }
+        if let Some(container_name) = &self.container_name {
+            fields.push(("VUINPUTD_CONTAINER_NAME", container_name.clone()));
+        }
+        if let Some(label) = &self.label {
+            fields.push(("VUINPUTD_LABEL", label.clone()));
+        }
+        fields
+    }
+}
+
+/// "create-device", "denied", ...
+fn value_name_of<T: Serialize>(value: &T) -> String {
+    serde_json::to_value(value)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// Encodes the fields for the journal socket. Values with a newline are sent with their length
+/// in front, as described in systemd-journald.socket(8).
+fn encode_journal_entry(fields: &[(&str, String)]) -> Vec<u8> {
+    let mut entry = Vec::new();
+    for (key, value) in fields {
+        entry.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    }
+    entry
+}
+
+struct AuditSinks {
+    file: Option<Mutex<File>>,
+    journal: Option<UnixDatagram>,
+}
+
+static SINKS: OnceLock<AuditSinks> = OnceLock::new();
+
+/// Opens the configured sinks, called once when vuinputd starts
+pub fn configure(log: Option<&Path>, journal: bool) -> io::Result<()> {
+    let file = match log {
+        Some(path) => Some(Mutex::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        )),
+        None => None,
+    };
+    let journal = match journal {
+        true => {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(JOURNAL_SOCKET)?;
+            Some(socket)
+        }
+        false => None,
+    };
+    if file.is_some() || journal.is_some() {
+        let _ = SINKS.set(AuditSinks { file, journal });
+    }
+    Ok(())
+}
+
+/// Writes the decision to the configured sinks, if there are any. `devnode` is the node of
+/// the device the decision is about, if it has one.
+pub fn record(
+    fh: u64,
+    vuinput_state: &VuInputState,
+    action: Action,
+    verdict: Verdict,
+    reason: Option<String>,
+    devnode: Option<&str>,
+) {
+    let Some(sinks) = SINKS.get() else {
+        return;
+    };
+    let record = AuditRecord::new(fh, vuinput_state, action, verdict, reason, devnode);
+    let json = match serde_json::to_string(&record) {
+        Ok(json) => json,
+        Err(e) => {
+            warn!("fh {}: failed to serialize the audit record: {}", fh, e);
+            return;
+        }
+    };
+    if let Some(file) = &sinks.file {
+        let mut file = file.lock().unwrap();
+        if let Err(e) = writeln!(file, "{}", json) {
+            warn!("fh {}: failed to write the audit log: {}", fh, e);
+        }
+    }
+    if let Some(journal) = &sinks.journal {
+        let entry = encode_journal_entry(&record.journal_fields(&json));
+        if let Err(e) = journal.send(&entry) {
+            warn!(
+                "fh {}: failed to send the audit record to the journal: {}",
+                fh, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_journal_entries() {
+        let entry = encode_journal_entry(&[
+            ("MESSAGE", "fh 3: reject denied".to_string()),
+            ("VUINPUTD_REASON", "two\nlines".to_string()),
+        ]);
+        let mut expected = b"MESSAGE=fh 3: reject denied\nVUINPUTD_REASON\n".to_vec();
+        expected.extend_from_slice(&9u64.to_le_bytes());
+        expected.extend_from_slice(b"two\nlines\n");
+        assert_eq!(entry, expected);
+    }
+
+    #[test]
+    fn records_are_json_lines() {
+        let record = AuditRecord {
+            timestamp: 1760000000.5,
+            action: Action::Reject,
+            verdict: Verdict::Denied,
+            reason: Some("UI_SET_KEYBIT KEY_SYSRQ".to_string()),
+            fh: 3,
+            pid: 4711,
+            container: 4690,
+            container_name: None,
+            namespaces: BTreeMap::from([("mnt", 4026532811)]),
+            policy: "strict-gamepad".to_string(),
+            devnode: None,
+            label: None,
+        };
+        let json = serde_json::to_string(&record).unwrap();
+        assert_eq!(
+            json,
+            r#"{"timestamp":1760000000.5,"action":"reject","verdict":"denied","reason":"UI_SET_KEYBIT KEY_SYSRQ","fh":3,"pid":4711,"container":4690,"namespaces":{"mnt":4026532811},"policy":"strict-gamepad","devnode":null}"#
+        );
+        let fields = record.journal_fields(&json);
+        assert!(fields.contains(&("VUINPUTD_ACTION", "reject".to_string())));
+        assert!(fields.contains(&("PRIORITY", "4".to_string())));
+        assert!(fields.contains(&(
+            "MESSAGE",
+            "fh 3 (container 4690): reject denied: UI_SET_KEYBIT KEY_SYSRQ".to_string()
+        )));
+    }
+}
@@ -0,0 +1,365 @@
+// SPDX-License-Identifier: MIT
+// vuinputctl: queries a running vuinputd over its control socket
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+use clap::{Parser, Subcommand};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[allow(dead_code)]
+#[path = "../cuse_device/diagnostic_ioctl.rs"]
+mod diagnostic_ioctl;
+#[allow(dead_code)]
+#[path = "../input_codes.rs"]
+mod input_codes;
+#[path = "vuinputctl/probe.rs"]
+mod probe;
+#[path = "../control/protocol.rs"]
+mod protocol;
+#[path = "vuinputctl/top.rs"]
+mod top;
+
+use protocol::{control_socket_path, Event, Request, Response};
+
+#[derive(Debug, Parser)]
+#[command(author, version, about)]
+struct Args {
+    /// Device name of the vuinputd instance (without /dev/)
+    #[arg(long, default_value = "vuinput")]
+    devname: String,
+
+    /// Path of the control socket (overrides --devname)
+    #[arg(long, value_name = "PATH")]
+    socket: Option<PathBuf>,
+
+    /// Print the raw JSON response
+    #[arg(long)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Show the job queue of the host and of each container
+    Jobs,
+    /// Show the open handles of /dev/uinput and their devices
+    Devices,
+    /// Show the containers with open handles
+    Containers,
+    /// Remove the device of a handle (see devices) and refuse further requests on it
+    Revoke { fh: u64 },
+    /// Set the device policy of a container (root pid, see containers) until restart
+    SetPolicy { container: u32, policy: String },
+    /// Show the last ioctls and writes of a handle (see devices) with their results
+    History { fh: u64 },
+    /// Name a handle (see devices) in devices, the audit log and the metrics. Without a
+    /// label, the label is removed.
+    Label {
+        fh: u64,
+        label: Option<String>,
+        /// Also set the label as NAME and ID_MODEL in the udev database of the container
+        #[arg(long)]
+        push: bool,
+    },
+    /// Also create the devices of a container (root pid, 1 for the host) in the target
+    /// containers, from now on. Without targets, the broadcast ends for new devices.
+    Broadcast { container: u32, targets: Vec<u32> },
+    /// Let a container (root pid) create devices with --approval prompt or deny, answers the
+    /// requests that wait for it
+    Approve { container: u32 },
+    /// Refuse the devices of a container (root pid), answers the requests that wait for it
+    Deny { container: u32 },
+    /// Show whether vuinputd is alive and ready to create devices, exits with 1 if not ready
+    Health,
+    /// Print devices as they are created and removed, policy violations and failed jobs
+    /// until Ctrl+C
+    Events,
+    /// Check whether a uinput node (e.g. in a container) is served by vuinputd and show what
+    /// its device policy permits. Does not use the control socket.
+    Probe {
+        #[arg(default_value = "/dev/uinput")]
+        path: PathBuf,
+    },
+    /// Show devices, event rates, policy drops and job queues, refreshed until Ctrl+C
+    Top {
+        /// Seconds between two refreshes
+        #[arg(long, default_value_t = 1.0)]
+        interval: f64,
+    },
+}
+
+fn send(socket: &Path, request: &Request) -> io::Result<Response> {
+    let mut stream = UnixStream::connect(socket)?;
+    serde_json::to_writer(&mut stream, request)?;
+    stream.write_all(b"\n")?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    serde_json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Prints the responses to Subscribe until vuinputd closes the connection
+fn stream_events(socket: &Path, json: bool) -> io::Result<()> {
+    let mut stream = UnixStream::connect(socket)?;
+    serde_json::to_writer(&mut stream, &Request::Subscribe)?;
+    stream.write_all(b"\n")?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if json {
+            println!("{}", line);
+        } else {
+            let response: Response = serde_json::from_str(&line)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            print_response(&response);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "vuinputd closed the connection",
+    ))
+}
+
+fn or_dash(value: &Option<String>) -> &str {
+    value.as_deref().unwrap_or("-")
+}
+
+fn print_response(response: &Response) {
+    match response {
+        Response::Jobs { queues } => {
+            println!(
+                "{:<40} {:>6}  {:<28} LAST ERROR",
+                "TARGET", "QUEUED", "RUNNING"
+            );
+            for queue in queues {
+                println!(
+                    "{:<40} {:>6}  {:<28} {}",
+                    queue.target,
+                    queue.queued,
+                    or_dash(&queue.running),
+                    or_dash(&queue.last_error)
+                );
+            }
+        }
+        Response::Devices { devices } => {
+            println!(
+                "{:>5} {:>8} {:>9}  {:<20} {:<15} {:<20} CAPABILITIES",
+                "FH", "PID", "CONTAINER", "DEVICE", "POLICY", "LABEL"
+            );
+            for device in devices {
+                let devnode = match (&device.devnode, device.revoked) {
+                    (_, true) => "(revoked)",
+                    (Some(devnode), false) => devnode.as_str(),
+                    (None, false) => "-",
+                };
+                println!(
+                    "{:>5} {:>8} {:>9}  {:<20} {:<15} {:<20} {}",
+                    device.fh,
+                    device.pid,
+                    device.container,
+                    devnode,
+                    device.policy,
+                    or_dash(&device.label),
+                    device.capabilities
+                );
+            }
+        }
+        Response::Containers { containers } => {
+            println!(
+                "{:>9} {:>7} {:>7}  {:<15} NAME",
+                "CONTAINER", "HANDLES", "DEVICES", "POLICY"
+            );
+            for container in containers {
+                println!(
+                    "{:>9} {:>7} {:>7}  {:<15} {}",
+                    container.container,
+                    container.handles,
+                    container.devices,
+                    container.policy.as_deref().unwrap_or("(configured)"),
+                    container.name.as_deref().unwrap_or("-")
+                );
+            }
+        }
+        Response::Revoked { fh, devnode } => match devnode {
+            Some(devnode) => println!("fh {}: revoked, {} is being removed", fh, devnode),
+            None => println!("fh {}: revoked, it had no device", fh),
+        },
+        Response::PolicySet {
+            container,
+            policy,
+            handles,
+        } => println!(
+            "container {}: device policy {} ({} open handles)",
+            container, policy, handles
+        ),
+        Response::Labeled { fh, label, pushed } => {
+            let pushed = if *pushed {
+                ", updating the udev database of the container"
+            } else {
+                ""
+            };
+            match label {
+                Some(label) => println!("fh {}: labeled '{}'{}", fh, label, pushed),
+                None => println!("fh {}: label removed{}", fh, pushed),
+            }
+        }
+        Response::BroadcastSet { container, targets } => match targets.is_empty() {
+            true => println!("container {}: new devices are not broadcast", container),
+            false => println!(
+                "container {}: new devices are broadcast to {}",
+                container,
+                targets
+                    .iter()
+                    .map(u32::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        },
+        Response::Approved {
+            container,
+            allow,
+            answered,
+        } => println!(
+            "container {}: {} ({} waiting requests answered)",
+            container,
+            if *allow { "approved" } else { "denied" },
+            answered
+        ),
+        Response::ContainerRegistered { pid, id } => {
+            println!("container {} registered as {}", pid, id)
+        }
+        Response::ContainerUnregistered { id, containers } => {
+            println!("{}: {} containers unregistered", id, containers)
+        }
+        Response::History { fh, operations } => {
+            println!("fh {}: last {} operations", fh, operations.len());
+            println!(
+                "{:>9}  {:<22} {:>10} {:>6} {:>6} {:>6}  RESULT",
+                "MS", "OPERATION", "CMD", "COUNT", "IN", "OUT"
+            );
+            for operation in operations {
+                let cmd = operation
+                    .cmd
+                    .map_or("-".to_string(), |cmd| format!("{:#x}", cmd));
+                let result = match operation.errno {
+                    None => "ok".to_string(),
+                    Some(errno) => format!("errno {}", errno),
+                };
+                println!(
+                    "{:>9}  {:<22} {:>10} {:>6} {:>6} {:>6}  {}",
+                    operation.elapsed_ms,
+                    operation.name,
+                    cmd,
+                    operation.count,
+                    operation.in_size,
+                    operation.out_size,
+                    result
+                );
+            }
+        }
+        Response::Health {
+            live,
+            ready,
+            checks,
+        } => {
+            println!("live: {}, ready: {}", live, ready);
+            for check in checks {
+                let state = if check.ok { "ok" } else { "failed" };
+                println!("  {:<14} {:<6} {}", check.name, state, check.detail);
+            }
+        }
+        Response::Subscribed => eprintln!("Waiting for events, Ctrl+C to quit"),
+        Response::Event { event, .. } => match event {
+            Event::DeviceCreated {
+                fh,
+                container,
+                devnode,
+            } => println!("created    fh {} container {}: {}", fh, container, devnode),
+            Event::DeviceRemoved {
+                fh,
+                container,
+                devnode,
+            } => println!("removed    fh {} container {}: {}", fh, container, devnode),
+            Event::PolicyViolation {
+                fh,
+                container,
+                policy,
+                violation,
+            } => println!(
+                "violation  fh {} container {}: {} (policy {})",
+                fh, container, violation, policy
+            ),
+            Event::JobFailed { target, error } => println!("job failed {}: {}", target, error),
+            Event::ApprovalRequested { fh, container, pid } => println!(
+                "approval   fh {} container {}: pid {} waits for vuinputctl approve {}",
+                fh, container, pid, container
+            ),
+        },
+        Response::Error { message } => eprintln!("Error: {}", message),
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    let socket = args
+        .socket
+        .clone()
+        .unwrap_or(control_socket_path(&args.devname));
+
+    let request = match args.command {
+        Command::Probe { path } => {
+            if let Err(e) = probe::run(&path) {
+                eprintln!("Error: {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Command::Events => {
+            if let Err(e) = stream_events(&socket, args.json) {
+                eprintln!("Error: {}: {}", socket.display(), e);
+            }
+            std::process::exit(1);
+        }
+        Command::Top { interval } => top::run(&socket, Duration::from_secs_f64(interval.max(0.1))),
+        Command::Jobs => Request::ListJobs,
+        Command::Devices => Request::ListDevices,
+        Command::Containers => Request::ListContainers,
+        Command::Revoke { fh } => Request::Revoke { fh },
+        Command::SetPolicy { container, policy } => Request::SetPolicy { container, policy },
+        Command::History { fh } => Request::History { fh },
+        Command::Label { fh, label, push } => Request::Label { fh, label, push },
+        Command::Broadcast { container, targets } => Request::Broadcast { container, targets },
+        Command::Approve { container } => Request::Approve {
+            container,
+            allow: true,
+        },
+        Command::Deny { container } => Request::Approve {
+            container,
+            allow: false,
+        },
+        Command::Health => Request::Health,
+    };
+
+    let response = match send(&socket, &request) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("Error: can't query {}: {}", socket.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    if args.json {
+        println!("{}", serde_json::to_string(&response).unwrap());
+    } else {
+        print_response(&response);
+    }
+    match response {
+        Response::Error { .. } | Response::Health { ready: false, .. } => std::process::exit(1),
+        _ => {}
+    }
+}
@@ -0,0 +1,117 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// vuinputctl probe: asks a uinput node (usually /dev/uinput in a container) with the diagnostic
+// ioctls whether it is served by vuinputd and what its device policy permits. It opens a handle
+// of its own, so it shows the policy a new client would get, not the one of a running game.
+
+use std::fs::OpenOptions;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+use crate::diagnostic_ioctl::{
+    read_str, vui_get_allowed_bits, VuinputInfo, ALLOWED_BITS_MAX_LEN, LIFECYCLE_CREATED,
+    VUINPUT_INFO_MAGIC, VUI_GET_INFO,
+};
+use crate::input_codes::{CodeName, TypeName};
+
+/// Up to this many names are listed, otherwise only the number of codes
+const MAX_NAMES: usize = 24;
+
+/// Number of codes per event type (KEY_CNT, REL_CNT, ...), EV_SYN is left out
+const CODE_COUNTS: &[(u16, usize)] = &[
+    (0x01, 0x300),
+    (0x02, 0x10),
+    (0x03, 0x40),
+    (0x04, 0x08),
+    (0x05, 0x11),
+    (0x11, 0x10),
+    (0x12, 0x08),
+    (0x15, 0x80),
+];
+
+pub fn run(path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    let fd = file.as_raw_fd();
+
+    let mut info = VuinputInfo {
+        magic: 0,
+        ..Default::default()
+    };
+    if unsafe { libc::ioctl(fd, VUI_GET_INFO as _, &mut info as *mut VuinputInfo) } < 0 {
+        let error = io::Error::last_os_error();
+        println!(
+            "{} is not served by vuinputd, or by a version without diagnostic ioctls ({})",
+            path.display(),
+            error
+        );
+        return Ok(());
+    }
+    if info.magic != VUINPUT_INFO_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "VUI_GET_INFO succeeded, but the answer is not from vuinputd",
+        ));
+    }
+
+    println!(
+        "{} is served by vuinputd (interface version {})",
+        path.display(),
+        info.version
+    );
+    println!("policy: {}", read_str(&info.policy));
+    if info.lifecycle == LIFECYCLE_CREATED {
+        println!(
+            "device: {} ({}:{})",
+            read_str(&info.devnode),
+            info.major,
+            info.minor
+        );
+    }
+
+    let types = allowed_bits(fd, 0, 4)?;
+    for (type_, count) in CODE_COUNTS {
+        if !is_set(&types, usize::from(*type_)) {
+            println!("{}: refused", TypeName(*type_));
+            continue;
+        }
+        let codes = allowed_bits(fd, *type_, count.div_ceil(8))?;
+        let (allowed, refused): (Vec<usize>, Vec<usize>) =
+            (0..*count).partition(|code| is_set(&codes, *code));
+        let names = |codes: &[usize]| {
+            codes
+                .iter()
+                .map(|code| CodeName(*type_, *code as u16).to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let summary = if refused.is_empty() {
+            "all".to_string()
+        } else if refused.len() <= MAX_NAMES {
+            format!("all but {}", names(&refused))
+        } else if allowed.len() <= MAX_NAMES {
+            names(&allowed)
+        } else {
+            format!("{} of {} codes", allowed.len(), count)
+        };
+        println!("{}: {}", TypeName(*type_), summary);
+    }
+    Ok(())
+}
+
+fn allowed_bits(fd: i32, type_: u16, len: usize) -> io::Result<Vec<u8>> {
+    let mut bitmap = vec![0u8; len.min(ALLOWED_BITS_MAX_LEN)];
+    let request = vui_get_allowed_bits(type_, bitmap.len());
+    if unsafe { libc::ioctl(fd, request as _, bitmap.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(bitmap)
+}
+
+fn is_set(bitmap: &[u8], bit: usize) -> bool {
+    bitmap
+        .get(bit / 8)
+        .is_some_and(|byte| byte & (1 << (bit % 8)) != 0)
+}
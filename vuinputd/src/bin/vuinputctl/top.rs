@@ -0,0 +1,206 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// vuinputctl top: polls the handles and job queues and redraws the terminal, like top(1).
+// The event rates are the difference of the counters of vuinputd between two polls.
+// DROP/S includes all dropped events: by the device policy (BLOCKED), while the session was
+// locked (LOCKED) and those uinput refused (FAILED).
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::protocol::{Device, JobQueue, Request, Response};
+use crate::{or_dash, send};
+
+const CLEAR_SCREEN: &str = "\x1b[H\x1b[2J";
+
+/// Counters of the previous poll by file handle
+type Counters = HashMap<u64, (u64, u64)>;
+
+/// Runs until interrupted (Ctrl+C). A vuinputd that is not reachable is shown as such and
+/// queried again with the next poll, e.g. while it restarts.
+pub fn run(socket: &Path, interval: Duration) -> ! {
+    let mut previous = Counters::new();
+    let mut last_poll = Instant::now();
+    loop {
+        let screen = match poll(socket) {
+            Ok((devices, queues)) => {
+                let elapsed = last_poll.elapsed();
+                last_poll = Instant::now();
+                let screen = render(&devices, &queues, &previous, elapsed, interval);
+                previous = devices
+                    .iter()
+                    .map(|device| (device.fh, (device.forwarded, dropped(device))))
+                    .collect();
+                screen
+            }
+            Err(e) => format!("can't query {}: {}\n", socket.display(), e),
+        };
+        print!("{}{}", CLEAR_SCREEN, screen);
+        let _ = io::stdout().flush();
+        thread::sleep(interval);
+    }
+}
+
+fn poll(socket: &Path) -> io::Result<(Vec<Device>, Vec<JobQueue>)> {
+    let devices = match send(socket, &Request::ListDevices)? {
+        Response::Devices { devices } => devices,
+        other => return Err(unexpected(other)),
+    };
+    let queues = match send(socket, &Request::ListJobs)? {
+        Response::Jobs { queues } => queues,
+        other => return Err(unexpected(other)),
+    };
+    Ok((devices, queues))
+}
+
+fn unexpected(response: Response) -> io::Error {
+    match response {
+        Response::Error { message } => io::Error::other(message),
+        other => io::Error::other(format!("unexpected response {:?}", other)),
+    }
+}
+
+fn dropped(device: &Device) -> u64 {
+    device.blocked + device.locked + device.failed
+}
+
+/// Events per second since the last poll, "-" for handles that are new
+fn rate(now: u64, before: Option<u64>, elapsed: Duration) -> String {
+    match before {
+        Some(before) if !elapsed.is_zero() => format!(
+            "{:.1}",
+            now.saturating_sub(before) as f64 / elapsed.as_secs_f64()
+        ),
+        _ => "-".to_string(),
+    }
+}
+
+fn render(
+    devices: &[Device],
+    queues: &[JobQueue],
+    previous: &Counters,
+    elapsed: Duration,
+    interval: Duration,
+) -> String {
+    let mut screen = String::new();
+    let created = devices.iter().filter(|d| d.devnode.is_some()).count();
+    let containers: BTreeSet<u32> = devices.iter().map(|d| d.container).collect();
+    let queued: usize = queues.iter().map(|q| q.queued).sum();
+    let running = queues.iter().filter(|q| q.running.is_some()).count();
+    let _ = writeln!(
+        screen,
+        "vuinputd - {} handles, {} devices, {} containers, {} jobs running, {} queued (every {:.1}s, Ctrl+C to quit)\n",
+        devices.len(),
+        created,
+        containers.len(),
+        running,
+        queued,
+        interval.as_secs_f64()
+    );
+
+    let _ = writeln!(
+        screen,
+        "{:>5} {:>8} {:>9}  {:<20} {:<15} {:>8} {:>8} {:>10} {:>8} {:>7} {:>7}",
+        "FH",
+        "PID",
+        "CONTAINER",
+        "DEVICE",
+        "POLICY",
+        "EV/S",
+        "DROP/S",
+        "FORWARDED",
+        "BLOCKED",
+        "LOCKED",
+        "FAILED"
+    );
+    for device in devices {
+        let devnode = match (&device.devnode, device.revoked) {
+            (_, true) => "(revoked)",
+            (Some(devnode), false) => devnode.as_str(),
+            (None, false) => "-",
+        };
+        let before = previous.get(&device.fh);
+        let _ = writeln!(
+            screen,
+            "{:>5} {:>8} {:>9}  {:<20} {:<15} {:>8} {:>8} {:>10} {:>8} {:>7} {:>7}",
+            device.fh,
+            device.pid,
+            device.container,
+            devnode,
+            device.policy,
+            rate(device.forwarded, before.map(|b| b.0), elapsed),
+            rate(dropped(device), before.map(|b| b.1), elapsed),
+            device.forwarded,
+            device.blocked,
+            device.locked,
+            device.failed
+        );
+    }
+
+    let _ = writeln!(
+        screen,
+        "\n{:<40} {:>6}  {:<28} LAST ERROR",
+        "JOB QUEUE", "QUEUED", "RUNNING"
+    );
+    for queue in queues {
+        let _ = writeln!(
+            screen,
+            "{:<40} {:>6}  {:<28} {}",
+            queue.target,
+            queue.queued,
+            or_dash(&queue.running),
+            or_dash(&queue.last_error)
+        );
+    }
+    screen
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_are_computed_from_the_previous_poll() {
+        let device = Device {
+            fh: 5,
+            pid: 4711,
+            container: 4690,
+            container_name: None,
+            devnode: Some("/dev/input/event7".to_string()),
+            label: None,
+            sysname: Some("input7".to_string()),
+            capabilities: "EV_KEY".to_string(),
+            policy: "strict-gamepad".to_string(),
+            revoked: false,
+            forwarded: 300,
+            blocked: 8,
+            locked: 3,
+            failed: 1,
+            ioctl_errors: 0,
+            descriptor: Default::default(),
+            broadcast: Vec::new(),
+            seat: None,
+            container_devnode: None,
+        };
+        let previous = Counters::from([(5, (100, 2))]);
+        let screen = render(
+            &[device],
+            &[],
+            &previous,
+            Duration::from_secs(2),
+            Duration::from_secs(2),
+        );
+        let row = screen.lines().find(|l| l.contains("event7")).unwrap();
+        let columns: Vec<&str> = row.split_whitespace().collect();
+        assert_eq!(columns[5..], ["100.0", "5.0", "300", "8", "3", "1"]);
+        assert!(screen.starts_with("vuinputd - 1 handles, 1 devices, 1 containers"));
+
+        assert_eq!(rate(300, None, Duration::from_secs(1)), "-");
+    }
+}
@@ -0,0 +1,402 @@
+// SPDX-License-Identifier: MIT
+// vuinputd-oci-hook: prepares containers for vuinputd as the OCI runtime starts them
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// The runtime runs the hook with the state of the container on stdin (id, pid, bundle and
+// annotations, see the OCI runtime spec). On prestart, it
+//
+// - registers the container with vuinputd over the control socket, so that it is known by
+//   its engine and id before it opens /dev/uinput
+// - creates /dev/uinput in the container as a node of the CUSE device (/dev/vuinput)
+// - with --on-host, bind-mounts /run/vuinputd/<devname>/dev-input and .../udev as /dev/input
+//   and /run/udev. vuinputd mounts the tmpfs on dev-input that allows the nodes, the hook
+//   refuses to start the container if it is missing. With --idmap, the mounts are idmapped to
+//   the user namespace of the container, so that its root owns what root of the host owns.
+// - on cgroup v1, allows the CUSE device and the input devices in the devices cgroup
+//
+// The hook runs before the runtime pivots into the root file system of the container, so the
+// root of the bundle is still at its path in the mount namespace of the container. On poststop,
+// the registration is removed again. Failures of the control socket are only reported, the
+// container starts without the registration then.
+
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use nix::sched::{setns, CloneFlags};
+use nix::sys::stat::{mknod, Mode, SFlag};
+
+#[path = "../control/protocol.rs"]
+mod protocol;
+
+use protocol::{control_socket_path, Request, Response};
+
+/// Flags of open_tree, mount_setattr and move_mount, see open_tree(2)
+const OPEN_TREE_CLONE: libc::c_uint = 1;
+const AT_RECURSIVE: libc::c_uint = 0x8000;
+const MOVE_MOUNT_F_EMPTY_PATH: libc::c_uint = 0x4;
+
+/// Major of the input devices, /dev/input/event* and /dev/uinput
+const INPUT_MAJOR: u32 = 13;
+
+#[derive(Debug, Parser)]
+#[command(author, version, about)]
+struct Args {
+    /// Device name of the vuinputd instance (without /dev/)
+    #[arg(long, default_value = "vuinput")]
+    devname: String,
+
+    /// Also bind-mount the nodes and udev data that vuinputd places on the host
+    /// (--placement on-host)
+    #[arg(long)]
+    on_host: bool,
+
+    /// Idmap the mounts of --on-host to the user namespace of the container (Linux 6.3 or
+    /// later for tmpfs)
+    #[arg(long, requires = "on_host")]
+    idmap: bool,
+
+    /// Engine the container is registered with (docker, podman, nspawn or lxc). By default
+    /// podman, if the container has been created by libpod, and docker otherwise.
+    #[arg(long)]
+    engine: Option<String>,
+
+    #[command(subcommand)]
+    stage: Stage,
+}
+
+#[derive(Debug, Subcommand)]
+enum Stage {
+    /// Set up the container, for the prestart or createRuntime hook
+    Prestart,
+    /// Remove the registration of the container, for the poststop hook
+    Poststop,
+}
+
+/// The state of the container the runtime passes on stdin
+#[derive(Debug, Deserialize)]
+struct State {
+    id: String,
+    /// 0 or missing once the container has stopped
+    #[serde(default)]
+    pid: u32,
+    bundle: PathBuf,
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+}
+
+/// The part of config.json in the bundle the hook needs
+#[derive(Debug, Deserialize)]
+struct Spec {
+    root: Root,
+}
+
+#[derive(Debug, Deserialize)]
+struct Root {
+    path: PathBuf,
+}
+
+fn send(socket: &Path, request: &Request) -> io::Result<Response> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    serde_json::to_writer(&mut stream, request)?;
+    stream.write_all(b"\n")?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    serde_json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Sends the request, vuinputd not answering is reported but not an error of the hook
+fn notify_vuinputd(devname: &str, request: &Request) {
+    let socket = control_socket_path(devname);
+    match send(&socket, request) {
+        Ok(Response::Error { message }) => eprintln!("vuinputd-oci-hook: {}", message),
+        Ok(_) => {}
+        Err(e) => eprintln!(
+            "vuinputd-oci-hook: {} not reachable, the container is not registered: {}",
+            socket.display(),
+            e
+        ),
+    }
+}
+
+fn guess_engine(annotations: &BTreeMap<String, String>) -> &'static str {
+    match annotations.get("io.container.manager").map(String::as_str) {
+        Some("libpod") => "podman",
+        _ => "docker",
+    }
+}
+
+/// The root file system of the container, config.json may give it relative to the bundle
+fn rootfs(bundle: &Path) -> Result<PathBuf, String> {
+    let config = bundle.join("config.json");
+    let spec: Spec = fs::read_to_string(&config)
+        .map_err(|e| e.to_string())
+        .and_then(|content| serde_json::from_str(&content).map_err(|e| e.to_string()))
+        .map_err(|e| format!("{}: {}", config.display(), e))?;
+    Ok(bundle.join(spec.root.path))
+}
+
+/// Whether the file system that holds `path` allows device nodes, from the content of
+/// /proc/self/mountinfo, and whether `path` is its mount point
+fn allows_devices(mountinfo: &str, path: &Path) -> Option<(bool, bool)> {
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let (left, _) = line.split_once(" - ")?;
+            let fields: Vec<&str> = left.split_whitespace().collect();
+            let mount_point = Path::new(*fields.get(4)?);
+            let nodev = fields.get(5)?.split(',').any(|option| option == "nodev");
+            path.starts_with(mount_point)
+                .then_some((mount_point, !nodev))
+        })
+        // the mount that was made last on the longest prefix is the one that counts
+        .max_by_key(|(mount_point, _)| mount_point.as_os_str().len())
+        .map(|(mount_point, allowed)| (allowed, mount_point == path))
+}
+
+/// Checks that vuinputd has set up the directories it places nodes and udev data in, see
+/// host_fs
+fn check_host_dirs(prefix: &Path) -> Result<(), String> {
+    let dev_input = prefix.join("dev-input");
+    for path in [dev_input.clone(), prefix.join("udev/control")] {
+        if !path.exists() {
+            return Err(format!(
+                "{} is missing, is vuinputd running with --placement on-host?",
+                path.display()
+            ));
+        }
+    }
+
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").map_err(|e| e.to_string())?;
+    match allows_devices(&mountinfo, &dev_input) {
+        Some((true, _)) => Ok(()),
+        _ => Err(format!(
+            "{} does not allow device nodes, see the log of vuinputd for why it could not \
+             mount its tmpfs there",
+            dev_input.display()
+        )),
+    }
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other)
+}
+
+/// A detached copy of the mounts at `path`, which can be attached in another mount namespace
+fn open_tree(path: &Path) -> io::Result<OwnedFd> {
+    let path = c_path(path)?;
+    let flags = OPEN_TREE_CLONE | AT_RECURSIVE | libc::O_CLOEXEC as libc::c_uint;
+    let fd = unsafe { libc::syscall(libc::SYS_open_tree, libc::AT_FDCWD, path.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+/// Maps the owners of the files in the detached `tree` with the user namespace of `pid`
+fn idmap(tree: &OwnedFd, pid: u32) -> io::Result<()> {
+    let userns = File::open(format!("/proc/{}/ns/user", pid))?;
+    let attr = libc::mount_attr {
+        attr_set: libc::MOUNT_ATTR_IDMAP,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: userns.as_raw_fd() as u64,
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            tree.as_raw_fd(),
+            c"".as_ptr(),
+            libc::AT_EMPTY_PATH | AT_RECURSIVE as libc::c_int,
+            &attr as *const libc::mount_attr,
+            std::mem::size_of::<libc::mount_attr>(),
+        )
+    };
+    match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+fn move_mount(tree: &OwnedFd, target: &Path) -> io::Result<()> {
+    let target = c_path(target)?;
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_move_mount,
+            tree.as_raw_fd(),
+            c"".as_ptr(),
+            libc::AT_FDCWD,
+            target.as_ptr(),
+            MOVE_MOUNT_F_EMPTY_PATH,
+        )
+    };
+    match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Adds the CUSE device and the input devices to the devices cgroup of the container. cgroup
+/// v2 controls devices with a BPF program of the runtime, which a hook can't extend.
+fn allow_devices(pid: u32, rdev: u64) -> Result<(), String> {
+    let cgroup = fs::read_to_string(format!("/proc/{}/cgroup", pid)).map_err(|e| e.to_string())?;
+    let Some(path) = cgroup.lines().find_map(|line| {
+        let mut fields = line.splitn(3, ':');
+        let controllers = fields.nth(1)?;
+        controllers
+            .split(',')
+            .any(|controller| controller == "devices")
+            .then(|| fields.next())
+            .flatten()
+    }) else {
+        return Ok(());
+    };
+    let allow = format!("/sys/fs/cgroup/devices{}/devices.allow", path);
+    for rule in [
+        format!("c {}:{} rwm", libc::major(rdev), libc::minor(rdev)),
+        format!("c {}:* rwm", INPUT_MAJOR),
+    ] {
+        fs::write(&allow, rule).map_err(|e| format!("{}: {}", allow, e))?;
+    }
+    Ok(())
+}
+
+fn prestart(args: &Args, state: &State) -> Result<(), String> {
+    if state.pid == 0 {
+        return Err("the state has no pid, is the hook registered for prestart?".to_string());
+    }
+    let rootfs = rootfs(&state.bundle)?;
+    let cuse_node = format!("/dev/{}", args.devname);
+    let rdev = fs::metadata(&cuse_node)
+        .map_err(|e| format!("{}: {}, is vuinputd running?", cuse_node, e))?
+        .rdev();
+
+    let engine = args
+        .engine
+        .clone()
+        .unwrap_or_else(|| guess_engine(&state.annotations).to_string());
+    notify_vuinputd(
+        &args.devname,
+        &Request::RegisterContainer {
+            pid: state.pid,
+            engine,
+            id: state.id.clone(),
+            annotations: state.annotations.clone(),
+        },
+    );
+
+    // the mounts of the host are taken along before entering the namespace of the container
+    let mut trees = Vec::new();
+    if args.on_host {
+        let prefix = PathBuf::from(format!("/run/vuinputd/{}", args.devname));
+        check_host_dirs(&prefix)?;
+        for (source, target) in [("dev-input", "dev/input"), ("udev", "run/udev")] {
+            let source = prefix.join(source);
+            let tree = open_tree(&source).map_err(|e| format!("{}: {}", source.display(), e))?;
+            if args.idmap {
+                idmap(&tree, state.pid)
+                    .map_err(|e| format!("could not idmap {}: {}", source.display(), e))?;
+            }
+            trees.push((tree, rootfs.join(target)));
+        }
+    }
+    allow_devices(state.pid, rdev)?;
+
+    let mnt = File::open(format!("/proc/{}/ns/mnt", state.pid)).map_err(|e| e.to_string())?;
+    setns(mnt, CloneFlags::CLONE_NEWNS)
+        .map_err(|e| format!("could not enter the mount namespace: {}", e))?;
+
+    let uinput = rootfs.join("dev/uinput");
+    if !uinput.exists() {
+        mknod(&uinput, SFlag::S_IFCHR, Mode::empty(), rdev)
+            .map_err(|e| format!("{}: {}", uinput.display(), e))?;
+    }
+    // not limited by the umask
+    fs::set_permissions(&uinput, fs::Permissions::from_mode(0o666))
+        .map_err(|e| format!("{}: {}", uinput.display(), e))?;
+    for (tree, target) in trees {
+        fs::create_dir_all(&target).map_err(|e| format!("{}: {}", target.display(), e))?;
+        move_mount(&tree, &target).map_err(|e| format!("{}: {}", target.display(), e))?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = Args::parse();
+    let state: State = match serde_json::from_reader(io::stdin()) {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("vuinputd-oci-hook: invalid container state on stdin: {}", e);
+            std::process::exit(1);
+        }
+    };
+    match args.stage {
+        Stage::Prestart => {
+            if let Err(e) = prestart(&args, &state) {
+                eprintln!("vuinputd-oci-hook: container {}: {}", state.id, e);
+                std::process::exit(1);
+            }
+        }
+        Stage::Poststop => notify_vuinputd(
+            &args.devname,
+            &Request::UnregisterContainer { id: state.id },
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &str = "\
+25 1 259:2 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw
+26 25 0:23 / /run rw,nosuid,nodev,noexec,relatime shared:2 - tmpfs tmpfs rw,mode=755
+27 26 0:45 / /run/vuinputd/games/dev-input rw,nosuid,noexec,relatime shared:3 - tmpfs tmpfs rw
+";
+
+    #[test]
+    fn finds_the_file_system_of_the_nodes() {
+        assert_eq!(
+            allows_devices(MOUNTINFO, Path::new("/run/vuinputd/vuinput/dev-input")),
+            Some((false, false))
+        );
+        assert_eq!(
+            allows_devices(MOUNTINFO, Path::new("/run/vuinputd/games/dev-input")),
+            Some((true, true))
+        );
+        assert_eq!(
+            allows_devices(MOUNTINFO, Path::new("/var/lib/vuinputd")),
+            Some((true, false))
+        );
+    }
+
+    #[test]
+    fn reads_the_state_of_the_runtime() {
+        let state: State = serde_json::from_str(
+            r#"{"ociVersion":"1.0.2","id":"3f2a9c1b7d4e","status":"created","pid":4242,
+                "bundle":"/run/containers/storage/overlay-containers/3f2a9c1b7d4e/userdata",
+                "annotations":{"io.container.manager":"libpod"}}"#,
+        )
+        .unwrap();
+        assert_eq!(state.pid, 4242);
+        assert_eq!(guess_engine(&state.annotations), "podman");
+        assert_eq!(guess_engine(&BTreeMap::new()), "docker");
+
+        let stopped: State =
+            serde_json::from_str(r#"{"id":"3f2a9c1b7d4e","status":"stopped","bundle":"/b"}"#)
+                .unwrap();
+        assert_eq!(stopped.pid, 0);
+    }
+}
@@ -0,0 +1,1395 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// The configuration file (/etc/vuinputd/config.toml by default). The keys are named like the
+// command line options and take the same values, e.g.
+//
+//   device-policy = "strict-gamepad"
+//   log-level = "info"
+//
+//   [limits]
+//   max-devices-per-container = 8
+//
+//   [strict-gamepad]
+//   extra-keys = ["KEY_RECORD", 0x2c1]
+//
+//   [hooks]
+//   on-device-created = "/usr/local/libexec/vuinputd-device-created"
+//
+//   [[container-policy]]
+//   name = "steam-*"
+//   policy = "strict-gamepad"
+//
+//   [[udev-property]]
+//   name = "Sunshine*"
+//   properties = { LIBINPUT_DEVICE_GROUP = "sunshine" }
+//
+// The same keys can be set with VUINPUTD_* environment variables, e.g.
+//
+//   VUINPUTD_DEVICE_POLICY=strict-gamepad
+//   VUINPUTD_LIMITS_MAX_DEVICES_PER_CONTAINER=8
+//
+// Each layer overrides the previous one: defaults < file < environment < command line. This
+// also holds after a reload, which only re-reads the file.
+
+use clap::ValueEnum;
+use log::{warn, LevelFilter};
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::container_runtime::ContainerRuntime;
+use crate::cuse_device::container_rules::{parse_label, ContainerRule};
+use crate::cuse_device::device_id::UsbId;
+use crate::global_config::{
+    Approval, DeviceNamePolicy, DeviceOwner, DevicePolicy, IdPolicy, LifecycleHooks, NodeNaming,
+    Placement, ProtocolDump, ReloadableConfig, SeatMode,
+};
+use crate::input_codes::{code_by_name, code_name};
+use crate::input_realizer::node_acl;
+use crate::input_realizer::udev_properties::UdevPropertyRule;
+use crate::process_tools::container_identity::Engine;
+
+pub const DEFAULT_CONFIG_FILE: &str = "/etc/vuinputd/config.toml";
+
+pub const ENV_PREFIX: &str = "VUINPUTD_";
+
+const EV_KEY: u16 = 0x01;
+
+/// UI_DEV_CREATE is answered only after its retries, see ioctl_error::create_backoff
+const MAX_CREATE_RETRIES: u32 = 8;
+
+// Tables of the file, VUINPUTD_LIMITS_X sets x in [limits]
+const TABLES: [&str; 4] = ["limits", "hooks", "strict-gamepad", "device-names"];
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    pub devname: Option<String>,
+    #[serde(deserialize_with = "value_enum")]
+    pub placement: Option<Placement>,
+    #[serde(deserialize_with = "value_enum")]
+    pub container_runtime: Option<ContainerRuntime>,
+    pub target_container: Option<String>,
+    #[serde(deserialize_with = "value_enum")]
+    pub device_owner: Option<DeviceOwner>,
+    #[serde(deserialize_with = "value_enum")]
+    pub device_policy: Option<DevicePolicy>,
+    #[serde(deserialize_with = "log_level")]
+    pub log_level: Option<LevelFilter>,
+    #[serde(deserialize_with = "value_enum")]
+    pub protocol_dump: Option<ProtocolDump>,
+    pub keystroke_privacy: Option<bool>,
+    #[serde(deserialize_with = "value_enum")]
+    pub device_name_policy: Option<DeviceNamePolicy>,
+    #[serde(deserialize_with = "value_enum")]
+    pub id_policy: Option<IdPolicy>,
+    /// Vendor and product ids id-policy allowlist keeps, e.g. ["045e:028e"]
+    #[serde(deserialize_with = "usb_ids")]
+    pub passthrough_ids: Option<Vec<UsbId>>,
+    /// Accept clients that set up their device by writing a struct uinput_user_dev
+    pub legacy_setup: Option<bool>,
+    /// Seconds the jobs get to finish when vuinputd stops
+    pub shutdown_timeout: Option<u64>,
+    /// User (name or uid) that gets a desktop notification when the device policy refuses a request
+    pub notify_user: Option<String>,
+    /// Seat (e.g. "seat0") whose lock screen blocks the keyboard keys of virtual devices
+    pub block_keys_when_locked: Option<String>,
+    /// Check that new devices did not end up on a seat of the host
+    pub verify_seat: Option<bool>,
+    #[serde(deserialize_with = "value_enum")]
+    pub seat_mode: Option<SeatMode>,
+    /// Seat of the udev rules of vuinputd, e.g. "seat_vuinput"
+    pub seat_name: Option<String>,
+    #[serde(deserialize_with = "value_enum")]
+    pub node_naming: Option<NodeNaming>,
+    /// Give the nodes in containers to the input group of the container (mode 0660)
+    pub input_group: Option<bool>,
+    /// Gids tried when a container has no input group in its /etc/group
+    pub input_gids: Option<Vec<u32>>,
+    /// Owner of the nodes in containers, a user (or uid) of the container
+    pub node_owner: Option<String>,
+    /// Group of the nodes in containers, a group (or gid) of the container; wins over input-group
+    pub node_group: Option<String>,
+    /// Mode of the nodes in containers, e.g. "0660" or 0o660
+    #[serde(deserialize_with = "file_mode")]
+    pub node_mode: Option<u32>,
+    /// Users of the containers (or "@active") that get rw on the nodes through an ACL
+    pub node_acl: Option<Vec<String>>,
+    /// Whether containers need the approval of the host for their devices
+    #[serde(deserialize_with = "value_enum")]
+    pub approval: Option<Approval>,
+    /// Seconds an approval or denial is remembered for a container
+    pub approval_ttl: Option<u64>,
+    /// Rhai script of device-policy "script"
+    pub policy_script: Option<String>,
+    /// Keep the devices of containers across restarts, see cuse_device::persistence
+    pub persist_devices: Option<bool>,
+    /// Address the Prometheus metrics are served on, see metrics
+    pub metrics_listen: Option<String>,
+    /// Offer org.vuinputd.Manager on the system bus, see control::dbus
+    pub dbus: Option<bool>,
+    /// Offer org.vuinputd.Portal for sandboxed apps, see control::portal
+    pub portal: Option<bool>,
+    /// File the Prometheus metrics are written into
+    pub metrics_textfile: Option<PathBuf>,
+    /// File the audit records are appended to, see audit
+    pub audit_log: Option<PathBuf>,
+    /// Send the audit records to the systemd journal
+    pub audit_journal: Option<bool>,
+    /// Device policies by container name or label, see cuse_device::container_rules
+    pub container_policy: Option<Vec<ContainerPolicy>>,
+    /// Properties of the udev events in containers, see input_realizer::udev_properties
+    pub udev_property: Option<Vec<UdevProperty>>,
+    pub limits: Limits,
+    pub hooks: Hooks,
+    pub strict_gamepad: StrictGamepad,
+    pub device_names: DeviceNames,
+}
+
+/// A [[container-policy]] rule, the conditions that are set must all match
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ContainerPolicy {
+    /// Name or id of the container, * and ? are wildcards
+    pub name: Option<String>,
+    pub engine: Option<String>,
+    /// "key" or "key=value"
+    pub label: Option<String>,
+    #[serde(deserialize_with = "value_enum")]
+    pub policy: Option<DevicePolicy>,
+}
+
+/// A [[udev-property]] rule, the conditions that are set must all match
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct UdevProperty {
+    /// Name of the device, * and ? are wildcards
+    pub name: Option<String>,
+    /// vendor:product, e.g. "045e:028e"
+    pub id: Option<String>,
+    /// An empty value removes the property
+    pub properties: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Limits {
+    pub max_devices_per_container: Option<u32>,
+    /// Retries of UI_DEV_CREATE on the host when it fails with EBUSY, EAGAIN or ENOMEM
+    pub create_retries: Option<u32>,
+}
+
+/// Programs run with the event as JSON on stdin, see jobs::run_hook_job
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Hooks {
+    pub on_device_created: Option<String>,
+    pub on_device_removed: Option<String>,
+    pub on_policy_violation: Option<String>,
+    /// Seconds a hook may run before it is killed
+    pub timeout: Option<u64>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct StrictGamepad {
+    /// Replaces the default list, an empty list only allows the gamepad buttons
+    #[serde(deserialize_with = "key_codes")]
+    pub extra_keys: Option<Vec<u16>>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DeviceNames {
+    /// Prefix names with "vuinputd:<container>:"
+    pub prefix: Option<bool>,
+    /// Replaces the default list of names that are denied
+    pub deny: Option<Vec<String>>,
+}
+
+/// Accepts the same names as the command line, e.g. "strict-gamepad"
+fn value_enum<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: ValueEnum,
+{
+    let value = String::deserialize(deserializer)?;
+    T::from_str(&value, false).map(Some).map_err(|_| {
+        let possible_values: Vec<String> = T::value_variants()
+            .iter()
+            .filter_map(|v| v.to_possible_value())
+            .map(|v| v.get_name().to_string())
+            .collect();
+        serde::de::Error::custom(format!(
+            "invalid value '{}', possible values: {}",
+            value,
+            possible_values.join(", ")
+        ))
+    })
+}
+
+fn log_level<'de, D>(deserializer: D) -> Result<Option<LevelFilter>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    LevelFilter::from_str(&value).map(Some).map_err(|_| {
+        serde::de::Error::custom(format!(
+            "invalid log level '{}', possible values: off, error, warn, info, debug, trace",
+            value
+        ))
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeyCode {
+    Code(u16),
+    Name(String),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FileMode {
+    Number(u32),
+    Octal(String),
+}
+
+/// Parses a mode of a file like chmod, e.g. "0660" or "660"
+pub fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("'{}' is not an octal file mode like 0660", mode))
+}
+
+fn file_mode<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match FileMode::deserialize(deserializer)? {
+        FileMode::Number(mode) if mode <= 0o7777 => Ok(Some(mode)),
+        FileMode::Number(mode) => Err(serde::de::Error::custom(format!(
+            "{:o} is not a file mode",
+            mode
+        ))),
+        FileMode::Octal(mode) => parse_mode(&mode)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+/// Key codes given as number or by name, e.g. ["KEY_RECORD", 0x2c1]
+fn key_codes<'de, D>(deserializer: D) -> Result<Option<Vec<u16>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<KeyCode>::deserialize(deserializer)?
+        .into_iter()
+        .map(|key| match key {
+            KeyCode::Code(code) => Ok(code),
+            KeyCode::Name(name) => match code_by_name(&name) {
+                Some((EV_KEY, code)) => Ok(code),
+                _ => Err(serde::de::Error::custom(format!(
+                    "unknown key '{}', expected a KEY_* or BTN_* name or a code",
+                    name
+                ))),
+            },
+        })
+        .collect::<Result<Vec<u16>, D::Error>>()
+        .map(Some)
+}
+
+impl ConfigFile {
+    /// All settings at their built-in defaults, only used to show the effective configuration
+    pub fn defaults() -> ConfigFile {
+        let reloadable = ReloadableConfig::default();
+        ConfigFile {
+            devname: Some("vuinput".to_string()),
+            placement: None,
+            container_runtime: Some(ContainerRuntime::default()),
+            target_container: None,
+            device_owner: Some(DeviceOwner::default()),
+            device_policy: Some(reloadable.policy),
+            log_level: Some(reloadable.log_level),
+            protocol_dump: Some(reloadable.protocol_dump),
+            keystroke_privacy: Some(reloadable.keystroke_privacy),
+            device_name_policy: Some(reloadable.device_name_policy),
+            id_policy: Some(reloadable.id_policy),
+            passthrough_ids: Some(reloadable.passthrough_ids),
+            legacy_setup: Some(reloadable.legacy_setup),
+            shutdown_timeout: Some(reloadable.shutdown_timeout.as_secs()),
+            notify_user: reloadable.notify_user,
+            block_keys_when_locked: reloadable.block_keys_when_locked,
+            verify_seat: Some(reloadable.verify_seat),
+            seat_mode: Some(reloadable.seat_mode),
+            seat_name: Some(reloadable.seat_name),
+            node_naming: Some(reloadable.node_naming),
+            input_group: Some(reloadable.input_group),
+            input_gids: Some(reloadable.input_gids),
+            node_owner: reloadable.node_owner,
+            node_group: reloadable.node_group,
+            node_mode: reloadable.node_mode,
+            node_acl: Some(reloadable.node_acl),
+            approval: Some(reloadable.approval),
+            approval_ttl: Some(reloadable.approval_ttl.as_secs()),
+            policy_script: None,
+            persist_devices: Some(false),
+            metrics_listen: None,
+            dbus: Some(false),
+            portal: Some(false),
+            metrics_textfile: None,
+            audit_log: None,
+            audit_journal: Some(false),
+            container_policy: Some(Vec::new()),
+            udev_property: Some(Vec::new()),
+            limits: Limits {
+                max_devices_per_container: reloadable.max_devices_per_container,
+                create_retries: Some(reloadable.create_retries),
+            },
+            hooks: Hooks {
+                on_device_created: None,
+                on_device_removed: None,
+                on_policy_violation: None,
+                timeout: Some(reloadable.hooks.timeout.as_secs()),
+            },
+            strict_gamepad: StrictGamepad {
+                extra_keys: Some(reloadable.gamepad_extra_keys),
+            },
+            device_names: DeviceNames {
+                prefix: Some(reloadable.name_prefix),
+                deny: Some(reloadable.denied_names),
+            },
+        }
+    }
+
+    pub fn parse(content: &str) -> Result<ConfigFile, String> {
+        let config: ConfigFile = toml::from_str(content).map_err(|e| e.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reads the file. A missing file is only an error, if it has been asked for explicitly.
+    pub fn load(path: &Path, required: bool) -> Result<ConfigFile, String> {
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                ConfigFile::parse(&content).map_err(|e| format!("{}: {}", path.display(), e))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound && !required => Ok(ConfigFile::default()),
+            Err(e) => Err(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    /// Reads the VUINPUTD_* variables. The names are those of the file in upper case with
+    /// underscores, values are parsed like in the file, but strings don't need quotes.
+    pub fn from_env(
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<ConfigFile, String> {
+        let mut config = ConfigFile::default();
+        for (name, value) in vars {
+            let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            let key = key.to_lowercase().replace('_', "-");
+            let entry = env_entry(&key, &value)
+                .or_else(|_| env_entry(&key, &toml::Value::String(value.clone()).to_string()))
+                .map_err(|e| format!("{}: {}", name, e))?;
+            config = config.overridden_by(&entry);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.placement.is_some() && self.container_runtime.is_some() {
+            return Err(
+                "placement and container-runtime cannot be used together (placement is deprecated)"
+                    .into(),
+            );
+        }
+        if let Some(devname) = &self.devname {
+            if devname.is_empty() || devname.contains('/') {
+                return Err(format!("devname '{}' is not a valid device name", devname));
+            }
+        }
+        if self.limits.max_devices_per_container == Some(0) {
+            return Err("limits.max-devices-per-container must be at least 1".into());
+        }
+        if self.limits.create_retries > Some(MAX_CREATE_RETRIES) {
+            return Err(format!(
+                "limits.create-retries must be at most {}",
+                MAX_CREATE_RETRIES
+            ));
+        }
+        if self
+            .policy_script
+            .as_ref()
+            .is_some_and(|p| !Path::new(p).is_absolute())
+        {
+            return Err("policy-script must be an absolute path".into());
+        }
+        if let Some(user) = self.node_acl.iter().flatten().find(|user| {
+            user.is_empty() || (user.starts_with('@') && user.as_str() != node_acl::ACTIVE_USER)
+        }) {
+            return Err(format!(
+                "node-acl: '{}' is no user, use a name, a uid or {}",
+                user,
+                node_acl::ACTIVE_USER
+            ));
+        }
+        for (key, program) in [
+            ("on-device-created", &self.hooks.on_device_created),
+            ("on-device-removed", &self.hooks.on_device_removed),
+            ("on-policy-violation", &self.hooks.on_policy_violation),
+        ] {
+            // hooks run as root, a relative path would be looked up in PATH
+            if program
+                .as_ref()
+                .is_some_and(|p| !Path::new(p).is_absolute())
+            {
+                return Err(format!("hooks.{} must be an absolute path", key));
+            }
+        }
+        if self.hooks.timeout == Some(0) {
+            return Err("hooks.timeout must be at least 1".into());
+        }
+        for (index, rule) in self.container_policy.iter().flatten().enumerate() {
+            if rule.policy.is_none() {
+                return Err(format!("container-policy {}: policy is missing", index + 1));
+            }
+            if rule.name.is_none() && rule.engine.is_none() && rule.label.is_none() {
+                return Err(format!(
+                    "container-policy {}: needs a name, engine or label to match",
+                    index + 1
+                ));
+            }
+            if let Some(engine) = rule
+                .engine
+                .as_ref()
+                .filter(|engine| Engine::from_name(engine).is_none())
+            {
+                return Err(format!(
+                    "container-policy {}: unknown engine '{}', possible values: docker, podman, nspawn, lxc",
+                    index + 1,
+                    engine
+                ));
+            }
+        }
+        for (index, rule) in self.udev_property.iter().flatten().enumerate() {
+            if let Some(Err(e)) = rule.id.as_ref().map(|id| id.parse::<UsbId>()) {
+                return Err(format!("udev-property {}: {}", index + 1, e));
+            }
+            let properties = rule.properties.iter().flatten();
+            if properties.clone().next().is_none() {
+                return Err(format!(
+                    "udev-property {}: properties are missing",
+                    index + 1
+                ));
+            }
+            // the properties end up in the udev database, one per line
+            for (key, value) in properties {
+                let invalid = |c: char| c == '\n' || c == '\0';
+                if key.is_empty()
+                    || key.contains('=')
+                    || key.contains(invalid)
+                    || value.contains(invalid)
+                {
+                    return Err(format!(
+                        "udev-property {}: invalid property '{}'",
+                        index + 1,
+                        key
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the file with all values replaced that are set in `other`
+    pub fn overridden_by(&self, other: &ConfigFile) -> ConfigFile {
+        // placement and container-runtime are two ways to set the same thing
+        let runtime_source = if other.placement.is_some() || other.container_runtime.is_some() {
+            other
+        } else {
+            self
+        };
+        ConfigFile {
+            devname: other.devname.clone().or(self.devname.clone()),
+            placement: runtime_source.placement.clone(),
+            container_runtime: runtime_source.container_runtime.clone(),
+            target_container: other
+                .target_container
+                .clone()
+                .or(self.target_container.clone()),
+            device_owner: other.device_owner.clone().or(self.device_owner.clone()),
+            device_policy: other.device_policy.or(self.device_policy),
+            log_level: other.log_level.or(self.log_level),
+            protocol_dump: other.protocol_dump.or(self.protocol_dump),
+            keystroke_privacy: other.keystroke_privacy.or(self.keystroke_privacy),
+            device_name_policy: other.device_name_policy.or(self.device_name_policy),
+            id_policy: other.id_policy.or(self.id_policy),
+            passthrough_ids: other
+                .passthrough_ids
+                .clone()
+                .or(self.passthrough_ids.clone()),
+            legacy_setup: other.legacy_setup.or(self.legacy_setup),
+            shutdown_timeout: other.shutdown_timeout.or(self.shutdown_timeout),
+            notify_user: other.notify_user.clone().or(self.notify_user.clone()),
+            block_keys_when_locked: other
+                .block_keys_when_locked
+                .clone()
+                .or(self.block_keys_when_locked.clone()),
+            verify_seat: other.verify_seat.or(self.verify_seat),
+            seat_mode: other.seat_mode.or(self.seat_mode),
+            seat_name: other.seat_name.clone().or(self.seat_name.clone()),
+            node_naming: other.node_naming.or(self.node_naming),
+            input_group: other.input_group.or(self.input_group),
+            input_gids: other.input_gids.clone().or(self.input_gids.clone()),
+            node_owner: other.node_owner.clone().or(self.node_owner.clone()),
+            node_group: other.node_group.clone().or(self.node_group.clone()),
+            node_mode: other.node_mode.or(self.node_mode),
+            node_acl: other.node_acl.clone().or(self.node_acl.clone()),
+            approval: other.approval.or(self.approval),
+            approval_ttl: other.approval_ttl.or(self.approval_ttl),
+            policy_script: other.policy_script.clone().or(self.policy_script.clone()),
+            persist_devices: other.persist_devices.or(self.persist_devices),
+            metrics_listen: other.metrics_listen.clone().or(self.metrics_listen.clone()),
+            dbus: other.dbus.or(self.dbus),
+            portal: other.portal.or(self.portal),
+            metrics_textfile: other
+                .metrics_textfile
+                .clone()
+                .or(self.metrics_textfile.clone()),
+            audit_log: other.audit_log.clone().or(self.audit_log.clone()),
+            audit_journal: other.audit_journal.or(self.audit_journal),
+            container_policy: other
+                .container_policy
+                .clone()
+                .or(self.container_policy.clone()),
+            udev_property: other.udev_property.clone().or(self.udev_property.clone()),
+            limits: Limits {
+                max_devices_per_container: other
+                    .limits
+                    .max_devices_per_container
+                    .or(self.limits.max_devices_per_container),
+                create_retries: other.limits.create_retries.or(self.limits.create_retries),
+            },
+            hooks: Hooks {
+                on_device_created: other
+                    .hooks
+                    .on_device_created
+                    .clone()
+                    .or(self.hooks.on_device_created.clone()),
+                on_device_removed: other
+                    .hooks
+                    .on_device_removed
+                    .clone()
+                    .or(self.hooks.on_device_removed.clone()),
+                on_policy_violation: other
+                    .hooks
+                    .on_policy_violation
+                    .clone()
+                    .or(self.hooks.on_policy_violation.clone()),
+                timeout: other.hooks.timeout.or(self.hooks.timeout),
+            },
+            strict_gamepad: StrictGamepad {
+                extra_keys: other
+                    .strict_gamepad
+                    .extra_keys
+                    .clone()
+                    .or(self.strict_gamepad.extra_keys.clone()),
+            },
+            device_names: DeviceNames {
+                prefix: other.device_names.prefix.or(self.device_names.prefix),
+                deny: other
+                    .device_names
+                    .deny
+                    .clone()
+                    .or(self.device_names.deny.clone()),
+            },
+        }
+    }
+
+    /// The settings in the order of the file, keys of tables are prefixed with the table name
+    fn entries(&self) -> Vec<(&'static str, Option<toml::Value>)> {
+        let string = |value: &Option<String>| value.clone().map(toml::Value::String);
+        let name = |value: Option<String>| value.map(toml::Value::String);
+        vec![
+            ("devname", string(&self.devname)),
+            ("placement", name(self.placement.as_ref().map(value_name))),
+            (
+                "container-runtime",
+                name(self.container_runtime.as_ref().map(value_name)),
+            ),
+            ("target-container", string(&self.target_container)),
+            (
+                "device-owner",
+                name(self.device_owner.as_ref().map(value_name)),
+            ),
+            (
+                "device-policy",
+                name(self.device_policy.as_ref().map(value_name)),
+            ),
+            (
+                "log-level",
+                name(self.log_level.map(|l| l.as_str().to_lowercase())),
+            ),
+            (
+                "protocol-dump",
+                name(self.protocol_dump.as_ref().map(value_name)),
+            ),
+            (
+                "keystroke-privacy",
+                self.keystroke_privacy.map(toml::Value::Boolean),
+            ),
+            (
+                "device-name-policy",
+                name(self.device_name_policy.as_ref().map(value_name)),
+            ),
+            ("id-policy", name(self.id_policy.as_ref().map(value_name))),
+            (
+                "passthrough-ids",
+                self.passthrough_ids.as_ref().map(|ids| {
+                    toml::Value::Array(
+                        ids.iter()
+                            .map(|id| toml::Value::String(id.to_string()))
+                            .collect(),
+                    )
+                }),
+            ),
+            ("legacy-setup", self.legacy_setup.map(toml::Value::Boolean)),
+            (
+                "shutdown-timeout",
+                self.shutdown_timeout
+                    .map(|seconds| toml::Value::Integer(seconds.try_into().unwrap_or(i64::MAX))),
+            ),
+            ("notify-user", string(&self.notify_user)),
+            (
+                "block-keys-when-locked",
+                string(&self.block_keys_when_locked),
+            ),
+            ("verify-seat", self.verify_seat.map(toml::Value::Boolean)),
+            ("seat-mode", name(self.seat_mode.as_ref().map(value_name))),
+            ("seat-name", string(&self.seat_name)),
+            (
+                "node-naming",
+                name(self.node_naming.as_ref().map(value_name)),
+            ),
+            ("input-group", self.input_group.map(toml::Value::Boolean)),
+            (
+                "input-gids",
+                self.input_gids.as_ref().map(|gids| {
+                    toml::Value::Array(
+                        gids.iter()
+                            .map(|gid| toml::Value::Integer((*gid).into()))
+                            .collect(),
+                    )
+                }),
+            ),
+            ("node-owner", string(&self.node_owner)),
+            ("node-group", string(&self.node_group)),
+            (
+                "node-mode",
+                self.node_mode
+                    .map(|mode| toml::Value::String(format!("{:04o}", mode))),
+            ),
+            (
+                "node-acl",
+                self.node_acl.as_ref().map(|users| {
+                    toml::Value::Array(
+                        users
+                            .iter()
+                            .map(|user| toml::Value::String(user.clone()))
+                            .collect(),
+                    )
+                }),
+            ),
+            ("approval", name(self.approval.as_ref().map(value_name))),
+            (
+                "approval-ttl",
+                self.approval_ttl
+                    .map(|seconds| toml::Value::Integer(seconds.try_into().unwrap_or(i64::MAX))),
+            ),
+            ("policy-script", string(&self.policy_script)),
+            (
+                "persist-devices",
+                self.persist_devices.map(toml::Value::Boolean),
+            ),
+            ("metrics-listen", string(&self.metrics_listen)),
+            ("dbus", self.dbus.map(toml::Value::Boolean)),
+            ("portal", self.portal.map(toml::Value::Boolean)),
+            (
+                "metrics-textfile",
+                self.metrics_textfile
+                    .as_ref()
+                    .map(|path| toml::Value::String(path.to_string_lossy().into_owned())),
+            ),
+            (
+                "audit-log",
+                self.audit_log
+                    .as_ref()
+                    .map(|path| toml::Value::String(path.to_string_lossy().into_owned())),
+            ),
+            (
+                "audit-journal",
+                self.audit_journal.map(toml::Value::Boolean),
+            ),
+            (
+                "container-policy",
+                self.container_policy.as_ref().map(|rules| {
+                    toml::Value::Array(
+                        rules
+                            .iter()
+                            .map(|rule| {
+                                let mut table = toml::Table::new();
+                                for (key, value) in [
+                                    ("name", rule.name.clone()),
+                                    ("engine", rule.engine.clone()),
+                                    ("label", rule.label.clone()),
+                                    ("policy", rule.policy.as_ref().map(value_name)),
+                                ] {
+                                    if let Some(value) = value {
+                                        table.insert(key.to_string(), toml::Value::String(value));
+                                    }
+                                }
+                                toml::Value::Table(table)
+                            })
+                            .collect(),
+                    )
+                }),
+            ),
+            (
+                "udev-property",
+                self.udev_property.as_ref().map(|rules| {
+                    toml::Value::Array(
+                        rules
+                            .iter()
+                            .map(|rule| {
+                                let mut table = toml::Table::new();
+                                for (key, value) in [("name", &rule.name), ("id", &rule.id)] {
+                                    if let Some(value) = value {
+                                        table.insert(
+                                            key.to_string(),
+                                            toml::Value::String(value.clone()),
+                                        );
+                                    }
+                                }
+                                if let Some(properties) = &rule.properties {
+                                    let properties = properties
+                                        .iter()
+                                        .map(|(key, value)| {
+                                            (key.clone(), toml::Value::String(value.clone()))
+                                        })
+                                        .collect();
+                                    table.insert(
+                                        "properties".to_string(),
+                                        toml::Value::Table(properties),
+                                    );
+                                }
+                                toml::Value::Table(table)
+                            })
+                            .collect(),
+                    )
+                }),
+            ),
+            (
+                "limits.max-devices-per-container",
+                self.limits
+                    .max_devices_per_container
+                    .map(|max| toml::Value::Integer(max.into())),
+            ),
+            (
+                "limits.create-retries",
+                self.limits
+                    .create_retries
+                    .map(|retries| toml::Value::Integer(retries.into())),
+            ),
+            (
+                "hooks.on-device-created",
+                string(&self.hooks.on_device_created),
+            ),
+            (
+                "hooks.on-device-removed",
+                string(&self.hooks.on_device_removed),
+            ),
+            (
+                "hooks.on-policy-violation",
+                string(&self.hooks.on_policy_violation),
+            ),
+            (
+                "hooks.timeout",
+                self.hooks
+                    .timeout
+                    .map(|seconds| toml::Value::Integer(seconds.try_into().unwrap_or(i64::MAX))),
+            ),
+            (
+                "strict-gamepad.extra-keys",
+                self.strict_gamepad.extra_keys.as_ref().map(|keys| {
+                    toml::Value::Array(
+                        keys.iter()
+                            .map(|key| match code_name(EV_KEY, *key) {
+                                Some(name) => toml::Value::String(name.to_string()),
+                                None => toml::Value::Integer((*key).into()),
+                            })
+                            .collect(),
+                    )
+                }),
+            ),
+            (
+                "device-names.prefix",
+                self.device_names.prefix.map(toml::Value::Boolean),
+            ),
+            (
+                "device-names.deny",
+                self.device_names.deny.as_ref().map(|names| {
+                    toml::Value::Array(names.iter().cloned().map(toml::Value::String).collect())
+                }),
+            ),
+        ]
+    }
+
+    /// The settings that can change on reload, with defaults for missing values
+    pub fn reloadable_config(&self) -> ReloadableConfig {
+        let defaults = ReloadableConfig::default();
+        let keystroke_privacy = self.keystroke_privacy.unwrap_or(defaults.keystroke_privacy);
+        let protocol_dump = match self.protocol_dump.unwrap_or(defaults.protocol_dump) {
+            ProtocolDump::Full if keystroke_privacy => {
+                warn!("protocol-dump full requires keystroke-privacy false, dumping redacted buffers instead");
+                ProtocolDump::Redacted
+            }
+            protocol_dump => protocol_dump,
+        };
+        ReloadableConfig {
+            policy: self.device_policy.unwrap_or(defaults.policy),
+            gamepad_extra_keys: self
+                .strict_gamepad
+                .extra_keys
+                .clone()
+                .unwrap_or(defaults.gamepad_extra_keys),
+            protocol_dump,
+            keystroke_privacy,
+            device_name_policy: self
+                .device_name_policy
+                .unwrap_or(defaults.device_name_policy),
+            name_prefix: self.device_names.prefix.unwrap_or(defaults.name_prefix),
+            denied_names: self
+                .device_names
+                .deny
+                .clone()
+                .unwrap_or(defaults.denied_names),
+            id_policy: self.id_policy.unwrap_or(defaults.id_policy),
+            passthrough_ids: self
+                .passthrough_ids
+                .clone()
+                .unwrap_or(defaults.passthrough_ids),
+            legacy_setup: self.legacy_setup.unwrap_or(defaults.legacy_setup),
+            log_level: self.log_level.unwrap_or(defaults.log_level),
+            max_devices_per_container: self.limits.max_devices_per_container,
+            create_retries: self
+                .limits
+                .create_retries
+                .unwrap_or(defaults.create_retries),
+            shutdown_timeout: self
+                .shutdown_timeout
+                .map_or(defaults.shutdown_timeout, Duration::from_secs),
+            notify_user: self.notify_user.clone(),
+            block_keys_when_locked: self.block_keys_when_locked.clone(),
+            verify_seat: self.verify_seat.unwrap_or(defaults.verify_seat),
+            seat_mode: self.seat_mode.unwrap_or(defaults.seat_mode),
+            seat_name: self.seat_name.clone().unwrap_or(defaults.seat_name),
+            node_naming: self.node_naming.unwrap_or(defaults.node_naming),
+            input_group: self.input_group.unwrap_or(defaults.input_group),
+            input_gids: self.input_gids.clone().unwrap_or(defaults.input_gids),
+            node_owner: self.node_owner.clone(),
+            node_group: self.node_group.clone(),
+            node_mode: self.node_mode,
+            node_acl: self.node_acl.clone().unwrap_or(defaults.node_acl),
+            approval: self.approval.unwrap_or(defaults.approval),
+            approval_ttl: self
+                .approval_ttl
+                .map_or(defaults.approval_ttl, Duration::from_secs),
+            policy_script: self.policy_script.as_ref().map(PathBuf::from),
+            container_rules: self
+                .container_policy
+                .iter()
+                .flatten()
+                .filter_map(|rule| {
+                    Some(ContainerRule {
+                        name: rule.name.clone(),
+                        engine: rule.engine.as_deref().and_then(Engine::from_name),
+                        label: rule.label.as_deref().map(parse_label),
+                        policy: rule.policy?,
+                    })
+                })
+                .collect(),
+            udev_property_rules: self
+                .udev_property
+                .iter()
+                .flatten()
+                .map(|rule| UdevPropertyRule {
+                    name: rule.name.clone(),
+                    id: rule.id.as_ref().and_then(|id| id.parse().ok()),
+                    properties: rule.properties.clone().unwrap_or_default(),
+                })
+                .collect(),
+            hooks: LifecycleHooks {
+                on_device_created: self.hooks.on_device_created.as_ref().map(PathBuf::from),
+                on_device_removed: self.hooks.on_device_removed.as_ref().map(PathBuf::from),
+                on_policy_violation: self.hooks.on_policy_violation.as_ref().map(PathBuf::from),
+                timeout: self
+                    .hooks
+                    .timeout
+                    .map_or(defaults.hooks.timeout, Duration::from_secs),
+            },
+        }
+    }
+
+    /// Names of the settings that differ and would need a restart to take effect
+    pub fn changes_requiring_restart(&self, other: &ConfigFile) -> Vec<&'static str> {
+        let mut changes = Vec::new();
+        if self.devname != other.devname {
+            changes.push("devname");
+        }
+        if self.placement != other.placement || self.container_runtime != other.container_runtime {
+            changes.push("container-runtime");
+        }
+        if self.target_container != other.target_container {
+            changes.push("target-container");
+        }
+        if self.device_owner != other.device_owner {
+            changes.push("device-owner");
+        }
+        if self.persist_devices != other.persist_devices {
+            changes.push("persist-devices");
+        }
+        if self.metrics_listen != other.metrics_listen {
+            changes.push("metrics-listen");
+        }
+        if self.dbus != other.dbus {
+            changes.push("dbus");
+        }
+        if self.portal != other.portal {
+            changes.push("portal");
+        }
+        if self.metrics_textfile != other.metrics_textfile {
+            changes.push("metrics-textfile");
+        }
+        if self.audit_log != other.audit_log {
+            changes.push("audit-log");
+        }
+        if self.audit_journal != other.audit_journal {
+            changes.push("audit-journal");
+        }
+        changes
+    }
+}
+
+/// Parses a single setting of the environment, e.g. ("limits-max-devices-per-container", "8")
+fn env_entry(key: &str, value: &str) -> Result<ConfigFile, String> {
+    let (table, key) = match TABLES.iter().find_map(|table| {
+        key.strip_prefix(table)
+            .and_then(|key| key.strip_prefix('-'))
+            .map(|key| (*table, key))
+    }) {
+        Some((table, key)) => (Some(table), key),
+        None => (None, key),
+    };
+    let entry = format!("{} = {}", key, value);
+    let content = match table {
+        Some(table) => format!("[{}]\n{}", table, entry),
+        None => entry,
+    };
+    toml::from_str(&content).map_err(|e| toml::de::Error::message(&e).to_string())
+}
+
+pub fn value_name<T: ValueEnum>(value: &T) -> String {
+    value
+        .to_possible_value()
+        .expect("no skipped variants")
+        .get_name()
+        .to_string()
+}
+
+/// Where a setting comes from, in the order of precedence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigSource {
+    Default,
+    File,
+    Environment,
+    CommandLine,
+}
+
+impl std::fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigSource::Default => write!(f, "default"),
+            ConfigSource::File => write!(f, "file"),
+            ConfigSource::Environment => write!(f, "environment"),
+            ConfigSource::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// Renders the effective configuration as a configuration file, every value annotated with
+/// the layer it comes from. Later layers take precedence.
+pub fn render_effective(layers: &[(ConfigSource, &ConfigFile)]) -> String {
+    let entries: Vec<_> = layers
+        .iter()
+        .map(|(source, config)| (*source, config.entries()))
+        .collect();
+    let mut output = String::new();
+    let mut current_table = None;
+    for (index, (key, _)) in entries[0].1.iter().enumerate() {
+        let (table, name) = match key.split_once('.') {
+            Some((table, name)) => (Some(table), name),
+            None => (None, *key),
+        };
+        if table != current_table {
+            output.push_str(&format!("\n[{}]\n", table.unwrap_or_default()));
+            current_table = table;
+        }
+        let value = entries
+            .iter()
+            .rev()
+            .find_map(|(source, entries)| entries[index].1.as_ref().map(|value| (source, value)));
+        match value {
+            Some((source, value)) => {
+                output.push_str(&format!("{} = {} # {}\n", name, value, source))
+            }
+            None => output.push_str(&format!("# {} is not set\n", name)),
+        }
+    }
+    output
+}
+
+/// Ids as shown by lsusb, e.g. ["045e:028e"]
+fn usb_ids<'de, D>(deserializer: D) -> Result<Option<Vec<UsbId>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|id| id.parse().map_err(serde::de::Error::custom))
+        .collect::<Result<Vec<UsbId>, D::Error>>()
+        .map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_all_knobs() {
+        let config = ConfigFile::parse(
+            r#"
+            devname = "vuinput-a"
+            container-runtime = "generic-placement-on-host"
+            device-policy = "strict-gamepad"
+            log-level = "info"
+            keystroke-privacy = false
+            shutdown-timeout = 30
+            notify-user = "alice"
+            block-keys-when-locked = "seat0"
+            verify-seat = true
+            seat-mode = "per-container"
+            seat-name = "seat_games"
+            node-naming = "container-local"
+            input-group = true
+            input-gids = [104, 995]
+            node-owner = "gamer"
+            node-group = "games"
+            node-mode = "0640"
+            node-acl = ["@active", "gamer"]
+            approval = "prompt"
+            approval-ttl = 600
+            policy-script = "/etc/vuinputd/policy.rhai"
+            persist-devices = true
+            metrics-listen = "127.0.0.1:9812"
+            dbus = true
+            portal = true
+            metrics-textfile = "/var/lib/node_exporter/vuinputd.prom"
+            audit-log = "/var/log/vuinputd/audit.jsonl"
+            audit-journal = true
+            id-policy = "allowlist"
+            passthrough-ids = ["045e:028e", "28DE:1205"]
+            legacy-setup = false
+
+            [[container-policy]]
+            name = "steam-*"
+            policy = "strict-gamepad"
+
+            [[container-policy]]
+            engine = "docker"
+            label = "com.example.trusted"
+            policy = "none"
+
+            [[udev-property]]
+            name = "Sunshine*"
+            properties = { LIBINPUT_DEVICE_GROUP = "sunshine", ID_BUS = "" }
+
+            [limits]
+            max-devices-per-container = 4
+            create-retries = 3
+
+            [hooks]
+            on-device-created = "/usr/local/bin/open-port"
+            timeout = 2
+
+            [device-names]
+            prefix = true
+            deny = ["Power Button"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.devname.as_deref(), Some("vuinput-a"));
+        assert_eq!(config.persist_devices, Some(true));
+        assert_eq!(config.metrics_listen.as_deref(), Some("127.0.0.1:9812"));
+        assert_eq!(config.dbus, Some(true));
+        assert_eq!(config.portal, Some(true));
+        assert_eq!(config.audit_journal, Some(true));
+        assert_eq!(
+            config.container_runtime,
+            Some(ContainerRuntime::GenericPlacementOnHost)
+        );
+        let reloadable = config.reloadable_config();
+        assert_eq!(reloadable.policy, DevicePolicy::StrictGamepad);
+        assert_eq!(reloadable.log_level, LevelFilter::Info);
+        assert!(!reloadable.keystroke_privacy);
+        assert!(!reloadable.legacy_setup);
+        assert_eq!(reloadable.max_devices_per_container, Some(4));
+        assert_eq!(reloadable.create_retries, 3);
+        assert_eq!(reloadable.shutdown_timeout, Duration::from_secs(30));
+        assert_eq!(reloadable.notify_user.as_deref(), Some("alice"));
+        assert_eq!(reloadable.block_keys_when_locked.as_deref(), Some("seat0"));
+        assert!(reloadable.verify_seat);
+        assert_eq!(reloadable.seat_mode, SeatMode::PerContainer);
+        assert_eq!(reloadable.seat_name, "seat_games");
+        assert_eq!(reloadable.node_naming, NodeNaming::ContainerLocal);
+        assert!(reloadable.input_group);
+        assert_eq!(reloadable.input_gids, [104, 995]);
+        assert_eq!(reloadable.node_owner.as_deref(), Some("gamer"));
+        assert_eq!(reloadable.node_group.as_deref(), Some("games"));
+        assert_eq!(reloadable.node_mode, Some(0o640));
+        assert_eq!(reloadable.node_acl, ["@active", "gamer"]);
+        assert_eq!(reloadable.approval, Approval::Prompt);
+        assert_eq!(reloadable.approval_ttl, Duration::from_secs(600));
+        assert_eq!(
+            reloadable.policy_script,
+            Some(PathBuf::from("/etc/vuinputd/policy.rhai"))
+        );
+        assert_eq!(
+            reloadable.hooks.on_device_created,
+            Some(PathBuf::from("/usr/local/bin/open-port"))
+        );
+        assert_eq!(reloadable.hooks.on_device_removed, None);
+        assert_eq!(reloadable.hooks.timeout, Duration::from_secs(2));
+        assert_eq!(reloadable.id_policy, IdPolicy::Allowlist);
+        assert!(reloadable.name_prefix);
+        assert_eq!(reloadable.denied_names, vec!["Power Button".to_string()]);
+        assert_eq!(
+            reloadable.container_rules,
+            [
+                ContainerRule {
+                    name: Some("steam-*".to_string()),
+                    engine: None,
+                    label: None,
+                    policy: DevicePolicy::StrictGamepad,
+                },
+                ContainerRule {
+                    name: None,
+                    engine: Some(Engine::Docker),
+                    label: Some(("com.example.trusted".to_string(), None)),
+                    policy: DevicePolicy::None,
+                },
+            ]
+        );
+        assert_eq!(
+            reloadable.udev_property_rules,
+            [UdevPropertyRule {
+                name: Some("Sunshine*".to_string()),
+                id: None,
+                properties: BTreeMap::from([
+                    ("ID_BUS".to_string(), String::new()),
+                    ("LIBINPUT_DEVICE_GROUP".to_string(), "sunshine".to_string()),
+                ]),
+            }]
+        );
+        assert_eq!(
+            reloadable.passthrough_ids,
+            vec![
+                UsbId {
+                    vendor: 0x045e,
+                    product: 0x028e
+                },
+                UsbId {
+                    vendor: 0x28de,
+                    product: 0x1205
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        assert!(ConfigFile::parse("device-policy = \"everything\"")
+            .unwrap_err()
+            .contains("possible values: none, mute-sys-rq"));
+        assert!(ConfigFile::parse("unknown-key = 1").is_err());
+        assert!(
+            ConfigFile::parse("placement = \"on-host\"\ncontainer-runtime = \"docker\"").is_err()
+        );
+        assert!(ConfigFile::parse("[limits]\nmax-devices-per-container = 0").is_err());
+        assert!(ConfigFile::parse("[limits]\ncreate-retries = 9").is_err());
+        assert!(
+            ConfigFile::parse("[hooks]\non-device-removed = \"close-port\"")
+                .unwrap_err()
+                .contains("must be an absolute path")
+        );
+        assert!(ConfigFile::parse("[hooks]\ntimeout = 0").is_err());
+        assert!(ConfigFile::parse("policy-script = \"policy.rhai\"").is_err());
+        assert!(
+            ConfigFile::parse("[[container-policy]]\nname = \"steam-*\"")
+                .unwrap_err()
+                .contains("policy is missing")
+        );
+        assert!(ConfigFile::parse("[[container-policy]]\npolicy = \"none\"").is_err());
+        assert!(
+            ConfigFile::parse("[[container-policy]]\nengine = \"k8s\"\npolicy = \"none\"").is_err()
+        );
+        assert!(ConfigFile::parse("[[udev-property]]\nname = \"Pad\"")
+            .unwrap_err()
+            .contains("properties are missing"));
+        assert!(ConfigFile::parse(
+            "[[udev-property]]\nid = \"45e:28e\"\nproperties = { A = \"1\" }"
+        )
+        .is_err());
+        assert!(ConfigFile::parse("[[udev-property]]\nproperties = { A = \"1\\nB=2\" }").is_err());
+        assert!(
+            ConfigFile::parse("[strict-gamepad]\nextra-keys = [\"REL_X\"]")
+                .unwrap_err()
+                .contains("unknown key 'REL_X'")
+        );
+        assert!(ConfigFile::parse("passthrough-ids = [\"045e\"]")
+            .unwrap_err()
+            .contains("not a vendor:product id"));
+        assert!(ConfigFile::parse("node-mode = \"0999\"")
+            .unwrap_err()
+            .contains("not an octal file mode"));
+        assert!(ConfigFile::parse("node-mode = 0o17777").is_err());
+        assert!(ConfigFile::parse("node-acl = [\"@seat\"]").is_err());
+    }
+
+    #[test]
+    fn gamepad_extra_keys() {
+        let defaults = ConfigFile::default().reloadable_config();
+        assert_eq!(defaults.gamepad_extra_keys, vec![167]);
+
+        let config =
+            ConfigFile::parse("[strict-gamepad]\nextra-keys = [\"KEY_HOMEPAGE\", 0x2c1]").unwrap();
+        assert_eq!(
+            config.reloadable_config().gamepad_extra_keys,
+            vec![172, 0x2c1]
+        );
+        let config = ConfigFile::parse("[strict-gamepad]\nextra-keys = []").unwrap();
+        assert!(config.reloadable_config().gamepad_extra_keys.is_empty());
+    }
+
+    #[test]
+    fn environment() {
+        let vars = [
+            ("PATH", "/usr/bin"),
+            ("VUINPUTD_DEVICE_POLICY", "sanitized"),
+            ("VUINPUTD_DEVNAME", "1234"),
+            ("VUINPUTD_KEYSTROKE_PRIVACY", "false"),
+            ("VUINPUTD_LIMITS_MAX_DEVICES_PER_CONTAINER", "8"),
+            ("VUINPUTD_STRICT_GAMEPAD_EXTRA_KEYS", "[\"KEY_MENU\"]"),
+            ("VUINPUTD_NODE_MODE", "0640"),
+            (
+                "VUINPUTD_HOOKS_ON_POLICY_VIOLATION",
+                "/usr/local/bin/report",
+            ),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        let config = ConfigFile::from_env(vars).unwrap();
+        assert_eq!(config.device_policy, Some(DevicePolicy::Sanitized));
+        assert_eq!(config.devname.as_deref(), Some("1234"));
+        assert_eq!(config.keystroke_privacy, Some(false));
+        assert_eq!(config.limits.max_devices_per_container, Some(8));
+        assert_eq!(config.strict_gamepad.extra_keys, Some(vec![139]));
+        assert_eq!(config.node_mode, Some(0o640));
+        assert_eq!(
+            config.hooks.on_policy_violation.as_deref(),
+            Some("/usr/local/bin/report")
+        );
+
+        let error =
+            ConfigFile::from_env([("VUINPUTD_KEYSTROKE_PRIVACY".to_string(), "yes".to_string())])
+                .unwrap_err();
+        assert!(
+            error.starts_with("VUINPUTD_KEYSTROKE_PRIVACY: "),
+            "{}",
+            error
+        );
+        assert!(ConfigFile::from_env([("VUINPUTD_UNKNOWN".to_string(), "1".to_string())]).is_err());
+    }
+
+    #[test]
+    fn renders_the_effective_configuration() {
+        let defaults = ConfigFile::defaults();
+        let file = ConfigFile::parse(
+            "device-policy = \"sanitized\"\n[[container-policy]]\nname = \"steam-*\"\npolicy = \"none\"",
+        )
+        .unwrap();
+        let command_line = ConfigFile {
+            device_policy: Some(DevicePolicy::StrictGamepad),
+            keystroke_privacy: Some(false),
+            ..Default::default()
+        };
+        let rendered = render_effective(&[
+            (ConfigSource::Default, &defaults),
+            (ConfigSource::File, &file),
+            (ConfigSource::CommandLine, &command_line),
+        ]);
+        assert!(rendered.starts_with("devname = \"vuinput\" # default\n# placement is not set\n"));
+        assert!(rendered.contains("device-policy = \"strict-gamepad\" # command line\n"));
+        assert!(rendered.contains("keystroke-privacy = false # command line\n"));
+        assert!(rendered.contains("\n[limits]\n# max-devices-per-container is not set\n"));
+        assert!(rendered.contains("\n[hooks]\n# on-device-created is not set\n"));
+        assert!(rendered.contains("timeout = 5 # default\n"));
+        assert!(rendered.contains("\n[strict-gamepad]\nextra-keys = [\"KEY_RECORD\"] # default\n"));
+        assert!(rendered.ends_with("[device-names]\nprefix = false # default\ndeny = [\"AT Translated Set 2 keyboard\", \"Power Button\", \"Sleep Button\", \"Lid Switch\"] # default\n"));
+        assert!(rendered
+            .contains("container-policy = [{ name = \"steam-*\", policy = \"none\" }] # file\n"));
+        // the output can be used as a configuration file
+        let parsed = ConfigFile::parse(&rendered).unwrap();
+        assert_eq!(parsed.device_policy, Some(DevicePolicy::StrictGamepad));
+        assert_eq!(parsed.container_policy, file.container_policy);
+    }
+
+    #[test]
+    fn command_line_wins() {
+        let file =
+            ConfigFile::parse("device-policy = \"sanitized\"\nlog-level = \"warn\"").unwrap();
+        let command_line = ConfigFile {
+            device_policy: Some(DevicePolicy::None),
+            ..Default::default()
+        };
+        let merged = file.overridden_by(&command_line);
+        assert_eq!(merged.device_policy, Some(DevicePolicy::None));
+        assert_eq!(merged.log_level, Some(LevelFilter::Warn));
+        assert_eq!(merged.changes_requiring_restart(&file), Vec::<&str>::new());
+        assert_eq!(
+            merged.changes_requiring_restart(&ConfigFile {
+                devname: Some("other".to_string()),
+                ..Default::default()
+            }),
+            vec!["devname"]
+        );
+    }
+}
@@ -3,23 +3,53 @@
 // Author: Johannes Leupolz <dev@leupolz.eu>
 
 use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
 
-use anyhow::bail;
+use anyhow::{anyhow, bail, Context};
 use async_trait::async_trait;
 
 use crate::{
     actions::action::Action,
     global_config::{self, get_scope},
-    input_realizer::{input_device, runtime_data},
+    input_realizer::{
+        input_device,
+        node_names::{existing_node, node_path, ExistingNode},
+        node_owner, runtime_data,
+    },
     process_tools::{self, Pid, RequestingProcess},
 };
 pub static PLACEMENT_IN_CONTAINER: GenericPlacementInContainer = GenericPlacementInContainer {};
 pub static PLACEMENT_ON_HOST: GenericPlacementOnHost = GenericPlacementOnHost {};
 pub static SEND_NETLINK_ONLY: GenericSendNetlinkMessageOnly = GenericSendNetlinkMessageOnly {};
 pub static INCUS: Incus = Incus {};
+pub static SIMULATED: SimulatedPlacement = SimulatedPlacement {};
 
 #[async_trait]
-pub trait InjectionStrategy {
+pub trait InjectionStrategy: Sync {
+    /// Whether the node `devname` is free in the container or already the node of
+    /// major:minor, see node_names. Strategies that leave the nodes to others can use any name.
+    fn node_is_usable(
+        &self,
+        _requesting_process: &RequestingProcess,
+        _devname: &str,
+        _major: u64,
+        _minor: u64,
+    ) -> bool {
+        true
+    }
+
+    /// Whether mknod_device_node creates the node, so that its name can differ from the host
+    fn creates_nodes(&self) -> bool {
+        true
+    }
+
+    /// Whether hidraw nodes reach the container, which live in /dev and not in /dev/input
+    fn places_hidraw_nodes(&self) -> bool {
+        self.creates_nodes()
+    }
+
     /// Create the device node.
     async fn mknod_device_node(
         &self,
@@ -61,15 +91,61 @@ pub trait InjectionStrategy {
         requesting_process: &RequestingProcess,
         netlink_message: HashMap<String, String>,
     ) -> anyhow::Result<()>;
+
+    /// Have the kernel send the event in the container, for a udevd of the container, see
+    /// container_udevd.
+    async fn emit_kernel_uevent(
+        &self,
+        _requesting_process: &RequestingProcess,
+        _netlink_message: HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        bail!("this placement can't send kernel uevents")
+    }
+}
+
+/// Runs `action` in the container and fails if it does
+async fn run_action(
+    action: Action,
+    requesting_process: &RequestingProcess,
+    enter_user_ns: bool,
+) -> anyhow::Result<()> {
+    let child_pid = process_tools::start_action(action, requesting_process, enter_user_ns)?;
+    match process_tools::await_process(Pid::Pid(child_pid)).await? {
+        0 => Ok(()),
+        status => bail!("the action exited with status {}", status),
+    }
 }
 
 pub struct GenericPlacementInContainer {}
 pub struct GenericPlacementOnHost {}
 pub struct GenericSendNetlinkMessageOnly {}
 pub struct Incus {}
+/// Writes what would end up in the container below the --simulate directory. Device
+/// nodes are plain files containing major and minor, as mknod needs privileges.
+pub struct SimulatedPlacement {}
+
+/// The node as vuinputd sees it, through the root of the container
+fn node_in_container(requesting_process: &RequestingProcess, devname: &str) -> String {
+    format!(
+        "{}/root{}",
+        requesting_process.pid_requestor_root.path(),
+        node_path(devname)
+    )
+}
 
 #[async_trait]
 impl InjectionStrategy for GenericPlacementInContainer {
+    fn node_is_usable(
+        &self,
+        requesting_process: &RequestingProcess,
+        devname: &str,
+        major: u64,
+        minor: u64,
+    ) -> bool {
+        let path = node_in_container(requesting_process, devname);
+        existing_node(Path::new(&path), major, minor) != ExistingNode::Foreign
+    }
+
     async fn mknod_device_node(
         &self,
         requesting_process: &RequestingProcess,
@@ -78,19 +154,12 @@ impl InjectionStrategy for GenericPlacementInContainer {
         minor: u64,
     ) -> anyhow::Result<()> {
         let mknod_device_action = Action::MknodDevice {
-            path: format!("/dev/input/{}", &devname),
+            path: node_path(devname),
             major: major,
             minor: minor,
+            ownership: node_owner::node_ownership(requesting_process),
         };
-
-        let child_pid =
-            process_tools::start_action(mknod_device_action, &requesting_process, false)
-                .expect("subprocess should work");
-
-        let _exit_info = process_tools::await_process(Pid::Pid(child_pid))
-            .await
-            .unwrap();
-        Ok(())
+        run_action(mknod_device_action, requesting_process, false).await
     }
 
     async fn remove_device_node(
@@ -100,7 +169,7 @@ impl InjectionStrategy for GenericPlacementInContainer {
         major: u64,
         minor: u64,
     ) -> anyhow::Result<()> {
-        let dev_path = format!("/dev/input/{}", devname);
+        let dev_path = node_path(devname);
         let remove_device_action = Action::RemoveDevice {
             path: dev_path,
             major: major,
@@ -174,20 +243,54 @@ impl InjectionStrategy for GenericPlacementInContainer {
         let _exit_info = process_tools::await_process(Pid::Pid(child_pid)).await;
         Ok(())
     }
+
+    async fn emit_kernel_uevent(
+        &self,
+        requesting_process: &RequestingProcess,
+        netlink_message: HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        run_action(
+            Action::EmitKernelUevent { netlink_message },
+            requesting_process,
+            false,
+        )
+        .await
+    }
 }
 
 #[async_trait]
 impl InjectionStrategy for GenericPlacementOnHost {
-    async fn mknod_device_node(
+    fn node_is_usable(
         &self,
         _requesting_process: &RequestingProcess,
         devname: &str,
         major: u64,
         minor: u64,
+    ) -> bool {
+        let path = format!(
+            "/run/vuinputd/{}/dev-input/{}",
+            global_config::get_vudevname(),
+            devname
+        );
+        existing_node(Path::new(&path), major, minor) != ExistingNode::Foreign
+    }
+
+    /// Only dev-input is bind-mounted into the container
+    fn places_hidraw_nodes(&self) -> bool {
+        false
+    }
+
+    async fn mknod_device_node(
+        &self,
+        requesting_process: &RequestingProcess,
+        devname: &str,
+        major: u64,
+        minor: u64,
     ) -> anyhow::Result<()> {
         let path_prefix = format!("/run/vuinputd/{}", global_config::get_vudevname());
         let path = format!("{}/dev-input/{}", path_prefix, devname);
-        input_device::ensure_input_device(path.clone(), major, minor)
+        let ownership = node_owner::node_ownership(requesting_process);
+        input_device::ensure_input_device(path.clone(), major, minor, &ownership)
             .expect(&format!("VUI-DEV-001: could not create {}", &path));
         //TODO: somewhat costly
         Ok(())
@@ -249,10 +352,24 @@ impl InjectionStrategy for GenericPlacementOnHost {
             .emit_netlink_message(requesting_process, netlink_message)
             .await
     }
+
+    async fn emit_kernel_uevent(
+        &self,
+        requesting_process: &RequestingProcess,
+        netlink_message: HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        PLACEMENT_IN_CONTAINER
+            .emit_kernel_uevent(requesting_process, netlink_message)
+            .await
+    }
 }
 
 #[async_trait]
 impl InjectionStrategy for GenericSendNetlinkMessageOnly {
+    fn creates_nodes(&self) -> bool {
+        false
+    }
+
     async fn mknod_device_node(
         &self,
         _requesting_process: &RequestingProcess,
@@ -302,10 +419,30 @@ impl InjectionStrategy for GenericSendNetlinkMessageOnly {
             .emit_netlink_message(requesting_process, netlink_message)
             .await
     }
+
+    async fn emit_kernel_uevent(
+        &self,
+        requesting_process: &RequestingProcess,
+        netlink_message: HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        PLACEMENT_IN_CONTAINER
+            .emit_kernel_uevent(requesting_process, netlink_message)
+            .await
+    }
 }
 
 #[async_trait]
 impl InjectionStrategy for Incus {
+    fn node_is_usable(
+        &self,
+        requesting_process: &RequestingProcess,
+        devname: &str,
+        major: u64,
+        minor: u64,
+    ) -> bool {
+        PLACEMENT_IN_CONTAINER.node_is_usable(requesting_process, devname, major, minor)
+    }
+
     async fn mknod_device_node(
         &self,
         _requesting_process: &RequestingProcess,
@@ -313,8 +450,8 @@ impl InjectionStrategy for Incus {
         _major: u64,
         _minor: u64,
     ) -> anyhow::Result<()> {
-        let hostpath = format!("path=/dev/input/{}", devname);
-        let incuspath = format!("path=/dev/input/{}", devname);
+        let hostpath = format!("path={}", node_path(devname));
+        let incuspath = format!("path={}", node_path(devname));
         let container_name = get_scope();
         let container_name = match container_name {
             global_config::Scope::Multi => bail!("no container name given"),
@@ -402,4 +539,105 @@ impl InjectionStrategy for Incus {
         let _exit_info = process_tools::await_process(Pid::Pid(child_pid)).await;
         Ok(())
     }
+
+    async fn emit_kernel_uevent(
+        &self,
+        requesting_process: &RequestingProcess,
+        netlink_message: HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        run_action(
+            Action::EmitKernelUevent { netlink_message },
+            requesting_process,
+            true,
+        )
+        .await
+    }
+}
+
+fn simulation_dir() -> anyhow::Result<&'static Path> {
+    global_config::get_simulation_dir().ok_or_else(|| anyhow!("vuinputd runs without --simulate"))
+}
+
+#[async_trait]
+impl InjectionStrategy for SimulatedPlacement {
+    /// The simulated nodes are files with major and minor, as written by mknod_device_node
+    fn node_is_usable(
+        &self,
+        _requesting_process: &RequestingProcess,
+        devname: &str,
+        major: u64,
+        minor: u64,
+    ) -> bool {
+        let Ok(dir) = simulation_dir() else {
+            return true;
+        };
+        let path = dir.join(node_path(devname).trim_start_matches('/'));
+        match fs::read_to_string(&path) {
+            Ok(node) => node == format!("c {}:{}\n", major, minor),
+            Err(_) => !path.exists(),
+        }
+    }
+
+    async fn mknod_device_node(
+        &self,
+        _requesting_process: &RequestingProcess,
+        devname: &str,
+        major: u64,
+        minor: u64,
+    ) -> anyhow::Result<()> {
+        let path = simulation_dir()?.join(node_path(devname).trim_start_matches('/'));
+        fs::write(&path, format!("c {}:{}\n", major, minor))
+            .with_context(|| format!("could not create {}", path.display()))
+    }
+
+    async fn remove_device_node(
+        &self,
+        _requesting_process: &RequestingProcess,
+        devname: &str,
+        _major: u64,
+        _minor: u64,
+    ) -> anyhow::Result<()> {
+        let path = simulation_dir()?.join(node_path(devname).trim_start_matches('/'));
+        fs::remove_file(&path).with_context(|| format!("could not remove {}", path.display()))
+    }
+
+    async fn write_udev_runtime_data(
+        &self,
+        _requesting_process: &RequestingProcess,
+        runtime_data: &str,
+        major: u64,
+        minor: u64,
+    ) -> anyhow::Result<()> {
+        let path_prefix = simulation_dir()?.join("run");
+        runtime_data::write_udev_data(&path_prefix.to_string_lossy(), runtime_data, major, minor)
+            .with_context(|| format!("could not write into {}", path_prefix.display()))
+    }
+
+    async fn remove_udev_runtime_data(
+        &self,
+        _requesting_process: &RequestingProcess,
+        major: u64,
+        minor: u64,
+    ) -> anyhow::Result<()> {
+        let path_prefix = simulation_dir()?.join("run");
+        runtime_data::delete_udev_data(&path_prefix.to_string_lossy(), major, minor)
+            .with_context(|| format!("could not remove udev data from {}", path_prefix.display()))
+    }
+
+    /// Appends the message to netlink.log, one line per message with sorted properties.
+    async fn emit_netlink_message(
+        &self,
+        _requesting_process: &RequestingProcess,
+        netlink_message: HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        let path = simulation_dir()?.join("netlink.log");
+        let mut properties: Vec<String> = netlink_message
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        properties.sort();
+        let mut log = OpenOptions::new().create(true).append(true).open(&path)?;
+        writeln!(log, "{}", properties.join(" "))
+            .with_context(|| format!("could not write into {}", path.display()))
+    }
 }
@@ -4,9 +4,9 @@
 
 use crate::{
     container_runtime::injection_strategy::{
-        GenericPlacementInContainer, GenericPlacementOnHost, GenericSendNetlinkMessageOnly, INCUS, InjectionStrategy, PLACEMENT_IN_CONTAINER, PLACEMENT_ON_HOST, SEND_NETLINK_ONLY
+        INCUS, InjectionStrategy, PLACEMENT_IN_CONTAINER, PLACEMENT_ON_HOST, SEND_NETLINK_ONLY, SIMULATED
     },
-    global_config::get_vudevname,
+    global_config::{get_simulation_dir, get_vudevname},
 };
 
 pub mod injection_strategy;
@@ -35,10 +35,13 @@ pub enum ContainerRuntime {
     Bubblewrap,
     /// Custom engine, please define a --strategie-file
     CustomEngine,
+    /// Set by --simulate, not selectable on its own. Writes into the simulation directory.
+    #[value(skip)]
+    Simulated,
 }
 
 impl ContainerRuntime {
-    fn uses_run_folder(&self) -> bool {
+    pub fn uses_run_folder(&self) -> bool {
         match self {
             ContainerRuntime::Auto => false,
             ContainerRuntime::GenericPlacementInContainer => false,
@@ -50,13 +53,21 @@ impl ContainerRuntime {
             ContainerRuntime::Nspawn => false,
             ContainerRuntime::Bubblewrap => true,
             ContainerRuntime::CustomEngine => false,
+            ContainerRuntime::Simulated => false,
         }
     }
 
     pub fn initialize(&self) {
         if self.uses_run_folder() {
             let path_prefix = format!("/run/vuinputd/{}", get_vudevname());
-            let _ = crate::input_realizer::host_fs::ensure_host_fs_structure(&path_prefix);
+            if let Err(e) = crate::input_realizer::host_fs::ensure_host_fs_structure(&path_prefix) {
+                log::error!("could not prepare {}: {}", path_prefix, e);
+            }
+        }
+        if let Some(simulation_dir) = get_simulation_dir() {
+            for dir in ["dev/input", "run/udev/data"] {
+                let _ = std::fs::create_dir_all(simulation_dir.join(dir));
+            }
         }
     }
 
@@ -72,6 +83,7 @@ impl ContainerRuntime {
             ContainerRuntime::Nspawn => &PLACEMENT_IN_CONTAINER,
             ContainerRuntime::Bubblewrap => &PLACEMENT_ON_HOST,
             ContainerRuntime::CustomEngine => todo!("not implemented yet"),
+            ContainerRuntime::Simulated => &SIMULATED,
         }
     }
 }
@@ -0,0 +1,253 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// org.vuinputd.Manager on the system bus (dbus), for desktop integrations and portals that
+// want to know when a container creates an input device. It offers what the control socket
+// offers them: ListDevices, RevokeDevice, SetPolicy and Approve, and the signals DeviceCreated,
+// DeviceRemoved and ApprovalRequested, which follow the events of the control socket.
+//
+// The bus decides who may call what, see dbus/org.vuinputd.Manager.conf: everybody may list
+// the devices and receive the signals, only root may revoke devices, change policies and
+// approve containers.
+// The same connection offers org.vuinputd.Portal (portal), see control::portal.
+//
+// zbus is only built with the dbus feature. Without it, dbus = true is reported at startup.
+
+use std::io;
+
+#[cfg(feature = "dbus")]
+pub use bus::*;
+
+#[cfg(not(feature = "dbus"))]
+pub use unavailable::*;
+
+pub const BUS_NAME: &str = "org.vuinputd.Manager";
+
+#[cfg(feature = "dbus")]
+mod bus {
+    use std::thread;
+
+    use log::{debug, warn};
+    use zbus::blocking::connection::{Builder, Connection};
+    use zbus::object_server::SignalEmitter;
+    use zbus::{fdo, interface};
+
+    use super::*;
+    use crate::control::portal::{self, Portal};
+    use crate::control::protocol::{Device, Event, Response};
+    use crate::control::{events, list_devices, set_policy};
+    use crate::cuse_device::approval;
+    use crate::cuse_device::revoke::revoke;
+
+    pub const OBJECT_PATH: &str = "/org/vuinputd/Manager";
+
+    /// fh, pid, container, devnode ("" before UI_DEV_CREATE), capabilities, policy, revoked
+    pub type DeviceEntry = (u64, u32, u32, String, String, String, bool);
+
+    fn device_entry(device: Device) -> DeviceEntry {
+        (
+            device.fh,
+            device.pid,
+            device.container,
+            device.devnode.unwrap_or_default(),
+            device.capabilities,
+            device.policy,
+            device.revoked,
+        )
+    }
+
+    struct Manager;
+
+    #[interface(name = "org.vuinputd.Manager")]
+    impl Manager {
+        /// Open handles of /dev/uinput and their devices
+        fn list_devices(&self) -> Vec<DeviceEntry> {
+            list_devices().into_iter().map(device_entry).collect()
+        }
+
+        /// Removes the device of a handle and returns its node
+        fn revoke_device(&self, fh: u64) -> fdo::Result<String> {
+            revoke(fh)
+                .map(Option::unwrap_or_default)
+                .map_err(fdo::Error::Failed)
+        }
+
+        /// Device policy of a container (root pid), returns the number of open handles
+        fn set_policy(&self, container: u32, policy: &str) -> fdo::Result<u32> {
+            set_policy(container, policy)
+                .map(|handles| handles as u32)
+                .map_err(fdo::Error::InvalidArgs)
+        }
+
+        /// Approves or denies the devices of a container (root pid), returns the number of
+        /// requests that waited for it
+        fn approve(&self, container: u32, allow: bool) -> fdo::Result<u32> {
+            approval::decide(container, allow)
+                .map(|answered| answered as u32)
+                .map_err(fdo::Error::InvalidArgs)
+        }
+
+        #[zbus(signal)]
+        async fn device_created(
+            emitter: &SignalEmitter<'_>,
+            fh: u64,
+            container: u32,
+            devnode: &str,
+        ) -> zbus::Result<()>;
+
+        #[zbus(signal)]
+        async fn device_removed(
+            emitter: &SignalEmitter<'_>,
+            fh: u64,
+            container: u32,
+            devnode: &str,
+        ) -> zbus::Result<()>;
+
+        #[zbus(signal)]
+        async fn approval_requested(
+            emitter: &SignalEmitter<'_>,
+            fh: u64,
+            container: u32,
+            pid: u32,
+        ) -> zbus::Result<()>;
+    }
+
+    fn connect(manager: bool, portal: bool) -> zbus::Result<Connection> {
+        let mut builder = Builder::system()?;
+        if manager {
+            builder = builder.name(BUS_NAME)?.serve_at(OBJECT_PATH, Manager)?;
+        }
+        if portal {
+            builder = builder
+                .name(portal::PORTAL_NAME)?
+                .serve_at(portal::PORTAL_PATH, Portal)?;
+        }
+        builder.build()
+    }
+
+    /// Takes the names on the system bus: org.vuinputd.Manager with `manager`, which sends
+    /// the signals from a thread of its own, and org.vuinputd.Portal with `portal`
+    pub fn start(manager: bool, portal: bool) -> io::Result<()> {
+        let connection = connect(manager, portal).map_err(io::Error::other)?;
+        debug!(
+            "serving on the system bus as {:?}",
+            connection.unique_name()
+        );
+        if portal {
+            let connection = connection.clone();
+            thread::Builder::new()
+                .name("dbus-portal".to_string())
+                .spawn(move || portal::watch_callers(connection))?;
+        }
+        if manager {
+            thread::Builder::new()
+                .name("dbus".to_string())
+                .spawn(move || emit_signals(connection))?;
+        }
+        Ok(())
+    }
+
+    fn emit_signals(connection: Connection) {
+        let emitter = match SignalEmitter::new(connection.inner(), OBJECT_PATH) {
+            Ok(emitter) => emitter,
+            Err(e) => {
+                warn!("no signals on the system bus: {}", e);
+                return;
+            }
+        };
+        loop {
+            // a subscriber that does not keep up is dropped, the signals go on with a new one
+            let signals = events::subscribe_to(|event| {
+                matches!(
+                    event,
+                    Event::DeviceCreated { .. }
+                        | Event::DeviceRemoved { .. }
+                        | Event::ApprovalRequested { .. }
+                )
+            });
+            for response in signals.iter() {
+                let Response::Event { event, .. } = response else {
+                    continue;
+                };
+                let sent = match &event {
+                    Event::DeviceCreated {
+                        fh,
+                        container,
+                        devnode,
+                    } => futures::executor::block_on(Manager::device_created(
+                        &emitter, *fh, *container, devnode,
+                    )),
+                    Event::DeviceRemoved {
+                        fh,
+                        container,
+                        devnode,
+                    } => futures::executor::block_on(Manager::device_removed(
+                        &emitter, *fh, *container, devnode,
+                    )),
+                    Event::ApprovalRequested { fh, container, pid } => futures::executor::block_on(
+                        Manager::approval_requested(&emitter, *fh, *container, *pid),
+                    ),
+                    _ => Ok(()),
+                };
+                if let Err(e) = sent {
+                    warn!("failed to send a signal on the system bus: {}", e);
+                }
+            }
+            warn!("signals on the system bus have been lost");
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn devices_without_a_node() {
+            let device = Device {
+                fh: 5,
+                pid: 4711,
+                container: 4690,
+                container_name: None,
+                devnode: None,
+                label: None,
+                sysname: None,
+                capabilities: "EV_KEY".to_string(),
+                policy: "strict-gamepad".to_string(),
+                revoked: false,
+                forwarded: 0,
+                blocked: 0,
+                locked: 0,
+                failed: 0,
+                ioctl_errors: 0,
+                descriptor: Default::default(),
+                broadcast: Vec::new(),
+                seat: None,
+                container_devnode: None,
+            };
+            assert_eq!(
+                device_entry(device),
+                (
+                    5,
+                    4711,
+                    4690,
+                    String::new(),
+                    "EV_KEY".to_string(),
+                    "strict-gamepad".to_string(),
+                    false
+                )
+            );
+        }
+    }
+}
+
+#[cfg(not(feature = "dbus"))]
+mod unavailable {
+    use super::*;
+
+    pub fn start(_manager: bool, _portal: bool) -> io::Result<()> {
+        Err(io::Error::other(
+            "vuinputd has been built without the dbus feature",
+        ))
+    }
+}
@@ -0,0 +1,287 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Lifecycle events for the subscribers of the control socket (vuinputctl events). Publishing
+// costs a lock when nobody listens. Each subscriber has a bounded queue; a subscriber that does
+// not keep up is dropped instead of slowing down the CUSE thread, which closes its connection.
+// The listeners within vuinputd (the state file, the desktop notifications and the signals on
+// the system bus) only subscribe to the events they handle, so that they do not count as
+// listeners of the others.
+// Device and policy events also start the configured hook, see jobs::run_hook_job.
+
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use crate::audit::{self, Action, Verdict};
+use crate::config_file::value_name;
+use crate::control::protocol::{Event, Response};
+use crate::cuse_device::state::VuInputState;
+use crate::global_config::{get_reloadable_config, LifecycleHooks};
+use crate::job_engine::JOB_DISPATCHER;
+use crate::jobs::run_hook_job::RunHookJob;
+
+const BACKLOG: usize = 1024;
+
+struct Subscriber {
+    sender: SyncSender<Response>,
+    wants: fn(&Event) -> bool,
+}
+
+static SUBSCRIBERS: Mutex<Vec<Subscriber>> = Mutex::new(Vec::new());
+
+/// All events, for the subscribers of the control socket
+pub fn subscribe() -> Receiver<Response> {
+    subscribe_to(|_| true)
+}
+
+/// The events `wants` returns true for
+pub fn subscribe_to(wants: fn(&Event) -> bool) -> Receiver<Response> {
+    let (sender, receiver) = mpsc::sync_channel(BACKLOG);
+    SUBSCRIBERS
+        .lock()
+        .unwrap()
+        .push(Subscriber { sender, wants });
+    receiver
+}
+
+fn is_wanted(event: &Event) -> bool {
+    SUBSCRIBERS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|subscriber| (subscriber.wants)(event))
+}
+
+fn message(event: Event) -> Response {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    Response::Event { time, event }
+}
+
+pub fn publish(event: Event) {
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    if !subscribers
+        .iter()
+        .any(|subscriber| (subscriber.wants)(&event))
+    {
+        return;
+    }
+    let message = message(event.clone());
+    subscribers.retain(|subscriber| {
+        if !(subscriber.wants)(&event) {
+            return true;
+        }
+        match subscriber.sender.try_send(message.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("dropping a subscriber of the control socket that does not keep up");
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    });
+}
+
+/// The hook configured for the event, with its name in the configuration file
+fn hook<'a>(hooks: &'a LifecycleHooks, event: &Event) -> Option<(&'static str, &'a PathBuf)> {
+    match event {
+        Event::DeviceCreated { .. } => hooks
+            .on_device_created
+            .as_ref()
+            .map(|program| ("on-device-created", program)),
+        Event::DeviceRemoved { .. } => hooks
+            .on_device_removed
+            .as_ref()
+            .map(|program| ("on-device-removed", program)),
+        Event::PolicyViolation { .. } => hooks
+            .on_policy_violation
+            .as_ref()
+            .map(|program| ("on-policy-violation", program)),
+        Event::JobFailed { .. } | Event::ApprovalRequested { .. } => None,
+    }
+}
+
+/// Publishes the event and queues its hook, if one is configured
+fn publish_with_hook(event: Event, hooks: &LifecycleHooks) {
+    if let Some((name, program)) = hook(hooks, &event) {
+        match serde_json::to_string(&message(event.clone())) {
+            Ok(input) => {
+                let job = RunHookJob::new(name, program.clone(), input + "\n", hooks.timeout);
+                JOB_DISPATCHER
+                    .get()
+                    .unwrap()
+                    .lock()
+                    .unwrap()
+                    .dispatch(Box::new(job));
+            }
+            Err(e) => warn!("not running hook {}: {}", name, e),
+        }
+    }
+    publish(event);
+}
+
+fn container(vuinput_state: &VuInputState) -> u32 {
+    vuinput_state.requesting_process.pid_requestor_root.as_raw()
+}
+
+pub fn device_created(fh: u64, vuinput_state: &VuInputState, devnode: &str) {
+    audit::record(
+        fh,
+        vuinput_state,
+        Action::CreateDevice,
+        Verdict::Allowed,
+        None,
+        Some(devnode),
+    );
+    publish_with_hook(
+        Event::DeviceCreated {
+            fh,
+            container: container(vuinput_state),
+            devnode: devnode.to_string(),
+        },
+        &get_reloadable_config().hooks,
+    );
+}
+
+pub fn device_removed(fh: u64, vuinput_state: &VuInputState, devnode: &str) {
+    audit::record(
+        fh,
+        vuinput_state,
+        Action::DestroyDevice,
+        Verdict::Allowed,
+        None,
+        Some(devnode),
+    );
+    publish_with_hook(
+        Event::DeviceRemoved {
+            fh,
+            container: container(vuinput_state),
+            devnode: devnode.to_string(),
+        },
+        &get_reloadable_config().hooks,
+    );
+}
+
+/// UI_DEV_CREATE of a container that waits for the host, see cuse_device::approval
+pub fn approval_requested(fh: u64, vuinput_state: &VuInputState) {
+    publish(Event::ApprovalRequested {
+        fh,
+        container: container(vuinput_state),
+        pid: vuinput_state.requesting_process.pid_requestor.as_raw(),
+    });
+}
+
+/// A request refused by the device policy or a limit, e.g. "UI_SET_KEYBIT KEY_A"
+pub fn policy_violation(fh: u64, vuinput_state: &VuInputState, violation: impl Display) {
+    audit::record(
+        fh,
+        vuinput_state,
+        Action::Reject,
+        Verdict::Denied,
+        Some(violation.to_string()),
+        vuinput_state
+            .input_device
+            .as_ref()
+            .map(|device| device.devnode.as_str()),
+    );
+    let config = get_reloadable_config();
+    let event = Event::PolicyViolation {
+        fh,
+        container: container(vuinput_state),
+        policy: value_name(&vuinput_state.policy(&config)),
+        violation: violation.to_string(),
+    };
+    if !is_wanted(&event) && config.hooks.on_policy_violation.is_none() {
+        return;
+    }
+    publish_with_hook(event, &config.hooks);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscribers_get_events_until_they_hang_up() {
+        let failed = Event::JobFailed {
+            target: "subscriber test".to_string(),
+            error: "Remove input device: timed out after 5s".to_string(),
+        };
+        let receiver = subscribe();
+        publish(failed.clone());
+        // the tests of the job engine publish as well
+        assert!(receiver
+            .iter()
+            .any(|message| matches!(message, Response::Event { event, .. } if event == failed)));
+
+        drop(receiver);
+        publish(failed.clone());
+        assert!(!is_wanted(&failed));
+    }
+
+    #[test]
+    fn subscribers_get_only_the_events_they_want() {
+        let removed = Event::DeviceRemoved {
+            fh: 3,
+            container: 4691,
+            devnode: "/dev/input/event7".to_string(),
+        };
+        let violation = Event::PolicyViolation {
+            fh: 3,
+            container: 4691,
+            policy: "strict-gamepad".to_string(),
+            violation: "UI_SET_KEYBIT KEY_A".to_string(),
+        };
+        let receiver = subscribe_to(|event| {
+            matches!(
+                event,
+                Event::DeviceRemoved {
+                    container: 4691,
+                    ..
+                }
+            )
+        });
+        assert!(is_wanted(&removed));
+        assert!(!is_wanted(&violation));
+        publish(violation);
+        publish(removed.clone());
+        assert!(
+            matches!(receiver.try_recv(), Ok(Response::Event { event, .. }) if event == removed)
+        );
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn hooks_of_events() {
+        let hooks = LifecycleHooks {
+            on_device_removed: Some(PathBuf::from("/usr/local/bin/close-port")),
+            ..Default::default()
+        };
+        let removed = Event::DeviceRemoved {
+            fh: 3,
+            container: 4690,
+            devnode: "/dev/input/event7".to_string(),
+        };
+        assert_eq!(
+            hook(&hooks, &removed),
+            Some((
+                "on-device-removed",
+                &PathBuf::from("/usr/local/bin/close-port")
+            ))
+        );
+        let created = Event::DeviceCreated {
+            fh: 3,
+            container: 4690,
+            devnode: "/dev/input/event7".to_string(),
+        };
+        assert_eq!(hook(&hooks, &created), None);
+    }
+}
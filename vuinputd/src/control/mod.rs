@@ -0,0 +1,462 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// The control socket answers queries of vuinputctl and the registrations of vuinputd-oci-hook.
+// It is only accessible by root (mode 0600). Every connection gets its own thread, so a client
+// that does not read its responses can't block the others.
+//
+// The mode is set on the socket before it is bound, so there is no moment in which another
+// user could connect. A socket unit may bind the socket with a looser mode, so the peer of
+// every connection must be root or the user vuinputd runs as (SO_PEERCRED) as well.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use log::{debug, info, warn};
+use nix::sys::socket::{
+    bind, getsockopt, listen, socket, sockopt, AddressFamily, Backlog, SockFlag, SockType, UnixAddr,
+};
+use nix::sys::stat::{fchmod, Mode};
+use nix::unistd::geteuid;
+
+use crate::config_file::value_name;
+use crate::control::protocol::{Container, Device, JobQueue, Request, Response};
+use crate::cuse_device::device_policy::{container_policy, set_container_policy};
+use crate::cuse_device::revoke::revoke;
+use crate::cuse_device::state::{get_vuinput_state, vuinput_states, VuFileHandle};
+use crate::cuse_device::{approval, broadcast, label};
+use crate::global_config::{get_reloadable_config, DevicePolicy};
+use crate::health;
+use crate::job_engine::JOB_DISPATCHER;
+use crate::process_tools::container_identity::{self, ContainerIdentity, Engine};
+use crate::process_tools::Pid;
+
+pub mod dbus;
+pub mod events;
+#[cfg(feature = "dbus")]
+pub mod portal;
+pub mod protocol;
+
+/// How long a subscriber may take to read an event before it is dropped
+const EVENT_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the connection of a subscriber is checked while there are no events
+const HANGUP_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn start_control_server(path: &Path) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // a socket left behind by a crashed instance would make bind fail
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let fd = socket(
+        AddressFamily::Unix,
+        SockType::Stream,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    // bind creates the file with the mode of the socket (minus the umask)
+    fchmod(&fd, Mode::S_IRUSR | Mode::S_IWUSR)?;
+    bind(fd.as_raw_fd(), &UnixAddr::new(path)?)?;
+    listen(&fd, Backlog::MAXCONN)?;
+    serve_control_socket(UnixListener::from(fd), path)
+}
+
+/// Root and the user vuinputd runs as may use the control socket
+fn is_allowed_peer(stream: &UnixStream) -> io::Result<bool> {
+    let credentials = getsockopt(stream, sockopt::PeerCredentials)?;
+    Ok(credentials.uid() == 0 || credentials.uid() == geteuid().as_raw())
+}
+
+/// Serves a socket that has been bound already, e.g. by a systemd socket unit.
+pub fn serve_control_socket(listener: UnixListener, path: &Path) -> io::Result<()> {
+    let path: PathBuf = path.to_path_buf();
+    thread::Builder::new()
+        .name("control".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        match is_allowed_peer(&stream) {
+                            Ok(true) => {}
+                            Ok(false) => {
+                                warn!("refused a connection to {} of another user", path.display());
+                                continue;
+                            }
+                            Err(e) => {
+                                warn!("credentials of a peer of {}: {}", path.display(), e);
+                                continue;
+                            }
+                        }
+                        let _ = thread::Builder::new()
+                            .name("control-connection".to_string())
+                            .spawn(move || {
+                                if let Err(e) = handle_connection(stream) {
+                                    debug!("control connection closed: {}", e);
+                                }
+                            });
+                    }
+                    Err(e) => warn!("accepting on {} failed: {}", path.display(), e),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+pub fn remove_control_socket(path: &Path) {
+    let _ = fs::remove_file(path);
+}
+
+fn handle_connection(stream: UnixStream) -> io::Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(Request::Subscribe) => return stream_events(&mut writer),
+            Ok(request) => handle_request(request),
+            Err(e) => Response::Error {
+                message: format!("invalid request: {}", e),
+            },
+        };
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+fn write_response(writer: &mut UnixStream, response: &Response) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, response)?;
+    writer.write_all(b"\n")
+}
+
+/// Takes over the connection until the client hangs up, which is also noticed while there
+/// are no events to send
+fn stream_events(writer: &mut UnixStream) -> io::Result<()> {
+    writer.set_write_timeout(Some(EVENT_WRITE_TIMEOUT))?;
+    let events = events::subscribe();
+    write_response(writer, &Response::Subscribed)?;
+    loop {
+        match events.recv_timeout(HANGUP_CHECK_INTERVAL) {
+            Ok(response) => write_response(writer, &response)?,
+            Err(RecvTimeoutError::Timeout) if has_hung_up(writer) => return Ok(()),
+            Err(RecvTimeoutError::Timeout) => {}
+            // the subscriber has been dropped, as it did not keep up
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    let lost = Response::Error {
+        message: "events have been lost, subscribe again".to_string(),
+    };
+    write_response(writer, &lost)
+}
+
+/// Whether the client has closed its end of the connection, without waiting
+fn has_hung_up(stream: &UnixStream) -> bool {
+    let mut pollfd = libc::pollfd {
+        fd: stream.as_raw_fd(),
+        events: libc::POLLRDHUP,
+        revents: 0,
+    };
+    let ready = unsafe { libc::poll(&mut pollfd, 1, 0) };
+    ready > 0 && pollfd.revents & (libc::POLLRDHUP | libc::POLLHUP | libc::POLLERR) != 0
+}
+
+fn list_devices() -> Vec<Device> {
+    let config = get_reloadable_config();
+    vuinput_states()
+        .into_iter()
+        .map(|(fh, state)| {
+            let state = state.lock().unwrap();
+            Device {
+                fh,
+                pid: state.requesting_process.pid_requestor.as_raw(),
+                container: state.requesting_process.pid_requestor_root.as_raw(),
+                container_name: state
+                    .requesting_process
+                    .identity
+                    .as_ref()
+                    .map(|identity| identity.to_string()),
+                devnode: state
+                    .input_device
+                    .as_ref()
+                    .map(|device| device.devnode.clone()),
+                label: state.label.clone(),
+                sysname: state
+                    .input_device
+                    .as_ref()
+                    .map(|device| device.sysname.clone()),
+                capabilities: state.descriptor.to_string(),
+                policy: value_name(&state.policy(&config)),
+                revoked: state.revoked,
+                forwarded: state.events_forwarded,
+                blocked: state.drops.policy,
+                locked: state.drops.session_locked,
+                failed: state.drops.uinput,
+                ioctl_errors: state.ioctl_errors,
+                descriptor: state.descriptor.to_protocol(),
+                broadcast: state
+                    .input_device
+                    .iter()
+                    .flat_map(|device| &device.mirrors)
+                    .map(|target| target.pid_requestor_root.as_raw())
+                    .collect(),
+                seat: state
+                    .input_device
+                    .as_ref()
+                    .and_then(|device| device.seat.lock().unwrap().clone()),
+                container_devnode: state
+                    .input_device
+                    .as_ref()
+                    .and_then(|device| device.container_devnode.lock().unwrap().clone()),
+            }
+        })
+        .collect()
+}
+
+fn list_containers() -> Vec<Container> {
+    let mut containers: BTreeMap<u32, Container> = BTreeMap::new();
+    for device in list_devices() {
+        let container = containers
+            .entry(device.container)
+            .or_insert_with(|| Container {
+                container: device.container,
+                name: device.container_name.clone(),
+                handles: 0,
+                devices: 0,
+                policy: container_policy(Pid::Pid(device.container)).map(|p| value_name(&p)),
+            });
+        container.handles += 1;
+        if device.devnode.is_some() {
+            container.devices += 1;
+        }
+    }
+    containers.into_values().collect()
+}
+
+fn set_policy(container: u32, policy: &str) -> Result<usize, String> {
+    let policy = DevicePolicy::from_str(policy, false)?;
+    set_container_policy(Pid::Pid(container), policy)?;
+    let mut handles = 0;
+    for (_, state) in vuinput_states() {
+        let mut state = state.lock().unwrap();
+        if state.requesting_process.pid_requestor_root.as_raw() == container {
+            state.policy_override = Some(policy);
+            handles += 1;
+        }
+    }
+    info!(
+        "device policy of container {} set to {:?} ({} open handles)",
+        container, policy, handles
+    );
+    Ok(handles)
+}
+
+fn handle_request(request: Request) -> Response {
+    match request {
+        Request::ListJobs => {
+            let mut queues: Vec<JobQueue> = JOB_DISPATCHER
+                .get()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .queue_status()
+                .into_iter()
+                .map(|(target, status)| JobQueue {
+                    target: target.to_string(),
+                    queued: status.queued,
+                    running: status.running,
+                    last_error: status.last_error,
+                })
+                .collect();
+            queues.sort_by(|a, b| a.target.cmp(&b.target));
+            Response::Jobs { queues }
+        }
+        Request::ListDevices => Response::Devices {
+            devices: list_devices(),
+        },
+        Request::ListContainers => Response::Containers {
+            containers: list_containers(),
+        },
+        Request::Revoke { fh } => match revoke(fh) {
+            Ok(devnode) => Response::Revoked { fh, devnode },
+            Err(message) => Response::Error { message },
+        },
+        Request::SetPolicy { container, policy } => match set_policy(container, &policy) {
+            Ok(handles) => Response::PolicySet {
+                container,
+                policy,
+                handles,
+            },
+            Err(message) => Response::Error { message },
+        },
+        Request::History { fh } => match get_vuinput_state(&VuFileHandle::Fh(fh)) {
+            Ok(state) => Response::History {
+                fh,
+                operations: state.lock().unwrap().history.to_protocol(),
+            },
+            Err(message) => Response::Error { message },
+        },
+        Request::Label { fh, label, push } => match label::set_label(fh, label.clone(), push) {
+            Ok(pushed) => Response::Labeled { fh, label, pushed },
+            Err(message) => Response::Error { message },
+        },
+        Request::Broadcast { container, targets } => {
+            match broadcast::set_grant(container, &targets) {
+                Ok(targets) => Response::BroadcastSet { container, targets },
+                Err(message) => Response::Error { message },
+            }
+        }
+        Request::Approve { container, allow } => match approval::decide(container, allow) {
+            Ok(answered) => Response::Approved {
+                container,
+                allow,
+                answered,
+            },
+            Err(message) => Response::Error { message },
+        },
+        Request::RegisterContainer {
+            pid,
+            engine,
+            id,
+            annotations,
+        } => match Engine::from_name(&engine) {
+            Some(engine) => {
+                container_identity::register(
+                    Pid::Pid(pid),
+                    ContainerIdentity {
+                        engine,
+                        id: id.clone(),
+                        name: None,
+                        labels: annotations,
+                        verified: true,
+                    },
+                );
+                Response::ContainerRegistered { pid, id }
+            }
+            None => Response::Error {
+                message: format!(
+                    "unknown engine '{}', possible values: docker, podman, nspawn, lxc",
+                    engine
+                ),
+            },
+        },
+        Request::UnregisterContainer { id } => Response::ContainerUnregistered {
+            containers: container_identity::unregister(&id),
+            id,
+        },
+        Request::Health => health::report(),
+        Request::Subscribe => unreachable!("handled by handle_connection"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::protocol::*;
+    use super::*;
+
+    #[test]
+    fn messages_are_json_lines() {
+        assert_eq!(
+            serde_json::from_str::<Request>(r#"{"command":"list-jobs"}"#).unwrap(),
+            Request::ListJobs
+        );
+        let response = Response::Jobs {
+            queues: vec![JobQueue {
+                target: "host".to_string(),
+                queued: 1,
+                running: Some("Remove input device".to_string()),
+                last_error: None,
+            }],
+        };
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"type":"jobs","queues":[{"target":"host","queued":1,"running":"Remove input device","last_error":null}]}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Request>(
+                r#"{"command":"set-policy","container":4690,"policy":"strict-gamepad"}"#
+            )
+            .unwrap(),
+            Request::SetPolicy {
+                container: 4690,
+                policy: "strict-gamepad".to_string()
+            }
+        );
+        assert_eq!(
+            serde_json::to_string(&Response::Revoked {
+                fh: 5,
+                devnode: Some("/dev/input/event7".to_string())
+            })
+            .unwrap(),
+            r#"{"type":"revoked","fh":5,"devnode":"/dev/input/event7"}"#
+        );
+    }
+
+    #[test]
+    fn notices_subscribers_that_hang_up() {
+        let (server, client) = UnixStream::pair().unwrap();
+        assert!(!has_hung_up(&server));
+        drop(client);
+        assert!(has_hung_up(&server));
+    }
+
+    #[test]
+    fn descriptors_hold_the_setup_of_the_handle() {
+        use crate::cuse_device::state::{AbsInfo, DeviceDescriptor, DeviceSetup};
+
+        let mut usetup: libc::uinput_setup = unsafe { std::mem::zeroed() };
+        usetup.id.bustype = 0x03;
+        usetup.id.vendor = 0x045e;
+        usetup.id.product = 0x028e;
+        for (target, byte) in usetup.name.iter_mut().zip(b"Gamepad") {
+            *target = *byte as libc::c_char;
+        }
+        let mut state = DeviceDescriptor {
+            setup: Some(DeviceSetup::from(&usetup)),
+            ..Default::default()
+        };
+        state.event_types.extend([0x01, 0x03]);
+        state.codes.entry(0x03).or_default().insert(0x00);
+        state.absinfo.insert(
+            0x00,
+            AbsInfo {
+                minimum: -32768,
+                maximum: 32767,
+                ..Default::default()
+            },
+        );
+        state.rejected.insert("UI_SET_KEYBIT KEY_SYSRQ".to_string());
+
+        let descriptor = state.to_protocol();
+        let setup = descriptor.setup.as_ref().unwrap();
+        assert_eq!((setup.name.as_str(), setup.vendor), ("Gamepad", 0x045e));
+        assert_eq!(descriptor.event_types, vec![0x01, 0x03]);
+        assert_eq!(descriptor.absinfo[&0x00].minimum, -32768);
+        let json = serde_json::to_string(&descriptor).unwrap();
+        assert!(json.contains(r#""codes":{"3":[0]}"#));
+        assert_eq!(
+            serde_json::from_str::<Descriptor>(&json).unwrap(),
+            descriptor
+        );
+    }
+
+    #[test]
+    fn set_policy_rejects_unknown_policies() {
+        assert!(super::set_policy(4690, "lenient").is_err());
+    }
+}
@@ -0,0 +1,528 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// org.vuinputd.Portal (portal): apps that can't open the CUSE node, e.g. Flatpaks, ask for a
+// virtual gamepad on the system bus. vuinputd creates it on the host, set up like a common
+// gamepad as far as the device policy of the container of the caller allows, and hands out a
+// write-only fd of its event node. Events written to it reach the readers of the device like
+// those of a real gamepad; the kernel drops what is outside the capabilities. The filter on
+// write of the CUSE node does not see these events, so policies that judge single events
+// (sanitized, script) only act on the capabilities here.
+//
+// The bus tells the uid and the pid of the caller. Only root and the users of the active
+// sessions on the seats of the host may call, and each uid holds MAX_DEVICES_PER_UID
+// gamepads at most. Otherwise a gamepad is created like with UI_DEV_CREATE of a client of the
+// CUSE node in the container of the caller: the policy, the name rules, the approval and
+// limits.max-devices-per-container apply, the audit log and the events see it under a handle
+// of its own, and it gets the id and the phys of vuinputd, so 90-vuinputd-protect.rules keeps
+// it off the seats of the host. A device stays until the caller closes it or leaves the bus.
+// Flatpak apps need --system-talk-name=org.vuinputd.Portal.
+
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Mutex;
+
+use libc::{c_char, O_CLOEXEC, O_NONBLOCK};
+use log::{debug, info, warn};
+use zbus::blocking::fdo::DBusProxy;
+use zbus::blocking::Connection;
+use zbus::message::Header;
+use zbus::names::BusName;
+use zbus::{fdo, interface, zvariant};
+
+use crate::control::events;
+use crate::cuse_device::container_rules::rule_policy;
+use crate::cuse_device::device_id::{marked_phys, VUINPUTD_PRODUCT, VUINPUTD_VENDOR};
+use crate::cuse_device::device_name::{apply_name_policy, NameRules, UINPUT_MAX_NAME_SIZE};
+use crate::cuse_device::device_policy::{container_policy, is_code_allowed, is_event_type_allowed};
+use crate::cuse_device::drop_counters::{DropCounters, DropWindow};
+use crate::cuse_device::op_history::OpHistory;
+use crate::cuse_device::reconnect::replay_setup;
+use crate::cuse_device::state::{
+    AbsInfo, DeviceDescriptor, DeviceLifecycle, DeviceSetup, KeyTracker, PollState, VuInputState,
+};
+use crate::cuse_device::vuinput_ioctl::create_device_retrying;
+use crate::cuse_device::vuinput_open::get_fresh_filehandle;
+use crate::cuse_device::{approval, device_limits, BUS_USB};
+use crate::global_config::{get_max_devices_per_container, get_reloadable_config, DevicePolicy};
+use crate::input_realizer::node_acl::active_session_uid;
+use crate::jobs;
+use crate::process_tools::{get_requesting_process, Pid, SELF_NAMESPACES};
+
+pub const PORTAL_NAME: &str = "org.vuinputd.Portal";
+pub const PORTAL_PATH: &str = "/org/vuinputd/Portal";
+
+/// Gamepads a uid can hold at a time, over all its connections to the bus
+const MAX_DEVICES_PER_UID: usize = 4;
+
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_ABS: u16 = 0x03;
+
+/// BTN_SOUTH, BTN_EAST, BTN_NORTH, BTN_WEST, BTN_TL, BTN_TR, BTN_TL2, BTN_TR2, BTN_SELECT,
+/// BTN_START, BTN_MODE, BTN_THUMBL and BTN_THUMBR
+const GAMEPAD_BUTTONS: [u16; 13] = [
+    0x130, 0x131, 0x133, 0x134, 0x136, 0x137, 0x138, 0x139, 0x13a, 0x13b, 0x13c, 0x13d, 0x13e,
+];
+
+const STICK: AbsInfo = AbsInfo {
+    value: 0,
+    minimum: -32768,
+    maximum: 32767,
+    fuzz: 16,
+    flat: 128,
+    resolution: 0,
+};
+const TRIGGER: AbsInfo = AbsInfo {
+    value: 0,
+    minimum: 0,
+    maximum: 255,
+    fuzz: 0,
+    flat: 0,
+    resolution: 0,
+};
+const HAT: AbsInfo = AbsInfo {
+    value: 0,
+    minimum: -1,
+    maximum: 1,
+    fuzz: 0,
+    flat: 0,
+    resolution: 0,
+};
+
+/// ABS_X, ABS_Y, ABS_RX and ABS_RY are the sticks, ABS_Z and ABS_RZ the triggers,
+/// ABS_HAT0X and ABS_HAT0Y the d-pad
+const GAMEPAD_AXES: [(u16, AbsInfo); 8] = [
+    (0x00, STICK),
+    (0x01, STICK),
+    (0x03, STICK),
+    (0x04, STICK),
+    (0x02, TRIGGER),
+    (0x05, TRIGGER),
+    (0x10, HAT),
+    (0x11, HAT),
+];
+
+struct PortalDevice {
+    /// Unique name of the caller on the bus, e.g. ":1.42"
+    sender: String,
+    uid: u32,
+    devnode: String,
+    /// Whether the device counts against limits.max-devices-per-container
+    in_container: bool,
+    /// Like that of a handle of the CUSE node, for the audit log and the events. Its file is
+    /// the uinput fd, closing it destroys the device.
+    state: VuInputState,
+}
+
+/// The gamepads by their handle, which is also their fh in the audit log and the events
+static DEVICES: Mutex<BTreeMap<u64, PortalDevice>> = Mutex::new(BTreeMap::new());
+/// Gamepads per uid, including those that are being created
+static HELD: Mutex<BTreeMap<u32, usize>> = Mutex::new(BTreeMap::new());
+
+/// Counts a gamepad of `uid`, unless it holds MAX_DEVICES_PER_UID already
+fn try_reserve(uid: u32) -> bool {
+    let mut held = HELD.lock().unwrap();
+    let count = held.entry(uid).or_insert(0);
+    if *count >= MAX_DEVICES_PER_UID {
+        return false;
+    }
+    *count += 1;
+    true
+}
+
+fn release_reserved(uid: u32) {
+    let mut held = HELD.lock().unwrap();
+    if let Some(count) = held.get_mut(&uid) {
+        *count = count.saturating_sub(1);
+        if *count == 0 {
+            held.remove(&uid);
+        }
+    }
+}
+
+/// Whether `uid` may create gamepads: root and the users of the active sessions on the seats
+/// of the host, who could plug in a real gamepad as well
+fn is_allowed_caller(uid: u32) -> bool {
+    if uid == 0 {
+        return true;
+    }
+    let host = Pid::Pid(1);
+    let Ok(seats) = fs::read_dir(format!("{}/root/run/systemd/seats", host.path())) else {
+        return false;
+    };
+    seats.flatten().any(|seat| {
+        seat.file_name()
+            .to_str()
+            .and_then(|seat| active_session_uid(host, seat))
+            == Some(uid)
+    })
+}
+
+/// The buttons and axes of a common gamepad the policy allows
+fn gamepad_descriptor(
+    policy: &DevicePolicy,
+    gamepad_extra_keys: &[u16],
+) -> Result<DeviceDescriptor, String> {
+    let mut descriptor = DeviceDescriptor::default();
+    if is_event_type_allowed(policy, EV_KEY) {
+        for code in GAMEPAD_BUTTONS {
+            if is_code_allowed(policy, gamepad_extra_keys, EV_KEY, code) {
+                descriptor.codes.entry(EV_KEY).or_default().insert(code);
+            }
+        }
+    }
+    if is_event_type_allowed(policy, EV_ABS) {
+        for (code, absinfo) in GAMEPAD_AXES {
+            if is_code_allowed(policy, gamepad_extra_keys, EV_ABS, code) {
+                descriptor.codes.entry(EV_ABS).or_default().insert(code);
+                descriptor.absinfo.insert(code, absinfo);
+            }
+        }
+    }
+    if !descriptor.codes.contains_key(&EV_KEY) {
+        return Err("the device policy allows no gamepad buttons".to_string());
+    }
+    descriptor.event_types.insert(EV_SYN);
+    descriptor
+        .event_types
+        .extend(descriptor.codes.keys().copied());
+    descriptor.phys = Some(marked_phys(None));
+    Ok(descriptor)
+}
+
+/// The setup of a gamepad named `name` with the id of vuinputd
+fn gamepad_setup(name: &str) -> DeviceSetup {
+    let mut setup = DeviceSetup {
+        bustype: BUS_USB,
+        vendor: VUINPUTD_VENDOR,
+        product: VUINPUTD_PRODUCT,
+        version: 1,
+        name: [0; UINPUT_MAX_NAME_SIZE],
+        ff_effects_max: 0,
+    };
+    for (dst, src) in setup
+        .name
+        .iter_mut()
+        .zip(name.bytes().take(UINPUT_MAX_NAME_SIZE - 1))
+    {
+        *dst = src as c_char;
+    }
+    setup
+}
+
+/// Creates the gamepad on the host and returns the handle and the node with an fd of it
+fn create(sender: &str, uid: u32, pid: u32, name: &str) -> Result<(u64, String, OwnedFd), String> {
+    if !is_allowed_caller(uid) {
+        return Err(format!(
+            "uid {} is neither root nor the user of an active session",
+            uid
+        ));
+    }
+    if !try_reserve(uid) {
+        return Err(format!(
+            "no more than {} gamepads per uid",
+            MAX_DEVICES_PER_UID
+        ));
+    }
+    let created = create_reserved(sender, uid, pid, name);
+    if created.is_err() {
+        release_reserved(uid);
+    }
+    created
+}
+
+fn create_reserved(
+    sender: &str,
+    uid: u32,
+    pid: u32,
+    name: &str,
+) -> Result<(u64, String, OwnedFd), String> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(O_NONBLOCK | O_CLOEXEC)
+        .open("/dev/uinput")
+        .map_err(|e| format!("couldn't open /dev/uinput: {}", e))?;
+    let fh = get_fresh_filehandle();
+    let requesting_process = get_requesting_process(Pid::Pid(pid));
+    let config = get_reloadable_config();
+    let mut state = VuInputState {
+        file,
+        policy_override: container_policy(requesting_process.pid_requestor_root),
+        rule_policy: rule_policy(
+            &config.container_rules,
+            requesting_process.identity.as_deref(),
+        ),
+        requesting_process,
+        input_device: None,
+        lifecycle: DeviceLifecycle::Opened,
+        keytracker: KeyTracker::new(),
+        poll: PollState::new(),
+        pending_read: None,
+        pending_create: None,
+        descriptor: DeviceDescriptor::default(),
+        node_policy: None,
+        revoked: false,
+        label: None,
+        events_forwarded: 0,
+        drops: DropCounters::default(),
+        drop_window: DropWindow::default(),
+        ioctl_errors: 0,
+        write_failures: 0,
+        last_reconnect: None,
+        history: OpHistory::new(),
+    };
+    let container = state.requesting_process.pid_requestor_root.as_raw();
+    let in_container = !SELF_NAMESPACES
+        .get()
+        .unwrap()
+        .equal_mnt_and_net(&state.requesting_process.namespaces);
+
+    let descriptor = gamepad_descriptor(&state.policy(&config), &config.gamepad_extra_keys)
+        .inspect_err(|violation| events::policy_violation(fh, &state, violation))?;
+    let mut setup = gamepad_setup(name);
+    apply_name_policy(&mut setup.name, &NameRules::new(&config, container)).map_err(
+        |violation| {
+            events::policy_violation(fh, &state, format_args!("device name: {}", violation));
+            format!("{:?}: {}", name, violation)
+        },
+    )?;
+    if in_container {
+        match approval::check(container) {
+            approval::Verdict::Approved => {}
+            approval::Verdict::Denied => {
+                events::policy_violation(fh, &state, "approval");
+                return Err(format!("container {} is not approved", container));
+            }
+            approval::Verdict::Ask => {
+                // a call on the bus can't wait like the ioctl, the app asks again once approved
+                events::approval_requested(fh, &state);
+                return Err(format!(
+                    "container {} waits for approval (vuinputctl approve {})",
+                    container, container
+                ));
+            }
+        }
+        let namespaces = &state.requesting_process.namespaces;
+        if !device_limits::try_reserve(namespaces, get_max_devices_per_container()) {
+            events::policy_violation(fh, &state, "limits.max-devices-per-container");
+            return Err("the container reached limits.max-devices-per-container".to_string());
+        }
+    }
+
+    let fd = state.file.as_raw_fd();
+    let created = unsafe {
+        replay_setup(fd, &setup, &descriptor).and_then(|()| create_device_retrying(fh, fd))
+    };
+    let input_device = match created {
+        Ok(input_device) => input_device,
+        Err(e) => {
+            if in_container {
+                device_limits::release(&state.requesting_process.namespaces);
+            }
+            return Err(e.to_string());
+        }
+    };
+    let devnode = input_device.devnode.clone();
+    let node = OpenOptions::new()
+        .write(true)
+        .custom_flags(O_CLOEXEC)
+        .open(&devnode)
+        .map_err(|e| format!("couldn't open {}: {}", devnode, e));
+    let node = match node {
+        Ok(node) => node,
+        Err(e) => {
+            if in_container {
+                device_limits::release(&state.requesting_process.namespaces);
+            }
+            return Err(e);
+        }
+    };
+    info!(
+        "portal: {} (uid {}, pid {}, container {}) created \"{}\" as {} (fh {})",
+        sender,
+        uid,
+        pid,
+        container,
+        setup.name(),
+        devnode,
+        fh
+    );
+    jobs::verify_seat_job::verify(&input_device);
+    state.descriptor = descriptor;
+    state.descriptor.setup = Some(setup);
+    state.input_device = Some(input_device);
+    state.lifecycle.advance(DeviceLifecycle::Created);
+    events::device_created(fh, &state, &devnode);
+    DEVICES.lock().unwrap().insert(
+        fh,
+        PortalDevice {
+            sender: sender.to_string(),
+            uid,
+            devnode: devnode.clone(),
+            in_container,
+            state,
+        },
+    );
+    Ok((fh, devnode, node.into()))
+}
+
+/// Destroys a gamepad that has been taken out of DEVICES
+fn destroy(fh: u64, mut device: PortalDevice) {
+    device.state.lifecycle.advance(DeviceLifecycle::Destroyed);
+    events::device_removed(fh, &device.state, &device.devnode);
+    if device.in_container {
+        device_limits::release(&device.state.requesting_process.namespaces);
+    }
+    release_reserved(device.uid);
+    // closes the uinput fd
+    drop(device);
+}
+
+/// Destroys the devices of a caller that left the bus
+fn release(sender: &str) {
+    let mut devices = DEVICES.lock().unwrap();
+    let handles: Vec<u64> = devices
+        .iter()
+        .filter(|(_, device)| device.sender == sender)
+        .map(|(handle, _)| *handle)
+        .collect();
+    let left: Vec<(u64, PortalDevice)> = handles
+        .into_iter()
+        .filter_map(|handle| devices.remove_entry(&handle))
+        .collect();
+    drop(devices);
+    for (handle, device) in left {
+        info!("portal: {} left, removing {}", sender, device.devnode);
+        destroy(handle, device);
+    }
+}
+
+/// The gamepads by the root pid of the container of their caller, for the metrics
+pub fn devices_per_container() -> Vec<u32> {
+    DEVICES
+        .lock()
+        .unwrap()
+        .values()
+        .map(|device| device.state.requesting_process.pid_requestor_root.as_raw())
+        .collect()
+}
+
+pub struct Portal;
+
+#[interface(name = "org.vuinputd.Portal")]
+impl Portal {
+    /// A gamepad named `name`: its handle, its node on the host and a write-only fd of the node
+    async fn create_gamepad(
+        &self,
+        name: &str,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> fdo::Result<(u64, String, zvariant::OwnedFd)> {
+        let sender = header
+            .sender()
+            .ok_or_else(|| fdo::Error::Failed("the call has no sender".to_string()))?
+            .to_owned();
+        let proxy = fdo::DBusProxy::new(connection).await?;
+        let uid = proxy
+            .get_connection_unix_user(BusName::from(sender.clone()))
+            .await?;
+        let pid = proxy
+            .get_connection_unix_process_id(BusName::from(sender.clone()))
+            .await?;
+        let (handle, devnode, node) = create(sender.as_str(), uid, pid, name).map_err(|e| {
+            warn!(
+                "portal: refused CreateGamepad of {} (uid {}): {}",
+                sender, uid, e
+            );
+            fdo::Error::Failed(e)
+        })?;
+        Ok((handle, devnode, node.into()))
+    }
+
+    /// Destroys a gamepad of the caller
+    fn close(&self, handle: u64, #[zbus(header)] header: Header<'_>) -> fdo::Result<()> {
+        let sender = header.sender().map(|sender| sender.to_string());
+        let mut devices = DEVICES.lock().unwrap();
+        let device = match devices.get(&handle) {
+            Some(device) if Some(&device.sender) == sender.as_ref() => {
+                devices.remove(&handle).unwrap()
+            }
+            _ => return Err(fdo::Error::InvalidArgs(format!("no gamepad {}", handle))),
+        };
+        drop(devices);
+        debug!("portal: {} closed {}", device.sender, device.devnode);
+        destroy(handle, device);
+        Ok(())
+    }
+}
+
+/// Removes the gamepads of callers once they leave the bus, runs until the bus goes away
+pub fn watch_callers(connection: Connection) {
+    let changes = DBusProxy::new(&connection).and_then(|proxy| proxy.receive_name_owner_changed());
+    let changes = match changes {
+        Ok(changes) => changes,
+        Err(e) => {
+            warn!("portal: callers that leave the bus can't be noticed: {}", e);
+            return;
+        }
+    };
+    for change in changes {
+        let Ok(args) = change.args() else {
+            continue;
+        };
+        if args.new_owner().is_none() {
+            release(args.name().as_str());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gamepads_follow_the_policy() {
+        let gamepad = gamepad_descriptor(&DevicePolicy::StrictGamepad, &[]).unwrap();
+        assert_eq!(
+            gamepad.event_types.iter().copied().collect::<Vec<_>>(),
+            [EV_SYN, EV_KEY, EV_ABS]
+        );
+        assert_eq!(gamepad.codes[&EV_KEY].len(), GAMEPAD_BUTTONS.len());
+        assert_eq!(gamepad.absinfo[&0x02], TRIGGER);
+        // 90-vuinputd-protect.rules recognizes the device by its phys as well
+        assert_eq!(gamepad.phys.as_deref(), Some("vuinputd"));
+
+        // strict-tablet allows ABS_X and ABS_Y, but no gamepad buttons
+        assert!(gamepad_descriptor(&DevicePolicy::StrictTablet, &[]).is_err());
+        let sanitized = gamepad_descriptor(&DevicePolicy::Sanitized, &[]).unwrap();
+        assert_eq!(sanitized.absinfo.len(), GAMEPAD_AXES.len());
+    }
+
+    #[test]
+    fn gamepads_are_limited_per_uid() {
+        let setup = gamepad_setup("pad");
+        assert_eq!(
+            (setup.vendor, setup.product),
+            (VUINPUTD_VENDOR, VUINPUTD_PRODUCT)
+        );
+        assert_eq!(setup.name(), "pad");
+
+        for _ in 0..MAX_DEVICES_PER_UID {
+            assert!(try_reserve(4242));
+        }
+        assert!(!try_reserve(4242));
+        assert!(try_reserve(4243));
+        release_reserved(4242);
+        assert!(try_reserve(4242));
+        for _ in 0..MAX_DEVICES_PER_UID {
+            release_reserved(4242);
+        }
+        release_reserved(4243);
+        assert!(!HELD.lock().unwrap().contains_key(&4242));
+    }
+}
@@ -0,0 +1,318 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Messages of the control socket: one JSON object per line in both directions.
+// This file is also compiled into vuinputctl, so it must only depend on serde and std.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Each instance has its own socket, named after its CUSE device.
+pub fn control_socket_path(devname: &str) -> PathBuf {
+    PathBuf::from(format!("/run/vuinputd/{}/control.sock", devname))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    /// Job queues per target (host and containers)
+    ListJobs,
+    /// Open handles of /dev/uinput and their devices
+    ListDevices,
+    /// Open handles grouped by container
+    ListContainers,
+    /// Removes the device of a handle, the handle can't be used anymore afterwards
+    Revoke { fh: u64 },
+    /// Device policy for the handles of a container (root pid), open ones and future ones
+    SetPolicy { container: u32, policy: String },
+    /// The last ioctls and writes of a handle
+    History { fh: u64 },
+    /// Attaches a label to a handle, None removes it. With push, the udev database of the
+    /// container gets it as the name and model of the device.
+    Label {
+        fh: u64,
+        label: Option<String>,
+        #[serde(default)]
+        push: bool,
+    },
+    /// Devices created in a container (root pid, 1 for the host) from now on also appear in
+    /// the target containers. No targets end the broadcast.
+    Broadcast { container: u32, targets: Vec<u32> },
+    /// Approves or denies the devices of a container (root pid) for approval-ttl, see approval.
+    /// Requests that wait for the decision are answered right away.
+    Approve { container: u32, allow: bool },
+    /// Tells vuinputd which container a root pid (host view) is, before it opens /dev/uinput.
+    /// Sent by vuinputd-oci-hook, the annotations of the container count as its labels.
+    RegisterContainer {
+        pid: u32,
+        engine: String,
+        id: String,
+        #[serde(default)]
+        annotations: BTreeMap<String, String>,
+    },
+    /// The container has stopped, sent by vuinputd-oci-hook
+    UnregisterContainer { id: String },
+    /// Liveness and readiness of vuinputd, see health
+    Health,
+    /// Answered with Subscribed, followed by an Event line for everything that happens
+    /// until the connection is closed
+    Subscribe,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Response {
+    Jobs {
+        queues: Vec<JobQueue>,
+    },
+    Devices {
+        devices: Vec<Device>,
+    },
+    Containers {
+        containers: Vec<Container>,
+    },
+    Revoked {
+        fh: u64,
+        devnode: Option<String>,
+    },
+    PolicySet {
+        container: u32,
+        policy: String,
+        handles: usize,
+    },
+    History {
+        fh: u64,
+        operations: Vec<Operation>,
+    },
+    Labeled {
+        fh: u64,
+        label: Option<String>,
+        /// The udev database of the container is being updated
+        pushed: bool,
+    },
+    BroadcastSet {
+        container: u32,
+        targets: Vec<u32>,
+    },
+    Approved {
+        container: u32,
+        allow: bool,
+        /// UI_DEV_CREATE requests that waited for the decision
+        answered: usize,
+    },
+    ContainerRegistered {
+        pid: u32,
+        id: String,
+    },
+    ContainerUnregistered {
+        id: String,
+        /// Root pids that have been registered for the container
+        containers: usize,
+    },
+    Health {
+        /// The control socket answers and the jobs are processed
+        live: bool,
+        /// Live, and clients can create devices: the CUSE nodes are registered and the udev
+        /// monitor runs
+        ready: bool,
+        checks: Vec<HealthCheck>,
+    },
+    Subscribed,
+    Event {
+        /// Seconds since the epoch
+        time: u64,
+        event: Event,
+    },
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Event {
+    DeviceCreated {
+        fh: u64,
+        container: u32,
+        devnode: String,
+    },
+    /// Destroyed by the client, on release or by revoke
+    DeviceRemoved {
+        fh: u64,
+        container: u32,
+        devnode: String,
+    },
+    /// A capability, event or device refused by the device policy or a limit. Blocked
+    /// events are only reported once per handle, see Device::blocked for the rest.
+    PolicyViolation {
+        fh: u64,
+        container: u32,
+        policy: String,
+        violation: String,
+    },
+    JobFailed {
+        target: String,
+        /// Description of the job and its error
+        error: String,
+    },
+    /// UI_DEV_CREATE of a container without a decision waits for vuinputctl approve or deny
+    ApprovalRequested {
+        fh: u64,
+        container: u32,
+        /// Process that created the device, host view
+        pid: u32,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobQueue {
+    pub target: String,
+    /// Jobs waiting behind the running one
+    pub queued: usize,
+    /// Description of the job that is executed right now
+    pub running: Option<String>,
+    /// Description and error of the last job that failed
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Device {
+    pub fh: u64,
+    /// Process that opened /dev/uinput, host view
+    pub pid: u32,
+    /// Root pid of the container of the process
+    pub container: u32,
+    /// The engine and name of the container, e.g. "docker steam (3f2a9c1b7d4e)", None on the
+    /// host or if the engine is not known
+    #[serde(default)]
+    pub container_name: Option<String>,
+    /// e.g. /dev/input/event7, None before UI_DEV_CREATE
+    pub devnode: Option<String>,
+    /// Set with vuinputctl label
+    #[serde(default)]
+    pub label: Option<String>,
+    /// e.g. input99, like UI_GET_SYSNAME
+    #[serde(default)]
+    pub sysname: Option<String>,
+    pub capabilities: String,
+    pub policy: String,
+    pub revoked: bool,
+    /// Events written to the device since the handle has been opened
+    #[serde(default)]
+    pub forwarded: u64,
+    /// Events dropped by the device policy
+    #[serde(default)]
+    pub blocked: u64,
+    /// Keys dropped while the session of the host was locked
+    #[serde(default)]
+    pub locked: u64,
+    /// Events that could not be written to the device
+    #[serde(default)]
+    pub failed: u64,
+    /// ioctls that failed on the host and were answered with their errno
+    #[serde(default)]
+    pub ioctl_errors: u64,
+    /// What the client has set up on the handle, the details behind capabilities
+    #[serde(default)]
+    pub descriptor: Descriptor,
+    /// Root pids of the other containers the device is broadcast to
+    #[serde(default)]
+    pub broadcast: Vec<u32>,
+    /// Where the host put the device, None if it has not been checked (verify-seat)
+    #[serde(default)]
+    pub seat: Option<SeatStatus>,
+    /// The node inside the container, e.g. /dev/input/event0 with node-naming
+    /// "container-local", None on the host or before it exists
+    #[serde(default)]
+    pub container_devnode: Option<String>,
+}
+
+/// The seat of a device on the host, see jobs::verify_seat_job
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "kebab-case")]
+pub enum SeatStatus {
+    /// On the seat of vuinputd, no compositor of the host picks it up
+    Isolated { seat: String },
+    /// On a seat of the host, but neither a keyboard nor a pointer, e.g. a gamepad
+    Shared { seat: String },
+    /// A keyboard or pointer on a seat of the host, its compositor gets the input
+    Exposed { seat: String },
+    /// udevadm or loginctl failed
+    Unknown { error: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Descriptor {
+    /// UI_DEV_SETUP or a legacy setup, None before either
+    pub setup: Option<Setup>,
+    /// Set with UI_SET_PHYS
+    pub phys: Option<String>,
+    pub event_types: Vec<u16>,
+    /// Codes per event type
+    pub codes: BTreeMap<u16, Vec<u16>>,
+    /// Ranges per ABS_* code
+    pub absinfo: BTreeMap<u16, AbsRange>,
+    /// INPUT_PROP_*
+    pub properties: Vec<u16>,
+    /// Bits refused by the device policy, e.g. "UI_SET_KEYBIT KEY_SYSRQ"
+    pub rejected: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Setup {
+    pub name: String,
+    pub bustype: u16,
+    pub vendor: u16,
+    pub product: u16,
+    pub version: u16,
+    pub ff_effects_max: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AbsRange {
+    pub minimum: i32,
+    pub maximum: i32,
+    pub fuzz: i32,
+    pub flat: i32,
+    pub resolution: i32,
+}
+
+/// An ioctl or write of a handle, see cuse_device::op_history
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Operation {
+    /// Milliseconds since the handle has been opened
+    pub elapsed_ms: u64,
+    pub name: String,
+    /// The ioctl command as the client sent it, None for writes
+    pub cmd: Option<u64>,
+    pub in_size: usize,
+    pub out_size: usize,
+    /// None if the operation has been answered without an error
+    pub errno: Option<i32>,
+    /// Consecutive writes with the same result are counted in one operation
+    pub count: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthCheck {
+    /// e.g. "cuse" or "udev-monitor"
+    pub name: String,
+    pub ok: bool,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Container {
+    /// Root pid of the container
+    pub container: u32,
+    /// The engine and name of the container, see Device
+    #[serde(default)]
+    pub name: Option<String>,
+    pub handles: usize,
+    /// Handles with a created device
+    pub devices: usize,
+    /// Set with set-policy, None if the configured policy applies
+    pub policy: Option<String>,
+}
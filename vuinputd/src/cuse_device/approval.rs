@@ -0,0 +1,262 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Approval of the devices of a container by the host (approval). With "prompt", UI_DEV_CREATE
+// of a container nobody decided on yet waits: the request is parked, the subscribers of the
+// control socket get an approval-requested event (a desktop notification with notify-user, a
+// signal with the dbus feature), and vuinputctl approve or deny answers it. A request that is
+// not decided within PROMPT_TIMEOUT is refused with EPERM without remembering a decision, so
+// the next attempt asks again. With "deny", only containers approved beforehand get devices.
+//
+// Decisions are kept per container for approval-ttl and apply in every mode, so a container
+// can also be denied while the others are approved automatically. A container is its root
+// process (pid and start time), so a new container that gets the pid of an approved one is
+// asked again. Only the handle that waits is blocked in its ioctl, the other handles go on. One
+// thread refuses the requests whose PROMPT_TIMEOUT has passed.
+
+use std::collections::BTreeMap;
+use std::sync::{Condvar, Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc::{ECANCELED, EPERM};
+use log::{info, warn};
+
+use crate::control::events;
+use crate::cuse_device::pending_reply::PendingReply;
+use crate::cuse_device::state::VuInputState;
+use crate::cuse_device::vuinput_ioctl::resume_create;
+use crate::global_config::{get_reloadable_config, Approval};
+use crate::process_tools::{Pid, ProcessInstance};
+
+/// After this time, a request nobody decided on is refused
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Approved,
+    Denied,
+    /// Park the request until the host decides, see wait
+    Ask,
+}
+
+/// Whether the container is allowed, and until when (None for a ttl beyond any clock)
+type Decision = (bool, Option<Instant>);
+
+struct Waiting {
+    fh: u64,
+    reply: PendingReply,
+    /// When the request is refused without a decision
+    until: Instant,
+}
+
+static DECISIONS: Mutex<BTreeMap<ProcessInstance, Decision>> = Mutex::new(BTreeMap::new());
+static WAITING: Mutex<BTreeMap<ProcessInstance, Vec<Waiting>>> = Mutex::new(BTreeMap::new());
+/// Wakes the timeout thread when a request has been parked
+static PARKED: Condvar = Condvar::new();
+static TIMEOUT_THREAD: Once = Once::new();
+
+fn is_current(decision: &Decision, now: Instant) -> bool {
+    decision.1.is_none_or(|until| now < until)
+}
+
+fn verdict(decision: Option<Decision>, approval: Approval, now: Instant) -> Verdict {
+    match decision {
+        Some(decision) if is_current(&decision, now) => match decision.0 {
+            true => Verdict::Approved,
+            false => Verdict::Denied,
+        },
+        _ => match approval {
+            Approval::Auto => Verdict::Approved,
+            Approval::Prompt => Verdict::Ask,
+            Approval::Deny => Verdict::Denied,
+        },
+    }
+}
+
+/// What to do with UI_DEV_CREATE of the container (root pid)
+pub fn check(container: u32) -> Verdict {
+    let decision = ProcessInstance::of(Pid::Pid(container))
+        .and_then(|instance| DECISIONS.lock().unwrap().get(&instance).copied());
+    verdict(decision, get_reloadable_config().approval, Instant::now())
+}
+
+/// Parks UI_DEV_CREATE of the handle until the host decides or PROMPT_TIMEOUT has passed
+pub fn wait(fh: u64, vuinput_state: &VuInputState, reply: PendingReply) {
+    let root = vuinput_state.requesting_process.pid_requestor_root;
+    let Some(container) = ProcessInstance::of(root) else {
+        warn!(
+            "fh {}: refusing UI_DEV_CREATE, container {} is gone",
+            fh,
+            root.as_raw()
+        );
+        reply.err(EPERM);
+        return;
+    };
+    TIMEOUT_THREAD.call_once(|| {
+        let spawned = thread::Builder::new()
+            .name("approval".to_string())
+            .spawn(time_out_requests);
+        if let Err(e) = spawned {
+            warn!("requests that wait for approval will not time out: {}", e);
+        }
+    });
+    WAITING
+        .lock()
+        .unwrap()
+        .entry(container)
+        .or_default()
+        .push(Waiting {
+            fh,
+            reply,
+            until: Instant::now() + PROMPT_TIMEOUT,
+        });
+    PARKED.notify_one();
+    info!(
+        "fh {}: UI_DEV_CREATE waits for the approval of container {} (vuinputctl approve {})",
+        fh, container.pid, container.pid
+    );
+    events::approval_requested(fh, vuinput_state);
+}
+
+/// Takes the requests whose time has come out of `waiting`, with their container
+fn take_expired(
+    waiting: &mut BTreeMap<ProcessInstance, Vec<Waiting>>,
+    now: Instant,
+) -> Vec<(u32, Waiting)> {
+    let mut expired = Vec::new();
+    waiting.retain(|container, requests| {
+        let (gone, kept): (Vec<_>, Vec<_>) = std::mem::take(requests)
+            .into_iter()
+            .partition(|request| request.until <= now);
+        expired.extend(gone.into_iter().map(|request| (container.pid, request)));
+        *requests = kept;
+        !requests.is_empty()
+    });
+    expired
+}
+
+/// The loop of the timeout thread, it sleeps until the earliest deadline
+fn time_out_requests() {
+    let mut waiting = WAITING.lock().unwrap();
+    loop {
+        let now = Instant::now();
+        let expired = take_expired(&mut waiting, now);
+        if !expired.is_empty() {
+            drop(waiting);
+            for (container, Waiting { fh, reply, .. }) in expired {
+                warn!(
+                    "fh {}: container {} has not been approved within {:?}, refusing UI_DEV_CREATE",
+                    fh, container, PROMPT_TIMEOUT
+                );
+                reply.err(EPERM);
+            }
+            waiting = WAITING.lock().unwrap();
+            continue;
+        }
+        let next = waiting
+            .values()
+            .flatten()
+            .map(|request| request.until)
+            .min();
+        waiting = match next {
+            Some(until) => {
+                PARKED
+                    .wait_timeout(waiting, until.saturating_duration_since(now))
+                    .unwrap()
+                    .0
+            }
+            None => PARKED.wait(waiting).unwrap(),
+        };
+    }
+}
+
+/// The parked request of the handle, if it still waits
+fn take_waiting(fh: u64) -> Option<PendingReply> {
+    let mut waiting = WAITING.lock().unwrap();
+    let (container, index) = waiting.iter().find_map(|(container, requests)| {
+        let index = requests.iter().position(|request| request.fh == fh)?;
+        Some((*container, index))
+    })?;
+    let requests = waiting.get_mut(&container)?;
+    let request = requests.remove(index);
+    if requests.is_empty() {
+        waiting.remove(&container);
+    }
+    Some(request.reply)
+}
+
+/// Fails UI_DEV_CREATE of the handle with ECANCELED, if it waits for approval. UI_DEV_DESTROY
+/// does so, to be answered after it, see op_sequencer.
+pub fn cancel(fh: u64) -> bool {
+    let Some(reply) = take_waiting(fh) else {
+        return false;
+    };
+    reply.err(ECANCELED);
+    true
+}
+
+/// Remembers the decision for approval-ttl and answers the requests of the container (root
+/// pid) that wait for it. Returns how many there were.
+pub fn decide(container: u32, allow: bool) -> Result<usize, String> {
+    let instance = ProcessInstance::of(Pid::Pid(container))
+        .ok_or_else(|| format!("there is no process {}", container))?;
+    let ttl = get_reloadable_config().approval_ttl;
+    let now = Instant::now();
+    {
+        let mut decisions = DECISIONS.lock().unwrap();
+        // decisions that have expired or whose container has stopped
+        decisions.retain(|instance, decision| is_current(decision, now) && instance.is_alive());
+        decisions.insert(instance, (allow, now.checked_add(ttl)));
+    }
+    info!(
+        "container {}: {} for {:?}",
+        container,
+        if allow { "approved" } else { "denied" },
+        ttl
+    );
+    let waiting = WAITING
+        .lock()
+        .unwrap()
+        .remove(&instance)
+        .unwrap_or_default();
+    let answered = waiting.len();
+    for Waiting { fh, reply, .. } in waiting {
+        if allow {
+            resume_create(fh, reply);
+        } else {
+            info!(
+                "fh {}: refusing UI_DEV_CREATE, container {} has been denied",
+                fh, container
+            );
+            reply.err(EPERM);
+        }
+    }
+    Ok(answered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decisions_expire_into_the_mode() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(3600);
+        assert_eq!(verdict(None, Approval::Auto, now), Verdict::Approved);
+        assert_eq!(verdict(None, Approval::Prompt, now), Verdict::Ask);
+        assert_eq!(verdict(None, Approval::Deny, now), Verdict::Denied);
+
+        let approved = Some((true, Some(later)));
+        assert_eq!(verdict(approved, Approval::Deny, now), Verdict::Approved);
+        assert_eq!(verdict(approved, Approval::Prompt, later), Verdict::Ask);
+        // a denial applies even if the others are approved automatically
+        let denied = Some((false, Some(later)));
+        assert_eq!(verdict(denied, Approval::Auto, now), Verdict::Denied);
+        assert_eq!(
+            verdict(Some((true, None)), Approval::Deny, later),
+            Verdict::Approved
+        );
+    }
+}
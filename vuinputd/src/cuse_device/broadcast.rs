@@ -0,0 +1,265 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Broadcast grants (vuinputctl broadcast): the devices that clients of a container create are
+// also injected into other containers, e.g. one gamepad of a streaming client for the game and
+// for an overlay recorder. The source is a container (root pid, 1 for clients on the host),
+// the grant is looked up when a device is created, so changing it does not touch existing
+// devices. Source and targets are kept with the start time of their root process, so a grant
+// ends with the containers and does not pass to new ones that get the same pid.
+//
+// Ownership stays with the handle that created the device: the other containers get a
+// mirror (node and udev events), and the mirrors go away with the device. Each target has
+// a job queue of its own, so a target that does not respond neither delays the reply to the
+// client nor the cleanup of the other targets. Mirrors do not count towards the limits of
+// the target, but they must pass its device policy: a target does not get a device it could
+// not have created itself.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+use log::{debug, info};
+
+use crate::config_file::value_name;
+use crate::cuse_device::container_rules::rule_policy;
+use crate::cuse_device::device_policy::{
+    class_violation, container_policy, is_code_allowed, is_event_type_allowed, is_property_allowed,
+};
+use crate::cuse_device::state::{DeviceDescriptor, VuInputDevice};
+use crate::global_config::{get_reloadable_config, DevicePolicy};
+use crate::input_codes::{code_name, type_name};
+use crate::job_engine::JOB_DISPATCHER;
+use crate::jobs::emit_udev_event_job::EmitUdevEventJob;
+use crate::jobs::mknod_device_job::MknodDeviceJob;
+use crate::jobs::remove_device_job::RemoveDeviceJob;
+use crate::process_tools::{
+    get_requesting_process, Pid, ProcessInstance, RequestingProcess, SELF_NAMESPACES,
+};
+
+/// Targets by source container (root processes)
+static GRANTS: Mutex<BTreeMap<ProcessInstance, BTreeSet<ProcessInstance>>> =
+    Mutex::new(BTreeMap::new());
+
+fn instance_of(container: u32) -> Result<ProcessInstance, String> {
+    ProcessInstance::of(Pid::Pid(container))
+        .ok_or_else(|| format!("there is no process {}", container))
+}
+
+/// Replaces the targets of `source`, no targets remove the grant
+pub fn set_grant(source: u32, targets: &[u32]) -> Result<Vec<u32>, String> {
+    let source = instance_of(source)?;
+    let targets = targets
+        .iter()
+        .filter(|target| **target != source.pid)
+        .map(|target| instance_of(*target))
+        .collect::<Result<BTreeSet<_>, _>>()?;
+    let pids: Vec<u32> = targets.iter().map(|target| target.pid).collect();
+    let mut grants = GRANTS.lock().unwrap();
+    // the grants of containers that have stopped in the meantime
+    grants.retain(|source, _| source.is_alive());
+    if targets.is_empty() {
+        grants.remove(&source);
+        info!(
+            "devices of container {} are not broadcast anymore",
+            source.pid
+        );
+    } else {
+        info!(
+            "devices of container {} are broadcast to {:?}",
+            source.pid, pids
+        );
+        grants.insert(source, targets);
+    }
+    Ok(pids)
+}
+
+/// The policy a device created by a client in `target` would get, without the policy of a
+/// CUSE node, which a mirror is not created through
+fn policy_of(target: &RequestingProcess) -> DevicePolicy {
+    let config = get_reloadable_config();
+    container_policy(target.pid_requestor_root)
+        .or(rule_policy(
+            &config.container_rules,
+            target.identity.as_deref(),
+        ))
+        .unwrap_or(config.policy)
+}
+
+/// The first capability of the device the policy refuses, e.g. "KEY_SYSRQ"
+fn refused_capability(
+    policy: &DevicePolicy,
+    gamepad_extra_keys: &[u16],
+    descriptor: &DeviceDescriptor,
+) -> Option<String> {
+    let name = |name: Option<&str>, code: u16| name.map_or(code.to_string(), str::to_string);
+    if let Some(type_) = descriptor
+        .event_types
+        .iter()
+        .find(|type_| !is_event_type_allowed(policy, **type_))
+    {
+        return Some(name(type_name(*type_), *type_));
+    }
+    for (type_, codes) in &descriptor.codes {
+        if let Some(code) = codes
+            .iter()
+            .find(|code| !is_code_allowed(policy, gamepad_extra_keys, *type_, **code))
+        {
+            return Some(name(code_name(*type_, *code), *code));
+        }
+    }
+    if let Some(prop) = descriptor
+        .properties
+        .iter()
+        .find(|prop| !is_property_allowed(policy, **prop))
+    {
+        return Some(format!("property {}", prop));
+    }
+    class_violation(policy, descriptor).map(str::to_string)
+}
+
+/// The containers a new device of `owner` is mirrored into: the targets of its grant that are
+/// still running and whose policy allows the device, neither the host nor the container of the
+/// owner itself
+pub fn targets_of(
+    owner: &RequestingProcess,
+    descriptor: &DeviceDescriptor,
+) -> Vec<RequestingProcess> {
+    let Some(source) = ProcessInstance::of(owner.pid_requestor_root) else {
+        return Vec::new();
+    };
+    let Some(targets) = GRANTS.lock().unwrap().get(&source).cloned() else {
+        return Vec::new();
+    };
+    let self_namespaces = SELF_NAMESPACES.get().unwrap();
+    let gamepad_extra_keys = get_reloadable_config().gamepad_extra_keys.clone();
+    targets
+        .into_iter()
+        .filter(|target| target.is_alive())
+        .map(|target| get_requesting_process(Pid::Pid(target.pid)))
+        .filter(|target| {
+            let namespaces = &target.namespaces;
+            namespaces.mnt.is_some()
+                && !self_namespaces.equal_mnt_and_net(namespaces)
+                && !owner.namespaces.equal_mnt_and_net(namespaces)
+        })
+        .filter(|target| {
+            let policy = policy_of(target);
+            match refused_capability(&policy, &gamepad_extra_keys, descriptor) {
+                Some(refused) => {
+                    info!(
+                        "not broadcasting to container {}, its device policy {} refuses {}",
+                        target.pid_requestor_root.as_raw(),
+                        value_name(&policy),
+                        refused
+                    );
+                    false
+                }
+                None => true,
+            }
+        })
+        .collect()
+}
+
+/// Creates the node of the device in each target and sends the udev events there. The client
+/// does not wait for them.
+pub fn create_mirrors(fh: u64, device: &VuInputDevice) {
+    let mut dispatcher = JOB_DISPATCHER.get().unwrap().lock().unwrap();
+    for target in &device.mirrors {
+        debug!(
+            "fh {}: broadcasting {} to container {}",
+            fh,
+            device.devnode,
+            target.pid_requestor_root.as_raw()
+        );
+        // the job logs a failure, only this target is affected
+        let mknod_job = MknodDeviceJob::new(
+            target.clone(),
+            device.devname.clone(),
+            device.syspath.clone(),
+            device.major,
+            device.minor,
+        )
+        .on_completion(Box::new(|_| {}));
+        let emit_udev_event_job = EmitUdevEventJob::new(
+            target.clone(),
+            device.devnode.clone(),
+            device.syspath.clone(),
+            device.major,
+            device.minor,
+            device.serial.map(|serial| serial.to_string()),
+        );
+        dispatcher.dispatch(Box::new(mknod_job));
+        dispatcher.dispatch(Box::new(emit_udev_event_job));
+    }
+}
+
+/// Removes the mirrors of a destroyed device, not awaited
+pub fn remove_mirrors(fh: u64, device: &VuInputDevice) {
+    let mut dispatcher = JOB_DISPATCHER.get().unwrap().lock().unwrap();
+    for target in &device.mirrors {
+        debug!(
+            "fh {}: removing the broadcast {} from container {}",
+            fh,
+            device.devnode,
+            target.pid_requestor_root.as_raw()
+        );
+        dispatcher.dispatch(Box::new(RemoveDeviceJob::new(
+            target.clone(),
+            device.devname.clone(),
+            device.syspath.clone(),
+            device.major,
+            device.minor,
+        )));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grants_exclude_the_source() {
+        let own = std::process::id();
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let target = child.id();
+        assert_eq!(set_grant(own, &[own, target, target]), Ok(vec![target]));
+        let source = instance_of(own).unwrap();
+        assert_eq!(GRANTS.lock().unwrap()[&source].len(), 1);
+        assert_eq!(set_grant(own, &[own]), Ok(Vec::new()));
+        assert!(!GRANTS.lock().unwrap().contains_key(&source));
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(set_grant(own, &[target]).is_err());
+    }
+
+    #[test]
+    fn mirrors_pass_the_policy_of_the_target() {
+        // EV_KEY with KEY_A and BTN_SOUTH
+        let mut keyboard = DeviceDescriptor::default();
+        keyboard.event_types.extend([0x00, 0x01]);
+        keyboard.codes.insert(0x01, [30, 0x130].into());
+        assert_eq!(
+            refused_capability(&DevicePolicy::StrictGamepad, &[], &keyboard).as_deref(),
+            Some("KEY_A")
+        );
+        assert_eq!(
+            refused_capability(&DevicePolicy::StrictGamepad, &[30], &keyboard),
+            None
+        );
+        assert_eq!(
+            refused_capability(&DevicePolicy::Sanitized, &[], &keyboard),
+            None
+        );
+        // EV_REL
+        keyboard.event_types.insert(0x02);
+        assert_eq!(
+            refused_capability(&DevicePolicy::StrictGamepad, &[30], &keyboard).as_deref(),
+            Some("EV_REL")
+        );
+    }
+}
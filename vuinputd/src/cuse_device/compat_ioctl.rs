@@ -0,0 +1,161 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// 32-bit clients on a 64-bit host (e.g. games under Wine or Proton) use other ioctl numbers
+// where the argument contains a pointer, as the size is part of the number:
+//   UI_SET_PHYS         the argument is a char *, 4 instead of 8 bytes
+//   UI_BEGIN_FF_UPLOAD  struct uinput_ff_upload contains two struct ff_effect, whose periodic
+//   UI_END_FF_UPLOAD    effect ends with a pointer (custom_data): 96 instead of 104 bytes
+// The other ioctls, struct uinput_user_dev and struct vuinput_info have the same layout for
+// all clients. The compat number is mapped to the native one, and the effects are copied like
+// uinput_ff_upload_from_user does it: the compat ff_effect is the native one up to the
+// pointer, of which only the lower 32 bits exist.
+
+use libc::{ff_effect, uinput_ff_upload};
+use uinput_ioctls::*;
+
+/// struct ff_effect_compat
+const FF_EFFECT_COMPAT_SIZE: usize = 44;
+/// request_id and retval in front of the effects
+const FF_UPLOAD_HEADER_SIZE: usize = 8;
+
+/// The native number of a compat ioctl, and whether it was one
+pub fn native_cmd(cmd: u64) -> (u64, bool) {
+    // on a 32-bit host, the compat numbers are the native ones
+    if cmd == UI_SET_PHYS_COMPAT && UI_SET_PHYS_COMPAT != UI_SET_PHYS {
+        (UI_SET_PHYS, true)
+    } else if cmd == UI_BEGIN_FF_UPLOAD_COMPAT && UI_BEGIN_FF_UPLOAD_COMPAT != UI_BEGIN_FF_UPLOAD {
+        (UI_BEGIN_FF_UPLOAD, true)
+    } else if cmd == UI_END_FF_UPLOAD_COMPAT && UI_END_FF_UPLOAD_COMPAT != UI_END_FF_UPLOAD {
+        (UI_END_FF_UPLOAD, true)
+    } else {
+        (cmd, false)
+    }
+}
+
+fn effect_from_compat(bytes: &[u8]) -> ff_effect {
+    let mut effect: ff_effect = unsafe { std::mem::zeroed() };
+    unsafe {
+        std::ptr::copy_nonoverlapping(
+            bytes.as_ptr(),
+            &mut effect as *mut ff_effect as *mut u8,
+            FF_EFFECT_COMPAT_SIZE,
+        )
+    };
+    effect
+}
+
+fn effect_to_compat(effect: &ff_effect, bytes: &mut [u8]) {
+    let native = unsafe {
+        std::slice::from_raw_parts(
+            effect as *const ff_effect as *const u8,
+            std::mem::size_of::<ff_effect>(),
+        )
+    };
+    bytes.copy_from_slice(&native[..FF_EFFECT_COMPAT_SIZE]);
+}
+
+/// The struct uinput_ff_upload of a client in its layout. None, if the size does not match.
+pub fn ff_upload_from_buffer(buffer: &[u8], compat: bool) -> Option<uinput_ff_upload> {
+    if !compat {
+        if buffer.len() != std::mem::size_of::<uinput_ff_upload>() {
+            return None;
+        }
+        return Some(unsafe {
+            std::ptr::read_unaligned(buffer.as_ptr() as *const uinput_ff_upload)
+        });
+    }
+    if buffer.len() != UINPUT_FF_UPLOAD_COMPAT_SIZE {
+        return None;
+    }
+    let (header, effects) = buffer.split_at(FF_UPLOAD_HEADER_SIZE);
+    let (effect, old) = effects.split_at(FF_EFFECT_COMPAT_SIZE);
+    let mut upload: uinput_ff_upload = unsafe { std::mem::zeroed() };
+    upload.request_id = u32::from_ne_bytes(header[0..4].try_into().unwrap());
+    upload.retval = i32::from_ne_bytes(header[4..8].try_into().unwrap());
+    upload.effect = effect_from_compat(effect);
+    upload.old = effect_from_compat(old);
+    Some(upload)
+}
+
+/// The struct uinput_ff_upload for the reply, in the layout of the client
+pub fn ff_upload_to_buffer(upload: &uinput_ff_upload, compat: bool) -> Vec<u8> {
+    if !compat {
+        let native = unsafe {
+            std::slice::from_raw_parts(
+                upload as *const uinput_ff_upload as *const u8,
+                std::mem::size_of::<uinput_ff_upload>(),
+            )
+        };
+        return native.to_vec();
+    }
+    let mut buffer = vec![0u8; UINPUT_FF_UPLOAD_COMPAT_SIZE];
+    buffer[0..4].copy_from_slice(&upload.request_id.to_ne_bytes());
+    buffer[4..8].copy_from_slice(&upload.retval.to_ne_bytes());
+    let (effect, old) = buffer[FF_UPLOAD_HEADER_SIZE..].split_at_mut(FF_EFFECT_COMPAT_SIZE);
+    effect_to_compat(&upload.effect, effect);
+    effect_to_compat(&upload.old, old);
+    buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FF_RUMBLE: u16 = 0x50;
+
+    // struct uinput_ff_upload_compat of a 32-bit client: request_id 7, retval 0, a rumble
+    // effect (id -1, replay.length 500) and an empty old effect
+    fn compat_upload() -> Vec<u8> {
+        let mut buffer = vec![0u8; UINPUT_FF_UPLOAD_COMPAT_SIZE];
+        buffer[0..4].copy_from_slice(&7u32.to_ne_bytes());
+        let effect = &mut buffer[8..8 + FF_EFFECT_COMPAT_SIZE];
+        effect[0..2].copy_from_slice(&FF_RUMBLE.to_ne_bytes());
+        effect[2..4].copy_from_slice(&(-1i16).to_ne_bytes());
+        effect[10..12].copy_from_slice(&500u16.to_ne_bytes());
+        // u.rumble.strong_magnitude, the union starts at offset 16
+        effect[16..18].copy_from_slice(&0x8000u16.to_ne_bytes());
+        buffer
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn maps_compat_numbers() {
+        assert_eq!(std::mem::size_of::<ff_effect>(), 48);
+        assert_eq!(
+            native_cmd(UI_BEGIN_FF_UPLOAD_COMPAT),
+            (UI_BEGIN_FF_UPLOAD, true)
+        );
+        assert_eq!(
+            native_cmd(UI_END_FF_UPLOAD_COMPAT),
+            (UI_END_FF_UPLOAD, true)
+        );
+        assert_eq!(native_cmd(UI_SET_PHYS_COMPAT), (UI_SET_PHYS, true));
+        assert_eq!(native_cmd(UI_BEGIN_FF_UPLOAD), (UI_BEGIN_FF_UPLOAD, false));
+        assert_eq!(native_cmd(UI_DEV_SETUP), (UI_DEV_SETUP, false));
+    }
+
+    #[test]
+    fn translates_ff_uploads() {
+        let compat = compat_upload();
+        let mut upload = ff_upload_from_buffer(&compat, true).unwrap();
+        assert_eq!(upload.request_id, 7);
+        assert_eq!(upload.effect.type_, FF_RUMBLE);
+        assert_eq!(upload.effect.id, -1);
+        assert_eq!(upload.effect.replay.length, 500);
+        let native = ff_upload_to_buffer(&upload, false);
+        assert_eq!(native[8 + 16..8 + 18], 0x8000u16.to_ne_bytes());
+
+        // uinput assigns the id of the effect
+        upload.effect.id = 3;
+        let reply = ff_upload_to_buffer(&upload, true);
+        assert_eq!(reply.len(), UINPUT_FF_UPLOAD_COMPAT_SIZE);
+        assert_eq!(&reply[10..12], &3i16.to_ne_bytes());
+        assert_eq!(reply[12..], compat[12..]);
+
+        assert!(ff_upload_from_buffer(&compat[..90], true).is_none());
+        assert_eq!(native.len(), std::mem::size_of::<uinput_ff_upload>());
+        assert_eq!(ff_upload_from_buffer(&native, false).unwrap().effect.id, -1);
+    }
+}
@@ -0,0 +1,226 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Device policies per container (container-policy). The rules pick the policy by what the
+// engine tells about the container, see process_tools::container_identity:
+//
+//   [[container-policy]]
+//   name = "steam-*"
+//   policy = "strict-gamepad"
+//
+//   [[container-policy]]
+//   name = "desktop"
+//   engine = "docker"
+//   policy = "none"
+//
+//   [[container-policy]]
+//   label = "com.example.untrusted"
+//   policy = "strict-gamepad"
+//
+// name matches the name or the id of the container, label a label ("key") or its value
+// ("key=value"), engine one of docker, podman, nspawn and lxc. Names and values may contain the
+// wildcards * and ?. A rule applies if all of its conditions match, the first rule that applies
+// wins. The rules are evaluated when a handle is opened, so two containers on the same node get
+// their own policy. set-policy still takes precedence, the policy of the node comes after them.
+//
+// Only containers their engine has confirmed are matched (ContainerIdentity::verified). Labels
+// come from the image as well as from the run, and images are built by whoever publishes them,
+// so a label should only make the policy stricter; a rule that relaxes it should name the
+// container instead.
+
+use crate::global_config::DevicePolicy;
+use crate::process_tools::container_identity::{ContainerIdentity, Engine};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContainerRule {
+    /// Pattern of the name or id of the container
+    pub name: Option<String>,
+    pub engine: Option<Engine>,
+    /// The key of a label, with an optional pattern of its value
+    pub label: Option<(String, Option<String>)>,
+    pub policy: DevicePolicy,
+}
+
+impl ContainerRule {
+    fn matches(&self, identity: &ContainerIdentity) -> bool {
+        let name = self.name.as_ref().is_none_or(|pattern| {
+            identity
+                .name
+                .iter()
+                .chain([&identity.id])
+                .any(|name| glob_match(pattern, name))
+        });
+        let engine = self.engine.is_none_or(|engine| engine == identity.engine);
+        let label = self.label.as_ref().is_none_or(|(key, pattern)| {
+            identity.labels.get(key).is_some_and(|value| {
+                pattern
+                    .as_ref()
+                    .is_none_or(|pattern| glob_match(pattern, value))
+            })
+        });
+        name && engine && label
+    }
+}
+
+/// Splits "key=value" into the key and the pattern of the value
+pub fn parse_label(label: &str) -> (String, Option<String>) {
+    match label.split_once('=') {
+        Some((key, value)) => (key.to_string(), Some(value.to_string())),
+        None => (label.to_string(), None),
+    }
+}
+
+/// The policy of the first rule that matches the container, None for processes of the host,
+/// containers of unknown engines and containers their engine has not confirmed
+pub fn rule_policy(
+    rules: &[ContainerRule],
+    identity: Option<&ContainerIdentity>,
+) -> Option<DevicePolicy> {
+    let identity = identity.filter(|identity| identity.verified)?;
+    rules
+        .iter()
+        .find(|rule| rule.matches(identity))
+        .map(|rule| rule.policy)
+}
+
+/// Shell-like matching of * (any run of characters) and ? (a single character)
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // position of the last * and of the text it has been tried at
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, tried)) => {
+                    p = star + 1;
+                    t = tried + 1;
+                    backtrack = Some((star, tried + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn identity(engine: Engine, name: Option<&str>, labels: &[(&str, &str)]) -> ContainerIdentity {
+        ContainerIdentity {
+            engine,
+            id: "3f2a9c1b7d4e8f60a1b2c3d4e5f60718293a4b5c6d7e8f901122334455667788".to_string(),
+            name: name.map(str::to_string),
+            labels: labels
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect::<BTreeMap<_, _>>(),
+            verified: true,
+        }
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(glob_match("steam-*", "steam-1"));
+        assert!(glob_match("steam-*", "steam-"));
+        assert!(glob_match("*-game?", "steam-games"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("steam-*", "steam"));
+        assert!(!glob_match("steam", "steam-1"));
+        assert!(!glob_match("a*b*c", "aXbYbZ"));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = [
+            ContainerRule {
+                name: Some("steam-*".to_string()),
+                engine: None,
+                label: None,
+                policy: DevicePolicy::StrictGamepad,
+            },
+            ContainerRule {
+                name: None,
+                engine: Some(Engine::Docker),
+                label: Some(parse_label("com.example.trusted")),
+                policy: DevicePolicy::None,
+            },
+            ContainerRule {
+                name: None,
+                engine: None,
+                label: Some(parse_label("tier=test*")),
+                policy: DevicePolicy::Sanitized,
+            },
+        ];
+        let trusted = [("com.example.trusted", "")];
+        assert_eq!(
+            rule_policy(
+                &rules,
+                Some(&identity(Engine::Docker, Some("steam-1"), &trusted))
+            ),
+            Some(DevicePolicy::StrictGamepad)
+        );
+        assert_eq!(
+            rule_policy(
+                &rules,
+                Some(&identity(Engine::Docker, Some("desktop"), &trusted))
+            ),
+            Some(DevicePolicy::None)
+        );
+        // the label alone is not enough, the rule is for docker
+        assert_eq!(
+            rule_policy(
+                &rules,
+                Some(&identity(Engine::Podman, Some("desktop"), &trusted))
+            ),
+            None
+        );
+        assert_eq!(
+            rule_policy(
+                &rules,
+                Some(&identity(Engine::Podman, None, &[("tier", "testing")]))
+            ),
+            Some(DevicePolicy::Sanitized)
+        );
+        assert_eq!(
+            rule_policy(
+                &rules,
+                Some(&identity(Engine::Podman, None, &[("tier", "prod")]))
+            ),
+            None
+        );
+        assert_eq!(rule_policy(&rules, None), None);
+
+        // a container without a name, e.g. registered by vuinputd-oci-hook, is matched by id
+        let by_id = [ContainerRule {
+            name: Some("3f2a9c1b7d4e*".to_string()),
+            engine: None,
+            label: None,
+            policy: DevicePolicy::StrictTablet,
+        }];
+        assert_eq!(
+            rule_policy(&by_id, Some(&identity(Engine::Docker, None, &[]))),
+            Some(DevicePolicy::StrictTablet)
+        );
+        // anybody can name a cgroup docker-<id>.scope, the engine has to confirm it
+        let unverified = ContainerIdentity {
+            verified: false,
+            ..identity(Engine::Docker, Some("steam-1"), &trusted)
+        };
+        assert_eq!(rule_policy(&rules, Some(&unverified)), None);
+    }
+}
@@ -0,0 +1,111 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Without the cuse kernel module, cuse_lowlevel_main only fails with a generic
+// "cuse: failed to open /dev/cuse". So check for /dev/cuse before, and load the
+// module if allowed.
+
+use crate::process_tools::{has_effective_capability, CAP_SYS_MODULE};
+use log::{debug, info};
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::process::Command;
+
+pub const CUSE_DEVICE: &str = "/dev/cuse";
+
+/// Whether vuinputd loads the cuse kernel module if /dev/cuse is missing
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum, Default)]
+pub enum ModprobeCuse {
+    #[default]
+    /// Run "modprobe cuse" if /dev/cuse is missing and CAP_SYS_MODULE is available
+    Auto,
+    /// Never load the module, fail instead
+    Never,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CuseStatus {
+    Available,
+    /// /dev/cuse does not exist, i.e. the module is not loaded
+    Missing,
+    /// /dev/cuse exists, but is not a character device
+    NotACharDevice,
+    /// /dev/cuse exists, but can't be opened (e.g. missing permissions)
+    NotAccessible(String),
+}
+
+pub fn cuse_status() -> CuseStatus {
+    let path = Path::new(CUSE_DEVICE);
+    match path.metadata() {
+        Err(e) if e.kind() == ErrorKind::NotFound => CuseStatus::Missing,
+        Err(e) => CuseStatus::NotAccessible(e.to_string()),
+        Ok(metadata) if !metadata.file_type().is_char_device() => CuseStatus::NotACharDevice,
+        Ok(_) => match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(_) => CuseStatus::Available,
+            Err(e) => CuseStatus::NotAccessible(e.to_string()),
+        },
+    }
+}
+
+fn modprobe_cuse() -> Result<(), String> {
+    match has_effective_capability(CAP_SYS_MODULE) {
+        Ok(true) => {}
+        Ok(false) => {
+            return Err(format!(
+                "VUI-CUSE-002: {} is missing and the cuse module can't be loaded without CAP_SYS_MODULE",
+                CUSE_DEVICE
+            ))
+        }
+        Err(e) => {
+            return Err(format!(
+                "VUI-CUSE-002: could not read the capabilities of vuinputd: {}",
+                e
+            ))
+        }
+    }
+
+    info!("{} is missing, loading the cuse module", CUSE_DEVICE);
+    let output = Command::new("modprobe")
+        .arg("cuse")
+        .output()
+        .map_err(|e| format!("VUI-CUSE-002: could not run modprobe: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "VUI-CUSE-002: modprobe cuse failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    debug!("modprobe cuse succeeded");
+    Ok(())
+}
+
+/// Makes sure that /dev/cuse can be used. Returns an error message with error code otherwise.
+pub fn ensure_cuse_available(modprobe: &ModprobeCuse) -> Result<(), String> {
+    let status = match (cuse_status(), modprobe) {
+        (CuseStatus::Missing, ModprobeCuse::Auto) => {
+            modprobe_cuse()?;
+            cuse_status()
+        }
+        (status, _) => status,
+    };
+
+    match status {
+        CuseStatus::Available => Ok(()),
+        CuseStatus::Missing => Err(format!(
+            "VUI-CUSE-001: {} is missing. Load the cuse kernel module (modprobe cuse)",
+            CUSE_DEVICE
+        )),
+        CuseStatus::NotACharDevice => Err(format!(
+            "VUI-CUSE-003: {} is not a character device",
+            CUSE_DEVICE
+        )),
+        CuseStatus::NotAccessible(e) => Err(format!(
+            "VUI-CUSE-003: {} can't be opened: {}",
+            CUSE_DEVICE, e
+        )),
+    }
+}
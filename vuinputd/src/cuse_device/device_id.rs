@@ -0,0 +1,159 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Vendor, product and bus type of UI_DEV_SETUP (or the legacy uinput_user_dev). By default
+// they are replaced with 1209:5020, which 90-vuinputd.hwdb uses to recognize the devices of
+// vuinputd on the host. Games that look for a specific controller (e.g. an Xbox 360 pad,
+// 045e:028e) need the real ids, so --id-policy can keep them. Such a device is recognized by
+// its phys instead, which starts with PHYS_MARKER (see 90-vuinputd-protect.rules).
+
+use std::fmt;
+use std::str::FromStr;
+
+use libc::input_id;
+
+use crate::cuse_device::BUS_USB;
+use crate::global_config::IdPolicy;
+
+/// The pid is registered for vuinputd, see https://pid.codes/1209/5020/
+pub const VUINPUTD_VENDOR: u16 = 0x1209;
+pub const VUINPUTD_PRODUCT: u16 = 0x5020;
+
+/// Prefix of the phys of devices that keep their ids
+pub const PHYS_MARKER: &str = "vuinputd";
+
+/// vendor:product as in lsusb, e.g. "045e:028e"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsbId {
+    pub vendor: u16,
+    pub product: u16,
+}
+
+impl FromStr for UsbId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |hex: &str| {
+            (hex.len() == 4)
+                .then(|| u16::from_str_radix(hex, 16).ok())
+                .flatten()
+        };
+        match s.split_once(':') {
+            Some((vendor, product)) => match (parse(vendor), parse(product)) {
+                (Some(vendor), Some(product)) => Ok(UsbId { vendor, product }),
+                _ => Err(format!("'{}' is not a vendor:product id like 045e:028e", s)),
+            },
+            None => Err(format!("'{}' is not a vendor:product id like 045e:028e", s)),
+        }
+    }
+}
+
+impl fmt::Display for UsbId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.vendor, self.product)
+    }
+}
+
+/// Replaces the id with the one of vuinputd, unless the policy keeps it. Returns the kept id.
+pub fn apply_id_policy(
+    id: &mut input_id,
+    policy: &IdPolicy,
+    passthrough_ids: &[UsbId],
+) -> Option<UsbId> {
+    let requested = UsbId {
+        vendor: id.vendor,
+        product: id.product,
+    };
+    let keep = match policy {
+        IdPolicy::Rewrite => false,
+        IdPolicy::Preserve => true,
+        IdPolicy::Allowlist => passthrough_ids.contains(&requested),
+    };
+    if keep {
+        return Some(requested);
+    }
+    id.bustype = BUS_USB;
+    id.vendor = VUINPUTD_VENDOR;
+    id.product = VUINPUTD_PRODUCT;
+    None
+}
+
+/// The phys of a device that keeps its id, e.g. "vuinputd/usb-0000:00:14.0-1/input0"
+pub fn marked_phys(requested: Option<&str>) -> String {
+    match requested {
+        Some(phys) if !phys.is_empty() => format!("{}/{}", PHYS_MARKER, phys),
+        _ => PHYS_MARKER.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xbox360() -> input_id {
+        input_id {
+            bustype: BUS_USB,
+            vendor: 0x045e,
+            product: 0x028e,
+            version: 0x0114,
+        }
+    }
+
+    #[test]
+    fn parses_ids() {
+        let id: UsbId = "045e:028E".parse().unwrap();
+        assert_eq!(
+            id,
+            UsbId {
+                vendor: 0x045e,
+                product: 0x028e
+            }
+        );
+        assert_eq!(id.to_string(), "045e:028e");
+        assert!("045e".parse::<UsbId>().is_err());
+        assert!("45e:28e".parse::<UsbId>().is_err());
+        assert!("045e:zzzz".parse::<UsbId>().is_err());
+    }
+
+    #[test]
+    fn applies_the_policy() {
+        let allowlist = ["045e:028e".parse().unwrap()];
+
+        let mut id = xbox360();
+        assert_eq!(
+            apply_id_policy(&mut id, &IdPolicy::Rewrite, &allowlist),
+            None
+        );
+        assert_eq!((id.vendor, id.product), (VUINPUTD_VENDOR, VUINPUTD_PRODUCT));
+        // the version is the client's in any case
+        assert_eq!(id.version, 0x0114);
+
+        let mut id = xbox360();
+        assert_eq!(
+            apply_id_policy(&mut id, &IdPolicy::Allowlist, &allowlist),
+            Some(allowlist[0])
+        );
+        assert_eq!((id.vendor, id.product), (0x045e, 0x028e));
+
+        let mut id = xbox360();
+        id.product = 0x02ea;
+        assert_eq!(
+            apply_id_policy(&mut id, &IdPolicy::Allowlist, &allowlist),
+            None
+        );
+        assert_eq!(id.vendor, VUINPUTD_VENDOR);
+
+        let mut id = xbox360();
+        id.bustype = 0x05;
+        assert!(apply_id_policy(&mut id, &IdPolicy::Preserve, &[]).is_some());
+        assert_eq!(id.bustype, 0x05);
+    }
+
+    #[test]
+    fn marks_the_phys() {
+        assert_eq!(marked_phys(None), "vuinputd");
+        assert_eq!(marked_phys(Some("")), "vuinputd");
+        assert_eq!(marked_phys(Some("usb-1/input0")), "vuinputd/usb-1/input0");
+    }
+}
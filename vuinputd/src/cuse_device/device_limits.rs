@@ -0,0 +1,73 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Counts the devices that exist per container (identified by its mnt and net namespace) to
+// enforce limits.max-devices-per-container. Requests from the namespaces of vuinputd itself
+// are not limited.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+use crate::process_tools::Namespaces;
+
+type ContainerKey = (Option<u64>, Option<u64>);
+
+static DEVICES_PER_CONTAINER: OnceLock<Mutex<HashMap<ContainerKey, u32>>> = OnceLock::new();
+
+fn key(namespaces: &Namespaces) -> ContainerKey {
+    (namespaces.mnt, namespaces.net)
+}
+
+fn counts() -> &'static Mutex<HashMap<ContainerKey, u32>> {
+    DEVICES_PER_CONTAINER.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Counts a new device, unless the container already reached the limit.
+pub fn try_reserve(namespaces: &Namespaces, limit: Option<u32>) -> bool {
+    let mut counts = counts().lock().unwrap();
+    let count = counts.entry(key(namespaces)).or_insert(0);
+    if limit.is_some_and(|limit| *count >= limit) {
+        return false;
+    }
+    *count += 1;
+    true
+}
+
+pub fn release(namespaces: &Namespaces) {
+    let mut counts = counts().lock().unwrap();
+    if let Some(count) = counts.get_mut(&key(namespaces)) {
+        *count = count.saturating_sub(1);
+        if *count == 0 {
+            counts.remove(&key(namespaces));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_is_per_container() {
+        let a = Namespaces {
+            mnt: Some(1001),
+            net: Some(1002),
+            ..Default::default()
+        };
+        let b = Namespaces {
+            mnt: Some(2001),
+            net: Some(2002),
+            ..Default::default()
+        };
+        assert!(try_reserve(&a, Some(1)));
+        assert!(!try_reserve(&a, Some(1)));
+        assert!(try_reserve(&b, Some(1)));
+        release(&a);
+        assert!(try_reserve(&a, Some(1)));
+        assert!(try_reserve(&a, None));
+        release(&a);
+        release(&a);
+        release(&b);
+    }
+}
@@ -0,0 +1,315 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// The name from uinput_setup (or the legacy uinput_user_dev) ends up in sysfs and in the
+// udev properties (NAME=...) that we write into the runtime data of the container. A name
+// with a newline would allow a client to inject arbitrary properties, so it is checked
+// before it is passed to the kernel.
+//
+// Desktops and input remappers also match devices by name, so a container could pass off its
+// device as the built-in keyboard of the host. [device-names] can prefix every name with the
+// container ("vuinputd:<container>:<name>") and deny names of well-known kernel devices.
+
+use crate::global_config::{DeviceNamePolicy, ReloadableConfig};
+use std::fmt;
+use std::os::raw::c_char;
+
+/// Size of the name field including the terminating NUL (see uinput.h)
+pub const UINPUT_MAX_NAME_SIZE: usize = 80;
+
+/// Devices of the host that desktops treat as built-in
+pub const DEFAULT_DENIED_NAMES: [&str; 4] = [
+    "AT Translated Set 2 keyboard",
+    "Power Button",
+    "Sleep Button",
+    "Lid Switch",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameViolation {
+    /// No terminating NUL within UINPUT_MAX_NAME_SIZE bytes
+    NotTerminated,
+    InvalidUtf8,
+    ControlCharacter,
+    /// The name is on the deny-list of [device-names]
+    Denied,
+}
+
+impl fmt::Display for NameViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NameViolation::NotTerminated => write!(f, "name is not NUL-terminated"),
+            NameViolation::InvalidUtf8 => write!(f, "name is not valid UTF-8"),
+            NameViolation::ControlCharacter => write!(f, "name contains control characters"),
+            NameViolation::Denied => write!(f, "name is on the deny-list"),
+        }
+    }
+}
+
+/// Returns the sanitized name and the first violation that has been found, if any.
+/// The sanitized name is valid UTF-8, has no control characters and fits into
+/// UINPUT_MAX_NAME_SIZE bytes including the terminating NUL.
+pub fn sanitize_name(raw: &[u8]) -> (String, Option<NameViolation>) {
+    let mut violation = None;
+
+    let raw = match raw.iter().position(|b| *b == 0) {
+        Some(end) if end < UINPUT_MAX_NAME_SIZE => &raw[..end],
+        _ => {
+            violation = Some(NameViolation::NotTerminated);
+            &raw[..raw.len().min(UINPUT_MAX_NAME_SIZE - 1)]
+        }
+    };
+
+    let decoded = String::from_utf8_lossy(raw);
+    if violation.is_none() && std::str::from_utf8(raw).is_err() {
+        violation = Some(NameViolation::InvalidUtf8);
+    }
+
+    let mut sanitized = String::with_capacity(decoded.len());
+    for c in decoded.chars() {
+        if c.is_control() {
+            violation.get_or_insert(NameViolation::ControlCharacter);
+            continue;
+        }
+        // replacement characters of the lossy decoding are 3 bytes long, so cap again
+        if sanitized.len() + c.len_utf8() > UINPUT_MAX_NAME_SIZE - 1 {
+            break;
+        }
+        sanitized.push(c);
+    }
+
+    (sanitized, violation)
+}
+
+/// What happens with the name of a device that a container sets up
+#[derive(Debug)]
+pub struct NameRules<'a> {
+    pub policy: DeviceNamePolicy,
+    /// Prefix every name with "vuinputd:<container>:"
+    pub prefix: bool,
+    pub denied: &'a [String],
+    /// Named in the prefix, see control::events
+    pub container: u32,
+}
+
+impl<'a> NameRules<'a> {
+    pub fn new(config: &'a ReloadableConfig, container: u32) -> Self {
+        Self {
+            policy: config.device_name_policy,
+            prefix: config.name_prefix,
+            denied: &config.denied_names,
+            container,
+        }
+    }
+}
+
+fn is_denied(name: &str, denied: &[String]) -> bool {
+    denied
+        .iter()
+        .any(|denied| denied.trim().eq_ignore_ascii_case(name.trim()))
+}
+
+/// "vuinputd:<container>:<name>", truncated to UINPUT_MAX_NAME_SIZE bytes including the NUL
+fn prefixed_name(container: u32, name: &str) -> String {
+    let mut prefixed = format!("vuinputd:{}:", container);
+    for c in name.chars() {
+        if prefixed.len() + c.len_utf8() > UINPUT_MAX_NAME_SIZE - 1 {
+            break;
+        }
+        prefixed.push(c);
+    }
+    prefixed
+}
+
+/// Checks the name field of a setup struct and rewrites it in place or rejects it, depending
+/// on the rules. With the policy sanitize, a denied name is prefixed even if the prefix is off.
+pub fn apply_name_policy(
+    name: &mut [c_char; UINPUT_MAX_NAME_SIZE],
+    rules: &NameRules,
+) -> Result<Option<NameViolation>, NameViolation> {
+    let raw: Vec<u8> = name.iter().map(|c| *c as u8).collect();
+    let (sanitized, mut violation) = sanitize_name(&raw);
+    // the sanitized name is the one that reaches the kernel, so "Power Button\n" is denied too
+    if is_denied(&sanitized, rules.denied) {
+        violation = Some(NameViolation::Denied);
+    }
+
+    let rewritten = match (violation, rules.policy) {
+        (Some(violation), DeviceNamePolicy::Reject) => return Err(violation),
+        (Some(NameViolation::Denied), _) => prefixed_name(rules.container, &sanitized),
+        (_, _) if rules.prefix => prefixed_name(rules.container, &sanitized),
+        (Some(_), _) => sanitized,
+        (None, _) => return Ok(None),
+    };
+    name.fill(0);
+    for (dst, src) in name.iter_mut().zip(rewritten.bytes()) {
+        *dst = src as c_char;
+    }
+    Ok(violation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_field(bytes: &[u8]) -> [c_char; UINPUT_MAX_NAME_SIZE] {
+        let mut field = [0 as c_char; UINPUT_MAX_NAME_SIZE];
+        for (dst, src) in field.iter_mut().zip(bytes) {
+            *dst = *src as c_char;
+        }
+        field
+    }
+
+    fn field_to_bytes(field: &[c_char; UINPUT_MAX_NAME_SIZE]) -> Vec<u8> {
+        field.iter().map(|c| *c as u8).collect()
+    }
+
+    fn rules(policy: DeviceNamePolicy) -> NameRules<'static> {
+        NameRules {
+            policy,
+            prefix: false,
+            denied: &[],
+            container: 4690,
+        }
+    }
+
+    #[test]
+    fn regular_name_is_untouched() {
+        let mut field = to_field(b"Example Keyboard");
+        let before = field;
+        assert_eq!(
+            apply_name_policy(&mut field, &rules(DeviceNamePolicy::Sanitize)),
+            Ok(None)
+        );
+        assert_eq!(field, before);
+    }
+
+    #[test]
+    fn newline_is_stripped_or_rejected() {
+        let mut field = to_field(b"Evil\nID_INPUT_KEYBOARD=1");
+        assert_eq!(
+            apply_name_policy(&mut field, &rules(DeviceNamePolicy::Reject)),
+            Err(NameViolation::ControlCharacter)
+        );
+        assert_eq!(
+            apply_name_policy(&mut field, &rules(DeviceNamePolicy::Sanitize)),
+            Ok(Some(NameViolation::ControlCharacter))
+        );
+        assert_eq!(
+            sanitize_name(&field_to_bytes(&field)).0,
+            "EvilID_INPUT_KEYBOARD=1"
+        );
+    }
+
+    #[test]
+    fn unterminated_name_is_truncated() {
+        let mut field = to_field(&[b'a'; UINPUT_MAX_NAME_SIZE]);
+        assert_eq!(
+            apply_name_policy(&mut field, &rules(DeviceNamePolicy::Sanitize)),
+            Ok(Some(NameViolation::NotTerminated))
+        );
+        assert_eq!(field[UINPUT_MAX_NAME_SIZE - 1], 0);
+        assert_eq!(field[UINPUT_MAX_NAME_SIZE - 2], b'a' as c_char);
+    }
+
+    #[test]
+    fn names_are_prefixed() {
+        let prefix = NameRules {
+            prefix: true,
+            ..rules(DeviceNamePolicy::Sanitize)
+        };
+        let mut field = to_field(b"Xbox 360 Controller");
+        assert_eq!(apply_name_policy(&mut field, &prefix), Ok(None));
+        assert_eq!(
+            sanitize_name(&field_to_bytes(&field)).0,
+            "vuinputd:4690:Xbox 360 Controller"
+        );
+
+        let mut field = to_field(&[b'a'; UINPUT_MAX_NAME_SIZE - 1]);
+        assert_eq!(apply_name_policy(&mut field, &prefix), Ok(None));
+        assert_eq!(field[UINPUT_MAX_NAME_SIZE - 1], 0);
+        assert!(sanitize_name(&field_to_bytes(&field))
+            .0
+            .starts_with("vuinputd:4690:aaa"));
+    }
+
+    #[test]
+    fn denied_names_are_prefixed_or_rejected() {
+        let denied: Vec<String> = DEFAULT_DENIED_NAMES.iter().map(|n| n.to_string()).collect();
+        let sanitize = NameRules {
+            denied: &denied,
+            ..rules(DeviceNamePolicy::Sanitize)
+        };
+        let reject = NameRules {
+            denied: &denied,
+            ..rules(DeviceNamePolicy::Reject)
+        };
+        let mut field = to_field(b"at translated set 2 keyboard");
+        assert_eq!(
+            apply_name_policy(&mut field, &reject),
+            Err(NameViolation::Denied)
+        );
+        assert_eq!(
+            apply_name_policy(&mut field, &sanitize),
+            Ok(Some(NameViolation::Denied))
+        );
+        assert_eq!(
+            sanitize_name(&field_to_bytes(&field)).0,
+            "vuinputd:4690:at translated set 2 keyboard"
+        );
+
+        // a control character does not hide the name from the deny-list
+        let mut field = to_field(b"Power Button\n");
+        assert_eq!(
+            apply_name_policy(&mut field, &reject),
+            Err(NameViolation::Denied)
+        );
+        assert_eq!(
+            apply_name_policy(&mut field, &sanitize),
+            Ok(Some(NameViolation::Denied))
+        );
+        assert_eq!(
+            sanitize_name(&field_to_bytes(&field)).0,
+            "vuinputd:4690:Power Button"
+        );
+    }
+
+    // Poor man's fuzzing of the setup path: random byte patterns (xorshift, fixed seed
+    // for reproducibility) must always result in a name that passes the checks again.
+    #[test]
+    fn fuzz_sanitized_names_are_clean() {
+        let mut state: u64 = 0x9e3779b97f4a7c15;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for _ in 0..10_000 {
+            let mut raw = [0u8; UINPUT_MAX_NAME_SIZE];
+            // bias towards interesting bytes: control characters, NUL and UTF-8 lead bytes
+            for byte in raw.iter_mut() {
+                let r = next();
+                *byte = match r % 8 {
+                    0 => (r >> 8) as u8 % 0x20,
+                    1 => 0xc0 | ((r >> 8) as u8 & 0x3f),
+                    2 => 0x80 | ((r >> 8) as u8 & 0x3f),
+                    3 if r % 64 == 3 => 0,
+                    _ => (r >> 8) as u8,
+                };
+            }
+
+            let mut field = to_field(&raw);
+            let result = apply_name_policy(&mut field, &rules(DeviceNamePolicy::Sanitize));
+            assert!(result.is_ok());
+
+            let sanitized = field_to_bytes(&field);
+            let end = sanitized.iter().position(|b| *b == 0).unwrap();
+            let name = std::str::from_utf8(&sanitized[..end]).unwrap();
+            assert!(!name.chars().any(|c| c.is_control()));
+            assert_eq!(sanitize_name(&sanitized), (name.to_string(), None));
+        }
+    }
+}
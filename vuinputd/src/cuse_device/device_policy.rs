@@ -2,20 +2,23 @@
 //
 // Author: Johannes Leupolz <dev@leupolz.eu>
 
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
 use libc::input_event;
 
 // event types and codes from https://github.com/torvalds/linux/blob/master/include/uapi/linux/input-event-codes.h
 
 const EV_SYN: u16 = 0x00;
-const EV_KEY: u16 = 0x01;
-const EV_REL: u16 = 0x02;
-const EV_ABS: u16 = 0x03;
-const EV_MSC: u16 = 0x04;
-const EV_SW: u16 = 0x05;
-const EV_LED: u16 = 0x11;
-const EV_SND: u16 = 0x12;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+pub const EV_MSC: u16 = 0x04;
+pub const EV_SW: u16 = 0x05;
+pub const EV_LED: u16 = 0x11;
+pub const EV_SND: u16 = 0x12;
 const EV_REP: u16 = 0x14;
-const EV_FF: u16 = 0x15;
+pub const EV_FF: u16 = 0x15;
 const EV_PWR: u16 = 0x16;
 const EV_FF_STATUS: u16 = 0x17;
 const EV_MAX: u16 = 0x1f;
@@ -42,6 +45,9 @@ const KEY_RESTART: u16 = 0x198;
 const KEY_FN: u16 = 0x1d0;
 // TODO: Should we block range until KEY_FN_RIGHT_SHIFT?
 
+// Share button of Xbox Series controllers, outside of the gamepad ranges
+const KEY_RECORD: u16 = 167;
+
 // Gamepad keys from https://github.com/torvalds/linux/blob/master/Documentation/input/gamepad.rst
 // First range
 const BTN_SOUTH: u16 = 0x130;
@@ -50,22 +56,261 @@ const BTN_THUMBR: u16 = 0x13e;
 const BTN_DPAD_UP: u16 = 0x220;
 const BTN_GRIPR2: u16 = 0x227;
 
-use crate::{cuse_device::state::KeyTracker, global_config::DevicePolicy};
+// Touchpad buttons, finger count and axes, as announced by e.g. the hid-multitouch driver
+const BTN_LEFT: u16 = 0x110;
+const BTN_MIDDLE: u16 = 0x112;
+const BTN_TOOL_FINGER: u16 = 0x145;
+const BTN_TOOL_QUINTTAP: u16 = 0x148;
+const BTN_TOUCH: u16 = 0x14a;
+const BTN_TOOL_DOUBLETAP: u16 = 0x14d;
+const BTN_TOOL_QUADTAP: u16 = 0x14f;
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+const ABS_PRESSURE: u16 = 0x18;
+const ABS_TOOL_WIDTH: u16 = 0x1c;
+const ABS_MT_SLOT: u16 = 0x2f;
+const ABS_MT_TOOL_Y: u16 = 0x3d;
+// libinput uses it to detect jumps of the cursor
+const MSC_TIMESTAMP: u16 = 0x05;
+
+// Absolute pointers and pens: mouse buttons, pen tools, pressure and tilt, the wheels
+const BTN_TASK: u16 = 0x117;
+const BTN_TOOL_PEN: u16 = 0x140;
+const BTN_TOOL_AIRBRUSH: u16 = 0x144;
+const BTN_STYLUS3: u16 = 0x149;
+const BTN_STYLUS2: u16 = 0x14c;
+const ABS_TILT_Y: u16 = 0x1b;
+const REL_HWHEEL: u16 = 0x06;
+const REL_WHEEL: u16 = 0x08;
+const REL_WHEEL_HI_RES: u16 = 0x0b;
+const REL_HWHEEL_HI_RES: u16 = 0x0c;
+
+// Device properties (UI_SET_PROPBIT)
+const INPUT_PROP_POINTER: u16 = 0x00;
+const INPUT_PROP_DIRECT: u16 = 0x01;
+const INPUT_PROP_BUTTONPAD: u16 = 0x02;
+const INPUT_PROP_SEMI_MT: u16 = 0x03;
+const INPUT_PROP_TOPBUTTONPAD: u16 = 0x04;
+
+/// Allowed in addition to the gamepad ranges by default: the Share button of Xbox Series
+/// controllers. The Guide button (BTN_MODE) is part of the first range.
+pub const DEFAULT_GAMEPAD_EXTRA_KEYS: [u16; 1] = [KEY_RECORD];
+
+// keys that are dangerous on their own, regardless of modifiers
+const SANITIZED_BLOCKED_KEYS: [u16; 8] = [
+    KEY_SYSRQ,
+    KEY_POWER,
+    KEY_SLEEP,
+    KEY_WAKEUP,
+    KEY_FN,
+    KEY_BREAK,
+    KEY_PAUSE,
+    KEY_RESTART,
+];
+
+use crate::{
+    cuse_device::{
+        policy_script,
+        state::{DeviceDescriptor, KeyTracker},
+    },
+    global_config::DevicePolicy,
+    process_tools::{Pid, ProcessInstance},
+};
+
+/// Policies set with vuinputctl set-policy, by root process of the container. They take
+/// precedence over the configured policy as long as the container runs, or until vuinputd is
+/// restarted.
+static CONTAINER_POLICIES: Mutex<BTreeMap<ProcessInstance, DevicePolicy>> =
+    Mutex::new(BTreeMap::new());
+
+pub fn set_container_policy(container: Pid, policy: DevicePolicy) -> Result<(), String> {
+    let instance = ProcessInstance::of(container)
+        .ok_or_else(|| format!("there is no process {}", container.as_raw()))?;
+    let mut policies = CONTAINER_POLICIES.lock().unwrap();
+    // the policies of containers that have stopped in the meantime
+    policies.retain(|instance, _| instance.is_alive());
+    policies.insert(instance, policy);
+    Ok(())
+}
+
+pub fn container_policy(container: Pid) -> Option<DevicePolicy> {
+    let instance = ProcessInstance::of(container)?;
+    CONTAINER_POLICIES.lock().unwrap().get(&instance).copied()
+}
+
+/// Whether a client may declare the event type with UI_SET_EVBIT, and whether written events
+/// of the type are forwarded.
+///
+/// No device of a container needs EV_PWR or EV_FF_STATUS. EV_PWR reaches the power handlers
+/// of the host, which may suspend or power it off. EV_FF_STATUS reports the state of force
+/// feedback effects, which only the driver of a real device knows. Only none forwards both,
+/// mute-sys-rq blocks EV_PWR, sanitized and the strict policies block both, and a script
+/// decides itself.
+pub fn is_event_type_allowed(policy: &DevicePolicy, type_: u16) -> bool {
+    match policy {
+        DevicePolicy::None => true,
+        DevicePolicy::MuteSysRq => type_ != EV_PWR,
+        DevicePolicy::Sanitized => !matches!(type_, EV_PWR | EV_FF_STATUS),
+        DevicePolicy::StrictGamepad => matches!(type_, EV_SYN | EV_KEY | EV_ABS | EV_FF),
+        DevicePolicy::StrictTouchpad => matches!(type_, EV_SYN | EV_KEY | EV_ABS | EV_MSC),
+        DevicePolicy::StrictTablet => matches!(type_, EV_SYN | EV_KEY | EV_ABS | EV_REL),
+        DevicePolicy::Script => policy_script::allow_type(type_),
+    }
+}
+
+/// Whether a client may declare the device property with UI_SET_PROPBIT. Only strict-touchpad
+/// and strict-tablet restrict them, to those that keep the device in its class for libinput.
+pub fn is_property_allowed(policy: &DevicePolicy, prop: u16) -> bool {
+    match policy {
+        DevicePolicy::StrictTouchpad => matches!(
+            prop,
+            INPUT_PROP_POINTER
+                | INPUT_PROP_BUTTONPAD
+                | INPUT_PROP_SEMI_MT
+                | INPUT_PROP_TOPBUTTONPAD
+        ),
+        // with DIRECT and BTN_TOUCH but no pen, udev takes the device for a touchscreen
+        DevicePolicy::StrictTablet => prop == INPUT_PROP_POINTER,
+        DevicePolicy::Script => policy_script::allow_property(prop),
+        _ => true,
+    }
+}
+
+/// Why the device would not be of the class the policy allows, checked at UI_DEV_CREATE once
+/// all of its capabilities are known. Without BTN_TOOL_FINGER, or with INPUT_PROP_DIRECT, udev
+/// takes a strict-touchpad device for a touchscreen, and libinput wants INPUT_PROP_POINTER.
+pub fn class_violation(
+    policy: &DevicePolicy,
+    descriptor: &DeviceDescriptor,
+) -> Option<&'static str> {
+    if *policy != DevicePolicy::StrictTouchpad {
+        return None;
+    }
+    let has_finger = descriptor
+        .codes
+        .get(&EV_KEY)
+        .is_some_and(|keys| keys.contains(&BTN_TOOL_FINGER));
+    if descriptor.properties.contains(&INPUT_PROP_DIRECT) {
+        Some("INPUT_PROP_DIRECT")
+    } else if !descriptor.properties.contains(&INPUT_PROP_POINTER) {
+        Some("no INPUT_PROP_POINTER")
+    } else if !has_finger {
+        Some("no BTN_TOOL_FINGER")
+    } else {
+        None
+    }
+}
+
+/// Whether a client may declare the code with UI_SET_KEYBIT, UI_SET_ABSBIT, etc. Keys that
+/// are only dangerous in combination (e.g. Alt+F1) can't be judged at setup and are left
+/// to the filter on write. `gamepad_extra_keys` are only considered by strict-gamepad.
+pub fn is_code_allowed(
+    policy: &DevicePolicy,
+    gamepad_extra_keys: &[u16],
+    type_: u16,
+    code: u16,
+) -> bool {
+    match policy {
+        DevicePolicy::None => true,
+        DevicePolicy::MuteSysRq => !(type_ == EV_KEY && code == KEY_SYSRQ),
+        DevicePolicy::Sanitized => !(type_ == EV_KEY && SANITIZED_BLOCKED_KEYS.contains(&code)),
+        DevicePolicy::StrictGamepad => match type_ {
+            // Analog sticks, triggers and force feedback
+            EV_ABS | EV_FF => true,
+            EV_KEY => {
+                matches!(code,
+                // Standard gamepad face + shoulder + stick buttons
+                BTN_SOUTH..=BTN_THUMBR
+                // D-Pad + extended gamepad buttons (triggers, paddles)
+                | BTN_DPAD_UP..=BTN_GRIPR2)
+                    || gamepad_extra_keys.contains(&code)
+            }
+            // Explicitly reject everything else (EV_REL, EV_MSC, etc.)
+            _ => false,
+        },
+        DevicePolicy::StrictTouchpad => match type_ {
+            EV_KEY => matches!(code,
+                // Physical buttons or clickpad
+                BTN_LEFT..=BTN_MIDDLE
+                // Number of fingers on the pad
+                | BTN_TOUCH | BTN_TOOL_FINGER | BTN_TOOL_DOUBLETAP..=BTN_TOOL_QUADTAP
+                | BTN_TOOL_QUINTTAP),
+            EV_ABS => matches!(
+                code,
+                ABS_X | ABS_Y | ABS_PRESSURE | ABS_TOOL_WIDTH | ABS_MT_SLOT..=ABS_MT_TOOL_Y
+            ),
+            EV_MSC => code == MSC_TIMESTAMP,
+            _ => false,
+        },
+        DevicePolicy::StrictTablet => match type_ {
+            EV_KEY => matches!(code,
+                BTN_LEFT..=BTN_TASK
+                | BTN_TOOL_PEN..=BTN_TOOL_AIRBRUSH
+                // BTN_STYLUS3, BTN_TOUCH, BTN_STYLUS and BTN_STYLUS2
+                | BTN_STYLUS3..=BTN_STYLUS2),
+            // ABS_PRESSURE, ABS_DISTANCE and the tilt
+            EV_ABS => matches!(code, ABS_X | ABS_Y | ABS_PRESSURE..=ABS_TILT_Y),
+            // wheels only, a relative motion would turn it into a mouse
+            EV_REL => matches!(
+                code,
+                REL_HWHEEL | REL_WHEEL | REL_WHEEL_HI_RES | REL_HWHEEL_HI_RES
+            ),
+            _ => false,
+        },
+        DevicePolicy::Script => policy_script::allow_code(type_, code),
+    }
+}
+
+/// Bitmap in the format of EVIOCGBIT of the codes of `type_` the policy lets through at setup,
+/// type 0 for the event types. Events that are only filtered on write are included.
+pub fn allowed_bitmap(
+    policy: &DevicePolicy,
+    gamepad_extra_keys: &[u16],
+    type_: u16,
+    len: usize,
+) -> Vec<u8> {
+    let mut bitmap = vec![0u8; len];
+    for (index, byte) in bitmap.iter_mut().enumerate() {
+        for bit in 0..8 {
+            let Ok(code) = u16::try_from(index * 8 + bit) else {
+                return bitmap;
+            };
+            let allowed = match type_ {
+                0 => is_event_type_allowed(policy, code),
+                _ => is_code_allowed(policy, gamepad_extra_keys, type_, code),
+            };
+            if allowed {
+                *byte |= 1 << bit;
+            }
+        }
+    }
+    bitmap
+}
 
-pub fn is_allowed(keytracker: &mut KeyTracker, policy: &DevicePolicy, event: &input_event) -> bool {
+/// Whether a written event is forwarded. A script may change the event instead of dropping it.
+pub fn is_allowed(
+    keytracker: &mut KeyTracker,
+    policy: &DevicePolicy,
+    gamepad_extra_keys: &[u16],
+    event: &mut input_event,
+) -> bool {
     match policy {
         DevicePolicy::None => true,
         DevicePolicy::MuteSysRq => is_allowed_in_mute_sysrq(keytracker, event),
         DevicePolicy::Sanitized => is_allowed_in_sanitized_mode(keytracker, event),
-        DevicePolicy::StrictGamepad => is_allowed_in_strict_gamepad_mode(keytracker, event),
+        DevicePolicy::StrictGamepad => {
+            is_allowed_in_strict_gamepad_mode(keytracker, gamepad_extra_keys, event)
+        }
+        DevicePolicy::StrictTouchpad | DevicePolicy::StrictTablet => {
+            event.type_ == EV_SYN || is_code_allowed(policy, &[], event.type_, event.code)
+        }
+        DevicePolicy::Script => policy_script::filter_event(event),
     }
 }
 
 fn is_allowed_in_mute_sysrq(_keytracker: &mut KeyTracker, event: &input_event) -> bool {
-    if event.type_ == EV_KEY && event.code == KEY_SYSRQ {
-        return false;
-    }
-    true
+    is_event_type_allowed(&DevicePolicy::MuteSysRq, event.type_)
+        && is_code_allowed(&DevicePolicy::MuteSysRq, &[], event.type_, event.code)
 }
 
 fn is_allowed_in_sanitized_mode(keytracker: &mut KeyTracker, event: &input_event) -> bool {
@@ -73,6 +318,10 @@ fn is_allowed_in_sanitized_mode(keytracker: &mut KeyTracker, event: &input_event
     let code = event.code;
     let value = event.value;
 
+    if !is_event_type_allowed(&DevicePolicy::Sanitized, type_) {
+        return false;
+    }
+
     if type_ == EV_KEY {
         match code {
             KEY_LEFTALT => keytracker.left_alt_down = value > 0,
@@ -84,10 +333,7 @@ fn is_allowed_in_sanitized_mode(keytracker: &mut KeyTracker, event: &input_event
     }
 
     if type_ == EV_KEY {
-        // 1. Block SysRq in general
-        if code == KEY_SYSRQ {
-            return false;
-        }
+        // 1. Block SysRq in general (part of the standalone keys, see 5.)
 
         let alt_down = keytracker.left_alt_down || keytracker.right_alt_down;
         let ctrl_down = keytracker.left_ctrl_down || keytracker.right_ctrl_down;
@@ -125,39 +371,253 @@ fn is_allowed_in_sanitized_mode(keytracker: &mut KeyTracker, event: &input_event
         // distro follows the ancient https://www.kernel.org/doc/Documentation/SAK.txt
 
         // 5. Block standalone dangerous keys
-        match code {
-            KEY_POWER | KEY_SLEEP | KEY_WAKEUP | KEY_FN | KEY_BREAK | KEY_PAUSE | KEY_RESTART => {
-                return false
-            }
-            _ => {}
+        if !is_code_allowed(&DevicePolicy::Sanitized, &[], type_, code) {
+            return false;
         }
     }
     true
 }
 
-fn is_allowed_in_strict_gamepad_mode(_keytracker: &mut KeyTracker, event: &input_event) -> bool {
-    match event.type_ {
-        EV_SYN => true,
+fn is_allowed_in_strict_gamepad_mode(
+    _keytracker: &mut KeyTracker,
+    gamepad_extra_keys: &[u16],
+    event: &input_event,
+) -> bool {
+    // digital buttons only, everything else is rejected (KEY_*, mouse buttons, etc.)
+    event.type_ == EV_SYN
+        || is_code_allowed(
+            &DevicePolicy::StrictGamepad,
+            gamepad_extra_keys,
+            event.type_,
+            event.code,
+        )
+}
 
-        // Analog sticks, triggers
-        EV_ABS => true,
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        // Force feedback
-        EV_FF => true,
+    #[test]
+    fn allowed_bitmaps() {
+        let types = allowed_bitmap(&DevicePolicy::StrictGamepad, &[], 0, 4);
+        // EV_SYN, EV_KEY, EV_ABS and EV_FF
+        assert_eq!(types, vec![0b0000_1011, 0, 0b0010_0000, 0]);
 
-        // Digital buttons only
-        EV_KEY => match event.code {
-            // Standard gamepad face + shoulder + stick buttons
-            BTN_SOUTH..=BTN_THUMBR => true,
+        let keys = allowed_bitmap(&DevicePolicy::MuteSysRq, &[], EV_KEY, 96);
+        assert_eq!(keys[(KEY_SYSRQ / 8) as usize] & (1 << (KEY_SYSRQ % 8)), 0);
+        assert_eq!(keys[0], 0xff);
+    }
 
-            // D-Pad + extended gamepad buttons (triggers, paddles)
-            BTN_DPAD_UP..=BTN_GRIPR2 => true,
+    #[test]
+    fn strict_touchpad() {
+        let policy = DevicePolicy::StrictTouchpad;
+        assert!(is_event_type_allowed(&policy, EV_MSC));
+        assert!(!is_event_type_allowed(&policy, EV_REL));
+        assert!(is_code_allowed(&policy, &[], EV_KEY, BTN_TOOL_DOUBLETAP));
+        assert!(is_code_allowed(&policy, &[], EV_ABS, ABS_MT_SLOT));
+        assert!(!is_code_allowed(&policy, &[], EV_KEY, KEY_SYSRQ));
+        assert!(!is_code_allowed(&policy, &[], EV_KEY, BTN_SOUTH));
+        assert!(is_property_allowed(&policy, INPUT_PROP_BUTTONPAD));
+        // INPUT_PROP_DIRECT would turn it into a touchscreen
+        assert!(!is_property_allowed(&policy, INPUT_PROP_DIRECT));
+        assert!(is_property_allowed(
+            &DevicePolicy::StrictGamepad,
+            INPUT_PROP_DIRECT
+        ));
 
-            // Everything else is rejected (KEY_*, mouse buttons, etc.)
-            _ => false,
-        },
+        // so would a device without fingers
+        let mut touchpad = DeviceDescriptor::default();
+        touchpad.codes.entry(EV_KEY).or_default().insert(BTN_TOUCH);
+        assert_eq!(
+            class_violation(&policy, &touchpad),
+            Some("no INPUT_PROP_POINTER")
+        );
+        touchpad.properties.insert(INPUT_PROP_POINTER);
+        assert_eq!(
+            class_violation(&policy, &touchpad),
+            Some("no BTN_TOOL_FINGER")
+        );
+        touchpad
+            .codes
+            .entry(EV_KEY)
+            .or_default()
+            .insert(BTN_TOOL_FINGER);
+        assert_eq!(class_violation(&policy, &touchpad), None);
+        assert_eq!(
+            class_violation(&DevicePolicy::StrictTablet, &DeviceDescriptor::default()),
+            None
+        );
+
+        let mut keytracker = KeyTracker::new();
+        let event = |type_, code| input_event {
+            time: libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            type_,
+            code,
+            value: 1,
+        };
+        assert!(is_allowed(
+            &mut keytracker,
+            &policy,
+            &[],
+            &mut event(EV_SYN, 0)
+        ));
+        assert!(is_allowed(
+            &mut keytracker,
+            &policy,
+            &[],
+            &mut event(EV_MSC, MSC_TIMESTAMP)
+        ));
+        assert!(!is_allowed(
+            &mut keytracker,
+            &policy,
+            &[],
+            &mut event(EV_KEY, KEY_SYSRQ)
+        ));
+    }
+
+    #[test]
+    fn strict_tablet() {
+        let policy = DevicePolicy::StrictTablet;
+        assert!(is_event_type_allowed(&policy, EV_REL));
+        assert!(!is_event_type_allowed(&policy, EV_MSC));
+        assert!(is_code_allowed(&policy, &[], EV_KEY, BTN_LEFT));
+        assert!(is_code_allowed(&policy, &[], EV_KEY, BTN_TOUCH));
+        assert!(is_code_allowed(&policy, &[], EV_ABS, ABS_TILT_Y));
+        assert!(is_code_allowed(&policy, &[], EV_REL, REL_WHEEL));
+        // REL_X
+        assert!(!is_code_allowed(&policy, &[], EV_REL, 0x00));
+        assert!(!is_code_allowed(&policy, &[], EV_KEY, KEY_SYSRQ));
+        assert!(!is_code_allowed(&policy, &[], EV_ABS, ABS_MT_SLOT));
+        assert!(is_property_allowed(&policy, INPUT_PROP_POINTER));
+        assert!(!is_property_allowed(&policy, INPUT_PROP_DIRECT));
+        assert!(!is_property_allowed(&policy, INPUT_PROP_BUTTONPAD));
+    }
+
+    #[test]
+    fn capabilities_at_setup() {
+        assert!(is_code_allowed(&DevicePolicy::None, &[], EV_KEY, KEY_SYSRQ));
+        assert!(!is_code_allowed(
+            &DevicePolicy::MuteSysRq,
+            &[],
+            EV_KEY,
+            KEY_SYSRQ
+        ));
+        assert!(is_code_allowed(
+            &DevicePolicy::MuteSysRq,
+            &[],
+            EV_KEY,
+            KEY_POWER
+        ));
+        assert!(!is_code_allowed(
+            &DevicePolicy::Sanitized,
+            &[],
+            EV_KEY,
+            KEY_POWER
+        ));
+        // only dangerous together with Alt, so it is left to the filter on write
+        assert!(is_code_allowed(
+            &DevicePolicy::Sanitized,
+            &[],
+            EV_KEY,
+            KEY_F1
+        ));
+
+        assert!(is_event_type_allowed(&DevicePolicy::StrictGamepad, EV_ABS));
+        assert!(!is_event_type_allowed(&DevicePolicy::StrictGamepad, EV_REL));
+        assert!(is_code_allowed(
+            &DevicePolicy::StrictGamepad,
+            &[],
+            EV_KEY,
+            BTN_SOUTH
+        ));
+        assert!(!is_code_allowed(
+            &DevicePolicy::StrictGamepad,
+            &[],
+            EV_KEY,
+            KEY_LEFTALT
+        ));
+        assert!(!is_code_allowed(
+            &DevicePolicy::StrictGamepad,
+            &[],
+            EV_MSC,
+            4
+        ));
+    }
+
+    #[test]
+    fn power_and_ff_status_events() {
+        let event = |type_| input_event {
+            time: libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            type_,
+            code: 0,
+            value: 1,
+        };
+        // (policy, EV_PWR, EV_FF_STATUS)
+        let decisions = [
+            (DevicePolicy::None, true, true),
+            (DevicePolicy::MuteSysRq, false, true),
+            (DevicePolicy::Sanitized, false, false),
+            (DevicePolicy::StrictGamepad, false, false),
+            (DevicePolicy::StrictTouchpad, false, false),
+            (DevicePolicy::StrictTablet, false, false),
+        ];
+        for (policy, power, ff_status) in decisions {
+            assert_eq!(
+                is_event_type_allowed(&policy, EV_PWR),
+                power,
+                "{:?}",
+                policy
+            );
+            assert_eq!(
+                is_event_type_allowed(&policy, EV_FF_STATUS),
+                ff_status,
+                "{:?}",
+                policy
+            );
+            let mut keytracker = KeyTracker::new();
+            let written =
+                |type_| is_allowed(&mut KeyTracker::new(), &policy, &[], &mut event(type_));
+            assert_eq!(written(EV_PWR), power, "{:?}", policy);
+            assert_eq!(written(EV_FF_STATUS), ff_status, "{:?}", policy);
+            // the rest of the device still works
+            assert!(is_allowed(
+                &mut keytracker,
+                &policy,
+                &[],
+                &mut event(EV_SYN)
+            ));
+        }
+    }
 
-        // Explicitly reject everything else (EV_REL, EV_MSC, etc.)
-        _ => false,
+    #[test]
+    fn gamepad_extra_keys() {
+        let extra_keys = [KEY_RECORD];
+        let mut share = input_event {
+            time: libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            type_: EV_KEY,
+            code: KEY_RECORD,
+            value: 1,
+        };
+        let mut keytracker = KeyTracker::new();
+        let policy = DevicePolicy::StrictGamepad;
+        assert!(!is_allowed(&mut keytracker, &policy, &[], &mut share));
+        assert!(is_allowed(
+            &mut keytracker,
+            &policy,
+            &extra_keys,
+            &mut share
+        ));
+        // the list doesn't open other policies or event types
+        assert!(!is_code_allowed(&policy, &extra_keys, EV_REL, KEY_RECORD));
+        assert!(!is_code_allowed(&policy, &extra_keys, EV_KEY, KEY_POWER));
     }
 }
@@ -0,0 +1,105 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Serial numbers of the devices in containers, e.g. "vuinput-5c0e9a1f-0". The input_id builtin
+// of udev reports "noserial" for every virtual device, so compositors and libinput quirks can't
+// tell two gamepads of a container apart, nor recognize one that reconnects. The serial names
+// the container (a hash of its mnt and net namespace, like device_limits identifies it) and the
+// lowest number that is not taken by another device of the container. A client that closes its
+// device and creates it again therefore gets the same serial, as long as the container runs.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+
+use crate::process_tools::Namespaces;
+
+static TAKEN: OnceLock<Mutex<HashMap<u32, BTreeSet<u32>>>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceSerial {
+    container: u32,
+    number: u32,
+}
+
+impl fmt::Display for DeviceSerial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "vuinput-{:08x}-{}", self.container, self.number)
+    }
+}
+
+/// FNV-1a of the namespace inodes, stable across restarts of vuinputd
+fn container_hash(namespaces: &Namespaces) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for inode in [namespaces.mnt, namespaces.net] {
+        for byte in inode.unwrap_or(0).to_le_bytes() {
+            hash ^= u32::from(byte);
+            hash = hash.wrapping_mul(0x01000193);
+        }
+    }
+    hash
+}
+
+fn taken() -> &'static Mutex<HashMap<u32, BTreeSet<u32>>> {
+    TAKEN.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Takes the lowest free number of the container. Give it back with `release`.
+pub fn allocate(namespaces: &Namespaces) -> DeviceSerial {
+    let container = container_hash(namespaces);
+    let mut taken = taken().lock().unwrap();
+    let numbers = taken.entry(container).or_default();
+    let number = (0..).find(|number| !numbers.contains(number)).unwrap();
+    numbers.insert(number);
+    DeviceSerial { container, number }
+}
+
+pub fn release(serial: &DeviceSerial) {
+    let mut taken = taken().lock().unwrap();
+    if let Some(numbers) = taken.get_mut(&serial.container) {
+        numbers.remove(&serial.number);
+        if numbers.is_empty() {
+            taken.remove(&serial.container);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_the_lowest_free_number() {
+        let a = Namespaces {
+            mnt: Some(3001),
+            net: Some(3002),
+            ..Default::default()
+        };
+        let b = Namespaces {
+            mnt: Some(4001),
+            net: Some(4002),
+            ..Default::default()
+        };
+        let first = allocate(&a);
+        let second = allocate(&a);
+        assert_eq!(
+            first.to_string(),
+            format!("vuinput-{:08x}-0", container_hash(&a))
+        );
+        assert_eq!(second.number, 1);
+        assert_eq!(allocate(&b).number, 0);
+        assert_ne!(container_hash(&a), container_hash(&b));
+
+        // the first device reconnects
+        release(&first);
+        assert_eq!(allocate(&a), first);
+
+        release(&first);
+        release(&second);
+        release(&DeviceSerial {
+            container: container_hash(&b),
+            number: 0,
+        });
+    }
+}
@@ -0,0 +1,147 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// ioctls of vuinputd itself on the CUSE node, so that tools in a container (vuinputctl probe,
+// or a debug build of a streaming host) can tell that /dev/uinput is mediated and what the
+// device policy permits. They use numbers of the uinput type 'U' above the highest of uinput,
+// UI_BEGIN_FF_UPLOAD to UI_END_FF_ERASE (0xc8 to 0xcb), so the kernel uinput answers them with
+// EINVAL:
+//
+//   VUI_GET_INFO                 _IOR('U', 0xf0, struct vuinput_info)
+//   VUI_GET_ALLOWED_BITS(ev,len) _IOC(_IOC_READ, 'U', 0xd0 + ev, len)
+//
+// VUI_GET_ALLOWED_BITS works like EVIOCGBIT: a bitmap of the codes of type ev the device policy
+// lets through (ev 0: the event types), returning the number of bytes written.
+// This file is also compiled into vuinputctl, so it must only depend on libc, nix and std.
+
+use libc::c_char;
+use nix::request_code_read;
+use nix::sys::ioctl::{NRMASK, NRSHIFT, SIZEMASK, SIZESHIFT};
+
+/// "VUIN", identifies the answer of vuinputd
+pub const VUINPUT_INFO_MAGIC: u32 = u32::from_be_bytes(*b"VUIN");
+pub const VUINPUT_INFO_VERSION: u32 = 1;
+
+pub const LIFECYCLE_OPENED: u32 = 0;
+pub const LIFECYCLE_CREATED: u32 = 1;
+pub const LIFECYCLE_DESTROYED: u32 = 2;
+
+/// struct vuinput_info. Strings are NUL-terminated, major and minor are 0 as long as no
+/// device has been created.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct VuinputInfo {
+    pub magic: u32,
+    pub version: u32,
+    /// LIFECYCLE_*
+    pub lifecycle: u32,
+    /// Capability bits refused by the device policy so far
+    pub rejected: u32,
+    pub major: u32,
+    pub minor: u32,
+    /// e.g. "strict-gamepad"
+    pub policy: [c_char; 32],
+    /// The event node on the host, e.g. "/dev/input/event7"
+    pub devnode: [c_char; 64],
+}
+
+impl Default for VuinputInfo {
+    fn default() -> Self {
+        VuinputInfo {
+            magic: VUINPUT_INFO_MAGIC,
+            version: VUINPUT_INFO_VERSION,
+            lifecycle: LIFECYCLE_OPENED,
+            rejected: 0,
+            major: 0,
+            minor: 0,
+            policy: [0; 32],
+            devnode: [0; 64],
+        }
+    }
+}
+
+pub const VUI_GET_INFO: u64 = request_code_read!(b'U', 0xf0, ::std::mem::size_of::<VuinputInfo>());
+
+/// Up to 0xef with EV_MAX, below VUI_GET_INFO
+const ALLOWED_BITS_NR: u64 = 0xd0;
+/// EV_MAX
+const EV_MAX: u16 = 0x1f;
+/// Large enough for KEY_MAX, the type with the most codes
+pub const ALLOWED_BITS_MAX_LEN: usize = (0x2ff + 1) / 8;
+
+pub fn vui_get_allowed_bits(type_: u16, len: usize) -> u64 {
+    request_code_read!(b'U', ALLOWED_BITS_NR + u64::from(type_), len)
+}
+
+/// The event type of a VUI_GET_ALLOWED_BITS request, None for any other ioctl
+pub fn allowed_bits_type(cmd: u64) -> Option<u16> {
+    let nr = (cmd >> NRSHIFT) & NRMASK;
+    let without_size = cmd & !(SIZEMASK << SIZESHIFT);
+    let type_ = u16::try_from(nr.checked_sub(ALLOWED_BITS_NR)?).ok()?;
+    (type_ <= EV_MAX && without_size == vui_get_allowed_bits(type_, 0)).then_some(type_)
+}
+
+/// Copies as much of `value` as fits, always leaving a terminating NUL
+pub fn copy_str(dst: &mut [c_char], value: &str) {
+    let len = value.len().min(dst.len().saturating_sub(1));
+    for (d, s) in dst.iter_mut().zip(value.as_bytes()[..len].iter()) {
+        *d = *s as c_char;
+    }
+    if let Some(nul) = dst.get_mut(len) {
+        *nul = 0;
+    }
+}
+
+pub fn read_str(src: &[c_char]) -> String {
+    let bytes: Vec<u8> = src
+        .iter()
+        .take_while(|c| **c != 0)
+        .map(|c| *c as u8)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::ioctl::{DIRMASK, DIRSHIFT, READ};
+
+    #[test]
+    fn ioctl_numbers() {
+        assert_eq!(std::mem::size_of::<VuinputInfo>(), 120);
+        assert_eq!(
+            allowed_bits_type(vui_get_allowed_bits(0x01, 96)),
+            Some(0x01)
+        );
+        assert_eq!(allowed_bits_type(vui_get_allowed_bits(0x00, 4)), Some(0x00));
+        assert_eq!(allowed_bits_type(VUI_GET_INFO), None);
+        // UI_SET_EVBIT
+        assert_eq!(allowed_bits_type(request_code_read!(b'U', 100, 4)), None);
+        assert_eq!(allowed_bits_type(vui_get_allowed_bits(0x20, 4)), None);
+        // UI_BEGIN_FF_UPLOAD, UI_END_FF_UPLOAD, UI_BEGIN_FF_ERASE and UI_END_FF_ERASE
+        let upload = std::mem::size_of::<libc::uinput_ff_upload>();
+        let erase = std::mem::size_of::<libc::uinput_ff_erase>();
+        for cmd in [
+            nix::request_code_readwrite!(b'U', 200, upload),
+            nix::request_code_write!(b'U', 201, upload),
+            nix::request_code_readwrite!(b'U', 202, erase),
+            nix::request_code_write!(b'U', 203, erase),
+        ] {
+            assert_eq!(allowed_bits_type(cmd), None);
+            // not even with the direction of VUI_GET_ALLOWED_BITS
+            let as_read = (cmd & !(DIRMASK << DIRSHIFT)) | (u64::from(READ) << DIRSHIFT);
+            assert_eq!(allowed_bits_type(as_read), None);
+            assert_ne!(cmd, VUI_GET_INFO);
+        }
+    }
+
+    #[test]
+    fn strings_are_truncated_and_terminated() {
+        let mut buffer: [c_char; 8] = [1; 8];
+        copy_str(&mut buffer, "strict-gamepad");
+        assert_eq!(read_str(&buffer), "strict-");
+        copy_str(&mut buffer, "none");
+        assert_eq!(read_str(&buffer), "none");
+    }
+}
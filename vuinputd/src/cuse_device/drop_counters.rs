@@ -0,0 +1,135 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Events a client writes, but that never reach its device, counted by why they were dropped.
+// They are shown by vuinputctl (devices --json, top), so that input that "feels laggy" can be
+// told apart from a policy that drops keys. If a handle drops many events, a summary is logged
+// once a minute instead of a line per event.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Drops within a minute that are worth a warning
+pub const DROP_WARNING_THRESHOLD: u64 = 100;
+
+const DROP_WARNING_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropCause {
+    /// Not allowed by the device policy of the handle
+    Policy,
+    /// A keyboard key while the session of the host is locked (block-keys-when-locked)
+    SessionLocked,
+    /// The write to the host uinput fd failed
+    Uinput,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DropCounters {
+    pub policy: u64,
+    pub session_locked: u64,
+    pub uinput: u64,
+}
+
+impl DropCounters {
+    pub fn add(&mut self, cause: DropCause, events: u64) {
+        let counter = match cause {
+            DropCause::Policy => &mut self.policy,
+            DropCause::SessionLocked => &mut self.session_locked,
+            DropCause::Uinput => &mut self.uinput,
+        };
+        *counter += events;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.policy + self.session_locked + self.uinput
+    }
+}
+
+/// e.g. "120 by the device policy, 3 by uinput errors"
+impl fmt::Display for DropCounters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts = [
+            (self.policy, "by the device policy"),
+            (self.session_locked, "while the session was locked"),
+            (self.uinput, "by uinput errors"),
+        ];
+        let mut first = true;
+        for (count, cause) in parts.into_iter().filter(|(count, _)| *count > 0) {
+            if !first {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} {}", count, cause)?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+/// The drops since the first one of the current minute
+#[derive(Debug, Default)]
+pub struct DropWindow {
+    start: Option<Instant>,
+    counters: DropCounters,
+}
+
+impl DropWindow {
+    pub fn record(&mut self, cause: DropCause, events: u64, now: Instant) {
+        self.start.get_or_insert(now);
+        self.counters.add(cause, events);
+    }
+
+    /// Once the minute is over, starts a new one and returns the drops of the past one, if
+    /// they reached DROP_WARNING_THRESHOLD
+    pub fn summary(&mut self, now: Instant) -> Option<DropCounters> {
+        let start = self.start?;
+        if now.duration_since(start) < DROP_WARNING_WINDOW {
+            return None;
+        }
+        let counters = std::mem::take(&mut self.counters);
+        self.start = None;
+        (counters.total() >= DROP_WARNING_THRESHOLD).then_some(counters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_by_cause() {
+        let mut counters = DropCounters::default();
+        counters.add(DropCause::Policy, 120);
+        counters.add(DropCause::Uinput, 3);
+        assert_eq!(counters.total(), 123);
+        assert_eq!(
+            counters.to_string(),
+            "120 by the device policy, 3 by uinput errors"
+        );
+        assert_eq!(DropCounters::default().to_string(), "");
+    }
+
+    #[test]
+    fn summarizes_a_minute() {
+        let start = Instant::now();
+        let mut window = DropWindow::default();
+        assert_eq!(window.summary(start), None);
+
+        window.record(DropCause::Policy, 60, start);
+        window.record(
+            DropCause::SessionLocked,
+            50,
+            start + Duration::from_secs(30),
+        );
+        assert_eq!(window.summary(start + Duration::from_secs(59)), None);
+        let summary = window.summary(start + Duration::from_secs(60)).unwrap();
+        assert_eq!(summary.policy, 60);
+        assert_eq!(summary.session_locked, 50);
+
+        // a new minute starts with the next drop, too few for a warning
+        window.record(DropCause::Uinput, 1, start + Duration::from_secs(100));
+        assert_eq!(window.summary(start + Duration::from_secs(161)), None);
+        assert_eq!(window.summary(start + Duration::from_secs(300)), None);
+    }
+}
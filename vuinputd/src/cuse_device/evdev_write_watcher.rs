@@ -19,6 +19,8 @@ use cuse_lowlevel::fuse_lowlevel;
 use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags};
 
 use crate::cuse_device::state::{get_vuinput_state, PollPhase, VuFileHandle};
+use crate::cuse_device::vuhid;
+use crate::cuse_device::vuinput_read::complete_pending_read;
 
 pub static EVDEV_WRITE_WATCHER: OnceLock<Mutex<EvdevWriteWatcher>> = OnceLock::new();
 
@@ -66,6 +68,14 @@ impl EvdevWriteWatcher {
         )
     }
 
+    /// Watches another uinput fd for the handle, see reconnect
+    pub fn add_fd<Fd: AsFd>(&self, fh: u64, uinput_fd: Fd) -> nix::Result<()> {
+        self.epoll.add(
+            uinput_fd,
+            EpollEvent::new(EpollFlags::EPOLLIN | EpollFlags::EPOLLET, fh),
+        )
+    }
+
     pub fn remove_device<Fd: AsFd>(&self, uinput_fd: Fd) -> nix::Result<()> {
         self.epoll.delete(uinput_fd)
     }
@@ -111,6 +121,11 @@ fn evdev_write_watch_loop(shutdown: Arc<AtomicBool>, epoll: Arc<Epoll>) {
                     handle.notify();
                 }
                 state.poll.pollphase = PollPhase::Readable;
+                if let Some(pending) = state.pending_read.take() {
+                    complete_pending_read(fh_val, &mut state, pending);
+                }
+            } else {
+                vuhid::on_readable(fh_val);
             }
         }
     }
@@ -0,0 +1,130 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+use std::ffi::{CString, NulError, OsStr};
+use std::os::raw::{c_char, c_int};
+use std::os::unix::ffi::OsStrExt;
+
+/// Owns a C-style argument vector (`argc`/`argv`) for libfuse.
+///
+/// The strings and the pointer array live as long as the `FuseArgs` value and are
+/// freed on drop, so no `CString::into_raw`/`from_raw` juggling is needed around
+/// `cuse_lowlevel_main`. libfuse copies the arguments it wants to keep
+/// (see `fuse_opt_parse`), the strings are never written to.
+#[derive(Debug)]
+pub struct FuseArgs {
+    args: Vec<CString>,
+    // null-terminated array of pointers into `args`. The heap buffers of the CStrings
+    // do not move when `args` grows, so only this array has to be rebuilt.
+    ptrs: Vec<*mut c_char>,
+}
+
+impl FuseArgs {
+    /// Creates an empty argument vector. Use this for `dev_info_argv` of `cuse_info`.
+    pub fn new() -> Self {
+        let mut fuse_args = Self {
+            args: Vec::new(),
+            ptrs: Vec::new(),
+        };
+        fuse_args.rebuild();
+        fuse_args
+    }
+
+    /// Creates an argument vector whose first element is the program name.
+    pub fn with_program_name(program_name: &OsStr) -> Result<Self, NulError> {
+        let mut fuse_args = Self::new();
+        fuse_args.push_bytes(program_name.as_bytes())?;
+        Ok(fuse_args)
+    }
+
+    /// Appends a single argument, e.g. `-f`.
+    pub fn push(&mut self, arg: &str) -> Result<&mut Self, NulError> {
+        self.push_bytes(arg.as_bytes())
+    }
+
+    /// Appends a mount/session option as `-o <option>`, e.g. `allow_other`.
+    pub fn push_option(&mut self, option: &str) -> Result<&mut Self, NulError> {
+        // validate first, so that a failing option does not leave a dangling "-o"
+        let option = CString::new(option)?;
+        self.push("-o")?;
+        self.args.push(option);
+        self.rebuild();
+        Ok(self)
+    }
+
+    fn push_bytes(&mut self, arg: &[u8]) -> Result<&mut Self, NulError> {
+        self.args.push(CString::new(arg)?);
+        self.rebuild();
+        Ok(self)
+    }
+
+    fn rebuild(&mut self) {
+        self.ptrs = self
+            .args
+            .iter()
+            .map(|arg| arg.as_ptr() as *mut c_char)
+            .chain(std::iter::once(std::ptr::null_mut()))
+            .collect();
+    }
+
+    pub fn argc(&self) -> c_int {
+        self.args.len() as c_int
+    }
+
+    /// Pointer to the null-terminated argv array. Valid as long as `self` is neither
+    /// modified nor dropped.
+    pub fn argv(&mut self) -> *mut *mut c_char {
+        self.ptrs.as_mut_ptr()
+    }
+
+    /// Same as `argv`, but typed for `cuse_info.dev_info_argv`.
+    pub fn argv_const(&mut self) -> *mut *const c_char {
+        self.ptrs.as_mut_ptr() as *mut *const c_char
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &CString> {
+        self.args.iter()
+    }
+}
+
+impl Default for FuseArgs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    #[test]
+    fn argv_is_null_terminated() {
+        let mut args = FuseArgs::with_program_name(OsStr::new("vuinputd")).unwrap();
+        args.push("-f").unwrap().push("-s").unwrap();
+        assert_eq!(args.argc(), 3);
+
+        let argv = args.argv();
+        unsafe {
+            assert_eq!(CStr::from_ptr(*argv).to_str().unwrap(), "vuinputd");
+            assert_eq!(CStr::from_ptr(*argv.add(2)).to_str().unwrap(), "-s");
+            assert!((*argv.add(3)).is_null());
+        }
+    }
+
+    #[test]
+    fn options_are_prefixed_with_dash_o() {
+        let mut args = FuseArgs::new();
+        args.push_option("allow_other").unwrap();
+        let collected: Vec<_> = args.iter().map(|a| a.to_str().unwrap()).collect();
+        assert_eq!(collected, vec!["-o", "allow_other"]);
+    }
+
+    #[test]
+    fn interior_nul_is_rejected_without_partial_push() {
+        let mut args = FuseArgs::new();
+        assert!(args.push_option("allow\0other").is_err());
+        assert_eq!(args.argc(), 0);
+    }
+}
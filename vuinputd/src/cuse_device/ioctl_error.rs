@@ -0,0 +1,129 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Errors of an ioctl that is forwarded to the host uinput fd. They end the request, not
+// vuinputd: the client gets the errno the kernel (or the lookup of the created device) reported,
+// the error is logged and counted for its handle (see vuinputctl devices --json).
+
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use libc::{c_int, EINVAL, EIO, ENODEV};
+use nix::errno::Errno;
+
+#[derive(Debug)]
+pub enum VuIoctlError {
+    /// The ioctl on the host uinput fd failed, e.g. UI_DEV_CREATE without UI_DEV_SETUP
+    Host { ioctl: &'static str, errno: Errno },
+    /// The buffer of the ioctl has not been mapped or has an unexpected size
+    Buffer { ioctl: &'static str, size: usize },
+    /// The device has been created, but its event node could not be looked up. The device
+    /// has been destroyed again.
+    DeviceNode { syspath: String, error: io::Error },
+    /// Like DeviceNode, but vuinputd can't see /sys/devices/virtual/input at all (VUI-SYS-001)
+    SysfsNotVisible { message: String },
+}
+
+impl VuIoctlError {
+    /// A closure for map_err, e.g. `ui_dev_setup(fd, setup).map_err(host("UI_DEV_SETUP"))`
+    pub fn host(ioctl: &'static str) -> impl Fn(Errno) -> VuIoctlError {
+        move |errno| VuIoctlError::Host { ioctl, errno }
+    }
+
+    /// The errno for fuse_reply_err
+    pub fn errno(&self) -> c_int {
+        match self {
+            VuIoctlError::Host { errno, .. } => *errno as c_int,
+            VuIoctlError::Buffer { .. } => EINVAL,
+            VuIoctlError::DeviceNode { error, .. } => error.raw_os_error().unwrap_or(EIO),
+            VuIoctlError::SysfsNotVisible { .. } => ENODEV,
+        }
+    }
+}
+
+impl fmt::Display for VuIoctlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VuIoctlError::Host { ioctl, errno } => write!(f, "{} failed: {}", ioctl, errno),
+            VuIoctlError::Buffer { ioctl, size } => {
+                write!(f, "{} with a buffer of {} bytes", ioctl, size)
+            }
+            VuIoctlError::DeviceNode { syspath, error } => {
+                write!(f, "no event node for {}: {}", syspath, error)
+            }
+            VuIoctlError::SysfsNotVisible { message } => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for VuIoctlError {}
+
+/// Errnos of UI_DEV_CREATE that may go away by themselves, e.g. while the host runs out of
+/// input minors or memory. Everything else (e.g. EINVAL without UI_DEV_SETUP) fails again.
+pub fn is_transient(errno: Errno) -> bool {
+    matches!(errno, Errno::EBUSY | Errno::EAGAIN | Errno::ENOMEM)
+}
+
+/// The wait before retry `attempt` (0-based): 10 ms, doubled up to 200 ms. The client's
+/// UI_DEV_CREATE is only answered after the retries, so limits.create-retries is bounded.
+pub fn create_backoff(attempt: u32) -> Duration {
+    Duration::from_millis(10u64.saturating_mul(1 << attempt.min(5)).min(200))
+}
+
+/// Rejects an ioctl whose buffer the kernel did not map, instead of reading from null.
+pub fn require_buffer(ioctl: &'static str, size: usize) -> Result<(), VuIoctlError> {
+    if size == 0 {
+        return Err(VuIoctlError::Buffer { ioctl, size });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_map_to_errno() {
+        let host = VuIoctlError::host("UI_DEV_CREATE")(Errno::EINVAL);
+        assert_eq!(host.errno(), EINVAL);
+        assert_eq!(
+            host.to_string(),
+            "UI_DEV_CREATE failed: EINVAL: Invalid argument"
+        );
+
+        let not_found = VuIoctlError::DeviceNode {
+            syspath: "/sys/devices/virtual/input/input42".to_string(),
+            error: io::Error::from_raw_os_error(libc::ENOENT),
+        };
+        assert_eq!(not_found.errno(), libc::ENOENT);
+        let no_os_error = VuIoctlError::DeviceNode {
+            syspath: "/sys/devices/virtual/input/input42".to_string(),
+            error: io::Error::new(io::ErrorKind::NotFound, "no device found"),
+        };
+        assert_eq!(no_os_error.errno(), EIO);
+        let no_sysfs = VuIoctlError::SysfsNotVisible {
+            message: "VUI-SYS-001: /sys/devices/virtual/input does not exist".to_string(),
+        };
+        assert_eq!(no_sysfs.errno(), ENODEV);
+        assert!(no_sysfs.to_string().starts_with("VUI-SYS-001"));
+
+        assert!(require_buffer("UI_DEV_SETUP", 92).is_ok());
+        assert_eq!(
+            require_buffer("UI_DEV_SETUP", 0).unwrap_err().errno(),
+            EINVAL
+        );
+    }
+
+    #[test]
+    fn retries_transient_errors_with_backoff() {
+        assert!(is_transient(Errno::EBUSY));
+        assert!(is_transient(Errno::ENOMEM));
+        assert!(!is_transient(Errno::EINVAL));
+        assert_eq!(create_backoff(0), Duration::from_millis(10));
+        assert_eq!(create_backoff(3), Duration::from_millis(80));
+        assert_eq!(create_backoff(5), Duration::from_millis(200));
+        assert_eq!(create_backoff(40), Duration::from_millis(200));
+    }
+}
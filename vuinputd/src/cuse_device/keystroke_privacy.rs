@@ -0,0 +1,108 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Formatting of input events for log messages. With keystroke privacy enabled (the
+// default), code and value of keyboard keys are never written to the log, otherwise
+// the journal would effectively become a keylogger. Buttons of mice and gamepads are
+// not sensitive and are logged as they are. Blocked events are always logged in full,
+// because they are needed to understand why a device policy kicked in.
+
+use crate::global_config::get_keystroke_privacy;
+use crate::input_codes::{CodeName, TypeName};
+use libc::input_event;
+use std::fmt;
+
+const EV_KEY: u16 = 0x01;
+
+// Mouse, joystick, gamepad and digitizer buttons (BTN_MISC..BTN_GEAR_UP)
+const BTN_MISC: u16 = 0x100;
+const BTN_GEAR_UP: u16 = 0x151;
+// BTN_DPAD_UP..BTN_TRIGGER_HAPPY40
+const BTN_DPAD_UP: u16 = 0x220;
+const BTN_TRIGGER_HAPPY40: u16 = 0x2e7;
+
+/// Returns true, if the EV_KEY code belongs to a keyboard key rather than a button.
+pub fn is_keyboard_key(code: u16) -> bool {
+    !matches!(code, BTN_MISC..=BTN_GEAR_UP | BTN_DPAD_UP..=BTN_TRIGGER_HAPPY40)
+}
+
+pub fn is_sensitive(type_: u16, code: u16) -> bool {
+    type_ == EV_KEY && is_keyboard_key(code)
+}
+
+pub struct LoggableEvent<'a> {
+    event: &'a input_event,
+    redact: bool,
+}
+
+impl fmt::Display for LoggableEvent<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.redact && is_sensitive(self.event.type_, self.event.code) {
+            write!(
+                f,
+                "type {} code <redacted> value <redacted>",
+                TypeName(self.event.type_)
+            )
+        } else {
+            write!(
+                f,
+                "type {} code {} value {}",
+                TypeName(self.event.type_),
+                CodeName(self.event.type_, self.event.code),
+                self.event.value
+            )
+        }
+    }
+}
+
+/// Formats an event according to the configured keystroke privacy.
+pub fn loggable(event: &input_event) -> LoggableEvent<'_> {
+    LoggableEvent {
+        event,
+        redact: get_keystroke_privacy(),
+    }
+}
+
+/// Formats an event that has been blocked by the device policy. Never redacted.
+pub fn blocked(event: &input_event) -> LoggableEvent<'_> {
+    LoggableEvent {
+        event,
+        redact: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_sensitive_but_buttons_are_not() {
+        assert!(is_sensitive(EV_KEY, 30)); // KEY_A
+        assert!(is_sensitive(EV_KEY, 0x160)); // KEY_OK
+        assert!(!is_sensitive(EV_KEY, 0x110)); // BTN_LEFT
+        assert!(!is_sensitive(EV_KEY, 0x130)); // BTN_SOUTH
+        assert!(!is_sensitive(EV_KEY, 0x220)); // BTN_DPAD_UP
+        assert!(!is_sensitive(0x02, 30)); // EV_REL
+    }
+
+    #[test]
+    fn redacted_event_hides_code_and_value() {
+        let mut event: input_event = unsafe { std::mem::zeroed() };
+        event.type_ = EV_KEY;
+        event.code = 30;
+        event.value = 1;
+        let redacted = LoggableEvent {
+            event: &event,
+            redact: true,
+        };
+        assert_eq!(
+            redacted.to_string(),
+            "type EV_KEY code <redacted> value <redacted>"
+        );
+        assert_eq!(
+            blocked(&event).to_string(),
+            "type EV_KEY code KEY_A value 1"
+        );
+    }
+}
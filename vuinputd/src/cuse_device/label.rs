@@ -0,0 +1,164 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// A label (vuinputctl label) names a handle for the operator, e.g. after the user of a
+// streaming session. vuinputctl devices, the audit records and the metrics show it. The input
+// device keeps the name the client gave it, as the kernel can't rename an input device. With
+// push, the udev database of the container gets the label as NAME and ID_MODEL of the device
+// (see LabelDeviceJob), for the applications that show those; removing the label with push
+// restores the properties of the host.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use log::info;
+
+use crate::audit::{self, Action, Verdict};
+use crate::cuse_device::state::{get_vuinput_state, VuFileHandle};
+use crate::job_engine::JOB_DISPATCHER;
+use crate::jobs::label_device_job::LabelDeviceJob;
+use crate::process_tools::SELF_NAMESPACES;
+
+const MAX_LABEL_LEN: usize = 64;
+
+/// The characters udev keeps in ID_MODEL and ID_MODEL_ENC, besides letters and digits
+const UDEV_SAFE: &str = "#+-.:=@_";
+
+fn validate(label: &str) -> Result<(), String> {
+    if label.trim().is_empty() {
+        return Err("the label is empty, leave it out to remove the label".to_string());
+    }
+    if label.chars().count() > MAX_LABEL_LEN {
+        return Err(format!(
+            "the label is longer than {} characters",
+            MAX_LABEL_LEN
+        ));
+    }
+    if label.chars().any(char::is_control) {
+        return Err("the label contains control characters".to_string());
+    }
+    // NAME is the label in quotes, without a way to escape one
+    if label.contains('"') {
+        return Err("the label contains '\"'".to_string());
+    }
+    Ok(())
+}
+
+/// Sets or removes the label of a handle. Returns whether the udev database of the container
+/// is being updated.
+pub fn set_label(fh: u64, label: Option<String>, push: bool) -> Result<bool, String> {
+    if let Some(label) = &label {
+        validate(label)?;
+    }
+    let vuinput_state_mutex = get_vuinput_state(&VuFileHandle::Fh(fh))?;
+    let mut vuinput_state = vuinput_state_mutex.lock().unwrap();
+    if vuinput_state.revoked {
+        return Err(format!("fh {} has been revoked", fh));
+    }
+    let in_container = !SELF_NAMESPACES
+        .get()
+        .unwrap()
+        .equal_mnt_and_net(&vuinput_state.requesting_process.namespaces);
+    if push && vuinput_state.input_device.is_none() {
+        return Err(format!("fh {} has no device to push the label to", fh));
+    }
+    if push && !in_container {
+        return Err(format!(
+            "fh {} is a handle of the host, there is no udev database of a container",
+            fh
+        ));
+    }
+
+    vuinput_state.label = label.clone();
+    let devnode = vuinput_state
+        .input_device
+        .as_ref()
+        .map(|device| device.devnode.clone());
+    let reason = match &label {
+        Some(label) => format!("labeled '{}'", label),
+        None => "label removed".to_string(),
+    };
+    audit::record(
+        fh,
+        &vuinput_state,
+        Action::Label,
+        Verdict::Allowed,
+        Some(reason.clone()),
+        devnode.as_deref(),
+    );
+    info!("fh {}: {}", fh, reason);
+
+    if let (true, Some(device)) = (push, &vuinput_state.input_device) {
+        // under the lock of the handle, so a removal of the device is queued after the job
+        JOB_DISPATCHER
+            .get()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .dispatch(Box::new(LabelDeviceJob::new(
+                vuinput_state.requesting_process.clone(),
+                device.syspath.clone(),
+                device.major,
+                device.minor,
+                device.serial.map(|serial| serial.to_string()),
+                label,
+            )));
+    }
+    Ok(push)
+}
+
+/// The properties of the label in the udev database, like the kernel and udev set them for
+/// the name of a device: NAME quoted, ID_MODEL with '_' for whitespace and for the characters
+/// udev does not allow, ID_MODEL_ENC with those characters escaped as \xNN
+pub fn udev_properties(label: &str) -> BTreeMap<String, String> {
+    let model: String = label
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join("_")
+        .chars()
+        .map(|c| match c.is_alphanumeric() || UDEV_SAFE.contains(c) {
+            true => c,
+            false => '_',
+        })
+        .collect();
+    let mut model_enc = String::new();
+    for c in label.chars() {
+        if c.is_ascii_alphanumeric() || UDEV_SAFE.contains(c) || !c.is_ascii() {
+            model_enc.push(c);
+        } else {
+            let _ = write!(model_enc, "\\x{:02x}", c as u32);
+        }
+    }
+    BTreeMap::from([
+        ("NAME".to_string(), format!("\"{}\"", label)),
+        ("ID_MODEL".to_string(), model),
+        ("ID_MODEL_ENC".to_string(), model_enc),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_are_short_printable_names() {
+        assert!(validate("stream of alice").is_ok());
+        assert!(validate("  ").is_err());
+        assert!(validate("two\nlines").is_err());
+        assert!(validate("\"quoted\"").is_err());
+        assert!(validate(&"x".repeat(65)).is_err());
+        assert!(validate(&"ä".repeat(64)).is_ok());
+    }
+
+    #[test]
+    fn properties_are_encoded_like_udev() {
+        let properties = udev_properties("Pad of Zoë (room 2)");
+        assert_eq!(properties["NAME"], "\"Pad of Zoë (room 2)\"");
+        assert_eq!(properties["ID_MODEL"], "Pad_of_Zoë__room_2_");
+        assert_eq!(
+            properties["ID_MODEL_ENC"],
+            "Pad\\x20of\\x20Zoë\\x20\\x28room\\x202\\x29"
+        );
+    }
+}
@@ -0,0 +1,261 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Old clients set up their device by writing a struct uinput_user_dev instead of calling
+// UI_DEV_SETUP and UI_ABS_SETUP. vuinputd translates the write into these ioctls, following
+// uinput_setup_device_legacy of uinput.c:
+//  - the write has to be exactly sizeof(struct uinput_user_dev) and the name must not be empty
+//  - the id (including the version) and ff_effects_max are taken over, then the id and name
+//    policy apply like for UI_DEV_SETUP
+//  - the kernel sets the range of all axes, but only validates the ones that are enabled. As
+//    UI_ABS_SETUP enables an axis, the ranges are kept until UI_DEV_CREATE and only applied to
+//    the axes the client enabled with UI_SET_ABSBIT (before or after the write)
+//
+// struct uinput_user_dev has fixed-size fields only, so 32-bit clients write the same 1116 bytes
+// as 64-bit clients. Unlike input_event, there is no compat layout.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use libc::{uinput_abs_setup, uinput_setup, uinput_user_dev, ABS_CNT};
+
+use crate::cuse_device::device_policy::EV_ABS;
+use crate::cuse_device::ioctl_error::VuIoctlError;
+use crate::cuse_device::state::{AbsInfo, DeviceDescriptor};
+use crate::cuse_device::uinput_compat;
+
+pub const UINPUT_USER_DEV_SIZE: usize = 1116;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LegacySetupError {
+    /// The write is not sizeof(struct uinput_user_dev)
+    Size(usize),
+    EmptyName,
+    /// The range of an enabled axis is rejected by uinput_validate_absinfo
+    InvalidRange {
+        code: u16,
+        minimum: i32,
+        maximum: i32,
+        flat: i32,
+    },
+}
+
+impl fmt::Display for LegacySetupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LegacySetupError::Size(size) => write!(
+                f,
+                "{} bytes instead of {} of struct uinput_user_dev",
+                size, UINPUT_USER_DEV_SIZE
+            ),
+            LegacySetupError::EmptyName => write!(f, "the name is empty"),
+            LegacySetupError::InvalidRange {
+                code,
+                minimum,
+                maximum,
+                flat,
+            } => write!(
+                f,
+                "invalid range of axis {}: {}..{} (flat {})",
+                code, minimum, maximum, flat
+            ),
+        }
+    }
+}
+
+pub struct LegacySetup {
+    pub setup: uinput_setup,
+    /// Ranges by ABS_* code: all axes with a non-zero field and the enabled ones, which the
+    /// kernel resets to zero otherwise
+    pub ranges: BTreeMap<u16, AbsInfo>,
+}
+
+/// The checks of uinput_validate_absinfo
+fn validate_range(code: u16, absinfo: &AbsInfo) -> Result<(), LegacySetupError> {
+    let invalid = LegacySetupError::InvalidRange {
+        code,
+        minimum: absinfo.minimum,
+        maximum: absinfo.maximum,
+        flat: absinfo.flat,
+    };
+    if (absinfo.minimum != 0 || absinfo.maximum != 0) && absinfo.maximum < absinfo.minimum {
+        return Err(invalid);
+    }
+    match absinfo.maximum.checked_sub(absinfo.minimum) {
+        Some(range) if absinfo.flat > range => Err(invalid),
+        _ => Ok(()),
+    }
+}
+
+/// Translates the written struct uinput_user_dev. `capabilities` are the bits declared so far.
+pub fn translate(
+    buffer: &[u8],
+    capabilities: &DeviceDescriptor,
+) -> Result<LegacySetup, LegacySetupError> {
+    if buffer.len() != UINPUT_USER_DEV_SIZE {
+        return Err(LegacySetupError::Size(buffer.len()));
+    }
+    let user_dev = unsafe { std::ptr::read_unaligned(buffer.as_ptr() as *const uinput_user_dev) };
+    if user_dev.name[0] == 0 {
+        return Err(LegacySetupError::EmptyName);
+    }
+
+    let mut setup: uinput_setup = unsafe { std::mem::zeroed() };
+    setup.id = user_dev.id;
+    setup.name = user_dev.name;
+    setup.ff_effects_max = user_dev.ff_effects_max;
+
+    // the kernel only validates the axes if EV_ABS is set
+    let enabled = match capabilities.event_types.contains(&EV_ABS) {
+        true => capabilities.codes.get(&EV_ABS).cloned().unwrap_or_default(),
+        false => Default::default(),
+    };
+    let mut ranges = BTreeMap::new();
+    for index in 0..ABS_CNT {
+        let code = index as u16;
+        let absinfo = AbsInfo {
+            value: 0,
+            minimum: user_dev.absmin[index],
+            maximum: user_dev.absmax[index],
+            fuzz: user_dev.absfuzz[index],
+            flat: user_dev.absflat[index],
+            resolution: 0,
+        };
+        if enabled.contains(&code) {
+            validate_range(code, &absinfo)?;
+        } else if absinfo == AbsInfo::default() {
+            continue;
+        }
+        ranges.insert(code, absinfo);
+    }
+    Ok(LegacySetup { setup, ranges })
+}
+
+/// Applies the ranges of a legacy setup to the enabled axes, right before UI_DEV_CREATE.
+pub fn apply_ranges(
+    fd: libc::c_int,
+    capabilities: &mut DeviceDescriptor,
+) -> Result<(), VuIoctlError> {
+    let ranges = std::mem::take(&mut capabilities.legacy_ranges);
+    for (code, absinfo) in ranges {
+        let enabled = capabilities
+            .codes
+            .get(&EV_ABS)
+            .is_some_and(|codes| codes.contains(&code));
+        if !enabled {
+            continue;
+        }
+        let mut setup: uinput_abs_setup = unsafe { std::mem::zeroed() };
+        setup.code = code;
+        setup.absinfo.minimum = absinfo.minimum;
+        setup.absinfo.maximum = absinfo.maximum;
+        setup.absinfo.fuzz = absinfo.fuzz;
+        setup.absinfo.flat = absinfo.flat;
+        unsafe { uinput_compat::abs_setup(fd, &setup) }?;
+        capabilities.absinfo.insert(code, absinfo);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ABS_X: usize = 0x00;
+    const ABS_Y: usize = 0x01;
+
+    fn user_dev(name: &[u8]) -> uinput_user_dev {
+        let mut user_dev: uinput_user_dev = unsafe { std::mem::zeroed() };
+        for (dst, src) in user_dev.name.iter_mut().zip(name) {
+            *dst = *src as libc::c_char;
+        }
+        user_dev.id.bustype = 0x03;
+        user_dev.id.vendor = 0x045e;
+        user_dev.id.product = 0x028e;
+        user_dev.id.version = 0x0114;
+        user_dev.ff_effects_max = 16;
+        user_dev
+    }
+
+    fn bytes(user_dev: &uinput_user_dev) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(
+                user_dev as *const uinput_user_dev as *const u8,
+                std::mem::size_of::<uinput_user_dev>(),
+            )
+        }
+    }
+
+    fn with_axes(axes: &[u16]) -> DeviceDescriptor {
+        let mut capabilities = DeviceDescriptor::default();
+        capabilities.event_types.insert(EV_ABS);
+        capabilities
+            .codes
+            .entry(EV_ABS)
+            .or_default()
+            .extend(axes.iter().copied());
+        capabilities
+    }
+
+    #[test]
+    fn layout_is_the_same_for_all_clients() {
+        assert_eq!(std::mem::size_of::<uinput_user_dev>(), UINPUT_USER_DEV_SIZE);
+        assert_eq!(std::mem::align_of::<uinput_user_dev>(), 4);
+    }
+
+    #[test]
+    fn takes_over_the_setup() {
+        let mut dev = user_dev(b"Example Gamepad");
+        dev.absmin[ABS_X] = -32768;
+        dev.absmax[ABS_X] = 32767;
+        dev.absflat[ABS_X] = 128;
+        dev.absmax[ABS_Y] = 255;
+
+        let legacy = translate(bytes(&dev), &with_axes(&[ABS_X as u16])).unwrap();
+        assert_eq!(legacy.setup.id.version, 0x0114);
+        assert_eq!(legacy.setup.ff_effects_max, 16);
+        assert_eq!(legacy.setup.name, dev.name);
+        assert_eq!(legacy.ranges.len(), 2);
+        assert_eq!(legacy.ranges[&(ABS_X as u16)].flat, 128);
+        // not enabled (yet), so it is kept, but not validated
+        assert_eq!(legacy.ranges[&(ABS_Y as u16)].maximum, 255);
+
+        // an enabled axis without range is reset
+        let legacy = translate(bytes(&user_dev(b"Example")), &with_axes(&[ABS_Y as u16])).unwrap();
+        assert_eq!(legacy.ranges[&(ABS_Y as u16)], AbsInfo::default());
+    }
+
+    #[test]
+    fn rejects_what_the_kernel_rejects() {
+        let dev = user_dev(b"Example");
+        let capabilities = DeviceDescriptor::default();
+        assert_eq!(
+            translate(&bytes(&dev)[..1000], &capabilities).err(),
+            Some(LegacySetupError::Size(1000))
+        );
+        assert_eq!(
+            translate(bytes(&user_dev(b"")), &capabilities).err(),
+            Some(LegacySetupError::EmptyName)
+        );
+
+        let mut dev = user_dev(b"Example");
+        dev.absmin[ABS_X] = 10;
+        dev.absmax[ABS_X] = -10;
+        assert!(translate(bytes(&dev), &capabilities).is_ok());
+        assert_eq!(
+            translate(bytes(&dev), &with_axes(&[ABS_X as u16])).err(),
+            Some(LegacySetupError::InvalidRange {
+                code: 0,
+                minimum: 10,
+                maximum: -10,
+                flat: 0
+            })
+        );
+
+        dev.absmin[ABS_X] = 0;
+        dev.absmax[ABS_X] = 100;
+        dev.absflat[ABS_X] = 101;
+        assert!(translate(bytes(&dev), &with_axes(&[ABS_X as u16])).is_err());
+    }
+}
@@ -2,9 +2,38 @@
 //
 // Author: Johannes Leupolz <dev@leupolz.eu>
 
+pub mod approval;
+pub mod broadcast;
+pub mod compat_ioctl;
+pub mod container_rules;
+pub mod cuse_module;
+pub mod device_id;
+pub mod device_limits;
+pub mod device_name;
 pub mod device_policy;
+pub mod device_serial;
+pub mod diagnostic_ioctl;
+pub mod drop_counters;
 pub mod evdev_write_watcher;
+pub mod fuse_args;
+pub mod ioctl_error;
+pub mod keystroke_privacy;
+pub mod label;
+pub mod legacy_setup;
+pub mod op_history;
+pub mod op_sequencer;
+pub mod pending_reply;
+pub mod persistence;
+pub mod policy_script;
+pub mod protocol_dump;
+pub mod reconnect;
+pub mod revoke;
+pub mod session_manager;
 pub mod state;
+pub mod sysfs_input;
+pub mod uinput_compat;
+pub mod vuhid;
+pub mod vuinput_init_done;
 pub mod vuinput_ioctl;
 pub mod vuinput_open;
 pub mod vuinput_poll;
@@ -29,7 +58,7 @@ pub const BUS_USB: u16 = 0x03;
 pub fn vuinput_make_cuse_ops() -> cuse_lowlevel::cuse_lowlevel_ops {
     cuse_lowlevel::cuse_lowlevel_ops {
         init: None,
-        init_done: None,
+        init_done: Some(vuinput_init_done::vuinput_init_done),
         destroy: None,
         open: Some(vuinput_open::vuinput_open),
         read: Some(vuinput_read::vuinput_read),
@@ -0,0 +1,196 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// The last operations of each handle (ioctls and writes with their sizes and results), to
+// diagnose a client that only fails now and then without running with trace logging all the
+// time. The history is logged when an operation of the handle fails for the first time and
+// shown by vuinputctl history <FH>.
+//
+// Writes of events follow each other by the thousands, so consecutive ones with the same
+// result are folded into one entry. Otherwise they would push the setup out of the history.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Instant;
+
+use log::warn;
+
+use crate::control::protocol;
+
+/// Operations kept per handle
+pub const HISTORY_LEN: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Operation {
+    /// Milliseconds since the handle has been opened
+    pub elapsed_ms: u64,
+    /// e.g. "UI_SET_KEYBIT" or "write"
+    pub name: String,
+    /// The ioctl command as the client sent it, None for writes
+    pub cmd: Option<u64>,
+    pub in_size: usize,
+    pub out_size: usize,
+    /// None if the operation has been answered without an error
+    pub errno: Option<i32>,
+    /// Folded writes, 1 for everything else
+    pub count: u32,
+}
+
+impl fmt::Display for Operation {
+    /// e.g. "+12ms UI_SET_KEYBIT (0x40045565) in 0 out 0: ok"
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "+{}ms {}", self.elapsed_ms, self.name)?;
+        if let Some(cmd) = self.cmd {
+            write!(f, " ({:#x})", cmd)?;
+        }
+        if self.count > 1 {
+            write!(f, " x{}", self.count)?;
+        }
+        write!(f, " in {} out {}: ", self.in_size, self.out_size)?;
+        match self.errno {
+            None => write!(f, "ok"),
+            Some(errno) => write!(f, "errno {}", errno),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct OpHistory {
+    opened: Instant,
+    operations: VecDeque<Operation>,
+    logged: bool,
+}
+
+impl OpHistory {
+    pub fn new() -> OpHistory {
+        OpHistory {
+            opened: Instant::now(),
+            operations: VecDeque::with_capacity(HISTORY_LEN),
+            logged: false,
+        }
+    }
+
+    pub fn record_ioctl(
+        &mut self,
+        name: &str,
+        cmd: u64,
+        in_size: usize,
+        out_size: usize,
+        errno: Option<i32>,
+    ) {
+        self.push(Operation {
+            elapsed_ms: self.elapsed_ms(),
+            name: name.to_string(),
+            cmd: Some(cmd),
+            in_size,
+            out_size,
+            errno,
+            count: 1,
+        });
+    }
+
+    /// A write of `size` bytes. Folded into the previous entry, if that is a write with the
+    /// same result.
+    pub fn record_write(&mut self, name: &str, size: usize, errno: Option<i32>) {
+        let elapsed_ms = self.elapsed_ms();
+        if let Some(last) = self.operations.back_mut() {
+            if last.cmd.is_none() && last.name == name && last.errno == errno {
+                last.elapsed_ms = elapsed_ms;
+                last.in_size += size;
+                last.count += 1;
+                return;
+            }
+        }
+        self.push(Operation {
+            elapsed_ms,
+            name: name.to_string(),
+            cmd: None,
+            in_size: size,
+            out_size: 0,
+            errno,
+            count: 1,
+        });
+    }
+
+    pub fn operations(&self) -> impl Iterator<Item = &Operation> {
+        self.operations.iter()
+    }
+
+    /// Logs the history once per handle, on its first failed operation
+    pub fn log_on_first_failure(&mut self, fh: u64) {
+        if self.logged {
+            return;
+        }
+        self.logged = true;
+        warn!(
+            "fh {}: first failed operation, the last {} operations were:",
+            fh,
+            self.operations.len()
+        );
+        for operation in &self.operations {
+            warn!("fh {}:   {}", fh, operation);
+        }
+    }
+
+    pub fn to_protocol(&self) -> Vec<protocol::Operation> {
+        self.operations
+            .iter()
+            .map(|operation| protocol::Operation {
+                elapsed_ms: operation.elapsed_ms,
+                name: operation.name.clone(),
+                cmd: operation.cmd,
+                in_size: operation.in_size,
+                out_size: operation.out_size,
+                errno: operation.errno,
+                count: operation.count,
+            })
+            .collect()
+    }
+
+    fn elapsed_ms(&self) -> u64 {
+        self.opened.elapsed().as_millis() as u64
+    }
+
+    fn push(&mut self, operation: Operation) {
+        if self.operations.len() == HISTORY_LEN {
+            self.operations.pop_front();
+        }
+        self.operations.push_back(operation);
+    }
+}
+
+impl Default for OpHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_last_operations_and_folds_writes() {
+        let mut history = OpHistory::new();
+        for bit in 0..HISTORY_LEN as u64 {
+            history.record_ioctl("UI_SET_KEYBIT", 0x40045565 + bit, 0, 0, None);
+        }
+        history.record_write("write", 24, None);
+        history.record_write("write", 48, None);
+        history.record_write("write", 24, Some(libc::EIO));
+
+        let operations: Vec<&Operation> = history.operations().collect();
+        assert_eq!(operations.len(), HISTORY_LEN);
+        // the two oldest ioctls are gone
+        assert_eq!(operations[0].cmd, Some(0x40045565 + 2));
+        let folded = operations[HISTORY_LEN - 2];
+        assert_eq!((folded.count, folded.in_size, folded.errno), (2, 72, None));
+        let failed = operations[HISTORY_LEN - 1];
+        assert_eq!((failed.count, failed.errno), (1, Some(libc::EIO)));
+        assert!(failed.to_string().ends_with("write in 24 out 0: errno 5"));
+        assert!(operations[0]
+            .to_string()
+            .ends_with("UI_SET_KEYBIT (0x40045567) in 0 out 0: ok"));
+    }
+}
@@ -0,0 +1,331 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// The order of the requests of a handle. With --cuse-threads, several threads serve the requests,
+// and the lock of the handle (VuInputState) does not care who came first: a write could be served
+// after an UI_DEV_DESTROY the client sent later, and fail, or worse, reach the next device of the
+// handle. The threads of a session therefore read the requests from /dev/cuse one at a time (see
+// session_manager) and draw the ticket of the handle of a read, write or ioctl before the next
+// request is read, in the order the kernel queued them. The handler waits for the turn of its
+// ticket before it locks the handle; the turn passes on when the handler returns, after it has
+// replied. A ticket whose handler did not wait for it, e.g. as libfuse answered the request
+// itself, is skipped. Handlers that run without a ticket drawn (a single thread, simulation)
+// draw one when they start.
+//
+// Replies that are sent later (UI_DEV_CREATE of a container, parked reads) do not hold the
+// turn, so a slow container does not block the threads. UI_DEV_CREATE is answered by the job
+// that creates the node, and UI_DEV_DESTROY waits for the removal, which is queued behind it;
+// a UI_DEV_CREATE that waits for approval is cancelled by UI_DEV_DESTROY (see approval).
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Opcodes of the requests whose handlers wait for their turn, see <linux/fuse.h>
+const FUSE_READ: u32 = 15;
+const FUSE_WRITE: u32 = 16;
+const FUSE_IOCTL: u32 = 39;
+/// Size of struct fuse_in_header, which is followed by the fh in fuse_read_in, fuse_write_in and
+/// fuse_ioctl_in
+const IN_HEADER_SIZE: usize = 40;
+
+static SEQUENCERS: Mutex<BTreeMap<u64, Arc<OpSequencer>>> = Mutex::new(BTreeMap::new());
+
+thread_local! {
+    /// The ticket drawn for the request this thread has read, until its handler waits for it
+    static RECEIVED: RefCell<Option<Ticket>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug, Default)]
+struct Serving {
+    ticket: u64,
+    /// Tickets after the one being served that are passed over when it is their turn
+    skipped: BTreeSet<u64>,
+}
+
+/// Hands out the turns of a handle by ticket
+#[derive(Debug, Default)]
+pub struct OpSequencer {
+    next_ticket: AtomicU64,
+    serving: Mutex<Serving>,
+    turn_over: Condvar,
+}
+
+/// A ticket of a handle. Dropped before its turn, it is skipped.
+#[derive(Debug)]
+struct Ticket {
+    sequencer: Arc<OpSequencer>,
+    fh: u64,
+    ticket: u64,
+}
+
+/// The turn of a request, it passes on when dropped
+#[derive(Debug)]
+pub struct Turn(Ticket);
+
+/// Skips the ticket drawn by received() if the handler has not waited for it
+#[derive(Debug)]
+pub struct Received(());
+
+impl OpSequencer {
+    fn draw(self: &Arc<Self>, fh: u64) -> Ticket {
+        Ticket {
+            sequencer: self.clone(),
+            fh,
+            ticket: self.next_ticket.fetch_add(1, Ordering::SeqCst),
+        }
+    }
+
+    /// Passes the turn on from `ticket`, or marks it to be skipped if it is not its turn yet
+    fn pass(&self, ticket: u64) {
+        let mut serving = self.serving.lock().unwrap();
+        if serving.ticket != ticket {
+            serving.skipped.insert(ticket);
+            return;
+        }
+        let Serving { ticket, skipped } = &mut *serving;
+        *ticket += 1;
+        while skipped.remove(ticket) {
+            *ticket += 1;
+        }
+        self.turn_over.notify_all();
+    }
+}
+
+impl Ticket {
+    /// Waits until it is the turn of the ticket
+    fn wait(self) -> Turn {
+        let mut serving = self.sequencer.serving.lock().unwrap();
+        while serving.ticket != self.ticket {
+            serving = self.sequencer.turn_over.wait(serving).unwrap();
+        }
+        drop(serving);
+        Turn(self)
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        self.sequencer.pass(self.ticket);
+    }
+}
+
+impl Turn {
+    pub fn ticket(&self) -> u64 {
+        self.0.ticket
+    }
+}
+
+impl Drop for Received {
+    fn drop(&mut self) {
+        RECEIVED.with(|received| received.borrow_mut().take());
+    }
+}
+
+fn sequencer(fh: u64) -> Arc<OpSequencer> {
+    SEQUENCERS.lock().unwrap().entry(fh).or_default().clone()
+}
+
+/// The handle of a request as read from /dev/cuse, if its handler waits for its turn
+pub fn handle_of(request: &[u8]) -> Option<u64> {
+    let opcode = u32::from_ne_bytes(request.get(4..8)?.try_into().ok()?);
+    if ![FUSE_READ, FUSE_WRITE, FUSE_IOCTL].contains(&opcode) {
+        return None;
+    }
+    let fh = request.get(IN_HEADER_SIZE..IN_HEADER_SIZE + 8)?;
+    Some(u64::from_ne_bytes(fh.try_into().ok()?))
+}
+
+/// Draws the ticket of a request of the handle that this thread has just read, for its handler.
+/// Call it before the next request is read, and keep the guard until the handler has returned.
+pub fn received(fh: u64) -> Received {
+    let ticket = sequencer(fh).draw(fh);
+    RECEIVED.with(|received| *received.borrow_mut() = Some(ticket));
+    Received(())
+}
+
+/// Waits for the turn of a request of the handle. Call it before locking the handle, and keep
+/// the turn until the request has been answered.
+pub fn enter(fh: u64) -> Turn {
+    let received = RECEIVED.with(|received| {
+        let mut received = received.borrow_mut();
+        match received.as_ref().is_some_and(|ticket| ticket.fh == fh) {
+            true => received.take(),
+            false => None,
+        }
+    });
+    received.unwrap_or_else(|| sequencer(fh).draw(fh)).wait()
+}
+
+/// Forgets a released handle
+pub fn forget(fh: u64) {
+    SEQUENCERS.lock().unwrap().remove(&fh);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cuse_device::drop_counters::{DropCounters, DropWindow};
+    use crate::cuse_device::op_history::OpHistory;
+    use crate::cuse_device::state::{
+        get_vuinput_state, insert_vuinput_state, remove_vuinput_state, DeviceDescriptor,
+        DeviceLifecycle, KeyTracker, PollState, VuFileHandle, VuInputDevice, VuInputState,
+        VUINPUT_STATE,
+    };
+    use crate::process_tools::{Namespaces, Pid, RequestingProcess};
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::sync::RwLock;
+    use std::thread;
+
+    const DESTROY: u64 = 20;
+
+    /// A handle with a device, as the handlers find it after UI_DEV_CREATE
+    fn created_handle(fh: u64) {
+        VUINPUT_STATE.get_or_init(|| RwLock::new(HashMap::new()));
+        let device = VuInputDevice {
+            major: 13,
+            minor: 75,
+            sysname: "input99".to_string(),
+            syspath: "/sys/devices/virtual/input/input99".to_string(),
+            devname: "input/event11".to_string(),
+            devnode: "/dev/input/event11".to_string(),
+            serial: None,
+            mirrors: Vec::new(),
+            seat: Arc::new(Mutex::new(None)),
+            container_devnode: Arc::new(Mutex::new(None)),
+        };
+        let state = VuInputState {
+            file: File::open("/dev/null").unwrap(),
+            requesting_process: RequestingProcess {
+                pid_requestor: Pid::Pid(4711),
+                pid_requestor_root: Pid::Pid(4690),
+                namespaces: Namespaces::default(),
+                is_compat: false,
+                identity: None,
+            },
+            input_device: Some(device),
+            lifecycle: DeviceLifecycle::Created,
+            keytracker: KeyTracker::new(),
+            poll: PollState::new(),
+            pending_read: None,
+            pending_create: None,
+            descriptor: DeviceDescriptor::default(),
+            policy_override: None,
+            rule_policy: None,
+            node_policy: None,
+            revoked: false,
+            label: None,
+            events_forwarded: 0,
+            drops: DropCounters::default(),
+            drop_window: DropWindow::default(),
+            ioctl_errors: 0,
+            write_failures: 0,
+            last_reconnect: None,
+            history: OpHistory::new(),
+        };
+        insert_vuinput_state(&VuFileHandle::Fh(fh), state).unwrap();
+    }
+
+    /// Serves `requests` requests of a handle like the threads of a session: each reads a
+    /// request and draws its ticket before the next one is read, then runs the handler on the
+    /// state of the handle. The request DESTROY (in the order they have been read) takes the
+    /// device like UI_DEV_DESTROY, the others write to it like vuinput_write.
+    fn hammer(fh: u64, requests: u64) {
+        created_handle(fh);
+        let reading = Arc::new(Mutex::new(0));
+        let threads: Vec<_> = (0..requests)
+            .map(|_| {
+                let reading = reading.clone();
+                thread::spawn(move || {
+                    let (request, _received) = {
+                        let mut read = reading.lock().unwrap();
+                        *read += 1;
+                        (*read - 1, received(fh))
+                    };
+                    // the handler may start after those of later requests
+                    thread::yield_now();
+                    let _turn = enter(fh);
+                    let state_mutex = get_vuinput_state(&VuFileHandle::Fh(fh)).unwrap();
+                    let mut state = state_mutex.lock().unwrap();
+                    match request {
+                        DESTROY => {
+                            state.input_device.take();
+                            state.lifecycle.advance(DeviceLifecycle::Destroyed);
+                        }
+                        // a write that overtook the destroy would reach a device of the past,
+                        // one that came after it would fail with EINVAL
+                        request => assert_eq!(state.input_device.is_some(), request < DESTROY),
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        remove_vuinput_state(&VuFileHandle::Fh(fh)).unwrap();
+        forget(fh);
+    }
+
+    #[test]
+    fn no_write_overtakes_a_destroy() {
+        for round in 0..50 {
+            hammer(1_000_000 + round, 32);
+        }
+    }
+
+    #[test]
+    fn skips_tickets_nobody_waits_for() {
+        let first = enter(3_000_000);
+        // libfuse answered the next request without a handler
+        thread::spawn(|| drop(received(3_000_000))).join().unwrap();
+        let later = thread::spawn(|| {
+            let _received = received(3_000_000);
+            enter(3_000_000).ticket()
+        });
+        drop(first);
+        assert_eq!(later.join().unwrap(), 2);
+        // a ticket of another handle is left to its own handler
+        let _received = received(3_000_001);
+        assert_eq!(enter(3_000_000).ticket(), 3);
+        forget(3_000_000);
+        forget(3_000_001);
+    }
+
+    #[test]
+    fn finds_the_handle_of_a_request() {
+        let request = |opcode: u32, fh: u64| {
+            let mut request = vec![0u8; IN_HEADER_SIZE];
+            request[4..8].copy_from_slice(&opcode.to_ne_bytes());
+            request.extend_from_slice(&fh.to_ne_bytes());
+            request.extend_from_slice(&[0u8; 16]);
+            request
+        };
+        assert_eq!(handle_of(&request(FUSE_WRITE, 42)), Some(42));
+        assert_eq!(handle_of(&request(FUSE_IOCTL, 43)), Some(43));
+        assert_eq!(handle_of(&request(FUSE_READ, 44)), Some(44));
+        // FUSE_RELEASE and FUSE_INTERRUPT do not wait for a turn
+        assert_eq!(handle_of(&request(18, 42)), None);
+        assert_eq!(handle_of(&request(36, 42)), None);
+        assert_eq!(
+            handle_of(&request(FUSE_WRITE, 42)[..IN_HEADER_SIZE + 4]),
+            None
+        );
+    }
+
+    #[test]
+    fn handles_do_not_wait_for_each_other() {
+        let first = enter(2_000_000);
+        // another handle gets its turn while the first one holds its own
+        let other = thread::spawn(|| enter(2_000_001).ticket()).join().unwrap();
+        assert_eq!((first.ticket(), other), (0, 0));
+        drop(first);
+        assert_eq!(enter(2_000_000).ticket(), 1);
+        forget(2_000_000);
+        forget(2_000_001);
+        assert_eq!(enter(2_000_000).ticket(), 0);
+        forget(2_000_000);
+    }
+}
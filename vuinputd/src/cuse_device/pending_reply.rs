@@ -0,0 +1,61 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// A request that is answered later by a job instead of by its handler, e.g. UI_DEV_CREATE of a
+// container, which is only answered once the device node exists in the container. The handler
+// returns right away, so a slow container does not hold up the requests of other handles.
+// libfuse allows replying from any thread, but every request needs exactly one reply: if the
+// job is dropped without replying (cancelled on shutdown or panicked), the request fails with
+// EIO instead of blocking the client forever.
+
+use ::cuse_lowlevel::*;
+use libc::EIO;
+use log::warn;
+
+#[derive(Debug)]
+pub struct PendingReply {
+    req: fuse_lowlevel::fuse_req_t,
+    fh: u64,
+    what: &'static str,
+}
+
+// the request is only touched by the one who replies
+unsafe impl Send for PendingReply {}
+
+impl PendingReply {
+    /// # Safety
+    /// `req` must be a request that has not been replied to, and must not be replied to by
+    /// anyone else.
+    pub unsafe fn new(req: fuse_lowlevel::fuse_req_t, fh: u64, what: &'static str) -> Self {
+        PendingReply { req, fh, what }
+    }
+
+    /// Replies to an ioctl without data
+    pub fn ioctl_ok(self) {
+        unsafe { fuse_lowlevel::fuse_reply_ioctl(self.req, 0, std::ptr::null(), 0) };
+        std::mem::forget(self);
+    }
+
+    pub fn err(self, errno: i32) {
+        unsafe { fuse_lowlevel::fuse_reply_err(self.req, errno) };
+        std::mem::forget(self);
+    }
+
+    /// The request, for a handler that replies to it itself
+    pub fn into_req(self) -> fuse_lowlevel::fuse_req_t {
+        let req = self.req;
+        std::mem::forget(self);
+        req
+    }
+}
+
+impl Drop for PendingReply {
+    fn drop(&mut self) {
+        warn!(
+            "fh {}: {} was not answered, failing it with EIO",
+            self.fh, self.what
+        );
+        unsafe { fuse_lowlevel::fuse_reply_err(self.req, EIO) };
+    }
+}
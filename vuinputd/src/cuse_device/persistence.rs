@@ -0,0 +1,465 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Devices of containers that survive a restart of vuinputd (persist-devices). Whenever a device
+// is created or removed, the descriptors of the devices in containers are saved to
+// /run/vuinputd/{devname}/state.json. When vuinputd starts again, the devices of the containers
+// that are still running are created again on the host and their nodes in the containers are
+// replaced with the new ones.
+//
+// The clients have lost their handles together with the old CUSE session. A client in the same
+// container that sets up an identical device takes the restored one over at UI_DEV_CREATE, so
+// the applications in the container keep reading from the same node. Restored devices that
+// nobody takes over within ADOPTION_PERIOD are removed again.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use libc::{O_CLOEXEC, O_NONBLOCK};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::control::events;
+use crate::control::protocol::{Descriptor, Event};
+use crate::cuse_device::device_serial;
+use crate::cuse_device::reconnect::{replace_watched_fd, replay_setup};
+use crate::cuse_device::state::{
+    vuinput_states, DeviceDescriptor, DeviceLifecycle, VuInputDevice, VuInputState,
+};
+use crate::cuse_device::vuinput_ioctl::create_device_retrying;
+use crate::job_engine::job::Job;
+use crate::job_engine::JOB_DISPATCHER;
+use crate::jobs::emit_udev_event_job::EmitUdevEventJob;
+use crate::jobs::mknod_device_job::MknodDeviceJob;
+use crate::jobs::remove_device_job::RemoveDeviceJob;
+use crate::process_tools::{get_requesting_process, Pid, RequestingProcess, SELF_NAMESPACES};
+
+/// Restored devices wait this long for their clients to come back
+const ADOPTION_PERIOD: Duration = Duration::from_secs(60);
+
+static STATE_FILE: OnceLock<PathBuf> = OnceLock::new();
+static RESTORED: Mutex<Vec<RestoredDevice>> = Mutex::new(Vec::new());
+/// Set when vuinputd stops, the removals on shutdown must not reach the state file
+static STOPPED: AtomicBool = AtomicBool::new(false);
+
+pub fn state_file_path(devname: &str) -> PathBuf {
+    PathBuf::from(format!("/run/vuinputd/{}/state.json", devname))
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct PersistedState {
+    devices: Vec<PersistedDevice>,
+    /// vuinputd has stopped and removed the nodes from the containers itself. Otherwise it
+    /// has crashed and the nodes of the old devices are still there.
+    #[serde(default)]
+    nodes_removed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct PersistedDevice {
+    container: ContainerIdentity,
+    /// The node in the container, e.g. input/event7
+    devname: String,
+    syspath: String,
+    major: u64,
+    minor: u64,
+    /// The id policy kept the id of the setup, so the phys gets marked
+    preserved_id: bool,
+    descriptor: Descriptor,
+}
+
+impl PersistedDevice {
+    fn name(&self) -> &str {
+        self.descriptor
+            .setup
+            .as_ref()
+            .map_or("(no name)", |setup| setup.name.as_str())
+    }
+}
+
+/// A container is only the same one, if its root process still has the same namespaces
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct ContainerIdentity {
+    root_pid: u32,
+    mnt: Option<u64>,
+    net: Option<u64>,
+}
+
+impl ContainerIdentity {
+    fn of(requesting_process: &RequestingProcess) -> ContainerIdentity {
+        ContainerIdentity {
+            root_pid: requesting_process.pid_requestor_root.as_raw(),
+            mnt: requesting_process.namespaces.mnt,
+            net: requesting_process.namespaces.net,
+        }
+    }
+
+    /// The root process of the container, if it is still running
+    fn requesting_process(&self) -> Option<RequestingProcess> {
+        let requesting_process = get_requesting_process(Pid::Pid(self.root_pid));
+        let namespaces = &requesting_process.namespaces;
+        let same =
+            namespaces.mnt.is_some() && namespaces.mnt == self.mnt && namespaces.net == self.net;
+        same.then_some(requesting_process)
+    }
+}
+
+/// A device created again after a restart, until a client takes it over
+struct RestoredDevice {
+    file: File,
+    requesting_process: RequestingProcess,
+    descriptor: DeviceDescriptor,
+    input_device: VuInputDevice,
+}
+
+fn is_change(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::DeviceCreated { .. } | Event::DeviceRemoved { .. }
+    )
+}
+
+/// Restores the devices of the state file and keeps the file up to date until stop
+pub fn start(path: &Path) -> io::Result<()> {
+    let _ = STATE_FILE.set(path.to_path_buf());
+    match load(path) {
+        Ok(Some(state)) => restore(state),
+        Ok(None) => {}
+        Err(e) => warn!("{}: {}, no devices are restored", path.display(), e),
+    }
+    save();
+
+    let mut changes = events::subscribe_to(is_change);
+    thread::Builder::new()
+        .name("persist".to_string())
+        .spawn(move || loop {
+            for _ in changes.iter() {
+                save();
+            }
+            // dropped as it did not keep up, the save catches up on what has been missed
+            changes = events::subscribe_to(is_change);
+            save();
+        })?;
+
+    if !RESTORED.lock().unwrap().is_empty() {
+        thread::Builder::new()
+            .name("persist-expiry".to_string())
+            .spawn(|| {
+                thread::sleep(ADOPTION_PERIOD);
+                if !STOPPED.load(Ordering::SeqCst) {
+                    release_restored("no client has taken it over");
+                    save();
+                }
+            })?;
+    }
+    Ok(())
+}
+
+/// Writes the state file a last time before the devices are removed on shutdown. Restored
+/// devices that have not been taken over are kept in it and removed.
+pub fn stop() {
+    let Some(path) = STATE_FILE.get() else {
+        return;
+    };
+    if STOPPED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    // the other nodes are removed by revoke_all right afterwards
+    let state = PersistedState {
+        devices: snapshot(),
+        nodes_removed: true,
+    };
+    release_restored("vuinputd stops");
+    if let Err(e) = write(path, &state) {
+        warn!("couldn't save the devices to {}: {}", path.display(), e);
+    }
+}
+
+/// Hands a restored device of the container over to the handle, if the handle has set up the
+/// same device. Returns false, if there is none and the device has to be created.
+pub fn adopt(fh: u64, vuinput_state: &mut VuInputState) -> bool {
+    let mut restored = RESTORED.lock().unwrap();
+    let container = ContainerIdentity::of(&vuinput_state.requesting_process);
+    let wanted = comparable(&vuinput_state.descriptor);
+    let Some(index) = restored.iter().position(|device| {
+        ContainerIdentity::of(&device.requesting_process) == container
+            && comparable(&device.descriptor) == wanted
+    }) else {
+        return false;
+    };
+    let device = restored.remove(index);
+    drop(restored);
+
+    let old_file = std::mem::replace(&mut vuinput_state.file, device.file);
+    replace_watched_fd(fh, &old_file, &vuinput_state.file);
+    let devnode = device.input_device.devnode.clone();
+    info!("fh {}: took over the restored device {}", fh, devnode);
+    vuinput_state.input_device = Some(device.input_device);
+    vuinput_state.lifecycle.advance(DeviceLifecycle::Created);
+    events::device_created(fh, vuinput_state, &devnode);
+    true
+}
+
+/// What has to match for a take-over, the rejected bits do not
+fn comparable(descriptor: &DeviceDescriptor) -> Descriptor {
+    Descriptor {
+        rejected: Vec::new(),
+        ..descriptor.to_protocol()
+    }
+}
+
+fn restore(state: PersistedState) {
+    // all stale nodes go first, a new device may get the name of an old one
+    let mut jobs: Vec<Box<dyn Job>> = Vec::new();
+    let mut node_jobs: Vec<Box<dyn Job>> = Vec::new();
+    for device in state.devices {
+        let Some(requesting_process) = device.container.requesting_process() else {
+            info!(
+                "not restoring \"{}\", container {} is gone",
+                device.name(),
+                device.container.root_pid
+            );
+            continue;
+        };
+        if !state.nodes_removed {
+            jobs.push(Box::new(RemoveDeviceJob::new(
+                requesting_process.clone(),
+                device.devname.clone(),
+                device.syspath.clone(),
+                device.major,
+                device.minor,
+            )));
+        }
+        let restored = match create(&device, requesting_process) {
+            Ok(restored) => restored,
+            Err(e) => {
+                warn!("couldn't restore \"{}\": {}", device.name(), e);
+                continue;
+            }
+        };
+        let input_device = &restored.input_device;
+        info!(
+            "restored \"{}\" of container {} as {}",
+            device.name(),
+            device.container.root_pid,
+            input_device.devnode
+        );
+        // the jobs log a failure, nobody is waiting for them
+        let mknod_job = MknodDeviceJob::new(
+            restored.requesting_process.clone(),
+            input_device.devname.clone(),
+            input_device.syspath.clone(),
+            input_device.major,
+            input_device.minor,
+        )
+        .record_node(input_device.container_devnode.clone())
+        .on_completion(Box::new(|_| {}));
+        let emit_udev_event_job = EmitUdevEventJob::new(
+            restored.requesting_process.clone(),
+            input_device.devnode.clone(),
+            input_device.syspath.clone(),
+            input_device.major,
+            input_device.minor,
+            input_device.serial.map(|serial| serial.to_string()),
+        );
+        node_jobs.push(Box::new(mknod_job));
+        node_jobs.push(Box::new(emit_udev_event_job));
+        RESTORED.lock().unwrap().push(restored);
+    }
+    let mut dispatcher = JOB_DISPATCHER.get().unwrap().lock().unwrap();
+    for job in jobs.into_iter().chain(node_jobs) {
+        dispatcher.dispatch(job);
+    }
+}
+
+/// Creates the device on the host like it has been set up before the restart
+fn create(
+    device: &PersistedDevice,
+    requesting_process: RequestingProcess,
+) -> Result<RestoredDevice, String> {
+    let descriptor = DeviceDescriptor::from_protocol(&device.descriptor, device.preserved_id);
+    let setup = descriptor
+        .setup
+        .ok_or("the setup of the device has not been saved")?;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(O_NONBLOCK | O_CLOEXEC)
+        .open("/dev/uinput")
+        .map_err(|e| format!("couldn't open /dev/uinput: {}", e))?;
+    let fd = file.as_raw_fd();
+    let mut input_device = unsafe {
+        replay_setup(fd, &setup, &descriptor).map_err(|e| e.to_string())?;
+        create_device_retrying(0, fd).map_err(|e| e.to_string())?
+    };
+    input_device.serial = Some(device_serial::allocate(&requesting_process.namespaces));
+    Ok(RestoredDevice {
+        file,
+        requesting_process,
+        descriptor,
+        input_device,
+    })
+}
+
+/// Removes the restored devices from the containers. Closing their files destroys them on
+/// the host.
+fn release_restored(reason: &str) {
+    let restored = std::mem::take(&mut *RESTORED.lock().unwrap());
+    if restored.is_empty() {
+        return;
+    }
+    let mut dispatcher = JOB_DISPATCHER.get().unwrap().lock().unwrap();
+    for device in restored {
+        let input_device = &device.input_device;
+        info!(
+            "removing the restored device {} ({})",
+            input_device.devnode, reason
+        );
+        if let Some(serial) = &input_device.serial {
+            device_serial::release(serial);
+        }
+        dispatcher.dispatch(Box::new(RemoveDeviceJob::new(
+            device.requesting_process.clone(),
+            input_device.devname.clone(),
+            input_device.syspath.clone(),
+            input_device.major,
+            input_device.minor,
+        )));
+    }
+}
+
+/// The created devices of the containers, including the restored ones
+fn snapshot() -> Vec<PersistedDevice> {
+    let self_namespaces = SELF_NAMESPACES.get().unwrap();
+    let mut devices = Vec::new();
+    for (_, state) in vuinput_states() {
+        let state = state.lock().unwrap();
+        let Some(input_device) = &state.input_device else {
+            continue;
+        };
+        if state.revoked || self_namespaces.equal_mnt_and_net(&state.requesting_process.namespaces)
+        {
+            continue;
+        }
+        devices.push(persisted(
+            &state.requesting_process,
+            input_device,
+            &state.descriptor,
+        ));
+    }
+    for device in RESTORED.lock().unwrap().iter() {
+        devices.push(persisted(
+            &device.requesting_process,
+            &device.input_device,
+            &device.descriptor,
+        ));
+    }
+    devices
+}
+
+fn persisted(
+    requesting_process: &RequestingProcess,
+    input_device: &VuInputDevice,
+    descriptor: &DeviceDescriptor,
+) -> PersistedDevice {
+    PersistedDevice {
+        container: ContainerIdentity::of(requesting_process),
+        devname: input_device.devname.clone(),
+        syspath: input_device.syspath.clone(),
+        major: input_device.major,
+        minor: input_device.minor,
+        preserved_id: descriptor.preserved_id.is_some(),
+        descriptor: descriptor.to_protocol(),
+    }
+}
+
+fn save() {
+    if STOPPED.load(Ordering::SeqCst) {
+        return;
+    }
+    let Some(path) = STATE_FILE.get() else {
+        return;
+    };
+    let state = PersistedState {
+        devices: snapshot(),
+        nodes_removed: false,
+    };
+    if let Err(e) = write(path, &state) {
+        warn!("couldn't save the devices to {}: {}", path.display(), e);
+    }
+}
+
+/// Replaces the file at once, a crash while writing must not leave half a file behind
+fn write(path: &Path, state: &PersistedState) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temporary = path.with_extension("json.tmp");
+    fs::write(&temporary, serde_json::to_vec_pretty(state)?)?;
+    fs::rename(&temporary, path)
+}
+
+fn load(path: &Path) -> io::Result<Option<PersistedState>> {
+    match fs::read(path) {
+        Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::protocol::Setup;
+
+    #[test]
+    fn state_files_are_written_and_read_back() {
+        let dir = std::env::temp_dir().join(format!("vuinputd-persistence-{}", std::process::id()));
+        let path = dir.join("vuinput").join("state.json");
+        assert_eq!(load(&path).unwrap(), None);
+
+        let state = PersistedState {
+            devices: vec![PersistedDevice {
+                container: ContainerIdentity {
+                    root_pid: 4690,
+                    mnt: Some(4026532300),
+                    net: Some(4026532303),
+                },
+                devname: "input/event7".to_string(),
+                syspath: "/sys/devices/virtual/input/input42".to_string(),
+                major: 13,
+                minor: 71,
+                preserved_id: false,
+                descriptor: Descriptor {
+                    setup: Some(Setup {
+                        name: "Gamepad".to_string(),
+                        bustype: 0x03,
+                        vendor: 0x1209,
+                        product: 0x5020,
+                        version: 1,
+                        ff_effects_max: 0,
+                    }),
+                    event_types: vec![0x01],
+                    ..Default::default()
+                },
+            }],
+            nodes_removed: false,
+        };
+        write(&path, &state).unwrap();
+        assert_eq!(load(&path).unwrap(), Some(state.clone()));
+        assert_eq!(state.devices[0].name(), "Gamepad");
+        assert!(!path.with_extension("json.tmp").exists());
+
+        fs::write(&path, "{\"devices\": [{\"container\": 1}]}").unwrap();
+        assert!(load(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,355 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// The device policy "script": a Rhai script (policy-script) decides instead of one of the
+// built-in policies. It may define any of these functions, a missing one allows everything:
+//
+//   fn allow_type(type) { ... }            UI_SET_EVBIT, true to allow the event type
+//   fn allow_code(type, code) { ... }      UI_SET_KEYBIT, UI_SET_ABSBIT, ... and UI_ABS_SETUP
+//   fn allow_property(prop) { ... }        UI_SET_PROPBIT
+//   fn on_event(type, code, value) { ... } every written event: true to forward it, false to
+//                                          drop it, or #{type: .., code: .., value: ..} to
+//                                          forward a changed one (missing keys are kept)
+//
+// code("KEY_A"), event_type("EV_KEY") and code_name(type, code) translate between names and
+// numbers. The functions can't keep state between calls and have no access to the host: Rhai
+// has no file or network access, and the engine limits operations, nesting and sizes. Each call
+// must finish within SCRIPT_TIME_LIMIT. A call that fails, runs too long or returns something
+// else denies, as does the policy without a loaded script.
+//
+// Rhai is only built with the scripted-policy feature. Without it, a configured script is
+// refused at startup and on reload.
+
+use std::path::Path;
+
+use libc::input_event;
+
+use crate::global_config::{DevicePolicy, ReloadableConfig};
+
+#[cfg(feature = "scripted-policy")]
+pub use scripted::*;
+
+#[cfg(not(feature = "scripted-policy"))]
+pub use unavailable::*;
+
+/// Loads the script of the configuration, at startup and on reload
+pub fn configure(config: &ReloadableConfig) -> Result<(), String> {
+    if config.policy == DevicePolicy::Script && config.policy_script.is_none() {
+        return Err("device-policy script needs a policy-script".into());
+    }
+    load(config.policy_script.as_deref())
+}
+
+#[cfg(feature = "scripted-policy")]
+mod scripted {
+    use std::cell::Cell;
+    use std::fs;
+    use std::sync::{Arc, RwLock};
+    use std::time::{Duration, Instant};
+
+    use log::{debug, info, warn};
+    use rhai::{CallFnOptions, Dynamic, Engine, FuncArgs, Map, Scope, AST};
+
+    use super::*;
+    use crate::input_codes::{code_by_name, code_name, type_by_name};
+
+    pub const SCRIPT_TIME_LIMIT: Duration = Duration::from_millis(2);
+    const MAX_OPERATIONS: u64 = 100_000;
+
+    const FUNCTIONS: [(&str, usize); 4] = [
+        ("allow_type", 1),
+        ("allow_code", 2),
+        ("allow_property", 1),
+        ("on_event", 3),
+    ];
+
+    struct PolicyScript {
+        engine: Engine,
+        ast: AST,
+        /// The functions of FUNCTIONS the script defines
+        defined: Vec<&'static str>,
+    }
+
+    static SCRIPT: RwLock<Option<Arc<PolicyScript>>> = RwLock::new(None);
+
+    thread_local! {
+        static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+    }
+
+    fn engine() -> Engine {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.set_max_call_levels(16);
+        engine.set_max_expr_depths(64, 32);
+        engine.set_max_string_size(1024);
+        engine.set_max_array_size(1024);
+        engine.set_max_map_size(64);
+        engine.disable_symbol("eval");
+        engine.on_progress(|_| {
+            DEADLINE
+                .get()
+                .filter(|deadline| Instant::now() > *deadline)
+                .map(|_| Dynamic::from("time limit exceeded"))
+        });
+        engine.on_print(|text| debug!("policy script: {}", text));
+        engine.on_debug(|text, _, _| debug!("policy script: {}", text));
+        engine.register_fn("code", |name: &str| -> i64 {
+            code_by_name(name).map_or(-1, |(_, code)| code.into())
+        });
+        engine.register_fn("event_type", |name: &str| -> i64 {
+            type_by_name(name).map_or(-1, i64::from)
+        });
+        engine.register_fn("code_name", |type_: i64, code: i64| -> String {
+            match (u16::try_from(type_), u16::try_from(code)) {
+                (Ok(type_), Ok(code)) => code_name(type_, code).unwrap_or_default().to_string(),
+                _ => String::new(),
+            }
+        });
+        engine
+    }
+
+    /// Compiles the script and uses it from now on. None unloads it.
+    pub fn load(path: Option<&Path>) -> Result<(), String> {
+        let Some(path) = path else {
+            *SCRIPT.write().unwrap() = None;
+            return Ok(());
+        };
+        let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let script = compile(&source).map_err(|e| format!("{}: {}", path.display(), e))?;
+        info!(
+            "loaded the policy script {} ({})",
+            path.display(),
+            script.defined.join(", ")
+        );
+        *SCRIPT.write().unwrap() = Some(Arc::new(script));
+        Ok(())
+    }
+
+    fn compile(source: &str) -> Result<PolicyScript, String> {
+        let engine = engine();
+        let ast = engine.compile(source).map_err(|e| e.to_string())?;
+        let defined = FUNCTIONS
+            .iter()
+            .filter(|(name, params)| {
+                ast.iter_functions()
+                    .any(|f| f.name == *name && f.params.len() == *params)
+            })
+            .map(|(name, _)| *name)
+            .collect();
+        Ok(PolicyScript {
+            engine,
+            ast,
+            defined,
+        })
+    }
+
+    impl PolicyScript {
+        /// None, if the script does not define the function
+        fn call(&self, name: &str, args: impl FuncArgs) -> Option<Result<Dynamic, String>> {
+            if !self.defined.contains(&name) {
+                return None;
+            }
+            DEADLINE.set(Some(Instant::now() + SCRIPT_TIME_LIMIT));
+            // the top level of the script only runs once, when it is loaded
+            let options = CallFnOptions::new().eval_ast(false);
+            let result = self.engine.call_fn_with_options::<Dynamic>(
+                options,
+                &mut Scope::new(),
+                &self.ast,
+                name,
+                args,
+            );
+            DEADLINE.set(None);
+            Some(result.map_err(|e| e.to_string()))
+        }
+
+        fn decide(&self, name: &str, args: impl FuncArgs) -> bool {
+            match self.call(name, args) {
+                None => true,
+                Some(Ok(value)) => value.as_bool().unwrap_or_else(|type_| {
+                    warn!(
+                        "policy script: {} returned {}, expected a bool",
+                        name, type_
+                    );
+                    false
+                }),
+                Some(Err(e)) => {
+                    warn!("policy script: {}: {}", name, e);
+                    false
+                }
+            }
+        }
+
+        fn filter_event(&self, event: &mut input_event) -> bool {
+            let args = (
+                i64::from(event.type_),
+                i64::from(event.code),
+                i64::from(event.value),
+            );
+            let value = match self.call("on_event", args) {
+                None => return true,
+                Some(Ok(value)) => value,
+                Some(Err(e)) => {
+                    warn!("policy script: on_event: {}", e);
+                    return false;
+                }
+            };
+            if let Ok(forward) = value.as_bool() {
+                return forward;
+            }
+            let Some(changed) = value.try_cast::<Map>() else {
+                warn!("policy script: on_event returned neither a bool nor a map");
+                return false;
+            };
+            match apply_changes(event, &changed) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("policy script: on_event: {}", e);
+                    false
+                }
+            }
+        }
+    }
+
+    fn apply_changes(event: &mut input_event, changed: &Map) -> Result<(), String> {
+        let field = |key: &str| -> Result<Option<i64>, String> {
+            changed
+                .get(key)
+                .map(|value| {
+                    value
+                        .as_int()
+                        .map_err(|type_| format!("{} is {}, expected an integer", key, type_))
+                })
+                .transpose()
+        };
+        let out_of_range = |key: &str| format!("{} is out of range", key);
+        if let Some(type_) = field("type")? {
+            event.type_ = u16::try_from(type_).map_err(|_| out_of_range("type"))?;
+        }
+        if let Some(code) = field("code")? {
+            event.code = u16::try_from(code).map_err(|_| out_of_range("code"))?;
+        }
+        if let Some(value) = field("value")? {
+            event.value = i32::try_from(value).map_err(|_| out_of_range("value"))?;
+        }
+        Ok(())
+    }
+
+    fn with_script(decide: impl FnOnce(&PolicyScript) -> bool) -> bool {
+        let script = SCRIPT.read().unwrap().clone();
+        match script {
+            Some(script) => decide(&script),
+            None => false,
+        }
+    }
+
+    pub fn allow_type(type_: u16) -> bool {
+        with_script(|script| script.decide("allow_type", (i64::from(type_),)))
+    }
+
+    pub fn allow_code(type_: u16, code: u16) -> bool {
+        with_script(|script| script.decide("allow_code", (i64::from(type_), i64::from(code))))
+    }
+
+    pub fn allow_property(prop: u16) -> bool {
+        with_script(|script| script.decide("allow_property", (i64::from(prop),)))
+    }
+
+    /// Whether to forward the event, which the script may have changed
+    pub fn filter_event(event: &mut input_event) -> bool {
+        with_script(|script| script.filter_event(event))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const EV_KEY: u16 = 0x01;
+        const EV_REL: u16 = 0x02;
+        const KEY_A: u16 = 30;
+        const KEY_B: u16 = 48;
+
+        fn event(type_: u16, code: u16, value: i32) -> input_event {
+            let mut event: input_event = unsafe { std::mem::zeroed() };
+            event.type_ = type_;
+            event.code = code;
+            event.value = value;
+            event
+        }
+
+        #[test]
+        fn decides_and_transforms() {
+            let script = compile(
+                r#"
+                fn allow_type(type) { type != event_type("EV_REL") }
+                fn on_event(type, code, value) {
+                    if code_name(type, code) == "KEY_A" { return #{ code: code("KEY_B") }; }
+                    if code == code("KEY_SYSRQ") { return false; }
+                    true
+                }
+                "#,
+            )
+            .unwrap();
+            assert_eq!(script.defined, vec!["allow_type", "on_event"]);
+            assert!(script.decide("allow_type", (i64::from(EV_KEY),)));
+            assert!(!script.decide("allow_type", (i64::from(EV_REL),)));
+            // not defined
+            assert!(script.decide("allow_code", (1i64, 2i64)));
+
+            let mut a = event(EV_KEY, KEY_A, 1);
+            assert!(script.filter_event(&mut a));
+            assert_eq!((a.type_, a.code, a.value), (EV_KEY, KEY_B, 1));
+            assert!(!script.filter_event(&mut event(EV_KEY, 99, 1)));
+        }
+
+        #[test]
+        fn failing_scripts_deny() {
+            let script = compile(
+                r#"
+                fn allow_type(type) { loop {} }
+                fn allow_code(type, code) { 42 }
+                fn on_event(type, code, value) { #{ value: 1 << 40 } }
+                "#,
+            )
+            .unwrap();
+            assert!(!script.decide("allow_type", (1i64,)));
+            assert!(!script.decide("allow_code", (1i64, 2i64)));
+            let mut key = event(EV_KEY, KEY_A, 1);
+            assert!(!script.filter_event(&mut key));
+            assert_eq!(key.value, 1);
+            assert!(compile("fn on_event(type, code, value) {").is_err());
+        }
+    }
+}
+
+#[cfg(not(feature = "scripted-policy"))]
+mod unavailable {
+    use super::*;
+
+    pub fn load(path: Option<&Path>) -> Result<(), String> {
+        match path {
+            Some(path) => Err(format!(
+                "{}: vuinputd has been built without the scripted-policy feature",
+                path.display()
+            )),
+            None => Ok(()),
+        }
+    }
+
+    pub fn allow_type(_type: u16) -> bool {
+        false
+    }
+
+    pub fn allow_code(_type: u16, _code: u16) -> bool {
+        false
+    }
+
+    pub fn allow_property(_prop: u16) -> bool {
+        false
+    }
+
+    pub fn filter_event(_event: &mut input_event) -> bool {
+        false
+    }
+}
@@ -0,0 +1,199 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Opt-in hexdumps of the buffers that are exchanged with the client, meant for
+// debugging struct layout mismatches (e.g. of 32-bit clients). Everything is logged
+// at trace level and only if --protocol-dump is not "off".
+//
+// In redacted mode, code and value of keyboard keys are zeroed before dumping, so that
+// typed passwords do not end up in the journal. With keystroke privacy enabled, "full"
+// is downgraded to "redacted" at startup.
+
+use crate::cuse_device::keystroke_privacy::is_sensitive;
+use crate::cuse_device::vuinput_write::input_event_compat;
+use crate::global_config::{get_protocol_dump, ProtocolDump};
+use libc::{input_event, uinput_ff_upload, uinput_setup};
+use log::{log_enabled, trace, Level};
+use std::ffi::CStr;
+use std::fmt::Write;
+
+const BYTES_PER_LINE: usize = 16;
+
+/// Returns the active dump mode or None, if nothing would be logged anyway.
+fn active_mode() -> Option<ProtocolDump> {
+    match get_protocol_dump() {
+        ProtocolDump::Off => None,
+        _ if !log_enabled!(Level::Trace) => None,
+        mode => Some(mode),
+    }
+}
+
+pub fn hexdump(data: &[u8]) -> String {
+    let mut out = String::new();
+    for (line, chunk) in data.chunks(BYTES_PER_LINE).enumerate() {
+        let _ = write!(out, "\n  {:04x}: ", line * BYTES_PER_LINE);
+        for i in 0..BYTES_PER_LINE {
+            match chunk.get(i) {
+                Some(b) => {
+                    let _ = write!(out, "{:02x} ", b);
+                }
+                None => out.push_str("   "),
+            }
+        }
+        out.push(' ');
+        for b in chunk {
+            out.push(if b.is_ascii_graphic() || *b == b' ' {
+                *b as char
+            } else {
+                '.'
+            });
+        }
+    }
+    out
+}
+
+/// Zeroes code and value of all keyboard keys in a buffer of input events.
+pub fn redact_input_events(data: &[u8], is_compat: bool) -> Vec<u8> {
+    let mut redacted = data.to_vec();
+    let (event_size, type_offset) = if is_compat {
+        (
+            std::mem::size_of::<input_event_compat>(),
+            std::mem::offset_of!(input_event_compat, type_),
+        )
+    } else {
+        (
+            std::mem::size_of::<input_event>(),
+            std::mem::offset_of!(input_event, type_),
+        )
+    };
+    // type_ (u16) is followed by code (u16) and value (i32) in both layouts
+    for event in redacted.chunks_exact_mut(event_size) {
+        let type_ = u16::from_ne_bytes([event[type_offset], event[type_offset + 1]]);
+        let code = u16::from_ne_bytes([event[type_offset + 2], event[type_offset + 3]]);
+        if is_sensitive(type_, code) {
+            event[type_offset + 2..type_offset + 8].fill(0);
+        }
+    }
+    redacted
+}
+
+pub fn dump_input_events(fh: u64, direction: &str, data: &[u8], is_compat: bool) {
+    let Some(mode) = active_mode() else {
+        return;
+    };
+    let (data, note) = match mode {
+        ProtocolDump::Full => (data.to_vec(), ""),
+        _ => (redact_input_events(data, is_compat), " (keys redacted)"),
+    };
+    trace!(
+        "fh {}: {} {} bytes (compat {}){}:{}",
+        fh,
+        direction,
+        data.len(),
+        is_compat,
+        note,
+        hexdump(&data)
+    );
+}
+
+/// Dumps a buffer of an ioctl. Known structs are decoded in addition to the hexdump.
+///
+/// # Safety
+/// `buf` must point to at least `size` readable bytes or be null.
+pub unsafe fn dump_ioctl_buffer(
+    fh: u64,
+    direction: &str,
+    cmd_name: &str,
+    buf: *const u8,
+    size: usize,
+) {
+    if active_mode().is_none() || buf.is_null() || size == 0 {
+        return;
+    }
+    let data = std::slice::from_raw_parts(buf, size);
+    let decoded = match cmd_name {
+        "UI_DEV_SETUP" if size >= std::mem::size_of::<uinput_setup>() => {
+            decode_uinput_setup(&*(buf as *const uinput_setup))
+        }
+        "UI_BEGIN_FF_UPLOAD" | "UI_END_FF_UPLOAD"
+            if size >= std::mem::size_of::<uinput_ff_upload>() =>
+        {
+            decode_ff_upload(&*(buf as *const uinput_ff_upload))
+        }
+        _ => String::new(),
+    };
+    trace!(
+        "fh {}: ioctl {} {} {} bytes{}{}",
+        fh,
+        cmd_name,
+        direction,
+        size,
+        decoded,
+        hexdump(data)
+    );
+}
+
+fn decode_uinput_setup(setup: &uinput_setup) -> String {
+    let name = CStr::from_bytes_until_nul(unsafe {
+        std::slice::from_raw_parts(setup.name.as_ptr() as *const u8, setup.name.len())
+    })
+    .map(|name| name.to_string_lossy().into_owned())
+    .unwrap_or_else(|_| "<not terminated>".to_string());
+    format!(
+        "\n  uinput_setup {{ bustype: {:#06x}, vendor: {:#06x}, product: {:#06x}, version: {:#06x}, name: {:?}, ff_effects_max: {} }}",
+        setup.id.bustype, setup.id.vendor, setup.id.product, setup.id.version, name, setup.ff_effects_max
+    )
+}
+
+fn decode_ff_upload(upload: &uinput_ff_upload) -> String {
+    format!(
+        "\n  uinput_ff_upload {{ request_id: {}, retval: {}, effect: {{ type: {:#06x}, id: {}, direction: {:#06x}, replay.length: {}, replay.delay: {} }}, old.type: {:#06x} }}",
+        upload.request_id,
+        upload.retval,
+        upload.effect.type_,
+        upload.effect.id,
+        upload.effect.direction,
+        upload.effect.replay.length,
+        upload.effect.replay.delay,
+        upload.old.type_
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EV_KEY: u16 = 0x01;
+
+    fn event_bytes(type_: u16, code: u16, value: i32) -> Vec<u8> {
+        let mut event: input_event = unsafe { std::mem::zeroed() };
+        event.type_ = type_;
+        event.code = code;
+        event.value = value;
+        unsafe {
+            std::slice::from_raw_parts(
+                &event as *const input_event as *const u8,
+                std::mem::size_of::<input_event>(),
+            )
+        }
+        .to_vec()
+    }
+
+    #[test]
+    fn key_events_are_redacted() {
+        let mut data = event_bytes(EV_KEY, 30, 1);
+        data.extend(event_bytes(0x02, 0, 5)); // EV_REL stays untouched
+
+        let redacted = redact_input_events(&data, false);
+        let size = std::mem::size_of::<input_event>();
+        assert_eq!(&redacted[..size], &event_bytes(EV_KEY, 0, 0)[..]);
+        assert_eq!(&redacted[size..], &data[size..]);
+    }
+
+    #[test]
+    fn hexdump_pads_last_line() {
+        let dump = hexdump(b"abc");
+        assert_eq!(dump, format!("\n  0000: 61 62 63 {} abc", "   ".repeat(13)));
+    }
+}
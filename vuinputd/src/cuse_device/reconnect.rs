@@ -0,0 +1,450 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Recovery of handles whose host uinput fd has failed for good. The errors of writes to uinput
+// are classified (see WriteFailure): ENODEV, and EINVAL for the whole events vuinputd writes,
+// mean that the device is gone (e.g. it has been torn down by a reload of the uinput module),
+// EIO that it may be, once it persists. Then the handle is connected to a fresh /dev/uinput:
+// the recorded setup (UI_SET_*BIT, UI_SET_PHYS, UI_DEV_SETUP and UI_ABS_SETUP) is replayed, the
+// device is created again and a container gets the new event node in place of the old one. The
+// client keeps its file and only notices the failed writes.
+//
+// If that is not possible, the handle is invalidated like a revoked one (see revoke): the
+// container loses the node and the client gets ENODEV, so it sets the device up again.
+//
+// Only the bits the device policy let through are recorded, so the replay does not
+// need to consult the policy again.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use libc::{c_char, c_int, O_CLOEXEC, O_NONBLOCK};
+use libc::{EINVAL, EIO, ENODEV};
+use log::{info, warn};
+use std::ffi::CString;
+use uinput_ioctls::*;
+
+use crate::control::events;
+use crate::cuse_device::broadcast;
+use crate::cuse_device::device_id::marked_phys;
+use crate::cuse_device::device_policy::{
+    EV_ABS, EV_FF, EV_KEY, EV_LED, EV_MSC, EV_REL, EV_SND, EV_SW,
+};
+use crate::cuse_device::evdev_write_watcher::EVDEV_WRITE_WATCHER;
+use crate::cuse_device::ioctl_error::VuIoctlError;
+use crate::cuse_device::revoke;
+use crate::cuse_device::state::{
+    AbsInfo, DeviceDescriptor, DeviceLifecycle, DeviceSetup, VuInputState,
+};
+use crate::cuse_device::uinput_compat;
+use crate::cuse_device::vuinput_ioctl::create_device;
+use crate::job_engine::JOB_DISPATCHER;
+use crate::jobs::emit_udev_event_job::EmitUdevEventJob;
+use crate::jobs::mknod_device_job::MknodDeviceJob;
+use crate::jobs::remove_device_job::RemoveDeviceJob;
+use crate::jobs::verify_seat_job;
+use crate::process_tools::SELF_NAMESPACES;
+
+/// Failed writes in a row after which the fd is considered dead
+pub const FAILURES_BEFORE_RECONNECT: u32 = 3;
+/// A device that fails again within this time after it has been reconnected is given up
+const MIN_RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+pub type SetBit = unsafe fn(c_int, nix::sys::ioctl::ioctl_param_type) -> nix::Result<c_int>;
+
+/// The UI_SET_*BIT ioctl that enables a code of the event type
+pub fn code_bit_ioctl(type_: u16) -> Option<(&'static str, SetBit)> {
+    let ioctl: (&'static str, SetBit) = match type_ {
+        EV_KEY => ("UI_SET_KEYBIT", ui_set_keybit),
+        EV_REL => ("UI_SET_RELBIT", ui_set_relbit),
+        EV_ABS => ("UI_SET_ABSBIT", ui_set_absbit),
+        EV_MSC => ("UI_SET_MSCBIT", ui_set_mscbit),
+        EV_LED => ("UI_SET_LEDBIT", ui_set_ledbit),
+        EV_SND => ("UI_SET_SNDBIT", ui_set_sndbit),
+        EV_FF => ("UI_SET_FFBIT", ui_set_ffbit),
+        EV_SW => ("UI_SET_SWBIT", ui_set_swbit),
+        _ => return None,
+    };
+    Some(ioctl)
+}
+
+/// What a failed write to uinput says about the device on the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteFailure {
+    /// ENODEV, or EINVAL: vuinputd only writes whole events, and uinput takes the writes to a
+    /// destroyed device for a legacy setup
+    Gone,
+    /// EIO, the device is given up when it persists
+    Failing,
+    /// Anything else, e.g. EAGAIN, which the client gets as it is
+    Other,
+}
+
+impl WriteFailure {
+    pub fn of(error: &io::Error) -> WriteFailure {
+        match error.raw_os_error() {
+            Some(ENODEV) | Some(EINVAL) => WriteFailure::Gone,
+            Some(EIO) => WriteFailure::Failing,
+            _ => WriteFailure::Other,
+        }
+    }
+
+    /// The errno for the client
+    pub fn errno(self, error: &io::Error) -> i32 {
+        match self {
+            WriteFailure::Gone => ENODEV,
+            WriteFailure::Failing => EIO,
+            WriteFailure::Other => error.raw_os_error().unwrap_or(EIO),
+        }
+    }
+}
+
+/// What became of a handle after a failed write to uinput
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// The handle has a new device, the write can be repeated
+    Reconnected,
+    /// The write fails, the handle stays as it is
+    Failed,
+    /// The device is gone for good, the handle has been invalidated
+    Invalidated,
+}
+
+/// Whether a failure of the write gives the device up: one that is gone at once, one that keeps
+/// failing after `failures` writes in a row
+fn gives_up(failure: WriteFailure, failures: u32) -> bool {
+    match failure {
+        WriteFailure::Gone => true,
+        WriteFailure::Failing => failures >= FAILURES_BEFORE_RECONNECT,
+        WriteFailure::Other => false,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Plan {
+    Reconnect,
+    Invalidate,
+}
+
+/// What becomes of a created device after the `failures`th failed write in a row, None if only
+/// the write fails. A device that fails again right after it has been reconnected is not
+/// reconnected once more.
+fn plan(
+    failure: WriteFailure,
+    failures: u32,
+    last_reconnect: Option<Instant>,
+    now: Instant,
+) -> Option<Plan> {
+    if !gives_up(failure, failures) {
+        return None;
+    }
+    match last_reconnect {
+        Some(last) if now.duration_since(last) < MIN_RECONNECT_INTERVAL => Some(Plan::Invalidate),
+        _ => Some(Plan::Reconnect),
+    }
+}
+
+/// Counts a failed write to uinput. A device that is gone, or keeps failing, is created again
+/// on a new fd; if that fails or the device has just been reconnected, the handle is
+/// invalidated.
+pub fn on_write_error(fh: u64, vuinput_state: &mut VuInputState, error: &io::Error) -> Recovery {
+    let failure = WriteFailure::of(error);
+    if failure == WriteFailure::Other {
+        return Recovery::Failed;
+    }
+    vuinput_state.write_failures += 1;
+    if vuinput_state.lifecycle != DeviceLifecycle::Created || vuinput_state.input_device.is_none() {
+        return Recovery::Failed;
+    }
+    let now = Instant::now();
+    match plan(
+        failure,
+        vuinput_state.write_failures,
+        vuinput_state.last_reconnect,
+        now,
+    ) {
+        None => return Recovery::Failed,
+        Some(Plan::Invalidate) => {
+            let cause = format!("it failed again right after reconnecting: {}", error);
+            revoke::invalidate(fh, vuinput_state, &cause);
+            return Recovery::Invalidated;
+        }
+        Some(Plan::Reconnect) => {}
+    }
+    vuinput_state.last_reconnect = Some(now);
+    warn!(
+        "fh {}: the device on the host failed ({}, {} writes in a row), reconnecting",
+        fh, error, vuinput_state.write_failures
+    );
+    match reconnect(fh, vuinput_state) {
+        Ok(devnode) => {
+            info!("fh {}: reconnected, the device is now {}", fh, devnode);
+            vuinput_state.write_failures = 0;
+            Recovery::Reconnected
+        }
+        Err(e) => {
+            let cause = format!("{}, reconnecting failed: {}", error, e);
+            revoke::invalidate(fh, vuinput_state, &cause);
+            Recovery::Invalidated
+        }
+    }
+}
+
+/// Creates the device again on a new uinput fd and swaps it in. The old fd is closed, which
+/// destroys the old device on the host, if there is anything left of it.
+fn reconnect(fh: u64, vuinput_state: &mut VuInputState) -> Result<String, String> {
+    let setup = vuinput_state
+        .descriptor
+        .setup
+        .ok_or("the setup of the device has not been recorded")?;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(O_NONBLOCK | O_CLOEXEC)
+        .open(Path::new("/dev/uinput"))
+        .map_err(|e| format!("couldn't open /dev/uinput: {}", e))?;
+    let fd = file.as_raw_fd();
+    let mut new_device = unsafe {
+        replay_setup(fd, &setup, &vuinput_state.descriptor).map_err(|e| e.to_string())?;
+        // a single attempt, the write can't wait for retries; the client sets the device up again
+        create_device(fh, fd).map_err(|e| e.to_string())?
+    };
+
+    let old_device = vuinput_state
+        .input_device
+        .take()
+        .expect("only created devices are reconnected");
+    new_device.serial = old_device.serial;
+    new_device.mirrors = old_device.mirrors.clone();
+    let old_file = std::mem::replace(&mut vuinput_state.file, file);
+    replace_watched_fd(fh, &old_file, &vuinput_state.file);
+    drop(old_file);
+
+    events::device_removed(fh, vuinput_state, &old_device.devnode);
+    events::device_created(fh, vuinput_state, &new_device.devnode);
+    let in_container = !SELF_NAMESPACES
+        .get()
+        .unwrap()
+        .equal_mnt_and_net(&vuinput_state.requesting_process.namespaces);
+    if in_container {
+        // one after another on the queue of the container, like destroy and create
+        let requesting_process = &vuinput_state.requesting_process;
+        let remove_job = RemoveDeviceJob::new(
+            requesting_process.clone(),
+            old_device.devname.clone(),
+            old_device.syspath.clone(),
+            old_device.major,
+            old_device.minor,
+        );
+        // the job logs a failure, the client is not waiting for it
+        let mknod_job = MknodDeviceJob::new(
+            requesting_process.clone(),
+            new_device.devname.clone(),
+            new_device.syspath.clone(),
+            new_device.major,
+            new_device.minor,
+        )
+        .record_node(new_device.container_devnode.clone())
+        .on_completion(Box::new(|_| {}));
+        let emit_udev_event_job = EmitUdevEventJob::new(
+            requesting_process.clone(),
+            new_device.devnode.clone(),
+            new_device.syspath.clone(),
+            new_device.major,
+            new_device.minor,
+            new_device.serial.map(|serial| serial.to_string()),
+        );
+        let mut dispatcher = JOB_DISPATCHER.get().unwrap().lock().unwrap();
+        dispatcher.dispatch(Box::new(remove_job));
+        dispatcher.dispatch(Box::new(mknod_job));
+        dispatcher.dispatch(Box::new(emit_udev_event_job));
+    }
+    broadcast::remove_mirrors(fh, &old_device);
+    broadcast::create_mirrors(fh, &new_device);
+    verify_seat_job::verify(&new_device);
+    let devnode = new_device.devnode.clone();
+    vuinput_state.input_device = Some(new_device);
+    Ok(devnode)
+}
+
+/// Lets the evdev write watcher watch the new uinput fd of the handle instead of the old one
+pub fn replace_watched_fd(fh: u64, old_file: &File, new_file: &File) {
+    let watcher = EVDEV_WRITE_WATCHER.get().unwrap().lock().unwrap();
+    if let Err(e) = watcher.remove_device(old_file) {
+        warn!("fh {}: couldn't stop watching the old uinput fd: {}", fh, e);
+    }
+    if let Err(e) = watcher.add_fd(fh, new_file) {
+        warn!("fh {}: couldn't watch the new uinput fd: {}", fh, e);
+    }
+}
+
+/// Sets a fresh uinput fd up like the recorded one, up to UI_DEV_CREATE
+///
+/// # Safety
+/// `fd` must be an open uinput fd that has not been set up yet.
+pub unsafe fn replay_setup(
+    fd: c_int,
+    setup: &DeviceSetup,
+    descriptor: &DeviceDescriptor,
+) -> Result<(), VuIoctlError> {
+    for type_ in &descriptor.event_types {
+        ui_set_evbit(fd, (*type_).into()).map_err(VuIoctlError::host("UI_SET_EVBIT"))?;
+    }
+    for (type_, codes) in &descriptor.codes {
+        let Some((name, set_bit)) = code_bit_ioctl(*type_) else {
+            continue;
+        };
+        for code in codes {
+            set_bit(fd, (*code).into()).map_err(VuIoctlError::host(name))?;
+        }
+    }
+    for prop in &descriptor.properties {
+        ui_set_propbit(fd, (*prop).into()).map_err(VuIoctlError::host("UI_SET_PROPBIT"))?;
+    }
+    if let Some(phys) = replayed_phys(descriptor) {
+        let phys = CString::new(phys).unwrap_or_default();
+        ui_set_phys(fd, phys.as_ptr() as *const *const c_char)
+            .map_err(VuIoctlError::host("UI_SET_PHYS"))?;
+    }
+    let usetup = setup.to_uinput_setup();
+    uinput_compat::dev_setup(fd, &usetup)?;
+    // also holds the ranges of a legacy setup, they have been applied at the first UI_DEV_CREATE
+    for (code, absinfo) in &descriptor.absinfo {
+        uinput_compat::abs_setup(fd, &abs_setup(*code, absinfo))?;
+    }
+    uinput_compat::finish_setup(fd, descriptor)
+}
+
+/// The phys as it has been set on the host: marked, if the device keeps its id
+fn replayed_phys(descriptor: &DeviceDescriptor) -> Option<String> {
+    match descriptor.preserved_id {
+        Some(_) => Some(marked_phys(descriptor.phys.as_deref())),
+        None => descriptor.phys.clone(),
+    }
+}
+
+/// UI_ABS_SETUP of a recorded axis, the current value is not part of the setup
+fn abs_setup(code: u16, absinfo: &AbsInfo) -> libc::uinput_abs_setup {
+    let mut abs_setup: libc::uinput_abs_setup = unsafe { std::mem::zeroed() };
+    abs_setup.code = code;
+    abs_setup.absinfo.minimum = absinfo.minimum;
+    abs_setup.absinfo.maximum = absinfo.maximum;
+    abs_setup.absinfo.fuzz = absinfo.fuzz;
+    abs_setup.absinfo.flat = absinfo.flat;
+    abs_setup.absinfo.resolution = absinfo.resolution;
+    abs_setup
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_the_errors_of_uinput() {
+        let error = |errno| io::Error::from_raw_os_error(errno);
+        for errno in [ENODEV, EINVAL] {
+            assert_eq!(WriteFailure::of(&error(errno)), WriteFailure::Gone);
+            assert_eq!(WriteFailure::Gone.errno(&error(errno)), ENODEV);
+        }
+        assert_eq!(WriteFailure::of(&error(EIO)), WriteFailure::Failing);
+        let busy = error(libc::EAGAIN);
+        assert_eq!(WriteFailure::of(&busy), WriteFailure::Other);
+        assert_eq!(WriteFailure::Other.errno(&busy), libc::EAGAIN);
+
+        // a device that is gone is given up at once, EIO only when it persists
+        assert!(gives_up(WriteFailure::Gone, 1));
+        assert!(!gives_up(
+            WriteFailure::Failing,
+            FAILURES_BEFORE_RECONNECT - 1
+        ));
+        assert!(gives_up(WriteFailure::Failing, FAILURES_BEFORE_RECONNECT));
+        assert!(!gives_up(WriteFailure::Other, 100));
+    }
+
+    #[test]
+    fn reconnects_once_per_interval() {
+        let now = Instant::now();
+        assert_eq!(
+            plan(WriteFailure::Gone, 1, None, now),
+            Some(Plan::Reconnect)
+        );
+        assert_eq!(plan(WriteFailure::Failing, 1, None, now), None);
+        assert_eq!(
+            plan(WriteFailure::Failing, FAILURES_BEFORE_RECONNECT, None, now),
+            Some(Plan::Reconnect)
+        );
+        assert_eq!(plan(WriteFailure::Other, 100, None, now), None);
+
+        // failing again right after a reconnect gives the handle up
+        let reconnected = now - Duration::from_secs(1);
+        assert_eq!(
+            plan(WriteFailure::Gone, 1, Some(reconnected), now),
+            Some(Plan::Invalidate)
+        );
+        assert_eq!(plan(WriteFailure::Failing, 1, Some(reconnected), now), None);
+        let long_ago = now - MIN_RECONNECT_INTERVAL;
+        assert_eq!(
+            plan(WriteFailure::Gone, 1, Some(long_ago), now),
+            Some(Plan::Reconnect)
+        );
+    }
+
+    #[test]
+    fn replays_the_recorded_setup() {
+        let mut descriptor = DeviceDescriptor {
+            phys: Some("usb-0000:00:14.0-1/input0".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            replayed_phys(&descriptor).as_deref(),
+            Some("usb-0000:00:14.0-1/input0")
+        );
+        descriptor.preserved_id = Some("045e:028e".parse().unwrap());
+        assert_eq!(
+            replayed_phys(&descriptor).as_deref(),
+            Some("vuinputd/usb-0000:00:14.0-1/input0")
+        );
+
+        let stick = AbsInfo {
+            value: 1200,
+            minimum: -32768,
+            maximum: 32767,
+            fuzz: 16,
+            flat: 128,
+            resolution: 0,
+        };
+        let setup = abs_setup(0x01, &stick);
+        assert_eq!(setup.code, 0x01);
+        assert_eq!(AbsInfo::from(setup.absinfo), AbsInfo { value: 0, ..stick });
+
+        // the first ioctl the host refuses ends the replay
+        descriptor.event_types.insert(EV_KEY);
+        let not_uinput = File::open("/dev/null").unwrap();
+        let replayed = unsafe {
+            replay_setup(
+                not_uinput.as_raw_fd(),
+                &DeviceSetup {
+                    bustype: 0x03,
+                    vendor: 0x045e,
+                    product: 0x028e,
+                    version: 1,
+                    name: [0; 80],
+                    ff_effects_max: 0,
+                },
+                &descriptor,
+            )
+        };
+        assert!(matches!(
+            replayed,
+            Err(VuIoctlError::Host {
+                ioctl: "UI_SET_EVBIT",
+                ..
+            })
+        ));
+    }
+}
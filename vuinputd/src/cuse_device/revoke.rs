@@ -0,0 +1,159 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Revoking a handle (vuinputctl revoke) destroys its device on the host and removes it from
+// the container, just like UI_DEV_DESTROY. The client keeps its file open, but every further
+// request is answered with ENODEV, so it can't simply set the device up again.
+//
+// When vuinputd stops, all handles are revoked this way, as the kernel does not send any
+// release requests after the CUSE session has ended. A handle whose device is gone on the host
+// and can't be created again (see reconnect) is invalidated the same way: the client gets ENODEV
+// from then on and sets its device up on a new handle.
+
+use ::cuse_lowlevel::*;
+use libc::ENODEV;
+use log::{debug, info, warn};
+use std::os::fd::AsRawFd;
+use uinput_ioctls::ui_dev_destroy;
+
+use crate::audit::{self, Action, Verdict};
+use crate::control::events;
+use crate::cuse_device::state::{
+    get_vuinput_state, vuinput_states, DeviceDescriptor, DeviceLifecycle, VuFileHandle,
+    VuInputState,
+};
+use crate::cuse_device::{broadcast, device_limits, device_serial};
+use crate::job_engine::JOB_DISPATCHER;
+use crate::jobs::remove_device_job::RemoveDeviceJob;
+use crate::process_tools::SELF_NAMESPACES;
+
+/// Returns the device node of the removed device, if one had been created.
+pub fn revoke(fh: u64) -> Result<Option<String>, String> {
+    let vuinput_state_mutex = get_vuinput_state(&VuFileHandle::Fh(fh))?;
+    let mut vuinput_state = vuinput_state_mutex.lock().unwrap();
+    if vuinput_state.revoked {
+        return Err(format!("fh {} has already been revoked", fh));
+    }
+    wake_client(&mut vuinput_state);
+    let devnode = destroy_device(fh, &mut vuinput_state);
+    audit::record(
+        fh,
+        &vuinput_state,
+        Action::Revoke,
+        Verdict::Allowed,
+        Some("revoked on the control socket".to_string()),
+        devnode.as_deref(),
+    );
+    match devnode {
+        Some(devnode) => {
+            info!("fh {}: revoked, removing {}", fh, devnode);
+            Ok(Some(devnode))
+        }
+        None => {
+            info!("fh {}: revoked, no device had been created", fh);
+            Ok(None)
+        }
+    }
+}
+
+/// Invalidates a handle whose device on the host is gone, see reconnect. Unlike revoke, the
+/// caller holds the lock of the handle.
+pub fn invalidate(fh: u64, vuinput_state: &mut VuInputState, cause: &str) {
+    wake_client(vuinput_state);
+    let devnode = destroy_device(fh, vuinput_state);
+    let reason = format!("the device on the host is gone ({})", cause);
+    audit::record(
+        fh,
+        vuinput_state,
+        Action::Revoke,
+        Verdict::Allowed,
+        Some(reason.clone()),
+        devnode.as_deref(),
+    );
+    match devnode {
+        Some(devnode) => warn!("fh {}: {}, removing {}", fh, reason, devnode),
+        None => warn!("fh {}: {}", fh, reason),
+    }
+}
+
+/// Answers a blocking read with ENODEV and wakes a poll, which then reports POLLERR, so that the
+/// client notices the revoke instead of waiting for input that never comes.
+fn wake_client(vuinput_state: &mut VuInputState) {
+    if let Some(pending) = vuinput_state.pending_read.take() {
+        unsafe { fuse_lowlevel::fuse_reply_err(pending.req, ENODEV) };
+    }
+    if let Some(mut waiter) = vuinput_state.poll.take_waiters() {
+        waiter.notify();
+    }
+}
+
+/// Revokes all handles when vuinputd stops and returns the number of removed devices. The
+/// CUSE session has ended already, so pending reads can't be answered anymore.
+pub fn revoke_all() -> usize {
+    let mut removed = 0;
+    for (fh, vuinput_state_mutex) in vuinput_states() {
+        let mut vuinput_state = vuinput_state_mutex.lock().unwrap();
+        if vuinput_state.revoked {
+            continue;
+        }
+        vuinput_state.pending_read = None;
+        let devnode = destroy_device(fh, &mut vuinput_state);
+        audit::record(
+            fh,
+            &vuinput_state,
+            Action::Revoke,
+            Verdict::Allowed,
+            Some("vuinputd stops".to_string()),
+            devnode.as_deref(),
+        );
+        if let Some(devnode) = devnode {
+            debug!("fh {}: removing {} on shutdown", fh, devnode);
+            removed += 1;
+        }
+    }
+    removed
+}
+
+/// Destroys the device on the host and dispatches its removal from the container. The
+/// RemoveDeviceJob is a cleanup job, so it also runs when the dispatcher is shutting down.
+fn destroy_device(fh: u64, vuinput_state: &mut VuInputState) -> Option<String> {
+    vuinput_state.revoked = true;
+    let input_device = vuinput_state.input_device.take();
+    if vuinput_state.lifecycle == DeviceLifecycle::Created {
+        vuinput_state.lifecycle.advance(DeviceLifecycle::Destroyed);
+    }
+    vuinput_state.descriptor = DeviceDescriptor::default();
+    if let Err(errno) = unsafe { ui_dev_destroy(vuinput_state.file.as_raw_fd()) } {
+        warn!("fh {}: UI_DEV_DESTROY on revoke failed: {}", fh, errno);
+    }
+
+    let input_device = input_device?;
+    events::device_removed(fh, vuinput_state, &input_device.devnode);
+    broadcast::remove_mirrors(fh, &input_device);
+    // not awaited, the container might not respond (see vuinput_release)
+    if !SELF_NAMESPACES
+        .get()
+        .unwrap()
+        .equal_mnt_and_net(&vuinput_state.requesting_process.namespaces)
+    {
+        device_limits::release(&vuinput_state.requesting_process.namespaces);
+        if let Some(serial) = &input_device.serial {
+            device_serial::release(serial);
+        }
+        let remove_job = RemoveDeviceJob::new(
+            vuinput_state.requesting_process.clone(),
+            input_device.devname.clone(),
+            input_device.syspath.clone(),
+            input_device.major,
+            input_device.minor,
+        );
+        JOB_DISPATCHER
+            .get()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .dispatch(Box::new(remove_job));
+    }
+    Some(input_device.devnode)
+}
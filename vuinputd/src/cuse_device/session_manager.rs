@@ -0,0 +1,459 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Runs one CUSE session per device node given with --device, so that e.g. gamepads and
+// keyboards of different container classes can be served by different nodes with different
+// policies. Each session runs in a thread of its own, which serves its requests with
+// --cuse-threads threads; the node is passed as userdata and can be looked up by the
+// request handlers with node_of_request. A node of kind "uhid" speaks the protocol of
+// /dev/uhid instead of the one of /dev/uinput, see vuhid.
+//
+// libfuse only knows one session for its signal handlers, so SIGINT and SIGTERM are blocked
+// and awaited by the main thread, which then ends all sessions. Tearing down the sessions
+// lets libfuse print "fuse_remove_signal_handlers: unknown session" for all but the last one.
+
+use std::ffi::{c_void, OsStr};
+use std::fmt;
+use std::io;
+use std::os::unix::thread::JoinHandleExt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use ::cuse_lowlevel::*;
+use clap::ValueEnum;
+use log::{debug, error, info};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::getpid;
+
+use crate::config_file::value_name;
+use crate::cuse_device::fuse_args::FuseArgs;
+use crate::cuse_device::op_sequencer;
+use crate::cuse_device::vuhid::vuhid_make_cuse_ops;
+use crate::cuse_device::vuinput_make_cuse_ops;
+use crate::global_config::DevicePolicy;
+use crate::signal_handling;
+
+const INTERRUPT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The default of libfuse for the maximum number of threads of a session
+pub const DEFAULT_CUSE_THREADS: u32 = 10;
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// The device a node emulates
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NodeKind {
+    #[default]
+    Uinput,
+    Uhid,
+}
+
+/// A CUSE node, given as `name=vuinput-gamepad,policy=strict-gamepad` or
+/// `name=vuhid,kind=uhid`. Without a policy, the configured device policy applies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceNode {
+    pub name: String,
+    pub policy: Option<DevicePolicy>,
+    pub kind: NodeKind,
+}
+
+impl FromStr for DeviceNode {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut name = None;
+        let mut policy = None;
+        let mut kind = NodeKind::default();
+        for part in spec.split(',') {
+            match part.split_once('=') {
+                Some(("name", value)) if !value.is_empty() => name = Some(value.to_string()),
+                Some(("kind", "uinput")) => kind = NodeKind::Uinput,
+                Some(("kind", "uhid")) => kind = NodeKind::Uhid,
+                Some(("kind", value)) => {
+                    return Err(format!("unknown kind '{}', expected uinput or uhid", value))
+                }
+                Some(("policy", value)) => {
+                    policy = Some(DevicePolicy::from_str(value, false).map_err(|_| {
+                        format!(
+                            "unknown policy '{}', expected one of: {}",
+                            value,
+                            DevicePolicy::value_variants()
+                                .iter()
+                                .map(value_name)
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        )
+                    })?)
+                }
+                _ => {
+                    return Err(format!(
+                        "invalid part '{}', expected name=..., policy=... or kind=...",
+                        part
+                    ))
+                }
+            }
+        }
+        let name = name.ok_or_else(|| "the name of the device node is missing".to_string())?;
+        if name.contains('/') {
+            return Err(format!("'{}' must not contain a slash", name));
+        }
+        Ok(DeviceNode { name, policy, kind })
+    }
+}
+
+impl fmt::Display for DeviceNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "name={}", self.name)?;
+        if let Some(policy) = &self.policy {
+            write!(f, ",policy={}", value_name(policy))?;
+        }
+        if self.kind == NodeKind::Uhid {
+            write!(f, ",kind=uhid")?;
+        }
+        Ok(())
+    }
+}
+
+/// The node that received the request. None, if vuinputd runs a single session (--devname).
+///
+/// # Safety
+/// `req` must be a request that is still pending.
+pub unsafe fn node_of_request(req: fuse_lowlevel::fuse_req_t) -> Option<&'static DeviceNode> {
+    node_of_userdata(fuse_lowlevel::fuse_req_userdata(req))
+}
+
+/// # Safety
+/// `userdata` must be the userdata of a session, i.e. null or a DeviceNode leaked by
+/// run_sessions.
+pub unsafe fn node_of_userdata(userdata: *mut c_void) -> Option<&'static DeviceNode> {
+    (userdata as *const DeviceNode).as_ref()
+}
+
+/// DEVNAME=... for cuse_info
+pub fn dev_info_args(devname: &str) -> io::Result<FuseArgs> {
+    let mut dev_info_args = FuseArgs::new();
+    dev_info_args
+        .push(&format!("DEVNAME={}", devname))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    Ok(dev_info_args)
+}
+
+/// The command line of a session: foreground, single-threaded for a single thread and the
+/// --fuse-option values
+pub fn session_args(argv0: &OsStr, fuse_options: &[String], threads: u32) -> io::Result<FuseArgs> {
+    let mut fuse_args = FuseArgs::with_program_name(argv0)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    fuse_args
+        .push("-f")
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if threads <= 1 {
+        fuse_args
+            .push("-s")
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    }
+    for option in fuse_options {
+        fuse_args
+            .push_option(option)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    }
+    Ok(fuse_args)
+}
+
+/// Serves the requests until the session is ended. The handlers of different handles run in
+/// parallel, those of a handle one after the other, in the order of the kernel (see
+/// op_sequencer).
+///
+/// # Safety
+/// `se` must be a session set up by cuse_lowlevel_setup and not torn down yet.
+pub unsafe fn session_loop(
+    se: *mut fuse_lowlevel::fuse_session,
+    multithreaded: bool,
+    threads: u32,
+) -> i32 {
+    if multithreaded {
+        serve_in_order(se, threads)
+    } else {
+        fuse_lowlevel::fuse_session_loop(se)
+    }
+}
+
+/// The request in `buf`, unless libfuse spliced it into a pipe
+unsafe fn request_of(buf: &fuse_lowlevel::fuse_buf, size: usize) -> Option<&[u8]> {
+    if buf.flags & fuse_lowlevel::fuse_buf_flags_FUSE_BUF_IS_FD != 0 || buf.mem.is_null() {
+        return None;
+    }
+    Some(std::slice::from_raw_parts(buf.mem as *const u8, size))
+}
+
+/// Reads and handles requests until the session is ended. A request is read under `reading`,
+/// which is held until its ticket has been drawn.
+unsafe fn serve_requests(se: *mut fuse_lowlevel::fuse_session, reading: &Mutex<()>) -> i32 {
+    let mut buf: fuse_lowlevel::fuse_buf = std::mem::zeroed();
+    let mut res = 0;
+    while fuse_lowlevel::fuse_session_exited(se) == 0 {
+        let received = {
+            let _reading = reading.lock().unwrap();
+            if fuse_lowlevel::fuse_session_exited(se) != 0 {
+                break;
+            }
+            res = fuse_lowlevel::fuse_session_receive_buf(se, &mut buf);
+            if res == -libc::EINTR || res == -libc::EAGAIN {
+                res = 0;
+                continue;
+            }
+            if res <= 0 {
+                // /dev/cuse has been closed or failed, which ends the session for every thread
+                fuse_lowlevel::fuse_session_exit(se);
+                break;
+            }
+            request_of(&buf, res as usize)
+                .and_then(op_sequencer::handle_of)
+                .map(op_sequencer::received)
+        };
+        fuse_lowlevel::fuse_session_process_buf(se, &buf);
+        drop(received);
+    }
+    libc::free(buf.mem);
+    res.min(0)
+}
+
+/// Serves the session with `threads` threads. The thread pool of libfuse lets its threads read
+/// from /dev/cuse at the same time, so a request read later could draw the earlier ticket of its
+/// handle. Here, they read one at a time and draw the ticket before the next request is read.
+unsafe fn serve_in_order(se: *mut fuse_lowlevel::fuse_session, threads: u32) -> i32 {
+    // a single session (--devname) has not installed it
+    if let Err(e) = signal_handling::install_session_interrupt() {
+        error!(
+            "failed to install the interrupt of the session threads: {}",
+            e
+        );
+    }
+    let reading = Arc::new(Mutex::new(()));
+    let mut workers = Vec::new();
+    for index in 0..threads.max(1) {
+        let session = Session(se);
+        let reading = reading.clone();
+        let worker = thread::Builder::new()
+            .name(format!("cuse-worker-{}", index))
+            .spawn(move || {
+                let session = session;
+                unsafe { serve_requests(session.0, &reading) }
+            });
+        match worker {
+            Ok(worker) => workers.push(worker),
+            Err(e) => {
+                error!("failed to start a thread of the session: {}", e);
+                break;
+            }
+        }
+    }
+    while fuse_lowlevel::fuse_session_exited(se) == 0 && workers.iter().any(|w| !w.is_finished()) {
+        thread::sleep(INTERRUPT_INTERVAL);
+    }
+    fuse_lowlevel::fuse_session_exit(se);
+    interrupt_until_finished(&workers);
+    let res = workers
+        .into_iter()
+        .filter_map(|worker| worker.join().ok())
+        .find(|res| *res != 0)
+        .unwrap_or(0);
+    fuse_lowlevel::fuse_session_reset(se);
+    res
+}
+
+struct Session(*mut fuse_lowlevel::fuse_session);
+
+// the session is only handed to fuse_session_exit and to the threads of serve_in_order, which
+// libfuse allows to serve it at the same time
+unsafe impl Send for Session {}
+
+/// Runs a session for every node until SIGINT or SIGTERM is received or one of the
+/// sessions ends. SIGINT and SIGTERM must have been blocked before the first thread had
+/// been spawned, see signal_handling::block_shutdown_signals.
+pub fn run_sessions(
+    nodes: &[DeviceNode],
+    argv0: &OsStr,
+    fuse_options: &[String],
+    cuse_threads: u32,
+    major: u32,
+    minor: u32,
+) -> io::Result<()> {
+    signal_handling::install_session_interrupt().map_err(io::Error::from)?;
+    let sessions: Arc<Mutex<Vec<Session>>> = Arc::new(Mutex::new(Vec::new()));
+
+    let mut threads = Vec::new();
+    for node in nodes {
+        // the handlers look up the node for every request, so it has to outlive the session
+        let node: &'static DeviceNode = Box::leak(Box::new(node.clone()));
+        let argv0 = argv0.to_os_string();
+        let fuse_options = fuse_options.to_vec();
+        let sessions = sessions.clone();
+        let thread = thread::Builder::new()
+            .name(format!("cuse-{}", node.name))
+            .spawn(move || {
+                if let Err(e) = serve(
+                    node,
+                    &argv0,
+                    &fuse_options,
+                    cuse_threads,
+                    major,
+                    minor,
+                    &sessions,
+                ) {
+                    error!("failed to serve /dev/{}: {}", node.name, e);
+                }
+                // a session that ended on its own takes the others down as well, the same way
+                // as a single session would end vuinputd
+                if !SHUTTING_DOWN.load(Ordering::SeqCst) {
+                    let _ = kill(getpid(), Signal::SIGTERM);
+                }
+            })?;
+        threads.push(thread);
+    }
+
+    match signal_handling::wait_for_shutdown_signal() {
+        Ok(signal) => info!("{} received, ending {} sessions", signal, nodes.len()),
+        Err(e) => error!("waiting for SIGINT and SIGTERM failed: {}", e),
+    }
+    // a session that registers after this point sees the flag and ends right away
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    for session in sessions.lock().unwrap().iter() {
+        unsafe { fuse_lowlevel::fuse_session_exit(session.0) };
+    }
+    interrupt_until_finished(&threads);
+    Ok(())
+}
+
+fn serve(
+    node: &'static DeviceNode,
+    argv0: &OsStr,
+    fuse_options: &[String],
+    threads: u32,
+    major: u32,
+    minor: u32,
+    sessions: &Mutex<Vec<Session>>,
+) -> io::Result<()> {
+    let mut dev_info_args = dev_info_args(&node.name)?;
+    let mut fuse_args = session_args(argv0, fuse_options, threads)?;
+    let ci = cuse_lowlevel::cuse_info {
+        // a fixed major/minor is only allowed for a single node
+        dev_major: major,
+        dev_minor: minor,
+        dev_info_argc: dev_info_args.argc() as u32,
+        dev_info_argv: dev_info_args.argv_const(),
+        flags: cuse_lowlevel::CUSE_UNRESTRICTED_IOCTL,
+    };
+    let cuse_ops = match node.kind {
+        NodeKind::Uinput => vuinput_make_cuse_ops(),
+        NodeKind::Uhid => vuhid_make_cuse_ops(),
+    };
+    let mut multithreaded = 0;
+    let se = unsafe {
+        cuse_lowlevel::cuse_lowlevel_setup(
+            fuse_args.argc(),
+            fuse_args.argv(),
+            &ci,
+            &cuse_ops,
+            &mut multithreaded,
+            node as *const DeviceNode as *mut c_void,
+        )
+    };
+    if se.is_null() {
+        return Err(io::Error::other("the CUSE session could not be set up"));
+    }
+    {
+        let mut sessions = sessions.lock().unwrap();
+        sessions.push(Session(se));
+        if SHUTTING_DOWN.load(Ordering::SeqCst) {
+            unsafe { fuse_lowlevel::fuse_session_exit(se) };
+        }
+    }
+    info!("serving /dev/{}", node.name);
+    let res = unsafe { session_loop(se, multithreaded != 0, threads) };
+    debug!("session of /dev/{} ended ({})", node.name, res);
+    sessions.lock().unwrap().retain(|s| s.0 != se);
+    unsafe { cuse_lowlevel::cuse_lowlevel_teardown(se) };
+    Ok(())
+}
+
+/// The threads block in reading from /dev/cuse. Interrupting the read lets them notice that
+/// the session has been ended. A thread that has not reached the read yet gets another
+/// interrupt after a short while.
+fn interrupt_until_finished<T>(threads: &[JoinHandle<T>]) {
+    while threads.iter().any(|t| !t.is_finished()) {
+        for thread in threads.iter().filter(|t| !t.is_finished()) {
+            unsafe { libc::pthread_kill(thread.as_pthread_t(), libc::SIGUSR1) };
+        }
+        thread::sleep(INTERRUPT_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_device_nodes() {
+        assert_eq!(
+            "name=vuinput-gamepad,policy=strict-gamepad".parse::<DeviceNode>(),
+            Ok(DeviceNode {
+                name: "vuinput-gamepad".to_string(),
+                policy: Some(DevicePolicy::StrictGamepad),
+                kind: NodeKind::Uinput,
+            })
+        );
+        assert_eq!(
+            "name=vuinput-kbd".parse::<DeviceNode>(),
+            Ok(DeviceNode {
+                name: "vuinput-kbd".to_string(),
+                policy: None,
+                kind: NodeKind::Uinput,
+            })
+        );
+        assert_eq!(
+            "name=vuhid,kind=uhid,policy=strict-gamepad".parse::<DeviceNode>(),
+            Ok(DeviceNode {
+                name: "vuhid".to_string(),
+                policy: Some(DevicePolicy::StrictGamepad),
+                kind: NodeKind::Uhid,
+            })
+        );
+        assert!("name=a,kind=hidraw".parse::<DeviceNode>().is_err());
+        assert!("policy=sanitized".parse::<DeviceNode>().is_err());
+        assert!("name=a,policy=gamepad-only".parse::<DeviceNode>().is_err());
+        assert!("name=../uinput".parse::<DeviceNode>().is_err());
+        assert!("name=a,mode=0600".parse::<DeviceNode>().is_err());
+    }
+
+    #[test]
+    fn single_thread_only_on_request() {
+        let args = |threads| {
+            session_args(
+                OsStr::new("vuinputd"),
+                &["allow_other".to_string()],
+                threads,
+            )
+            .unwrap()
+            .iter()
+            .map(|arg| arg.to_str().unwrap().to_string())
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(args(1), ["vuinputd", "-f", "-s", "-o", "allow_other"]);
+        assert_eq!(args(10), ["vuinputd", "-f", "-o", "allow_other"]);
+    }
+
+    #[test]
+    fn display_roundtrips() {
+        for spec in [
+            "name=vuinput-gamepad,policy=strict-gamepad",
+            "name=vuinput-kbd",
+            "name=vuhid,policy=strict-gamepad,kind=uhid",
+        ] {
+            assert_eq!(spec.parse::<DeviceNode>().unwrap().to_string(), spec);
+        }
+    }
+}
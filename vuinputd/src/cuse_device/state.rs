@@ -2,14 +2,27 @@
 //
 // Author: Johannes Leupolz <dev@leupolz.eu>
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs::File;
 use std::ptr::NonNull;
 use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::Instant;
 
 use ::cuse_lowlevel::*;
+use libc::{c_char, uinput_setup};
+use log::error;
 use smallvec::SmallVec;
 
+use crate::control::protocol::{AbsRange, Descriptor, SeatStatus, Setup};
+use crate::cuse_device::device_id::UsbId;
+use crate::cuse_device::device_name::UINPUT_MAX_NAME_SIZE;
+use crate::cuse_device::device_serial::DeviceSerial;
+use crate::cuse_device::drop_counters::{DropCause, DropCounters, DropWindow};
+use crate::cuse_device::op_history::OpHistory;
+use crate::cuse_device::pending_reply::PendingReply;
+use crate::global_config::{DevicePolicy, ReloadableConfig};
+use crate::input_codes::{CodeName, PropName, TypeName};
+use crate::metrics;
 use crate::process_tools::RequestingProcess;
 
 pub type PendingPollHandles = SmallVec<[*mut fuse_lowlevel::fuse_pollhandle; 1]>;
@@ -18,9 +31,56 @@ pub type PendingPollHandles = SmallVec<[*mut fuse_lowlevel::fuse_pollhandle; 1]>
 pub struct VuInputDevice {
     pub major: u64,
     pub minor: u64,
+    /// e.g. input99, what UI_GET_SYSNAME answers while the device exists
+    pub sysname: String,
+    /// SYS_INPUT_DIR followed by sysname
     pub syspath: String,
     pub devname: String,
     pub devnode: String,
+    /// Only for devices in containers, see device_serial
+    pub serial: Option<DeviceSerial>,
+    /// Other containers the device is broadcast to, see broadcast
+    pub mirrors: Vec<RequestingProcess>,
+    /// Set by jobs::verify_seat_job
+    pub seat: Arc<Mutex<Option<SeatStatus>>>,
+    /// The node in the container of the client, which differs from devnode if it was taken
+    /// or with node-naming "container-local". Set by jobs::mknod_device_job.
+    pub container_devnode: Arc<Mutex<Option<String>>>,
+}
+
+/// Lifecycle of the uinput device behind a file handle:
+/// Opened -> Created -> Destroyed -> Created -> ...
+/// UI_DEV_DESTROY and RELEASE both tear a created device down, whoever comes first
+/// takes input_device, so the removal in the container is only triggered once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DeviceLifecycle {
+    #[default]
+    Opened,
+    Created,
+    Destroyed,
+}
+
+impl DeviceLifecycle {
+    /// Moves on to `next`. Any other transition than those above is a bug of vuinputd; it is
+    /// refused and the lifecycle stays as it is.
+    pub fn advance(&mut self, next: DeviceLifecycle) {
+        let allowed = matches!(
+            (*self, next),
+            (
+                DeviceLifecycle::Opened | DeviceLifecycle::Destroyed,
+                DeviceLifecycle::Created
+            ) | (DeviceLifecycle::Created, DeviceLifecycle::Destroyed)
+        );
+        if !allowed {
+            error!(
+                "refused the transition of the device from {:?} to {:?}",
+                self, next
+            );
+            debug_assert!(allowed, "{:?} -> {:?}", self, next);
+            return;
+        }
+        *self = next;
+    }
 }
 
 #[derive(Debug)]
@@ -125,9 +185,11 @@ impl PollState {
         }
     }
     pub fn has_waiters(&self) -> bool {
-        !self.pending.is_some()
+        self.pending.is_some()
     }
 
+    /// The kernel keeps one poll handle per file and wakes all of its pollers with it,
+    /// so a newer handle simply replaces the older one.
     pub fn set_waiter(&mut self, handle: NonNull<fuse_lowlevel::fuse_pollhandle>) {
         self.pending = Some(PollHandle::new(handle));
     }
@@ -147,13 +209,297 @@ impl Drop for PollState {
     }
 }
 
+/// A blocking read() of the client that waits for the host uinput fd to become readable.
+/// It is answered by the evdev write watcher or, if the client gets a signal, by the
+/// interrupt callback. Whoever takes it from the state replies.
+#[derive(Debug)]
+pub struct PendingRead {
+    pub req: fuse_lowlevel::fuse_req_t,
+    pub size: usize,
+}
+
+unsafe impl Send for PendingRead {}
+
+/// The range of an absolute axis, as configured with UI_ABS_SETUP
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AbsInfo {
+    pub value: i32,
+    pub minimum: i32,
+    pub maximum: i32,
+    pub fuzz: i32,
+    pub flat: i32,
+    pub resolution: i32,
+}
+
+impl From<libc::input_absinfo> for AbsInfo {
+    fn from(absinfo: libc::input_absinfo) -> Self {
+        AbsInfo {
+            value: absinfo.value,
+            minimum: absinfo.minimum,
+            maximum: absinfo.maximum,
+            fuzz: absinfo.fuzz,
+            flat: absinfo.flat,
+            resolution: absinfo.resolution,
+        }
+    }
+}
+
+impl std::fmt::Display for AbsInfo {
+    /// e.g. "-32768..32767 (fuzz 16, flat 128)"
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}..{} (fuzz {}, flat {})",
+            self.minimum, self.maximum, self.fuzz, self.flat
+        )?;
+        if self.resolution != 0 {
+            write!(f, " resolution {}", self.resolution)?;
+        }
+        Ok(())
+    }
+}
+
+/// The setup of UI_DEV_SETUP or of a legacy setup, as the id and name policies left it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceSetup {
+    pub bustype: u16,
+    pub vendor: u16,
+    pub product: u16,
+    pub version: u16,
+    pub name: [c_char; UINPUT_MAX_NAME_SIZE],
+    pub ff_effects_max: u32,
+}
+
+impl From<&uinput_setup> for DeviceSetup {
+    fn from(setup: &uinput_setup) -> Self {
+        DeviceSetup {
+            bustype: setup.id.bustype,
+            vendor: setup.id.vendor,
+            product: setup.id.product,
+            version: setup.id.version,
+            name: setup.name,
+            ff_effects_max: setup.ff_effects_max,
+        }
+    }
+}
+
+impl DeviceSetup {
+    pub fn name(&self) -> String {
+        let bytes: Vec<u8> = self
+            .name
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as u8)
+            .collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    pub fn to_uinput_setup(&self) -> uinput_setup {
+        let mut setup: uinput_setup = unsafe { std::mem::zeroed() };
+        setup.id.bustype = self.bustype;
+        setup.id.vendor = self.vendor;
+        setup.id.product = self.product;
+        setup.id.version = self.version;
+        setup.name = self.name;
+        setup.ff_effects_max = self.ff_effects_max;
+        setup
+    }
+}
+
+/// Everything a client has set up on its handle: the capability bits (UI_SET_*BIT) the device
+/// policy let through, the ones it rejected, axis ranges, phys and the setup struct. Enough to
+/// create the same device again (see reconnect) and to show it with vuinputctl devices --json.
+/// Reset on UI_DEV_DESTROY, as the kernel starts with a blank device afterwards.
+#[derive(Debug, Default)]
+pub struct DeviceDescriptor {
+    pub event_types: BTreeSet<u16>,
+    /// codes per event type, e.g. EV_KEY -> {BTN_SOUTH, BTN_EAST}
+    pub codes: BTreeMap<u16, BTreeSet<u16>>,
+    /// ranges of the axes set up with UI_ABS_SETUP, by ABS_* code
+    pub absinfo: BTreeMap<u16, AbsInfo>,
+    /// INPUT_PROP_* set with UI_SET_PROPBIT
+    pub properties: BTreeSet<u16>,
+    /// Bits refused by the device policy, e.g. "UI_SET_KEYBIT KEY_SYSRQ"
+    pub rejected: BTreeSet<String>,
+    /// The id of the setup, if the id policy kept it. The phys gets marked then.
+    pub preserved_id: Option<UsbId>,
+    /// Set with UI_SET_PHYS
+    pub phys: Option<String>,
+    /// Ranges of a legacy setup (struct uinput_user_dev), applied at UI_DEV_CREATE
+    pub legacy_ranges: BTreeMap<u16, AbsInfo>,
+    /// Kept to set the device up again when the host uinput fd fails, see reconnect
+    pub setup: Option<DeviceSetup>,
+}
+
+impl DeviceDescriptor {
+    /// The descriptor as vuinputctl and the state file of persist-devices see it
+    pub fn to_protocol(&self) -> Descriptor {
+        Descriptor {
+            setup: self.setup.map(|setup| Setup {
+                name: setup.name(),
+                bustype: setup.bustype,
+                vendor: setup.vendor,
+                product: setup.product,
+                version: setup.version,
+                ff_effects_max: setup.ff_effects_max,
+            }),
+            phys: self.phys.clone(),
+            event_types: self.event_types.iter().copied().collect(),
+            codes: self
+                .codes
+                .iter()
+                .map(|(type_, codes)| (*type_, codes.iter().copied().collect()))
+                .collect(),
+            absinfo: self
+                .absinfo
+                .iter()
+                .map(|(code, absinfo)| {
+                    let range = AbsRange {
+                        minimum: absinfo.minimum,
+                        maximum: absinfo.maximum,
+                        fuzz: absinfo.fuzz,
+                        flat: absinfo.flat,
+                        resolution: absinfo.resolution,
+                    };
+                    (*code, range)
+                })
+                .collect(),
+            properties: self.properties.iter().copied().collect(),
+            rejected: self.rejected.iter().cloned().collect(),
+        }
+    }
+
+    /// The reverse of to_protocol. The ranges of a legacy setup are part of absinfo there.
+    pub fn from_protocol(descriptor: &Descriptor, preserved_id: bool) -> DeviceDescriptor {
+        let setup = descriptor.setup.as_ref().map(|setup| {
+            let mut name = [0; UINPUT_MAX_NAME_SIZE];
+            for (target, byte) in name
+                .iter_mut()
+                .zip(setup.name.bytes().take(UINPUT_MAX_NAME_SIZE - 1))
+            {
+                *target = byte as c_char;
+            }
+            DeviceSetup {
+                bustype: setup.bustype,
+                vendor: setup.vendor,
+                product: setup.product,
+                version: setup.version,
+                name,
+                ff_effects_max: setup.ff_effects_max,
+            }
+        });
+        DeviceDescriptor {
+            event_types: descriptor.event_types.iter().copied().collect(),
+            codes: descriptor
+                .codes
+                .iter()
+                .map(|(type_, codes)| (*type_, codes.iter().copied().collect()))
+                .collect(),
+            absinfo: descriptor
+                .absinfo
+                .iter()
+                .map(|(code, range)| {
+                    let absinfo = AbsInfo {
+                        value: 0,
+                        minimum: range.minimum,
+                        maximum: range.maximum,
+                        fuzz: range.fuzz,
+                        flat: range.flat,
+                        resolution: range.resolution,
+                    };
+                    (*code, absinfo)
+                })
+                .collect(),
+            properties: descriptor.properties.iter().copied().collect(),
+            rejected: descriptor.rejected.iter().cloned().collect(),
+            preserved_id: match (preserved_id, &setup) {
+                (true, Some(setup)) => Some(UsbId {
+                    vendor: setup.vendor,
+                    product: setup.product,
+                }),
+                _ => None,
+            },
+            phys: descriptor.phys.clone(),
+            legacy_ranges: BTreeMap::new(),
+            setup,
+        }
+    }
+}
+
+impl std::fmt::Display for DeviceDescriptor {
+    /// e.g. "EV_KEY [BTN_SOUTH, BTN_EAST], EV_ABS [ABS_X], INPUT_PROP_POINTER"
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut separator = "";
+        for type_ in &self.event_types {
+            write!(f, "{}{}", separator, TypeName(*type_))?;
+            if let Some(codes) = self.codes.get(type_) {
+                let names: Vec<String> = codes
+                    .iter()
+                    .map(|code| CodeName(*type_, *code).to_string())
+                    .collect();
+                write!(f, " [{}]", names.join(", "))?;
+            }
+            separator = ", ";
+        }
+        for prop in &self.properties {
+            write!(f, "{}{}", separator, PropName(*prop))?;
+            separator = ", ";
+        }
+        if !self.rejected.is_empty() {
+            write!(f, "{}{} rejected", separator, self.rejected.len())?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct VuInputState {
     pub file: File,
     pub requesting_process: RequestingProcess,
     pub input_device: Option<VuInputDevice>,
+    pub lifecycle: DeviceLifecycle,
     pub keytracker: KeyTracker,
     pub poll: PollState,
+    pub pending_read: Option<PendingRead>,
+    /// UI_DEV_CREATE that waits to be retried after a transient error, see vuinput_ioctl
+    pub pending_create: Option<PendingReply>,
+    pub descriptor: DeviceDescriptor,
+    /// Set by vuinputctl set-policy for the container, replaces the configured policy
+    pub policy_override: Option<DevicePolicy>,
+    /// The policy of the first container-policy rule that matched when the handle was opened
+    pub rule_policy: Option<DevicePolicy>,
+    /// The policy of the node the handle has been opened on (--device), if it has one
+    pub node_policy: Option<DevicePolicy>,
+    /// Set by vuinputctl revoke: the device is gone and the handle only answers ENODEV
+    pub revoked: bool,
+    /// Set by vuinputctl label, a name for the operator, e.g. "stream of alice"
+    pub label: Option<String>,
+    /// Events written to uinput and events dropped on the way, for vuinputctl
+    pub events_forwarded: u64,
+    pub drops: DropCounters,
+    pub drop_window: DropWindow,
+    /// ioctls that failed on the host uinput fd (see ioctl_error)
+    pub ioctl_errors: u64,
+    /// Writes to uinput that failed in a row, see reconnect
+    pub write_failures: u32,
+    pub last_reconnect: Option<Instant>,
+    /// The last ioctls and writes, for vuinputctl history
+    pub history: OpHistory,
+}
+
+impl VuInputState {
+    pub fn policy(&self, config: &ReloadableConfig) -> DevicePolicy {
+        self.policy_override
+            .or(self.rule_policy)
+            .or(self.node_policy)
+            .unwrap_or(config.policy)
+    }
+
+    pub fn record_drop(&mut self, cause: DropCause, events: u64) {
+        self.drops.add(cause, events);
+        metrics::record_drop(cause, events);
+        self.drop_window.record(cause, events, Instant::now());
+    }
 }
 
 #[derive(Debug, Eq, Hash, PartialEq, Clone)]
@@ -213,6 +559,25 @@ pub fn remove_vuinput_state(fh: &VuFileHandle) -> Result<Arc<Mutex<VuInputState>
     Ok(old_value)
 }
 
+/// All open handles, ordered by file handle. The states are collected first, so that the
+/// map isn't locked while waiting for a handle that is busy with a request.
+pub fn vuinput_states() -> Vec<(u64, Arc<Mutex<VuInputState>>)> {
+    let Some(map) = VUINPUT_STATE.get() else {
+        return Vec::new();
+    };
+    let mut states: Vec<(u64, Arc<Mutex<VuInputState>>)> = map
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(fh, state)| {
+            let VuFileHandle::Fh(fh) = fh;
+            (*fh, state.clone())
+        })
+        .collect();
+    states.sort_by_key(|(fh, _)| *fh);
+    states
+}
+
 pub fn initialize_vuinput_state() {
     VUINPUT_STATE
         .set(RwLock::new(HashMap::new()))
@@ -235,3 +600,23 @@ pub static VUINPUT_STATE: OnceLock<RwLock<HashMap<VuFileHandle, Arc<Mutex<VuInpu
 
 // For log limiting. Idea: Move to log_limit crate
 pub static DEDUP_LAST_ERROR: OnceLock<Mutex<Option<(u64, VuError)>>> = OnceLock::new();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lifecycle_goes_round() {
+        let mut lifecycle = DeviceLifecycle::default();
+        lifecycle.advance(DeviceLifecycle::Created);
+        lifecycle.advance(DeviceLifecycle::Destroyed);
+        lifecycle.advance(DeviceLifecycle::Created);
+        assert_eq!(lifecycle, DeviceLifecycle::Created);
+    }
+
+    #[test]
+    #[should_panic(expected = "Opened -> Destroyed")]
+    fn lifecycle_refuses_to_skip_the_device() {
+        DeviceLifecycle::Opened.advance(DeviceLifecycle::Destroyed);
+    }
+}
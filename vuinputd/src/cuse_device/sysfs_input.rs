@@ -0,0 +1,70 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// After UI_DEV_CREATE, vuinputd looks up the event node of the new device below
+// /sys/devices/virtual/input. If vuinputd itself runs in a container without the /sys of the
+// host, the kernel creates the device, but vuinputd can't find it and can't hand it to the
+// client. This is checked at startup and by "vuinputd doctor" (VUI-SYS-001).
+
+use nix::sys::statfs::{statfs, SYSFS_MAGIC};
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::cuse_device::vuinput_ioctl::SYS_INPUT_DIR;
+use crate::host_root::host_path;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SysfsStatus {
+    Available,
+    /// The directory does not exist, e.g. /sys is not mounted
+    Missing,
+    /// The directory exists, but is not on a sysfs (e.g. an empty tmpfs or a bind mount)
+    NotSysfs,
+    NotAccessible(String),
+}
+
+pub fn sysfs_status() -> SysfsStatus {
+    let path = host_path(SYS_INPUT_DIR);
+    let path = Path::new(&path);
+    match path.read_dir() {
+        Err(e) if e.kind() == ErrorKind::NotFound => return SysfsStatus::Missing,
+        Err(e) => return SysfsStatus::NotAccessible(e.to_string()),
+        Ok(_) => {}
+    }
+    match statfs(path) {
+        Ok(fs) if fs.filesystem_type() == SYSFS_MAGIC => SysfsStatus::Available,
+        Ok(_) => SysfsStatus::NotSysfs,
+        Err(e) => SysfsStatus::NotAccessible(e.to_string()),
+    }
+}
+
+/// The VUI-SYS-001 message for anything but Available
+pub fn describe(status: &SysfsStatus) -> Option<String> {
+    let problem = match status {
+        SysfsStatus::Available => return None,
+        SysfsStatus::Missing => "does not exist".to_string(),
+        SysfsStatus::NotSysfs => "is not on a sysfs".to_string(),
+        SysfsStatus::NotAccessible(e) => format!("can't be read: {}", e),
+    };
+    Some(format!(
+        "VUI-SYS-001: {} {}. Devices can't be created unless vuinputd sees the /sys of the host",
+        host_path(SYS_INPUT_DIR).trim_end_matches('/'),
+        problem
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_the_problem() {
+        assert_eq!(describe(&SysfsStatus::Available), None);
+        assert_eq!(
+            describe(&SysfsStatus::Missing).unwrap(),
+            "VUI-SYS-001: /sys/devices/virtual/input does not exist. \
+             Devices can't be created unless vuinputd sees the /sys of the host"
+        );
+    }
+}
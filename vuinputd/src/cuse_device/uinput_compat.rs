@@ -0,0 +1,287 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// The uinput of the host, by the version of its interface. vuinputd answers UI_GET_VERSION of
+// clients with 5 and takes the ioctls of that version, but older kernels lack some of them:
+//
+//   version  kernel  brought
+//   4        3.15    UI_GET_SYSNAME
+//   5        4.5     UI_DEV_SETUP, UI_ABS_SETUP, UI_GET_VERSION
+//
+// The version is probed once at startup. UI_GET_VERSION came with version 5 itself, so a kernel
+// that rejects it has version 4 or older. The two are told apart by UI_GET_SYSNAME, which fails
+// with ENOENT on a fresh handle instead of EINVAL.
+//
+// Before version 5 (legacy setup), UI_DEV_SETUP of the client is only recorded and UI_ABS_SETUP
+// only sets the bit of the axis. Right before UI_DEV_CREATE, the recorded setup and ranges are
+// written to the host as a struct uinput_user_dev, the way old clients set up their device. The
+// resolution of the axes has no place in struct uinput_user_dev and gets lost. Before version 4,
+// vuinputd can't find the devices it creates, as it looks them up by UI_GET_SYSNAME.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::OnceLock;
+
+use libc::{c_char, c_int, c_void, uinput_abs_setup, uinput_setup, uinput_user_dev, O_CLOEXEC};
+use libc::{ABS_CNT, O_NONBLOCK};
+use nix::errno::Errno;
+use uinput_ioctls::{ui_abs_setup, ui_dev_setup, ui_get_sysname, ui_get_version, ui_set_absbit};
+
+use crate::cuse_device::ioctl_error::VuIoctlError;
+use crate::cuse_device::state::{AbsInfo, DeviceDescriptor, DeviceSetup};
+
+/// The version vuinputd offers to clients
+pub const UINPUT_VERSION: u32 = 5;
+
+/// The ioctls vuinputd uses that uinput has not always had: the version and the kernel that
+/// brought them
+const FEATURE_MATRIX: [(&str, u32, &str); 4] = [
+    ("UI_GET_SYSNAME", 4, "3.15"),
+    ("UI_DEV_SETUP", 5, "4.5"),
+    ("UI_ABS_SETUP", 5, "4.5"),
+    ("UI_GET_VERSION", 5, "4.5"),
+];
+
+static FEATURES: OnceLock<UinputFeatures> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompatMode {
+    /// The ioctls of the clients go to the host as they are
+    Native,
+    /// The setup goes to the host as a struct uinput_user_dev
+    LegacySetup,
+    /// Created devices can't be found without UI_GET_SYSNAME
+    Unsupported,
+}
+
+impl fmt::Display for CompatMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompatMode::Native => write!(f, "native"),
+            CompatMode::LegacySetup => write!(f, "legacy setup"),
+            CompatMode::Unsupported => write!(f, "unsupported"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UinputFeatures {
+    pub version: u32,
+}
+
+impl UinputFeatures {
+    /// Whether the host knows `ioctl`; those not in FEATURE_MATRIX always exist
+    pub fn has(&self, ioctl: &str) -> bool {
+        FEATURE_MATRIX
+            .iter()
+            .all(|(name, version, _)| *name != ioctl || self.version >= *version)
+    }
+
+    /// The ioctls of FEATURE_MATRIX the host lacks, with the kernel that brought them
+    pub fn missing(&self) -> Vec<String> {
+        FEATURE_MATRIX
+            .iter()
+            .filter(|(name, _, _)| !self.has(name))
+            .map(|(name, _, kernel)| format!("{} (Linux {})", name, kernel))
+            .collect()
+    }
+
+    pub fn mode(&self) -> CompatMode {
+        if !self.has("UI_GET_SYSNAME") {
+            CompatMode::Unsupported
+        } else if !self.has("UI_DEV_SETUP") {
+            CompatMode::LegacySetup
+        } else {
+            CompatMode::Native
+        }
+    }
+}
+
+impl fmt::Display for UinputFeatures {
+    /// e.g. "uinput version 4 (legacy setup, without UI_DEV_SETUP (Linux 4.5), ...)"
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "uinput version {} ({}", self.version, self.mode())?;
+        let missing = self.missing();
+        if !missing.is_empty() {
+            write!(f, ", without {}", missing.join(", "))?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Probes the version of uinput on a fresh handle
+///
+/// # Safety
+/// `fd` must be an open uinput fd that has not been set up yet.
+pub unsafe fn probe(fd: c_int) -> UinputFeatures {
+    let mut version = 0;
+    if ui_get_version(fd, &mut version).is_ok() {
+        return UinputFeatures { version };
+    }
+    let mut sysname: [c_char; 64] = [0; 64];
+    let version = match ui_get_sysname(fd, sysname.as_mut_slice()) {
+        Err(Errno::ENOENT) => 4,
+        _ => 3,
+    };
+    UinputFeatures { version }
+}
+
+/// Opens /dev/uinput of the host and probes it
+pub fn probe_host() -> io::Result<UinputFeatures> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(O_NONBLOCK | O_CLOEXEC)
+        .open("/dev/uinput")?;
+    Ok(unsafe { probe(file.as_raw_fd()) })
+}
+
+/// Sets the features of the host for the lifetime of the daemon
+pub fn initialize(features: UinputFeatures) {
+    let _ = FEATURES.set(features);
+}
+
+/// The features of the host, those of UINPUT_VERSION if it has not been probed
+pub fn features() -> UinputFeatures {
+    FEATURES.get().copied().unwrap_or(UinputFeatures {
+        version: UINPUT_VERSION,
+    })
+}
+
+/// UI_DEV_SETUP on the host, or nothing with a legacy setup, see finish_setup
+///
+/// # Safety
+/// `fd` must be an open uinput fd.
+pub unsafe fn dev_setup(fd: c_int, setup: &uinput_setup) -> Result<(), VuIoctlError> {
+    if features().has("UI_DEV_SETUP") {
+        ui_dev_setup(fd, setup).map_err(VuIoctlError::host("UI_DEV_SETUP"))?;
+    }
+    Ok(())
+}
+
+/// UI_ABS_SETUP on the host. With a legacy setup, the axis is only enabled, like UI_ABS_SETUP
+/// does, and the range goes with finish_setup.
+///
+/// # Safety
+/// `fd` must be an open uinput fd.
+pub unsafe fn abs_setup(fd: c_int, setup: &uinput_abs_setup) -> Result<(), VuIoctlError> {
+    match features().has("UI_ABS_SETUP") {
+        true => ui_abs_setup(fd, setup).map_err(VuIoctlError::host("UI_ABS_SETUP"))?,
+        false => {
+            ui_set_absbit(fd, setup.code.into()).map_err(VuIoctlError::host("UI_SET_ABSBIT"))?
+        }
+    };
+    Ok(())
+}
+
+/// The recorded setup and ranges as struct uinput_user_dev
+pub fn user_dev(setup: &DeviceSetup, absinfo: &BTreeMap<u16, AbsInfo>) -> uinput_user_dev {
+    let mut user_dev: uinput_user_dev = unsafe { std::mem::zeroed() };
+    let usetup = setup.to_uinput_setup();
+    user_dev.name = usetup.name;
+    user_dev.id = usetup.id;
+    user_dev.ff_effects_max = usetup.ff_effects_max;
+    for (code, absinfo) in absinfo.range(..ABS_CNT as u16) {
+        let index = *code as usize;
+        user_dev.absmin[index] = absinfo.minimum;
+        user_dev.absmax[index] = absinfo.maximum;
+        user_dev.absfuzz[index] = absinfo.fuzz;
+        user_dev.absflat[index] = absinfo.flat;
+    }
+    user_dev
+}
+
+/// With a legacy setup, writes the recorded setup to the host right before UI_DEV_CREATE.
+/// Without a setup, nothing is written and UI_DEV_CREATE fails like it would with version 5.
+///
+/// # Safety
+/// `fd` must be an open uinput fd whose device has not been created.
+pub unsafe fn finish_setup(fd: c_int, descriptor: &DeviceDescriptor) -> Result<(), VuIoctlError> {
+    if features().has("UI_DEV_SETUP") {
+        return Ok(());
+    }
+    let Some(setup) = &descriptor.setup else {
+        return Ok(());
+    };
+    let user_dev = user_dev(setup, &descriptor.absinfo);
+    let size = std::mem::size_of::<uinput_user_dev>();
+    if libc::write(
+        fd,
+        &user_dev as *const uinput_user_dev as *const c_void,
+        size,
+    ) < 0
+    {
+        return Err(VuIoctlError::Host {
+            ioctl: "write of struct uinput_user_dev",
+            errno: Errno::last(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_the_mode_by_the_version() {
+        let current = UinputFeatures { version: 5 };
+        assert_eq!(current.mode(), CompatMode::Native);
+        assert!(current.missing().is_empty());
+        assert!(current.has("UI_SET_EVBIT"));
+
+        let old = UinputFeatures { version: 4 };
+        assert_eq!(old.mode(), CompatMode::LegacySetup);
+        assert!(!old.has("UI_ABS_SETUP"));
+        assert!(old.has("UI_GET_SYSNAME"));
+        assert_eq!(
+            old.to_string(),
+            "uinput version 4 (legacy setup, without UI_DEV_SETUP (Linux 4.5), \
+            UI_ABS_SETUP (Linux 4.5), UI_GET_VERSION (Linux 4.5))"
+        );
+        assert_eq!(
+            UinputFeatures { version: 3 }.mode(),
+            CompatMode::Unsupported
+        );
+    }
+
+    #[test]
+    fn writes_the_setup_as_uinput_user_dev() {
+        let mut usetup: uinput_setup = unsafe { std::mem::zeroed() };
+        usetup.id.vendor = 0x045e;
+        usetup.id.product = 0x028e;
+        usetup.name[..4].copy_from_slice(&[b'p' as c_char, b'a' as c_char, b'd' as c_char, 0]);
+        usetup.ff_effects_max = 16;
+        let absinfo = BTreeMap::from([(
+            0x01,
+            AbsInfo {
+                value: 0,
+                minimum: -32768,
+                maximum: 32767,
+                fuzz: 16,
+                flat: 128,
+                resolution: 10,
+            },
+        )]);
+        let user_dev = user_dev(&DeviceSetup::from(&usetup), &absinfo);
+        assert_eq!(user_dev.id.vendor, 0x045e);
+        assert_eq!(user_dev.id.product, 0x028e);
+        assert_eq!(user_dev.name[..3], usetup.name[..3]);
+        assert_eq!(user_dev.ff_effects_max, 16);
+        assert_eq!(
+            (
+                user_dev.absmin[1],
+                user_dev.absmax[1],
+                user_dev.absfuzz[1],
+                user_dev.absflat[1]
+            ),
+            (-32768, 32767, 16, 128)
+        );
+        assert_eq!(user_dev.absmax[0], 0);
+    }
+}
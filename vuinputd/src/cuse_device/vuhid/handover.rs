@@ -0,0 +1,296 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Hands the nodes of the HID devices of vuhid to the containers. The kernel adds a HID device
+// in a worker after UHID_CREATE2, and its driver creates the input devices and the hidraw node
+// when it binds, so vuinputd doesn't learn the nodes from the write. The udev monitor reports
+// every node below /devices/virtual/misc/uhid once udev is done with it instead, and the node
+// goes to the container of the handle that created the HID device: the same MknodDeviceJob and
+// EmitUdevEventJob as for a uinput device, and a RemoveDeviceJob when the client destroys the
+// device or closes the handle.
+//
+// An input device whose capabilities don't pass the policy can't stay on the host either: the
+// reports of the client still reach it, and the sysrq and VT handlers of the host listen to
+// it. The HID device is destroyed instead, through a duplicate of the /dev/uhid fd of the
+// handle, and the handle fails from then on (see is_destroyed).
+//
+// The handle is found by the phys of the HID device, which vuinputd sets to
+// "vuinputd/uhid<fh>/<phys of the client>". The marker also keeps the devices off the seats of
+// the host (see 90-vuinputd-protect.rules). The monitor runs on the thread of the job
+// dispatcher, so the registry has a lock of its own and never waits for a handle.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::Write;
+use std::sync::Mutex;
+
+use libc::{c_int, EIO, ENOSPC};
+use log::{debug, error, warn};
+
+use crate::cuse_device::device_id::{self, PHYS_MARKER};
+use crate::cuse_device::device_limits;
+use crate::cuse_device::vuhid::policy;
+use crate::cuse_device::vuhid::protocol::UHID_DESTROY;
+use crate::global_config::{get_container_runtime, get_max_devices_per_container, DevicePolicy};
+use crate::host_root::host_path;
+use crate::input_realizer::udev_properties::read_capabilities;
+use crate::job_engine::JOB_DISPATCHER;
+use crate::jobs::emit_udev_event_job::EmitUdevEventJob;
+use crate::jobs::mknod_device_job::MknodDeviceJob;
+use crate::jobs::remove_device_job::RemoveDeviceJob;
+use crate::process_tools::RequestingProcess;
+
+const UHID_DEVICES: &str = "/sys/devices/virtual/misc/uhid";
+
+/// A node of a HID device that has been handed to the container
+#[derive(Debug)]
+struct HandedNode {
+    devname: String,
+    syspath: String,
+    major: u64,
+    minor: u64,
+}
+
+#[derive(Debug)]
+struct HidDevice {
+    requesting_process: RequestingProcess,
+    /// Duplicate of the /dev/uhid fd of the handle, to destroy the device
+    uhid: File,
+    policy: DevicePolicy,
+    gamepad_extra_keys: Vec<u16>,
+    nodes: Vec<HandedNode>,
+    /// Destroyed because an input device failed the policy
+    destroyed: bool,
+}
+
+/// The HID devices that clients in containers created, by handle
+static HID_DEVICES: Mutex<BTreeMap<u64, HidDevice>> = Mutex::new(BTreeMap::new());
+
+/// The phys vuinputd gives the HID device of handle `fh`, e.g. "vuinputd/uhid7/usb-1/input0"
+pub fn marked_phys(fh: u64, requested: &str) -> String {
+    if requested.is_empty() {
+        device_id::marked_phys(Some(&format!("uhid{}", fh)))
+    } else {
+        device_id::marked_phys(Some(&format!("uhid{}/{}", fh, requested)))
+    }
+}
+
+/// The handle of a phys set by marked_phys
+fn handle_of_phys(phys: &str) -> Option<u64> {
+    let rest = phys.strip_prefix(PHYS_MARKER)?.strip_prefix("/uhid")?;
+    rest.split('/').next()?.parse().ok()
+}
+
+/// The sysfs directory of the HID device a node belongs to, e.g.
+/// /sys/devices/virtual/misc/uhid/0003:054C:0CE6.0004 for its hidraw node
+fn hid_device_of(syspath: &str) -> Option<String> {
+    let rest = syspath.strip_prefix(UHID_DEVICES)?.strip_prefix('/')?;
+    let (hid, _) = rest.split_once('/')?;
+    Some(format!("{}/{}", UHID_DEVICES, hid))
+}
+
+fn hid_phys(hid_device: &str) -> Option<String> {
+    let uevent = fs::read_to_string(host_path(&format!("{}/uevent", hid_device))).ok()?;
+    uevent
+        .lines()
+        .find_map(|line| line.strip_prefix("HID_PHYS="))
+        .map(str::to_string)
+}
+
+/// Registers the HID device a client in a container is about to create on `uhid`. ENOSPC, if
+/// the container already has limits.max-devices-per-container devices.
+pub fn register(
+    fh: u64,
+    requesting_process: &RequestingProcess,
+    uhid: &File,
+    policy: DevicePolicy,
+    gamepad_extra_keys: Vec<u16>,
+) -> Result<(), c_int> {
+    let uhid = uhid.try_clone().map_err(|e| {
+        error!("fh {}: couldn't duplicate the fd of /dev/uhid: {}", fh, e);
+        e.raw_os_error().unwrap_or(EIO)
+    })?;
+    if !device_limits::try_reserve(
+        &requesting_process.namespaces,
+        get_max_devices_per_container(),
+    ) {
+        warn!(
+            "fh {}: the container reached limits.max-devices-per-container",
+            fh
+        );
+        return Err(ENOSPC);
+    }
+    HID_DEVICES.lock().unwrap().insert(
+        fh,
+        HidDevice {
+            requesting_process: requesting_process.clone(),
+            uhid,
+            policy,
+            gamepad_extra_keys,
+            nodes: Vec::new(),
+            destroyed: false,
+        },
+    );
+    Ok(())
+}
+
+/// Whether the HID device of the handle has been destroyed for a policy violation
+pub fn is_destroyed(fh: u64) -> bool {
+    HID_DEVICES
+        .lock()
+        .unwrap()
+        .get(&fh)
+        .is_some_and(|device| device.destroyed)
+}
+
+fn dispatch_removal(fh: u64, requesting_process: &RequestingProcess, nodes: &[HandedNode]) {
+    let mut dispatcher = JOB_DISPATCHER.get().unwrap().lock().unwrap();
+    for node in nodes {
+        debug!(
+            "fh {}: dispatched cleanup of {} in container",
+            fh, node.devname
+        );
+        dispatcher.dispatch(Box::new(RemoveDeviceJob::new(
+            requesting_process.clone(),
+            node.devname.clone(),
+            node.syspath.clone(),
+            node.major,
+            node.minor,
+        )));
+    }
+}
+
+/// Destroys the HID device on the host and removes the nodes it has in the container. The
+/// registration (and the limit) stays until the handle retires it.
+fn destroy(fh: u64, device: &mut HidDevice) {
+    device.destroyed = true;
+    if let Err(e) = device.uhid.write_all(&UHID_DESTROY.to_ne_bytes()) {
+        error!("fh {}: couldn't destroy the HID device: {}", fh, e);
+    }
+    let nodes = std::mem::take(&mut device.nodes);
+    dispatch_removal(fh, &device.requesting_process, &nodes);
+}
+
+/// Forgets the HID device of the handle and removes its nodes from the container, not awaited.
+/// Returns the number of removed nodes.
+pub fn retire(fh: u64) -> usize {
+    let Some(device) = HID_DEVICES.lock().unwrap().remove(&fh) else {
+        return 0;
+    };
+    device_limits::release(&device.requesting_process.namespaces);
+    dispatch_removal(fh, &device.requesting_process, &device.nodes);
+    device.nodes.len()
+}
+
+/// Retires all HID devices when vuinputd stops, returns the number of removed nodes
+pub fn retire_all() -> usize {
+    let handles: Vec<u64> = HID_DEVICES.lock().unwrap().keys().copied().collect();
+    handles.into_iter().map(retire).sum()
+}
+
+/// Called by the udev monitor for every node that udev has added
+pub fn on_node_added(syspath: &str, properties: &HashMap<String, String>) {
+    let Some(fh) = hid_device_of(syspath)
+        .and_then(|hid_device| hid_phys(&hid_device))
+        .and_then(|phys| handle_of_phys(&phys))
+    else {
+        return;
+    };
+    let (Some(devnode), Some(major), Some(minor)) = (
+        properties.get("DEVNAME"),
+        properties.get("MAJOR").and_then(|major| major.parse().ok()),
+        properties.get("MINOR").and_then(|minor| minor.parse().ok()),
+    ) else {
+        return;
+    };
+    let devname = devnode.rsplit('/').next().unwrap_or(devnode).to_string();
+
+    let mut devices = HID_DEVICES.lock().unwrap();
+    // a client on the host, or the device is gone already
+    let Some(device) = devices.get_mut(&fh).filter(|device| !device.destroyed) else {
+        return;
+    };
+    if devname.starts_with("hidraw") {
+        if !get_container_runtime()
+            .injection_strategy()
+            .places_hidraw_nodes()
+        {
+            debug!(
+                "fh {}: {} stays on the host, the placement does not support hidraw nodes",
+                fh, devnode
+            );
+            return;
+        }
+    } else {
+        let Some(capabilities) = read_capabilities(syspath) else {
+            return;
+        };
+        if let Some(violation) =
+            policy::first_violation(&device.policy, &device.gamepad_extra_keys, &capabilities)
+        {
+            warn!(
+                "fh {}: destroying the HID device, the device policy does not allow {} of its input device {} ({})",
+                fh, violation, devnode, capabilities.name
+            );
+            destroy(fh, device);
+            return;
+        }
+    }
+
+    debug!("fh {}: handing {} to the container", fh, devnode);
+    device.nodes.push(HandedNode {
+        devname: devname.clone(),
+        syspath: syspath.to_string(),
+        major,
+        minor,
+    });
+    let mut dispatcher = JOB_DISPATCHER.get().unwrap().lock().unwrap();
+    // the job logs a failure
+    let mknod_job = MknodDeviceJob::new(
+        device.requesting_process.clone(),
+        devname,
+        syspath.to_string(),
+        major,
+        minor,
+    )
+    .on_completion(Box::new(|_| {}));
+    let emit_udev_event_job = EmitUdevEventJob::new(
+        device.requesting_process.clone(),
+        devnode.clone(),
+        syspath.to_string(),
+        major,
+        minor,
+        None,
+    );
+    dispatcher.dispatch(Box::new(mknod_job));
+    dispatcher.dispatch(Box::new(emit_udev_event_job));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_handle_of_a_node() {
+        let hid = "/sys/devices/virtual/misc/uhid/0003:054C:0CE6.0004";
+        assert_eq!(
+            hid_device_of(&format!("{}/input/input131", hid)).as_deref(),
+            Some(hid)
+        );
+        assert_eq!(
+            hid_device_of(&format!("{}/hidraw/hidraw3", hid)).as_deref(),
+            Some(hid)
+        );
+        assert_eq!(hid_device_of("/sys/devices/virtual/input/input126"), None);
+
+        assert_eq!(handle_of_phys(&marked_phys(7, "")), Some(7));
+        assert_eq!(handle_of_phys(&marked_phys(42, "usb-1/input0")), Some(42));
+        assert_eq!(
+            marked_phys(42, "usb-1/input0"),
+            "vuinputd/uhid42/usb-1/input0"
+        );
+        assert_eq!(handle_of_phys("vuinputd/usb-1/input0"), None);
+        assert_eq!(handle_of_phys("usb-0000:00:14.0-1/input0"), None);
+    }
+}
@@ -0,0 +1,469 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// /dev/vuhid, a CUSE node that speaks the protocol of /dev/uhid for clients that emulate HID
+// devices rather than input devices, e.g. controller emulators that need hid-playstation or
+// hid-nintendo on the other side. Each handle opens /dev/uhid on the host and forwards the
+// events of the client: UHID_CREATE2 once the policy allows the report descriptor (see
+// policy), with a marked phys (see handover), everything else as it is. The events the kernel
+// sends back (UHID_START, UHID_OUTPUT, UHID_GET_REPORT, ...) are read like the force feedback
+// requests of uinput: the host fd is non-blocking, and a blocking read is parked until the
+// evdev write watcher sees the fd readable.
+//
+// If an input device of the HID device fails the policy, handover destroys the HID device and
+// the handle fails with ENODEV from then on, like a revoked uinput handle.
+//
+// The deprecated UHID_CREATE passes the report descriptor as a pointer into the memory of the
+// client, which vuinputd can't follow, so it is refused with EINVAL.
+
+pub mod handover;
+pub mod policy;
+pub mod protocol;
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::{AsFd, AsRawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::ptr::NonNull;
+use std::sync::{Arc, Mutex, RwLock, TryLockError};
+use std::thread;
+
+use ::cuse_lowlevel::*;
+use libc::{c_char, c_int, c_void, off_t, size_t};
+use libc::{EAGAIN, EALREADY, EINTR, EINVAL, EIO, ENODEV, ENOENT, EPERM};
+use libc::{O_CLOEXEC, O_NONBLOCK, POLLIN, POLLOUT, POLLRDNORM, POLLWRNORM};
+use log::{debug, error, info, warn};
+
+use crate::config_file::value_name;
+use crate::cuse_device::container_rules::rule_policy;
+use crate::cuse_device::device_policy::container_policy;
+use crate::cuse_device::evdev_write_watcher::EVDEV_WRITE_WATCHER;
+use crate::cuse_device::session_manager::node_of_request;
+use crate::cuse_device::state::{PendingRead, PollPhase, PollState};
+use crate::cuse_device::vuinput_open::{get_fresh_filehandle, requesting_process_of};
+use crate::cuse_device::vuinput_read::answer_interrupted;
+use crate::cuse_device::{op_sequencer, vuinput_init_done};
+use crate::global_config::{get_reloadable_config, DevicePolicy, ReloadableConfig};
+use crate::process_tools::{RequestingProcess, SELF_NAMESPACES};
+use protocol::{Create2, EVENT_SIZE, UHID_CREATE2, UHID_DESTROY, UHID_LEGACY_CREATE};
+
+pub fn vuhid_make_cuse_ops() -> cuse_lowlevel::cuse_lowlevel_ops {
+    cuse_lowlevel::cuse_lowlevel_ops {
+        init: None,
+        init_done: Some(vuinput_init_done::vuinput_init_done),
+        destroy: None,
+        open: Some(vuhid_open),
+        read: Some(vuhid_read),
+        write: Some(vuhid_write),
+        flush: None,
+        release: Some(vuhid_release),
+        fsync: None,
+        ioctl: None,
+        poll: Some(vuhid_poll),
+    }
+}
+
+#[derive(Debug)]
+pub struct VuHidState {
+    /// /dev/uhid on the host
+    pub file: File,
+    pub requesting_process: RequestingProcess,
+    pub in_container: bool,
+    pub policy_override: Option<DevicePolicy>,
+    pub rule_policy: Option<DevicePolicy>,
+    pub node_policy: Option<DevicePolicy>,
+    /// Whether the client created a HID device that it has not destroyed yet
+    pub created: bool,
+    /// The HID device has been destroyed for a policy violation, see handover
+    pub failed: bool,
+    pub poll: PollState,
+    pub pending_read: Option<PendingRead>,
+}
+
+impl VuHidState {
+    /// Chosen the same way as for a uinput handle
+    fn policy(&self, config: &ReloadableConfig) -> DevicePolicy {
+        self.policy_override
+            .or(self.rule_policy)
+            .or(self.node_policy)
+            .unwrap_or(config.policy)
+    }
+}
+
+static VUHID_STATES: RwLock<BTreeMap<u64, Arc<Mutex<VuHidState>>>> = RwLock::new(BTreeMap::new());
+
+fn get_vuhid_state(fh: u64) -> Option<Arc<Mutex<VuHidState>>> {
+    VUHID_STATES.read().unwrap().get(&fh).cloned()
+}
+
+unsafe extern "C" fn vuhid_open(
+    req: fuse_lowlevel::fuse_req_t,
+    fi: *mut fuse_lowlevel::fuse_file_info,
+) {
+    let fh = get_fresh_filehandle();
+    let requesting_process = requesting_process_of(req, fh);
+    (*fi).fh = fh;
+    let file = match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(O_NONBLOCK | O_CLOEXEC)
+        .open("/dev/uhid")
+    {
+        Ok(file) => file,
+        Err(e) => {
+            error!("couldn't open /dev/uhid: {}", e);
+            fuse_lowlevel::fuse_reply_err(req, ENOENT);
+            return;
+        }
+    };
+    let in_container = !SELF_NAMESPACES
+        .get()
+        .unwrap()
+        .equal_mnt_and_net(&requesting_process.namespaces);
+    let policy_override = container_policy(requesting_process.pid_requestor_root);
+    let rule_policy = rule_policy(
+        &get_reloadable_config().container_rules,
+        requesting_process.identity.as_deref(),
+    );
+    let node_policy = node_of_request(req).and_then(|node| node.policy);
+    let state_mutex = Arc::new(Mutex::new(VuHidState {
+        file,
+        requesting_process,
+        in_container,
+        policy_override,
+        rule_policy,
+        node_policy,
+        created: false,
+        failed: false,
+        poll: PollState::new(),
+        pending_read: None,
+    }));
+    VUHID_STATES
+        .write()
+        .unwrap()
+        .insert(fh, state_mutex.clone());
+    EVDEV_WRITE_WATCHER
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .add_fd(fh, state_mutex.lock().unwrap().file.as_fd())
+        .unwrap();
+    fuse_lowlevel::fuse_reply_open(req, fi);
+}
+
+/// Whether handover destroyed the HID device of the handle, which then fails for good
+fn has_failed(fh: u64, state: &mut VuHidState) -> bool {
+    if state.created && handover::is_destroyed(fh) {
+        state.created = false;
+        state.failed = true;
+        handover::retire(fh);
+    }
+    state.failed
+}
+
+/// Writes an event to /dev/uhid, errors as errno
+fn forward(file: &mut File, event: &[u8]) -> Result<(), c_int> {
+    file.write(event)
+        .map(|_| ())
+        .map_err(|e| e.raw_os_error().unwrap_or(EIO))
+}
+
+/// Creates the HID device of UHID_CREATE2 on the host, if the policy allows it
+fn create(fh: u64, state: &mut VuHidState, buffer: &[u8]) -> Result<(), c_int> {
+    if state.created {
+        // the kernel would say the same
+        return Err(EALREADY);
+    }
+    let mut event = protocol::padded(buffer);
+    let Some(create) = Create2::parse(&event) else {
+        return Err(EINVAL);
+    };
+    let config = get_reloadable_config();
+    let policy = state.policy(&config);
+    let descriptor = &create.report_descriptor;
+    let parsed = protocol::applications(descriptor).and_then(|applications| {
+        protocol::input_usages(descriptor).map(|usages| (applications, usages))
+    });
+    let (applications, usages) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            warn!(
+                "fh {}: refused HID device '{}', its report descriptor is malformed: {}",
+                fh, create.name, e
+            );
+            return Err(EINVAL);
+        }
+    };
+    if let Some(application) = applications
+        .iter()
+        .find(|application| !policy::is_application_allowed(&policy, application))
+    {
+        warn!(
+            "fh {}: refused HID device '{}', device policy {} does not allow the application collection {:04x}:{:04x}",
+            fh,
+            create.name,
+            value_name(&policy),
+            application.page,
+            application.usage
+        );
+        return Err(EPERM);
+    }
+    if let Some((page, usage)) = policy::first_blocked_usage(&policy, &usages) {
+        warn!(
+            "fh {}: refused HID device '{}', device policy {} does not allow the input usage {:04x}:{:04x}",
+            fh,
+            create.name,
+            value_name(&policy),
+            page,
+            usage
+        );
+        return Err(EPERM);
+    }
+    if state.in_container {
+        handover::register(
+            fh,
+            &state.requesting_process,
+            &state.file,
+            policy,
+            config.gamepad_extra_keys.clone(),
+        )
+        .inspect_err(|_| warn!("fh {}: refused HID device '{}'", fh, create.name))?;
+    }
+    protocol::set_phys(&mut event, &handover::marked_phys(fh, &create.phys));
+    if let Err(errno) = forward(&mut state.file, &event) {
+        handover::retire(fh);
+        return Err(errno);
+    }
+    state.created = true;
+    info!(
+        "fh {}: created HID device '{}' ({:04x}:{:04x})",
+        fh, create.name, create.vendor, create.product
+    );
+    Ok(())
+}
+
+unsafe extern "C" fn vuhid_write(
+    req: fuse_lowlevel::fuse_req_t,
+    buf: *const c_char,
+    size: size_t,
+    _off: off_t,
+    fi: *mut fuse_lowlevel::fuse_file_info,
+) {
+    let fh = (*fi).fh;
+    let buffer = std::slice::from_raw_parts(buf as *const u8, size);
+    let _turn = op_sequencer::enter(fh);
+    let state_mutex = get_vuhid_state(fh).unwrap();
+    let mut state = state_mutex.lock().unwrap();
+
+    if has_failed(fh, &mut state) {
+        fuse_lowlevel::fuse_reply_err(req, ENODEV);
+        return;
+    }
+    let result = match protocol::event_type(buffer) {
+        None => Err(EINVAL),
+        Some(UHID_LEGACY_CREATE) => {
+            warn!(
+                "fh {}: refused UHID_CREATE, only UHID_CREATE2 is supported",
+                fh
+            );
+            Err(EINVAL)
+        }
+        Some(UHID_CREATE2) => create(fh, &mut state, buffer),
+        Some(UHID_DESTROY) => {
+            let result = forward(&mut state.file, buffer);
+            if state.created {
+                state.created = false;
+                handover::retire(fh);
+            }
+            result
+        }
+        Some(_) => forward(&mut state.file, buffer),
+    };
+    match result {
+        Ok(()) => {
+            fuse_lowlevel::fuse_reply_write(req, size);
+        }
+        Err(errno) => {
+            fuse_lowlevel::fuse_reply_err(req, errno);
+        }
+    }
+}
+
+/// Whether the host fd has more to read, without waiting
+fn is_readable(file: &File) -> bool {
+    let mut pollfd = libc::pollfd {
+        fd: file.as_raw_fd(),
+        events: POLLIN,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut pollfd, 1, 0) > 0 && pollfd.revents & POLLIN != 0 }
+}
+
+/// Reads a single event, as uhid does, cut to `size`. Errors are returned as errno.
+fn read_event(fh: u64, state: &mut VuHidState, size: usize) -> Result<Vec<u8>, c_int> {
+    let mut buffer = vec![0u8; size.min(EVENT_SIZE)];
+    state.poll.pollphase = PollPhase::Reading;
+    let bytes = match state.file.read(&mut buffer) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+            state.poll.pollphase = PollPhase::Empty;
+            return Err(EAGAIN);
+        }
+        Err(e) => {
+            debug!("fh {}: error reading from uhid: {e:?}", fh);
+            return Err(e.raw_os_error().unwrap_or(EIO));
+        }
+    };
+    if !is_readable(&state.file) {
+        state.poll.pollphase = PollPhase::Empty;
+    }
+    buffer.truncate(bytes);
+    Ok(buffer)
+}
+
+unsafe extern "C" fn vuhid_read(
+    req: fuse_lowlevel::fuse_req_t,
+    size: size_t,
+    _off: off_t,
+    fi: *mut fuse_lowlevel::fuse_file_info,
+) {
+    let fh = (*fi).fh;
+    let nonblocking = (*fi).flags & O_NONBLOCK != 0;
+    let _turn = op_sequencer::enter(fh);
+    if !nonblocking {
+        // before the lock of the handle, see vuinput_read::park_read
+        fuse_lowlevel::fuse_req_interrupt_func(req, Some(interrupt_read), fh as *mut c_void);
+    }
+    let state_mutex = get_vuhid_state(fh).unwrap();
+    let mut state = state_mutex.lock().unwrap();
+
+    if has_failed(fh, &mut state) {
+        fuse_lowlevel::fuse_reply_err(req, ENODEV);
+        return;
+    }
+    match read_event(fh, &mut state, size) {
+        Ok(buffer) => {
+            fuse_lowlevel::fuse_reply_buf(req, buffer.as_ptr() as *const c_char, buffer.len());
+        }
+        Err(EAGAIN) if !nonblocking => park_read(&mut state, req, size),
+        Err(errno) => {
+            fuse_lowlevel::fuse_reply_err(req, errno);
+        }
+    }
+}
+
+/// Keeps a blocking read open until the kernel has an event, see vuinput_read::park_read
+unsafe fn park_read(state: &mut VuHidState, req: fuse_lowlevel::fuse_req_t, size: usize) {
+    if fuse_lowlevel::fuse_req_interrupted(req) != 0 {
+        fuse_lowlevel::fuse_reply_err(req, EINTR);
+        return;
+    }
+    if let Some(previous) = state.pending_read.replace(PendingRead { req, size }) {
+        fuse_lowlevel::fuse_reply_err(previous.req, EIO);
+    }
+}
+
+/// Like vuinput_read::interrupt_read, never waits for the lock of the handle
+unsafe extern "C" fn interrupt_read(req: fuse_lowlevel::fuse_req_t, data: *mut c_void) {
+    let fh = data as u64;
+    let Some(state_mutex) = get_vuhid_state(fh) else {
+        return;
+    };
+    match state_mutex.try_lock() {
+        Ok(mut state) => return answer_interrupted(fh, &mut state.pending_read, req),
+        Err(TryLockError::Poisoned(_)) => return,
+        Err(TryLockError::WouldBlock) => {}
+    }
+    let req = req as usize;
+    thread::spawn(move || {
+        let mut state = state_mutex.lock().unwrap();
+        answer_interrupted(fh, &mut state.pending_read, req as _);
+    });
+}
+
+/// Called by the evdev write watcher once the uhid fd of the handle became readable
+pub fn on_readable(fh: u64) {
+    let Some(state_mutex) = get_vuhid_state(fh) else {
+        return;
+    };
+    let mut state = state_mutex.lock().unwrap();
+    if let Some(mut handle) = state.poll.take_waiters() {
+        handle.notify();
+    }
+    state.poll.pollphase = PollPhase::Readable;
+    if let Some(pending) = state.pending_read.take() {
+        match read_event(fh, &mut state, pending.size) {
+            Ok(buffer) => unsafe {
+                fuse_lowlevel::fuse_reply_buf(
+                    pending.req,
+                    buffer.as_ptr() as *const c_char,
+                    buffer.len(),
+                );
+            },
+            Err(EAGAIN) => state.pending_read = Some(pending),
+            Err(errno) => unsafe {
+                fuse_lowlevel::fuse_reply_err(pending.req, errno);
+            },
+        }
+    }
+}
+
+/// Like uhid, the node is always writable and readable while the kernel has events queued
+unsafe extern "C" fn vuhid_poll(
+    req: fuse_lowlevel::fuse_req_t,
+    fi: *mut fuse_lowlevel::fuse_file_info,
+    ph: *mut fuse_lowlevel::fuse_pollhandle,
+) {
+    let state_mutex = get_vuhid_state((*fi).fh).unwrap();
+    let mut state = state_mutex.lock().unwrap();
+
+    let mut revents = POLLOUT | POLLWRNORM;
+    match state.poll.pollphase {
+        PollPhase::Empty => {
+            if let Some(ph) = NonNull::new(ph) {
+                state.poll.set_waiter(ph);
+            }
+        }
+        PollPhase::Readable | PollPhase::Reading => {
+            revents |= POLLIN | POLLRDNORM;
+            if !ph.is_null() {
+                fuse_lowlevel::fuse_pollhandle_destroy(ph);
+            }
+        }
+    }
+    fuse_lowlevel::fuse_reply_poll(req, revents as u32);
+}
+
+unsafe extern "C" fn vuhid_release(
+    req: fuse_lowlevel::fuse_req_t,
+    fi: *mut fuse_lowlevel::fuse_file_info,
+) {
+    let fh = (*fi).fh;
+    let state_mutex = VUHID_STATES.write().unwrap().remove(&fh).unwrap();
+    op_sequencer::forget(fh);
+
+    let mut state = state_mutex.lock().unwrap();
+    if let Some(pending) = state.pending_read.take() {
+        // a blocking read keeps the file open, so this should not happen
+        fuse_lowlevel::fuse_reply_err(pending.req, EIO);
+    }
+    EVDEV_WRITE_WATCHER
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .remove_device(state.file.as_fd())
+        .unwrap();
+    if state.created {
+        handover::retire(fh);
+    }
+    drop(state);
+    // closing /dev/uhid destroys the HID device on the host
+    drop(state_mutex);
+
+    // see vuinput_release, RELEASE always needs a reply
+    fuse_lowlevel::fuse_reply_err(req, 0);
+}
@@ -0,0 +1,295 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// The device policies for HID devices. vuinputd can't filter the reports of a HID device the
+// way it filters the events of uinput, since only the kernel knows what a report means for
+// the device. The input devices of a HID device exist on the host as well, and the sysrq and
+// VT handlers of the host listen to every device with keys. The policy is therefore applied
+// to the device as a whole:
+//
+//   - at UHID_CREATE2, the application collections of the report descriptor have to fit the
+//     policy, e.g. gamepads and joysticks for strict-gamepad. Vendor-defined collections carry
+//     the feature reports of many controllers and are accepted by every policy.
+//   - also at UHID_CREATE2, no Input item may report a usage that hid-input turns into a key
+//     the policy blocks, wherever it is nested: the keyboard page for the strict policies,
+//     Print Screen (KEY_SYSRQ) for mute-sys-rq, the power keys and Ctrl and Alt for sanitized,
+//     as the combinations of Ctrl+Alt+Fn can't be filtered in reports.
+//   - the input devices the kernel makes of the HID device (one per application collection,
+//     or whatever a specific driver like hid-playstation creates) have to pass the policy, the
+//     same check as for UI_SET_*BIT of uinput. This catches the keys of drivers that don't
+//     follow the descriptor. If one of them fails, the HID device is destroyed (see handover).
+//
+// The hidraw node passes the reports through unfiltered, so it is always handed over. A
+// script can't judge HID devices, the policy "script" refuses them.
+
+use crate::cuse_device::device_policy::{
+    is_code_allowed, is_event_type_allowed, is_property_allowed, EV_ABS, EV_FF, EV_KEY, EV_LED,
+    EV_MSC, EV_REL, EV_SND, EV_SW,
+};
+use crate::cuse_device::vuhid::protocol::{Application, UsageRange};
+use crate::global_config::DevicePolicy;
+use crate::input_codes::{CodeName, PropName, TypeName};
+use crate::input_realizer::udev_properties::InputCapabilities;
+
+const PAGE_GENERIC_DESKTOP: u16 = 0x01;
+const PAGE_KEYBOARD: u16 = 0x07;
+const PAGE_CONSUMER: u16 = 0x0c;
+const PAGE_DIGITIZER: u16 = 0x0d;
+const PAGE_VENDOR_DEFINED: u16 = 0xff00;
+
+const USAGE_JOYSTICK: u16 = 0x04;
+const USAGE_GAMEPAD: u16 = 0x05;
+const USAGE_MULTI_AXIS_CONTROLLER: u16 = 0x08;
+
+const USAGE_DIGITIZER: u16 = 0x01;
+const USAGE_PEN: u16 = 0x02;
+const USAGE_TOUCH_SCREEN: u16 = 0x04;
+const USAGE_TOUCH_PAD: u16 = 0x05;
+/// Switches precision touchpads between mouse and touchpad mode
+const USAGE_DEVICE_CONFIGURATION: u16 = 0x0e;
+
+/// Usages hid-input turns into KEY_SYSRQ: Print Screen of the keyboard page
+const SYSRQ_USAGES: &[(u16, u16, u16)] = &[(PAGE_KEYBOARD, 0x46, 0x46)];
+
+/// Usages hid-input turns into the keys sanitized blocks (KEY_SYSRQ, KEY_PAUSE, KEY_POWER,
+/// KEY_SLEEP, KEY_WAKEUP), and the left and right Ctrl and Alt of the keyboard page
+const SANITIZED_USAGES: &[(u16, u16, u16)] = &[
+    (PAGE_KEYBOARD, 0x46, 0x46),
+    (PAGE_KEYBOARD, 0x48, 0x48),
+    (PAGE_KEYBOARD, 0x66, 0x66),
+    (PAGE_KEYBOARD, 0xe0, 0xe0),
+    (PAGE_KEYBOARD, 0xe2, 0xe2),
+    (PAGE_KEYBOARD, 0xe4, 0xe4),
+    (PAGE_KEYBOARD, 0xe6, 0xe6),
+    (PAGE_GENERIC_DESKTOP, 0x81, 0x83),
+    (PAGE_CONSUMER, 0x30, 0x34),
+];
+
+/// Usages no strict policy allows: all keys of the keyboard page, the system controls of the
+/// generic desktop page (power, sleep, menus) and the power controls of the consumer page
+const STRICT_USAGES: &[(u16, u16, u16)] = &[
+    (PAGE_KEYBOARD, 0x00, 0xffff),
+    (PAGE_GENERIC_DESKTOP, 0x80, 0x9f),
+    (PAGE_CONSUMER, 0x30, 0x35),
+];
+
+/// hid-input reports the usage of every key with MSC_SCAN
+const MSC_SCAN: u16 = 0x04;
+
+/// The capability files of sysfs and their event types
+const CAPABILITY_TYPES: [(&str, u16); 8] = [
+    ("key", EV_KEY),
+    ("rel", EV_REL),
+    ("abs", EV_ABS),
+    ("msc", EV_MSC),
+    ("led", EV_LED),
+    ("snd", EV_SND),
+    ("ff", EV_FF),
+    ("sw", EV_SW),
+];
+
+/// Whether the policy allows a HID device with the application collection
+pub fn is_application_allowed(policy: &DevicePolicy, application: &Application) -> bool {
+    if application.page >= PAGE_VENDOR_DEFINED {
+        return *policy != DevicePolicy::Script;
+    }
+    match policy {
+        DevicePolicy::None | DevicePolicy::MuteSysRq | DevicePolicy::Sanitized => true,
+        DevicePolicy::StrictGamepad => {
+            application.page == PAGE_GENERIC_DESKTOP
+                && matches!(
+                    application.usage,
+                    USAGE_JOYSTICK | USAGE_GAMEPAD | USAGE_MULTI_AXIS_CONTROLLER
+                )
+        }
+        DevicePolicy::StrictTouchpad => {
+            application.page == PAGE_DIGITIZER
+                && matches!(
+                    application.usage,
+                    USAGE_TOUCH_PAD | USAGE_DEVICE_CONFIGURATION
+                )
+        }
+        DevicePolicy::StrictTablet => {
+            application.page == PAGE_DIGITIZER
+                && matches!(
+                    application.usage,
+                    USAGE_DIGITIZER | USAGE_PEN | USAGE_TOUCH_SCREEN
+                )
+        }
+        DevicePolicy::Script => false,
+    }
+}
+
+/// The first usage of the Input items of a report descriptor that the policy blocks, as
+/// (page, usage). None, if the descriptor may be created.
+pub fn first_blocked_usage(policy: &DevicePolicy, usages: &[UsageRange]) -> Option<(u16, u16)> {
+    let blocked = match policy {
+        DevicePolicy::None => return None,
+        // refused at the application collections already
+        DevicePolicy::Script => return usages.first().map(|usage| (usage.page, usage.min)),
+        DevicePolicy::MuteSysRq => SYSRQ_USAGES,
+        DevicePolicy::Sanitized => SANITIZED_USAGES,
+        DevicePolicy::StrictGamepad | DevicePolicy::StrictTouchpad | DevicePolicy::StrictTablet => {
+            STRICT_USAGES
+        }
+    };
+    usages.iter().find_map(|usage| {
+        blocked
+            .iter()
+            .find(|(page, min, max)| usage.page == *page && usage.min <= *max && *min <= usage.max)
+            .map(|(_, min, _)| (usage.page, usage.min.max(*min)))
+    })
+}
+
+/// The first capability of an input device of a HID device that the policy does not allow,
+/// e.g. "KEY_SYSRQ". None, if the device may be handed to the container.
+pub fn first_violation(
+    policy: &DevicePolicy,
+    gamepad_extra_keys: &[u16],
+    device: &InputCapabilities,
+) -> Option<String> {
+    if *policy == DevicePolicy::Script {
+        return Some("the policy script".to_string());
+    }
+    let codes = |file: &str| device.bits.get(file).into_iter().flatten().copied();
+    let only_scan_codes = codes("msc").all(|code| code == MSC_SCAN);
+    // the scan codes come with every key, they don't tell the keys
+    let type_allowed =
+        |type_: u16| is_event_type_allowed(policy, type_) || type_ == EV_MSC && only_scan_codes;
+    let code_allowed = |type_: u16, code: u16| {
+        is_code_allowed(policy, gamepad_extra_keys, type_, code)
+            || type_ == EV_MSC && code == MSC_SCAN
+    };
+    if let Some(type_) = codes("ev").find(|type_| !type_allowed(*type_)) {
+        return Some(TypeName(type_).to_string());
+    }
+    for (file, type_) in CAPABILITY_TYPES {
+        if let Some(code) = codes(file).find(|code| !code_allowed(type_, *code)) {
+            return Some(CodeName(type_, code).to_string());
+        }
+    }
+    device
+        .properties
+        .iter()
+        .find(|prop| !is_property_allowed(policy, **prop))
+        .map(|prop| PropName(*prop).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    fn device(bits: &[(&'static str, &[u16])]) -> InputCapabilities {
+        InputCapabilities {
+            name: "Wireless Controller".to_string(),
+            bits: bits
+                .iter()
+                .map(|(file, codes)| (*file, codes.iter().copied().collect::<BTreeSet<u16>>()))
+                .collect::<BTreeMap<_, _>>(),
+            ..InputCapabilities::default()
+        }
+    }
+
+    #[test]
+    fn strict_policies_take_their_class_of_devices() {
+        let gamepad = Application {
+            page: PAGE_GENERIC_DESKTOP,
+            usage: USAGE_GAMEPAD,
+        };
+        let keyboard = Application {
+            page: PAGE_GENERIC_DESKTOP,
+            usage: 0x06,
+        };
+        let vendor = Application {
+            page: 0xff80,
+            usage: 0x01,
+        };
+        let strict = DevicePolicy::StrictGamepad;
+        assert!(is_application_allowed(&strict, &gamepad));
+        assert!(is_application_allowed(&strict, &vendor));
+        assert!(!is_application_allowed(&strict, &keyboard));
+        assert!(!is_application_allowed(
+            &DevicePolicy::StrictTablet,
+            &gamepad
+        ));
+        assert!(is_application_allowed(&DevicePolicy::MuteSysRq, &keyboard));
+        assert!(!is_application_allowed(&DevicePolicy::Script, &vendor));
+    }
+
+    #[test]
+    fn keys_the_policy_blocks_are_found_in_every_input() {
+        let range = |page, min, max| UsageRange { page, min, max };
+        // Print Screen within the keys a keyboard reports
+        let keyboard = [range(PAGE_KEYBOARD, 0x04, 0x65), range(0x09, 1, 3)];
+        assert_eq!(
+            first_blocked_usage(&DevicePolicy::MuteSysRq, &keyboard),
+            Some((PAGE_KEYBOARD, 0x46))
+        );
+        assert_eq!(first_blocked_usage(&DevicePolicy::None, &keyboard), None);
+        // a key next to the buttons of a gamepad
+        let gamepad = [range(0x09, 1, 14), range(PAGE_KEYBOARD, 0x3a, 0x3a)];
+        assert_eq!(
+            first_blocked_usage(&DevicePolicy::StrictGamepad, &gamepad),
+            Some((PAGE_KEYBOARD, 0x3a))
+        );
+        assert_eq!(
+            first_blocked_usage(&DevicePolicy::MuteSysRq, &gamepad),
+            None
+        );
+        let modifiers = [range(PAGE_KEYBOARD, 0xe0, 0xe7)];
+        assert_eq!(
+            first_blocked_usage(&DevicePolicy::Sanitized, &modifiers),
+            Some((PAGE_KEYBOARD, 0xe0))
+        );
+        assert_eq!(
+            first_blocked_usage(&DevicePolicy::StrictGamepad, &[range(0x09, 1, 14)]),
+            None
+        );
+    }
+
+    #[test]
+    fn input_devices_are_checked_like_uinput_devices() {
+        // a gamepad of hid-input: buttons, sticks and the scan codes of the buttons
+        let gamepad = device(&[
+            ("ev", &[0x00, EV_KEY, EV_ABS, EV_MSC]),
+            ("key", &[0x130, 0x131, 0x13c]),
+            ("abs", &[0x00, 0x01]),
+            ("msc", &[MSC_SCAN]),
+        ]);
+        assert_eq!(
+            first_violation(&DevicePolicy::StrictGamepad, &[], &gamepad),
+            None
+        );
+        let keyboard = device(&[
+            ("ev", &[0x00, EV_KEY, EV_MSC, EV_LED, 0x14]),
+            ("key", &[1, 30, 99]),
+            ("msc", &[MSC_SCAN]),
+            ("led", &[0, 1]),
+        ]);
+        assert_eq!(
+            first_violation(&DevicePolicy::MuteSysRq, &[], &keyboard),
+            Some("KEY_SYSRQ".to_string())
+        );
+        assert_eq!(first_violation(&DevicePolicy::None, &[], &keyboard), None);
+        assert_eq!(
+            first_violation(&DevicePolicy::StrictGamepad, &[], &keyboard),
+            Some("EV_LED".to_string())
+        );
+        // the touchpad of a controller is an input device of its own
+        let touchpad = device(&[
+            ("ev", &[0x00, EV_KEY, EV_ABS]),
+            ("key", &[0x110, 0x14a]),
+            ("abs", &[0x00, 0x01, 0x2f, 0x35, 0x36]),
+        ]);
+        assert_eq!(
+            first_violation(&DevicePolicy::StrictTouchpad, &[], &touchpad),
+            None
+        );
+        assert_eq!(
+            first_violation(&DevicePolicy::StrictGamepad, &[], &touchpad),
+            Some("BTN_LEFT".to_string())
+        );
+    }
+}
@@ -0,0 +1,357 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// The protocol of /dev/uhid, see include/uapi/linux/uhid.h. Every write and every read carries
+// a single struct uhid_event: the type as u32, followed by the request of that type. The struct
+// is packed and holds no pointers, except for the deprecated UHID_CREATE, so 32-bit clients use
+// the same layout. The kernel fills a short write up with zeros.
+
+use std::ops::Range;
+
+pub const UHID_LEGACY_CREATE: u32 = 0;
+pub const UHID_DESTROY: u32 = 1;
+pub const UHID_CREATE2: u32 = 11;
+
+/// HID_MAX_DESCRIPTOR_SIZE, the size of rd_data of struct uhid_create2_req
+pub const HID_MAX_DESCRIPTOR_SIZE: usize = 4096;
+
+// struct uhid_create2_req, from the start of the event
+const NAME: Range<usize> = 4..132;
+const PHYS: Range<usize> = 132..196;
+const RD_SIZE: usize = 260;
+const BUS: usize = 262;
+const VENDOR: usize = 264;
+const PRODUCT: usize = 268;
+const RD_DATA: usize = 280;
+
+/// sizeof(struct uhid_event), the largest request is UHID_CREATE2
+pub const EVENT_SIZE: usize = RD_DATA + HID_MAX_DESCRIPTOR_SIZE;
+
+/// The type of the event, None if the write is too short to hold one
+pub fn event_type(buffer: &[u8]) -> Option<u32> {
+    Some(u32::from_ne_bytes(buffer.get(..4)?.try_into().unwrap()))
+}
+
+/// The event as the kernel reads it from a write of the client
+pub fn padded(buffer: &[u8]) -> Vec<u8> {
+    let mut event = vec![0u8; EVENT_SIZE];
+    let len = buffer.len().min(EVENT_SIZE);
+    event[..len].copy_from_slice(&buffer[..len]);
+    event
+}
+
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+fn u16_at(event: &[u8], offset: usize) -> u16 {
+    u16::from_ne_bytes(event[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(event: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes(event[offset..offset + 4].try_into().unwrap())
+}
+
+/// The parts of UHID_CREATE2 vuinputd looks at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Create2 {
+    pub name: String,
+    pub phys: String,
+    pub bus: u16,
+    pub vendor: u32,
+    pub product: u32,
+    pub report_descriptor: Vec<u8>,
+}
+
+impl Create2 {
+    /// Reads a padded UHID_CREATE2, None if rd_size exceeds rd_data (the kernel refuses it)
+    pub fn parse(event: &[u8]) -> Option<Create2> {
+        let rd_size = u16_at(event, RD_SIZE) as usize;
+        if rd_size > HID_MAX_DESCRIPTOR_SIZE {
+            return None;
+        }
+        Some(Create2 {
+            name: c_string(&event[NAME]),
+            phys: c_string(&event[PHYS]),
+            bus: u16_at(event, BUS),
+            vendor: u32_at(event, VENDOR),
+            product: u32_at(event, PRODUCT),
+            report_descriptor: event[RD_DATA..RD_DATA + rd_size].to_vec(),
+        })
+    }
+}
+
+/// Replaces the phys of a padded UHID_CREATE2, cut to the field at a character boundary
+pub fn set_phys(event: &mut [u8], phys: &str) {
+    let mut len = phys.len().min(PHYS.len() - 1);
+    while !phys.is_char_boundary(len) {
+        len -= 1;
+    }
+    let field = &mut event[PHYS];
+    field.fill(0);
+    field[..len].copy_from_slice(&phys.as_bytes()[..len]);
+}
+
+/// A collection at the top level of a report descriptor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Application {
+    pub page: u16,
+    pub usage: u16,
+}
+
+/// Usages an Input item reports, from Usage (min == max) or Usage Minimum and Maximum
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsageRange {
+    pub page: u16,
+    pub min: u16,
+    pub max: u16,
+}
+
+/// The short items of a report descriptor as (type, tag, size, data), long items are skipped.
+/// Err if an item runs past the end of the descriptor.
+fn items(descriptor: &[u8]) -> Result<Vec<(u8, u8, usize, u32)>, String> {
+    let mut items = Vec::new();
+    let mut i = 0;
+    while i < descriptor.len() {
+        let prefix = descriptor[i];
+        if prefix == 0xfe {
+            // long item: size, tag and data, not used by any defined item
+            let size = *descriptor
+                .get(i + 1)
+                .ok_or_else(|| format!("long item at {} is cut off", i))?;
+            i += 3 + size as usize;
+            continue;
+        }
+        let size = match prefix & 0x03 {
+            3 => 4,
+            size => size as usize,
+        };
+        let data = descriptor
+            .get(i + 1..i + 1 + size)
+            .ok_or_else(|| format!("item {:#04x} at {} is cut off", prefix, i))?;
+        let value = data
+            .iter()
+            .rev()
+            .fold(0u32, |value, byte| value << 8 | *byte as u32);
+        items.push(((prefix >> 2) & 0x03, prefix >> 4, size, value));
+        i += 1 + size;
+    }
+    Ok(items)
+}
+
+/// Walks the items of a report descriptor and calls `main` for every main item with its tag,
+/// data, the current usage page and the usages (local items) that belong to it. Usages without a page
+/// of their own get the page that is current at the main item, like hid-core does it.
+fn walk(
+    descriptor: &[u8],
+    mut main: impl FnMut(u8, u32, u16, &[UsageRange]),
+) -> Result<(), String> {
+    let mut page: u16 = 0;
+    // Push and Pop of the global items, only the usage page matters here
+    let mut pages = Vec::new();
+    // the usages of the next main item, with their page if they name one
+    let mut usages: Vec<(Option<u16>, u16, u16)> = Vec::new();
+    let mut minimum: Option<(Option<u16>, u16)> = None;
+    // a 32-bit usage names its page itself
+    let split = |size: usize, value: u32| match size {
+        4 => (Some((value >> 16) as u16), value as u16),
+        _ => (None, value as u16),
+    };
+    for (type_, tag, size, value) in items(descriptor)? {
+        match (type_, tag) {
+            // Usage Page, Push, Pop
+            (1, 0x0) => page = value as u16,
+            (1, 0xa) => pages.push(page),
+            (1, 0xb) => page = pages.pop().unwrap_or(page),
+            // Usage, Usage Minimum, Usage Maximum
+            (2, 0x0) => {
+                let (usage_page, usage) = split(size, value);
+                usages.push((usage_page, usage, usage));
+            }
+            (2, 0x1) => minimum = Some(split(size, value)),
+            (2, 0x2) => {
+                if let Some((usage_page, min)) = minimum.take() {
+                    let (_, max) = split(size, value);
+                    usages.push((usage_page, min, max.max(min)));
+                }
+            }
+            (0, tag) => {
+                let resolved: Vec<UsageRange> = usages
+                    .iter()
+                    .map(|(usage_page, min, max)| UsageRange {
+                        page: usage_page.unwrap_or(page),
+                        min: *min,
+                        max: *max,
+                    })
+                    .collect();
+                main(tag, value, page, &resolved);
+                // the local items only last until the next main item
+                usages.clear();
+                minimum = None;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// The application collections at the top level of a report descriptor, in the order they
+/// are declared. Each of them becomes a device of its own for hid-input. Err if an item runs
+/// past the end of the descriptor.
+pub fn applications(descriptor: &[u8]) -> Result<Vec<Application>, String> {
+    let mut applications = Vec::new();
+    let mut depth = 0usize;
+    walk(descriptor, |tag, value, page, usages| match tag {
+        // Collection, 1 is Application
+        0xa => {
+            if depth == 0 && value == 1 {
+                applications.push(match usages.first() {
+                    Some(usage) => Application {
+                        page: usage.page,
+                        usage: usage.min,
+                    },
+                    None => Application { page, usage: 0 },
+                });
+            }
+            depth += 1;
+        }
+        // End Collection
+        0xc => depth = depth.saturating_sub(1),
+        _ => {}
+    })?;
+    Ok(applications)
+}
+
+/// The usages of all Input items of a report descriptor, wherever they are nested. hid-input
+/// maps these usages to the codes of the input devices, e.g. the keyboard usage 0x46 to
+/// KEY_SYSRQ, even inside a gamepad collection.
+pub fn input_usages(descriptor: &[u8]) -> Result<Vec<UsageRange>, String> {
+    let mut input_usages = Vec::new();
+    walk(descriptor, |tag, _, _, usages| {
+        // Input
+        if tag == 0x8 {
+            input_usages.extend_from_slice(usages);
+        }
+    })?;
+    Ok(input_usages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The report descriptor of a gamepad with a vendor-defined collection for feature
+    /// reports, shortened
+    const GAMEPAD: &[u8] = &[
+        0x05, 0x01, // Usage Page (Generic Desktop)
+        0x09, 0x05, // Usage (Gamepad)
+        0xa1, 0x01, // Collection (Application)
+        0x85, 0x01, //   Report ID (1)
+        0x09, 0x30, //   Usage (X)
+        0xa1, 0x00, //   Collection (Physical)
+        0x09, 0x01, //     Usage (Pointer)
+        0xc0, //         End Collection
+        0x05, 0x09, //   Usage Page (Button)
+        0x19, 0x01, //   Usage Minimum (1)
+        0x29, 0x0e, //   Usage Maximum (14)
+        0x81, 0x02, //   Input (Data,Var,Abs)
+        0xc0, //       End Collection
+        0x06, 0x00, 0xff, // Usage Page (Vendor Defined 0xFF00)
+        0x09, 0x20, // Usage (0x20)
+        0xa1, 0x01, // Collection (Application)
+        0xb1, 0x02, //   Feature (Data,Var,Abs)
+        0xc0, //       End Collection
+        0x0b, 0x06, 0x00, 0x01, 0x00, // Usage (Generic Desktop, Keyboard)
+        0xa1, 0x01, // Collection (Application)
+        0xc0, //       End Collection
+    ];
+
+    #[test]
+    fn finds_the_top_level_applications() {
+        assert_eq!(
+            applications(GAMEPAD),
+            Ok(vec![
+                Application {
+                    page: 0x01,
+                    usage: 0x05
+                },
+                Application {
+                    page: 0xff00,
+                    usage: 0x20
+                },
+                Application {
+                    page: 0x01,
+                    usage: 0x06
+                },
+            ])
+        );
+        assert!(applications(&GAMEPAD[..GAMEPAD.len() - 3]).is_ok());
+        assert!(applications(&[0x06, 0x00]).is_err());
+    }
+
+    #[test]
+    fn finds_the_usages_of_the_inputs() {
+        assert_eq!(
+            input_usages(GAMEPAD),
+            Ok(vec![UsageRange {
+                page: 0x09,
+                min: 1,
+                max: 14
+            }])
+        );
+        // Print Screen of the keyboard page, hidden in a gamepad collection
+        let hidden = [
+            0x05, 0x01, 0x09, 0x05, 0xa1, 0x01, 0x09, 0x30, 0x05, 0x07, 0x81, 0x02, 0x0b, 0x46,
+            0x00, 0x07, 0x00, 0x81, 0x02, 0xc0,
+        ];
+        assert_eq!(
+            input_usages(&hidden),
+            Ok(vec![
+                UsageRange {
+                    page: 0x07,
+                    min: 0x30,
+                    max: 0x30
+                },
+                UsageRange {
+                    page: 0x07,
+                    min: 0x46,
+                    max: 0x46
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn reads_create2_as_the_kernel_does() {
+        let mut event = vec![0u8; 300];
+        event[..4].copy_from_slice(&UHID_CREATE2.to_ne_bytes());
+        event[NAME.start..NAME.start + 7].copy_from_slice(b"Pad 1\0x");
+        event[PHYS.start..PHYS.start + 4].copy_from_slice(b"usb1");
+        event[RD_SIZE..RD_SIZE + 2].copy_from_slice(&4u16.to_ne_bytes());
+        event[BUS..BUS + 2].copy_from_slice(&3u16.to_ne_bytes());
+        event[VENDOR..VENDOR + 4].copy_from_slice(&0x054cu32.to_ne_bytes());
+        event[RD_DATA..RD_DATA + 4].copy_from_slice(&GAMEPAD[..4]);
+
+        assert_eq!(event_type(&event), Some(UHID_CREATE2));
+        assert_eq!(event_type(&event[..3]), None);
+        let mut event = padded(&event);
+        assert_eq!(event.len(), EVENT_SIZE);
+        let create = Create2::parse(&event).unwrap();
+        assert_eq!(
+            (create.name.as_str(), create.phys.as_str(), create.bus),
+            ("Pad 1", "usb1", 3)
+        );
+        assert_eq!((create.vendor, create.product), (0x054c, 0));
+        assert_eq!(create.report_descriptor, &GAMEPAD[..4]);
+
+        // 16 bytes and then two per character, the field holds 63
+        set_phys(&mut event, &format!("vuinputd/uhid7/x{}", "ä".repeat(40)));
+        let phys = Create2::parse(&event).unwrap().phys;
+        assert_eq!((phys.len(), phys.chars().last()), (62, Some('ä')));
+
+        event[RD_SIZE..RD_SIZE + 2].copy_from_slice(&4097u16.to_ne_bytes());
+        assert_eq!(Create2::parse(&event), None);
+    }
+}
@@ -0,0 +1,72 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+use crate::cuse_device::session_manager::node_of_userdata;
+use crate::global_config::{get_cuse_node_permissions, get_vudevname, CuseNodePermissions};
+use crate::sd_daemon;
+use crate::signal_handling::record_shutdown_signals;
+use log::{error, info};
+use std::fs;
+use std::io;
+use std::os::raw::c_void;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::Duration;
+
+const NODE_WAIT_RETRIES: u32 = 20;
+const NODE_WAIT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Called by libfuse after the CUSE_INIT reply has been sent, i.e. after the kernel
+/// registered the character device. devtmpfs creates the node as root:root 0600,
+/// so this is the place to apply the mode and ownership requested on the command line.
+/// libfuse has installed its signal handlers by now, so they can be wrapped here as well.
+/// With several nodes (--device), the session manager takes care of the signals instead.
+///
+/// # Safety
+/// Only to be called by libfuse: `userdata` must be the userdata of the session, i.e. null
+/// or a DeviceNode leaked by run_sessions, see node_of_userdata.
+pub unsafe extern "C" fn vuinput_init_done(userdata: *mut c_void) {
+    let node = node_of_userdata(userdata);
+    if node.is_none() {
+        record_shutdown_signals();
+    }
+
+    let devname = node.map_or_else(get_vudevname, |node| &node.name);
+    let permissions = get_cuse_node_permissions();
+    if permissions.is_unset() {
+        sd_daemon::node_ready(devname);
+        return;
+    }
+
+    let node = format!("/dev/{}", devname);
+    match apply_cuse_node_permissions(Path::new(&node), permissions) {
+        Ok(()) => info!("applied permissions {:?} to {}", permissions, node),
+        Err(e) => error!("failed to apply permissions to {}: {}", node, e),
+    }
+    // clients started after vuinputd must find the node with its final permissions
+    sd_daemon::node_ready(devname);
+}
+
+fn apply_cuse_node_permissions(node: &Path, permissions: &CuseNodePermissions) -> io::Result<()> {
+    // the node is usually there already, but give devtmpfs some time if it is not
+    let mut retries = 0;
+    while !node.exists() {
+        if retries >= NODE_WAIT_RETRIES {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "device node did not appear",
+            ));
+        }
+        retries += 1;
+        std::thread::sleep(NODE_WAIT_INTERVAL);
+    }
+
+    if permissions.uid.is_some() || permissions.gid.is_some() {
+        std::os::unix::fs::chown(node, permissions.uid, permissions.gid)?;
+    }
+    if let Some(mode) = permissions.mode {
+        fs::set_permissions(node, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
@@ -3,15 +3,43 @@
 // Author: Johannes Leupolz <dev@leupolz.eu>
 
 use ::cuse_lowlevel::*;
-use libc::{EBADRQC, input_absinfo, iovec, size_t};
-use libc::{uinput_abs_setup, uinput_ff_erase, uinput_ff_upload, uinput_setup};
-use log::debug;
-use std::ffi::CStr;
+use async_io::Timer;
+use libc::{iovec, size_t, E2BIG, EBADF, EBADRQC, ECANCELED, EINVAL, EIO, ENODEV, ENOSPC, EPERM};
+use libc::{uinput_abs_setup, uinput_ff_erase, uinput_setup};
+use log::{debug, error, warn};
+use nix::errno::Errno;
+use std::ffi::{CStr, CString};
 use std::os::fd::AsRawFd;
 use std::os::raw::{c_char, c_int, c_uint, c_void};
 use uinput_ioctls::*;
 
+use crate::config_file::value_name;
+use crate::control::events;
+use crate::cuse_device::device_id::{apply_id_policy, marked_phys};
+use crate::cuse_device::device_name::{apply_name_policy, NameRules};
+use crate::cuse_device::device_policy::{
+    self, EV_ABS, EV_FF, EV_KEY, EV_LED, EV_MSC, EV_REL, EV_SND, EV_SW,
+};
+use crate::cuse_device::diagnostic_ioctl::{
+    allowed_bits_type, copy_str, VuinputInfo, ALLOWED_BITS_MAX_LEN, LIFECYCLE_CREATED,
+    LIFECYCLE_DESTROYED, LIFECYCLE_OPENED, VUI_GET_INFO,
+};
+use crate::cuse_device::ioctl_error::{create_backoff, is_transient, require_buffer, VuIoctlError};
+use crate::cuse_device::pending_reply::PendingReply;
+use crate::cuse_device::protocol_dump::dump_ioctl_buffer;
+use crate::cuse_device::uinput_compat;
+use crate::cuse_device::{
+    approval, compat_ioctl, device_limits, device_serial, legacy_setup, persistence, sysfs_input,
+};
 use crate::cuse_device::{get_vuinput_state, VuFileHandle};
+use crate::fault_injection;
+use crate::global_config::{
+    get_create_retries, get_max_devices_per_container, get_reloadable_config, DevicePolicy,
+};
+use crate::host_root::host_path;
+use crate::input_codes::{CodeName, PropName, TypeName};
+use crate::job_engine::closure_job::job;
+use crate::job_engine::job::JobTarget;
 use crate::job_engine::JOB_DISPATCHER;
 use crate::jobs::emit_udev_event_job::EmitUdevEventJob;
 use crate::jobs::mknod_device_job::MknodDeviceJob;
@@ -39,32 +67,58 @@ pub unsafe extern "C" fn vuinput_ioctl(
     // https://docs.rs/linux-raw-sys/0.11.0/src/linux_raw_sys/x86_64/ioctl.rs.html#529
 
     let cmd_u64 = (_cmd as c_uint).into();
-    // normalize the variable length ones
+    // normalize the variable length ones and the ones of 32-bit clients
     let cmd_without_size = cmd_u64 & !(nix::sys::ioctl::SIZEMASK << nix::sys::ioctl::SIZESHIFT);
+    let (cmd_native, compat) = compat_ioctl::native_cmd(cmd_u64);
     let cmd_normalized = match cmd_without_size {
         UI_GET_SYSNAME_WITHOUT_SIZE => UI_GET_SYSNAME_WITHOUT_SIZE,
-        //UI_ABS_SETUP => UI_ABS_SETUP_WITHOUT_SIZE,
-        _ => cmd_u64,
+        UI_ABS_SETUP_WITHOUT_SIZE => UI_ABS_SETUP_WITHOUT_SIZE,
+        _ => cmd_native,
     };
     let vufh = VuFileHandle::from_fuse_file_info(_fi.as_ref().unwrap());
-    let vuinput_state_mutex = get_vuinput_state(&vufh).unwrap();
+    let vuinput_state_mutex = match get_vuinput_state(&vufh) {
+        Ok(vuinput_state_mutex) => vuinput_state_mutex,
+        Err(e) => {
+            error!("{}: ioctl on an unknown handle ({})", vufh, e);
+            fuse_lowlevel::fuse_reply_err(_req, EBADF);
+            return;
+        }
+    };
     let fh = &(*_fi).fh;
+    // before the lock of the handle, see op_sequencer
+    let _turn = op_sequencer::enter(*fh);
     let mut vuinput_state = vuinput_state_mutex.lock().unwrap();
 
+    if vuinput_state.revoked {
+        debug!("fh {}: ioctl on a revoked handle", fh);
+        fuse_lowlevel::fuse_reply_err(_req, ENODEV);
+        return;
+    }
+
     // ensure for all ioctls that need mapped data, that we have the data correctly mapped
     match (_in_bufsz, _out_bufsz, cmd_normalized) {
-        (0, _, UI_ABS_SETUP) => {
-            //todo: i guess this needs to be reworked as this is variable size. i guess it is not reachable at all
-            debug!("fh {}: submitting _in_bufsz for UI_ABS_SETUP", fh);
+        (0, _, UI_ABS_SETUP_WITHOUT_SIZE) => {
+            // The kernel accepts shorter structs than its own and fills up the rest with zeros.
+            let size = ioctl_size(cmd_u64);
+            debug!("fh {}: submitting _in_bufsz for UI_ABS_SETUP({})", fh, size);
+            if size > ::std::mem::size_of::<uinput_abs_setup>() {
+                fuse_lowlevel::fuse_reply_err(_req, E2BIG);
+                return;
+            }
+            if size == 0 {
+                // a retry without a buffer would be sent again and again
+                fuse_lowlevel::fuse_reply_err(_req, EINVAL);
+                return;
+            }
             let iov = iovec {
                 iov_base: _arg,
-                iov_len: ::std::mem::size_of::<uinput_abs_setup>(),
+                iov_len: size,
             };
             fuse_lowlevel::fuse_reply_ioctl_retry(_req, &iov, 1, std::ptr::null(), 0);
             return;
         }
         (_, 0, UI_GET_SYSNAME_WITHOUT_SIZE) => {
-            let size = (cmd_u64 & nix::sys::ioctl::SIZEMASK) >> nix::sys::ioctl::SIZESHIFT;
+            let size = ioctl_size(cmd_u64);
             debug!(
                 "fh {}: submitting _out_bufsz for UI_GET_SYSNAME({}) ",
                 fh, size
@@ -77,7 +131,7 @@ pub unsafe extern "C" fn vuinput_ioctl(
             return;
         }
         (_, 0, UI_GET_VERSION) => {
-            let size = (cmd_u64 & nix::sys::ioctl::SIZEMASK) >> nix::sys::ioctl::SIZESHIFT;
+            let size = ioctl_size(cmd_u64);
             debug!(
                 "fh {}: submitting _out_bufsz for UI_GET_VERSION({}) ",
                 fh, size
@@ -89,6 +143,32 @@ pub unsafe extern "C" fn vuinput_ioctl(
             fuse_lowlevel::fuse_reply_ioctl_retry(_req, std::ptr::null(), 0, &iov, 1);
             return;
         }
+        (_, 0, VUI_GET_INFO) => {
+            debug!("fh {}: submitting _out_bufsz for VUI_GET_INFO", fh);
+            let iov = iovec {
+                iov_base: _arg,
+                iov_len: std::mem::size_of::<VuinputInfo>(),
+            };
+            fuse_lowlevel::fuse_reply_ioctl_retry(_req, std::ptr::null(), 0, &iov, 1);
+            return;
+        }
+        (_, 0, cmd) if allowed_bits_type(cmd).is_some() => {
+            let size = ioctl_size(cmd_u64).min(ALLOWED_BITS_MAX_LEN);
+            debug!(
+                "fh {}: submitting _out_bufsz for VUI_GET_ALLOWED_BITS({})",
+                fh, size
+            );
+            if size == 0 {
+                fuse_lowlevel::fuse_reply_ioctl(_req, 0, std::ptr::null(), 0);
+                return;
+            }
+            let iov = iovec {
+                iov_base: _arg,
+                iov_len: size,
+            };
+            fuse_lowlevel::fuse_reply_ioctl_retry(_req, std::ptr::null(), 0, &iov, 1);
+            return;
+        }
         (0, _, UI_DEV_SETUP) => {
             debug!("fh {}: submitting _in_bufsz for UI_DEV_SETUP", fh);
             let iov = iovec {
@@ -108,19 +188,26 @@ pub unsafe extern "C" fn vuinput_ioctl(
             return;
         }
         (0, _, UI_BEGIN_FF_UPLOAD) => {
-            debug!("fh {}: submitting _in_bufsz for UI_BEGIN_FF_UPLOAD", fh);
+            debug!(
+                "fh {}: submitting _in_bufsz for UI_BEGIN_FF_UPLOAD (compat {})",
+                fh, compat
+            );
+            // the size of struct uinput_ff_upload of the client
             let iov = iovec {
                 iov_base: _arg,
-                iov_len: ::std::mem::size_of::<uinput_ff_upload>(),
+                iov_len: ioctl_size(cmd_u64),
             };
             fuse_lowlevel::fuse_reply_ioctl_retry(_req, &iov, 1, &iov, 1);
             return;
         }
         (0, _, UI_END_FF_UPLOAD) => {
-            debug!("fh {}: submitting _in_bufsz for UI_END_FF_UPLOAD", fh);
+            debug!(
+                "fh {}: submitting _in_bufsz for UI_END_FF_UPLOAD (compat {})",
+                fh, compat
+            );
             let iov = iovec {
                 iov_base: _arg,
-                iov_len: ::std::mem::size_of::<uinput_ff_upload>(),
+                iov_len: ioctl_size(cmd_u64),
             };
             fuse_lowlevel::fuse_reply_ioctl_retry(_req, &iov, 1, std::ptr::null(), 0);
             return;
@@ -148,65 +235,329 @@ pub unsafe extern "C" fn vuinput_ioctl(
         }
     }
 
-    let fd = vuinput_state.file.as_raw_fd();
-
+    let call = IoctlCall {
+        fh: *fh,
+        cmd: cmd_normalized,
+        compat,
+        arg: _arg,
+        in_buf: _in_buf,
+        in_bufsz: _in_bufsz,
+        out_bufsz: _out_bufsz,
+        approved: false,
+        create_attempt: 0,
+    };
     // now we can assume that the data is mapped or it is not required
-    match cmd_normalized {
-        UI_DEV_CREATE => {
-            debug!("fh {}: ioctl UI_DEV_CREATE", fh);
-            ui_dev_create(fd).unwrap();
+    let name = ioctl_name(cmd_normalized);
+    // UI_DEV_CREATE is failed in create_device, so that it is retried as well
+    let injected = match cmd_normalized {
+        UI_DEV_CREATE => Ok(()),
+        _ => fault_injection::fail(name).map_err(VuIoctlError::host(name)),
+    };
+    let result = injected.and_then(|()| forward_ioctl(_req, &call, &mut vuinput_state));
+    let errno = result.as_ref().err().map(VuIoctlError::errno);
+    vuinput_state
+        .history
+        .record_ioctl(name, cmd_u64, _in_bufsz, _out_bufsz, errno);
+    if let Err(error) = result {
+        vuinput_state.ioctl_errors += 1;
+        warn!(
+            "fh {}: {} (errno {}, {} errors on this handle)",
+            fh,
+            error,
+            error.errno(),
+            vuinput_state.ioctl_errors
+        );
+        vuinput_state.history.log_on_first_failure(*fh);
+        fuse_lowlevel::fuse_reply_err(_req, error.errno());
+    }
+}
 
-            let mut resultbuf: [c_char; 64] = [0; 64];
-            ui_get_sysname(fd, resultbuf.as_mut_slice()).unwrap();
-            let sysname = format!(
-                "{}{}",
-                SYS_INPUT_DIR,
-                CStr::from_ptr(resultbuf.as_ptr()).to_string_lossy()
-            );
-            debug!("fh {}: syspath: {}", fh, sysname);
-            let (devname, devnode) = fetch_device_node(&sysname).unwrap();
-            debug!("fh {}: devnode: {}", fh, devnode);
-            let (major, minor) = fetch_major_minor(&devnode).unwrap();
-            debug!("fh {}: major: {} minor: {} ", fh, major, minor);
-            vuinput_state.input_device = Some(VuInputDevice {
-                major: major,
-                minor: minor,
-                syspath: sysname.clone(),
-                devname: devname.clone(),
-                devnode: devnode.clone(),
-            });
+/// The name of a normalized ioctl command, for the history of the handle
+fn ioctl_name(cmd: u64) -> &'static str {
+    match cmd {
+        UI_DEV_CREATE => "UI_DEV_CREATE",
+        UI_DEV_DESTROY => "UI_DEV_DESTROY",
+        UI_DEV_SETUP => "UI_DEV_SETUP",
+        UI_ABS_SETUP_WITHOUT_SIZE => "UI_ABS_SETUP",
+        UI_GET_SYSNAME_WITHOUT_SIZE => "UI_GET_SYSNAME",
+        UI_GET_VERSION => "UI_GET_VERSION",
+        UI_SET_EVBIT => "UI_SET_EVBIT",
+        UI_SET_KEYBIT => "UI_SET_KEYBIT",
+        UI_SET_RELBIT => "UI_SET_RELBIT",
+        UI_SET_ABSBIT => "UI_SET_ABSBIT",
+        UI_SET_MSCBIT => "UI_SET_MSCBIT",
+        UI_SET_LEDBIT => "UI_SET_LEDBIT",
+        UI_SET_SNDBIT => "UI_SET_SNDBIT",
+        UI_SET_FFBIT => "UI_SET_FFBIT",
+        UI_SET_PHYS => "UI_SET_PHYS",
+        UI_SET_SWBIT => "UI_SET_SWBIT",
+        UI_SET_PROPBIT => "UI_SET_PROPBIT",
+        UI_BEGIN_FF_UPLOAD => "UI_BEGIN_FF_UPLOAD",
+        UI_END_FF_UPLOAD => "UI_END_FF_UPLOAD",
+        UI_BEGIN_FF_ERASE => "UI_BEGIN_FF_ERASE",
+        UI_END_FF_ERASE => "UI_END_FF_ERASE",
+        VUI_GET_INFO => "VUI_GET_INFO",
+        cmd if allowed_bits_type(cmd).is_some() => "VUI_GET_ALLOWED_BITS",
+        _ => "unknown ioctl",
+    }
+}
 
-            // Create device in container, if the request was really from another namespace
-            if !SELF_NAMESPACES
+/// Whether the handle still has the device the mknod job has been dispatched for, and not a
+/// newer one of a destroy and another create in the meantime
+fn is_same_device(vuinput_state: &VuInputState, major: u64, minor: u64) -> bool {
+    vuinput_state
+        .input_device
+        .as_ref()
+        .is_some_and(|device| (device.major, device.minor) == (major, minor))
+}
+
+/// Takes back UI_DEV_CREATE of a container whose node could not be created: the client gets
+/// EIO, so the device on the host, its slot of the limit and its serial must not remain. The
+/// udev data of the device is removed from the container behind the failed mknod.
+fn undo_create(fh: u64, major: u64, minor: u64) {
+    let Ok(vuinput_state_mutex) = get_vuinput_state(&VuFileHandle::Fh(fh)) else {
+        return;
+    };
+    let mut vuinput_state = vuinput_state_mutex.lock().unwrap();
+    // destroyed or revoked in the meantime, whoever did that has cleaned up
+    if !is_same_device(&vuinput_state, major, minor) {
+        return;
+    }
+    let input_device = vuinput_state.input_device.take().unwrap();
+    vuinput_state.lifecycle.advance(DeviceLifecycle::Destroyed);
+    vuinput_state.descriptor = DeviceDescriptor::default();
+    if let Err(errno) = unsafe { ui_dev_destroy(vuinput_state.file.as_raw_fd()) } {
+        warn!(
+            "fh {}: UI_DEV_DESTROY after the failed mknod failed: {}",
+            fh, errno
+        );
+    }
+    warn!(
+        "fh {}: removed {}, its node could not be created in the container",
+        fh, input_device.devnode
+    );
+    broadcast::remove_mirrors(fh, &input_device);
+    device_limits::release(&vuinput_state.requesting_process.namespaces);
+    if let Some(serial) = &input_device.serial {
+        device_serial::release(serial);
+    }
+    let remove_job = RemoveDeviceJob::new(
+        vuinput_state.requesting_process.clone(),
+        input_device.devname.clone(),
+        input_device.syspath.clone(),
+        input_device.major,
+        input_device.minor,
+    );
+    JOB_DISPATCHER
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .dispatch(Box::new(remove_job));
+}
+
+/// An ioctl whose buffers have been mapped by the kernel, if it has any
+struct IoctlCall {
+    fh: u64,
+    /// with the size removed for the variable length ones, native for 32-bit clients
+    cmd: u64,
+    /// The client uses the compat layout of the ioctl (see compat_ioctl)
+    compat: bool,
+    arg: *mut c_void,
+    in_buf: *const c_void,
+    in_bufsz: size_t,
+    out_bufsz: size_t,
+    /// UI_DEV_CREATE that waited for the approval of its container, see approval
+    approved: bool,
+    /// The retries of UI_DEV_CREATE after transient errors so far, see retry_create
+    create_attempt: u32,
+}
+
+/// Forwards the ioctl to the host uinput fd and replies on success. Requests refused by
+/// vuinputd itself (device policy, limits) are answered here as well; only errors of the
+/// forwarding are returned to be answered by the caller.
+unsafe fn forward_ioctl(
+    req: fuse_lowlevel::fuse_req_t,
+    call: &IoctlCall,
+    vuinput_state: &mut VuInputState,
+) -> Result<(), VuIoctlError> {
+    let fh = call.fh;
+    let fd = vuinput_state.file.as_raw_fd();
+    if let Some(type_) = allowed_bits_type(call.cmd) {
+        reply_allowed_bits(req, fh, vuinput_state, type_, call.out_bufsz);
+        return Ok(());
+    }
+    match call.cmd {
+        UI_DEV_CREATE => {
+            debug!("fh {}: ioctl UI_DEV_CREATE", fh);
+            let policy = vuinput_state.policy(&get_reloadable_config());
+            if let Some(violation) =
+                device_policy::class_violation(&policy, &vuinput_state.descriptor)
+            {
+                warn!(
+                    "fh {}: refused UI_DEV_CREATE, {} leaves the class of {:?}",
+                    fh, violation, policy
+                );
+                events::policy_violation(
+                    fh,
+                    vuinput_state,
+                    format_args!("UI_DEV_CREATE {}", violation),
+                );
+                fuse_lowlevel::fuse_reply_err(req, EPERM);
+                return Ok(());
+            }
+            let in_container = !SELF_NAMESPACES
                 .get()
                 .unwrap()
-                .equal_mnt_and_net(&vuinput_state.requesting_process.namespaces)
+                .equal_mnt_and_net(&vuinput_state.requesting_process.namespaces);
+            if in_container && !call.approved {
+                let container = vuinput_state.requesting_process.pid_requestor_root.as_raw();
+                match approval::check(container) {
+                    approval::Verdict::Approved => {}
+                    approval::Verdict::Denied => {
+                        warn!(
+                            "fh {}: refused UI_DEV_CREATE, container {} is not approved",
+                            fh, container
+                        );
+                        events::policy_violation(fh, vuinput_state, "approval");
+                        fuse_lowlevel::fuse_reply_err(req, EPERM);
+                        return Ok(());
+                    }
+                    approval::Verdict::Ask => {
+                        approval::wait(
+                            fh,
+                            vuinput_state,
+                            PendingReply::new(req, fh, "UI_DEV_CREATE"),
+                        );
+                        return Ok(());
+                    }
+                }
+            }
+            let namespaces = &vuinput_state.requesting_process.namespaces;
+            if in_container
+                && !device_limits::try_reserve(namespaces, get_max_devices_per_container())
             {
+                warn!(
+                    "fh {}: refused UI_DEV_CREATE, the container reached limits.max-devices-per-container",
+                    fh
+                );
+                events::policy_violation(fh, vuinput_state, "limits.max-devices-per-container");
+                fuse_lowlevel::fuse_reply_err(req, ENOSPC);
+                return Ok(());
+            }
+            // the ranges of a legacy setup, for the axes that have been enabled in the meantime
+            if let Err(error) = legacy_setup::apply_ranges(fd, &mut vuinput_state.descriptor) {
+                if in_container {
+                    device_limits::release(&vuinput_state.requesting_process.namespaces);
+                }
+                return Err(error);
+            }
+            // a device of the container that has been restored after a restart (persist-devices)
+            if in_container && persistence::adopt(fh, vuinput_state) {
+                fuse_lowlevel::fuse_reply_ioctl(req, 0, std::ptr::null(), 0);
+                return Ok(());
+            }
+            if vuinput_state.descriptor.preserved_id.is_some() {
+                // the hwdb does not recognize the device by its id, 90-vuinputd-protect.rules uses the phys
+                let phys = CString::new(marked_phys(vuinput_state.descriptor.phys.as_deref()))
+                    .unwrap_or_default();
+                if let Err(errno) = ui_set_phys(fd, phys.as_ptr() as *const *const c_char) {
+                    if in_container {
+                        device_limits::release(&vuinput_state.requesting_process.namespaces);
+                    }
+                    return Err(VuIoctlError::Host {
+                        ioctl: "UI_SET_PHYS",
+                        errno,
+                    });
+                }
+            }
+            // a legacy setup reaches the host only now, see uinput_compat
+            let created = uinput_compat::finish_setup(fd, &vuinput_state.descriptor)
+                .and_then(|()| create_device(fh, fd));
+            let mut input_device = match created {
+                Ok(input_device) => input_device,
+                Err(VuIoctlError::Host {
+                    ioctl: "UI_DEV_CREATE",
+                    errno,
+                }) if is_transient(errno) && call.create_attempt < get_create_retries() => {
+                    if in_container {
+                        device_limits::release(&vuinput_state.requesting_process.namespaces);
+                    }
+                    let reply = PendingReply::new(req, fh, "UI_DEV_CREATE");
+                    retry_create(fh, vuinput_state, reply, call.create_attempt, errno);
+                    return Ok(());
+                }
+                Err(error) => {
+                    if in_container {
+                        device_limits::release(&vuinput_state.requesting_process.namespaces);
+                    }
+                    return Err(error);
+                }
+            };
+            debug!(
+                "fh {}: declared capabilities {}",
+                fh, vuinput_state.descriptor
+            );
+            if in_container {
+                input_device.serial = Some(device_serial::allocate(
+                    &vuinput_state.requesting_process.namespaces,
+                ));
+            }
+            let serial = input_device.serial.map(|serial| serial.to_string());
+            let sysname = input_device.syspath.clone();
+            let devname = input_device.devname.clone();
+            let devnode = input_device.devnode.clone();
+            let container_devnode = input_device.container_devnode.clone();
+            let (major, minor) = (input_device.major, input_device.minor);
+            input_device.mirrors =
+                broadcast::targets_of(&vuinput_state.requesting_process, &vuinput_state.descriptor);
+            vuinput_state.input_device = Some(input_device);
+            vuinput_state.lifecycle.advance(DeviceLifecycle::Created);
+
+            // Create device in container, if the request was really from another namespace.
+            // The job answers the request once the node exists, the handler returns right away.
+            if in_container {
+                let reply = PendingReply::new(req, fh, "UI_DEV_CREATE");
+                let created = devnode.clone();
                 let mknod_job = MknodDeviceJob::new(
                     vuinput_state.requesting_process.clone(),
                     devname.clone(),
                     sysname.clone(),
                     major,
                     minor,
-                );
-                let awaiter = mknod_job.get_awaiter_for_state();
+                )
+                .record_node(container_devnode)
+                .on_completion(Box::new(move |result| match result {
+                    Ok(()) => {
+                        debug!("fh {}: mknod_device in container has been finished ", fh);
+                        if let Ok(vuinput_state) = get_vuinput_state(&VuFileHandle::Fh(fh)) {
+                            let vuinput_state = vuinput_state.lock().unwrap();
+                            if is_same_device(&vuinput_state, major, minor) {
+                                events::device_created(fh, &vuinput_state, &created);
+                            }
+                        }
+                        reply.ioctl_ok();
+                    }
+                    Err(_) => {
+                        undo_create(fh, major, minor);
+                        reply.err(EIO);
+                    }
+                }));
                 JOB_DISPATCHER
                     .get()
                     .unwrap()
                     .lock()
                     .unwrap()
                     .dispatch(Box::new(mknod_job));
-                awaiter(&jobs::mknod_device_job::State::Finished);
-                debug!("fh {}: mknod_device in container has been finished ", fh);
-                fuse_lowlevel::fuse_reply_ioctl(_req, 0, std::ptr::null(), 0);
 
-                // we do not wait for the udev stuff
+                // we do not wait for the udev stuff, it is queued behind the mknod
                 let emit_udev_event_job = EmitUdevEventJob::new(
                     vuinput_state.requesting_process.clone(),
                     devnode.clone(),
                     sysname.clone(),
                     major,
                     minor,
+                    serial,
                 );
                 JOB_DISPATCHER
                     .get()
@@ -215,21 +566,79 @@ pub unsafe extern "C" fn vuinput_ioctl(
                     .unwrap()
                     .dispatch(Box::new(emit_udev_event_job));
             } else {
-                fuse_lowlevel::fuse_reply_ioctl(_req, 0, std::ptr::null(), 0);
+                events::device_created(fh, vuinput_state, &devnode);
+                fuse_lowlevel::fuse_reply_ioctl(req, 0, std::ptr::null(), 0);
+            }
+            if let Some(input_device) = &vuinput_state.input_device {
+                broadcast::create_mirrors(fh, input_device);
+                jobs::verify_seat_job::verify(input_device);
             }
         }
         UI_DEV_DESTROY => {
             debug!("fh {}: ioctl UI_DEV_DESTROY", fh);
-            let input_device = vuinput_state.input_device.take();
-
-            // Remove device in container, if the request was really from another namespace
-            if input_device.is_some()
+            // the client sent the create first, so it gets its answer first
+            if approval::cancel(fh) {
+                debug!(
+                    "fh {}: cancelled UI_DEV_CREATE, which waited for approval",
+                    fh
+                );
+            }
+            if let Some(reply) = vuinput_state.pending_create.take() {
+                debug!(
+                    "fh {}: cancelled UI_DEV_CREATE, which waited for a retry",
+                    fh
+                );
+                reply.err(ECANCELED);
+            }
+            if vuinput_state.lifecycle != DeviceLifecycle::Created {
+                // The kernel accepts a destroy without a created device and returns 0,
+                // so a second destroy is no error.
+                debug!(
+                    "fh {}: no device has been created ({:?}), nothing to remove",
+                    fh, vuinput_state.lifecycle
+                );
+            }
+            let in_container = vuinput_state.input_device.is_some()
                 && !SELF_NAMESPACES
                     .get()
                     .unwrap()
-                    .equal_mnt_and_net(&vuinput_state.requesting_process.namespaces)
-            {
-                let input_device = input_device.unwrap();
+                    .equal_mnt_and_net(&vuinput_state.requesting_process.namespaces);
+            // The device on the host goes after its node in the container, with a clone of the
+            // uinput file. Without one, it goes right away, before anything has been changed.
+            let uinput = match in_container {
+                true => vuinput_state
+                    .file
+                    .try_clone()
+                    .inspect_err(|e| {
+                        warn!(
+                            "fh {}: can't clone the uinput file, destroying the device before \
+                            its node in the container: {}",
+                            fh, e
+                        )
+                    })
+                    .ok(),
+                false => None,
+            };
+            if in_container && uinput.is_none() {
+                ui_dev_destroy(fd).map_err(VuIoctlError::host("UI_DEV_DESTROY"))?;
+            }
+            // Whoever takes input_device (destroy or release) removes the device from the container.
+            let input_device = vuinput_state.input_device.take();
+            if vuinput_state.lifecycle == DeviceLifecycle::Created {
+                vuinput_state.lifecycle.advance(DeviceLifecycle::Destroyed);
+            }
+            vuinput_state.descriptor = DeviceDescriptor::default();
+            if let Some(input_device) = &input_device {
+                events::device_removed(fh, vuinput_state, &input_device.devnode);
+                broadcast::remove_mirrors(fh, input_device);
+            }
+
+            // Remove device in container, if the request was really from another namespace
+            if let (true, Some(input_device)) = (in_container, input_device) {
+                device_limits::release(&vuinput_state.requesting_process.namespaces);
+                if let Some(serial) = &input_device.serial {
+                    device_serial::release(serial);
+                }
                 let remove_job = RemoveDeviceJob::new(
                     vuinput_state.requesting_process.clone(),
                     input_device.devname.clone(),
@@ -237,185 +646,801 @@ pub unsafe extern "C" fn vuinput_ioctl(
                     input_device.major,
                     input_device.minor,
                 );
-                let awaiter = remove_job.get_awaiter_for_state();
+                // The job answers the request once the device is gone. Waiting here would hold
+                // the lock of the handle for as long as the container takes.
+                let remove_job = match uinput {
+                    Some(uinput) => {
+                        let reply = PendingReply::new(req, fh, "UI_DEV_DESTROY");
+                        remove_job.on_completion(Box::new(move || {
+                            debug!(
+                                "fh {}: removing dev-nodes from container has been finished ",
+                                fh
+                            );
+                            match ui_dev_destroy(uinput.as_raw_fd()) {
+                                Ok(_) => reply.ioctl_ok(),
+                                Err(errno) => {
+                                    warn!(
+                                        "fh {}: UI_DEV_DESTROY failed on the host: {}",
+                                        fh, errno
+                                    );
+                                    reply.err(errno as c_int);
+                                }
+                            }
+                        }))
+                    }
+                    None => {
+                        fuse_lowlevel::fuse_reply_ioctl(req, 0, std::ptr::null(), 0);
+                        remove_job
+                    }
+                };
                 JOB_DISPATCHER
                     .get()
                     .unwrap()
                     .lock()
                     .unwrap()
                     .dispatch(Box::new(remove_job));
-                awaiter(&jobs::remove_device_job::State::Finished);
-                debug!(
-                    "fh {}: removing dev-nodes from container has been finished ",
-                    fh
-                );
+                return Ok(());
             }
 
-            ui_dev_destroy(fd).unwrap();
-            fuse_lowlevel::fuse_reply_ioctl(_req, 0, std::ptr::null(), 0);
+            // Also forward the destroy if nothing has been created, it resets a pending setup in the kernel.
+            ui_dev_destroy(fd).map_err(VuIoctlError::host("UI_DEV_DESTROY"))?;
+            fuse_lowlevel::fuse_reply_ioctl(req, 0, std::ptr::null(), 0);
         }
         UI_DEV_SETUP => {
             debug!("fh {}: ioctl UI_DEV_SETUP", fh);
-            assert!(_in_bufsz != 0, "should have _in_bufsz");
-            let setup_ptr = _in_buf as *mut uinput_setup;
+            require_buffer("UI_DEV_SETUP", call.in_bufsz)?;
+            dump_ioctl_buffer(
+                fh,
+                "in",
+                "UI_DEV_SETUP",
+                call.in_buf as *const u8,
+                call.in_bufsz,
+            );
+            let setup_ptr = call.in_buf as *mut uinput_setup;
             debug!(
                 "product: {:x} vendor: {:x}",
                 (*setup_ptr).id.product,
                 (*setup_ptr).id.vendor
             );
-            // replace vendor and product id to the values from sunshine (see inputtino_common.h of sunshine)
-            // The pid is registered for vuinputd, see https://pid.codes/1209/5020/
-            (*setup_ptr).id.bustype = BUS_USB;
-            (*setup_ptr).id.product = 0x5020;
-            (*setup_ptr).id.vendor = 0x1209;
-            ui_dev_setup(fd, setup_ptr).unwrap();
-            fuse_lowlevel::fuse_reply_ioctl(_req, 0, std::ptr::null(), 0);
+            let config = get_reloadable_config();
+            let preserved_id = apply_id_policy(
+                &mut (*setup_ptr).id,
+                &config.id_policy,
+                &config.passthrough_ids,
+            );
+            if let Some(id) = preserved_id {
+                debug!("fh {}: keeping the id {} of the setup", fh, id);
+            }
+            let container = vuinput_state.requesting_process.pid_requestor_root.as_raw();
+            match apply_name_policy(&mut (*setup_ptr).name, &NameRules::new(&config, container)) {
+                Ok(None) => {}
+                Ok(Some(violation)) => {
+                    warn!("fh {}: sanitized device name ({})", fh, violation);
+                }
+                Err(violation) => {
+                    warn!("fh {}: rejected UI_DEV_SETUP ({})", fh, violation);
+                    events::policy_violation(
+                        fh,
+                        vuinput_state,
+                        format_args!("device name: {}", violation),
+                    );
+                    fuse_lowlevel::fuse_reply_err(req, EINVAL);
+                    return Ok(());
+                }
+            }
+            uinput_compat::dev_setup(fd, &*setup_ptr)?;
+            vuinput_state.descriptor.preserved_id = preserved_id;
+            vuinput_state.descriptor.setup = Some(DeviceSetup::from(&*setup_ptr));
+            fuse_lowlevel::fuse_reply_ioctl(req, 0, std::ptr::null(), 0);
         }
-        UI_ABS_SETUP => {
-            //todo: i guess this needs to be reworked as this is variable size. i guess it is not reachable at all
-            debug!("fh {}: ioctl UI_ABS_SETUP", fh);
-            assert!(_in_bufsz != 0, "should have _in_bufsz");
-
-            let abs_setup_ptr = _in_buf as *const uinput_abs_setup;
-            ui_abs_setup(fd, abs_setup_ptr).unwrap();
+        UI_ABS_SETUP_WITHOUT_SIZE => {
+            require_buffer("UI_ABS_SETUP", call.in_bufsz)?;
+            dump_ioctl_buffer(
+                fh,
+                "in",
+                "UI_ABS_SETUP",
+                call.in_buf as *const u8,
+                call.in_bufsz,
+            );
+            let buffer = std::slice::from_raw_parts(call.in_buf as *const u8, call.in_bufsz);
+            let setup = abs_setup_from_buffer(buffer).ok_or(VuIoctlError::Buffer {
+                ioctl: "UI_ABS_SETUP",
+                size: call.in_bufsz,
+            })?;
+            let absinfo = AbsInfo::from(setup.absinfo);
+            debug!(
+                "fh {}: ioctl UI_ABS_SETUP {} {}",
+                fh,
+                CodeName(EV_ABS, setup.code),
+                absinfo
+            );
 
-            fuse_lowlevel::fuse_reply_ioctl(_req, 0, std::ptr::null(), 0);
+            // The kernel enables the axis as well, so it has to pass the policy like UI_SET_ABSBIT.
+            if !is_capability_allowed(
+                req,
+                fh,
+                vuinput_state,
+                "UI_ABS_SETUP",
+                Capability::Code(EV_ABS),
+                setup.code,
+            ) {
+                return Ok(());
+            }
+            // always the full struct, the missing fields are zero like in the kernel
+            uinput_compat::abs_setup(fd, &setup)?;
+            let descriptor = &mut vuinput_state.descriptor;
+            descriptor
+                .codes
+                .entry(EV_ABS)
+                .or_default()
+                .insert(setup.code);
+            descriptor.absinfo.insert(setup.code, absinfo);
+            // overrides the range of a legacy setup, like in the kernel
+            descriptor.legacy_ranges.remove(&setup.code);
+            fuse_lowlevel::fuse_reply_ioctl(req, 0, std::ptr::null(), 0);
         }
         UI_GET_SYSNAME_WITHOUT_SIZE => {
-            debug!("fh {}: ioctl UI_GET_SYSNAME {_out_bufsz}", fh);
-            assert!(
-                _out_bufsz == 64,
-                "should have _out_bufsz of length 64 (currently hardcoded)"
-            );
+            debug!("fh {}: ioctl UI_GET_SYSNAME {}", fh, call.out_bufsz);
+            // the retry above always asks for 64 bytes
+            if call.out_bufsz != 64 {
+                return Err(VuIoctlError::Buffer {
+                    ioctl: "UI_GET_SYSNAME",
+                    size: call.out_bufsz,
+                });
+            }
             let mut resultbuf: [c_char; 64] = [0; 64];
-            ui_get_sysname(fd, resultbuf.as_mut_slice()).unwrap();
+            match &vuinput_state.input_device {
+                // the name of a device never changes, the host only answers before UI_DEV_CREATE
+                Some(input_device) => copy_str(&mut resultbuf, &input_device.sysname),
+                None => {
+                    ui_get_sysname(fd, resultbuf.as_mut_slice())
+                        .map_err(VuIoctlError::host("UI_GET_SYSNAME"))?;
+                }
+            }
             let sysname = CStr::from_ptr(resultbuf.as_ptr()).to_string_lossy();
             debug!("fh {}: sysname: {}", fh, sysname);
+            dump_ioctl_buffer(
+                fh,
+                "out",
+                "UI_GET_SYSNAME",
+                resultbuf.as_ptr() as *const u8,
+                call.out_bufsz,
+            );
             fuse_lowlevel::fuse_reply_ioctl(
-                _req,
+                req,
                 0,
                 resultbuf.as_mut_ptr() as *mut c_void,
-                _out_bufsz,
+                call.out_bufsz,
             );
         }
         UI_GET_VERSION => {
-            let mut version_of_kernel = 0;
-            let pversion_of_kernel = std::ptr::from_mut(&mut version_of_kernel);
-            ui_get_version(fd, pversion_of_kernel).unwrap();
+            // older kernels lack UI_GET_VERSION, vuinputd translates the ioctls of version 5 for them
+            let mut version_of_kernel = uinput_compat::features().version;
+            if uinput_compat::features().has("UI_GET_VERSION") {
+                let pversion_of_kernel = std::ptr::from_mut(&mut version_of_kernel);
+                ui_get_version(fd, pversion_of_kernel)
+                    .map_err(VuIoctlError::host("UI_GET_VERSION"))?;
+            }
             debug!("fh {}: ioctl UI_GET_VERSION {}", fh, version_of_kernel);
-            let reply_arg = 5;
+            let reply_arg = uinput_compat::UINPUT_VERSION;
             let preply_arg = std::ptr::from_ref(&reply_arg);
             fuse_lowlevel::fuse_reply_ioctl(
-                _req,
+                req,
                 0,
                 preply_arg as *const c_void,
                 std::mem::size_of::<c_uint>(),
             );
         }
         UI_SET_EVBIT => {
-            let value = _arg as c_uint;
-            debug!("fh {}: ioctl UI_SET_EVBIT {}", fh, value);
-            ui_set_evbit(fd, value.into()).unwrap();
-            fuse_lowlevel::fuse_reply_ioctl(_req, 0, std::ptr::null(), 0);
+            let value = call.arg as c_uint;
+            set_capability_bit(
+                req,
+                fh,
+                vuinput_state,
+                "UI_SET_EVBIT",
+                Capability::EventType,
+                value,
+                ui_set_evbit,
+            )?;
         }
         UI_SET_KEYBIT => {
-            let value = _arg as c_uint;
-            debug!("fh {}: ioctl UI_SET_KEYBIT {}", fh, value);
-            ui_set_keybit(fd, value.into()).unwrap();
-            fuse_lowlevel::fuse_reply_ioctl(_req, 0, std::ptr::null(), 0);
+            let value = call.arg as c_uint;
+            set_capability_bit(
+                req,
+                fh,
+                vuinput_state,
+                "UI_SET_KEYBIT",
+                Capability::Code(EV_KEY),
+                value,
+                ui_set_keybit,
+            )?;
         }
         UI_SET_RELBIT => {
-            let value = _arg as c_uint;
-            debug!("fh {}: ioctl UI_SET_RELBIT {}", fh, value);
-            ui_set_relbit(fd, value.into()).unwrap();
-            fuse_lowlevel::fuse_reply_ioctl(_req, 0, std::ptr::null(), 0);
+            let value = call.arg as c_uint;
+            set_capability_bit(
+                req,
+                fh,
+                vuinput_state,
+                "UI_SET_RELBIT",
+                Capability::Code(EV_REL),
+                value,
+                ui_set_relbit,
+            )?;
         }
         UI_SET_ABSBIT => {
-            let value = _arg as c_uint;
-            debug!("fh {}: ioctl UI_SET_ABSBIT {}", fh, value);
-            ui_set_absbit(fd, value.into()).unwrap();
-            fuse_lowlevel::fuse_reply_ioctl(_req, 0, std::ptr::null(), 0);
+            let value = call.arg as c_uint;
+            set_capability_bit(
+                req,
+                fh,
+                vuinput_state,
+                "UI_SET_ABSBIT",
+                Capability::Code(EV_ABS),
+                value,
+                ui_set_absbit,
+            )?;
         }
         UI_SET_MSCBIT => {
-            let value = _arg as c_uint;
-            debug!("fh {}: ioctl UI_SET_MSCBIT {}", fh, value);
-            ui_set_mscbit(fd, value.into()).unwrap();
-            fuse_lowlevel::fuse_reply_ioctl(_req, 0, std::ptr::null(), 0);
+            let value = call.arg as c_uint;
+            set_capability_bit(
+                req,
+                fh,
+                vuinput_state,
+                "UI_SET_MSCBIT",
+                Capability::Code(EV_MSC),
+                value,
+                ui_set_mscbit,
+            )?;
         }
         UI_SET_LEDBIT => {
-            let value = _arg as c_uint;
-            debug!("fh {}: ioctl UI_SET_LEDBIT {}", fh, value);
-            ui_set_ledbit(fd, value.into()).unwrap();
-            fuse_lowlevel::fuse_reply_ioctl(_req, 0, std::ptr::null(), 0);
+            let value = call.arg as c_uint;
+            set_capability_bit(
+                req,
+                fh,
+                vuinput_state,
+                "UI_SET_LEDBIT",
+                Capability::Code(EV_LED),
+                value,
+                ui_set_ledbit,
+            )?;
         }
         UI_SET_SNDBIT => {
-            let value = _arg as c_uint;
-            debug!("fh {}: ioctl UI_SET_SNDBIT {}", fh, value);
-            ui_set_sndbit(fd, value.into()).unwrap();
-            fuse_lowlevel::fuse_reply_ioctl(_req, 0, std::ptr::null(), 0);
+            let value = call.arg as c_uint;
+            set_capability_bit(
+                req,
+                fh,
+                vuinput_state,
+                "UI_SET_SNDBIT",
+                Capability::Code(EV_SND),
+                value,
+                ui_set_sndbit,
+            )?;
         }
         UI_SET_FFBIT => {
-            let value = _arg as c_uint;
-            debug!("fh {}: ioctl UI_SET_FFBIT {}", fh, value);
-            ui_set_ffbit(fd, value.into()).unwrap();
-            fuse_lowlevel::fuse_reply_ioctl(_req, 0, std::ptr::null(), 0);
+            let value = call.arg as c_uint;
+            set_capability_bit(
+                req,
+                fh,
+                vuinput_state,
+                "UI_SET_FFBIT",
+                Capability::Code(EV_FF),
+                value,
+                ui_set_ffbit,
+            )?;
         }
         UI_SET_PHYS => {
-            assert!(_in_bufsz != 0, "should have _in_bufsz");
+            require_buffer("UI_SET_PHYS", call.in_bufsz)?;
             debug!("fh {}: ioctl UI_SET_PHYS", fh);
             // inbuf is actually a *const c_char, but
             // but the macro to generate ui_set_phys expects a ptr to the actual data structure.
-            let phys = _in_buf as *const *const c_char;
-            ui_set_phys(fd, phys).unwrap();
-            fuse_lowlevel::fuse_reply_ioctl(_req, 0, std::ptr::null(), 0);
+            dump_ioctl_buffer(
+                fh,
+                "in",
+                "UI_SET_PHYS",
+                call.in_buf as *const u8,
+                call.in_bufsz,
+            );
+            let phys = call.in_buf as *const *const c_char;
+            ui_set_phys(fd, phys).map_err(VuIoctlError::host("UI_SET_PHYS"))?;
+            // kept for the marker of preserved ids, which is only set at UI_DEV_CREATE
+            let buffer = std::slice::from_raw_parts(call.in_buf as *const u8, call.in_bufsz);
+            let phys = CStr::from_bytes_until_nul(buffer)
+                .map(|phys| phys.to_string_lossy().into_owned())
+                .unwrap_or_else(|_| String::from_utf8_lossy(buffer).into_owned());
+            vuinput_state.descriptor.phys = Some(phys);
+            fuse_lowlevel::fuse_reply_ioctl(req, 0, std::ptr::null(), 0);
         }
         UI_SET_SWBIT => {
-            let value = _arg as c_uint;
-            debug!("fh {}: ioctl UI_SET_SWBIT {}", fh, value);
-            ui_set_swbit(fd, value.into()).unwrap();
-            fuse_lowlevel::fuse_reply_ioctl(_req, 0, std::ptr::null(), 0);
+            let value = call.arg as c_uint;
+            set_capability_bit(
+                req,
+                fh,
+                vuinput_state,
+                "UI_SET_SWBIT",
+                Capability::Code(EV_SW),
+                value,
+                ui_set_swbit,
+            )?;
         }
         UI_SET_PROPBIT => {
-            let value = _arg as c_uint;
-            debug!("fh {}: ioctl UI_SET_PROPBIT {}", fh, value);
-            ui_set_propbit(fd, value.into()).unwrap();
-            fuse_lowlevel::fuse_reply_ioctl(_req, 0, std::ptr::null(), 0);
+            let value = call.arg as c_uint;
+            set_capability_bit(
+                req,
+                fh,
+                vuinput_state,
+                "UI_SET_PROPBIT",
+                Capability::Property,
+                value,
+                ui_set_propbit,
+            )?;
         }
         UI_BEGIN_FF_UPLOAD => {
-            assert!(_in_bufsz != 0, "should have _in_bufsz");
+            require_buffer("UI_BEGIN_FF_UPLOAD", call.in_bufsz)?;
             debug!("fh {}: ioctl UI_BEGIN_FF_UPLOAD", fh);
-            let ff_upload_ptr = _in_buf as *mut uinput_ff_upload;
-            debug!("request_id: {:x}", (*ff_upload_ptr).request_id);
-            ui_begin_ff_upload(fd, ff_upload_ptr).unwrap();
-            fuse_lowlevel::fuse_reply_ioctl(_req, 0, ff_upload_ptr as *mut c_void, _out_bufsz);
+            dump_ioctl_buffer(
+                fh,
+                "in",
+                "UI_BEGIN_FF_UPLOAD",
+                call.in_buf as *const u8,
+                call.in_bufsz,
+            );
+            let buffer = std::slice::from_raw_parts(call.in_buf as *const u8, call.in_bufsz);
+            let mut ff_upload = compat_ioctl::ff_upload_from_buffer(buffer, call.compat).ok_or(
+                VuIoctlError::Buffer {
+                    ioctl: "UI_BEGIN_FF_UPLOAD",
+                    size: call.in_bufsz,
+                },
+            )?;
+            debug!("request_id: {:x}", ff_upload.request_id);
+            ui_begin_ff_upload(fd, &mut ff_upload)
+                .map_err(VuIoctlError::host("UI_BEGIN_FF_UPLOAD"))?;
+            let reply = compat_ioctl::ff_upload_to_buffer(&ff_upload, call.compat);
+            let size = call.out_bufsz.min(reply.len());
+            dump_ioctl_buffer(fh, "out", "UI_BEGIN_FF_UPLOAD", reply.as_ptr(), size);
+            fuse_lowlevel::fuse_reply_ioctl(req, 0, reply.as_ptr() as *const c_void, size);
         }
         UI_END_FF_UPLOAD => {
-            assert!(_in_bufsz != 0, "should have _in_bufsz");
+            require_buffer("UI_END_FF_UPLOAD", call.in_bufsz)?;
             debug!("fh {}: ioctl UI_END_FF_UPLOAD", fh);
-            let ff_upload_ptr = _in_buf as *const uinput_ff_upload;
-            debug!("request_id: {:x}", (*ff_upload_ptr).request_id);
-            ui_end_ff_upload(fd, ff_upload_ptr).unwrap();
-            fuse_lowlevel::fuse_reply_ioctl(_req, 0, std::ptr::null(), 0);
+            dump_ioctl_buffer(
+                fh,
+                "in",
+                "UI_END_FF_UPLOAD",
+                call.in_buf as *const u8,
+                call.in_bufsz,
+            );
+            let buffer = std::slice::from_raw_parts(call.in_buf as *const u8, call.in_bufsz);
+            let ff_upload = compat_ioctl::ff_upload_from_buffer(buffer, call.compat).ok_or(
+                VuIoctlError::Buffer {
+                    ioctl: "UI_END_FF_UPLOAD",
+                    size: call.in_bufsz,
+                },
+            )?;
+            debug!("request_id: {:x}", ff_upload.request_id);
+            ui_end_ff_upload(fd, &ff_upload).map_err(VuIoctlError::host("UI_END_FF_UPLOAD"))?;
+            fuse_lowlevel::fuse_reply_ioctl(req, 0, std::ptr::null(), 0);
         }
         UI_BEGIN_FF_ERASE => {
-            assert!(_in_bufsz != 0, "should have _in_bufsz");
+            require_buffer("UI_BEGIN_FF_ERASE", call.in_bufsz)?;
             debug!("fh {}: ioctl UI_BEGIN_FF_ERASE", fh);
-            let ff_erase_ptr = _in_buf as *mut uinput_ff_erase;
+            dump_ioctl_buffer(
+                fh,
+                "in",
+                "UI_BEGIN_FF_ERASE",
+                call.in_buf as *const u8,
+                call.in_bufsz,
+            );
+            let ff_erase_ptr = call.in_buf as *mut uinput_ff_erase;
             debug!("request_id: {:x}", (*ff_erase_ptr).request_id);
-            ui_begin_ff_erase(fd, ff_erase_ptr).unwrap();
-            fuse_lowlevel::fuse_reply_ioctl(_req, 0, ff_erase_ptr as *mut c_void, _out_bufsz);
+            ui_begin_ff_erase(fd, ff_erase_ptr).map_err(VuIoctlError::host("UI_BEGIN_FF_ERASE"))?;
+            dump_ioctl_buffer(
+                fh,
+                "out",
+                "UI_BEGIN_FF_ERASE",
+                ff_erase_ptr as *const u8,
+                call.out_bufsz,
+            );
+            fuse_lowlevel::fuse_reply_ioctl(req, 0, ff_erase_ptr as *mut c_void, call.out_bufsz);
         }
         UI_END_FF_ERASE => {
-            assert!(_in_bufsz != 0, "should have _in_bufsz");
+            require_buffer("UI_END_FF_ERASE", call.in_bufsz)?;
             debug!("fh {}: ioctl UI_END_FF_ERASE", fh);
-            let ff_erase_ptr = _in_buf as *const uinput_ff_erase;
+            dump_ioctl_buffer(
+                fh,
+                "in",
+                "UI_END_FF_ERASE",
+                call.in_buf as *const u8,
+                call.in_bufsz,
+            );
+            let ff_erase_ptr = call.in_buf as *const uinput_ff_erase;
             debug!("request_id: {:x}", (*ff_erase_ptr).request_id);
-            ui_end_ff_erase(fd, ff_erase_ptr).unwrap();
-            fuse_lowlevel::fuse_reply_ioctl(_req, 0, std::ptr::null(), 0);
+            ui_end_ff_erase(fd, ff_erase_ptr).map_err(VuIoctlError::host("UI_END_FF_ERASE"))?;
+            fuse_lowlevel::fuse_reply_ioctl(req, 0, std::ptr::null(), 0);
+        }
+        VUI_GET_INFO => {
+            debug!("fh {}: ioctl VUI_GET_INFO", fh);
+            let info = vuinput_info(vuinput_state);
+            let size = call.out_bufsz.min(std::mem::size_of::<VuinputInfo>());
+            fuse_lowlevel::fuse_reply_ioctl(
+                req,
+                0,
+                &info as *const VuinputInfo as *const c_void,
+                size,
+            );
         }
         _ => {
-            debug!("fh {}: ioctl cmd {}", fh, _cmd);
-            fuse_lowlevel::fuse_reply_err(_req, EBADRQC);
+            debug!("fh {}: ioctl cmd {}", fh, call.cmd);
+            fuse_lowlevel::fuse_reply_err(req, EBADRQC);
+        }
+    }
+    Ok(())
+}
+
+/// Goes on with UI_DEV_CREATE of a handle once its container has been approved, see approval
+pub fn resume_create(fh: u64, reply: PendingReply) {
+    let vuinput_state_mutex = match get_vuinput_state(&VuFileHandle::Fh(fh)) {
+        Ok(vuinput_state_mutex) => vuinput_state_mutex,
+        Err(e) => {
+            warn!("fh {}: approved, but {}", fh, e);
+            reply.err(ENODEV);
+            return;
+        }
+    };
+    let mut vuinput_state = vuinput_state_mutex.lock().unwrap();
+    create_again(fh, reply, &mut vuinput_state, 0);
+}
+
+/// Retries UI_DEV_CREATE after a transient error, once create_backoff(attempt) has passed.
+/// A job waits for it instead of the handler, so neither the handle nor the CUSE session is
+/// held up meanwhile. The request is parked in pending_create, UI_DEV_DESTROY cancels it.
+fn retry_create(
+    fh: u64,
+    vuinput_state: &mut VuInputState,
+    reply: PendingReply,
+    attempt: u32,
+    errno: Errno,
+) {
+    let backoff = create_backoff(attempt);
+    warn!(
+        "fh {}: UI_DEV_CREATE failed with {}, retrying in {:?}",
+        fh, errno, backoff
+    );
+    vuinput_state.pending_create = Some(reply);
+    JOB_DISPATCHER.get().unwrap().lock().unwrap().dispatch(job!(
+        format!("Retry UI_DEV_CREATE of fh {}", fh),
+        JobTarget::Container(vuinput_state.requesting_process.clone()),
+        async move {
+            Timer::after(backoff).await;
+            // a released handle has answered the request already
+            let Ok(vuinput_state_mutex) = get_vuinput_state(&VuFileHandle::Fh(fh)) else {
+                return;
+            };
+            let mut vuinput_state = vuinput_state_mutex.lock().unwrap();
+            let Some(reply) = vuinput_state.pending_create.take() else {
+                debug!(
+                    "fh {}: UI_DEV_CREATE has been cancelled before its retry",
+                    fh
+                );
+                return;
+            };
+            create_again(fh, reply, &mut vuinput_state, attempt + 1);
+        }
+    ));
+}
+
+/// UI_DEV_CREATE of a request that has been parked, after the approval or for a retry
+fn create_again(
+    fh: u64,
+    reply: PendingReply,
+    vuinput_state: &mut VuInputState,
+    create_attempt: u32,
+) {
+    if vuinput_state.revoked {
+        reply.err(ENODEV);
+        return;
+    }
+    let req = reply.into_req();
+    let call = IoctlCall {
+        fh,
+        cmd: UI_DEV_CREATE,
+        compat: false,
+        arg: std::ptr::null_mut(),
+        in_buf: std::ptr::null(),
+        in_bufsz: 0,
+        out_bufsz: 0,
+        approved: true,
+        create_attempt,
+    };
+    unsafe {
+        if let Err(error) = forward_ioctl(req, &call, vuinput_state) {
+            vuinput_state.ioctl_errors += 1;
+            warn!("fh {}: {} (errno {})", fh, error, error.errno());
+            fuse_lowlevel::fuse_reply_err(req, error.errno());
+        }
+    }
+}
+
+/// create_device, retried after transient errors up to limits.create-retries. It sleeps
+/// between the attempts, so it is for callers that don't serve CUSE requests (the portal,
+/// restoring persisted devices); the ioctl is retried by a job, see retry_create.
+///
+/// # Safety
+/// As for create_device.
+pub unsafe fn create_device_retrying(fh: u64, fd: c_int) -> Result<VuInputDevice, VuIoctlError> {
+    let retries = get_create_retries();
+    let mut attempt = 0;
+    loop {
+        match create_device(fh, fd) {
+            Err(VuIoctlError::Host {
+                ioctl: "UI_DEV_CREATE",
+                errno,
+            }) if is_transient(errno) && attempt < retries => {
+                let backoff = create_backoff(attempt);
+                warn!(
+                    "fh {}: UI_DEV_CREATE failed with {}, retrying in {:?}",
+                    fh, errno, backoff
+                );
+                std::thread::sleep(backoff);
+                attempt += 1;
+            }
+            // the errno of the last attempt is the one the caller gets
+            result => return result,
+        }
+    }
+}
+
+/// UI_DEV_CREATE on the host and the lookup of the new event node. If the node can't be
+/// found, the device is destroyed again, as it could never be handed to the container.
+/// A single attempt, see create_device_retrying.
+///
+/// # Safety
+/// `fd` must be an open uinput fd whose device has been set up but not created.
+pub unsafe fn create_device(fh: u64, fd: c_int) -> Result<VuInputDevice, VuIoctlError> {
+    fault_injection::fail("UI_DEV_CREATE")
+        .and_then(|()| ui_dev_create(fd))
+        .map_err(VuIoctlError::host("UI_DEV_CREATE"))?;
+
+    let mut resultbuf: [c_char; 64] = [0; 64];
+    if let Err(errno) = ui_get_sysname(fd, resultbuf.as_mut_slice()) {
+        let _ = ui_dev_destroy(fd);
+        return Err(VuIoctlError::Host {
+            ioctl: "UI_GET_SYSNAME",
+            errno,
+        });
+    }
+    let name = CStr::from_ptr(resultbuf.as_ptr())
+        .to_string_lossy()
+        .into_owned();
+    let sysname = format!("{}{}", SYS_INPUT_DIR, name);
+    debug!("fh {}: syspath: {}", fh, sysname);
+    // the event node shows up in sysfs after the input device, sometimes only after the ioctl
+    let lookup = retry_while_not_found(NODE_LOOKUP_ATTEMPTS, NODE_LOOKUP_DELAY, || {
+        let (devname, devnode) = fetch_device_node(&host_path(&sysname))?;
+        let (major, minor) = fetch_major_minor(&devnode)?;
+        Ok((devname, devnode, major, minor))
+    });
+    let (devname, devnode, major, minor) = match lookup {
+        Ok(found) => found,
+        Err(error) => {
+            let _ = ui_dev_destroy(fd);
+            if let Some(message) = sysfs_input::describe(&sysfs_input::sysfs_status()) {
+                return Err(VuIoctlError::SysfsNotVisible { message });
+            }
+            return Err(VuIoctlError::DeviceNode {
+                syspath: sysname,
+                error,
+            });
+        }
+    };
+    debug!(
+        "fh {}: devnode: {} major: {} minor: {}",
+        fh, devnode, major, minor
+    );
+    Ok(VuInputDevice {
+        major,
+        minor,
+        sysname: name,
+        syspath: sysname,
+        devname,
+        devnode,
+        serial: None,
+        mirrors: Vec::new(),
+        seat: Default::default(),
+        container_devnode: Default::default(),
+    })
+}
+
+/// Forwards a UI_SET_*BIT request, if the device policy allows the bit, and records it.
+/// `type_` is the event type of the code, None for UI_SET_EVBIT, where the value is the
+/// event type itself. Values out of range are left to the kernel to reject.
+unsafe fn set_capability_bit(
+    req: fuse_lowlevel::fuse_req_t,
+    fh: u64,
+    vuinput_state: &mut VuInputState,
+    name: &'static str,
+    capability: Capability,
+    value: c_uint,
+    set_bit: unsafe fn(c_int, nix::sys::ioctl::ioctl_param_type) -> nix::Result<c_int>,
+) -> Result<(), VuIoctlError> {
+    let bit_name = match u16::try_from(value) {
+        Ok(bit) => capability.bit_name(bit),
+        Err(_) => value.to_string(),
+    };
+    debug!("fh {}: ioctl {} {}", fh, name, bit_name);
+    if let Ok(bit) = u16::try_from(value) {
+        if !is_capability_allowed(req, fh, vuinput_state, name, capability, bit) {
+            return Ok(());
+        }
+    }
+
+    set_bit(vuinput_state.file.as_raw_fd(), value.into()).map_err(VuIoctlError::host(name))?;
+    if let Ok(bit) = u16::try_from(value) {
+        capability.record(&mut vuinput_state.descriptor, bit);
+    }
+    fuse_lowlevel::fuse_reply_ioctl(req, 0, std::ptr::null(), 0);
+    Ok(())
+}
+
+/// What a UI_SET_*BIT request declares
+#[derive(Debug, Clone, Copy)]
+pub enum Capability {
+    /// UI_SET_EVBIT
+    EventType,
+    /// UI_SET_KEYBIT, UI_SET_ABSBIT, etc. with their event type
+    Code(u16),
+    /// UI_SET_PROPBIT
+    Property,
+}
+
+impl Capability {
+    pub fn bit_name(self, bit: u16) -> String {
+        match self {
+            Capability::EventType => TypeName(bit).to_string(),
+            Capability::Code(type_) => CodeName(type_, bit).to_string(),
+            Capability::Property => PropName(bit).to_string(),
+        }
+    }
+
+    /// Whether the device policy lets the bit be declared
+    pub fn is_allowed(self, policy: &DevicePolicy, gamepad_extra_keys: &[u16], bit: u16) -> bool {
+        match self {
+            Capability::EventType => device_policy::is_event_type_allowed(policy, bit),
+            Capability::Code(type_) => {
+                device_policy::is_code_allowed(policy, gamepad_extra_keys, type_, bit)
+            }
+            Capability::Property => device_policy::is_property_allowed(policy, bit),
+        }
+    }
+
+    /// Adds the bit the host uinput has accepted to the capabilities of the device
+    pub fn record(self, descriptor: &mut DeviceDescriptor, bit: u16) {
+        match self {
+            Capability::EventType => {
+                descriptor.event_types.insert(bit);
+            }
+            Capability::Code(type_) => {
+                descriptor.codes.entry(type_).or_default().insert(bit);
+            }
+            Capability::Property => {
+                descriptor.properties.insert(bit);
+            }
+        }
+    }
+}
+
+/// Checks a capability against the device policy. A rejected one is recorded and answered
+/// with EPERM.
+unsafe fn is_capability_allowed(
+    req: fuse_lowlevel::fuse_req_t,
+    fh: u64,
+    vuinput_state: &mut VuInputState,
+    name: &str,
+    capability: Capability,
+    bit: u16,
+) -> bool {
+    let config = get_reloadable_config();
+    let policy = vuinput_state.policy(&config);
+    let allowed = capability.is_allowed(&policy, &config.gamepad_extra_keys, bit);
+    let bit_name = capability.bit_name(bit);
+    if !allowed {
+        warn!(
+            "fh {}: {} {} rejected by the device policy {:?}",
+            fh, name, bit_name, policy
+        );
+        vuinput_state
+            .descriptor
+            .rejected
+            .insert(format!("{} {}", name, bit_name));
+        events::policy_violation(fh, vuinput_state, format_args!("{} {}", name, bit_name));
+        fuse_lowlevel::fuse_reply_err(req, EPERM);
+    }
+    allowed
+}
+
+/// The answer to VUI_GET_INFO
+fn vuinput_info(vuinput_state: &VuInputState) -> VuinputInfo {
+    let mut info = VuinputInfo {
+        lifecycle: match vuinput_state.lifecycle {
+            DeviceLifecycle::Opened => LIFECYCLE_OPENED,
+            DeviceLifecycle::Created => LIFECYCLE_CREATED,
+            DeviceLifecycle::Destroyed => LIFECYCLE_DESTROYED,
+        },
+        rejected: vuinput_state.descriptor.rejected.len() as u32,
+        ..Default::default()
+    };
+    copy_str(
+        &mut info.policy,
+        &value_name(&vuinput_state.policy(&get_reloadable_config())),
+    );
+    if let Some(input_device) = &vuinput_state.input_device {
+        info.major = input_device.major as u32;
+        info.minor = input_device.minor as u32;
+        copy_str(&mut info.devnode, &input_device.devnode);
+    }
+    info
+}
+
+/// Answers VUI_GET_ALLOWED_BITS with the bitmap of the policy of the handle
+unsafe fn reply_allowed_bits(
+    req: fuse_lowlevel::fuse_req_t,
+    fh: u64,
+    vuinput_state: &VuInputState,
+    type_: u16,
+    out_bufsz: size_t,
+) {
+    let config = get_reloadable_config();
+    let policy = vuinput_state.policy(&config);
+    let len = out_bufsz.min(ALLOWED_BITS_MAX_LEN);
+    debug!(
+        "fh {}: ioctl VUI_GET_ALLOWED_BITS({}, {})",
+        fh,
+        TypeName(type_),
+        len
+    );
+    let bitmap = device_policy::allowed_bitmap(&policy, &config.gamepad_extra_keys, type_, len);
+    fuse_lowlevel::fuse_reply_ioctl(req, len as c_int, bitmap.as_ptr() as *const c_void, len);
+}
+
+/// The size encoded in an ioctl number, e.g. the len of UI_GET_SYSNAME(len)
+fn ioctl_size(cmd: u64) -> usize {
+    ((cmd >> nix::sys::ioctl::SIZESHIFT) & nix::sys::ioctl::SIZEMASK) as usize
+}
+
+/// A struct uinput_abs_setup from a client that may be shorter than ours. None, if it is
+/// longer (E2BIG in the kernel).
+fn abs_setup_from_buffer(buffer: &[u8]) -> Option<uinput_abs_setup> {
+    if buffer.len() > ::std::mem::size_of::<uinput_abs_setup>() {
+        return None;
+    }
+    let mut setup: uinput_abs_setup = unsafe { ::std::mem::zeroed() };
+    unsafe {
+        std::ptr::copy_nonoverlapping(
+            buffer.as_ptr(),
+            &mut setup as *mut uinput_abs_setup as *mut u8,
+            buffer.len(),
+        )
+    };
+    Some(setup)
+}
+
+/// Lookups of the event node of a new device, NODE_LOOKUP_DELAY apart
+const NODE_LOOKUP_ATTEMPTS: u32 = 10;
+const NODE_LOOKUP_DELAY: std::time::Duration = std::time::Duration::from_millis(20);
+
+/// Calls `lookup` up to `attempts` times, as long as it fails with NotFound
+fn retry_while_not_found<T>(
+    attempts: u32,
+    delay: std::time::Duration,
+    mut lookup: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let mut attempt = 1;
+    loop {
+        match lookup() {
+            Err(e) if e.kind() == ErrorKind::NotFound && attempt < attempts => {
+                debug!("{}, looking again in {:?}", e, delay);
+                std::thread::sleep(delay);
+                attempt += 1;
+            }
+            result => return result,
         }
     }
 }
@@ -430,7 +1455,10 @@ pub fn fetch_device_node(path: &str) -> io::Result<(String, String)> {
         }
     }
     // If no device is found, return an error
-    Err(io::Error::new(ErrorKind::NotFound, "no device found"))
+    Err(io::Error::new(
+        ErrorKind::NotFound,
+        format!("no event node in {}", path),
+    ))
 }
 
 /// Returns (major, minor) numbers of a device node at `path`
@@ -451,3 +1479,69 @@ pub fn fetch_major_minor(path: &str) -> io::Result<(u64, u64)> {
 
     Ok((major, minor))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn abs_setup_may_be_shorter() {
+        assert_eq!(
+            ioctl_size(UI_ABS_SETUP),
+            std::mem::size_of::<uinput_abs_setup>()
+        );
+        assert_eq!(
+            UI_ABS_SETUP & !(nix::sys::ioctl::SIZEMASK << nix::sys::ioctl::SIZESHIFT),
+            UI_ABS_SETUP_WITHOUT_SIZE
+        );
+
+        // code ABS_Y, value 0, minimum -100, maximum 100, fuzz and the rest cut off
+        let mut buffer = vec![0x01, 0x00, 0x00, 0x00, 0, 0, 0, 0];
+        buffer.extend_from_slice(&(-100i32).to_ne_bytes());
+        buffer.extend_from_slice(&100i32.to_ne_bytes());
+        let setup = abs_setup_from_buffer(&buffer).unwrap();
+        assert_eq!(setup.code, 0x01);
+        assert_eq!(
+            AbsInfo::from(setup.absinfo),
+            AbsInfo {
+                minimum: -100,
+                maximum: 100,
+                ..Default::default()
+            }
+        );
+
+        let too_long = vec![0u8; std::mem::size_of::<uinput_abs_setup>() + 1];
+        assert!(abs_setup_from_buffer(&too_long).is_none());
+    }
+
+    #[test]
+    fn event_node_lookup_waits_for_sysfs() {
+        let no_delay = std::time::Duration::ZERO;
+        let mut lookups = 0;
+        let found = retry_while_not_found(10, no_delay, || {
+            lookups += 1;
+            match lookups {
+                1 | 2 => Err(io::Error::new(ErrorKind::NotFound, "no event node")),
+                _ => Ok("event7"),
+            }
+        });
+        assert_eq!(found.unwrap(), "event7");
+        assert_eq!(lookups, 3);
+
+        lookups = 0;
+        let never = retry_while_not_found(3, no_delay, || -> io::Result<()> {
+            lookups += 1;
+            Err(io::Error::new(ErrorKind::NotFound, "no event node"))
+        });
+        assert_eq!(never.unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(lookups, 3);
+
+        lookups = 0;
+        let denied = retry_while_not_found(3, no_delay, || -> io::Result<()> {
+            lookups += 1;
+            Err(io::Error::from_raw_os_error(libc::EACCES))
+        });
+        assert_eq!(denied.unwrap_err().raw_os_error(), Some(libc::EACCES));
+        assert_eq!(lookups, 1);
+    }
+}
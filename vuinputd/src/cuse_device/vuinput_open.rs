@@ -6,7 +6,7 @@ use ::cuse_lowlevel::*;
 use libc::ENOENT;
 use libc::O_CLOEXEC;
 use libc::O_NONBLOCK;
-use log::{debug, error};
+use log::{debug, error, warn};
 use std::fs::OpenOptions;
 use std::os::fd::AsFd;
 use std::os::unix::fs::OpenOptionsExt;
@@ -14,32 +14,59 @@ use std::path::Path;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::OnceLock;
 
+use crate::cuse_device::container_rules::rule_policy;
+use crate::cuse_device::device_policy::container_policy;
+use crate::cuse_device::drop_counters::{DropCounters, DropWindow};
 use crate::cuse_device::evdev_write_watcher::EVDEV_WRITE_WATCHER;
+use crate::cuse_device::op_history::OpHistory;
+use crate::cuse_device::session_manager::node_of_request;
 use crate::cuse_device::*;
-use crate::process_tools::{get_requesting_process, Pid};
+use crate::global_config::get_reloadable_config;
+use crate::process_tools::pid_translation::translate_pid;
+use crate::process_tools::{get_requesting_process, Pid, RequestingProcess};
 
 pub static VUINPUT_COUNTER: OnceLock<AtomicU64> = OnceLock::new();
 
-fn get_fresh_filehandle() -> u64 {
+/// The handles of all nodes are counted together, vuhid included
+pub fn get_fresh_filehandle() -> u64 {
     let ctr = VUINPUT_COUNTER.get().unwrap();
     ctr.fetch_add(1, Ordering::SeqCst).into()
 }
 
+/// The process that opens the handle `fh`
+///
+/// # Safety
+/// `req` must be a pending open request.
+pub unsafe fn requesting_process_of(req: fuse_lowlevel::fuse_req_t, fh: u64) -> RequestingProcess {
+    let ctx = fuse_lowlevel::fuse_req_ctx(req);
+    debug!("fh {}: opened by process id {} (host view)", fh, (*ctx).pid);
+    let request_pid: u32 = (*ctx)
+        .pid
+        .try_into()
+        .expect("pid must be a positive integer");
+    let pid = match translate_pid(request_pid) {
+        Ok(pid) => pid,
+        Err(e) => {
+            warn!("fh {}: {}", fh, e);
+            Pid::Pid(request_pid)
+        }
+    };
+    let requesting_process = get_requesting_process(pid);
+    debug!("fh {}: requested by {}", fh, requesting_process);
+    requesting_process
+}
+
+/// Opens a handle on a fresh host uinput fd for the process that opened the node
+///
+/// # Safety
+/// Only to be called by libfuse: `_req` must be a pending open request and `_fi` its file
+/// info.
 pub unsafe extern "C" fn vuinput_open(
     _req: fuse_lowlevel::fuse_req_t,
     _fi: *mut fuse_lowlevel::fuse_file_info,
 ) {
     let fh = get_fresh_filehandle();
-    let ctx = fuse_lowlevel::fuse_req_ctx(_req);
-    debug!("fh {}: opened by process id {} (host view)", fh, (*ctx).pid);
-    let pid = Pid::Pid(
-        (*ctx)
-            .pid
-            .try_into()
-            .expect("pid must be a positive integer"),
-    );
-    let requesting_process = get_requesting_process(pid);
-    debug!("fh {}: namespaces {}", fh, requesting_process);
+    let requesting_process = requesting_process_of(_req, fh);
     // namespaces net:4026531840, uts:4026531838, ipc:4026531839, pid:4026531836, pid_for_children:4026531836, user:4026531837, mnt:4026531841, cgroup:4026531835, time:4026531834, time_for_children:4026531834
     (*_fi).fh = fh;
     // Open the path, returns `io::Result<File>`
@@ -51,14 +78,36 @@ pub unsafe extern "C" fn vuinput_open(
     match open_vuinput_result {
         Ok(v) => {
             let vu_fh: VuFileHandle = VuFileHandle::Fh(fh);
+            let policy_override = container_policy(requesting_process.pid_requestor_root);
+            let rule_policy = rule_policy(
+                &get_reloadable_config().container_rules,
+                requesting_process.identity.as_deref(),
+            );
+            let node_policy = node_of_request(_req).and_then(|node| node.policy);
             insert_vuinput_state(
                 &vu_fh,
                 VuInputState {
                     file: v,
                     requesting_process,
                     input_device: None,
+                    lifecycle: DeviceLifecycle::Opened,
                     keytracker: KeyTracker::new(),
                     poll: PollState::new(),
+                    pending_read: None,
+                    pending_create: None,
+                    descriptor: DeviceDescriptor::default(),
+                    policy_override,
+                    rule_policy,
+                    node_policy,
+                    revoked: false,
+                    label: None,
+                    events_forwarded: 0,
+                    drops: DropCounters::default(),
+                    drop_window: DropWindow::default(),
+                    ioctl_errors: 0,
+                    write_failures: 0,
+                    last_reconnect: None,
+                    history: OpHistory::new(),
                 },
             )
             .unwrap();
@@ -5,9 +5,9 @@
 use crate::cuse_device::*;
 use crate::global_config::get_device_policy;
 use ::cuse_lowlevel::*;
-use libc::{__s32, __u16, input_event, POLLIN};
 use libc::{off_t, size_t, EIO};
 use libc::{uinput_abs_setup, uinput_setup};
+use libc::{POLLERR, POLLIN, POLLOUT, POLLRDNORM, POLLWRNORM};
 use log::{debug, trace};
 use std::io::{Read, Write};
 use std::os::fd::AsRawFd;
@@ -21,39 +21,40 @@ use uinput_ioctls::*;
 // Note that poll in fuse blocks (because it calls fuse_simple_request, which is designed to block)
 // until the handle is notified.
 
+// Like uinput_poll, the device is always writable and readable as long as the queue with
+// force-feedback requests is not empty. When nothing can be read, the poll handle is kept
+// and the evdev write watcher notifies it once the host fd becomes readable.
 pub unsafe extern "C" fn vuinput_poll(
     req: fuse_lowlevel::fuse_req_t,
     fi: *mut fuse_lowlevel::fuse_file_info,
     ph: *mut fuse_lowlevel::fuse_pollhandle,
 ) {
-    //fuse_lowlevel::fuse_reply_err(req, EIO);
-    //return;
-
     let vuinput_state_mutex =
         get_vuinput_state(&VuFileHandle::from_fuse_file_info(fi.as_ref().unwrap())).unwrap();
     let mut vuinput_state = vuinput_state_mutex.lock().unwrap();
 
+    let mut revents = POLLOUT | POLLWRNORM;
+    if vuinput_state.revoked {
+        // every further request fails with ENODEV, see revoke
+        if !ph.is_null() {
+            fuse_lowlevel::fuse_pollhandle_destroy(ph);
+        }
+        fuse_lowlevel::fuse_reply_poll(req, (POLLIN | POLLERR) as u32);
+        return;
+    }
     match vuinput_state.poll.pollphase {
         PollPhase::Empty => {
-            if ph != std::ptr::null_mut() {
-                let ph = NonNull::<fuse_lowlevel::fuse_pollhandle>::new(ph);
-                vuinput_state.poll.set_waiter(ph.unwrap());
-            }
-            fuse_lowlevel::fuse_reply_poll(req, 0);
-        }
-        PollPhase::Readable => {
-            if ph != std::ptr::null_mut() {
-                fuse_lowlevel::fuse_lowlevel_notify_poll(ph);
-                fuse_lowlevel::fuse_pollhandle_destroy(ph);
+            if let Some(ph) = NonNull::new(ph) {
+                vuinput_state.poll.set_waiter(ph);
             }
-            fuse_lowlevel::fuse_reply_poll(req, POLLIN.try_into().unwrap());
         }
-        PollPhase::Reading => {
-            if ph != std::ptr::null_mut() {
-                fuse_lowlevel::fuse_lowlevel_notify_poll(ph);
+        PollPhase::Readable | PollPhase::Reading => {
+            revents |= POLLIN | POLLRDNORM;
+            // there is nothing to wait for, the kernel does not need a notification
+            if !ph.is_null() {
                 fuse_lowlevel::fuse_pollhandle_destroy(ph);
             }
-            fuse_lowlevel::fuse_reply_poll(req, POLLIN.try_into().unwrap());
         }
     }
+    fuse_lowlevel::fuse_reply_poll(req, revents as u32);
 }
@@ -2,16 +2,28 @@
 //
 // Author: Johannes Leupolz <dev@leupolz.eu>
 
+// read() hands out the events uinput queues for the client: the EV_UINPUT requests
+// UI_FF_UPLOAD/UI_FF_ERASE and the EV_FF play/stop events of force-feedback devices.
+// The uinput fd on the host is always non-blocking, so that the CUSE thread never sleeps
+// in read(). A blocking read() of the client is parked instead and answered by the evdev
+// write watcher as soon as the fd becomes readable.
+
+use crate::cuse_device::vuinput_write::{input_event_compat, map_to_compat};
 use crate::cuse_device::*;
 use ::cuse_lowlevel::*;
-use libc::{__s32, __u16, input_event, EAGAIN};
+use libc::{c_int, c_void, input_event, EAGAIN, EINTR, EINVAL, ENODEV, O_NONBLOCK};
 use libc::{off_t, size_t, EIO};
-use log::{debug, trace};
-use std::io::{Read, Write};
-use std::os::fd::AsRawFd;
-use uinput_ioctls::*;
+use log::debug;
+use std::io::Read;
+use std::sync::TryLockError;
+use std::thread;
+
+/// uinput queues at most this many events per device (UINPUT_BUFFER_SIZE in uinput.h),
+/// so a single read can't return more.
+const UINPUT_BUFFER_SIZE: usize = 16;
+
+const NORMAL_SIZE: usize = std::mem::size_of::<input_event>();
 
-// TODO: compat-mode+ ensure sizeof(struct input_event)
 pub unsafe extern "C" fn vuinput_read(
     _req: fuse_lowlevel::fuse_req_t,
     _size: size_t,
@@ -23,54 +35,163 @@ pub unsafe extern "C" fn vuinput_read(
         "vuinput_read: offset needs to be 0 but is {}",
         _off
     );
-    //fuse_lowlevel::fuse_reply_err(_req, EIO);
-    //return;
 
-    let fh = &(*_fi).fh;
+    let fh = (*_fi).fh;
+    let nonblocking = (*_fi).flags & O_NONBLOCK != 0;
+    let _turn = op_sequencer::enter(fh);
+    if !nonblocking {
+        // before the lock of the handle, see park_read
+        fuse_lowlevel::fuse_req_interrupt_func(_req, Some(interrupt_read), fh as *mut c_void);
+    }
     let vuinput_state_mutex =
         get_vuinput_state(&VuFileHandle::from_fuse_file_info(_fi.as_ref().unwrap())).unwrap();
     let mut vuinput_state = vuinput_state_mutex.lock().unwrap();
 
-    const NORMAL_SIZE: usize = std::mem::size_of::<libc::input_event>();
-    let is_compat = vuinput_state.requesting_process.is_compat;
-    // TODO: ARM: && !compat_uses_64bit_time()
+    if vuinput_state.revoked {
+        fuse_lowlevel::fuse_reply_err(_req, ENODEV);
+        return;
+    }
+
+    match read_events(fh, &mut vuinput_state, _size) {
+        Ok(buffer) => {
+            fuse_lowlevel::fuse_reply_buf(_req, buffer.as_ptr() as *const i8, buffer.len());
+        }
+        Err(EAGAIN) if !nonblocking => park_read(&mut vuinput_state, _req, _size),
+        Err(errno) => {
+            fuse_lowlevel::fuse_reply_err(_req, errno);
+        }
+    }
+}
 
-    let mut buffer: [u8; 24] = [0; 24];
+/// Answers a parked read, called by the evdev write watcher once the fd became readable.
+/// A wakeup without data leaves the read parked.
+pub fn complete_pending_read(fh: u64, vuinput_state: &mut VuInputState, pending: PendingRead) {
+    match read_events(fh, vuinput_state, pending.size) {
+        Ok(buffer) => unsafe {
+            fuse_lowlevel::fuse_reply_buf(pending.req, buffer.as_ptr() as *const i8, buffer.len());
+        },
+        Err(EAGAIN) => vuinput_state.pending_read = Some(pending),
+        Err(errno) => unsafe {
+            fuse_lowlevel::fuse_reply_err(pending.req, errno);
+        },
+    }
+}
 
-    vuinput_state.poll.pollphase = PollPhase::Reading;
-    // read up to 24 bytes
-    //println!("vuinput_read: read");
-    let result = vuinput_state.file.read(&mut buffer);
+/// Reads as many whole events as fit into `size` bytes and returns them in the layout of
+/// the requesting process. Errors are returned as errno.
+fn read_events(fh: u64, vuinput_state: &mut VuInputState, size: usize) -> Result<Vec<u8>, c_int> {
+    let is_compat = vuinput_state.requesting_process.is_compat;
+    let event_size = if is_compat {
+        std::mem::size_of::<input_event_compat>()
+    } else {
+        NORMAL_SIZE
+    };
+    let count = (size / event_size).min(UINPUT_BUFFER_SIZE);
+    if count == 0 {
+        // same as uinput for buffers that can't hold a single event
+        return Err(EINVAL);
+    }
 
-    //println!("vuinput_read: read finished");
-    match result {
-        Ok(NORMAL_SIZE) => {
-            if !is_compat {
-                let buffer = buffer.as_ptr() as *const i8;
-                fuse_lowlevel::fuse_reply_buf(_req, buffer, 24);
-            } else {
-                debug!(
-                    "fh {}: error reading from uinput: not implemented yet for 32 bit users",
-                    fh
-                );
-                // details how to implement it can be found in vuinput_write.rs
-                fuse_lowlevel::fuse_reply_err(_req, EIO);
-            }
+    let mut buffer = vec![0u8; count * NORMAL_SIZE];
+    vuinput_state.poll.pollphase = PollPhase::Reading;
+    let bytes = match vuinput_state.file.read(&mut buffer) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+            vuinput_state.poll.pollphase = PollPhase::Empty;
+            return Err(EAGAIN);
         }
         Err(e) => {
-            if e.kind() == io::ErrorKind::WouldBlock {
-                // EAGAIN / EWOULDBLOCK
-                //println!("Received EAGAIN: The read would block!");
-                vuinput_state.poll.pollphase = PollPhase::Empty;
-                fuse_lowlevel::fuse_reply_err(_req, EAGAIN);
-            } else {
-                debug!("fh {}: error reading from uinput: {e:?}", fh);
-                fuse_lowlevel::fuse_reply_err(_req, EIO);
-            }
+            debug!("fh {}: error reading from uinput: {e:?}", fh);
+            return Err(e.raw_os_error().unwrap_or(EIO));
         }
-        Ok(_) => {
-            debug!("fh {}: error reading from uinput: wrong size", fh);
-            fuse_lowlevel::fuse_reply_err(_req, EIO);
+    };
+    if bytes % NORMAL_SIZE != 0 {
+        debug!("fh {}: error reading from uinput: wrong size {}", fh, bytes);
+        return Err(EIO);
+    }
+    if bytes < buffer.len() {
+        // uinput hands out everything that fits, so the queue has been drained
+        vuinput_state.poll.pollphase = PollPhase::Empty;
+    }
+    buffer.truncate(bytes);
+
+    if is_compat {
+        buffer = buffer
+            .chunks_exact(NORMAL_SIZE)
+            .flat_map(|chunk| {
+                let event =
+                    unsafe { std::ptr::read_unaligned(chunk.as_ptr() as *const input_event) };
+                let compat = map_to_compat(&event);
+                unsafe {
+                    std::slice::from_raw_parts(
+                        &compat as *const input_event_compat as *const u8,
+                        std::mem::size_of::<input_event_compat>(),
+                    )
+                }
+                .to_vec()
+            })
+            .collect();
+    }
+    protocol_dump::dump_input_events(fh, "read", &buffer, is_compat);
+    Ok(buffer)
+}
+
+/// Keeps a blocking read open until there is something to read.
+///
+/// vuinput_read registers the interrupt callback before it takes the lock of the handle.
+/// libfuse calls the callback with the lock of the request held, on any thread of the
+/// session, and right away from fuse_req_interrupt_func if the interrupt came first. Under
+/// the lock of the handle, the registration would deadlock with the callback on another
+/// thread, or with itself. An interrupt that came before the read is parked is caught by
+/// fuse_req_interrupted here, a later one by the callback.
+unsafe fn park_read(vuinput_state: &mut VuInputState, req: fuse_lowlevel::fuse_req_t, size: usize) {
+    if fuse_lowlevel::fuse_req_interrupted(req) != 0 {
+        fuse_lowlevel::fuse_reply_err(req, EINTR);
+        return;
+    }
+    if let Some(previous) = vuinput_state
+        .pending_read
+        .replace(PendingRead { req, size })
+    {
+        // only one blocking reader per handle is supported, the older one gets an error
+        fuse_lowlevel::fuse_reply_err(previous.req, EIO);
+    }
+}
+
+/// Called by libfuse when the client got a signal while its read is blocking. The callback
+/// runs with the lock of the request held and does not wait for the handle: if a handler or
+/// the watcher holds it, a thread answers the read once the handle is free.
+unsafe extern "C" fn interrupt_read(req: fuse_lowlevel::fuse_req_t, data: *mut c_void) {
+    let fh = data as u64;
+    let Ok(vuinput_state_mutex) = get_vuinput_state(&VuFileHandle::Fh(fh)) else {
+        return;
+    };
+    match vuinput_state_mutex.try_lock() {
+        Ok(mut vuinput_state) => {
+            return answer_interrupted(fh, &mut vuinput_state.pending_read, req);
         }
+        Err(TryLockError::Poisoned(_)) => return,
+        Err(TryLockError::WouldBlock) => {}
+    }
+    // a parked request stays valid until it is answered, so only a parked one is used
+    let req = req as usize;
+    thread::spawn(move || {
+        let mut vuinput_state = vuinput_state_mutex.lock().unwrap();
+        answer_interrupted(fh, &mut vuinput_state.pending_read, req as _);
+    });
+}
+
+/// Fails the parked read with EINTR, if it is `req` and has been interrupted
+pub fn answer_interrupted(
+    fh: u64,
+    pending_read: &mut Option<PendingRead>,
+    req: fuse_lowlevel::fuse_req_t,
+) {
+    let interrupted = pending_read.as_ref().is_some_and(|pending| {
+        pending.req == req && unsafe { fuse_lowlevel::fuse_req_interrupted(pending.req) } != 0
+    });
+    if let (true, Some(pending)) = (interrupted, pending_read.take()) {
+        debug!("fh {}: blocking read interrupted", fh);
+        unsafe { fuse_lowlevel::fuse_reply_err(pending.req, EINTR) };
     }
 }
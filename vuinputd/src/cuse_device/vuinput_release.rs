@@ -2,12 +2,15 @@
 //
 // Author: Johannes Leupolz <dev@leupolz.eu>
 
+use crate::control::events;
 use crate::cuse_device::evdev_write_watcher::EVDEV_WRITE_WATCHER;
+use crate::cuse_device::*;
+use crate::cuse_device::{device_limits, device_serial};
 use crate::job_engine::JOB_DISPATCHER;
 use crate::jobs::remove_device_job::RemoveDeviceJob;
 use crate::process_tools::SELF_NAMESPACES;
-use crate::{cuse_device::*, jobs};
 use ::cuse_lowlevel::*;
+use libc::EIO;
 use log::debug;
 use std::os::fd::AsFd;
 use std::sync::Arc;
@@ -19,36 +22,18 @@ pub unsafe extern "C" fn vuinput_release(
     let fh = &(*_fi).fh;
     let vu_fh = VuFileHandle::from_fuse_file_info(_fi.as_ref().unwrap());
     let vuinput_state_mutex = remove_vuinput_state(&vu_fh).unwrap();
+    op_sequencer::forget(*fh);
 
     let mut vuinput_state = vuinput_state_mutex.lock().unwrap();
     let input_device = vuinput_state.input_device.take();
-
-    // Remove device in container, if the request was really from another namespace
-    // Only do this in case it has not already been done by the ioctl UI_DEV_DESTROY
-    // this here is relevant if the process was killed and didn't have the chance to send the
-    // ioctl UI_DEV_DESTROY.
-    if input_device.is_some()
-        && !SELF_NAMESPACES
-            .get()
-            .unwrap()
-            .equal_mnt_and_net(&vuinput_state.requesting_process.namespaces)
-    {
-        let input_device = input_device.unwrap();
-        let remove_job = RemoveDeviceJob::new(
-            vuinput_state.requesting_process.clone(),
-            input_device.devname.clone(),
-            input_device.syspath.clone(),
-            input_device.major,
-            input_device.minor,
-        );
-        let awaiter = remove_job.get_awaiter_for_state();
-        JOB_DISPATCHER
-            .get()
-            .unwrap()
-            .lock()
-            .unwrap()
-            .dispatch(Box::new(remove_job));
-        awaiter(&jobs::remove_device_job::State::Finished);
+    if let Some(input_device) = &input_device {
+        events::device_removed(*fh, &vuinput_state, &input_device.devnode);
+        broadcast::remove_mirrors(*fh, input_device);
+    }
+    let requesting_process = vuinput_state.requesting_process.clone();
+    if let Some(pending) = vuinput_state.pending_read.take() {
+        // a blocking read keeps the file open, so this should not happen
+        fuse_lowlevel::fuse_reply_err(pending.req, EIO);
     }
 
     EVDEV_WRITE_WATCHER
@@ -67,7 +52,45 @@ pub unsafe extern "C" fn vuinput_release(
         Arc::strong_count(&vuinput_state_mutex)
     );
     drop(vuinput_state_mutex); // this also closes the file when no other references are open
-                               // TODO: maybe also ensure that nothing is left in the containers
+                               // and thereby destroys the device on the host
+
+    // Remove device in container, if the request was really from another namespace
+    // Only do this in case it has not already been done by the ioctl UI_DEV_DESTROY
+    // this here is relevant if the process was killed and didn't have the chance to send the
+    // ioctl UI_DEV_DESTROY.
+    // The cleanup is not awaited: the container might be dead and time out, and blocking
+    // here would stall the CUSE thread and thereby all devices. The job queue of the
+    // container keeps the cleanup ordered with later jobs of the same container, and
+    // RemoveDeviceJob retries failing steps itself.
+    if let Some(input_device) = input_device {
+        if !SELF_NAMESPACES
+            .get()
+            .unwrap()
+            .equal_mnt_and_net(&requesting_process.namespaces)
+        {
+            device_limits::release(&requesting_process.namespaces);
+            if let Some(serial) = &input_device.serial {
+                device_serial::release(serial);
+            }
+            let remove_job = RemoveDeviceJob::new(
+                requesting_process,
+                input_device.devname.clone(),
+                input_device.syspath.clone(),
+                input_device.major,
+                input_device.minor,
+            );
+            JOB_DISPATCHER
+                .get()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .dispatch(Box::new(remove_job));
+            debug!(
+                "fh {}: dispatched cleanup of {} in container",
+                fh, input_device.devname
+            );
+        }
+    }
 
     // Note: For CUSE, the kernel always issues RELEASE via fuse_sync_release(),
     // which forces a *synchronous* request (fuse_simple_request()).
@@ -2,19 +2,29 @@
 //
 // Author: Johannes Leupolz <dev@leupolz.eu>
 
+use crate::control::events;
+use crate::cuse_device::device_id::apply_id_policy;
+use crate::cuse_device::device_name::{apply_name_policy, NameRules};
+use crate::cuse_device::drop_counters::DropCause;
+use crate::cuse_device::reconnect::{Recovery, WriteFailure};
 use crate::cuse_device::*;
-use crate::global_config::get_device_policy;
+use crate::global_config::{get_reloadable_config, DevicePolicy, ReloadableConfig};
+use crate::metrics;
+use crate::session_lock;
 use ::cuse_lowlevel::*;
 use libc::{__s32, __u16, input_event};
-use libc::{off_t, size_t, EIO};
-use libc::{uinput_abs_setup, uinput_setup};
-use log::{debug, trace};
-use std::io::Write;
+use libc::{off_t, size_t, EINVAL, EIO, ENODEV};
+use log::{debug, trace, warn};
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::mem::size_of;
 use std::os::fd::AsRawFd;
 use std::os::raw::c_char;
-use uinput_ioctls::*;
+use std::time::Instant;
+
+/// A write before UI_DEV_CREATE, in the history of the handle
+const LEGACY_SETUP: &str = "write (legacy setup)";
 
-// TODO: compat-mode+ ensure sizeof(struct input_event)
 pub unsafe extern "C" fn vuinput_write(
     _req: fuse_lowlevel::fuse_req_t,
     _buf: *const c_char,
@@ -30,95 +40,159 @@ pub unsafe extern "C" fn vuinput_write(
 
     let fh = &(*_fi).fh;
     let slice = std::slice::from_raw_parts(_buf as *const u8, _size);
+    let _turn = op_sequencer::enter(*fh);
     let vuinput_state_mutex =
         get_vuinput_state(&VuFileHandle::from_fuse_file_info(_fi.as_ref().unwrap())).unwrap();
     let mut vuinput_state = vuinput_state_mutex.lock().unwrap();
 
+    if vuinput_state.revoked {
+        fuse_lowlevel::fuse_reply_err(_req, ENODEV);
+        return;
+    }
+
     if vuinput_state.input_device.is_none() {
+        let config = get_reloadable_config();
+        if !config.legacy_setup {
+            warn!(
+                "fh {}: refused legacy device setup, legacy-setup is disabled",
+                fh
+            );
+            events::policy_violation(*fh, &vuinput_state, "legacy-setup");
+            vuinput_state
+                .history
+                .record_write(LEGACY_SETUP, _size, Some(EINVAL));
+            fuse_lowlevel::fuse_reply_err(_req, EINVAL);
+            return;
+        }
         debug!(
-            "{}: legacy device setup recognized! Ignore the data and use hardcoded values",
+            "{}: legacy device setup recognized! Translating it to UI_DEV_SETUP and UI_ABS_SETUP",
             fh
         );
 
-        assert!(_size == std::mem::size_of::<libc::uinput_user_dev>());
-        let legacy_uinput_user_dev = _buf as *const libc::uinput_user_dev;
-
-        let mut usetup: uinput_setup = unsafe { std::mem::zeroed() };
-        usetup.id.bustype = BUS_USB;
-        // The pid is registered for vuinputd, see https://pid.codes/1209/5020/
-        usetup.id.vendor = 0x1209;
-        usetup.id.product = 0x5020;
-        usetup.id.version = (*legacy_uinput_user_dev).id.version;
-        usetup.ff_effects_max = (*legacy_uinput_user_dev).ff_effects_max;
-        usetup.name = (*legacy_uinput_user_dev).name;
-
-        // Call IOCTLs to setup and create the device
-        // Assuming your wrappers accept (fd, ptr_to_usetup) etc.
-        // We'll pass pointer to usetup
-        let usetup_ptr = &mut usetup as *mut uinput_setup;
-        let fd = vuinput_state.file.as_raw_fd();
-        ui_dev_setup(fd, usetup_ptr).unwrap();
-
-        // setup abs
-        for code in 0..libc::ABS_CNT {
-            if (*legacy_uinput_user_dev).absmax[code] != 0
-                || (*legacy_uinput_user_dev).absmin[code] != 0
-            {
-                let mut abs_setup: uinput_abs_setup = unsafe { std::mem::zeroed() };
-                abs_setup.code = code.try_into().unwrap();
-                abs_setup.absinfo.maximum = (*legacy_uinput_user_dev).absmax[code];
-                abs_setup.absinfo.minimum = (*legacy_uinput_user_dev).absmin[code];
-                abs_setup.absinfo.fuzz = (*legacy_uinput_user_dev).absfuzz[code];
-                abs_setup.absinfo.flat = (*legacy_uinput_user_dev).absflat[code];
-
-                let abs_setup_ptr = &mut abs_setup as *mut uinput_abs_setup;
-                ui_abs_setup(fd, abs_setup_ptr).unwrap();
+        let legacy = match legacy_setup::translate(slice, &vuinput_state.descriptor) {
+            Ok(legacy) => legacy,
+            Err(e) => {
+                warn!("fh {}: rejected legacy device setup ({})", fh, e);
+                vuinput_state
+                    .history
+                    .record_write(LEGACY_SETUP, _size, Some(EINVAL));
+                fuse_lowlevel::fuse_reply_err(_req, EINVAL);
+                return;
+            }
+        };
+        let mut usetup = legacy.setup;
+        let preserved_id =
+            apply_id_policy(&mut usetup.id, &config.id_policy, &config.passthrough_ids);
+        let container = vuinput_state.requesting_process.pid_requestor_root.as_raw();
+        match apply_name_policy(&mut usetup.name, &NameRules::new(&config, container)) {
+            Ok(None) => {}
+            Ok(Some(violation)) => {
+                warn!("fh {}: sanitized device name ({})", fh, violation);
             }
+            Err(violation) => {
+                warn!("fh {}: rejected legacy device setup ({})", fh, violation);
+                vuinput_state
+                    .history
+                    .record_write(LEGACY_SETUP, _size, Some(EINVAL));
+                fuse_lowlevel::fuse_reply_err(_req, EINVAL);
+                return;
+            }
+        }
+
+        let fd = vuinput_state.file.as_raw_fd();
+        if let Err(e) = uinput_compat::dev_setup(fd, &usetup) {
+            warn!("fh {}: legacy device setup failed: {}", fh, e);
+            vuinput_state
+                .history
+                .record_write(LEGACY_SETUP, _size, Some(e.errno()));
+            vuinput_state.history.log_on_first_failure(*fh);
+            fuse_lowlevel::fuse_reply_err(_req, e.errno());
+            return;
         }
+        vuinput_state.descriptor.preserved_id = preserved_id;
+        vuinput_state.descriptor.setup = Some(DeviceSetup::from(&usetup));
+        // applied at UI_DEV_CREATE, see legacy_setup
+        vuinput_state.descriptor.legacy_ranges = legacy.ranges;
+        vuinput_state
+            .history
+            .record_write(LEGACY_SETUP, _size, None);
 
         fuse_lowlevel::fuse_reply_write(_req, _size);
         return;
     }
 
-    let mut bytes = 0;
-    let mut result = Result::Ok(0);
-
-    let compat_size = std::mem::size_of::<input_event_compat>();
-    let normal_size = std::mem::size_of::<libc::input_event>();
     let is_compat = vuinput_state.requesting_process.is_compat;
-    // TODO: ARM: && !compat_uses_64bit_time()
+    let event_size = match is_compat {
+        true => size_of::<input_event_compat>(),
+        false => size_of::<input_event>(),
+    };
+    // like uinput_inject_events: a trailing partial event is not written
+    if _size != 0 && _size < event_size {
+        fuse_lowlevel::fuse_reply_err(_req, EINVAL);
+        return;
+    }
 
-    let policy = get_device_policy();
+    let config = get_reloadable_config();
+    let policy = vuinput_state.policy(&config);
+    protocol_dump::dump_input_events(*fh, "write", slice, is_compat);
 
-    if !is_compat {
-        while bytes + normal_size <= _size && result.is_ok() {
-            let position = _buf.byte_add(bytes);
-            let input_event = position as *const input_event;
-            if device_policy::is_allowed(&mut vuinput_state.keytracker, policy, &*input_event) {
-                result = vuinput_state.file.write(&slice[bytes..bytes + normal_size]);
-            }
-            bytes += normal_size;
-        }
-    } else {
-        while bytes + compat_size <= _size && result.is_ok() {
-            let position = _buf.byte_add(bytes);
-            let compat = position as *const input_event_compat;
-            let normal = map_to_64_bit(&*compat);
-            let normal_ptr = (&normal as *const libc::input_event) as *const u8;
-            let slice = std::slice::from_raw_parts(normal_ptr, normal_size);
-            if device_policy::is_allowed(&mut vuinput_state.keytracker, policy, &normal) {
-                result = vuinput_state.file.write(&slice);
-            }
-            bytes += compat_size;
+    let (bytes, batch) = batch_events(slice, is_compat, |event| {
+        is_forwarded(*fh, &mut vuinput_state, &policy, &config, event)
+    });
+    // uinput returns the bytes written before an error, the next write reports the error
+    let mut result = write_batch(&mut vuinput_state.file, &batch);
+    let mut recovery = None;
+    if let Err((e, _)) = &result {
+        recovery = Some(reconnect::on_write_error(*fh, &mut vuinput_state, e));
+        // the events of the lost device are written again to the new one
+        if recovery == Some(Recovery::Reconnected) {
+            result = write_batch(&mut vuinput_state.file, &batch);
         }
+    }
+    if result.is_ok() {
+        vuinput_state.write_failures = 0;
+    }
+    let written = match &result {
+        Ok(()) => batch.len(),
+        Err((_, written)) => *written,
     };
+    let event_size = size_of::<input_event>();
+    vuinput_state.events_forwarded += (written / event_size) as u64;
+    metrics::record_forwarded((written / event_size) as u64);
+    if result.is_err() {
+        let unwritten = batch.len().div_ceil(event_size) - written / event_size;
+        vuinput_state.record_drop(DropCause::Uinput, unwritten as u64);
+    }
+    // an invalidated handle answers ENODEV from now on, also to this write
+    let errno = result.as_ref().err().map(|(e, _)| match recovery {
+        Some(Recovery::Invalidated) => ENODEV,
+        _ => WriteFailure::of(e).errno(e),
+    });
+    vuinput_state.history.record_write("write", _size, errno);
+    if errno.is_some() {
+        vuinput_state.history.log_on_first_failure(*fh);
+    }
+    if let Some(drops) = vuinput_state.drop_window.summary(Instant::now()) {
+        warn!(
+            "fh {}: dropped {} events in the last minute ({})",
+            fh,
+            drops.total(),
+            drops
+        );
+    }
 
     match result {
         Ok(_) => {
-            trace!("wrote {} of {} bytes (compat {})", bytes, _size, is_compat);
+            trace!(
+                "consumed {} of {} bytes, wrote {} bytes (compat {})",
+                bytes,
+                _size,
+                batch.len(),
+                is_compat
+            );
             fuse_lowlevel::fuse_reply_write(_req, bytes);
         }
-        Err(e) => {
+        Err((e, _)) => {
             let mut last_error = DEDUP_LAST_ERROR.get().unwrap().lock().unwrap();
 
             match *last_error {
@@ -130,11 +204,119 @@ pub unsafe extern "C" fn vuinput_write(
 
             *last_error = Some((*fh, VuError::WriteError));
 
-            fuse_lowlevel::fuse_reply_err(_req, EIO);
+            fuse_lowlevel::fuse_reply_err(_req, errno.unwrap_or(EIO));
         }
     }
 }
 
+/// Collects the complete events of a write() that `forward` lets through, in the native layout,
+/// and returns them together with the number of bytes consumed. `forward` may change an event
+/// (policy script). They are forwarded with a single write: as long as no event is dropped or
+/// changed, that is the buffer of a native client as it is, otherwise the events are copied.
+fn batch_events(
+    buffer: &[u8],
+    is_compat: bool,
+    mut forward: impl FnMut(&mut input_event) -> bool,
+) -> (usize, Cow<'_, [u8]>) {
+    let event_size = match is_compat {
+        true => size_of::<input_event_compat>(),
+        false => size_of::<input_event>(),
+    };
+    let mut contiguous = !is_compat;
+    let mut batch: Vec<u8> = Vec::new();
+    let mut bytes = 0;
+    while bytes + event_size <= buffer.len() {
+        let position = buffer[bytes..].as_ptr();
+        let mut event = unsafe {
+            match is_compat {
+                true => map_to_64_bit(&std::ptr::read_unaligned(
+                    position as *const input_event_compat,
+                )),
+                false => std::ptr::read_unaligned(position as *const input_event),
+            }
+        };
+        let original = (event.type_, event.code, event.value);
+        let forwarded = forward(&mut event);
+        if forwarded && contiguous && (event.type_, event.code, event.value) != original {
+            batch.extend_from_slice(&buffer[..bytes]);
+            contiguous = false;
+        }
+        if forwarded && !contiguous {
+            batch.extend_from_slice(unsafe {
+                std::slice::from_raw_parts(
+                    &event as *const input_event as *const u8,
+                    size_of::<input_event>(),
+                )
+            });
+        } else if !forwarded && contiguous {
+            batch.extend_from_slice(&buffer[..bytes]);
+            contiguous = false;
+        }
+        bytes += event_size;
+    }
+    match contiguous {
+        true => (bytes, Cow::Borrowed(&buffer[..bytes])),
+        false => (bytes, Cow::Owned(batch)),
+    }
+}
+
+/// Applies the lock screen and the device policy to an event and counts it
+fn is_forwarded(
+    fh: u64,
+    vuinput_state: &mut VuInputState,
+    policy: &DevicePolicy,
+    config: &ReloadableConfig,
+    event: &mut input_event,
+) -> bool {
+    if session_lock::is_blocked(event) {
+        vuinput_state.record_drop(DropCause::SessionLocked, 1);
+        debug!(
+            "fh {}: dropped a key, the session of the host is locked",
+            fh
+        );
+        return false;
+    }
+    if !device_policy::is_allowed(
+        &mut vuinput_state.keytracker,
+        policy,
+        &config.gamepad_extra_keys,
+        event,
+    ) {
+        vuinput_state.record_drop(DropCause::Policy, 1);
+        // only the first one, the rest are counted (vuinputctl top)
+        if vuinput_state.drops.policy == 1 {
+            let blocked = keystroke_privacy::blocked(event);
+            events::policy_violation(fh, vuinput_state, format_args!("event {}", blocked));
+        }
+        debug!(
+            "fh {}: blocked event {}",
+            fh,
+            keystroke_privacy::blocked(event)
+        );
+        return false;
+    }
+    trace!("fh {}: event {}", fh, keystroke_privacy::loggable(event));
+    true
+}
+
+/// Writes the batch like write_all, but returns how much of it has been written on an error
+fn write_batch(file: &mut impl Write, batch: &[u8]) -> Result<(), (io::Error, usize)> {
+    let mut written = 0;
+    while written < batch.len() {
+        match file.write(&batch[written..]) {
+            Ok(0) => return Err((io::ErrorKind::WriteZero.into(), written)),
+            Ok(n) => written += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err((e, written)),
+        }
+    }
+    Ok(())
+}
+
+/// struct input_event as sent by a 32-bit client to a 64-bit kernel (input_event_compat in
+/// drivers/input/input-compat.h). The time consists of two compat_ulong_t on every
+/// architecture, also for clients built with a 64-bit time_t: the uapi header uses
+/// __kernel_ulong_t instead of struct timeval for them.
 #[repr(C)]
 pub struct input_event_compat {
     pub input_event_sec: u32,
@@ -144,25 +326,272 @@ pub struct input_event_compat {
     pub value: __s32,
 }
 
-// this is static for the architecture
-pub fn compat_uses_64bit_time() -> bool {
-    let uname = nix::sys::utsname::uname().unwrap();
-    let arch = uname.machine().to_str().unwrap();
+pub const EM_X86_64: u16 = 62;
 
-    match arch {
-        "x86_64" => false,
-        "ppc64" => false, // some setups still 32-bit time_t
-        _ => true,        // arm64, riscv64, s390x all use 64-bit
-    }
+/// Whether a 32-bit client sends the native struct input_event nevertheless, the same as
+/// COMPAT_USE_64BIT_TIME of the kernel. This is only the case for x32 on x86_64; i386 on
+/// x86_64, arm on arm64 and rv32 on riscv64 all use input_event_compat.
+#[cfg(target_arch = "x86_64")]
+pub fn compat_uses_64bit_time(e_machine: u16) -> bool {
+    e_machine == EM_X86_64
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn compat_uses_64bit_time(_e_machine: u16) -> bool {
+    false
 }
 
+// the casts are no-ops on 64-bit hosts, on 32-bit hosts there is no compat client
 pub fn map_to_64_bit(compat: &input_event_compat) -> input_event {
     let mut mapped: input_event = unsafe { std::mem::zeroed() };
-    mapped.time.tv_sec = compat.input_event_sec.into();
-    mapped.time.tv_usec = compat.input_event_usec.into();
+    mapped.time.tv_sec = compat.input_event_sec as libc::time_t;
+    mapped.time.tv_usec = compat.input_event_usec as libc::suseconds_t;
     mapped.type_ = compat.type_;
     mapped.code = compat.code;
     mapped.value = compat.value;
 
     mapped
 }
+
+pub fn map_to_compat(event: &input_event) -> input_event_compat {
+    input_event_compat {
+        input_event_sec: event.time.tv_sec as u32,
+        input_event_usec: event.time.tv_usec as u32,
+        type_: event.type_,
+        code: event.code,
+        value: event.value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cuse_device::device_policy::EV_KEY;
+    use std::mem::offset_of;
+
+    const EV_SYN: u16 = 0x00;
+    const EM_386: u16 = 3;
+    const EM_ARM: u16 = 40;
+    const EM_RISCV: u16 = 243;
+
+    #[test]
+    fn compat_layout_matches_the_kernel() {
+        assert_eq!(size_of::<input_event_compat>(), 16);
+        assert_eq!(offset_of!(input_event_compat, input_event_usec), 4);
+        assert_eq!(offset_of!(input_event_compat, type_), 8);
+        assert_eq!(offset_of!(input_event_compat, value), 12);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn native_layout_has_64bit_time() {
+        assert_eq!(size_of::<input_event>(), 24);
+        assert_eq!(offset_of!(input_event, type_), 16);
+    }
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn native_layout_is_the_compat_layout() {
+        assert_eq!(size_of::<input_event>(), size_of::<input_event_compat>());
+        assert_eq!(offset_of!(input_event, type_), 8);
+    }
+
+    #[test]
+    fn only_x32_uses_64bit_time() {
+        assert!(!compat_uses_64bit_time(EM_386));
+        assert!(!compat_uses_64bit_time(EM_ARM));
+        assert!(!compat_uses_64bit_time(EM_RISCV));
+        assert_eq!(
+            compat_uses_64bit_time(EM_X86_64),
+            cfg!(target_arch = "x86_64")
+        );
+    }
+
+    #[test]
+    fn compat_events_survive_the_roundtrip() {
+        // a key press as written by a 32-bit client, in the byte order of the host
+        let mut written = Vec::new();
+        written.extend_from_slice(&1_700_000_000u32.to_ne_bytes());
+        written.extend_from_slice(&999_999u32.to_ne_bytes());
+        written.extend_from_slice(&EV_KEY.to_ne_bytes());
+        written.extend_from_slice(&30u16.to_ne_bytes()); // KEY_A
+        written.extend_from_slice(&1i32.to_ne_bytes());
+
+        let compat =
+            unsafe { std::ptr::read_unaligned(written.as_ptr() as *const input_event_compat) };
+        let event = map_to_64_bit(&compat);
+        assert_eq!(event.time.tv_sec, 1_700_000_000);
+        assert_eq!(event.time.tv_usec, 999_999);
+        assert_eq!(event.type_, EV_KEY);
+        assert_eq!(event.code, 30);
+        assert_eq!(event.value, 1);
+
+        let read = map_to_compat(&event);
+        let read = unsafe {
+            std::slice::from_raw_parts(
+                &read as *const input_event_compat as *const u8,
+                size_of::<input_event_compat>(),
+            )
+        };
+        assert_eq!(read, written.as_slice());
+    }
+
+    fn native_event(type_: u16, code: u16, value: i32) -> Vec<u8> {
+        let mut event: input_event = unsafe { std::mem::zeroed() };
+        event.type_ = type_;
+        event.code = code;
+        event.value = value;
+        unsafe {
+            std::slice::from_raw_parts(
+                &event as *const input_event as *const u8,
+                size_of::<input_event>(),
+            )
+        }
+        .to_vec()
+    }
+
+    #[test]
+    fn batches_native_events() {
+        let mut written = Vec::new();
+        written.extend(native_event(EV_KEY, 30, 1));
+        written.extend(native_event(EV_KEY, 99, 1)); // KEY_SYSRQ
+        written.extend(native_event(EV_SYN, 0, 0));
+        // a partial event is left to the next write
+        written.extend_from_slice(&[0u8; 5]);
+
+        let (bytes, batch) = batch_events(&written, false, |_| true);
+        assert_eq!(bytes, 3 * size_of::<input_event>());
+        assert!(matches!(batch, Cow::Borrowed(_)));
+        assert_eq!(batch, &written[..bytes]);
+
+        let (bytes, batch) = batch_events(&written, false, |event| event.code != 99);
+        assert_eq!(bytes, 3 * size_of::<input_event>());
+        let mut expected = native_event(EV_KEY, 30, 1);
+        expected.extend(native_event(EV_SYN, 0, 0));
+        assert_eq!(batch, expected.as_slice());
+
+        let (bytes, batch) = batch_events(&written[..20], false, |_| true);
+        assert_eq!((bytes, batch.len()), (0, 0));
+
+        // KEY_SYSRQ turned into KEY_B by a policy script
+        let (_, batch) = batch_events(&written, false, |event| {
+            if event.code == 99 {
+                event.code = 48;
+            }
+            true
+        });
+        let mut expected = native_event(EV_KEY, 30, 1);
+        expected.extend(native_event(EV_KEY, 48, 1));
+        expected.extend(native_event(EV_SYN, 0, 0));
+        assert!(matches!(batch, Cow::Owned(_)));
+        assert_eq!(batch, expected.as_slice());
+    }
+
+    /// Accepts `capacity` bytes, then fails like uinput with an invalid event
+    struct FailingDevice {
+        capacity: usize,
+        written: Vec<u8>,
+    }
+
+    impl Write for FailingDevice {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.capacity == 0 {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            let n = buf.len().min(self.capacity);
+            self.capacity -= n;
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reports_what_has_been_written() {
+        let batch = [native_event(EV_KEY, 30, 1), native_event(EV_SYN, 0, 0)].concat();
+        let mut device = FailingDevice {
+            capacity: size_of::<input_event>(),
+            written: Vec::new(),
+        };
+        let (error, written) = write_batch(&mut device, &batch).unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::EINVAL));
+        assert_eq!(written, size_of::<input_event>());
+
+        let mut device = FailingDevice {
+            capacity: usize::MAX,
+            written: Vec::new(),
+        };
+        assert!(write_batch(&mut device, &batch).is_ok());
+        assert!(write_batch(&mut device, &[]).is_ok());
+        assert_eq!(device.written, batch);
+    }
+
+    #[test]
+    fn batches_compat_events() {
+        let compat = |code: u16| {
+            let mut event = Vec::new();
+            event.extend_from_slice(&7u32.to_ne_bytes());
+            event.extend_from_slice(&8u32.to_ne_bytes());
+            event.extend_from_slice(&EV_KEY.to_ne_bytes());
+            event.extend_from_slice(&code.to_ne_bytes());
+            event.extend_from_slice(&1i32.to_ne_bytes());
+            event
+        };
+        let mut written = compat(30);
+        written.extend(compat(31));
+        written.extend(compat(32));
+
+        let (bytes, batch) = batch_events(&written, true, |event| event.code != 31);
+        assert_eq!(bytes, 48);
+        assert_eq!(batch.len(), 2 * size_of::<input_event>());
+        let second = unsafe {
+            std::ptr::read_unaligned(
+                batch[size_of::<input_event>()..].as_ptr() as *const input_event
+            )
+        };
+        assert_eq!(second.code, 32);
+        assert_eq!(second.time.tv_usec, 8);
+    }
+
+    #[test]
+    fn compat_time_is_unsigned() {
+        // compat_ulong_t, so 32-bit clients are fine until 2106
+        let compat = input_event_compat {
+            input_event_sec: 3_000_000_000,
+            input_event_usec: 1,
+            type_: EV_KEY,
+            code: 30,
+            value: 0,
+        };
+        let event = map_to_64_bit(&compat);
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(event.time.tv_sec, 3_000_000_000);
+        assert_eq!(map_to_compat(&event).input_event_sec, 3_000_000_000);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn native_events_have_64bit_time() {
+        // a key release as written by a 64-bit client, with a time after 2106
+        let mut written = Vec::new();
+        written.extend_from_slice(&5_000_000_000i64.to_ne_bytes());
+        written.extend_from_slice(&42i64.to_ne_bytes());
+        written.extend_from_slice(&EV_KEY.to_ne_bytes());
+        written.extend_from_slice(&30u16.to_ne_bytes());
+        written.extend_from_slice(&0i32.to_ne_bytes());
+        assert_eq!(written.len(), size_of::<input_event>());
+
+        let event = unsafe { std::ptr::read_unaligned(written.as_ptr() as *const input_event) };
+        assert_eq!(event.time.tv_sec, 5_000_000_000);
+        assert_eq!(event.time.tv_usec, 42);
+        assert_eq!(event.code, 30);
+        // the compat layout has no room for it, like in the kernel
+        assert_eq!(
+            map_to_compat(&event).input_event_sec,
+            5_000_000_000u64 as u32
+        );
+    }
+}
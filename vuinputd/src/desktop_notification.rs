@@ -0,0 +1,166 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Tells a desktop user about requests the device policy refused (notify-user), e.g. a game in
+// a container that tries to switch the VT or to send SysRq, instead of letting it fail silently.
+// The notifications are sent with notify-send, running as that user on their session bus. As a
+// client that is refused one capability usually tries a few more, each container gets at most
+// one notification per NOTIFY_INTERVAL. Containers that wait for approval (approval prompt) are
+// always announced, the user is the one who has to answer.
+
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use nix::unistd::User;
+
+use crate::control::events;
+use crate::control::protocol::{Event, Response};
+use crate::global_config::get_notify_user;
+
+const NOTIFY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Listens for policy violations and approval requests until vuinputd stops. Whether and whom
+/// to notify is looked up for each event, so notify-user can be changed by a reload.
+pub fn start() -> io::Result<()> {
+    let messages = events::subscribe_to(|event| {
+        matches!(
+            event,
+            Event::PolicyViolation { .. } | Event::ApprovalRequested { .. }
+        ) && get_notify_user().is_some()
+    });
+    thread::Builder::new()
+        .name("notify".to_string())
+        .spawn(move || {
+            let mut last_notified = HashMap::new();
+            for message in messages.iter() {
+                let Response::Event { event, .. } = message else {
+                    continue;
+                };
+                let Some(user) = get_notify_user() else {
+                    continue;
+                };
+                let (title, body) = match event {
+                    Event::PolicyViolation {
+                        container,
+                        policy,
+                        violation,
+                        ..
+                    } if is_due(&mut last_notified, container, Instant::now()) => {
+                        ("Input blocked", body(container, &policy, &violation))
+                    }
+                    Event::ApprovalRequested { container, .. } => {
+                        ("Input device requested", approval_body(container))
+                    }
+                    _ => continue,
+                };
+                if let Err(e) = notify(&user, title, &body) {
+                    warn!("failed to notify {}: {}", user, e);
+                }
+            }
+            warn!("desktop notifications stopped, vuinputd could not keep up with the events");
+        })?;
+    Ok(())
+}
+
+fn is_due(last_notified: &mut HashMap<u32, Instant>, container: u32, now: Instant) -> bool {
+    match last_notified.get(&container) {
+        Some(last) if now.duration_since(*last) < NOTIFY_INTERVAL => false,
+        _ => {
+            last_notified.insert(container, now);
+            true
+        }
+    }
+}
+
+fn body(container: u32, policy: &str, violation: &str) -> String {
+    format!(
+        "An application in container {} tried {}, which the device policy {} does not allow.",
+        container, violation, policy
+    )
+}
+
+fn approval_body(container: u32) -> String {
+    format!(
+        "An application in container {} wants to create an input device. Allow it with \
+         vuinputctl approve {}, refuse it with vuinputctl deny {}.",
+        container, container, container
+    )
+}
+
+/// Runs notify-send as the user, a name or a uid. It is not waited for, a session bus that
+/// does not answer must not hold up the next notification.
+fn notify(user: &str, title: &str, body: &str) -> io::Result<()> {
+    let user = match user.parse::<u32>() {
+        Ok(uid) => User::from_uid(uid.into()),
+        Err(_) => User::from_name(user),
+    }
+    .map_err(io::Error::from)?
+    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "unknown user"))?;
+
+    let runtime_dir = format!("/run/user/{}", user.uid);
+    let bus = format!("{}/bus", runtime_dir);
+    if !Path::new(&bus).exists() {
+        debug!("{} has no session bus ({}), not notifying", user.name, bus);
+        return Ok(());
+    }
+    let mut child = Command::new("notify-send")
+        .args(["--app-name=vuinputd", "--icon=input-keyboard"])
+        .arg(title)
+        .arg(body)
+        .env_clear()
+        .env("PATH", "/usr/local/bin:/usr/bin:/bin")
+        .env("HOME", &user.dir)
+        .env("XDG_RUNTIME_DIR", &runtime_dir)
+        .env("DBUS_SESSION_BUS_ADDRESS", format!("unix:path={}", bus))
+        .uid(user.uid.as_raw())
+        .gid(user.gid.as_raw())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()?;
+    // reap it, so that it does not stay around as a zombie
+    thread::spawn(move || child.wait());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notifies_each_container_once_per_interval() {
+        let mut last_notified = HashMap::new();
+        let start = Instant::now();
+        assert!(is_due(&mut last_notified, 4690, start));
+        assert!(!is_due(
+            &mut last_notified,
+            4690,
+            start + Duration::from_secs(5)
+        ));
+        assert!(is_due(
+            &mut last_notified,
+            5120,
+            start + Duration::from_secs(5)
+        ));
+        assert!(is_due(&mut last_notified, 4690, start + NOTIFY_INTERVAL));
+    }
+
+    #[test]
+    fn body_names_container_and_policy() {
+        assert_eq!(
+            body(
+                4690,
+                "sanitized",
+                "event type EV_KEY code KEY_SYSRQ value 1"
+            ),
+            "An application in container 4690 tried event type EV_KEY code KEY_SYSRQ value 1, \
+             which the device policy sanitized does not allow."
+        );
+    }
+}
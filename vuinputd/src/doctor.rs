@@ -0,0 +1,138 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Pre-flight checks of the host, run with "vuinputd doctor". The checks only look,
+// they never change anything on the system.
+
+use crate::cuse_device::cuse_module::{cuse_status, CuseStatus, CUSE_DEVICE};
+use crate::cuse_device::sysfs_input::{describe, sysfs_status};
+use crate::cuse_device::uinput_compat::{self, CompatMode};
+use crate::cuse_device::vuinput_ioctl::SYS_INPUT_DIR;
+use crate::process_tools::{has_effective_capability, CAP_SYS_ADMIN};
+use crate::udev_rules;
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::os::fd::AsRawFd;
+
+const UINPUT_DEVICE: &str = "/dev/uinput";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CheckOutcome {
+    Ok(String),
+    Warn(String),
+    Fail(String),
+}
+
+fn check_cuse() -> CheckOutcome {
+    match cuse_status() {
+        CuseStatus::Available => CheckOutcome::Ok(format!("{} is available", CUSE_DEVICE)),
+        CuseStatus::Missing => CheckOutcome::Fail(format!(
+            "VUI-CUSE-001: {} is missing. Run \"modprobe cuse\" or start vuinputd with --modprobe-cuse auto",
+            CUSE_DEVICE
+        )),
+        CuseStatus::NotACharDevice => CheckOutcome::Fail(format!(
+            "VUI-CUSE-003: {} is not a character device",
+            CUSE_DEVICE
+        )),
+        CuseStatus::NotAccessible(e) => CheckOutcome::Fail(format!(
+            "VUI-CUSE-003: {} can't be opened: {}",
+            CUSE_DEVICE, e
+        )),
+    }
+}
+
+fn check_uinput() -> CheckOutcome {
+    match OpenOptions::new().write(true).open(UINPUT_DEVICE) {
+        Ok(file) => {
+            let features = unsafe { uinput_compat::probe(file.as_raw_fd()) };
+            match features.mode() {
+                CompatMode::Native => {
+                    CheckOutcome::Ok(format!("{} can be opened, {}", UINPUT_DEVICE, features))
+                }
+                CompatMode::LegacySetup => CheckOutcome::Warn(format!(
+                    "{}: UI_DEV_SETUP is translated, the resolution of axes is lost",
+                    features
+                )),
+                CompatMode::Unsupported => CheckOutcome::Fail(format!(
+                    "{}: created devices can't be found, Linux 3.15 or newer is needed",
+                    features
+                )),
+            }
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => CheckOutcome::Fail(format!(
+            "{} is missing. Run \"modprobe uinput\"",
+            UINPUT_DEVICE
+        )),
+        Err(e) => CheckOutcome::Fail(format!("{} can't be opened: {}", UINPUT_DEVICE, e)),
+    }
+}
+
+fn check_capabilities() -> CheckOutcome {
+    match has_effective_capability(CAP_SYS_ADMIN) {
+        Ok(true) => CheckOutcome::Ok("CAP_SYS_ADMIN is effective".to_string()),
+        Ok(false) => CheckOutcome::Warn(
+            "CAP_SYS_ADMIN is missing, devices can't be placed into containers".to_string(),
+        ),
+        Err(e) => CheckOutcome::Warn(format!("could not read the capabilities: {}", e)),
+    }
+}
+
+fn check_sysfs() -> CheckOutcome {
+    match describe(&sysfs_status()) {
+        None => CheckOutcome::Ok(format!("{} is visible", SYS_INPUT_DIR)),
+        Some(message) => CheckOutcome::Fail(message),
+    }
+}
+
+/// Rules that come after those of vuinputd and might undo them. Whether they match the devices
+/// of vuinputd only shows with a device, see jobs::rules_conflict_job.
+fn check_udev_rules() -> CheckOutcome {
+    let assignments = udev_rules::read_rules();
+    if !udev_rules::has_protect_rules(&assignments) {
+        return CheckOutcome::Warn(
+            "90-vuinputd-protect.rules is not installed, keyboards and mice of containers end up on seat0"
+                .to_string(),
+        );
+    }
+    let conflicts = udev_rules::conflicts(&assignments);
+    if conflicts.is_empty() {
+        return CheckOutcome::Ok(
+            "no rule after 90-vuinputd-protect.rules changes the seat or the class of devices"
+                .to_string(),
+        );
+    }
+    let messages: Vec<String> = conflicts.iter().map(udev_rules::remediation).collect();
+    CheckOutcome::Warn(format!(
+        "VUI-UDEV-004: rules that might move devices of vuinputd to a seat of the host: {}",
+        messages.join("; ")
+    ))
+}
+
+type Check = (&'static str, fn() -> CheckOutcome);
+
+fn checks() -> Vec<Check> {
+    vec![
+        ("cuse", check_cuse),
+        ("uinput", check_uinput),
+        ("sysfs", check_sysfs),
+        ("capabilities", check_capabilities),
+        ("udev-rules", check_udev_rules),
+    ]
+}
+
+/// Runs all checks and prints the results. Returns false if a check failed.
+pub fn run_doctor() -> bool {
+    let mut passed = true;
+    for (name, check) in checks() {
+        match check() {
+            CheckOutcome::Ok(message) => println!("[ OK ] {}: {}", name, message),
+            CheckOutcome::Warn(message) => println!("[WARN] {}: {}", name, message),
+            CheckOutcome::Fail(message) => {
+                passed = false;
+                println!("[FAIL] {}: {}", name, message)
+            }
+        }
+    }
+    passed
+}
@@ -0,0 +1,253 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Reader of evemu recordings (https://gitlab.freedesktop.org/libevdev/evemu), as attached to
+// bug reports: the description of a device (evemu-describe) and its events (evemu-record).
+// evemu-record writes both into one file, so the events are also found behind a description.
+//
+//   N: <name>
+//   I: <bustype> <vendor> <product> <version>          hex
+//   P: <8 bytes of the property bits>                  hex, repeated for more bytes
+//   B: <type> <8 bytes of the code bits of the type>   hex, type 00 holds the event types
+//   A: <code> <min> <max> <fuzz> <flat> [<resolution>] code in hex
+//   E: <sec>.<usec> <type> <code> <value>              type and code in hex
+//
+// Lines starting with # are comments, other lines are ignored.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::cuse_device::state::AbsInfo;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Description {
+    pub name: String,
+    pub bustype: u16,
+    pub vendor: u16,
+    pub product: u16,
+    pub version: u16,
+    pub properties: Vec<u8>,
+    /// Bitmask of the codes per event type, type 0 holds the event types
+    pub masks: BTreeMap<u16, Vec<u8>>,
+    pub absinfo: BTreeMap<u16, AbsInfo>,
+}
+
+impl Description {
+    /// The codes of the type (for type 0, the event types)
+    pub fn codes(&self, ev_type: u16) -> Vec<u16> {
+        self.masks
+            .get(&ev_type)
+            .map(|mask| bits(mask))
+            .unwrap_or_default()
+    }
+
+    pub fn properties(&self) -> Vec<u16> {
+        bits(&self.properties)
+    }
+}
+
+fn bits(mask: &[u8]) -> Vec<u16> {
+    (0..mask.len() * 8)
+        .filter(|bit| mask[bit / 8] & (1 << (bit % 8)) != 0)
+        .map(|bit| bit as u16)
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordedEvent {
+    /// Time of the recording
+    pub time: Duration,
+    pub type_: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+pub fn parse_description(text: &str) -> Result<Description, String> {
+    let mut description = Description::default();
+    let mut has_name = false;
+    for (number, line) in lines(text) {
+        let error = |what: &str| format!("line {}: {}: {}", number, what, line);
+        let Some((key, rest)) = line.split_once(':') else {
+            continue;
+        };
+        let fields: Vec<&str> = rest.split_whitespace().collect();
+        match key {
+            "N" => {
+                description.name = rest.trim().to_string();
+                has_name = true;
+            }
+            "I" => {
+                let id = hex_fields::<u16>(&fields).ok_or_else(|| error("invalid id"))?;
+                let [bustype, vendor, product, version] = id[..] else {
+                    return Err(error("expected bustype, vendor, product and version"));
+                };
+                description.bustype = bustype;
+                description.vendor = vendor;
+                description.product = product;
+                description.version = version;
+            }
+            "P" => {
+                let bytes = hex_fields::<u8>(&fields).ok_or_else(|| error("invalid properties"))?;
+                description.properties.extend(bytes);
+            }
+            "B" => {
+                let bytes = hex_fields::<u8>(&fields).ok_or_else(|| error("invalid bits"))?;
+                let Some((ev_type, mask)) = bytes.split_first() else {
+                    return Err(error("missing the event type"));
+                };
+                description
+                    .masks
+                    .entry(u16::from(*ev_type))
+                    .or_default()
+                    .extend(mask);
+            }
+            "A" => {
+                let code = fields
+                    .first()
+                    .and_then(|code| u16::from_str_radix(code, 16).ok())
+                    .ok_or_else(|| error("invalid axis"))?;
+                let values: Vec<i32> = fields[1..]
+                    .iter()
+                    .map(|value| value.parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| error("invalid range"))?;
+                if values.len() < 4 {
+                    return Err(error("expected min, max, fuzz and flat"));
+                }
+                let absinfo = AbsInfo {
+                    value: 0,
+                    minimum: values[0],
+                    maximum: values[1],
+                    fuzz: values[2],
+                    flat: values[3],
+                    resolution: values.get(4).copied().unwrap_or(0),
+                };
+                description.absinfo.insert(code, absinfo);
+            }
+            _ => {}
+        }
+    }
+    if !has_name {
+        return Err("no device description (N: line) found".to_string());
+    }
+    Ok(description)
+}
+
+pub fn parse_events(text: &str) -> Result<Vec<RecordedEvent>, String> {
+    let mut events = Vec::new();
+    for (number, line) in lines(text) {
+        let Some(rest) = line.strip_prefix("E:") else {
+            continue;
+        };
+        let event =
+            parse_event(rest).ok_or_else(|| format!("line {}: invalid event: {}", number, line))?;
+        events.push(event);
+    }
+    Ok(events)
+}
+
+fn parse_event(fields: &str) -> Option<RecordedEvent> {
+    let fields: Vec<&str> = fields.split_whitespace().collect();
+    let [time, ev_type, code, value] = fields[..] else {
+        return None;
+    };
+    let (sec, usec) = time.split_once('.')?;
+    Some(RecordedEvent {
+        time: Duration::from_secs(sec.parse().ok()?) + Duration::from_micros(usec.parse().ok()?),
+        type_: u16::from_str_radix(ev_type, 16).ok()?,
+        code: u16::from_str_radix(code, 16).ok()?,
+        value: value.parse().ok()?,
+    })
+}
+
+/// Numbered lines without comments, also the ones behind an event (E: ... # EV_KEY / KEY_A)
+fn lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.split('#').next().unwrap_or_default().trim()))
+        .filter(|(_, line)| !line.is_empty())
+}
+
+fn hex_fields<T: TryFrom<u32>>(fields: &[&str]) -> Option<Vec<T>> {
+    fields
+        .iter()
+        .map(|field| {
+            u32::from_str_radix(field, 16)
+                .ok()
+                .and_then(|value| T::try_from(value).ok())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // shortened recording of a mouse by evemu-record
+    const RECORDING: &str = "\
+# EVEMU 1.3
+# Input device name: \"Example Mouse\"
+N: Example Mouse
+I: 0003 1234 5678 0001
+P: 00 00 00 00 00 00 00 00
+B: 00 07 00 00 00 00 00 00 00
+B: 01 00 00 00 00 00 00 00 00
+B: 01 00 00 00 00 00 00 00 00
+B: 01 00 00 00 00 00 00 00 00
+B: 01 00 00 00 00 00 00 00 00
+B: 01 00 00 07 00 00 00 00 00
+B: 02 03 00 00 00 00 00 00 00
+B: 03 03 00 00 00 00 00 00 00
+A: 00 0 1920 0 0 0
+A: 01 0 1080 0 0 12
+################################
+#      Waiting for events      #
+################################
+E: 0.000001 0001 0110 0001	# EV_KEY / BTN_LEFT             1
+E: 0.000001 0000 0000 0000	# ------------ SYN_REPORT (0) ---------- +0ms
+E: 1.250000 0002 0000 -5	# EV_REL / REL_X               -5
+";
+
+    #[test]
+    fn parses_recordings() {
+        let description = parse_description(RECORDING).unwrap();
+        assert_eq!(description.name, "Example Mouse");
+        assert_eq!(
+            (
+                description.bustype,
+                description.vendor,
+                description.product,
+                description.version
+            ),
+            (0x03, 0x1234, 0x5678, 1)
+        );
+        assert_eq!(description.codes(0), vec![0, 1, 2]);
+        assert_eq!(description.codes(1), vec![0x110, 0x111, 0x112]);
+        assert_eq!(description.codes(2), vec![0, 1]);
+        assert!(description.properties().is_empty());
+        assert_eq!(description.absinfo[&1].maximum, 1080);
+        assert_eq!(description.absinfo[&1].resolution, 12);
+
+        let events = parse_events(RECORDING).unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(
+            events[2],
+            RecordedEvent {
+                time: Duration::from_millis(1250),
+                type_: 2,
+                code: 0,
+                value: -5,
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_recordings() {
+        assert!(parse_description("I: 0003 1234 5678 0001").is_err());
+        assert!(parse_description("N: mouse\nI: 0003 1234").is_err());
+        assert!(parse_description("N: mouse\nA: 00 0").is_err());
+        assert!(parse_events("E: 0.1 0001 zz 1").is_err());
+        assert!(parse_events("N: mouse").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,183 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Fault injection for tests (fault-injection). VUI_FAULTS makes chosen operations fail with
+// an errno instead of being done, to check that vuinputd survives what the kernel or a
+// container may answer:
+//
+//   VUI_FAULTS="UI_DEV_CREATE=EAGAIN:2,mknod=EACCES,netlink=ENOBUFS"
+//
+// The points are the ioctls vuinputd forwards to the host uinput fd (UI_DEV_CREATE,
+// UI_SET_KEYBIT, ...), mknod of the device nodes and netlink, the udev events sent into
+// containers. The errno is a name or a number; with a count, the point fails only that many
+// times. The helpers that vuinputd starts in the containers inherit the variable, each with
+// its own counts.
+//
+// The variable does not start with VUINPUTD_, as those are read as configuration. Without the
+// feature, the checks are compiled away and a warning tells that VUI_FAULTS is ignored.
+
+use nix::errno::Errno;
+
+#[cfg(feature = "fault-injection")]
+pub use injection::*;
+
+#[cfg(not(feature = "fault-injection"))]
+pub use disabled::*;
+
+pub const FAULTS_ENV: &str = "VUI_FAULTS";
+
+#[cfg(feature = "fault-injection")]
+mod injection {
+    use std::sync::{Mutex, OnceLock};
+
+    use log::{info, warn};
+
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Fault {
+        point: String,
+        errno: Errno,
+        /// None to fail every time
+        remaining: Option<u32>,
+    }
+
+    static FAULTS: OnceLock<Mutex<Vec<Fault>>> = OnceLock::new();
+
+    fn parse_errno(name: &str) -> Option<Errno> {
+        if let Ok(number) = name.parse::<i32>() {
+            return Some(Errno::from_raw(number)).filter(|errno| *errno != Errno::UnknownErrno);
+        }
+        // the variants of Errno are named like the constants of errno.h
+        (1..256)
+            .map(Errno::from_raw)
+            .find(|errno| format!("{:?}", errno) == name)
+    }
+
+    fn parse_faults(spec: &str) -> Result<Vec<Fault>, String> {
+        spec.split(',')
+            .map(str::trim)
+            .filter(|fault| !fault.is_empty())
+            .map(|fault| {
+                let (point, errno) = fault
+                    .split_once('=')
+                    .ok_or_else(|| format!("{}: expected <point>=<errno>[:<count>]", fault))?;
+                if !(point.starts_with("UI_") || point == "mknod" || point == "netlink") {
+                    return Err(format!("{}: unknown point {}", fault, point));
+                }
+                let (errno, count) = match errno.split_once(':') {
+                    Some((errno, count)) => (errno, Some(count)),
+                    None => (errno, None),
+                };
+                let remaining = count
+                    .map(|count| count.parse::<u32>())
+                    .transpose()
+                    .map_err(|e| format!("{}: {}", fault, e))?;
+                Ok(Fault {
+                    point: point.to_string(),
+                    errno: parse_errno(errno)
+                        .ok_or_else(|| format!("{}: unknown errno {}", fault, errno))?,
+                    remaining,
+                })
+            })
+            .collect()
+    }
+
+    fn faults() -> &'static Mutex<Vec<Fault>> {
+        FAULTS.get_or_init(|| {
+            let spec = std::env::var(FAULTS_ENV).unwrap_or_default();
+            Mutex::new(parse_faults(&spec).unwrap_or_else(|e| {
+                warn!("{} is ignored: {}", FAULTS_ENV, e);
+                Vec::new()
+            }))
+        })
+    }
+
+    /// Reads VUI_FAULTS and logs the faults that will be injected
+    pub fn init() {
+        for fault in faults().lock().unwrap().iter() {
+            match fault.remaining {
+                Some(count) => warn!(
+                    "fault injection: {} fails with {} ({} times)",
+                    fault.point, fault.errno, count
+                ),
+                None => warn!(
+                    "fault injection: {} fails with {}",
+                    fault.point, fault.errno
+                ),
+            }
+        }
+    }
+
+    /// The errno to fail `point` with, if a fault is injected into it
+    pub fn fail(point: &str) -> Result<(), Errno> {
+        let mut faults = faults().lock().unwrap();
+        let Some(fault) = faults
+            .iter_mut()
+            .find(|fault| fault.point == point && fault.remaining != Some(0))
+        else {
+            return Ok(());
+        };
+        if let Some(remaining) = &mut fault.remaining {
+            *remaining -= 1;
+        }
+        info!("fault injection: {} fails with {}", point, fault.errno);
+        Err(fault.errno)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn faults_are_parsed_and_counted() {
+            assert_eq!(
+                parse_faults("UI_DEV_CREATE=EAGAIN:2, mknod=13").unwrap(),
+                [
+                    Fault {
+                        point: "UI_DEV_CREATE".to_string(),
+                        errno: Errno::EAGAIN,
+                        remaining: Some(2),
+                    },
+                    Fault {
+                        point: "mknod".to_string(),
+                        errno: Errno::EACCES,
+                        remaining: None,
+                    },
+                ]
+            );
+            assert_eq!(parse_faults("").unwrap(), []);
+            assert!(parse_faults("write=EIO").is_err());
+            assert!(parse_faults("netlink=ENOSUCHERRNO").is_err());
+            assert!(parse_faults("netlink").is_err());
+
+            let faults = FAULTS.get_or_init(|| Mutex::new(Vec::new()));
+            *faults.lock().unwrap() = parse_faults("UI_SET_KEYBIT=EINVAL:1").unwrap();
+            assert_eq!(fail("UI_SET_EVBIT"), Ok(()));
+            assert_eq!(fail("UI_SET_KEYBIT"), Err(Errno::EINVAL));
+            assert_eq!(fail("UI_SET_KEYBIT"), Ok(()));
+        }
+    }
+}
+
+#[cfg(not(feature = "fault-injection"))]
+mod disabled {
+    use log::warn;
+
+    use super::*;
+
+    pub fn init() {
+        if std::env::var_os(FAULTS_ENV).is_some() {
+            warn!(
+                "{} is ignored, vuinputd has been built without the fault-injection feature",
+                FAULTS_ENV
+            );
+        }
+    }
+
+    #[inline(always)]
+    pub fn fail(_point: &str) -> Result<(), Errno> {
+        Ok(())
+    }
+}
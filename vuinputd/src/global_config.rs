@@ -3,22 +3,164 @@
 // Author: Johannes Leupolz <dev@leupolz.eu>
 
 use clap::ValueEnum;
-use std::sync::OnceLock;
+use log::LevelFilter;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Duration;
 
 use crate::container_runtime::ContainerRuntime;
+use crate::cuse_device::container_rules::ContainerRule;
+use crate::cuse_device::device_id::UsbId;
+use crate::cuse_device::device_name::DEFAULT_DENIED_NAMES;
+use crate::cuse_device::device_policy::DEFAULT_GAMEPAD_EXTRA_KEYS;
+use crate::input_realizer::udev_properties::UdevPropertyRule;
 
+/// Settings that are fixed for the lifetime of the daemon. Changing them requires a restart,
+/// as they shape the CUSE session or the devices that already exist.
 #[derive(Debug)]
 pub struct GlobalConfig {
-    pub policy: DevicePolicy,
     pub container_runtime: ContainerRuntime,
     pub vudevname: String,
     pub device_owner: DeviceOwner,
     pub scope: Scope,
+    pub cuse_node: CuseNodePermissions,
+    /// Set with --simulate, the artifacts of the simulated container are written below
+    pub simulation_dir: Option<PathBuf>,
+}
+
+/// Settings that are applied again when the configuration file is reloaded (SIGHUP).
+/// They only affect new requests, e.g. a new device policy is used for the next write.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReloadableConfig {
+    pub policy: DevicePolicy,
+    /// Keys strict-gamepad allows in addition to the gamepad buttons
+    pub gamepad_extra_keys: Vec<u16>,
+    pub protocol_dump: ProtocolDump,
+    pub keystroke_privacy: bool,
+    pub device_name_policy: DeviceNamePolicy,
+    /// Prefix device names with "vuinputd:<container>:"
+    pub name_prefix: bool,
+    /// Names a container may not use, compared case-insensitively
+    pub denied_names: Vec<String>,
+    pub id_policy: IdPolicy,
+    /// Vendor and product ids IdPolicy::Allowlist keeps
+    pub passthrough_ids: Vec<UsbId>,
+    /// Accept the setup by a write of struct uinput_user_dev, see cuse_device::legacy_setup
+    pub legacy_setup: bool,
+    pub log_level: LevelFilter,
+    /// Maximum number of devices a single container may create at the same time
+    pub max_devices_per_container: Option<u32>,
+    /// How often a transiently failing UI_DEV_CREATE is retried, 0 to fail right away
+    pub create_retries: u32,
+    /// How long the cleanup of the containers may take when vuinputd stops
+    pub shutdown_timeout: Duration,
+    /// Desktop user to notify about policy violations, see desktop_notification
+    pub notify_user: Option<String>,
+    /// Seat whose lock screen blocks keyboard keys, see session_lock
+    pub block_keys_when_locked: Option<String>,
+    /// Check the seat of new devices, see jobs::verify_seat_job
+    pub verify_seat: bool,
+    /// Seat the devices are on inside the containers, see input_realizer::seat
+    pub seat_mode: SeatMode,
+    /// Seat of 90-vuinputd-protect.rules, and the seat or prefix of the seats in containers
+    pub seat_name: String,
+    /// Names of the device nodes in the containers, see input_realizer::node_names
+    pub node_naming: NodeNaming,
+    /// Give the nodes in containers to their input group, see input_realizer::input_group
+    pub input_group: bool,
+    /// Gids for containers without an input group in /etc/group, as seen in the container
+    pub input_gids: Vec<u32>,
+    /// Owner, group and mode of the nodes in containers, see input_realizer::node_owner
+    pub node_owner: Option<String>,
+    pub node_group: Option<String>,
+    pub node_mode: Option<u32>,
+    /// Users of the containers that get rw on the nodes through an ACL, see
+    /// input_realizer::node_acl
+    pub node_acl: Vec<String>,
+    /// Whether containers need the approval of the host for their devices, see
+    /// cuse_device::approval
+    pub approval: Approval,
+    /// How long an approval or denial is remembered for a container
+    pub approval_ttl: Duration,
+    /// Rhai script of DevicePolicy::Script, see policy_script
+    pub policy_script: Option<PathBuf>,
+    /// Policies by container, the first rule that matches wins
+    pub container_rules: Vec<ContainerRule>,
+    /// Properties of the udev events in containers by device, see
+    /// input_realizer::udev_properties
+    pub udev_property_rules: Vec<UdevPropertyRule>,
+    pub hooks: LifecycleHooks,
+}
+
+/// Programs that are run for lifecycle events, see jobs::run_hook_job
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LifecycleHooks {
+    pub on_device_created: Option<PathBuf>,
+    pub on_device_removed: Option<PathBuf>,
+    pub on_policy_violation: Option<PathBuf>,
+    /// After this time, a hook is killed and its job counts as failed
+    pub timeout: Duration,
+}
+
+impl Default for LifecycleHooks {
+    fn default() -> Self {
+        Self {
+            on_device_created: None,
+            on_device_removed: None,
+            on_policy_violation: None,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Default for ReloadableConfig {
+    fn default() -> Self {
+        Self {
+            policy: DevicePolicy::default(),
+            gamepad_extra_keys: DEFAULT_GAMEPAD_EXTRA_KEYS.to_vec(),
+            protocol_dump: ProtocolDump::default(),
+            keystroke_privacy: true,
+            device_name_policy: DeviceNamePolicy::default(),
+            name_prefix: false,
+            denied_names: DEFAULT_DENIED_NAMES
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            id_policy: IdPolicy::default(),
+            passthrough_ids: Vec::new(),
+            legacy_setup: true,
+            log_level: LevelFilter::Debug,
+            max_devices_per_container: None,
+            create_retries: 0,
+            shutdown_timeout: Duration::from_secs(10),
+            notify_user: None,
+            block_keys_when_locked: None,
+            verify_seat: false,
+            seat_mode: SeatMode::default(),
+            seat_name: "seat_vuinput".to_string(),
+            node_naming: NodeNaming::default(),
+            input_group: false,
+            input_gids: Vec::new(),
+            node_owner: None,
+            node_group: None,
+            node_mode: None,
+            node_acl: Vec::new(),
+            approval: Approval::default(),
+            approval_ttl: Duration::from_secs(3600),
+            policy_script: None,
+            container_rules: Vec::new(),
+            udev_property_rules: Vec::new(),
+            hooks: LifecycleHooks::default(),
+        }
+    }
 }
 
 // The actual static variable. It starts empty and is set once in main().
 pub static CONFIG: OnceLock<GlobalConfig> = OnceLock::new();
 
+// Swapped as a whole on reload, so a reader never sees a half-applied configuration.
+pub static RELOADABLE_CONFIG: OnceLock<RwLock<Arc<ReloadableConfig>>> = OnceLock::new();
+
 /// Defines the operational scope of the vuinputd instance
 #[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub enum Scope {
@@ -29,6 +171,21 @@ pub enum Scope {
     Single(String),
 }
 
+/// Permissions applied to the CUSE node (/dev/{vudevname}) once it is registered.
+/// Fields that are `None` are left untouched, i.e. to the defaults of devtmpfs and udev.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CuseNodePermissions {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl CuseNodePermissions {
+    pub fn is_unset(&self) -> bool {
+        self.mode.is_none() && self.uid.is_none() && self.gid.is_none()
+    }
+}
+
 /// The device policy decides what events stay and what is filtered out.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Default)]
 #[clap(rename_all = "kebab-case")] // This ensures StrictGamepad becomes "strict-gamepad"
@@ -42,7 +199,84 @@ pub enum DevicePolicy {
     Sanitized,
     /// Only allow Gamepad-like devices. Block mice and keyboards.
     StrictGamepad,
+    /// Only allow touchpads as used by remote desktops. Block mice and keyboards.
+    StrictTouchpad,
+    /// Only allow absolute pointers and pen tablets as used by streaming clients. Block
+    /// keyboards and relative mice.
+    StrictTablet,
+    /// Let the script of policy-script decide (requires the scripted-policy feature)
+    Script,
+}
+/// What to do with device names that contain control characters, invalid UTF-8 or
+/// are not NUL-terminated
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Default)]
+pub enum DeviceNamePolicy {
+    #[default]
+    /// Strip control characters, replace invalid UTF-8 and truncate overlong names
+    Sanitize,
+    /// Refuse the device setup with EINVAL
+    Reject,
+}
+
+/// Whether the vendor and product id of UI_DEV_SETUP reach the host. Devices that keep
+/// their ids are not recognized by 90-vuinputd.hwdb, their phys marks them instead.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Default)]
+pub enum IdPolicy {
+    #[default]
+    /// Replace vendor, product and bus type with 1209:5020 (USB)
+    Rewrite,
+    /// Keep the ids of all clients, e.g. 045e:028e for games that expect an Xbox 360 pad
+    Preserve,
+    /// Keep the ids given with --passthrough-id, rewrite all others
+    Allowlist,
+}
+
+/// The seat that the udev events and the udev database in the container announce
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Default)]
+pub enum SeatMode {
+    #[default]
+    /// No seat, the devices are on the default seat (seat0) of the container
+    None,
+    /// All containers get their devices on --seat-name
+    Shared,
+    /// Each container gets a seat of its own, --seat-name followed by the pid of the container
+    PerContainer,
+}
+
+/// How the device nodes in the containers are named
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Default)]
+pub enum NodeNaming {
+    #[default]
+    /// Like on the host, e.g. /dev/input/event17, unless the name is taken in the container
+    Host,
+    /// Counted per container from /dev/input/event0
+    ContainerLocal,
 }
+
+/// Who decides whether a container may create devices
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Default)]
+pub enum Approval {
+    #[default]
+    /// Every container may, as far as its device policy allows
+    Auto,
+    /// The first UI_DEV_CREATE of a container waits until the host approves or denies it
+    Prompt,
+    /// Only containers the host approved beforehand
+    Deny,
+}
+
+/// Hexdumps of the buffers exchanged with clients (logged at trace level)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Default)]
+pub enum ProtocolDump {
+    #[default]
+    /// Do not dump anything
+    Off,
+    /// Dump buffers, but zero code and value of keyboard keys
+    Redacted,
+    /// Dump buffers as they are, including typed keys
+    Full,
+}
+
 /// Where to create runtime artifacts (device nodes + udev data)
 /// Deprecated, use --container-runtime instead. Currently just maps to
 /// --container-runtime
@@ -79,30 +313,40 @@ impl DeviceOwner {
     }
 }
 
-pub fn initialize_global_config(
-    device_policy: &DevicePolicy,
-    container_runtime: &ContainerRuntime,
-    devname: &Option<String>,
-    device_owner: &DeviceOwner,
-    scope: &Scope,
-) {
-    if CONFIG
-        .set(GlobalConfig {
-            policy: device_policy.clone(),
-            container_runtime: container_runtime.clone(),
-            vudevname: devname.clone().unwrap_or("vuinput".to_string()),
-            device_owner: device_owner.clone(),
-            scope: scope.clone(),
-        })
-        .is_err()
+/// Called once by main(), after the layers of the configuration (see config_file) have been
+/// merged and before the CUSE session starts. The getters panic if called earlier.
+pub fn initialize_global_config(config: GlobalConfig, reloadable: ReloadableConfig) {
+    apply_log_level(reloadable.log_level);
+    if CONFIG.set(config).is_err()
+        || RELOADABLE_CONFIG
+            .set(RwLock::new(Arc::new(reloadable)))
+            .is_err()
     {
         eprintln!("Failed to initialize global config");
         std::process::exit(1);
     }
 }
 
-pub fn get_device_policy<'a>() -> &'a DevicePolicy {
-    &CONFIG.get().unwrap().policy
+/// Replaces the reloadable part of the configuration and returns the previous one.
+pub fn replace_reloadable_config(reloadable: ReloadableConfig) -> Arc<ReloadableConfig> {
+    apply_log_level(reloadable.log_level);
+    let mut guard = RELOADABLE_CONFIG.get().unwrap().write().unwrap();
+    std::mem::replace(&mut *guard, Arc::new(reloadable))
+}
+
+/// RUST_LOG always wins over the configured log level.
+fn apply_log_level(level: LevelFilter) {
+    if std::env::var_os("RUST_LOG").is_none() {
+        log::set_max_level(level);
+    }
+}
+
+pub fn get_reloadable_config() -> Arc<ReloadableConfig> {
+    RELOADABLE_CONFIG.get().unwrap().read().unwrap().clone()
+}
+
+pub fn get_device_policy() -> DevicePolicy {
+    get_reloadable_config().policy
 }
 
 pub fn get_container_runtime<'a>() -> &'a ContainerRuntime {
@@ -120,3 +364,43 @@ pub fn get_device_owner<'a>() -> &'a DeviceOwner {
 pub fn get_scope<'a>() -> &'a Scope {
     &CONFIG.get().unwrap().scope
 }
+
+pub fn get_cuse_node_permissions<'a>() -> &'a CuseNodePermissions {
+    &CONFIG.get().unwrap().cuse_node
+}
+
+pub fn get_simulation_dir<'a>() -> Option<&'a Path> {
+    CONFIG.get().unwrap().simulation_dir.as_deref()
+}
+
+pub fn get_protocol_dump() -> ProtocolDump {
+    get_reloadable_config().protocol_dump
+}
+
+pub fn get_keystroke_privacy() -> bool {
+    get_reloadable_config().keystroke_privacy
+}
+
+pub fn get_max_devices_per_container() -> Option<u32> {
+    get_reloadable_config().max_devices_per_container
+}
+
+pub fn get_create_retries() -> u32 {
+    get_reloadable_config().create_retries
+}
+
+pub fn get_shutdown_timeout() -> Duration {
+    get_reloadable_config().shutdown_timeout
+}
+
+pub fn get_notify_user() -> Option<String> {
+    get_reloadable_config().notify_user.clone()
+}
+
+pub fn get_block_keys_when_locked() -> Option<String> {
+    get_reloadable_config().block_keys_when_locked.clone()
+}
+
+pub fn get_hooks() -> LifecycleHooks {
+    get_reloadable_config().hooks.clone()
+}
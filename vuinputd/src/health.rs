@@ -0,0 +1,168 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Liveness and readiness of a running instance, for probes of container orchestrators and for
+// scripts that start containers only once vuinputd serves them:
+//
+//   live   the control socket answers and the job dispatcher still runs
+//   ready  live, all CUSE nodes are registered and the udev monitor listens (when simulating
+//          devices, there is no udev monitor)
+//
+// The daemon answers the health request of the control socket, "vuinputd health" asks it and
+// turns the answer into the exit code.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::control::protocol::{HealthCheck, Request, Response};
+use crate::job_engine::JOB_DISPATCHER;
+use crate::jobs::monitor_udev_job::is_monitor_running;
+use crate::sd_daemon;
+
+/// How often "vuinputd health --wait" asks again
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// A daemon that does not answer within this time counts as not live
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+static UDEV_MONITOR_EXPECTED: AtomicBool = AtomicBool::new(false);
+
+/// Called when the udev monitor is started, so that readiness waits for it
+pub fn expect_udev_monitor() {
+    UDEV_MONITOR_EXPECTED.store(true, Ordering::Relaxed);
+}
+
+/// The answer to Request::Health
+pub fn report() -> Response {
+    let dispatcher_running = JOB_DISPATCHER
+        .get()
+        .is_some_and(|dispatcher| dispatcher.lock().unwrap().is_running());
+    let udev_monitor = UDEV_MONITOR_EXPECTED
+        .load(Ordering::Relaxed)
+        .then(is_monitor_running);
+    evaluate(dispatcher_running, sd_daemon::is_ready(), udev_monitor)
+}
+
+/// `udev_monitor` is None if no monitor is used
+fn evaluate(dispatcher_running: bool, nodes_ready: bool, udev_monitor: Option<bool>) -> Response {
+    let check = |name: &str, ok: bool, detail: &str| HealthCheck {
+        name: name.to_string(),
+        ok,
+        detail: detail.to_string(),
+    };
+    let mut checks = vec![
+        check(
+            "dispatcher",
+            dispatcher_running,
+            if dispatcher_running {
+                "jobs are processed"
+            } else {
+                "the job dispatcher has stopped"
+            },
+        ),
+        check(
+            "cuse",
+            nodes_ready,
+            if nodes_ready {
+                "all nodes are registered"
+            } else {
+                "waiting for the kernel to register the nodes, or stopping"
+            },
+        ),
+    ];
+    if let Some(running) = udev_monitor {
+        checks.push(check(
+            "udev-monitor",
+            running,
+            if running {
+                "listening for udev events"
+            } else {
+                "not listening for udev events"
+            },
+        ));
+    }
+    let live = dispatcher_running;
+    Response::Health {
+        live,
+        ready: live && checks.iter().all(|check| check.ok),
+        checks,
+    }
+}
+
+fn query(socket: &Path) -> io::Result<Response> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    stream.set_write_timeout(Some(QUERY_TIMEOUT))?;
+    serde_json::to_writer(&mut stream, &Request::Health)?;
+    stream.write_all(b"\n")?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    serde_json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// "vuinputd health": asks the instance behind `socket` and returns the exit code, 0 if it is
+/// ready (or only live, with `live_only`). With `wait`, asks again until then.
+pub fn run_health(socket: &Path, live_only: bool, wait: Option<Duration>) -> i32 {
+    let deadline = Instant::now() + wait.unwrap_or_default();
+    loop {
+        let (healthy, summary) = match query(socket) {
+            Ok(Response::Health {
+                live,
+                ready,
+                checks,
+            }) => {
+                let summary = checks
+                    .iter()
+                    .map(|check| {
+                        let state = if check.ok { "ok" } else { "failed" };
+                        format!("{}: {} ({})", check.name, state, check.detail)
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                (if live_only { live } else { ready }, summary)
+            }
+            Ok(response) => (false, format!("unexpected answer: {:?}", response)),
+            Err(e) => (false, format!("{}: {}", socket.display(), e)),
+        };
+        if healthy || Instant::now() >= deadline {
+            match healthy {
+                true => println!("{}", summary),
+                false => eprintln!("{}", summary),
+            }
+            return if healthy { 0 } else { 1 };
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn live_and_ready(response: Response) -> (bool, bool) {
+        match response {
+            Response::Health { live, ready, .. } => (live, ready),
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    #[test]
+    fn ready_needs_all_checks() {
+        assert_eq!(
+            live_and_ready(evaluate(true, true, Some(true))),
+            (true, true)
+        );
+        assert_eq!(live_and_ready(evaluate(true, true, None)), (true, true));
+        assert_eq!(
+            live_and_ready(evaluate(true, true, Some(false))),
+            (true, false)
+        );
+        assert_eq!(live_and_ready(evaluate(true, false, None)), (true, false));
+        assert_eq!(live_and_ready(evaluate(false, true, None)), (false, false));
+    }
+}
@@ -0,0 +1,70 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// vuinputd reads some filesystems of the host: /proc for the processes that open the device,
+// /sys for the created devices and /run/udev for the udev data of the host. If vuinputd runs
+// in a privileged container itself, they can be bind-mounted below a directory (e.g. /host)
+// that is passed with --host-root.
+//
+// The pid of a request (fuse_req_ctx) is the one in the PID namespace of vuinputd. If that is
+// not the namespace of the /proc below the host root, the pid is translated (see
+// process_tools::pid_translation). Clients in sibling containers are not visible in the PID
+// namespace of a containerized vuinputd at all (the pid is 0), so the container of vuinputd
+// has to share the PID namespace of the host (e.g. podman run --pid=host).
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+static HOST_ROOT: OnceLock<PathBuf> = OnceLock::new();
+static SAME_PID_NAMESPACE: OnceLock<Option<bool>> = OnceLock::new();
+
+/// Set once at startup, before any path of the host is read
+pub fn set_host_root(root: &Path) {
+    let _ = HOST_ROOT.set(root.to_path_buf());
+}
+
+pub fn host_root() -> Option<&'static Path> {
+    HOST_ROOT.get().map(PathBuf::as_path)
+}
+
+fn join(root: Option<&Path>, path: &str) -> String {
+    match root {
+        Some(root) => format!("{}{}", root.to_string_lossy().trim_end_matches('/'), path),
+        None => path.to_string(),
+    }
+}
+
+/// The absolute path of the host (e.g. "/run/udev/data") as seen by vuinputd
+pub fn host_path(path: &str) -> String {
+    join(host_root(), path)
+}
+
+/// Whether vuinputd runs in the PID namespace of the /proc of the host. None, if that can't
+/// be told, e.g. because the /proc of the host is not mounted.
+pub fn is_in_host_pid_namespace() -> Option<bool> {
+    *SAME_PID_NAMESPACE.get_or_init(|| {
+        let own = fs::read_link("/proc/self/ns/pid").ok()?;
+        let host = fs::read_link(host_path("/proc/1/ns/pid")).ok()?;
+        Some(own == host)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes_paths_of_the_host() {
+        assert_eq!(join(None, "/run/udev/data"), "/run/udev/data");
+        assert_eq!(
+            join(Some(Path::new("/host/")), "/run/udev/data"),
+            "/host/run/udev/data"
+        );
+        assert_eq!(
+            join(Some(Path::new("/host")), "/sys/devices/virtual/input/"),
+            "/host/sys/devices/virtual/input/"
+        );
+    }
+}
@@ -0,0 +1,219 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Containers that run a udevd of their own (systemd-udevd or eudev). Usually, vuinputd writes
+// /run/udev/data of the container and sends the event as udevd would have sent it. A udevd in
+// the container writes its database as well, and the two race for the same files.
+//
+// If the container has the control socket of udevd and a udevd process in its mount namespace,
+// vuinputd hands the device to that udevd instead, like `udevadm trigger` would: the kernel sends
+// the event in the network namespace of the container, with "add@/devices/..." and the
+// properties of the kernel only (uevent injection, see netlink_message::send_kernel_uevent). The
+// udevd runs the rules of the container, writes the database and tells its listeners.
+//
+// udevd ignores kernel events that do not come from the kernel, so the injection needs Linux 4.18.
+// Whether the udevd took the event shows by its database entry of the device; if the injection
+// fails or no entry shows up within UDEVD_TIMEOUT, vuinputd falls back to writing the database
+// itself, for the rest of the life of the container.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_io::Timer;
+
+use crate::host_root::host_path;
+use crate::process_tools::{get_namespace, get_self_namespace, Pid, RequestingProcess};
+
+/// How long the udevd of a container may take for the database entry of a device
+pub const UDEVD_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Names of udevd in /proc/<pid>/comm
+const UDEVD_NAMES: [&str; 2] = ["systemd-udevd", "udevd"];
+
+/// Properties of the kernel for input devices and their event nodes, in the order of the
+/// kernel. Everything else of an event has been added by udevd.
+const KERNEL_PROPERTIES: [&str; 18] = [
+    "ACTION",
+    "DEVPATH",
+    "SUBSYSTEM",
+    "PRODUCT",
+    "NAME",
+    "PHYS",
+    "UNIQ",
+    "PROP",
+    "EV",
+    "KEY",
+    "REL",
+    "ABS",
+    "MSC",
+    "LED",
+    "SND",
+    "FF",
+    "SW",
+    "MODALIAS",
+];
+
+/// Whether the udevd of a container took the events of vuinputd, by root pid of the container
+static ACCEPTED: Mutex<BTreeMap<u32, bool>> = Mutex::new(BTreeMap::new());
+
+/// The directory of the container, as vuinputd sees it
+fn container_root(requesting_process: &RequestingProcess) -> String {
+    format!("{}/root", requesting_process.pid_requestor_root.path())
+}
+
+fn has_control_socket(root: &str) -> bool {
+    fs::metadata(format!("{}/run/udev/control", root))
+        .is_ok_and(|metadata| metadata.file_type().is_socket())
+}
+
+/// Whether a udevd process runs in the mount namespace `mnt`
+fn has_udevd_process(mnt: u64) -> bool {
+    let Ok(entries) = fs::read_dir(host_path("/proc")) else {
+        return false;
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<u32>().ok())
+        .filter(|pid| {
+            fs::read_to_string(format!("{}/comm", Pid::Pid(*pid).path()))
+                .is_ok_and(|comm| UDEVD_NAMES.contains(&comm.trim_end()))
+        })
+        .any(|pid| get_namespace(Pid::Pid(pid)).mnt == Some(mnt))
+}
+
+/// Whether vuinputd hands the devices of the container to a udevd of the container: one runs,
+/// and it has not ignored an event of vuinputd yet
+pub fn is_in_charge(requesting_process: &RequestingProcess) -> bool {
+    let container = requesting_process.pid_requestor_root.as_raw();
+    if ACCEPTED.lock().unwrap().get(&container) == Some(&false) {
+        return false;
+    }
+    // the udevd of the host (or of the --simulate run) is not one of a container
+    let Some(mnt) = requesting_process.namespaces.mnt else {
+        return false;
+    };
+    if get_self_namespace().mnt == Some(mnt) {
+        return false;
+    }
+    has_control_socket(&container_root(requesting_process)) && has_udevd_process(mnt)
+}
+
+/// Whether the udevd of the container took the events of vuinputd, so that the removal goes to
+/// it as well
+pub fn has_accepted(requesting_process: &RequestingProcess) -> bool {
+    let container = requesting_process.pid_requestor_root.as_raw();
+    ACCEPTED.lock().unwrap().get(&container) == Some(&true) && is_in_charge(requesting_process)
+}
+
+/// Records whether the udevd of the container took an event of vuinputd. Containers that are
+/// gone are forgotten.
+pub fn set_accepted(requesting_process: &RequestingProcess, accepted: bool) {
+    let container = requesting_process.pid_requestor_root.as_raw();
+    let mut verdicts = ACCEPTED.lock().unwrap();
+    verdicts.retain(|pid, _| fs::metadata(Pid::Pid(*pid).path()).is_ok());
+    verdicts.insert(container, accepted);
+}
+
+/// Waits up to `timeout` until the database entry of the device exists in the container
+pub async fn await_database_entry(
+    requesting_process: &RequestingProcess,
+    major: u64,
+    minor: u64,
+    timeout: Duration,
+) -> bool {
+    let path = format!(
+        "{}/run/udev/data/c{}:{}",
+        container_root(requesting_process),
+        major,
+        minor
+    );
+    let deadline = Instant::now() + timeout;
+    loop {
+        if Path::new(&path).exists() {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        Timer::after(Duration::from_millis(50)).await;
+    }
+}
+
+/// The event as the kernel sends it: "ACTION@DEVPATH", then its properties separated by NUL.
+/// DEVNAME is relative to /dev, like the kernel has it.
+pub fn encode_kernel_uevent(properties: &HashMap<String, String>) -> Option<Vec<u8>> {
+    let action = properties.get("ACTION")?;
+    let devpath = properties.get("DEVPATH")?;
+    let mut payload = format!("{}@{}", action, devpath).into_bytes();
+    payload.push(0);
+    let mut push = |key: &str, value: &str| {
+        payload.extend(format!("{}={}", key, value).into_bytes());
+        payload.push(0);
+    };
+    for key in KERNEL_PROPERTIES {
+        if let Some(value) = properties.get(key) {
+            push(key, value);
+        }
+    }
+    for key in ["MAJOR", "MINOR"] {
+        if let Some(value) = properties.get(key) {
+            push(key, value);
+        }
+    }
+    if let Some(devname) = properties.get("DEVNAME") {
+        push("DEVNAME", devname.strip_prefix("/dev/").unwrap_or(devname));
+    }
+    if let Some(seqnum) = properties.get("SEQNUM") {
+        push("SEQNUM", seqnum);
+    }
+    Some(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_events_like_the_kernel() {
+        let properties: HashMap<String, String> = [
+            ("ACTION", "add"),
+            ("DEVPATH", "/devices/virtual/input/input155/event12"),
+            ("SUBSYSTEM", "input"),
+            ("DEVNAME", "/dev/input/event12"),
+            ("MAJOR", "13"),
+            ("MINOR", "76"),
+            ("SEQNUM", "18610"),
+            ("ID_INPUT", "1"),
+            ("ID_SEAT", "seat_vuinput"),
+            ("TAGS", ":seat_vuinput:"),
+            ("USEC_INITIALIZED", "1674737477373"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+        let payload = encode_kernel_uevent(&properties).unwrap();
+        assert_eq!(
+            String::from_utf8(payload)
+                .unwrap()
+                .split('\0')
+                .collect::<Vec<_>>(),
+            [
+                "add@/devices/virtual/input/input155/event12",
+                "ACTION=add",
+                "DEVPATH=/devices/virtual/input/input155/event12",
+                "SUBSYSTEM=input",
+                "MAJOR=13",
+                "MINOR=76",
+                "DEVNAME=input/event12",
+                "SEQNUM=18610",
+                "",
+            ]
+        );
+        assert!(encode_kernel_uevent(&HashMap::new()).is_none());
+    }
+}
@@ -2,21 +2,31 @@
 //
 // Author: Johannes Leupolz <dev@leupolz.eu>
 
-use std::io::{self, BufRead};
-use std::{
-    fs::{self, File},
-    path::Path,
-};
+// The directories of --placement on-host below /run/vuinputd/<devname>. /run is usually
+// mounted with nodev, which makes the nodes in dev-input useless and bind mounts of them with
+// dev fail. vuinputd therefore mounts its own small tmpfs without nodev on dev-input. It stays
+// mounted when vuinputd stops: the bind mounts of running containers refer to it, and they would
+// keep a detached tmpfs that never sees the nodes of the next run. It has "vuinputd" as its
+// source, so that the next run takes it over instead of stacking another one.
+
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Source of the tmpfs vuinputd mounts, shown in /proc/self/mountinfo and by findmnt
+const TMPFS_SOURCE: &str = "vuinputd";
 
 /// Ensure required dev-input, udev directories and files exist
 pub fn ensure_host_fs_structure(path_prefix: &str) -> io::Result<()> {
-    let _ = check_if_path_allows_char_devs(&path_prefix);
     let dev_input_dir = format!("{}/dev-input", path_prefix);
     let dev_input_dir = Path::new(&dev_input_dir);
     // Create directory like `mkdir -p`
     if !dev_input_dir.exists() {
         fs::create_dir_all(dev_input_dir)?;
     }
+    ensure_dev_tmpfs(dev_input_dir)?;
 
     // Note that this structure _must_ exist, before a service using libinput is run.
     let data_dir = format!("{}/udev/data", path_prefix);
@@ -36,45 +46,131 @@ pub fn ensure_host_fs_structure(path_prefix: &str) -> io::Result<()> {
     Ok(())
 }
 
-/// simple heuristic that checks whether path_prefix allows the hosting of character devices
-/// This heuristic is not 100%, but a simple indicator
-pub fn check_if_path_allows_char_devs(path: &str) -> io::Result<()> {
-    let file = File::open("/proc/self/mountinfo")?;
-    let reader = io::BufReader::new(file);
-
-    for line in reader.lines() {
-        let line = line?;
-
-        let (left, _) = match line.split_once(" - ") {
-            Some(v) => v,
-            None => continue,
-        };
-
-        let fields: Vec<&str> = left.split_whitespace().collect();
+/// The mount that holds a path, from /proc/<pid>/mountinfo
+#[derive(Debug, PartialEq, Eq)]
+pub struct Mount {
+    pub mount_point: PathBuf,
+    pub fs_type: String,
+    pub source: String,
+    pub nodev: bool,
+    /// The owners of its files are translated, see mount_setattr(2)
+    pub idmapped: bool,
+}
 
-        // mount point is field 5
-        let mount_point = fields.get(4).copied().unwrap_or("");
-        // mount options are field 6
-        let options = fields.get(5).copied().unwrap_or("");
+/// The mount of `path` in the content of a mountinfo, whose mount points are relative to the
+/// root of the process it has been read from
+pub fn mount_of(mountinfo: &str, path: &Path) -> Option<Mount> {
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let (left, right) = line.split_once(" - ")?;
+            let fields: Vec<&str> = left.split_whitespace().collect();
+            let mut right = right.split_whitespace();
+            let mount_point = PathBuf::from(fields.get(4)?);
+            let has_option = |option: &str| {
+                fields
+                    .get(5)
+                    .is_some_and(|o| o.split(',').any(|o| o == option))
+            };
+            path.starts_with(&mount_point).then(|| Mount {
+                nodev: has_option("nodev"),
+                idmapped: has_option("idmapped"),
+                mount_point,
+                fs_type: right.next().unwrap_or_default().to_string(),
+                source: right.next().unwrap_or_default().to_string(),
+            })
+        })
+        // the mount that was made last on the longest prefix is the one that counts
+        .fold(None, |found: Option<Mount>, mount| match &found {
+            Some(f) if f.mount_point.as_os_str().len() > mount.mount_point.as_os_str().len() => {
+                found
+            }
+            _ => Some(mount),
+        })
+}
 
-        if mount_point.contains(path) {
-            if options.split(',').any(|o| o == "nodev") {
+/// Mounts a tmpfs without nodev on `dev_input`, unless the nodes can already be used there
+fn ensure_dev_tmpfs(dev_input: &Path) -> io::Result<()> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    match mount_of(&mountinfo, dev_input) {
+        Some(mount) if mount.mount_point == dev_input => {
+            if mount.fs_type == "tmpfs" && mount.source == TMPFS_SOURCE {
+                log::info!("taking over the tmpfs on {}", dev_input.display());
+            } else if mount.nodev {
                 log::warn!(
-                    "mount {} is present but mounted with nodev; device nodes will not work",
-                    path
+                    "{} is mounted with nodev; device nodes will not work",
+                    dev_input.display()
                 );
             } else {
-                log::info!("mount {} is present and allows device nodes", path);
+                log::info!("using the mount on {}", dev_input.display());
             }
-
             return Ok(());
         }
+        Some(mount) if !mount.nodev => return Ok(()),
+        _ => {}
     }
 
-    log::warn!(
-        "expected mount {} not found; user likely forgot to mount tmpfs with dev-option on it",
-        path
-    );
-
+    let target = CString::new(dev_input.as_os_str().as_bytes()).map_err(io::Error::other)?;
+    let source = CString::new(TMPFS_SOURCE).map_err(io::Error::other)?;
+    let ret = unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            c"tmpfs".as_ptr(),
+            libc::MS_NOSUID | libc::MS_NOEXEC,
+            c"mode=0755,size=1m".as_ptr().cast(),
+        )
+    };
+    if ret != 0 {
+        let e = io::Error::last_os_error();
+        log::warn!(
+            "could not mount a tmpfs with dev on {}; device nodes will not work: {}",
+            dev_input.display(),
+            e
+        );
+        return Err(e);
+    }
+    log::info!("mounted a tmpfs with dev on {}", dev_input.display());
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &str = "\
+25 1 259:2 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw
+26 25 0:23 / /run rw,nosuid,nodev,noexec,relatime shared:2 - tmpfs tmpfs rw,mode=755
+27 26 0:45 / /run/vuinputd/games/dev-input rw,nosuid,noexec,relatime shared:3 - tmpfs vuinputd rw,size=1024k,mode=755
+28 25 0:45 / /srv/games/dev/input rw,nosuid,noexec,relatime,idmapped shared:3 - tmpfs vuinputd rw,size=1024k,mode=755
+";
+
+    #[test]
+    fn finds_the_mount_of_the_nodes() {
+        let run = mount_of(MOUNTINFO, Path::new("/run/vuinputd/vuinput/dev-input")).unwrap();
+        assert_eq!(run.mount_point, Path::new("/run"));
+        assert!(run.nodev);
+
+        let games = mount_of(MOUNTINFO, Path::new("/run/vuinputd/games/dev-input")).unwrap();
+        assert_eq!(
+            games,
+            Mount {
+                mount_point: PathBuf::from("/run/vuinputd/games/dev-input"),
+                fs_type: "tmpfs".to_string(),
+                source: TMPFS_SOURCE.to_string(),
+                nodev: false,
+                idmapped: false,
+            }
+        );
+        assert!(
+            mount_of(MOUNTINFO, Path::new("/srv/games/dev/input/event3"))
+                .unwrap()
+                .idmapped
+        );
+
+        // /run is not a prefix of /running
+        let root = mount_of(MOUNTINFO, Path::new("/running")).unwrap();
+        assert_eq!(root.mount_point, Path::new("/"));
+        assert!(!root.nodev);
+    }
+}
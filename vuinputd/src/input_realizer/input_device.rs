@@ -4,11 +4,48 @@
 
 use anyhow::anyhow;
 use nix::sys::stat::{makedev, mknod, stat, Mode, SFlag};
+use nix::unistd::{chown, Gid, Uid};
+use serde::{Deserialize, Serialize};
 use std::fs;
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::Path;
 
-pub fn ensure_input_device(dev_path: String, major: u64, minor: u64) -> anyhow::Result<()> {
+use crate::fault_injection;
+use crate::input_realizer::node_acl;
+
+/// Owner, group and mode of a node, the ids as seen from the host, see node_owner
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeOwnership {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Applied once the node belongs to uid and gid, the node stays at 0666 if it can't be
+    /// given to them
+    pub mode: u32,
+    /// Host uids that get rw through an ACL, see node_acl. None leaves the ACL of the node
+    /// alone, an empty list removes it.
+    #[serde(default)]
+    pub acl_uids: Option<Vec<u32>>,
+}
+
+impl Default for NodeOwnership {
+    fn default() -> Self {
+        Self {
+            uid: None,
+            gid: None,
+            mode: 0o666,
+            acl_uids: None,
+        }
+    }
+}
+
+/// Creates the node of major:minor at `dev_path` with the given ownership. By default, the
+/// node belongs to root of the host and anybody may open it.
+pub fn ensure_input_device(
+    dev_path: String,
+    major: u64,
+    minor: u64,
+    ownership: &NodeOwnership,
+) -> anyhow::Result<()> {
     let input_dir = Path::new("/dev/input");
     // Create directory like `mkdir -p`
     if !input_dir.exists() {
@@ -18,7 +55,7 @@ pub fn ensure_input_device(dev_path: String, major: u64, minor: u64) -> anyhow::
 
     let path = Path::new(&dev_path);
     let expected_dev = makedev(major, minor);
-    let expected_mode = 0o666;
+    let mut expected_mode = 0o666;
 
     // --- Step 1: Ensure node correctness ---
     let needs_replacement = if path.exists() {
@@ -38,6 +75,7 @@ pub fn ensure_input_device(dev_path: String, major: u64, minor: u64) -> anyhow::
         println!("Replacing {}", dev_path);
         let _ = fs::remove_file(path);
         let mode = Mode::from_bits_truncate(expected_mode);
+        fault_injection::fail("mknod")?;
         mknod(path, SFlag::S_IFCHR, mode, expected_dev)?;
     } else {
         println!("{} is already correct device", dev_path);
@@ -45,26 +83,41 @@ pub fn ensure_input_device(dev_path: String, major: u64, minor: u64) -> anyhow::
 
     // --- Step 2: Ensure ownership and permissions ---
     if let Ok(meta) = fs::metadata(path) {
+        let uid = ownership.uid.filter(|uid| *uid != meta.uid());
+        let gid = ownership.gid.filter(|gid| *gid != meta.gid());
+        // the owner first: a node of root with 0660 could not be opened by anybody
+        let owned = match (uid, gid) {
+            (None, None) => true,
+            _ => match chown(path, uid.map(Uid::from_raw), gid.map(Gid::from_raw)) {
+                Ok(()) => true,
+                Err(e) => {
+                    println!(
+                        "Could not give {} to {}:{}: {}",
+                        dev_path,
+                        uid.unwrap_or(meta.uid()),
+                        gid.unwrap_or(meta.gid()),
+                        e
+                    );
+                    false
+                }
+            },
+        };
+        if owned {
+            expected_mode = ownership.mode;
+        }
         let perms = meta.permissions().mode() & 0o777;
+        let acl_uids = ownership.acl_uids.as_deref().unwrap_or_default();
 
-        if perms != expected_mode {
+        if perms != node_acl::visible_mode(expected_mode, acl_uids) {
             println!("Fixing mode of {} (was {:o})", dev_path, perms);
             fs::set_permissions(path, fs::Permissions::from_mode(expected_mode))?;
         }
-
-        /* TODO: Think about it
-        let expected_uid = 0;
-        let expected_gid = 0;
-        let uid = meta.uid();
-        let gid = meta.gid();
-        if uid != expected_uid || gid != expected_gid {
-            println!(
-                "Fixing ownership of {} (was uid={}, gid={})",
-                dev_path, uid, gid
-            );
-            chown(path, Some(Uid::from_raw(expected_uid)), Some(Gid::from_raw(expected_gid)))?;
+        // after the mode, chmod would change the mask of the ACL
+        if let Some(uids) = &ownership.acl_uids {
+            if let Err(e) = node_acl::apply(path, expected_mode, uids) {
+                println!("Could not grant {} to {:?}: {}", dev_path, uids, e);
+            }
         }
-         */
     }
 
     Ok(())
@@ -0,0 +1,102 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// The group of the device nodes in containers (input-group). Without it, the nodes belong to
+// root with mode 0666. With it, they belong to the input group of the container with mode 0660,
+// so that its members can open them without being root and other users can't. Images differ in
+// the gid of that group (e.g. 104 on Debian, 997 on Fedora) and rootless containers often map
+// only a few gids, so the gid is looked up in /etc/group of the container, read through
+// /proc/<pid>/root, i.e. its mount namespace, with the care of container_fs. Images without an input group get the first of
+// input-gids the user namespace of the container maps.
+//
+// The node is created from outside the user namespace of the container, so the gid is
+// translated to the host with the gid_map of the container.
+
+use log::{debug, warn};
+
+use crate::global_config::get_reloadable_config;
+use crate::process_tools::container_fs;
+use crate::process_tools::ns_fscreds::get_gid_in_container;
+use crate::process_tools::RequestingProcess;
+
+/// The gid of the group `name` in the content of an /etc/group
+fn group_gid(group_file: &str, name: &str) -> Option<u32> {
+    group_file.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        if fields.next()? != name {
+            return None;
+        }
+        fields.nth(1)?.trim().parse().ok()
+    })
+}
+
+/// The input group of /etc/group, then the fallback gids: the first one `to_host` maps,
+/// with its gid in the container
+fn choose_gid(
+    group_file: &str,
+    fallback_gids: &[u32],
+    to_host: impl Fn(u32) -> Option<u32>,
+) -> Option<(u32, u32)> {
+    group_gid(group_file, "input")
+        .into_iter()
+        .chain(fallback_gids.iter().copied())
+        .find_map(|gid| to_host(gid).map(|host_gid| (gid, host_gid)))
+}
+
+/// The host gid of the group the nodes in the container of `requesting_process` get, None
+/// if input-group is off or the container maps none of the gids
+pub fn node_gid(requesting_process: &RequestingProcess) -> Option<u32> {
+    let config = get_reloadable_config();
+    if !config.input_group {
+        return None;
+    }
+    let root = requesting_process.pid_requestor_root;
+    let group_file = container_fs::read_to_string(root, "/etc/group")
+        .inspect_err(|e| warn!("/etc/group of container {}: {}", root.as_raw(), e))
+        .unwrap_or_default();
+    let to_host = |gid: u32| get_gid_in_container(root, gid.into()).ok();
+    match choose_gid(&group_file, &config.input_gids, to_host) {
+        Some((gid, host_gid)) => {
+            debug!(
+                "nodes of container {} get gid {} (host gid {})",
+                root.as_raw(),
+                gid,
+                host_gid
+            );
+            Some(host_gid)
+        }
+        None => {
+            warn!(
+                "container {} has no input group and maps none of input-gids, its nodes stay \
+                 readable by everybody",
+                root.as_raw()
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GROUP: &str = "root:x:0:\nplugdev:x:46:alice\ninput:x:104:alice,bob\n";
+
+    #[test]
+    fn finds_the_input_group_of_the_image() {
+        assert_eq!(group_gid(GROUP, "input"), Some(104));
+        assert_eq!(group_gid(GROUP, "video"), None);
+        assert_eq!(group_gid("input:x:\n", "input"), None);
+
+        // a rootless container that maps 0-65535 to 100000-165535
+        let rootless = |gid: u32| (gid < 65536).then_some(100000 + gid);
+        assert_eq!(choose_gid(GROUP, &[], rootless), Some((104, 100104)));
+        // an image without an input group gets the first fallback that is mapped
+        assert_eq!(
+            choose_gid("root:x:0:\n", &[70000, 995], rootless),
+            Some((995, 100995))
+        );
+        assert_eq!(choose_gid("root:x:0:\n", &[70000], rootless), None);
+    }
+}
@@ -2,7 +2,17 @@
 //
 // Author: Johannes Leupolz <dev@leupolz.eu>
 
+pub mod container_udevd;
 pub mod host_fs;
 pub mod input_device;
+pub mod input_group;
 pub mod netlink_message;
+pub mod node_acl;
+pub mod node_names;
+pub mod node_owner;
 pub mod runtime_data;
+pub mod seat;
+pub mod udev_properties;
+
+#[cfg(test)]
+mod snapshot_tests;
@@ -10,13 +10,19 @@ use std::io::IoSlice;
 
 use log::debug;
 use nix::sys::socket::{
-    bind, sendmsg, socket, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol, SockType,
+    bind, recv, sendmsg, setsockopt, socket, sockopt, AddressFamily, MsgFlags, NetlinkAddr,
+    SockFlag, SockProtocol, SockType,
 };
+use nix::sys::time::TimeVal;
+
+use crate::fault_injection;
 
 /// Netlink constants
 pub const UDEV_EVENT_MODE: u32 = 2;
 pub const UDEV_MONITOR_MAGIC: u32 = 0xfeedcafe;
 pub const MAX_NETLINK_PAYLOAD: usize = 64 * 1024; // 64 KiB
+/// Type of the messages that ask the kernel to send a uevent into a network namespace
+const UEVENT_SEND: u16 = 16;
 
 // to test, use "udevadm --debug monitor -p"
 
@@ -72,15 +78,35 @@ impl MonitorNetlinkHeader {
     }
 }
 
+/// The hash of a subsystem or devtype for the filter of the listeners. Needs to be compatible
+/// with string_hash32 of https://github.com/systemd/systemd/blob/main/src/libsystemd/sd-device/device-monitor.c,
+/// which is MurmurHash2 with seed 0 (src/basic/MurmurHash2.c), reading the blocks in the byte
+/// order of the host like systemd does.
 pub fn string_hash32(s: &str) -> u32 {
-    // Note: needs to be compatible with https://github.com/systemd/systemd/blob/main/src/libudev/libudev-monitor.c
-    // and https://gitlab.freedesktop.org/libinput/libinput/-/blob/main/src/udev-seat.c?ref_type=heads.
-    // Because in our use case, only subsystem "input" is relevant, we just hard code the values from murmur hash 2.
-    match s {
-        "input" => 3248653424,
-        "" => 0,
-        _ => panic!("uncovered use case"),
+    const M: u32 = 0x5bd1e995;
+    const R: u32 = 24;
+    let data = s.as_bytes();
+    let mut h = data.len() as u32;
+    let mut blocks = data.chunks_exact(4);
+    for block in &mut blocks {
+        let mut k = u32::from_ne_bytes(block.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        for (index, byte) in tail.iter().enumerate() {
+            h ^= (*byte as u32) << (8 * index);
+        }
+        h = h.wrapping_mul(M);
     }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h
 }
 
 /// Open netlink socket, bind to groups
@@ -123,6 +149,7 @@ pub fn send_udev_monitor_message(
     let header = MonitorNetlinkHeader::new(payload.len(), subsystem, devtype);
     let header_bytes = header.to_bytes();
 
+    fault_injection::fail("netlink").map_err(|e| format!("Could not send message: {}", e))?;
     let fd = open_netlink(groups)?;
 
     // prepare iovecs
@@ -147,19 +174,90 @@ pub fn send_udev_monitor_message(
     Ok(())
 }
 
-pub fn send_udev_monitor_message_with_properties(properties: HashMap<String, String>) {
-    let device_name = match properties.get("DEVNAME") {
-        Some(name) => name,
-        None => "unknown device",
+/// Has the kernel send `payload` ("add@/devices/...\0KEY=VALUE\0...") as a uevent of its own to
+/// the listeners of the network namespace of the caller (Linux 4.18). Unlike messages of
+/// send_udev_monitor_message, udevd takes these: they come from the kernel. The caller needs
+/// CAP_SYS_ADMIN in the user namespace that owns the network namespace.
+pub fn send_kernel_uevent(payload: &[u8]) -> Result<(), String> {
+    let header_len = mem::size_of::<libc::nlmsghdr>();
+    if payload.len() + header_len > MAX_NETLINK_PAYLOAD {
+        return Err(format!(
+            "Total payload too large: {} bytes (max {})",
+            payload.len() + header_len,
+            MAX_NETLINK_PAYLOAD
+        ));
+    }
+    let header = libc::nlmsghdr {
+        nlmsg_len: (header_len + payload.len()) as u32,
+        nlmsg_type: UEVENT_SEND,
+        nlmsg_flags: (libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16,
+        nlmsg_seq: 1,
+        nlmsg_pid: 0,
     };
-    debug!("Sending udev message over netlink for {}", device_name);
+    let header_bytes = unsafe {
+        std::slice::from_raw_parts(&header as *const libc::nlmsghdr as *const u8, header_len)
+    };
+
+    fault_injection::fail("netlink").map_err(|e| format!("Could not send message: {}", e))?;
+    let fd = open_netlink(0)?;
+    setsockopt(&fd, sockopt::ReceiveTimeout, &TimeVal::new(1, 0))
+        .map_err(|e| format!("Could not set a timeout: {}", e))?;
+
+    let iov = [IoSlice::new(header_bytes), IoSlice::new(payload)];
+    // to the kernel itself, not to a group
+    let sockaddr = NetlinkAddr::new(0, 0);
+    sendmsg(
+        fd.as_raw_fd(),
+        &iov,
+        &[],
+        MsgFlags::empty(),
+        Some(&sockaddr),
+    )
+    .map_err(|e| format!("Could not send message: {}", e))?;
+
+    // the acknowledgement: struct nlmsghdr of type NLMSG_ERROR, then the negated errno
+    let mut ack = [0u8; 64];
+    let len = recv(fd.as_raw_fd(), &mut ack, MsgFlags::empty())
+        .map_err(|e| format!("No acknowledgement of the kernel: {}", e))?;
+    if len < header_len + 4 {
+        return Err(format!(
+            "Short acknowledgement of the kernel: {} bytes",
+            len
+        ));
+    }
+    let error = i32::from_ne_bytes(ack[header_len..header_len + 4].try_into().unwrap());
+    if error != 0 {
+        return Err(format!(
+            "The kernel refused the uevent: {}",
+            nix::errno::Errno::from_raw(-error)
+        ));
+    }
+    debug!("kernel uevent sent");
+    Ok(())
+}
+
+/// Encodes the properties as `KEY=VALUE\0` pairs, sorted by key so that the same
+/// properties always result in the same message.
+pub fn encode_properties(properties: &HashMap<String, String>) -> Vec<u8> {
+    let mut keys: Vec<&String> = properties.keys().collect();
+    keys.sort();
     let mut payload: Vec<u8> = Vec::new();
-    for (key, value) in properties.iter() {
+    for key in keys {
         payload.extend(key.as_bytes());
         payload.extend("=".as_bytes());
-        payload.extend(value.as_bytes());
+        payload.extend(properties[key].as_bytes());
         payload.push(0);
     }
+    payload
+}
+
+pub fn send_udev_monitor_message_with_properties(properties: HashMap<String, String>) {
+    let device_name = match properties.get("DEVNAME") {
+        Some(name) => name,
+        None => "unknown device",
+    };
+    debug!("Sending udev message over netlink for {}", device_name);
+    let payload = encode_properties(&properties);
 
     send_udev_monitor_message(&payload, Some("input"), None, UDEV_EVENT_MODE).unwrap();
 }
@@ -0,0 +1,149 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// POSIX ACLs on the device nodes in containers (node-acl), like setfacl -m u:<uid>:rw. logind
+// does the same for the devices tagged with uaccess: the user of the active session on a seat
+// gets access to them, whatever owner and mode they have. A container with logind does not see
+// the devices of vuinputd come from its own udevd, so vuinputd grants the access itself.
+//
+// node-acl names users of the container or "@active", the user of the active session on the
+// seat of the container's devices, which is read from /run/systemd/seats/<seat> of the
+// container (ACTIVE_UID=, written by logind). jobs::node_acl_job applies the ACL again when
+// that user changes.
+//
+// The ACL is written as the xattr system.posix_acl_access, in the format of the kernel (see
+// include/uapi/linux/posix_acl_xattr.h), so that no libacl is needed.
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::process_tools::container_fs;
+use crate::process_tools::Pid;
+
+/// The entry of node-acl for the user of the active session
+pub const ACTIVE_USER: &str = "@active";
+
+const XATTR_NAME: &std::ffi::CStr = c"system.posix_acl_access";
+const ACL_VERSION: u32 = 2;
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+/// What the users of node-acl get
+const ACL_RW: u16 = 0o6;
+
+/// The ACL of a node with `mode` that also grants rw to `uids`, as the value of the xattr
+fn encode(mode: u32, uids: &[u32]) -> Vec<u8> {
+    let mut uids = uids.to_vec();
+    // the kernel wants the named users in ascending order and each only once
+    uids.sort_unstable();
+    uids.dedup();
+    let bits = |shift: u32| ((mode >> shift) & 0o7) as u16;
+    let mut entries = vec![(ACL_USER_OBJ, bits(6), ACL_UNDEFINED_ID)];
+    entries.extend(uids.iter().map(|uid| (ACL_USER, ACL_RW, *uid)));
+    entries.push((ACL_GROUP_OBJ, bits(3), ACL_UNDEFINED_ID));
+    entries.push((ACL_MASK, bits(3) | ACL_RW, ACL_UNDEFINED_ID));
+    entries.push((ACL_OTHER, bits(0), ACL_UNDEFINED_ID));
+
+    let mut value = ACL_VERSION.to_le_bytes().to_vec();
+    for (tag, perm, id) in entries {
+        value.extend(tag.to_le_bytes());
+        value.extend(perm.to_le_bytes());
+        value.extend(id.to_le_bytes());
+    }
+    value
+}
+
+/// The mode stat shows for a node with `mode` and the ACL of `uids`: the group bits are those
+/// of the mask
+pub fn visible_mode(mode: u32, uids: &[u32]) -> u32 {
+    match uids {
+        [] => mode,
+        _ => mode | ((ACL_RW as u32) << 3),
+    }
+}
+
+/// Grants rw on `path` to `uids` (host uids), keeping owner, group and other at `mode`.
+/// Without uids, an ACL of the node is removed.
+pub fn apply(path: &Path, mode: u32, uids: &[u32]) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other)?;
+    let ret = match uids {
+        [] => unsafe { libc::removexattr(path.as_ptr(), XATTR_NAME.as_ptr()) },
+        uids => {
+            let value = encode(mode, uids);
+            unsafe {
+                libc::setxattr(
+                    path.as_ptr(),
+                    XATTR_NAME.as_ptr(),
+                    value.as_ptr().cast(),
+                    value.len(),
+                    0,
+                )
+            }
+        }
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        // there was no ACL to remove
+        Some(libc::ENODATA) if uids.is_empty() => Ok(()),
+        _ => Err(e),
+    }
+}
+
+/// The ACTIVE_UID of the content of a seat file of logind
+fn active_uid(seat_file: &str) -> Option<u32> {
+    seat_file
+        .lines()
+        .find_map(|line| line.strip_prefix("ACTIVE_UID="))
+        .and_then(|uid| uid.trim().parse().ok())
+}
+
+/// The uid (in the container) of the user of the active session on `seat` of the container of
+/// `root`, None without logind or an active session
+pub fn active_session_uid(root: Pid, seat: &str) -> Option<u32> {
+    let seat_file = format!("/run/systemd/seats/{}", seat);
+    active_uid(&container_fs::read_to_string(root, &seat_file).ok()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_the_acl_like_setfacl() {
+        // setfacl -m u:1000:rw on a node with 0660, getfattr -n system.posix_acl_access -e hex
+        let value = encode(0o660, &[1000]);
+        let hex: String = value.iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(
+            hex,
+            "02000000\
+             01000600ffffffff\
+             02000600e8030000\
+             04000600ffffffff\
+             10000600ffffffff\
+             20000000ffffffff"
+        );
+        // sorted and without duplicates
+        assert_eq!(encode(0o600, &[1001, 1000, 1001]).len(), 4 + 6 * 8);
+        assert_eq!(encode(0o600, &[1001, 1000])[16..20], 1000u32.to_le_bytes());
+
+        assert_eq!(visible_mode(0o600, &[1000]), 0o660);
+        assert_eq!(visible_mode(0o600, &[]), 0o600);
+    }
+
+    #[test]
+    fn reads_the_active_user_of_a_seat() {
+        let seat = "# This is private data. Do not parse.\nIS_SEAT0=1\nCAN_MULTI_SESSION=1\n\
+                    ACTIVE=c2\nACTIVE_UID=1000\nSESSIONS=c2 c1\nUIDS=1000 1001\n";
+        assert_eq!(active_uid(seat), Some(1000));
+        assert_eq!(active_uid("IS_SEAT0=1\nCAN_TTY=1\n"), None);
+    }
+}
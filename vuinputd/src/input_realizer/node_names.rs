@@ -0,0 +1,282 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Names of the device nodes inside the containers. Usually the node has the name the host
+// gave it (event7 for /dev/input/event7), but some images ship a static /dev/input, and
+// `docker run --device /dev/input/event5:/dev/input/event0` puts a real device under a name
+// the host may hand out again. Replacing such a node would shadow the device the container
+// already uses, so the new device gets the first free name from event1024 on instead. The
+// kernel does not give out names that high, so the fallback names do not collide with the
+// names of later devices.
+//
+// With node-naming "container-local", the names do not follow the host at all: each container
+// counts from event0, so the names neither collide with what the image ships (those are
+// skipped) nor tell how many devices the host has.
+//
+// The name is decided once, when the node is created, and used by the uevents (DEVNAME) and
+// the removal of the device. The udev database in /run/udev/data is keyed by the device
+// number and stays as it is.
+//
+// Event nodes live in /dev/input. The hidraw nodes of HID devices (see cuse_device::vuhid)
+// live in /dev itself, like on the host, and are counted the same way (hidraw1024, ...).
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::Mutex;
+
+use log::{debug, info};
+use nix::sys::stat::makedev;
+
+use crate::global_config::NodeNaming;
+use crate::process_tools::RequestingProcess;
+
+/// First number of the fallback names
+pub const FALLBACK_START: u32 = 1024;
+
+/// Name prefix of the nodes that are not in /dev/input
+const HIDRAW: &str = "hidraw";
+
+/// Mount namespace, major and minor
+type NodeKey = (Option<u64>, u64, u64);
+
+static ASSIGNED: Mutex<BTreeMap<NodeKey, String>> = Mutex::new(BTreeMap::new());
+
+fn key(requesting_process: &RequestingProcess, major: u64, minor: u64) -> NodeKey {
+    (requesting_process.namespaces.mnt, major, minor)
+}
+
+/// What is at `path`, compared to the node of major:minor. A symlink is not followed, it is
+/// foreign even if it points to the node: mknod can't replace it, and it might lead out of
+/// the container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExistingNode {
+    Absent,
+    /// The node of this device, e.g. left over from an earlier run
+    Same,
+    /// Anything else: another device, a file, a directory or a symlink
+    Foreign,
+}
+
+pub fn existing_node(path: &Path, major: u64, minor: u64) -> ExistingNode {
+    match fs::symlink_metadata(path) {
+        Err(_) => ExistingNode::Absent,
+        Ok(meta) if meta.file_type().is_char_device() && meta.rdev() == makedev(major, minor) => {
+            ExistingNode::Same
+        }
+        Ok(_) => ExistingNode::Foreign,
+    }
+}
+
+/// The path of the node `devname` in the container, e.g. /dev/input/event7 or /dev/hidraw2
+pub fn node_path(devname: &str) -> String {
+    match devname.starts_with(HIDRAW) {
+        true => format!("/dev/{}", devname),
+        false => format!("/dev/input/{}", devname),
+    }
+}
+
+/// The first of `devname` (with NodeNaming::Host), event1024, event1025, ... that `is_free`.
+/// NodeNaming::ContainerLocal counts from event0 instead.
+fn choose(devname: &str, naming: NodeNaming, is_free: impl Fn(&str) -> bool) -> String {
+    let start = match naming {
+        NodeNaming::Host if is_free(devname) => return devname.to_string(),
+        NodeNaming::Host => FALLBACK_START,
+        NodeNaming::ContainerLocal => 0,
+    };
+    let prefix = devname.trim_end_matches(|c: char| c.is_ascii_digit());
+    (start..)
+        .map(|number| format!("{}{}", prefix, number))
+        .find(|name| is_free(name))
+        .unwrap()
+}
+
+/// Decides the name of the node of major:minor in the container of `requesting_process`.
+/// `is_usable` tells whether a name is free in the container or holds this device already;
+/// names assigned to other devices of the container are skipped in any case.
+pub fn assign(
+    requesting_process: &RequestingProcess,
+    devname: &str,
+    major: u64,
+    minor: u64,
+    naming: NodeNaming,
+    is_usable: impl Fn(&str) -> bool,
+) -> String {
+    let mut assigned = ASSIGNED.lock().unwrap();
+    let key = key(requesting_process, major, minor);
+    let mnt = requesting_process.namespaces.mnt;
+    let name = choose(devname, naming, |name| {
+        let taken = assigned
+            .iter()
+            .any(|(other, other_name)| other.0 == mnt && *other != key && other_name == name);
+        !taken && is_usable(name)
+    });
+    if naming == NodeNaming::ContainerLocal {
+        debug!(
+            "{}:{} is {} in container {}",
+            major,
+            minor,
+            node_path(&name),
+            requesting_process.pid_requestor_root.as_raw()
+        );
+    } else if name != devname {
+        info!(
+            "{} is taken in container {}, using {} for {}:{}",
+            node_path(devname),
+            requesting_process.pid_requestor_root.as_raw(),
+            node_path(&name),
+            major,
+            minor
+        );
+    }
+    assigned.insert(key, name.clone());
+    name
+}
+
+/// The name assigned to major:minor in the container, `devname` if there is none
+pub fn assigned(
+    requesting_process: &RequestingProcess,
+    devname: &str,
+    major: u64,
+    minor: u64,
+) -> String {
+    ASSIGNED
+        .lock()
+        .unwrap()
+        .get(&key(requesting_process, major, minor))
+        .cloned()
+        .unwrap_or_else(|| devname.to_string())
+}
+
+/// Forgets the name once the node is removed and returns it, `devname` if there is none
+pub fn release(
+    requesting_process: &RequestingProcess,
+    devname: &str,
+    major: u64,
+    minor: u64,
+) -> String {
+    ASSIGNED
+        .lock()
+        .unwrap()
+        .remove(&key(requesting_process, major, minor))
+        .unwrap_or_else(|| devname.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process_tools::{Namespaces, Pid};
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+
+    fn container(mnt: u64) -> RequestingProcess {
+        RequestingProcess {
+            pid_requestor: Pid::Pid(4711),
+            pid_requestor_root: Pid::Pid(4690),
+            namespaces: Namespaces {
+                mnt: Some(mnt),
+                ..Namespaces::default()
+            },
+            is_compat: false,
+            identity: None,
+        }
+    }
+
+    #[test]
+    fn avoids_nodes_of_a_prepopulated_dev() {
+        // an image with a static /dev/input: event0 is a plain file, event1 a symlink to a
+        // device, event4 a dangling one, and event2 already the node of /dev/null (1:3), which
+        // only root could create, so /dev/null itself stands in for it
+        let dir = std::env::temp_dir().join(format!("vuinputd-nodes-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("event0"), "").unwrap();
+        symlink("/dev/null", dir.join("event1")).unwrap();
+        symlink(dir.join("gone"), dir.join("event4")).unwrap();
+        fs::write(dir.join("event1024"), "").unwrap();
+        let path = |name: &str| match name {
+            "event2" => PathBuf::from("/dev/null"),
+            _ => dir.join(name),
+        };
+
+        assert_eq!(existing_node(&path("event0"), 1, 3), ExistingNode::Foreign);
+        assert_eq!(existing_node(&path("event1"), 1, 3), ExistingNode::Foreign);
+        assert_eq!(existing_node(&path("event2"), 1, 3), ExistingNode::Same);
+        assert_eq!(existing_node(&path("event3"), 13, 67), ExistingNode::Absent);
+        assert_eq!(
+            existing_node(&path("event4"), 13, 68),
+            ExistingNode::Foreign
+        );
+
+        let process = container(4026532811);
+        let usable = |major: u64, minor: u64| {
+            move |name: &str| existing_node(&path(name), major, minor) != ExistingNode::Foreign
+        };
+        let host = NodeNaming::Host;
+        assert_eq!(
+            assign(&process, "event0", 13, 64, host, usable(13, 64)),
+            "event1025"
+        );
+        assert_eq!(
+            assign(&process, "event2", 1, 3, host, usable(1, 3)),
+            "event2"
+        );
+        assert_eq!(
+            assign(&process, "event3", 13, 67, host, usable(13, 67)),
+            "event3"
+        );
+        // the node of event1 is not there yet, but event1025 is taken by the first device
+        assert_eq!(
+            assign(&process, "event1", 13, 65, host, usable(13, 65)),
+            "event1026"
+        );
+        // another container has a /dev of its own
+        assert_eq!(
+            assign(&container(4026532999), "event1025", 13, 300, host, |_| true),
+            "event1025"
+        );
+
+        assert_eq!(assigned(&process, "event0", 13, 64), "event1025");
+        assert_eq!(release(&process, "event0", 13, 64), "event1025");
+        assert_eq!(assigned(&process, "event0", 13, 64), "event0");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn counts_from_event0_in_each_container() {
+        let local = NodeNaming::ContainerLocal;
+        let first = container(4026533001);
+        let second = container(4026533002);
+        // event1 is shipped with the image of the first container
+        let usable = |name: &str| name != "event1";
+        assert_eq!(assign(&first, "event17", 13, 81, local, usable), "event0");
+        assert_eq!(assign(&first, "event18", 13, 82, local, usable), "event2");
+        assert_eq!(
+            assign(&second, "event19", 13, 83, local, |_| true),
+            "event0"
+        );
+        // the same device keeps its name when its node is created again
+        assert_eq!(assign(&first, "event18", 13, 82, local, usable), "event2");
+        assert_eq!(release(&first, "event17", 13, 81), "event0");
+        assert_eq!(assign(&first, "event20", 13, 84, local, usable), "event0");
+    }
+
+    #[test]
+    fn hidraw_nodes_are_counted_apart() {
+        let process = container(4026533003);
+        let local = NodeNaming::ContainerLocal;
+        assert_eq!(
+            assign(&process, "event17", 13, 81, local, |_| true),
+            "event0"
+        );
+        assert_eq!(
+            assign(&process, "hidraw5", 241, 5, local, |_| true),
+            "hidraw0"
+        );
+        assert_eq!(node_path("event0"), "/dev/input/event0");
+        assert_eq!(node_path("hidraw0"), "/dev/hidraw0");
+    }
+}
@@ -0,0 +1,215 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Owner, group and mode of the device nodes in containers. node-owner and node-group name a
+// user and group of the container, looked up in its /etc/passwd and /etc/group (read with
+// container_fs) unless they are numbers, and translated to the host with its uid_map and
+// gid_map. Without node-group, input-group decides the group, see input_group. The mode is
+// node-mode, by default 0660 with a group and 0666 without.
+//
+// Without node-owner, containers with a shifted user namespace, like those of systemd-nspawn
+// --private-users=pick, rootless podman or unprivileged LXC, get their root as the owner.
+// vuinputd creates the nodes from outside the user namespace, so they would belong to root of
+// the host, which the container sees as nobody:nogroup, and seatd and logind refuse such
+// nodes.
+//
+// A container that sees /dev/input through an idmapped mount (e.g. vuinputd-oci-hook --idmap)
+// already sees root of the host as its root; chowning would break that, so these nodes stay
+// as they are, and node-owner and node-group are not translated for them.
+//
+// The users of node-acl are resolved like node-owner, see node_acl for the ACL itself.
+
+use std::fs;
+use std::path::Path;
+
+use log::{debug, warn};
+
+use crate::global_config::get_reloadable_config;
+use crate::input_realizer::host_fs::{mount_of, Mount};
+use crate::input_realizer::input_device::NodeOwnership;
+use crate::input_realizer::node_acl::{self, ACTIVE_USER};
+use crate::input_realizer::{input_group, seat};
+use crate::process_tools::container_fs;
+use crate::process_tools::ns_fscreds::{get_gid_in_container, get_uid_in_container};
+use crate::process_tools::{Pid, RequestingProcess};
+
+/// The host uid and gid to give the nodes, None if root of the container is root of the host
+/// or the mount of the nodes translates the owner anyway
+fn shifted_owner(
+    root_uid: Option<u32>,
+    root_gid: Option<u32>,
+    mount: Option<&Mount>,
+) -> Option<(u32, u32)> {
+    if mount.is_some_and(|mount| mount.idmapped) {
+        return None;
+    }
+    match (root_uid?, root_gid?) {
+        (0, 0) => None,
+        owner => Some(owner),
+    }
+}
+
+/// The id of `name` (or the id `name` is) in the content of an /etc/passwd or /etc/group
+fn database_id(database: &str, name: &str) -> Option<u32> {
+    if let Ok(id) = name.parse() {
+        return Some(id);
+    }
+    database.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        if fields.next()? != name {
+            return None;
+        }
+        fields.nth(1)?.trim().parse().ok()
+    })
+}
+
+type ToHost = fn(Pid, u64) -> anyhow::Result<u32>;
+
+/// The host id of the user or group `name` of the container, warns if there is none. With an
+/// idmapped mount, the id is given as it is.
+fn resolve(root: Pid, name: &str, database: &str, to_host: Option<ToHost>) -> Option<u32> {
+    let content = container_fs::read_to_string(root, &format!("/etc/{}", database))
+        .inspect_err(|e| warn!("/etc/{} of container {}: {}", database, root.as_raw(), e))
+        .unwrap_or_default();
+    let Some(id) = database_id(&content, name) else {
+        warn!(
+            "container {} has no {} in /etc/{}, its nodes keep their default owner",
+            root.as_raw(),
+            name,
+            database
+        );
+        return None;
+    };
+    let Some(to_host) = to_host else {
+        return Some(id);
+    };
+    to_host(root, id.into())
+        .inspect_err(|e| warn!("{} of container {}: {}", name, root.as_raw(), e))
+        .ok()
+}
+
+/// The ownership of the nodes in the container of `requesting_process`
+pub fn node_ownership(requesting_process: &RequestingProcess) -> NodeOwnership {
+    let config = get_reloadable_config();
+    let root = requesting_process.pid_requestor_root;
+    let mountinfo = fs::read_to_string(format!("{}/mountinfo", root.path())).unwrap_or_default();
+    let mount = mount_of(&mountinfo, Path::new("/dev/input"));
+    let idmapped = mount.as_ref().is_some_and(|mount| mount.idmapped);
+    let shifted = shifted_root(root, mount.as_ref());
+    let uid = match &config.node_owner {
+        Some(user) => resolve(
+            root,
+            user,
+            "passwd",
+            (!idmapped).then_some(get_uid_in_container as ToHost),
+        ),
+        None => shifted.map(|(uid, _)| uid),
+    };
+    let group = match &config.node_group {
+        Some(group) => resolve(
+            root,
+            group,
+            "group",
+            (!idmapped).then_some(get_gid_in_container as ToHost),
+        ),
+        None => input_group::node_gid(requesting_process),
+    };
+    let default_mode = match group {
+        Some(_) => 0o660,
+        None => 0o666,
+    };
+    let acl_uids = (!config.node_acl.is_empty()).then(|| {
+        let to_host = (!idmapped).then_some(get_uid_in_container as ToHost);
+        config
+            .node_acl
+            .iter()
+            .filter_map(|user| match user.as_str() {
+                ACTIVE_USER => active_user(requesting_process, to_host),
+                user => resolve(root, user, "passwd", to_host),
+            })
+            .collect()
+    });
+    NodeOwnership {
+        uid,
+        gid: group.or(shifted.map(|(_, gid)| gid)),
+        mode: config.node_mode.unwrap_or(default_mode),
+        acl_uids,
+    }
+}
+
+/// The host uid of the user of the active session on the seat of the devices of
+/// `requesting_process`, None if nobody is logged in there
+fn active_user(requesting_process: &RequestingProcess, to_host: Option<ToHost>) -> Option<u32> {
+    let root = requesting_process.pid_requestor_root;
+    let seat = seat::container_seat(requesting_process).unwrap_or_else(|| "seat0".to_string());
+    let uid = node_acl::active_session_uid(root, &seat)?;
+    let Some(to_host) = to_host else {
+        return Some(uid);
+    };
+    to_host(root, uid.into())
+        .inspect_err(|e| warn!("active user of container {}: {}", root.as_raw(), e))
+        .ok()
+}
+
+/// The host uid and gid of root of the container, if its nodes need them
+fn shifted_root(root: Pid, mount: Option<&Mount>) -> Option<(u32, u32)> {
+    let owner = shifted_owner(
+        get_uid_in_container(root, 0).ok(),
+        get_gid_in_container(root, 0).ok(),
+        mount,
+    );
+    if let Some((uid, gid)) = owner {
+        debug!(
+            "nodes of container {} belong to its root (host {}:{})",
+            root.as_raw(),
+            uid,
+            gid
+        );
+    }
+    owner
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn users_and_groups_are_looked_up_by_name() {
+        let passwd = "root:x:0:0:root:/root:/bin/bash\ngamer:x:1000:1000::/home/gamer:/bin/sh\n";
+        assert_eq!(database_id(passwd, "gamer"), Some(1000));
+        assert_eq!(database_id(passwd, "root"), Some(0));
+        assert_eq!(database_id(passwd, "1001"), Some(1001));
+        assert_eq!(database_id(passwd, "nobody"), None);
+        assert_eq!(database_id("input:x:104:gamer\n", "input"), Some(104));
+    }
+
+    #[test]
+    fn only_shifted_containers_need_an_owner() {
+        let dev_input = |idmapped| Mount {
+            mount_point: PathBuf::from("/dev/input"),
+            fs_type: "tmpfs".to_string(),
+            source: "vuinputd".to_string(),
+            nodev: false,
+            idmapped,
+        };
+        // --private-users=pick, e.g. 0-65535 mapped to 1878392832-...
+        assert_eq!(
+            shifted_owner(Some(1878392832), Some(1878392832), None),
+            Some((1878392832, 1878392832))
+        );
+        assert_eq!(
+            shifted_owner(Some(100000), Some(100000), Some(&dev_input(false))),
+            Some((100000, 100000))
+        );
+        assert_eq!(
+            shifted_owner(Some(100000), Some(100000), Some(&dev_input(true))),
+            None
+        );
+        assert_eq!(shifted_owner(Some(0), Some(0), None), None);
+        // root of the container is not mapped at all
+        assert_eq!(shifted_owner(None, Some(100000), None), None);
+    }
+}
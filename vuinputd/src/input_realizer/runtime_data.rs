@@ -2,12 +2,15 @@
 //
 // Author: Johannes Leupolz <dev@leupolz.eu>
 
+use std::collections::{BTreeMap, HashMap};
 use std::fs::{self, File};
 use std::io::{self, Write};
 use std::path::Path;
 
 use log::{info, warn};
 
+use crate::host_root::host_path;
+
 /// Ensure required udev directories and files exist
 pub fn ensure_udev_structure() -> io::Result<()> {
     // Note that this structure _must_ exist, before a service using libinput is run. The time of device creation might be too late.
@@ -35,16 +38,11 @@ pub fn ensure_udev_structure() -> io::Result<()> {
     Ok(())
 }
 
-/// Write udev data entry for a given major/minor number
-/// - `content` = original udev data text
-/// - `major`, `minor` = device numbers
-///
-/// Performs these transforms:
+/// Transforms the udev data of the host for the container:
 ///  - remove all lines containing `ID_SEAT=`
 ///  - remove all lines containing `seat_` references (G:, Q: lines)
 ///  - replace ID_VUINPUT_* with ID_INPUT_*
-///  - write updated content to `/run/udev/data/c<major>:<minor>`
-pub fn write_udev_data(path_prefix: &str, content: &str, major: u64, minor: u64) -> io::Result<()> {
+pub fn clean_udev_data(content: &str) -> String {
     let mut cleaned = String::new();
 
     for line in content.lines() {
@@ -56,17 +54,83 @@ pub fn write_udev_data(path_prefix: &str, content: &str, major: u64, minor: u64)
         // perform replacements
         let line = line
             .replace("ID_VUINPUT_KEYBOARD=1", "ID_INPUT_KEYBOARD=1")
-            .replace("ID_VUINPUT_MOUSE=1", "ID_INPUT_MOUSE=1");
+            .replace("ID_VUINPUT_MOUSE=1", "ID_INPUT_MOUSE=1")
+            .replace("ID_VUINPUT_TOUCHPAD=1", "ID_INPUT_TOUCHPAD=1")
+            .replace("ID_VUINPUT_TABLET=1", "ID_INPUT_TABLET=1")
+            .replace("ID_VUINPUT_TOUCHSCREEN=1", "ID_INPUT_TOUCHSCREEN=1");
 
         cleaned.push_str(&line);
         cleaned.push('\n');
     }
+    cleaned
+}
 
-    let path = format!("{}/udev/data/c{}:{}", path_prefix, major, minor);
-    let mut file = File::create(&path)?;
-    file.write_all(cleaned.as_bytes())?;
+/// Replaces the ID_SERIAL the input_id builtin reported ("noserial") with the serial of the
+/// device, see device_serial
+pub fn set_serial(content: &str, serial: &str) -> String {
+    let property = format!("E:ID_SERIAL={}", serial);
+    let mut replaced = String::new();
+    let mut found = false;
+    for line in content.lines() {
+        if line.starts_with("E:ID_SERIAL=") {
+            found = true;
+            replaced.push_str(&property);
+        } else {
+            replaced.push_str(line);
+        }
+        replaced.push('\n');
+    }
+    if !found {
+        replaced.push_str(&property);
+        replaced.push('\n');
+    }
+    replaced
+}
 
-    Ok(())
+/// Sets the E: lines of `changes` (see udev_properties::changes), an empty value removes the
+/// property
+pub fn set_properties(content: &str, changes: &BTreeMap<String, String>) -> String {
+    let mut replaced = String::new();
+    for line in content.lines() {
+        let key = line
+            .strip_prefix("E:")
+            .and_then(|property| property.split_once('='))
+            .map(|(key, _)| key);
+        if key.is_some_and(|key| changes.contains_key(key)) {
+            continue;
+        }
+        replaced.push_str(line);
+        replaced.push('\n');
+    }
+    for (key, value) in changes.iter().filter(|(_, value)| !value.is_empty()) {
+        replaced.push_str(&format!("E:{}={}\n", key, value));
+    }
+    replaced
+}
+
+/// The properties (E: lines) of a udev data entry
+pub fn properties(content: &str) -> HashMap<String, String> {
+    content
+        .lines()
+        .filter_map(|line| line.strip_prefix("E:")?.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Write udev data entry for a given major/minor number
+/// - `content` = udev data text for the container, i.e. transformed with `clean_udev_data`
+/// - `major`, `minor` = device numbers
+///
+/// The result is written to `<path_prefix>/udev/data/c<major>:<minor>`. Like udevd, the file is
+/// written under another name and renamed, so that readers never see half of it.
+pub fn write_udev_data(path_prefix: &str, content: &str, major: u64, minor: u64) -> io::Result<()> {
+    let path = format!("{}/udev/data/c{}:{}", path_prefix, major, minor);
+    let temporary = format!("{}/udev/data/.#c{}:{}", path_prefix, major, minor);
+    let mut file = File::create(&temporary)?;
+    file.write_all(content.as_bytes())?;
+    fs::rename(&temporary, &path).inspect_err(|_| {
+        let _ = fs::remove_file(&temporary);
+    })
 }
 
 /// Delete udev data for a given major/minor number
@@ -78,7 +142,7 @@ pub fn delete_udev_data(path_prefix: &str, major: u64, minor: u64) -> io::Result
 }
 
 pub fn read_udev_data(major: u64, minor: u64) -> io::Result<String> {
-    let path = format!("/run/udev/data/c{}:{}", major, minor);
+    let path = host_path(&format!("/run/udev/data/c{}:{}", major, minor));
     fs::read_to_string(path)
 }
 
@@ -109,18 +173,59 @@ Q:power-switch
 V:1
 "#;
 
-        let mut cleaned = String::new();
-        for line in input.lines() {
-            if line.contains("ID_SEAT=") || line.contains("seat_") {
-                continue;
-            }
-            let line = line
-                .replace("ID_VUINPUT_KEYBOARD=1", "ID_INPUT_KEYBOARD=1")
-                .replace("ID_VUINPUT_MOUSE=1", "ID_INPUT_MOUSE=1");
-            cleaned.push_str(&line);
-            cleaned.push('\n');
-        }
+        assert_eq!(super::clean_udev_data(input), expected);
+    }
+
+    #[test]
+    fn serial_is_replaced() {
+        assert_eq!(
+            super::set_serial(
+                "E:ID_INPUT=1\nE:ID_SERIAL=noserial\nV:1\n",
+                "vuinput-5c0e9a1f-0"
+            ),
+            "E:ID_INPUT=1\nE:ID_SERIAL=vuinput-5c0e9a1f-0\nV:1\n"
+        );
+        assert_eq!(
+            super::set_serial("E:ID_INPUT=1\n", "vuinput-5c0e9a1f-1"),
+            "E:ID_INPUT=1\nE:ID_SERIAL=vuinput-5c0e9a1f-1\n"
+        );
+    }
+
+    #[test]
+    fn properties_are_set_and_removed() {
+        let changes = std::collections::BTreeMap::from([
+            ("ID_BUS".to_string(), String::new()),
+            ("ID_INPUT_JOYSTICK".to_string(), "1".to_string()),
+            ("ID_INPUT_KEY".to_string(), "1".to_string()),
+        ]);
+        assert_eq!(
+            super::set_properties(
+                "E:ID_INPUT=1\nE:ID_BUS=usb\nE:ID_INPUT_KEY=0\nG:seat\n",
+                &changes
+            ),
+            "E:ID_INPUT=1\nG:seat\nE:ID_INPUT_JOYSTICK=1\nE:ID_INPUT_KEY=1\n"
+        );
+        let properties = super::properties("E:ID_INPUT=1\nE:NAME=\"a=b\"\nG:seat\n");
+        assert_eq!(properties.len(), 2);
+        assert_eq!(properties["NAME"], "\"a=b\"");
+    }
 
-        assert_eq!(cleaned, expected);
+    #[test]
+    fn data_is_replaced_as_a_whole() {
+        let dir = std::env::temp_dir().join(format!("vuinputd-udev-data-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("udev/data")).unwrap();
+        let prefix = dir.to_string_lossy();
+        super::write_udev_data(&prefix, "E:ID_INPUT=1\nE:ID_INPUT_KEY=1\n", 13, 76).unwrap();
+        super::write_udev_data(&prefix, "E:ID_INPUT=1\n", 13, 76).unwrap();
+        let entries: Vec<_> = std::fs::read_dir(dir.join("udev/data"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, ["c13:76"]);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("udev/data/c13:76")).unwrap(),
+            "E:ID_INPUT=1\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
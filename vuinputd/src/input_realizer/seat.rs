@@ -0,0 +1,123 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// The seat the udev events and the udev database in a container announce a device on
+// (seat-mode). The seat of the host (seat_vuinput) is always removed, see clean_udev_data and
+// container_properties, so by default the devices are on the default seat of the container.
+// With "shared", all containers get their devices on seat-name, with "per-container" each
+// container gets a seat of its own (e.g. seat_vuinput_4690), so that a compositor in one
+// container that is started on its seat does not pick up the devices of another container
+// that shares its /dev/input or /run/udev.
+
+use std::collections::HashMap;
+
+use log::warn;
+
+use crate::global_config::{get_reloadable_config, SeatMode};
+use crate::process_tools::RequestingProcess;
+
+/// "seat" followed by at least one of [a-zA-Z0-9_-], as logind requires
+pub fn is_valid_seat_name(name: &str) -> bool {
+    name.strip_prefix("seat").is_some_and(|rest| {
+        !rest.is_empty()
+            && rest
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    })
+}
+
+/// The seat of the devices of `requesting_process`, None for the default seat
+pub fn container_seat(requesting_process: &RequestingProcess) -> Option<String> {
+    let config = get_reloadable_config();
+    let seat = match config.seat_mode {
+        SeatMode::None => return None,
+        SeatMode::Shared => config.seat_name.clone(),
+        SeatMode::PerContainer => format!(
+            "{}_{}",
+            config.seat_name,
+            requesting_process.pid_requestor_root.as_raw()
+        ),
+    };
+    if !is_valid_seat_name(&seat) {
+        warn!("{} is no valid seat name, using the default seat", seat);
+        return None;
+    }
+    Some(seat)
+}
+
+/// Puts the device on `seat` in the udev database of the container (cleaned with
+/// clean_udev_data): the property and the tag, as udev writes them
+pub fn set_seat_in_runtime_data(content: &str, seat: &str) -> String {
+    let mut with_seat = String::new();
+    for line in content.lines() {
+        // the version line ends the file of udev, the rest goes in front of it
+        if line.starts_with("V:") {
+            push_seat_lines(&mut with_seat, seat);
+        }
+        with_seat.push_str(line);
+        with_seat.push('\n');
+    }
+    if !content.lines().any(|line| line.starts_with("V:")) {
+        push_seat_lines(&mut with_seat, seat);
+    }
+    with_seat
+}
+
+fn push_seat_lines(content: &mut String, seat: &str) {
+    for line in [
+        format!("E:ID_SEAT={}", seat),
+        format!("G:{}", seat),
+        format!("Q:{}", seat),
+    ] {
+        content.push_str(&line);
+        content.push('\n');
+    }
+}
+
+/// Puts the device on `seat` in the properties of the udev event. Seat tags of the host are
+/// replaced.
+pub fn set_seat_in_properties(properties: &mut HashMap<String, String>, seat: &str) {
+    properties.insert("ID_SEAT".to_string(), seat.to_string());
+    for key in ["TAGS", "CURRENT_TAGS"] {
+        let tags = properties.get(key).map(String::as_str).unwrap_or_default();
+        let mut tags: Vec<&str> = tags
+            .split(':')
+            .filter(|tag| !tag.is_empty() && !tag.starts_with("seat_"))
+            .collect();
+        tags.push(seat);
+        properties.insert(key.to_string(), format!(":{}:", tags.join(":")));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announces_the_seat_of_the_container() {
+        assert!(is_valid_seat_name("seat_vuinput_4690"));
+        assert!(!is_valid_seat_name("seat"));
+        assert!(!is_valid_seat_name("vuinput"));
+        assert!(!is_valid_seat_name("seat_a\nE:ID_INPUT_KEYBOARD=1"));
+
+        let runtime_data = "I:16427452068006\nE:ID_INPUT=1\nG:power-switch\nQ:power-switch\nV:1\n";
+        assert_eq!(
+            set_seat_in_runtime_data(runtime_data, "seat_vuinput_4690"),
+            "I:16427452068006\nE:ID_INPUT=1\nG:power-switch\nQ:power-switch\n\
+            E:ID_SEAT=seat_vuinput_4690\nG:seat_vuinput_4690\nQ:seat_vuinput_4690\nV:1\n"
+        );
+
+        let mut properties = HashMap::from([
+            (
+                "TAGS".to_string(),
+                ":seat_vuinput:power-switch:".to_string(),
+            ),
+            ("DEVNAME".to_string(), "/dev/input/event9".to_string()),
+        ]);
+        set_seat_in_properties(&mut properties, "seat_vuinput_4690");
+        assert_eq!(properties["ID_SEAT"], "seat_vuinput_4690");
+        assert_eq!(properties["TAGS"], ":power-switch:seat_vuinput_4690:");
+        assert_eq!(properties["CURRENT_TAGS"], ":seat_vuinput_4690:");
+    }
+}
@@ -0,0 +1,140 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Golden samples of systemd-udevd (snapshots/*.udev-data from /run/udev/data, *.monitor from
+// `udevadm monitor -p`) for a keyboard, a mouse, a gamepad and a touchpad created by vuinputd
+// on a host with 90-vuinputd-protect.rules. The tests compare what vuinputd makes out of them
+// with the *.expected files byte by byte, so that a change of the format shows up in review.
+//
+// After an intended change, regenerate the expected files with
+//   VUINPUTD_UPDATE_SNAPSHOTS=1 cargo test snapshot
+// and check the diff.
+
+use std::fmt::Write;
+use std::fs;
+use std::path::PathBuf;
+
+use super::netlink_message::{encode_properties, string_hash32, MonitorNetlinkHeader};
+use super::runtime_data::clean_udev_data;
+use crate::jobs::monitor_udev_job::container_properties;
+
+const DEVICES: [&str; 4] = ["keyboard", "mouse", "gamepad", "touchpad"];
+
+fn snapshot_path(file: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("src/input_realizer/snapshots")
+        .join(file)
+}
+
+fn read_sample(file: &str) -> String {
+    fs::read_to_string(snapshot_path(file))
+        .unwrap_or_else(|e| panic!("can't read sample {}: {}", file, e))
+}
+
+fn assert_snapshot(file: &str, actual: &str) {
+    let path = snapshot_path(file);
+    if std::env::var_os("VUINPUTD_UPDATE_SNAPSHOTS").is_some() {
+        fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected =
+        fs::read_to_string(&path).unwrap_or_else(|e| panic!("can't read snapshot {}: {}", file, e));
+    assert!(
+        expected == actual,
+        "{} differs from the output:\n{}",
+        file,
+        actual
+    );
+}
+
+/// Like `xxd`: offset, 16 bytes in hex and the printable characters
+fn hexdump(bytes: &[u8]) -> String {
+    let mut dump = String::new();
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        let _ = write!(dump, "{:08x}:", line * 16);
+        for (i, byte) in chunk.iter().enumerate() {
+            let separator = if i % 2 == 0 { " " } else { "" };
+            let _ = write!(dump, "{}{:02x}", separator, byte);
+        }
+        let padding = (16 - chunk.len()) * 2 + (16 - chunk.len()) / 2;
+        dump.push_str(&" ".repeat(padding + 2));
+        for byte in chunk {
+            dump.push(if byte.is_ascii_graphic() || *byte == b' ' {
+                *byte as char
+            } else {
+                '.'
+            });
+        }
+        dump.push('\n');
+    }
+    dump
+}
+
+/// KEY=VALUE lines of `udevadm monitor -p`, without the UDEV header line
+fn parse_monitor_sample(sample: &str) -> Vec<(String, String)> {
+    sample
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+#[test]
+fn snapshot_udev_runtime_data() {
+    for device in DEVICES {
+        let sample = read_sample(&format!("{}.udev-data", device));
+        assert_snapshot(
+            &format!("{}.udev-data.expected", device),
+            &clean_udev_data(&sample),
+        );
+    }
+}
+
+#[test]
+fn snapshot_netlink_message() {
+    for device in DEVICES {
+        let sample = read_sample(&format!("{}.monitor", device));
+        let properties = container_properties(parse_monitor_sample(&sample).into_iter());
+        let payload = encode_properties(&properties);
+        let mut message = MonitorNetlinkHeader::new(payload.len(), Some("input"), None).to_bytes();
+        message.extend(&payload);
+        assert_snapshot(&format!("{}.netlink.expected", device), &hexdump(&message));
+    }
+}
+
+#[test]
+fn netlink_header_matches_sd_device_monitor() {
+    // struct monitor_netlink_header of sd-device/device-monitor.c, integers in network byte order
+    // except for the sizes and offsets
+    let header = MonitorNetlinkHeader::new(300, Some("input"), None).to_bytes();
+    assert_eq!(header.len(), 40);
+    assert_eq!(&header[0..8], b"libudev\0");
+    assert_eq!(&header[8..12], &[0xfe, 0xed, 0xca, 0xfe]);
+    assert_eq!(u32::from_ne_bytes(header[12..16].try_into().unwrap()), 40);
+    assert_eq!(u32::from_ne_bytes(header[16..20].try_into().unwrap()), 40);
+    assert_eq!(u32::from_ne_bytes(header[20..24].try_into().unwrap()), 300);
+    // MurmurHash2 of "input" with seed 0
+    assert_eq!(
+        u32::from_be_bytes(header[24..28].try_into().unwrap()),
+        3248653424
+    );
+    assert_eq!(&header[28..40], &[0u8; 12]);
+}
+
+#[cfg(target_endian = "little")]
+#[test]
+fn string_hash_is_the_murmur_hash2_of_systemd() {
+    // MurmurHash2 with seed 0, as systemd computes it on little-endian hosts
+    assert_eq!(string_hash32("input"), 3248653424);
+    assert_eq!(string_hash32(""), 0);
+    assert_eq!(string_hash32("hidraw"), 3268080535);
+    assert_eq!(string_hash32("leds"), 688113347);
+    assert_eq!(string_hash32("usb_device"), 670627084);
+    // a devtype goes into its own field of the header
+    let header = MonitorNetlinkHeader::new(0, Some("usb"), Some("usb_device")).to_bytes();
+    assert_eq!(
+        u32::from_be_bytes(header[28..32].try_into().unwrap()),
+        670627084
+    );
+}
@@ -0,0 +1,593 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Properties of the udev events and the udev database in containers that are derived from the
+// device itself. A container has no udevd of its own; it gets what the udevd of the host made
+// of the device, run through clean_udev_data. That misses what the rules of the host did not
+// set, e.g. because 90-vuinputd-protect.rules cleared it, the host has older rules or the
+// device got no hwdb entry. libinput then ignores the device or treats it as the wrong kind.
+//
+// The engine reads the device as the kernel shows it in sysfs (id, name and capabilities of
+// /sys/devices/virtual/input/inputN) and
+//
+//   - adds the properties of the input_id builtin of udev (ID_INPUT, ID_INPUT_KEY,
+//     ID_INPUT_KEYBOARD, ID_INPUT_MOUSE, ID_INPUT_JOYSTICK, ID_INPUT_TOUCHPAD, ...), ID_BUS
+//     and .INPUT_CLASS of 60-persistent-input.rules and the MODALIAS of the input device,
+//     unless the host has set them
+//   - then applies the [[udev-property]] rules of the configuration, like hwdb entries:
+//
+//       [[udev-property]]
+//       name = "Sunshine*"
+//       id = "045e:028e"
+//       properties = { ID_INPUT_JOYSTICK = "1", LIBINPUT_DEVICE_GROUP = "sunshine" }
+//
+// A rule applies if its name (wildcards * and ?) and id match, each rule that applies sets its
+// properties and an empty value removes a property. The rules take precedence over the host.
+// The classification follows input_id, without its quirks for odd hardware.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+
+use crate::cuse_device::container_rules::glob_match;
+use crate::cuse_device::device_id::UsbId;
+use crate::host_root::host_path;
+
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const EV_SW: u16 = 0x05;
+
+const REL_X: u16 = 0x00;
+const REL_Y: u16 = 0x01;
+const REL_HWHEEL: u16 = 0x06;
+const REL_WHEEL: u16 = 0x08;
+
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+const ABS_Z: u16 = 0x02;
+const ABS_RX: u16 = 0x03;
+const ABS_PRESSURE: u16 = 0x18;
+const ABS_MT_SLOT: u16 = 0x2f;
+const ABS_MT_POSITION_X: u16 = 0x35;
+const ABS_MT_POSITION_Y: u16 = 0x36;
+
+const KEY_ESC: u16 = 1;
+const KEY_D: u16 = 32;
+/// The first key the MODALIAS lists, the kernel leaves out the ones every keyboard has
+const KEY_MIN_INTERESTING: u16 = 113;
+const BTN_MISC: u16 = 0x100;
+const BTN_0: u16 = 0x100;
+const BTN_1: u16 = 0x101;
+const BTN_MOUSE: u16 = 0x110;
+const BTN_JOYSTICK: u16 = 0x120;
+const BTN_DIGI: u16 = 0x140;
+const BTN_TOOL_PEN: u16 = 0x140;
+const BTN_TOOL_FINGER: u16 = 0x145;
+const BTN_TOUCH: u16 = 0x14a;
+const BTN_STYLUS: u16 = 0x14b;
+const KEY_OK: u16 = 0x160;
+const BTN_DPAD_UP: u16 = 0x220;
+const BTN_DPAD_RIGHT: u16 = 0x223;
+const KEY_ALS_TOGGLE: u16 = 0x230;
+const BTN_TRIGGER_HAPPY1: u16 = 0x2c0;
+const BTN_TRIGGER_HAPPY40: u16 = 0x2e7;
+
+const INPUT_PROP_DIRECT: u16 = 0x01;
+const INPUT_PROP_POINTING_STICK: u16 = 0x05;
+const INPUT_PROP_ACCELEROMETER: u16 = 0x06;
+
+/// The capability files of sysfs in the order of the MODALIAS, with its letter for them
+const CAPABILITIES: [(&str, char); 9] = [
+    ("ev", 'e'),
+    ("key", 'k'),
+    ("rel", 'r'),
+    ("abs", 'a'),
+    ("msc", 'm'),
+    ("led", 'l'),
+    ("snd", 's'),
+    ("ff", 'f'),
+    ("sw", 'w'),
+];
+
+/// An input device as sysfs shows it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InputCapabilities {
+    pub name: String,
+    pub bustype: u16,
+    pub vendor: u16,
+    pub product: u16,
+    pub version: u16,
+    /// The bits of the files in capabilities/, by file name, e.g. "key" -> {BTN_SOUTH, ...}
+    pub bits: BTreeMap<&'static str, BTreeSet<u16>>,
+    /// INPUT_PROP_* of the file properties
+    pub properties: BTreeSet<u16>,
+}
+
+/// A [[udev-property]] rule, the conditions that are set must all match
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdevPropertyRule {
+    /// Pattern of the name of the device
+    pub name: Option<String>,
+    pub id: Option<UsbId>,
+    /// Properties to set, an empty value removes the property
+    pub properties: BTreeMap<String, String>,
+}
+
+impl UdevPropertyRule {
+    fn matches(&self, device: &InputCapabilities) -> bool {
+        let name = self
+            .name
+            .as_ref()
+            .is_none_or(|pattern| glob_match(pattern, &device.name));
+        let id = self
+            .id
+            .is_none_or(|id| id.vendor == device.vendor && id.product == device.product);
+        name && id
+    }
+}
+
+/// The bits of a bitmap of sysfs: longs in hex, the most significant first, e.g. "120013"
+fn parse_bitmap(bitmap: &str) -> BTreeSet<u16> {
+    let mut bits = BTreeSet::new();
+    for (index, word) in bitmap.split_whitespace().rev().enumerate() {
+        let Ok(word) = u64::from_str_radix(word, 16) else {
+            continue;
+        };
+        for bit in 0..usize::BITS {
+            if word & (1 << bit) != 0 {
+                bits.insert((index as u32 * usize::BITS + bit) as u16);
+            }
+        }
+    }
+    bits
+}
+
+/// Reads id, name and capabilities of the input device at `sys_path`, e.g.
+/// /sys/devices/virtual/input/input99. None, if it is gone.
+pub fn read_capabilities(sys_path: &str) -> Option<InputCapabilities> {
+    let read = |file: &str| fs::read_to_string(host_path(&format!("{}/{}", sys_path, file)));
+    let id = |file: &str| {
+        read(&format!("id/{}", file))
+            .ok()
+            .and_then(|hex| u16::from_str_radix(hex.trim(), 16).ok())
+            .unwrap_or_default()
+    };
+    let name = read("name").ok()?;
+    Some(InputCapabilities {
+        name: name.trim_end_matches('\n').to_string(),
+        bustype: id("bustype"),
+        vendor: id("vendor"),
+        product: id("product"),
+        version: id("version"),
+        bits: CAPABILITIES
+            .iter()
+            .map(|(file, _)| {
+                let bitmap = read(&format!("capabilities/{}", file)).unwrap_or_default();
+                (*file, parse_bitmap(&bitmap))
+            })
+            .collect(),
+        properties: parse_bitmap(&read("properties").unwrap_or_default()),
+    })
+}
+
+impl InputCapabilities {
+    fn has(&self, file: &str, bit: u16) -> bool {
+        self.bits.get(file).is_some_and(|bits| bits.contains(&bit))
+    }
+
+    fn has_any(&self, file: &str, range: std::ops::RangeInclusive<u16>) -> bool {
+        self.bits
+            .get(file)
+            .is_some_and(|bits| bits.range(range).next().is_some())
+    }
+
+    fn has_prop(&self, prop: u16) -> bool {
+        self.properties.contains(&prop)
+    }
+
+    /// The MODALIAS of the input device, as the kernel builds it (input_print_modalias)
+    pub fn modalias(&self) -> String {
+        let mut modalias = format!(
+            "input:b{:04X}v{:04X}p{:04X}e{:04X}-",
+            self.bustype, self.vendor, self.product, self.version
+        );
+        for (file, letter) in CAPABILITIES {
+            modalias.push(letter);
+            let first = if file == "key" {
+                KEY_MIN_INTERESTING
+            } else {
+                0
+            };
+            for bit in self.bits.get(file).into_iter().flatten() {
+                if *bit >= first {
+                    modalias.push_str(&format!("{:X},", bit));
+                }
+            }
+        }
+        modalias
+    }
+}
+
+/// ID_BUS of a bustype, as udev names the buses
+fn bus_name(bustype: u16) -> Option<&'static str> {
+    match bustype {
+        0x01 => Some("pci"),
+        0x03 => Some("usb"),
+        0x05 => Some("bluetooth"),
+        0x06 => Some("virtual"),
+        0x11 => Some("i8042"),
+        0x18 => Some("i2c"),
+        0x19 => Some("host"),
+        0x1c => Some("spi"),
+        _ => None,
+    }
+}
+
+/// The ID_INPUT_* of the pointing devices, like test_pointers of the input_id builtin. Returns
+/// whether the device is one.
+fn pointer_properties(
+    device: &InputCapabilities,
+    properties: &mut BTreeMap<String, String>,
+) -> bool {
+    let mut set = |key: &str| {
+        properties.insert(key.to_string(), "1".to_string());
+    };
+    let has_keys = device.has("ev", EV_KEY);
+    let has_abs_coordinates = device.has("abs", ABS_X) && device.has("abs", ABS_Y);
+    if device.has_prop(INPUT_PROP_ACCELEROMETER)
+        || (!has_keys && has_abs_coordinates && device.has("abs", ABS_Z))
+    {
+        set("ID_INPUT_ACCELEROMETER");
+        return false;
+    }
+    let has_stylus_or_pen = device.has("key", BTN_STYLUS) || device.has("key", BTN_TOOL_PEN);
+    let finger_but_no_pen = device.has("key", BTN_TOOL_FINGER) && !device.has("key", BTN_TOOL_PEN);
+    let has_mouse_button = device.has_any("key", BTN_MOUSE..=BTN_JOYSTICK - 1);
+    let has_rel_coordinates =
+        device.has("ev", EV_REL) && device.has("rel", REL_X) && device.has("rel", REL_Y);
+    // a device that claims all axes has no real multitouch
+    let has_mt_coordinates = device.has("abs", ABS_MT_POSITION_X)
+        && device.has("abs", ABS_MT_POSITION_Y)
+        && !(device.has("abs", ABS_MT_SLOT) && device.has("abs", ABS_MT_SLOT - 1));
+    let is_direct = device.has_prop(INPUT_PROP_DIRECT);
+    let has_touch = device.has("key", BTN_TOUCH);
+    let has_pad_buttons =
+        device.has("key", BTN_0) && device.has("key", BTN_1) && !device.has("key", BTN_TOOL_PEN);
+    let has_wheel = device.has("rel", REL_WHEEL) || device.has("rel", REL_HWHEEL);
+    let has_joystick_axes_or_buttons = device.has_any("key", BTN_JOYSTICK..=BTN_DIGI - 1)
+        || device.has_any("key", BTN_TRIGGER_HAPPY1..=BTN_TRIGGER_HAPPY40)
+        || device.has_any("key", BTN_DPAD_UP..=BTN_DPAD_RIGHT)
+        || device.has_any("abs", ABS_RX..=ABS_PRESSURE - 1);
+
+    let (mut is_tablet, mut is_tablet_pad, mut is_touchpad) = (false, false, false);
+    let (mut is_mouse, mut is_touchscreen, mut is_joystick) = (false, false, false);
+    if has_abs_coordinates {
+        if has_stylus_or_pen {
+            is_tablet = true;
+        } else if finger_but_no_pen && !is_direct {
+            is_touchpad = true;
+        } else if has_mouse_button {
+            // e.g. the absolute pointers of streaming clients and of VMs
+            is_mouse = true;
+        } else if has_touch || is_direct {
+            is_touchscreen = true;
+        } else if has_joystick_axes_or_buttons {
+            is_joystick = true;
+        }
+    } else if has_joystick_axes_or_buttons {
+        is_joystick = true;
+    }
+    if has_mt_coordinates {
+        if has_stylus_or_pen {
+            is_tablet = true;
+        } else if finger_but_no_pen && !is_direct {
+            is_touchpad = true;
+        } else if has_touch || is_direct {
+            is_touchscreen = true;
+        }
+    }
+    if is_tablet && has_pad_buttons {
+        is_tablet_pad = true;
+    }
+    if has_pad_buttons && has_wheel && !has_rel_coordinates {
+        is_tablet = true;
+        is_tablet_pad = true;
+    }
+    if !is_tablet
+        && !is_touchpad
+        && !is_joystick
+        && has_mouse_button
+        && (has_rel_coordinates || !has_abs_coordinates)
+    {
+        is_mouse = true;
+    }
+
+    let is_pointing_stick = device.has_prop(INPUT_PROP_POINTING_STICK);
+    for (is, key) in [
+        (is_pointing_stick, "ID_INPUT_POINTINGSTICK"),
+        (is_mouse, "ID_INPUT_MOUSE"),
+        (is_touchpad, "ID_INPUT_TOUCHPAD"),
+        (is_touchscreen, "ID_INPUT_TOUCHSCREEN"),
+        (is_joystick, "ID_INPUT_JOYSTICK"),
+        (is_tablet, "ID_INPUT_TABLET"),
+        (is_tablet_pad, "ID_INPUT_TABLET_PAD"),
+    ] {
+        if is {
+            set(key);
+        }
+    }
+    is_tablet || is_mouse || is_touchpad || is_touchscreen || is_joystick || is_pointing_stick
+}
+
+/// What udev of a host without vuinputd's rules would have set for the device
+pub fn builtin_properties(device: &InputCapabilities) -> BTreeMap<String, String> {
+    let mut properties = BTreeMap::new();
+    properties.insert("ID_INPUT".to_string(), "1".to_string());
+    let is_pointer = pointer_properties(device, &mut properties);
+
+    // like test_key: keys of a keyboard or of buttons that are not those of a pointer
+    let has_key = device.has_any("key", 0..=BTN_MISC - 1)
+        || device.has_any("key", KEY_OK..=BTN_DPAD_UP - 1)
+        || device.has_any("key", KEY_ALS_TOGGLE..=BTN_TRIGGER_HAPPY1 - 1);
+    let has_scroll_wheel_only = !is_pointer
+        && !has_key
+        && device.has("ev", EV_REL)
+        && (device.has("rel", REL_WHEEL) || device.has("rel", REL_HWHEEL));
+    if has_key || has_scroll_wheel_only {
+        properties.insert("ID_INPUT_KEY".to_string(), "1".to_string());
+    }
+    // the first 32 bits (escape, the digits, Q to P, A and S) make a keyboard
+    if (KEY_ESC..KEY_D).all(|key| device.has("key", key)) {
+        properties.insert("ID_INPUT_KEYBOARD".to_string(), "1".to_string());
+    }
+    if device.has("ev", EV_SW) {
+        properties.insert("ID_INPUT_SWITCH".to_string(), "1".to_string());
+    }
+
+    // 60-persistent-input.rules, the last class that applies wins
+    let class = [
+        ("ID_INPUT_KEYBOARD", "kbd"),
+        ("ID_INPUT_MOUSE", "mouse"),
+        ("ID_INPUT_TOUCHPAD", "mouse"),
+        ("ID_INPUT_TABLET", "mouse"),
+        ("ID_INPUT_JOYSTICK", "joystick"),
+    ]
+    .iter()
+    .rev()
+    .find(|(key, _)| properties.contains_key(*key))
+    .map(|(_, class)| class.to_string());
+    if let Some(class) = class {
+        properties.insert(".INPUT_CLASS".to_string(), class);
+    }
+    if let Some(bus) = bus_name(device.bustype) {
+        properties.insert("ID_BUS".to_string(), bus.to_string());
+    }
+    properties.insert("MODALIAS".to_string(), device.modalias());
+    properties
+}
+
+/// The properties to set in the event of the device, given the `existing` ones of the host:
+/// the builtin ones the host has not set, then those of the rules. An empty value means that
+/// the property is removed.
+pub fn changes(
+    existing: &HashMap<String, String>,
+    device: &InputCapabilities,
+    rules: &[UdevPropertyRule],
+) -> BTreeMap<String, String> {
+    let mut changes: BTreeMap<String, String> = builtin_properties(device)
+        .into_iter()
+        .filter(|(key, _)| !existing.contains_key(key))
+        .collect();
+    for rule in rules.iter().filter(|rule| rule.matches(device)) {
+        changes.extend(rule.properties.clone());
+    }
+    changes
+}
+
+/// Applies `changes` to the properties of a netlink event
+pub fn set_in_properties(
+    properties: &mut HashMap<String, String>,
+    changes: &BTreeMap<String, String>,
+) {
+    for (key, value) in changes {
+        if value.is_empty() {
+            properties.remove(key);
+        } else {
+            properties.insert(key.clone(), value.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EV_ABS: u16 = 0x03;
+    const BTN_LEFT: u16 = 0x110;
+    const BTN_RIGHT: u16 = 0x111;
+    const BTN_SOUTH: u16 = 0x130;
+    const BTN_EAST: u16 = 0x131;
+    const ABS_HAT0X: u16 = 0x10;
+
+    fn device(bits: &[(&'static str, &[u16])], properties: &[u16]) -> InputCapabilities {
+        InputCapabilities {
+            name: "Virtual Device".to_string(),
+            bustype: 0x06,
+            vendor: 0x1234,
+            product: 0x5678,
+            version: 1,
+            bits: bits
+                .iter()
+                .map(|(file, bits)| (*file, bits.iter().copied().collect()))
+                .collect(),
+            properties: properties.iter().copied().collect(),
+        }
+    }
+
+    fn classes(device: &InputCapabilities) -> Vec<String> {
+        builtin_properties(device)
+            .into_iter()
+            .filter(|(key, _)| key.starts_with("ID_INPUT_") || key == ".INPUT_CLASS")
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect()
+    }
+
+    #[test]
+    fn reads_the_bitmaps_of_sysfs() {
+        // capabilities/ev of a keyboard with autorepeat and LEDs
+        assert_eq!(
+            parse_bitmap("120013\n").into_iter().collect::<Vec<_>>(),
+            [0, 1, 4, 17, 20]
+        );
+        // the second long holds the bits from 64 on
+        let key = parse_bitmap("10000 3");
+        assert_eq!(key.into_iter().collect::<Vec<_>>(), [0, 1, 64 + 16]);
+        assert!(parse_bitmap("0").is_empty());
+    }
+
+    #[test]
+    fn classifies_like_input_id() {
+        let keyboard_keys: Vec<u16> = (KEY_ESC..=KEY_D).chain([KEY_MIN_INTERESTING]).collect();
+        let keyboard = device(&[("ev", &[0, EV_KEY]), ("key", &keyboard_keys)], &[]);
+        assert_eq!(
+            classes(&keyboard),
+            [".INPUT_CLASS=kbd", "ID_INPUT_KEY=1", "ID_INPUT_KEYBOARD=1"].map(String::from)
+        );
+
+        let mouse = device(
+            &[
+                ("ev", &[0, EV_KEY, EV_REL]),
+                ("key", &[BTN_LEFT, BTN_RIGHT]),
+                ("rel", &[REL_X, REL_Y, REL_WHEEL]),
+            ],
+            &[],
+        );
+        assert_eq!(
+            classes(&mouse),
+            [".INPUT_CLASS=mouse", "ID_INPUT_MOUSE=1"].map(String::from)
+        );
+
+        let gamepad = device(
+            &[
+                ("ev", &[0, EV_KEY, EV_ABS]),
+                ("key", &[BTN_SOUTH, BTN_EAST]),
+                ("abs", &[ABS_X, ABS_Y, ABS_RX, ABS_HAT0X]),
+            ],
+            &[],
+        );
+        assert_eq!(
+            classes(&gamepad),
+            [".INPUT_CLASS=joystick", "ID_INPUT_JOYSTICK=1"].map(String::from)
+        );
+
+        let touchpad = device(
+            &[
+                ("ev", &[0, EV_KEY, EV_ABS]),
+                ("key", &[BTN_LEFT, BTN_TOOL_FINGER, BTN_TOUCH]),
+                ("abs", &[ABS_X, ABS_Y, ABS_MT_POSITION_X, ABS_MT_POSITION_Y]),
+            ],
+            &[],
+        );
+        assert_eq!(
+            classes(&touchpad),
+            [".INPUT_CLASS=mouse", "ID_INPUT_TOUCHPAD=1"].map(String::from)
+        );
+
+        let touchscreen = device(
+            &[
+                ("ev", &[0, EV_KEY, EV_ABS]),
+                ("key", &[BTN_TOUCH]),
+                ("abs", &[ABS_X, ABS_Y]),
+            ],
+            &[INPUT_PROP_DIRECT],
+        );
+        assert_eq!(
+            classes(&touchscreen),
+            ["ID_INPUT_TOUCHSCREEN=1"].map(String::from)
+        );
+
+        let tablet = device(
+            &[
+                ("ev", &[0, EV_KEY, EV_ABS]),
+                ("key", &[BTN_TOOL_PEN, BTN_TOUCH, BTN_STYLUS]),
+                ("abs", &[ABS_X, ABS_Y, ABS_PRESSURE]),
+            ],
+            &[],
+        );
+        assert_eq!(
+            classes(&tablet),
+            [".INPUT_CLASS=mouse", "ID_INPUT_TABLET=1"].map(String::from)
+        );
+    }
+
+    #[test]
+    fn builds_the_modalias_like_the_kernel() {
+        let gamepad = InputCapabilities {
+            bustype: 0x03,
+            vendor: 0x045e,
+            product: 0x028e,
+            version: 0x0114,
+            ..device(
+                &[
+                    ("ev", &[0, EV_KEY, EV_ABS, 0x15]),
+                    ("key", &[KEY_ESC, BTN_SOUTH, BTN_EAST]),
+                    ("abs", &[ABS_X, ABS_Y, ABS_HAT0X]),
+                    ("ff", &[0x50, 0x51]),
+                ],
+                &[],
+            )
+        };
+        // KEY_ESC is below KEY_MIN_INTERESTING
+        assert_eq!(
+            gamepad.modalias(),
+            "input:b0003v045Ep028Ee0114-e0,1,3,15,k130,131,ra0,1,10,mlsf50,51,w"
+        );
+        let properties = builtin_properties(&gamepad);
+        assert_eq!(properties["ID_BUS"], "usb");
+        assert_eq!(properties["MODALIAS"], gamepad.modalias());
+    }
+
+    #[test]
+    fn keeps_the_host_and_applies_the_rules() {
+        let gamepad = device(
+            &[("ev", &[0, EV_KEY]), ("key", &[BTN_SOUTH, BTN_EAST])],
+            &[],
+        );
+        let existing = HashMap::from([
+            ("ID_INPUT".to_string(), "1".to_string()),
+            (".INPUT_CLASS".to_string(), "joystick".to_string()),
+        ]);
+        let rules = [
+            UdevPropertyRule {
+                name: Some("Virtual*".to_string()),
+                id: None,
+                properties: BTreeMap::from([
+                    ("LIBINPUT_DEVICE_GROUP".to_string(), "pads".to_string()),
+                    ("ID_BUS".to_string(), String::new()),
+                ]),
+            },
+            UdevPropertyRule {
+                name: None,
+                id: Some(UsbId {
+                    vendor: 0x045e,
+                    product: 0x028e,
+                }),
+                properties: BTreeMap::from([("ID_INPUT_JOYSTICK".to_string(), String::new())]),
+            },
+        ];
+        // the second rule is for another device
+        let changes = changes(&existing, &gamepad, &rules);
+        assert!(!changes.contains_key("ID_INPUT"));
+        assert!(!changes.contains_key(".INPUT_CLASS"));
+        assert_eq!(changes["ID_INPUT_JOYSTICK"], "1");
+        assert_eq!(changes["LIBINPUT_DEVICE_GROUP"], "pads");
+        assert_eq!(changes["ID_BUS"], "");
+
+        let mut properties = existing.clone();
+        properties.insert("ID_BUS".to_string(), "usb".to_string());
+        set_in_properties(&mut properties, &changes);
+        assert_eq!(properties["LIBINPUT_DEVICE_GROUP"], "pads");
+        assert_eq!(properties["ID_INPUT_JOYSTICK"], "1");
+        assert!(!properties.contains_key("ID_BUS"));
+    }
+}
@@ -2,19 +2,104 @@
 //
 // Author: Johannes Leupolz <dev@leupolz.eu>
 
+//! Small jobs without a struct of their own. The job! macro covers the common case:
+//!
+//! ```ignore
+//! JOB_DISPATCHER.get().unwrap().lock().unwrap().dispatch(job!("Log something", async move {
+//!     info!("running on the host queue");
+//! }));
+//! ```
+//!
+//! For anything else, e.g. a cleanup job of a container with a timeout, use the builder:
+//!
+//! ```ignore
+//! let job = ClosureJob::builder("Remove leftovers")
+//!     .target(JobTarget::Container(requesting_process))
+//!     .cleanup(true)
+//!     .timeout(Duration::from_secs(5))
+//!     .task(move |_job| async move { /* ... */ });
+//! ```
+//!
+//! Jobs that need to be awaited by the caller or keep state are better off as a struct
+//! implementing `Job`, see the jobs module.
+
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_io::Timer;
+use futures::future::{select, Either};
 
 use super::job::{Job, JobTarget};
 
+type TaskCreator = Box<dyn FnOnce(&ClosureJob) -> Pin<Box<dyn Future<Output = ()>>> + Send>;
+
 pub struct ClosureJob {
     desc: String,
     execute_after_cancellation: bool,
     target: JobTarget,
-    task_creator: Box<dyn Fn(&ClosureJob) -> Pin<Box<dyn Future<Output = ()>>> + Send + 'static>,
+    timeout: Option<Duration>,
+    // a job is only run once, so the closure may consume what it captured
+    task_creator: Mutex<Option<TaskCreator>>,
+    failure: Arc<Mutex<Option<String>>>,
+}
+
+/// Collects the settings of a ClosureJob, `task` finishes it.
+pub struct ClosureJobBuilder {
+    desc: String,
+    execute_after_cancellation: bool,
+    target: JobTarget,
+    timeout: Option<Duration>,
+}
+
+impl ClosureJobBuilder {
+    /// Queue to run on, the host queue by default
+    pub fn target(mut self, target: JobTarget) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// Cleanup jobs still run, when the dispatcher is shut down with CancelNonCleanup
+    pub fn cleanup(mut self, cleanup: bool) -> Self {
+        self.execute_after_cancellation = cleanup;
+        self
+    }
+
+    /// The task is dropped after `timeout` and the job counts as failed
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn task<F, Fut>(self, f: F) -> ClosureJob
+    where
+        F: FnOnce(&ClosureJob) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        ClosureJob {
+            desc: self.desc,
+            execute_after_cancellation: self.execute_after_cancellation,
+            target: self.target,
+            timeout: self.timeout,
+            task_creator: Mutex::new(Some(Box::new(move |job: &ClosureJob| {
+                Box::pin(f(job)) as Pin<Box<dyn Future<Output = ()>>>
+            }))),
+            failure: Arc::new(Mutex::new(None)),
+        }
+    }
 }
 
 impl ClosureJob {
+    pub fn builder(desc: impl Into<String>) -> ClosureJobBuilder {
+        ClosureJobBuilder {
+            desc: desc.into(),
+            execute_after_cancellation: false,
+            target: JobTarget::Host,
+            timeout: None,
+        }
+    }
+
     pub fn new(
         desc: impl Into<String>,
         target: JobTarget,
@@ -24,14 +109,15 @@ impl ClosureJob {
                 + Send // the closure itself can be sent across threads
                 + 'static,
         >,
-    ) -> Self
-where {
-        Self {
-            desc: desc.into(),
-            execute_after_cancellation,
-            target,
-            task_creator: f,
-        }
+    ) -> Self {
+        ClosureJob::builder(desc)
+            .target(target)
+            .cleanup(execute_after_cancellation)
+            .task(f)
+    }
+
+    pub fn target(&self) -> &JobTarget {
+        &self.target
     }
 }
 
@@ -45,20 +131,61 @@ impl Job for ClosureJob {
     }
 
     fn create_task(self: &ClosureJob) -> Pin<Box<dyn Future<Output = ()>>> {
-        let creator = &self.task_creator;
+        let creator = self
+            .task_creator
+            .lock()
+            .unwrap()
+            .take()
+            .expect("the task of a ClosureJob can only be created once");
         let task = creator(self);
-        task
+        let Some(timeout) = self.timeout else {
+            return task;
+        };
+        let failure = self.failure.clone();
+        Box::pin(async move {
+            if let Either::Right(_) = select(task, Timer::after(timeout)).await {
+                *failure.lock().unwrap() = Some(format!("timed out after {:?}", timeout));
+            }
+        })
     }
 
     fn job_target(&self) -> JobTarget {
         self.target.clone()
     }
+
+    fn failure(&self) -> Option<String> {
+        self.failure.lock().unwrap().clone()
+    }
 }
 
+/// Creates a boxed ClosureJob, ready to be dispatched. Without a target, the job runs on
+/// the host queue.
+macro_rules! job {
+    ($desc:expr, async move $body:block) => {
+        $crate::job_engine::closure_job::job!(
+            $desc,
+            $crate::job_engine::job::JobTarget::Host,
+            async move $body
+        )
+    };
+    ($desc:expr, $target:expr, async move $body:block) => {
+        Box::new(
+            $crate::job_engine::closure_job::ClosureJob::builder($desc)
+                .target($target)
+                .task(move |_job| async move $body),
+        )
+    };
+}
+
+#[allow(unused_imports)]
+pub(crate) use job;
+
 #[cfg(test)]
 mod tests {
-    use super::super::job::{Dispatcher, JobTarget};
+    use super::super::job::{Dispatcher, JobTarget, ShutdownMode};
     use super::ClosureJob;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
 
     /// Example usage
     #[test]
@@ -66,29 +193,66 @@ mod tests {
         let mut dispatcher = Dispatcher::new();
 
         // Send a Host job
-        dispatcher.dispatch(Box::new(ClosureJob::new(
-            "Host maintenance",
-            JobTarget::Host,
-            false,
-            Box::new(|job: &ClosureJob| {
-                let target = job.target.clone();
-                Box::pin(async move {
-                    println!("Running host job on {:?}", target);
-                })
-            }),
-        )));
+        dispatcher.dispatch(job!("Host maintenance", async move {
+            println!("Running host job");
+        }));
 
         // Sending a Container job works the same
-        // dispatcher.dispatch(Job::new(JobTarget::Container(ns.clone()), "Container task", false, |target| async move {
-        //     println!("Running container job for {:?}", target);
+        // dispatcher.dispatch(job!("Container task", JobTarget::Container(process), async move {
+        //     println!("Running container job");
         // }));
 
-        //
-        // JOB_DISPATCHER.get().unwrap().lock().unwrap().dispatch(Box::new(ClosureJob::new("Monitor udev events", JobTarget::BackgroundLoop,false,
-        //     Box::new(move |_target| Box::pin(monitor_udev::udev_monitor_loop(cancel_token.clone()))))));
+        // Jobs that need more than a target use the builder
+        dispatcher.dispatch(Box::new(
+            ClosureJob::builder("Host cleanup")
+                .target(JobTarget::Host)
+                .cleanup(true)
+                .timeout(Duration::from_secs(1))
+                .task(move |job: &ClosureJob| {
+                    let target = job.target().clone();
+                    async move {
+                        println!("Running cleanup job on {:?}", target);
+                    }
+                }),
+        ));
 
         // Allow loops to run briefly before dropping all senders -> graceful shutdown
-        dispatcher.close();
-        dispatcher.wait_until_finished();
+        dispatcher.close(ShutdownMode::Drain);
+        dispatcher.wait_until_finished(Duration::from_secs(5));
+    }
+
+    #[test]
+    fn timeout_marks_the_job_as_failed() {
+        let mut dispatcher = Dispatcher::new();
+        let (_never_sent, never_received) = async_channel::bounded::<()>(1);
+        dispatcher.dispatch(Box::new(
+            ClosureJob::builder("Stuck")
+                .timeout(Duration::from_millis(50))
+                .task(move |_job| async move {
+                    let _ = never_received.recv().await;
+                }),
+        ));
+        let finished = Arc::new(Mutex::new(false));
+        let finished_in_job = finished.clone();
+        dispatcher.dispatch(job!("After stuck", async move {
+            *finished_in_job.lock().unwrap() = true;
+        }));
+
+        // wait for the second job, the status entry is gone once the queue ended
+        let mut last_error = None;
+        for _ in 0..100 {
+            if *finished.lock().unwrap() {
+                last_error = dispatcher
+                    .queue_status()
+                    .into_iter()
+                    .find_map(|(_, status)| status.last_error);
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        dispatcher.close(ShutdownMode::Drain);
+        dispatcher.wait_until_finished(Duration::from_secs(5));
+
+        assert_eq!(last_error.as_deref(), Some("Stuck: timed out after 50ms"));
     }
 }
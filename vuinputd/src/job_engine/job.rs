@@ -6,11 +6,17 @@ use async_channel::{Receiver, Sender};
 use futures::executor::{LocalPool, LocalSpawner};
 use futures::future::RemoteHandle;
 use futures::task::LocalSpawnExt;
+use futures::FutureExt;
 use log::debug;
-use std::sync::Mutex;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Mutex, Weak};
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc};
 
+use crate::control::events;
+use crate::control::protocol::Event;
+use crate::metrics;
 use crate::process_tools::RequestingProcess;
 
 // To discuss:
@@ -28,6 +34,30 @@ pub enum JobTarget {
     Container(RequestingProcess),
 }
 
+impl std::fmt::Display for JobTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobTarget::Host => write!(f, "host"),
+            JobTarget::BackgroundLoop => write!(f, "background"),
+            JobTarget::Container(process) => match &process.identity {
+                Some(identity) => write!(
+                    f,
+                    "container (pid {}, root pid {}, {})",
+                    process.pid_requestor.to_string_rep(),
+                    process.pid_requestor_root.to_string_rep(),
+                    identity
+                ),
+                None => write!(
+                    f,
+                    "container (pid {}, root pid {})",
+                    process.pid_requestor.to_string_rep(),
+                    process.pid_requestor_root.to_string_rep()
+                ),
+            },
+        }
+    }
+}
+
 pub trait Job: Send + 'static {
     /// Free-form description, used for logging or debugging
     fn desc(&self) -> &str;
@@ -42,8 +72,46 @@ pub trait Job: Send + 'static {
 
     /// Main entry point — creates the future that executes this job
     fn create_task(self: &Self) -> Pin<Box<dyn Future<Output = ()>>>;
+
+    /// Error of the finished task, for jobs that fail without panicking.
+    /// Queried by the dispatcher after the task has finished.
+    fn failure(&self) -> Option<String> {
+        None
+    }
 }
 
+/// Bookkeeping of a single job queue, so users can see where the jobs of a target are stuck.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueueStatus {
+    /// Jobs that wait for the running one
+    pub queued: usize,
+    pub running: Option<String>,
+    /// Description and error of the last job that failed
+    pub last_error: Option<String>,
+}
+
+/// How the dispatcher treats queued jobs when it is closed. Background loops are
+/// cancelled in every mode, a job that is already running finishes unless aborted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownMode {
+    /// Run all queued jobs
+    Drain,
+    /// Only run the queued jobs that return true for execute_after_cancellation
+    CancelNonCleanup,
+    /// Drop the running and all queued jobs
+    Abort,
+}
+
+type TargetMap = Mutex<HashMap<JobTarget, Sender<Box<dyn Job>>>>;
+type Targets = Arc<TargetMap>;
+type QueueStatuses = Arc<Mutex<HashMap<JobTarget, QueueStatus>>>;
+type FutureHandles = Arc<Mutex<Vec<RemoteHandle<()>>>>;
+type Shutdown = Arc<Mutex<Option<ShutdownMode>>>;
+
+/// Time the jobs get to end after they have been aborted. A job that blocks its thread, e.g. on
+/// a lock, can't be interrupted; its thread is left behind.
+const ABORT_GRACE: Duration = Duration::from_secs(1);
+
 impl std::fmt::Debug for dyn Job {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Job")
@@ -58,7 +126,14 @@ impl std::fmt::Debug for dyn Job {
 pub struct Dispatcher {
     thread_handle: Option<JoinHandle<()>>,
     tx: Option<Sender<Box<dyn Job>>>,
-    future_handles: Arc<Mutex<Vec<RemoteHandle<()>>>>,
+    // the dispatcher loop and the per-target loops
+    loop_handles: FutureHandles,
+    // background loops never end by themselves, so they are kept apart to cancel them
+    background_handles: FutureHandles,
+    shutdown: Shutdown,
+    // Weak, as the target loops only end once the dispatcher loop dropped their senders
+    targets: Weak<TargetMap>,
+    statuses: QueueStatuses,
 }
 
 impl Dispatcher {
@@ -67,12 +142,18 @@ impl Dispatcher {
         let (tx, rx) = async_channel::unbounded();
 
         // Map of active per-target senders.
-        let targets: Arc<Mutex<HashMap<JobTarget, Sender<Box<dyn Job>>>>> =
-            Arc::new(Mutex::new(HashMap::new()));
+        let targets: Targets = Arc::new(Mutex::new(HashMap::new()));
+        let statuses: QueueStatuses = Arc::new(Mutex::new(HashMap::new()));
+        let targets_for_thread = targets.clone();
+        let statuses_for_thread = statuses.clone();
 
         let rx_in_thread: Receiver<Box<dyn Job>> = rx.clone();
-        let future_handles: Arc<Mutex<Vec<RemoteHandle<()>>>> = Arc::new(Mutex::new(Vec::new()));
-        let future_handles_for_thread = future_handles.clone();
+        let loop_handles: FutureHandles = Arc::new(Mutex::new(Vec::new()));
+        let background_handles: FutureHandles = Arc::new(Mutex::new(Vec::new()));
+        let shutdown: Shutdown = Arc::new(Mutex::new(None));
+        let loop_handles_for_thread = loop_handles.clone();
+        let background_handles_for_thread = background_handles.clone();
+        let shutdown_for_thread = shutdown.clone();
         // run dispatcher in a dedicated thread
         let thread_handle = thread::spawn(move || {
             let mut pool = LocalPool::new();
@@ -81,12 +162,15 @@ impl Dispatcher {
             let dispatcher_loop_handle = spawner
                 .spawn_local_with_handle(spawn_dispatcher_loop(
                     spawner.clone(),
-                    targets,
+                    targets_for_thread,
+                    statuses_for_thread,
                     rx_in_thread,
-                    future_handles_for_thread.clone(),
+                    loop_handles_for_thread.clone(),
+                    background_handles_for_thread,
+                    shutdown_for_thread,
                 ))
                 .unwrap();
-            future_handles_for_thread
+            loop_handles_for_thread
                 .lock()
                 .unwrap()
                 .push(dispatcher_loop_handle);
@@ -96,39 +180,149 @@ impl Dispatcher {
         Self {
             thread_handle: Some(thread_handle),
             tx: Some(tx),
-            future_handles: future_handles,
+            loop_handles,
+            background_handles,
+            shutdown,
+            targets: Arc::downgrade(&targets),
+            statuses,
         }
     }
 
+    /// Snapshot of all job queues (background loops have no queue)
+    pub fn queue_status(&self) -> Vec<(JobTarget, QueueStatus)> {
+        queue_status(&self.targets, &self.statuses)
+    }
+
+    /// False once the dispatcher has been closed or its thread is gone, e.g. after a background
+    /// loop panicked
+    pub fn is_running(&self) -> bool {
+        self.tx.is_some()
+            && self
+                .thread_handle
+                .as_ref()
+                .is_some_and(|handle| !handle.is_finished())
+    }
+
+    /// Queues the job. After close(), e.g. when a job that is left dispatches another one, the
+    /// job is dropped.
     pub fn dispatch(&mut self, job: Box<dyn Job>) {
-        self.tx
-            .as_ref()
-            .expect("Dispatcher already closed")
-            .send_blocking(job)
-            .unwrap();
+        let Some(tx) = self.tx.as_ref() else {
+            log::info!("Dispatcher already closed, dropping job: {}", job.desc());
+            return;
+        };
+        if let Err(e) = tx.send_blocking(job) {
+            log::warn!("Dispatcher loop has ended, dropping job: {}", e.0.desc());
+        }
     }
 
-    pub fn close(&mut self) {
+    /// Stops accepting jobs. Closing again, e.g. with Abort after Drain, is allowed.
+    pub fn close(&mut self, mode: ShutdownMode) {
         self.tx = None;
-        debug!("Checking for running jobs before shutdown");
-        self.future_handles.lock().unwrap().clear();
-        debug!("Pending jobs canceled");
+        *self.shutdown.lock().unwrap() = Some(mode);
+        // dropping a RemoteHandle cancels its future
+        self.background_handles.lock().unwrap().clear();
+        if mode == ShutdownMode::Abort {
+            self.loop_handles.lock().unwrap().clear();
+            debug!("Running and pending jobs aborted");
+        } else {
+            debug!("Dispatcher closed ({:?})", mode);
+        }
     }
 
-    pub fn wait_until_finished(&mut self) {
-        self.tx = None;
-        self.future_handles.lock().unwrap().clear();
-        let handle = self.thread_handle.take();
-        handle.unwrap().join().unwrap();
+    /// The thread of the dispatcher, to wait for it after close(). None if it has been taken
+    /// already.
+    pub fn take_thread(&mut self) -> Option<DispatcherThread> {
+        Some(DispatcherThread {
+            handle: self.thread_handle.take()?,
+            loop_handles: self.loop_handles.clone(),
+            targets: self.targets.clone(),
+            statuses: self.statuses.clone(),
+        })
     }
+
+    /// Waits for the jobs that are left after close(), see DispatcherThread::wait_until_finished.
+    /// Jobs that dispatch other jobs need the dispatcher, so the global one is not waited for
+    /// with this, but with the thread taken out of it.
+    pub fn wait_until_finished(&mut self, grace: Duration) {
+        if let Some(thread) = self.take_thread() {
+            thread.wait_until_finished(grace);
+        }
+    }
+}
+
+/// The thread of a closed dispatcher, which can be waited for without holding the lock of the
+/// dispatcher: the jobs that are left may take it to dispatch other jobs.
+#[derive(Debug)]
+pub struct DispatcherThread {
+    handle: JoinHandle<()>,
+    loop_handles: FutureHandles,
+    targets: Weak<TargetMap>,
+    statuses: QueueStatuses,
+}
+
+impl DispatcherThread {
+    /// Waits until `deadline` and returns whether the thread has finished
+    fn finished_by(&self, deadline: Instant) -> bool {
+        while !self.handle.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        self.handle.is_finished()
+    }
+
+    /// Waits for the jobs that are left after close(). If they take longer than `grace`, they
+    /// are aborted, and if they do not end within ABORT_GRACE then, the thread is left behind.
+    pub fn wait_until_finished(self, grace: Duration) {
+        if !self.finished_by(Instant::now() + grace) {
+            log::warn!(
+                "Jobs did not finish within {:?}, aborting them: {:?}",
+                grace,
+                queue_status(&self.targets, &self.statuses)
+            );
+            // dropping a RemoteHandle cancels its future
+            self.loop_handles.lock().unwrap().clear();
+            if !self.finished_by(Instant::now() + ABORT_GRACE) {
+                log::warn!(
+                    "Jobs did not end within {:?} after being aborted, leaving them behind: {:?}",
+                    ABORT_GRACE,
+                    queue_status(&self.targets, &self.statuses)
+                );
+                return;
+            }
+        }
+        if self.handle.join().is_err() {
+            log::warn!("The thread of the dispatcher panicked");
+        }
+    }
+}
+
+fn queue_status(
+    targets: &Weak<TargetMap>,
+    statuses: &QueueStatuses,
+) -> Vec<(JobTarget, QueueStatus)> {
+    let Some(targets) = targets.upgrade() else {
+        return Vec::new();
+    };
+    let targets = targets.lock().unwrap();
+    let statuses = statuses.lock().unwrap();
+    targets
+        .iter()
+        .map(|(target, tx)| {
+            let mut status = statuses.get(target).cloned().unwrap_or_default();
+            status.queued = tx.len();
+            (target.clone(), status)
+        })
+        .collect()
 }
 
 /// Run the dispatcher: listen for incoming jobs and route them to the right loop.
 async fn spawn_dispatcher_loop(
     spawner: LocalSpawner,
-    targets: Arc<Mutex<HashMap<JobTarget, Sender<Box<dyn Job>>>>>,
+    targets: Targets,
+    statuses: QueueStatuses,
     rx: Receiver<Box<dyn Job>>,
-    future_handles: Arc<Mutex<Vec<RemoteHandle<()>>>>,
+    loop_handles: FutureHandles,
+    background_handles: FutureHandles,
+    shutdown: Shutdown,
 ) {
     loop {
         let received_job = rx.recv().await;
@@ -136,17 +330,29 @@ async fn spawn_dispatcher_loop(
             Ok(job) => {
                 if job.job_target() == JobTarget::BackgroundLoop {
                     // this is a separate loop that just runs in parallel and does not need a queue to be ordered.
+                    // The handles are locked before looking at the shutdown mode, so close() either sees
+                    // the new handle or the loop is not spawned at all.
+                    let mut background_handles = background_handles.lock().unwrap();
+                    if shutdown.lock().unwrap().is_some() {
+                        log::info!(
+                            "Not spawning background loop {:?} during shutdown",
+                            job.desc()
+                        );
+                        continue;
+                    }
                     let background_loop_handle =
                         spawner.spawn_local_with_handle(job.create_task()).unwrap();
-                    future_handles.lock().unwrap().push(background_loop_handle);
+                    background_handles.push(background_loop_handle);
                     log::info!("Spawned new background loop for {:?}", job.desc());
                 } else {
                     let target = job.job_target();
                     let (tx, newly_created) = get_or_spawn_target_loop(
                         spawner.clone(),
                         targets.clone(),
+                        statuses.clone(),
+                        shutdown.clone(),
                         target.clone(),
-                        future_handles.clone(),
+                        loop_handles.clone(),
                     )
                     .await;
                     if newly_created {
@@ -170,9 +376,11 @@ async fn spawn_dispatcher_loop(
 /// Get or lazily create a target-specific queue and loop.
 async fn get_or_spawn_target_loop(
     spawner: LocalSpawner,
-    targets: Arc<Mutex<HashMap<JobTarget, Sender<Box<dyn Job>>>>>,
+    targets: Targets,
+    statuses: QueueStatuses,
+    shutdown: Shutdown,
     target: JobTarget,
-    future_handles: Arc<Mutex<Vec<RemoteHandle<()>>>>,
+    loop_handles: FutureHandles,
 ) -> (Sender<Box<dyn Job>>, bool) {
     let mut map = targets.lock().unwrap();
     if let Some(tx) = map.get(&target) {
@@ -184,27 +392,64 @@ async fn get_or_spawn_target_loop(
     drop(map); // release lock before spawning
 
     let job_target_loop_handle = spawner
-        .spawn_local_with_handle(job_target_loop(target.clone(), rx))
+        .spawn_local_with_handle(job_target_loop(target.clone(), rx, statuses, shutdown))
         .unwrap();
-    future_handles.lock().unwrap().push(job_target_loop_handle);
+    loop_handles.lock().unwrap().push(job_target_loop_handle);
 
     (tx, true)
 }
 
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        format!("panicked: {}", message)
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        format!("panicked: {}", message)
+    } else {
+        "panicked".to_string()
+    }
+}
+
 /// The main loop for a single job target (container or host).
-async fn job_target_loop(target: JobTarget, rx: Receiver<Box<dyn Job>>) {
+async fn job_target_loop(
+    target: JobTarget,
+    rx: Receiver<Box<dyn Job>>,
+    statuses: QueueStatuses,
+    shutdown: Shutdown,
+) {
     log::info!("Starting loop for {:?}", target);
+    let set_status = |update: &dyn Fn(&mut QueueStatus)| {
+        update(statuses.lock().unwrap().entry(target.clone()).or_default());
+    };
     while let Ok(job) = rx.recv().await {
+        if *shutdown.lock().unwrap() == Some(ShutdownMode::CancelNonCleanup)
+            && !job.execute_after_cancellation()
+        {
+            log::debug!("Skipping job after cancellation: {}", job.desc());
+            continue;
+        }
         log::debug!("Executing job: {}", job.desc());
-        job.create_task().await;
+        set_status(&|status| status.running = Some(job.desc().to_string()));
+        let started = Instant::now();
+        // a panicking job must not take the other jobs of the dispatcher thread down with it
+        let failure = match AssertUnwindSafe(job.create_task()).catch_unwind().await {
+            Ok(()) => job.failure(),
+            Err(panic) => Some(panic_message(panic.as_ref())),
+        };
+        metrics::record_job(job.desc(), started.elapsed());
+        let error = failure.map(|failure| format!("{}: {}", job.desc(), failure));
+        if let Some(error) = &error {
+            events::publish(Event::JobFailed {
+                target: target.to_string(),
+                error: error.clone(),
+            });
+        }
+        set_status(&|status| {
+            status.running = None;
+            if error.is_some() {
+                status.last_error = error.clone();
+            }
+        });
     }
+    statuses.lock().unwrap().remove(&target);
     log::info!("Loop for {:?} ended — channel closed", target);
 }
-
-/*
-macro_rules! job {
-    ($desc:expr, async move { $($body:tt)* }) => {
-        Box::new(ClosureJob::new($desc, |_: JobTarget| async move { $($body)* }))
-    };
-}
-     */
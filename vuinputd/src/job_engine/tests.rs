@@ -1,5 +1,5 @@
-use crate::job_engine::closure_job::ClosureJob;
-use crate::job_engine::job::{Dispatcher, JobTarget};
+use crate::job_engine::closure_job::{job, ClosureJob};
+use crate::job_engine::job::{Dispatcher, JobTarget, ShutdownMode};
 
 use super::*;
 use futures::executor::LocalPool;
@@ -7,6 +7,9 @@ use futures::task::LocalSpawnExt;
 use std::cell::RefCell;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const GRACE: Duration = Duration::from_secs(5);
 
 /// Simple shared integer counter
 fn shared_counter() -> Arc<Mutex<i32>> {
@@ -22,38 +25,139 @@ fn test_job_ordering() {
     let c = shared_counter();
 
     let c1 = c.clone();
-    dispatcher.dispatch(Box::new(ClosureJob::new(
-        "set to 5",
-        JobTarget::Host,
-        false,
-        Box::new(move |_job| {
-            let c1 = c1.clone();
-            Box::pin(async move {
-                *c1.lock().unwrap() = 5;
-            })
-        }),
-    )));
+    dispatcher.dispatch(job!("set to 5", async move {
+        *c1.lock().unwrap() = 5;
+    }));
 
     // job 2: increment to 6
     let c2 = c.clone();
-    dispatcher.dispatch(Box::new(ClosureJob::new(
-        "increment to 6",
-        JobTarget::Host,
-        false,
-        Box::new(move |_job| {
-            let c2 = c2.clone();
-            Box::pin(async move {
-                *c2.lock().unwrap() += 1;
-            })
-        }),
-    )));
+    dispatcher.dispatch(job!("increment to 6", JobTarget::Host, async move {
+        *c2.lock().unwrap() += 1;
+    }));
 
-    dispatcher.close();
-    dispatcher.wait_until_finished();
+    dispatcher.close(ShutdownMode::Drain);
+    dispatcher.wait_until_finished(GRACE);
 
     assert_eq!(*c.lock().unwrap(), 6);
 }
 
+/// Sender to report that the job has started and receiver to wait for the gate to open
+type Gate = (async_channel::Sender<()>, async_channel::Receiver<()>);
+
+/// Job that adds to the counter, after `gate` has been opened (if any)
+fn counting_job(
+    desc: &'static str,
+    cleanup: bool,
+    counter: &Arc<Mutex<i32>>,
+    amount: i32,
+    gate: Option<Gate>,
+) -> Box<ClosureJob> {
+    let counter = counter.clone();
+    Box::new(
+        ClosureJob::builder(desc)
+            .cleanup(cleanup)
+            .task(move |_job| async move {
+                if let Some((started, gate)) = gate {
+                    let _ = started.send(()).await;
+                    let _ = gate.recv().await;
+                }
+                *counter.lock().unwrap() += amount;
+            }),
+    )
+}
+
+#[test]
+fn test_cancel_runs_only_cleanup_jobs() {
+    let mut dispatcher = Dispatcher::new();
+    let c = shared_counter();
+    let (started, wait_for_start) = async_channel::bounded(1);
+    let (open_gate, gate) = async_channel::bounded(1);
+
+    // the first job keeps the others queued until the dispatcher has been closed
+    dispatcher.dispatch(counting_job("running", false, &c, 1, Some((started, gate))));
+    dispatcher.dispatch(counting_job("queued", false, &c, 10, None));
+    dispatcher.dispatch(counting_job("cleanup", true, &c, 100, None));
+
+    wait_for_start.recv_blocking().unwrap();
+    dispatcher.close(ShutdownMode::CancelNonCleanup);
+    open_gate.send_blocking(()).unwrap();
+    dispatcher.wait_until_finished(GRACE);
+
+    assert_eq!(*c.lock().unwrap(), 101);
+}
+
+#[test]
+fn test_abort_after_grace_period() {
+    let mut dispatcher = Dispatcher::new();
+    let c = shared_counter();
+    let (started, _) = async_channel::bounded(1);
+    let (_never_opened, gate) = async_channel::bounded(1);
+
+    dispatcher.dispatch(counting_job("stuck", false, &c, 1, Some((started, gate))));
+    dispatcher.dispatch(counting_job("cleanup", true, &c, 100, None));
+
+    dispatcher.close(ShutdownMode::Drain);
+    dispatcher.wait_until_finished(Duration::from_millis(100));
+
+    assert_eq!(*c.lock().unwrap(), 0);
+}
+
+#[test]
+fn test_dispatch_from_a_job_while_closing() {
+    let dispatcher = Arc::new(Mutex::new(Dispatcher::new()));
+    let c = shared_counter();
+    let (started, wait_for_start) = async_channel::bounded(1);
+    let (open_gate, gate) = async_channel::bounded(1);
+
+    // the job dispatches another one once the dispatcher has been closed
+    let (dispatcher_in_job, c_in_job) = (dispatcher.clone(), c.clone());
+    dispatcher
+        .lock()
+        .unwrap()
+        .dispatch(job!("dispatching", async move {
+            let _ = started.send(()).await;
+            let _ = gate.recv().await;
+            dispatcher_in_job
+                .lock()
+                .unwrap()
+                .dispatch(counting_job("too late", true, &c_in_job, 10, None));
+            *c_in_job.lock().unwrap() += 1;
+        }));
+
+    wait_for_start.recv_blocking().unwrap();
+    let thread = {
+        let mut dispatcher = dispatcher.lock().unwrap();
+        dispatcher.close(ShutdownMode::Drain);
+        dispatcher.take_thread().unwrap()
+    };
+    open_gate.send_blocking(()).unwrap();
+    thread.wait_until_finished(GRACE);
+
+    assert_eq!(*c.lock().unwrap(), 1);
+}
+
+#[test]
+fn test_abort_leaves_a_blocked_job_behind() {
+    let mut dispatcher = Dispatcher::new();
+    let lock = Arc::new(Mutex::new(()));
+    let held = lock.lock().unwrap();
+    let (started, wait_for_start) = async_channel::bounded(1);
+
+    // blocks the thread of the dispatcher, which no abort can interrupt
+    let lock_in_job = lock.clone();
+    dispatcher.dispatch(job!("blocked", async move {
+        let _ = started.send(()).await;
+        let _guard = lock_in_job.lock().unwrap();
+    }));
+
+    wait_for_start.recv_blocking().unwrap();
+    dispatcher.close(ShutdownMode::Drain);
+    let waiting = std::time::Instant::now();
+    dispatcher.wait_until_finished(Duration::from_millis(100));
+    assert!(waiting.elapsed() < GRACE);
+    drop(held);
+}
+
 /*
 
 //
@@ -3,7 +3,7 @@
 // Author: Johannes Leupolz <dev@leupolz.eu>
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     future::Future,
     pin::Pin,
     sync::{Arc, Condvar, Mutex},
@@ -11,12 +11,16 @@ use std::{
 };
 
 use async_io::Timer;
-use log::debug;
+use log::{debug, warn};
 
 use crate::{
     actions::action::Action,
-    global_config::get_container_runtime,
-    input_realizer::runtime_data,
+    container_runtime::injection_strategy::InjectionStrategy,
+    global_config::{get_container_runtime, get_reloadable_config},
+    input_realizer::{
+        container_udevd, node_names, runtime_data, seat,
+        udev_properties::{self, InputCapabilities, UdevPropertyRule},
+    },
     job_engine::job::{Job, JobTarget},
     jobs::monitor_udev_job::EVENT_STORE,
     process_tools::{self, await_process, Pid, RequestingProcess},
@@ -37,6 +41,8 @@ pub struct EmitUdevEventJob {
     sys_path: String,
     major: u64,
     minor: u64,
+    /// Replaces "noserial" of the host, see device_serial
+    serial: Option<String>,
     sync_state: Arc<(Mutex<State>, Condvar)>,
 }
 
@@ -47,6 +53,7 @@ impl EmitUdevEventJob {
         sys_path: String,
         major: u64,
         minor: u64,
+        serial: Option<String>,
     ) -> Self {
         Self {
             requesting_process: requesting_process.clone(),
@@ -55,6 +62,7 @@ impl EmitUdevEventJob {
             sys_path: sys_path,
             major: major,
             minor: minor,
+            serial,
             sync_state: Arc::new((Mutex::new(State::Initialized), Condvar::new())),
         }
     }
@@ -101,31 +109,31 @@ impl Job for EmitUdevEventJob {
 
 impl EmitUdevEventJob {
     async fn emit_udev_event(self) {
-        // temporary hack that needs to be replaced. We try 50 times
-        // Should be: Wait for the device to be created, the runtime data to be written and the
-        // netlink message to be sent
         self.set_state(&State::Started);
+        // the udev monitor wakes us when it has the event of the device
+        let netlink_event = EVENT_STORE
+            .get()
+            .unwrap()
+            .take_when_announced(&self.sys_path, Duration::from_secs(5))
+            .await;
         let mut netlink_data: Option<HashMap<String, String>> = None;
+        let mut parent_data: Option<HashMap<String, String>> = None;
+        if let Some(netlink_event) = netlink_event {
+            if netlink_event.tombstone || netlink_event.remove_data.is_some() {
+                debug!("do nothing, because the device has already been removed in the meantime");
+                return;
+            }
+            netlink_data = netlink_event.add_data;
+            parent_data = netlink_event.parent_data;
+        }
+        // temporary hack that needs to be replaced. We try 50 times
+        // Should be: Wait for udev to write the runtime data
         let mut runtime_data: Option<String> = None;
         let mut number_of_attempt = 1;
-        while number_of_attempt <= 50 && !(netlink_data.is_some() && runtime_data.is_some()) {
-            if netlink_data.is_none() {
-                if let Some(netlink_event) = EVENT_STORE
-                    .get()
-                    .unwrap()
-                    .lock()
-                    .unwrap()
-                    .take(&self.sys_path)
-                {
-                    if netlink_event.tombstone || netlink_event.remove_data.is_some() {
-                        debug!("do nothing, because the device has already been removed in the meantime");
-                        return;
-                    }
-                    netlink_data = netlink_event.add_data;
-                };
-            }
-            if runtime_data.is_none() {
-                runtime_data = runtime_data::read_udev_data(self.major, self.minor).ok();
+        while number_of_attempt <= 50 && netlink_data.is_some() {
+            runtime_data = runtime_data::read_udev_data(self.major, self.minor).ok();
+            if runtime_data.is_some() {
+                break;
             }
 
             number_of_attempt += 1;
@@ -135,19 +143,61 @@ impl EmitUdevEventJob {
         if netlink_data.is_none() || runtime_data.is_none() {
             if netlink_data.is_none() {
                 debug!("Give up reading netlink data");
-            }
-            if runtime_data.is_none() {
+            } else {
                 debug!("Give up reading runtime data");
             }
             self.set_state(&State::Finished);
             return;
         }
 
-        let runtime_data = runtime_data.unwrap();
-        let netlink_data = netlink_data.unwrap();
+        let mut netlink_data = netlink_data.unwrap();
+        if let Some(serial) = &self.serial {
+            netlink_data.insert("ID_SERIAL".to_string(), serial.clone());
+        }
+        // what the udevd of the host left out, and the [[udev-property]] rules
+        let device = udev_properties::read_capabilities(&self.sys_path);
+        let rules = &get_reloadable_config().udev_property_rules;
+        let changes = device
+            .as_ref()
+            .map(|device| udev_properties::changes(&netlink_data, device, rules))
+            .unwrap_or_default();
+        udev_properties::set_in_properties(&mut netlink_data, &changes);
+        let seat = seat::container_seat(&self.requesting_process);
+        if let Some(seat) = &seat {
+            seat::set_seat_in_properties(&mut netlink_data, seat);
+        }
+        let runtime_data = container_runtime_data(
+            &runtime_data.unwrap(),
+            self.serial.as_deref(),
+            &changes,
+            seat.as_deref(),
+        );
+        let parent_data = parent_data.map(|parent_data| {
+            parent_properties(
+                parent_data,
+                self.serial.as_deref(),
+                device.as_ref(),
+                rules,
+                seat.as_deref(),
+            )
+        });
+        let devname = self.dev_path.rsplit('/').next().unwrap_or_default();
+        let node = node_names::assigned(&self.requesting_process, devname, self.major, self.minor);
+        if node != devname {
+            netlink_data.insert("DEVNAME".to_string(), node_names::node_path(&node));
+        }
 
         let injector = get_container_runtime().injection_strategy();
 
+        if container_udevd::is_in_charge(&self.requesting_process)
+            && self
+                .hand_to_container_udevd(injector, parent_data.clone(), netlink_data.clone())
+                .await
+        {
+            self.set_state(&State::Finished);
+            return;
+        }
+
         injector
             .write_udev_runtime_data(
                 &self.requesting_process,
@@ -158,6 +208,20 @@ impl EmitUdevEventJob {
             .await
             .unwrap();
 
+        // like udevd, the add of inputN comes before the one of its event node. Consumers that
+        // look up the parent (e.g. for its NAME or PRODUCT) get it from their cache then.
+        if let Some(parent_data) = parent_data {
+            if let Err(e) = injector
+                .emit_netlink_message(&self.requesting_process, parent_data)
+                .await
+            {
+                warn!(
+                    "failed to emit the add event of the parent of {}: {:#}",
+                    self.dev_path, e
+                );
+            }
+        }
+
         injector
             .emit_netlink_message(&self.requesting_process, netlink_data)
             .await
@@ -165,4 +229,109 @@ impl EmitUdevEventJob {
 
         self.set_state(&State::Finished);
     }
+
+    /// Has the kernel send the add events in the container, for its udevd, see container_udevd.
+    /// False if that udevd did not take them, and vuinputd has to write its database after all.
+    async fn hand_to_container_udevd(
+        &self,
+        injector: &dyn InjectionStrategy,
+        parent_data: Option<HashMap<String, String>>,
+        netlink_data: HashMap<String, String>,
+    ) -> bool {
+        for event in parent_data.into_iter().chain([netlink_data]) {
+            if let Err(e) = injector
+                .emit_kernel_uevent(&self.requesting_process, event)
+                .await
+            {
+                warn!(
+                    "VUI-UDEV-005 — can't hand {} to the udevd of the container: {:#}. vuinputd \
+                    writes the udev database of the container itself",
+                    self.dev_path, e
+                );
+                container_udevd::set_accepted(&self.requesting_process, false);
+                return false;
+            }
+        }
+        let accepted = container_udevd::await_database_entry(
+            &self.requesting_process,
+            self.major,
+            self.minor,
+            container_udevd::UDEVD_TIMEOUT,
+        )
+        .await;
+        if !accepted {
+            warn!(
+                "VUI-UDEV-005 — the udevd of the container did not take {} within {:?}. vuinputd \
+                writes the udev database of the container itself",
+                self.dev_path,
+                container_udevd::UDEVD_TIMEOUT
+            );
+        }
+        container_udevd::set_accepted(&self.requesting_process, accepted);
+        accepted
+    }
+}
+
+/// The udev database entry of the host as the container gets it: without the seats of the
+/// host, with the serial, the `changes` of udev_properties and the seat of the container
+pub fn container_runtime_data(
+    host_data: &str,
+    serial: Option<&str>,
+    changes: &BTreeMap<String, String>,
+    seat: Option<&str>,
+) -> String {
+    let mut runtime_data = runtime_data::clean_udev_data(host_data);
+    if let Some(serial) = serial {
+        runtime_data = runtime_data::set_serial(&runtime_data, serial);
+    }
+    runtime_data = runtime_data::set_properties(&runtime_data, changes);
+    if let Some(seat) = seat {
+        runtime_data = seat::set_seat_in_runtime_data(&runtime_data, seat);
+    }
+    runtime_data
+}
+
+/// The add event of the parent inputN as the container gets it: the serial, the properties and
+/// the seat are those of its event node. It has no node, so there is nothing to rename.
+fn parent_properties(
+    mut properties: HashMap<String, String>,
+    serial: Option<&str>,
+    device: Option<&InputCapabilities>,
+    rules: &[UdevPropertyRule],
+    seat: Option<&str>,
+) -> HashMap<String, String> {
+    if let Some(serial) = serial {
+        properties.insert("ID_SERIAL".to_string(), serial.to_string());
+    }
+    if let Some(device) = device {
+        let changes = udev_properties::changes(&properties, device, rules);
+        udev_properties::set_in_properties(&mut properties, &changes);
+    }
+    if let Some(seat) = seat {
+        seat::set_seat_in_properties(&mut properties, seat);
+    }
+    properties
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_parent_gets_serial_and_seat_of_its_event_node() {
+        let host = HashMap::from([
+            ("ACTION".to_string(), "add".to_string()),
+            (
+                "DEVPATH".to_string(),
+                "/devices/virtual/input/input155".to_string(),
+            ),
+            ("ID_SERIAL".to_string(), "noserial".to_string()),
+            ("TAGS".to_string(), ":seat:".to_string()),
+        ]);
+        let parent = parent_properties(host, Some("vuinput-4"), None, &[], Some("seat_vuinput_7"));
+        assert_eq!(parent["ID_SERIAL"], "vuinput-4");
+        assert_eq!(parent["ID_SEAT"], "seat_vuinput_7");
+        assert_eq!(parent["TAGS"], ":seat:seat_vuinput_7:");
+        assert!(!parent.contains_key("DEVNAME"));
+    }
 }
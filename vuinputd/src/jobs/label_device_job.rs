@@ -0,0 +1,118 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Writes the udev database entry of a device in the container again, with the properties of
+// its label (see cuse_device::label). The entry is built from the one of the host, the same
+// way EmitUdevEventJob does. No uevent is sent, so applications see the label the next time
+// they look the device up. A udevd of the container keeps its own database, which is left
+// alone.
+
+use std::future::Future;
+use std::pin::Pin;
+
+use log::{debug, warn};
+
+use crate::cuse_device::label;
+use crate::global_config::{get_container_runtime, get_reloadable_config};
+use crate::input_realizer::{container_udevd, runtime_data, seat, udev_properties};
+use crate::job_engine::job::{Job, JobTarget};
+use crate::jobs::emit_udev_event_job::container_runtime_data;
+use crate::process_tools::RequestingProcess;
+
+#[derive(Clone, Debug)]
+pub struct LabelDeviceJob {
+    requesting_process: RequestingProcess,
+    sys_path: String,
+    major: u64,
+    minor: u64,
+    serial: Option<String>,
+    /// None restores the properties of the host
+    label: Option<String>,
+}
+
+impl LabelDeviceJob {
+    pub fn new(
+        requesting_process: RequestingProcess,
+        sys_path: String,
+        major: u64,
+        minor: u64,
+        serial: Option<String>,
+        label: Option<String>,
+    ) -> Self {
+        Self {
+            requesting_process,
+            sys_path,
+            major,
+            minor,
+            serial,
+            label,
+        }
+    }
+
+    async fn push(self) {
+        if container_udevd::is_in_charge(&self.requesting_process) {
+            warn!(
+                "the udevd of the container keeps the udev database of c{}:{}, the label is not pushed",
+                self.major, self.minor
+            );
+            return;
+        }
+        let host_data = match runtime_data::read_udev_data(self.major, self.minor) {
+            Ok(host_data) => host_data,
+            Err(e) => {
+                warn!(
+                    "can't push the label of c{}:{}, the udev database of the host has no entry: {}",
+                    self.major, self.minor, e
+                );
+                return;
+            }
+        };
+        let rules = &get_reloadable_config().udev_property_rules;
+        let mut changes = udev_properties::read_capabilities(&self.sys_path)
+            .map(|device| {
+                udev_properties::changes(&runtime_data::properties(&host_data), &device, rules)
+            })
+            .unwrap_or_default();
+        if let Some(label) = &self.label {
+            changes.extend(label::udev_properties(label));
+        }
+        let seat = seat::container_seat(&self.requesting_process);
+        let runtime_data = container_runtime_data(
+            &host_data,
+            self.serial.as_deref(),
+            &changes,
+            seat.as_deref(),
+        );
+        match get_container_runtime()
+            .injection_strategy()
+            .write_udev_runtime_data(
+                &self.requesting_process,
+                &runtime_data,
+                self.major,
+                self.minor,
+            )
+            .await
+        {
+            Ok(()) => debug!("pushed the label of c{}:{}", self.major, self.minor),
+            Err(e) => warn!(
+                "failed to push the label of c{}:{}: {:#}",
+                self.major, self.minor, e
+            ),
+        }
+    }
+}
+
+impl Job for LabelDeviceJob {
+    fn desc(&self) -> &str {
+        "push device label"
+    }
+
+    fn create_task(self: &LabelDeviceJob) -> Pin<Box<dyn Future<Output = ()>>> {
+        Box::pin(self.clone().push())
+    }
+
+    fn job_target(&self) -> JobTarget {
+        JobTarget::Container(self.requesting_process.clone())
+    }
+}
@@ -4,14 +4,17 @@
 
 use std::{
     future::Future,
+    io,
     pin::Pin,
     sync::{Arc, Condvar, Mutex},
 };
 
+use log::error;
+
 use crate::{
     actions::action::Action,
-    global_config::{self, get_container_runtime, Placement},
-    input_realizer::input_device,
+    global_config::{self, get_container_runtime, get_reloadable_config, NodeNaming, Placement},
+    input_realizer::{input_device, node_names},
     job_engine::job::{Job, JobTarget},
     process_tools::{self, await_process, Pid, RequestingProcess},
 };
@@ -23,7 +26,10 @@ pub enum State {
     Finished,
 }
 
-#[derive(Clone, Debug)]
+/// Called with the result once the job has finished, e.g. to reply to UI_DEV_CREATE
+pub type Completion = Box<dyn FnOnce(io::Result<()>) + Send>;
+
+#[derive(Clone)]
 pub struct MknodDeviceJob {
     requesting_process: RequestingProcess,
     target: JobTarget,
@@ -32,6 +38,9 @@ pub struct MknodDeviceJob {
     major: u64,
     minor: u64,
     sync_state: Arc<(Mutex<State>, Condvar)>,
+    completion: Arc<Mutex<Option<Completion>>>,
+    node: Arc<Mutex<Option<String>>>,
+    failure: Arc<Mutex<Option<String>>>,
 }
 
 impl MknodDeviceJob {
@@ -50,9 +59,26 @@ impl MknodDeviceJob {
             major: major,
             minor: minor,
             sync_state: Arc::new((Mutex::new(State::Initialized), Condvar::new())),
+            completion: Arc::new(Mutex::new(None)),
+            node: Arc::new(Mutex::new(None)),
+            failure: Arc::new(Mutex::new(None)),
         }
     }
 
+    /// Stores the path of the node in the container in `node` once it exists, e.g. in
+    /// VuInputDevice::container_devnode
+    pub fn record_node(mut self, node: Arc<Mutex<Option<String>>>) -> Self {
+        self.node = node;
+        self
+    }
+
+    /// Lets the job report its result instead of being awaited. If the job never runs, the
+    /// completion is dropped without being called.
+    pub fn on_completion(self, completion: Completion) -> Self {
+        *self.completion.lock().unwrap() = Some(completion);
+        self
+    }
+
     fn set_state(&self, new_state: &State) -> () {
         let (lock, cvar) = &*self.sync_state;
         let mut current_state = lock.lock().unwrap();
@@ -91,22 +117,48 @@ impl Job for MknodDeviceJob {
     fn job_target(&self) -> JobTarget {
         self.target.clone()
     }
+
+    fn failure(&self) -> Option<String> {
+        self.failure.lock().unwrap().clone()
+    }
 }
 
 impl MknodDeviceJob {
     async fn mknod_device(self) {
         let injector = get_container_runtime().injection_strategy();
+        // the names of strategies that leave the nodes to others have to match the host
+        let naming = match injector.creates_nodes() {
+            true => get_reloadable_config().node_naming,
+            false => NodeNaming::Host,
+        };
+        let devname = node_names::assign(
+            &self.requesting_process,
+            &self.devname,
+            self.major,
+            self.minor,
+            naming,
+            |name| injector.node_is_usable(&self.requesting_process, name, self.major, self.minor),
+        );
 
-        injector
-            .mknod_device_node(
-                &self.requesting_process,
-                &self.devname,
-                self.major,
-                self.minor,
-            )
-            .await
-            .unwrap();
+        let result = injector
+            .mknod_device_node(&self.requesting_process, &devname, self.major, self.minor)
+            .await;
+        if result.is_ok() {
+            *self.node.lock().unwrap() = Some(node_names::node_path(&devname));
+        }
 
         self.set_state(&State::Finished);
+        match self.completion.lock().unwrap().take() {
+            Some(completion) => {
+                if let Err(e) = &result {
+                    error!("failed to create {} in the container: {}", devname, e);
+                    *self.failure.lock().unwrap() = Some(format!("{}: {}", devname, e));
+                }
+                completion(result.map_err(io::Error::other));
+            }
+            None => {
+                result.unwrap();
+            }
+        }
     }
 }
@@ -3,6 +3,13 @@
 // Author: Johannes Leupolz <dev@leupolz.eu>
 
 pub mod emit_udev_event_job;
+pub mod label_device_job;
 pub mod mknod_device_job;
 pub mod monitor_udev_job;
+pub mod node_acl_job;
+pub mod reload_config_job;
 pub mod remove_device_job;
+pub mod rules_conflict_job;
+pub mod run_hook_job;
+pub mod verify_seat_job;
+pub mod watchdog_job;
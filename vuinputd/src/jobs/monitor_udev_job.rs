@@ -5,8 +5,9 @@
 use std::{
     collections::HashMap,
     future::Future,
+    hash::{DefaultHasher, Hash, Hasher},
     os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd},
-    pin::Pin,
+    pin::{pin, Pin},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc, Mutex, OnceLock,
@@ -14,12 +15,19 @@ use std::{
     time::{Duration, Instant},
 };
 
-use async_io::Async;
+use async_channel::Sender;
+use async_io::{Async, Timer};
+use futures::future::select;
 use libudev::Monitor;
 use log::debug;
 use regex::Regex;
 
+use crate::cuse_device::vuhid::handover;
 use crate::job_engine::job::{Job, JobTarget};
+use crate::jobs::rules_conflict_job;
+
+/// Input devices of uinput and of uhid (with their event nodes), and hidraw nodes of uhid
+const DEVPATH_PATTERN: &str = r"^(/devices/virtual/(?:misc/uhid/[^/]+/)?input/input\d+)(/event\d+)?$|^(/devices/virtual/misc/uhid/[^/]+/hidraw/hidraw\d+)$";
 
 // === Basic types ===
 
@@ -35,6 +43,9 @@ pub struct UdevEvent {
     pub seqnum: u64,
     pub kind: EventKind,
     pub payload: HashMap<String, String>,
+    /// An event of the input device itself (inputN) rather than of its event node. It is kept
+    /// with the entry of the event node, but does not announce the device.
+    pub parent: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -43,30 +54,88 @@ pub struct Entry {
     pub seqnum: u64,
     pub add_data: Option<HashMap<String, String>>,
     pub remove_data: Option<HashMap<String, String>>,
+    /// The last event of the parent inputN, which udev sends before the add of the event node
+    pub parent_data: Option<HashMap<String, String>>,
     pub add_processed: bool,
     pub tombstone: bool,
     pub last_update: Instant,
 }
 
+impl Entry {
+    /// Whether the event node has been seen, the parent alone doesn't count
+    fn is_announced(&self) -> bool {
+        self.add_data.is_some() || self.remove_data.is_some()
+    }
+}
+
 // === EventStore ===
 
+// The store is touched by the monitor loop for every event and by the jobs of every device
+// that is created or removed. Behind a single lock, a desktop with a lot of hotplug churn
+// serialized all of them, so the entries are spread over shards by syspath, and jobs that
+// wait for the announcement of their device register as waiters of the syspath instead of
+// polling: the monitor wakes them when it stores the event. The #[ignore]d benchmark in the
+// tests compares the shards with a single one.
+
+/// Shards of EVENT_STORE, contention is low enough beyond that
+const SHARDS: usize = 16;
+
+#[derive(Debug, Default)]
+struct Shard {
+    entries: HashMap<String, Entry>,
+    /// Jobs waiting for the first event of a syspath
+    waiters: HashMap<String, Vec<Sender<()>>>,
+}
+
+impl Shard {
+    fn take(&mut self, syspath: &str) -> Option<Entry> {
+        let e = self.entries.get_mut(syspath)?;
+
+        let result = e.clone();
+
+        if e.tombstone {
+            return Some(result);
+        }
+
+        if !e.add_processed {
+            e.add_processed = true;
+        }
+        if e.remove_data.is_some() {
+            e.tombstone = true;
+        }
+
+        Some(result)
+    }
+}
+
 #[derive(Debug)]
 pub struct EventStore {
-    entries: HashMap<String, Entry>,
+    shards: Vec<Mutex<Shard>>,
     ttl: Duration,
 }
 
 impl EventStore {
     pub fn new(ttl: Duration) -> Self {
+        Self::with_shards(ttl, SHARDS)
+    }
+
+    fn with_shards(ttl: Duration, shards: usize) -> Self {
         Self {
-            entries: HashMap::new(),
+            shards: (0..shards).map(|_| Mutex::default()).collect(),
             ttl,
         }
     }
 
-    pub fn on_event(&mut self, event: UdevEvent) {
+    fn shard(&self, syspath: &str) -> &Mutex<Shard> {
+        let mut hasher = DefaultHasher::new();
+        syspath.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    pub fn on_event(&self, event: UdevEvent) {
         let now = Instant::now();
-        let e = self
+        let mut shard = self.shard(&event.syspath).lock().unwrap();
+        let e = shard
             .entries
             .entry(event.syspath.clone())
             .or_insert_with(|| Entry {
@@ -74,11 +143,18 @@ impl EventStore {
                 seqnum: event.seqnum,
                 add_data: None,
                 remove_data: None,
+                parent_data: None,
                 add_processed: false,
                 tombstone: false,
                 last_update: now,
             });
 
+        if event.parent {
+            e.parent_data = Some(event.payload);
+            e.last_update = now;
+            return;
+        }
+
         e.seqnum = event.seqnum;
         e.last_update = now;
         e.tombstone = false;
@@ -93,41 +169,83 @@ impl EventStore {
                 e.remove_data = Some(event.payload);
             }
         }
-    }
 
-    pub fn take(&mut self, syspath: &str) -> Option<Entry> {
-        let e = self.entries.get_mut(syspath)?;
-
-        let result = e.clone();
-
-        if e.tombstone {
-            return Some(result);
+        for waiter in shard.waiters.remove(&event.syspath).unwrap_or_default() {
+            // a waiter that has given up has dropped its receiver
+            let _ = waiter.try_send(());
         }
+    }
 
-        if !e.add_processed {
-            e.add_processed = true;
-        }
-        if e.remove_data.is_some() {
-            e.tombstone = true;
-        }
+    /// Devices the store holds events of
+    pub fn size(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().entries.len())
+            .sum()
+    }
 
-        Some(result)
+    pub fn take(&self, syspath: &str) -> Option<Entry> {
+        self.shard(syspath).lock().unwrap().take(syspath)
     }
 
-    pub fn cleanup(&mut self) {
-        let now = Instant::now();
-        self.entries.retain(|_, e| {
-            if e.tombstone {
-                return false;
+    /// Like take, but waits up to `timeout` for the first event of the syspath
+    pub async fn take_when_announced(&self, syspath: &str, timeout: Duration) -> Option<Entry> {
+        let announced = {
+            let mut shard = self.shard(syspath).lock().unwrap();
+            if shard.entries.get(syspath).is_some_and(Entry::is_announced) {
+                return shard.take(syspath);
             }
-            now.duration_since(e.last_update) < self.ttl
-        });
+            let (sender, receiver) = async_channel::bounded(1);
+            shard
+                .waiters
+                .entry(syspath.to_string())
+                .or_default()
+                .push(sender);
+            receiver
+        };
+        let announced = pin!(announced.recv());
+        let timeout = pin!(Timer::after(timeout));
+        select(announced, timeout).await;
+        self.take(syspath)
+    }
+
+    pub fn cleanup(&self) {
+        let now = Instant::now();
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            shard.entries.retain(|_, e| {
+                if e.tombstone {
+                    return false;
+                }
+                now.duration_since(e.last_update) < self.ttl
+            });
+            shard.waiters.retain(|_, waiters| {
+                waiters.retain(|waiter| !waiter.is_closed());
+                !waiters.is_empty()
+            });
+        }
     }
 }
 
 // === Global store ===
 
-pub static EVENT_STORE: OnceLock<Arc<Mutex<EventStore>>> = OnceLock::new();
+pub static EVENT_STORE: OnceLock<EventStore> = OnceLock::new();
+
+static MONITOR_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Whether the monitor listens for udev events, see health
+pub fn is_monitor_running() -> bool {
+    MONITOR_RUNNING.load(Ordering::Relaxed)
+}
+
+/// Clears MONITOR_RUNNING however the loop ends, also if it panics
+struct RunningGuard;
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        MONITOR_RUNNING.store(false, Ordering::Relaxed);
+    }
+}
 
 pub struct MonitorBackgroundLoop {}
 impl MonitorBackgroundLoop {
@@ -154,12 +272,46 @@ impl Job for MonitorBackgroundLoop {
     }
 }
 
+/// The properties of a udev event as they are sent into the container: the markers of
+/// 90-vuinputd-protect.rules are turned back into ID_INPUT_* and the seat is dropped.
+pub fn container_properties(
+    properties: impl Iterator<Item = (String, String)>,
+) -> HashMap<String, String> {
+    properties
+        .filter(|(key, _)| key != "ID_SEAT")
+        .map(|(key, value)| {
+            let key = match key.as_str() {
+                "ID_VUINPUT_KEYBOARD" => "ID_INPUT_KEYBOARD".to_string(),
+                "ID_VUINPUT_MOUSE" => "ID_INPUT_MOUSE".to_string(),
+                "ID_VUINPUT_TOUCHPAD" => "ID_INPUT_TOUCHPAD".to_string(),
+                "ID_VUINPUT_TABLET" => "ID_INPUT_TABLET".to_string(),
+                "ID_VUINPUT_TOUCHSCREEN" => "ID_INPUT_TOUCHSCREEN".to_string(),
+                _ => key,
+            };
+            (key, value)
+        })
+        .collect()
+}
+
+/// The key of an event in EVENT_STORE, and whether the event is one of the parent. Events of
+/// an input device and of its event node are kept under the input device, e.g.
+/// /sys/devices/virtual/input/input126 for .../input126/event9. A hidraw node has no parent.
+fn store_key(re: &Regex, devpath: &str) -> Option<(String, bool)> {
+    let caps = re.captures(devpath)?;
+    match (caps.get(1), caps.get(3)) {
+        (Some(input), _) => Some((format!("/sys{}", input.as_str()), caps.get(2).is_none())),
+        (None, Some(hidraw)) => Some((format!("/sys{}", hidraw.as_str()), false)),
+        (None, None) => None,
+    }
+}
+
 pub async fn udev_monitor_loop(cancel_token: Arc<AtomicBool>) {
     // Clone a reference to the shared store which should already be initialized in main.
 
     // Initialize shared store
-    let store = Arc::new(Mutex::new(EventStore::new(Duration::from_secs(60))));
-    EVENT_STORE.set(store.clone()).unwrap();
+    EVENT_STORE
+        .set(EventStore::new(Duration::from_secs(60)))
+        .unwrap();
 
     // Create monitor that listens for kernel events.
     // Use match_subsystem to filter for "input" subsystem as requested.
@@ -169,6 +321,7 @@ pub async fn udev_monitor_loop(cancel_token: Arc<AtomicBool>) {
     let context = libudev::Context::new().unwrap();
     let mut monitor = Monitor::new(&context).unwrap();
     monitor.match_subsystem("input").unwrap();
+    monitor.match_subsystem("hidraw").unwrap();
     let mut monitor_socket = monitor.listen().expect("Failed to create udev monitor");
 
     // Wrap the monitor in a small AsFd adapter
@@ -186,8 +339,10 @@ pub async fn udev_monitor_loop(cancel_token: Arc<AtomicBool>) {
     }
 
     let async_monitor = Async::new(FdWrap(monitor_socket.as_raw_fd())).unwrap();
+    MONITOR_RUNNING.store(true, Ordering::Relaxed);
+    let _running = RunningGuard;
 
-    let re = Regex::new(r"^/devices/virtual/input/input(\d+)/event(\d+)$").unwrap();
+    let re = Regex::new(DEVPATH_PATTERN).unwrap();
 
     loop {
         // check cancel token first
@@ -200,29 +355,21 @@ pub async fn udev_monitor_loop(cancel_token: Arc<AtomicBool>) {
         debug!("Event registered");
 
         if let Some(event) = monitor_socket.receive_event() {
-            let mut properties: HashMap<_, _> = HashMap::new();
-            for property in event.properties() {
-                let key: String = property.name().to_str().unwrap().to_string();
-                let key = match key.as_str() {
-                    "ID_VUINPUT_KEYBOARD" => "ID_INPUT_KEYBOARD".to_string(),
-                    "ID_VUINPUT_MOUSE" => "ID_INPUT_MOUSE".to_string(),
-                    _ => key,
-                };
-
-                let value: String = property.value().to_str().unwrap().to_string();
-                if key != "ID_SEAT" {
-                    properties.insert(key, value);
-                }
-            }
+            let host_properties: HashMap<String, String> = event
+                .properties()
+                .map(|property| {
+                    (
+                        property.name().to_str().unwrap().to_string(),
+                        property.value().to_str().unwrap().to_string(),
+                    )
+                })
+                .collect();
+            rules_conflict_job::on_event(&host_properties);
+            let properties = container_properties(host_properties.into_iter());
 
             let value_of_devpath = properties.get("DEVPATH").unwrap();
 
-            if let Some(caps) = re.captures(value_of_devpath) {
-                // result is something like /devices/virtual/input/input126/event9
-                // println!("devpath {}",value_of_devpath);
-                let input_number: u32 = caps[1].parse().unwrap();
-                // let event_number: u32 = caps[2].parse().unwrap();
-                let syspath = format!("/sys/devices/virtual/input/input{}", input_number);
+            if let Some((syspath, parent)) = store_key(&re, value_of_devpath) {
                 let seqnum: u64 = properties.get("SEQNUM").unwrap().parse().unwrap();
                 let kind = match properties.get("ACTION").unwrap().as_str() {
                     "ADD" => EventKind::Add,
@@ -230,20 +377,28 @@ pub async fn udev_monitor_loop(cancel_token: Arc<AtomicBool>) {
                     _ => EventKind::Add,
                 };
 
-                let mut event_store = EVENT_STORE.get().unwrap().lock().unwrap();
+                // the nodes of HID devices are handed to the container once udev is done
+                let handed_over = (!parent && properties.get("ACTION").unwrap() == "add")
+                    .then(|| (syspath.clone(), properties.clone()));
+
+                let event_store = EVENT_STORE.get().unwrap();
                 let udev_event = UdevEvent {
                     syspath: syspath,
                     seqnum: seqnum,
                     kind: kind,
                     payload: properties,
+                    parent,
                 };
                 event_store.on_event(udev_event);
+                if let Some((syspath, properties)) = handed_over {
+                    handover::on_node_added(&syspath, &properties);
+                }
             }
         }
 
         if Instant::now() > next_cleanup {
             next_cleanup = Instant::now() + Duration::from_secs(60);
-            EVENT_STORE.get().unwrap().lock().unwrap().cleanup();
+            EVENT_STORE.get().unwrap().cleanup();
         }
     } // loop
 
@@ -337,3 +492,130 @@ fn main() {
     println!("Main exiting");
 }
  */
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    fn add(syspath: &str, seqnum: u64) -> UdevEvent {
+        UdevEvent {
+            syspath: syspath.to_string(),
+            seqnum,
+            kind: EventKind::Add,
+            payload: HashMap::from([("ACTION".to_string(), "add".to_string())]),
+            parent: false,
+        }
+    }
+
+    #[test]
+    fn events_are_kept_by_device() {
+        let re = Regex::new(DEVPATH_PATTERN).unwrap();
+        let key = |devpath: &str| store_key(&re, devpath);
+        assert_eq!(
+            key("/devices/virtual/input/input126/event9"),
+            Some(("/sys/devices/virtual/input/input126".to_string(), false))
+        );
+        assert_eq!(
+            key("/devices/virtual/input/input126"),
+            Some(("/sys/devices/virtual/input/input126".to_string(), true))
+        );
+        let hid = "/devices/virtual/misc/uhid/0003:054C:0CE6.0004";
+        assert_eq!(
+            key(&format!("{}/input/input131/event14", hid)),
+            Some((format!("/sys{}/input/input131", hid), false))
+        );
+        assert_eq!(
+            key(&format!("{}/hidraw/hidraw3", hid)),
+            Some((format!("/sys{}/hidraw/hidraw3", hid), false))
+        );
+        assert_eq!(key(&format!("{}/input/input131/js0", hid)), None);
+        assert_eq!(key("/devices/pci0000:00/0000:00:14.0/usb1/1-1/1-1:1.0/0003:046D:C52B.0001/hidraw/hidraw0"), None);
+    }
+
+    #[test]
+    fn parent_events_do_not_announce_the_device() {
+        let store = EventStore::new(Duration::from_secs(60));
+        let syspath = "/sys/devices/virtual/input/input44";
+        store.on_event(UdevEvent {
+            parent: true,
+            ..add(syspath, 10)
+        });
+        // the job gives up after the timeout, with nothing to emit
+        let early = block_on(store.take_when_announced(syspath, Duration::from_millis(10)));
+        assert!(early.unwrap().add_data.is_none());
+        let entry = thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                store.on_event(add(syspath, 11));
+            });
+            block_on(store.take_when_announced(syspath, Duration::from_secs(5)))
+        })
+        .unwrap();
+        assert_eq!(entry.seqnum, 11);
+        assert!(entry.add_data.is_some());
+        assert!(entry.parent_data.is_some());
+    }
+
+    #[test]
+    fn waiters_are_woken_by_the_monitor() {
+        let store = EventStore::new(Duration::from_secs(60));
+        let syspath = "/sys/devices/virtual/input/input42";
+        let started = Instant::now();
+        let entry = thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                store.on_event(add(syspath, 7));
+            });
+            block_on(store.take_when_announced(syspath, Duration::from_secs(5)))
+        });
+        assert_eq!(entry.map(|entry| entry.seqnum), Some(7));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        // already announced, no waiting
+        assert!(block_on(store.take_when_announced(syspath, Duration::ZERO)).is_some());
+
+        let missing = "/sys/devices/virtual/input/input43";
+        assert!(block_on(store.take_when_announced(missing, Duration::from_millis(10))).is_none());
+        store.cleanup();
+        assert!(store
+            .shards
+            .iter()
+            .all(|shard| shard.lock().unwrap().waiters.is_empty()));
+        assert_eq!(store.size(), 1);
+    }
+
+    #[test]
+    fn sharding_does_not_slow_down_a_single_job() {
+        const DEVICES: u64 = 5_000;
+        // the best of a few runs, so that a busy machine doesn't decide
+        let fastest = |shards: usize| {
+            (0..3)
+                .map(|_| {
+                    let store = EventStore::with_shards(Duration::from_secs(60), shards);
+                    let started = Instant::now();
+                    for seqnum in 0..DEVICES {
+                        let syspath = format!("/sys/input{}", seqnum);
+                        store.on_event(add(&syspath, seqnum));
+                        assert!(
+                            block_on(store.take_when_announced(&syspath, Duration::ZERO)).is_some()
+                        );
+                    }
+                    started.elapsed()
+                })
+                .min()
+                .unwrap()
+        };
+        let unsharded = fastest(1);
+        let sharded = fastest(SHARDS);
+        assert!(
+            sharded < unsharded * 2 + Duration::from_millis(20),
+            "{:?} with {} shards, {:?} without",
+            sharded,
+            SHARDS,
+            unsharded
+        );
+    }
+}
@@ -0,0 +1,150 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Follows the active session of the containers for node-acl = ["@active"]. logind changes
+// the ACLs of its devices when another user becomes active on a seat, e.g. after a switch of
+// the user or a logout; the loop does the same for the nodes of vuinputd. It looks at the seat
+// files of logind in the containers every few seconds and, if the users of node-acl resolve to
+// other uids than before, queues a NodeAclJob for the node. The job runs in the queue of the
+// container, so it can't overtake the removal of the device.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use async_io::Timer;
+use log::{info, warn};
+
+use crate::cuse_device::state::vuinput_states;
+use crate::global_config::{get_container_runtime, get_reloadable_config};
+use crate::input_realizer::node_acl::ACTIVE_USER;
+use crate::input_realizer::node_owner;
+use crate::job_engine::job::{Job, JobTarget};
+use crate::job_engine::JOB_DISPATCHER;
+use crate::process_tools::RequestingProcess;
+
+/// How often the active sessions are looked at
+const INTERVAL: Duration = Duration::from_secs(2);
+
+/// Applies owner, mode and ACL to an existing node of a device again
+#[derive(Clone)]
+pub struct NodeAclJob {
+    requesting_process: RequestingProcess,
+    devname: String,
+    major: u64,
+    minor: u64,
+}
+
+impl NodeAclJob {
+    async fn apply(self) {
+        let injector = get_container_runtime().injection_strategy();
+        if let Err(e) = injector
+            .mknod_device_node(
+                &self.requesting_process,
+                &self.devname,
+                self.major,
+                self.minor,
+            )
+            .await
+        {
+            warn!("failed to update the ACL of {}: {}", self.devname, e);
+        }
+    }
+}
+
+impl Job for NodeAclJob {
+    fn desc(&self) -> &str {
+        "update node acl"
+    }
+
+    fn create_task(self: &NodeAclJob) -> Pin<Box<dyn Future<Output = ()>>> {
+        Box::pin(self.clone().apply())
+    }
+
+    fn job_target(&self) -> JobTarget {
+        JobTarget::Container(self.requesting_process.clone())
+    }
+}
+
+#[derive(Default)]
+pub struct NodeAclBackgroundLoop {}
+
+impl NodeAclBackgroundLoop {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+/// Queues a NodeAclJob for each node whose ACL users have changed since `applied`, returns
+/// the users of all nodes
+fn update(applied: &HashMap<(u64, String), Vec<u32>>) -> HashMap<(u64, String), Vec<u32>> {
+    let mut current = HashMap::new();
+    if !get_container_runtime().injection_strategy().creates_nodes() {
+        return current;
+    }
+    for (fh, state) in vuinput_states() {
+        // UI_DEV_DESTROY holds the lock while it waits for a job of the dispatcher, whose
+        // thread this loop runs on; a busy handle is looked at next time
+        let Ok(state) = state.try_lock() else {
+            continue;
+        };
+        let Some(device) = &state.input_device else {
+            continue;
+        };
+        let Some(node) = device.container_devnode.lock().unwrap().clone() else {
+            continue;
+        };
+        let ownership = node_owner::node_ownership(&state.requesting_process);
+        let uids = ownership.acl_uids.unwrap_or_default();
+        let key = (fh, node);
+        // a node seen for the first time might have been created before the last change
+        if applied.get(&key) != Some(&uids) {
+            let devname = key.1.trim_start_matches("/dev/input/").to_string();
+            info!("users of the ACL of {} are now {:?}", key.1, uids);
+            // still under the lock of the state, so a removal is queued after the job
+            JOB_DISPATCHER
+                .get()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .dispatch(Box::new(NodeAclJob {
+                    requesting_process: state.requesting_process.clone(),
+                    devname,
+                    major: device.major,
+                    minor: device.minor,
+                }));
+        }
+        current.insert(key, uids);
+    }
+    current
+}
+
+impl Job for NodeAclBackgroundLoop {
+    fn desc(&self) -> &str {
+        "Follow the active sessions for node-acl"
+    }
+
+    fn create_task(self: &NodeAclBackgroundLoop) -> Pin<Box<dyn Future<Output = ()>>> {
+        Box::pin(async move {
+            let mut applied = HashMap::new();
+            loop {
+                Timer::after(INTERVAL).await;
+                if !get_reloadable_config()
+                    .node_acl
+                    .iter()
+                    .any(|user| user == ACTIVE_USER)
+                {
+                    applied.clear();
+                    continue;
+                }
+                applied = update(&applied);
+            }
+        })
+    }
+
+    fn job_target(&self) -> JobTarget {
+        JobTarget::BackgroundLoop
+    }
+}
@@ -0,0 +1,92 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+use std::{future::Future, path::PathBuf, pin::Pin};
+
+use log::{error, info, warn};
+
+use crate::{
+    config_file::ConfigFile,
+    cuse_device::policy_script,
+    global_config::replace_reloadable_config,
+    job_engine::job::{Job, JobTarget},
+};
+
+/// Re-reads the configuration file and swaps the reloadable settings. The CUSE session
+/// and the devices that have already been created stay untouched.
+#[derive(Clone, Debug)]
+pub struct ReloadConfigJob {
+    path: PathBuf,
+    required: bool,
+    /// Values from the environment and the command line, they take precedence over the file
+    overrides: ConfigFile,
+    /// Effective configuration at startup, to detect changes that need a restart
+    at_startup: ConfigFile,
+}
+
+impl ReloadConfigJob {
+    pub fn new(
+        path: PathBuf,
+        required: bool,
+        overrides: ConfigFile,
+        at_startup: ConfigFile,
+    ) -> Self {
+        Self {
+            path,
+            required,
+            overrides,
+            at_startup,
+        }
+    }
+
+    async fn reload(self) {
+        let file = match ConfigFile::load(&self.path, self.required) {
+            Ok(file) => file,
+            Err(e) => {
+                error!("keeping the current configuration, reload failed: {}", e);
+                return;
+            }
+        };
+        let effective = file.overridden_by(&self.overrides);
+
+        let changes = self.at_startup.changes_requiring_restart(&effective);
+        if !changes.is_empty() {
+            warn!(
+                "ignoring changes of {} until vuinputd is restarted",
+                changes.join(", ")
+            );
+        }
+
+        let reloadable = effective.reloadable_config();
+        // also re-read when unchanged, the script may have been edited
+        if let Err(e) = policy_script::configure(&reloadable) {
+            error!("keeping the current configuration, reload failed: {}", e);
+            return;
+        }
+        let previous = replace_reloadable_config(reloadable.clone());
+        if *previous == reloadable {
+            info!("reloaded {}, nothing changed", self.path.display());
+        } else {
+            info!("reloaded {}: {:?}", self.path.display(), reloadable);
+        }
+    }
+}
+
+impl Job for ReloadConfigJob {
+    fn desc(&self) -> &str {
+        "Reload configuration"
+    }
+
+    fn execute_after_cancellation(&self) -> bool {
+        false
+    }
+
+    fn create_task(self: &ReloadConfigJob) -> Pin<Box<dyn Future<Output = ()>>> {
+        Box::pin(self.clone().reload())
+    }
+
+    fn job_target(&self) -> JobTarget {
+        JobTarget::Host
+    }
+}
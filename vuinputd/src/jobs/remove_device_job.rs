@@ -3,30 +3,42 @@
 // Author: Johannes Leupolz <dev@leupolz.eu>
 
 use std::{
+    collections::HashMap,
     future::Future,
+    path::Path,
     pin::Pin,
     sync::{Arc, Condvar, Mutex},
+    time::Duration,
 };
 
-use log::debug;
+use async_io::Timer;
+use log::{debug, error, warn};
 
 use crate::{
     actions::action::Action,
     global_config::{self, get_container_runtime, Placement},
-    input_realizer::{input_device, runtime_data},
+    input_realizer::{container_udevd, input_device, node_names, runtime_data},
     job_engine::job::{Job, JobTarget},
     jobs::monitor_udev_job::EVENT_STORE,
     process_tools::{self, await_process, Pid, RequestingProcess},
 };
 
+// Awaiting Finished also returns for Failed, as both are terminal states.
 #[derive(Clone, Debug, Copy, PartialOrd, PartialEq)]
 pub enum State {
     Initialized,
     Started,
     Finished,
+    Failed,
 }
 
-#[derive(Clone, Debug)]
+const MAX_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Called once the job has reached Finished or Failed, e.g. to reply to UI_DEV_DESTROY
+pub type Completion = Box<dyn FnOnce() + Send>;
+
+#[derive(Clone)]
 pub struct RemoveDeviceJob {
     requesting_process: RequestingProcess,
     target: JobTarget,
@@ -35,6 +47,8 @@ pub struct RemoveDeviceJob {
     major: u64,
     minor: u64,
     sync_state: Arc<(Mutex<State>, Condvar)>,
+    failure: Arc<Mutex<Option<String>>>,
+    completion: Arc<Mutex<Option<Completion>>>,
 }
 
 impl RemoveDeviceJob {
@@ -53,14 +67,30 @@ impl RemoveDeviceJob {
             major: major,
             minor: minor,
             sync_state: Arc::new((Mutex::new(State::Initialized), Condvar::new())),
+            failure: Arc::new(Mutex::new(None)),
+            completion: Arc::new(Mutex::new(None)),
         }
     }
+
+    /// Lets the job report that it is done instead of being awaited. If the job never runs,
+    /// the completion is dropped without being called.
+    pub fn on_completion(self, completion: Completion) -> Self {
+        *self.completion.lock().unwrap() = Some(completion);
+        self
+    }
+
     fn set_state(&self, new_state: &State) -> () {
         let (lock, cvar) = &*self.sync_state;
         let mut current_state = lock.lock().unwrap();
         *current_state = *new_state;
         // We notify the condvar that the value has changed.
         cvar.notify_all();
+        drop(current_state);
+        if *new_state >= State::Finished {
+            if let Some(completion) = self.completion.lock().unwrap().take() {
+                completion();
+            }
+        }
     }
 
     pub fn get_awaiter_for_state(&self) -> impl FnOnce(&State) -> () {
@@ -82,8 +112,9 @@ impl Job for RemoveDeviceJob {
         "Remove input device"
     }
 
+    // leftovers in the container are worse than a slower shutdown
     fn execute_after_cancellation(&self) -> bool {
-        false
+        true
     }
 
     fn create_task(self: &RemoveDeviceJob) -> Pin<Box<dyn Future<Output = ()>>> {
@@ -93,19 +124,51 @@ impl Job for RemoveDeviceJob {
     fn job_target(&self) -> JobTarget {
         self.target.clone()
     }
+
+    fn failure(&self) -> Option<String> {
+        self.failure.lock().unwrap().clone()
+    }
 }
 
 impl RemoveDeviceJob {
+    fn container_is_gone(&self) -> bool {
+        !Path::new(&self.requesting_process.pid_requestor_root.path()).exists()
+    }
+
+    /// Runs a step of the cleanup and retries it with a growing delay. There is no point
+    /// in retrying, once the container is gone.
+    async fn with_retries<F, Fut>(&self, step: &str, mut run_step: F) -> anyhow::Result<()>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = anyhow::Result<()>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match run_step().await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < MAX_ATTEMPTS && !self.container_is_gone() => {
+                    warn!(
+                        "{} for {} failed (attempt {}/{}): {:#}",
+                        step, self.dev_name, attempt, MAX_ATTEMPTS, e
+                    );
+                    Timer::after(RETRY_DELAY * attempt).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e.context(format!("{} failed", step))),
+            }
+        }
+    }
+
     async fn remove_device(self) {
         self.set_state(&State::Started);
+        let node = node_names::release(
+            &self.requesting_process,
+            &self.dev_name,
+            self.major,
+            self.minor,
+        );
 
-        let netlink_event = match EVENT_STORE
-            .get()
-            .unwrap()
-            .lock()
-            .unwrap()
-            .take(&self.sys_path)
-        {
+        let netlink_event = match EVENT_STORE.get().unwrap().take(&self.sys_path) {
             Some(netlink_event) => netlink_event,
             None => {
                 debug!("do nothing, because the device has never been announced via netlink");
@@ -119,34 +182,79 @@ impl RemoveDeviceJob {
             self.set_state(&State::Finished);
             return;
         }
-        let netlink_data = netlink_event.add_data;
-
-        let mut netlink_data = netlink_data.unwrap().clone();
+        // the parent alone has been seen
+        let Some(mut netlink_data) = netlink_event.add_data else {
+            debug!("do nothing, because the device has never been announced via netlink");
+            self.set_state(&State::Finished);
+            return;
+        };
 
         let _ = netlink_data.insert("ACTION".to_string(), "remove".to_string());
+        let _ = netlink_data.insert("DEVNAME".to_string(), node_names::node_path(&node));
 
         let injector = get_container_runtime().injection_strategy();
 
-        injector
-            .remove_device_node(
-                &self.requesting_process,
-                &self.dev_name,
-                self.major,
-                self.minor,
-            )
+        // best effort: a failing step must not keep the others from cleaning up
+        if let Err(e) = self
+            .with_retries("removing the device node", || {
+                injector.remove_device_node(&self.requesting_process, &node, self.major, self.minor)
+            })
             .await
-            .unwrap();
+        {
+            error!("cleanup of {}: {:#}", self.dev_name, e);
+            *self.failure.lock().unwrap() = Some(format!("{:#}", e));
+        }
 
-        injector
-            .remove_udev_runtime_data(&self.requesting_process, self.major, self.minor)
-            .await
-            .unwrap();
+        // a udevd of the container that took the add removes its database entry itself
+        let handed_over = container_udevd::has_accepted(&self.requesting_process);
+        let emit = |event: HashMap<String, String>| match handed_over {
+            true => injector.emit_kernel_uevent(&self.requesting_process, event),
+            false => injector.emit_netlink_message(&self.requesting_process, event),
+        };
 
-        injector
-            .emit_netlink_message(&self.requesting_process, netlink_data)
+        if !handed_over {
+            if let Err(e) = self
+                .with_retries("removing the udev runtime data", || {
+                    injector.remove_udev_runtime_data(
+                        &self.requesting_process,
+                        self.major,
+                        self.minor,
+                    )
+                })
+                .await
+            {
+                error!("cleanup of {}: {:#}", self.dev_name, e);
+                *self.failure.lock().unwrap() = Some(format!("{:#}", e));
+            }
+        }
+
+        if let Err(e) = self
+            .with_retries("emitting the remove event", || emit(netlink_data.clone()))
             .await
-            .unwrap();
+        {
+            error!("cleanup of {}: {:#}", self.dev_name, e);
+            *self.failure.lock().unwrap() = Some(format!("{:#}", e));
+        }
+
+        // the parent inputN goes after its event node, as with udevd
+        if let Some(mut parent_data) = netlink_event.parent_data {
+            parent_data.insert("ACTION".to_string(), "remove".to_string());
+            if let Err(e) = self
+                .with_retries("emitting the remove event of the parent", || {
+                    emit(parent_data.clone())
+                })
+                .await
+            {
+                error!("cleanup of {}: {:#}", self.dev_name, e);
+                *self.failure.lock().unwrap() = Some(format!("{:#}", e));
+            }
+        }
 
+        if self.failure.lock().unwrap().is_some() {
+            self.set_state(&State::Failed);
+            return;
+        }
+        debug!("cleanup of {} in {} finished", self.dev_name, self.target);
         self.set_state(&State::Finished);
     }
 }
@@ -0,0 +1,251 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Looks for the rule that took a device of vuinputd back to a seat of the host. The udev monitor
+// queues the job when an event of a keyboard or pointer marked by 90-vuinputd-protect.rules
+// (ID_VUINPUT=1) carries another ID_SEAT than seat-name, where none means seat0 as for logind, or
+// is a keyboard or pointer for compositors again (ID_INPUT_KEYBOARD=1, ...). `udevadm test` runs
+// the rules for the device once more, without applying them, and logs each assignment with its
+// rule; see udev_rules for what counts as a conflict. Each conflicting rule is logged once, with what to do about it.
+
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_io::Async;
+use futures::AsyncReadExt;
+use log::{debug, warn};
+
+use crate::global_config::get_reloadable_config;
+use crate::job_engine::job::{Job, JobTarget};
+use crate::job_engine::JOB_DISPATCHER;
+use crate::jobs::verify_seat_job::ISOLATED_CLASSES;
+use crate::process_tools::{await_process, Pid};
+use crate::udev_rules::{self, Assignment};
+
+/// udevadm test takes a while, a burst of new devices is checked once
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// When the last check was queued
+static LAST_CHECK: Mutex<Option<Instant>> = Mutex::new(None);
+/// Rules that have been reported, by file and line
+static REPORTED: Mutex<Option<HashSet<(String, u32)>>> = Mutex::new(None);
+
+#[derive(Clone, Debug)]
+pub struct RulesConflictJob {
+    /// e.g. /sys/devices/virtual/input/input99/event11
+    syspath: String,
+    /// The seat the device got, and the class it got back, e.g. "seat0" or
+    /// "seat_vuinput with ID_INPUT_KEYBOARD=1"
+    placement: String,
+    failure: Arc<Mutex<Option<String>>>,
+}
+
+/// The log of `udevadm test` for the device, which it writes to stderr
+async fn udevadm_test(syspath: &str) -> io::Result<String> {
+    let mut child = Command::new("udevadm")
+        .args(["test", "--action=add", syspath])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stderr = Async::new(child.stderr.take().unwrap())?;
+    let mut output = String::new();
+    stderr.read_to_string(&mut output).await?;
+    match await_process(Pid::Pid(child.id())).await? {
+        0 => Ok(output),
+        status => Err(io::Error::other(format!(
+            "udevadm test exited with status {}",
+            status
+        ))),
+    }
+}
+
+/// The conflicts that have not been reported yet, which are marked as reported
+fn not_reported(conflicts: Vec<Assignment>) -> Vec<Assignment> {
+    let mut reported = REPORTED.lock().unwrap();
+    let reported = reported.get_or_insert_with(HashSet::new);
+    conflicts
+        .into_iter()
+        .filter(|conflict| reported.insert((conflict.file.clone(), conflict.line)))
+        .collect()
+}
+
+impl RulesConflictJob {
+    async fn check(self) {
+        let output = match udevadm_test(&self.syspath).await {
+            Ok(output) => output,
+            Err(e) => {
+                debug!(
+                    "can't look for the rule that put {} on {}: {}",
+                    self.syspath, self.placement, e
+                );
+                return;
+            }
+        };
+        let assignments = udev_rules::parse_udevadm_test(&output);
+        let conflicts = udev_rules::conflicts(&assignments);
+        if conflicts.is_empty() {
+            debug!(
+                "{} is on {}, but udevadm test shows no rule after those of vuinputd that put it there",
+                self.syspath, self.placement
+            );
+            return;
+        }
+        let messages: Vec<String> = not_reported(conflicts)
+            .iter()
+            .map(udev_rules::remediation)
+            .collect();
+        for message in &messages {
+            warn!(
+                "VUI-UDEV-004 — {} is on {}: {}",
+                self.syspath, self.placement, message
+            );
+        }
+        if !messages.is_empty() {
+            *self.failure.lock().unwrap() = Some(messages.join("; "));
+        }
+    }
+}
+
+impl Job for RulesConflictJob {
+    fn desc(&self) -> &str {
+        "look for conflicting udev rules"
+    }
+
+    fn execute_after_cancellation(&self) -> bool {
+        false
+    }
+
+    fn create_task(self: &RulesConflictJob) -> Pin<Box<dyn Future<Output = ()>>> {
+        Box::pin(self.clone().check())
+    }
+
+    fn job_target(&self) -> JobTarget {
+        JobTarget::Host
+    }
+
+    fn failure(&self) -> Option<String> {
+        self.failure.lock().unwrap().clone()
+    }
+}
+
+/// Where a keyboard or pointer of vuinputd ended up after the rules of the host, if that is not
+/// on the seat of the rules of vuinputd or it is a keyboard or pointer for compositors again.
+/// Other input devices of vuinputd, like gamepads, have no seat; hidraw nodes always have one.
+fn unexpected_placement(
+    properties: &HashMap<String, String>,
+    vuinput_seat: &str,
+) -> Option<String> {
+    let is_set = |key: &str| properties.get(key).map(String::as_str) == Some("1");
+    if !is_set("ID_VUINPUT")
+        || properties.get("ACTION").map(String::as_str) == Some("remove")
+        || !(ISOLATED_CLASSES.iter().any(|class| is_set(class))
+            || properties.get("SUBSYSTEM").map(String::as_str) == Some("hidraw"))
+    {
+        return None;
+    }
+    let seat = properties
+        .get("ID_SEAT")
+        .map(String::as_str)
+        .filter(|seat| !seat.is_empty())
+        .unwrap_or("seat0");
+    match ISOLATED_CLASSES
+        .iter()
+        .find(|class| class.starts_with("ID_INPUT_") && is_set(class))
+    {
+        Some(class) => Some(format!("{} with {}=1", seat, class)),
+        None if seat != vuinput_seat => Some(seat.to_string()),
+        None => None,
+    }
+}
+
+/// Called by the udev monitor with the properties of each event as the host has them. Queues a
+/// check if a device of vuinputd is on the wrong seat, at most once per CHECK_INTERVAL.
+pub fn on_event(properties: &HashMap<String, String>) {
+    let Some(placement) = unexpected_placement(properties, &get_reloadable_config().seat_name)
+    else {
+        return;
+    };
+    let Some(devpath) = properties.get("DEVPATH") else {
+        return;
+    };
+    {
+        let mut last_check = LAST_CHECK.lock().unwrap();
+        if last_check.is_some_and(|last| last.elapsed() < CHECK_INTERVAL) {
+            return;
+        }
+        *last_check = Some(Instant::now());
+    }
+    JOB_DISPATCHER
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .dispatch(Box::new(RulesConflictJob {
+            syspath: format!("/sys{}", devpath),
+            placement,
+            failure: Arc::new(Mutex::new(None)),
+        }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notices_devices_on_another_seat() {
+        let properties = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        };
+        let placement =
+            |pairs: &[(&str, &str)]| unexpected_placement(&properties(pairs), "seat_vuinput");
+        let keyboard = [
+            ("ACTION", "add"),
+            ("ID_VUINPUT", "1"),
+            ("ID_VUINPUT_KEYBOARD", "1"),
+        ];
+        let on = |seat| [&keyboard[..], &[("ID_SEAT", seat)]].concat();
+        assert_eq!(placement(&on("seat0")), Some("seat0".to_string()));
+        assert_eq!(placement(&on("seat_vuinput")), None);
+        // logind puts devices without a seat on seat0
+        assert_eq!(placement(&on("")), Some("seat0".to_string()));
+        assert_eq!(placement(&keyboard), Some("seat0".to_string()));
+        // a keyboard for compositors again
+        let reclassified = [&on("seat_vuinput")[..], &[("ID_INPUT_KEYBOARD", "1")]].concat();
+        assert_eq!(
+            placement(&reclassified),
+            Some("seat_vuinput with ID_INPUT_KEYBOARD=1".to_string())
+        );
+        let hidraw = [("SUBSYSTEM", "hidraw"), ("ID_VUINPUT", "1")];
+        assert_eq!(placement(&hidraw), Some("seat0".to_string()));
+        // devices of the host, and gamepads without a seat
+        assert_eq!(placement(&[("ID_SEAT", "seat0")]), None);
+        assert_eq!(
+            placement(&[("ID_VUINPUT", "1"), ("ID_INPUT_JOYSTICK", "1")]),
+            None
+        );
+        let removed = [&on("seat0")[..], &[("ACTION", "remove")]].concat();
+        assert_eq!(placement(&removed), None);
+    }
+
+    #[test]
+    fn reports_a_rule_once() {
+        let conflict = Assignment {
+            file: "/etc/udev/rules.d/99-test-once.rules".to_string(),
+            line: 3,
+            key: "ID_SEAT".to_string(),
+            value: "seat0".to_string(),
+        };
+        assert_eq!(not_reported(vec![conflict.clone()]), vec![conflict.clone()]);
+        assert!(not_reported(vec![conflict]).is_empty());
+    }
+}
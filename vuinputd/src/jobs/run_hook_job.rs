@@ -0,0 +1,172 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Runs the program configured for a lifecycle event ([hooks] in the configuration file), e.g.
+// to open a firewall port when a gamepad of a streaming client appears. The program gets the
+// event on stdin, as the same JSON line vuinputctl events prints:
+//
+//   {"type":"event","time":1760601600,"event":{"kind":"device-created","fh":3,...}}
+//
+// Hooks run one after another on the host queue, so a hook sees the events in the order they
+// happened. A hook that does not exit within hooks.timeout is killed. A failing hook does not
+// affect the device, it is only reported like any other failed job.
+
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_io::Timer;
+use futures::future::{select, Either};
+use log::debug;
+
+use crate::job_engine::job::{Job, JobTarget};
+use crate::process_tools::{await_process, Pid};
+
+#[derive(Clone, Debug)]
+pub struct RunHookJob {
+    desc: String,
+    program: PathBuf,
+    /// The event as a JSON line
+    input: String,
+    timeout: Duration,
+    failure: Arc<Mutex<Option<String>>>,
+}
+
+impl RunHookJob {
+    pub fn new(name: &str, program: PathBuf, input: String, timeout: Duration) -> Self {
+        Self {
+            desc: format!("Run hook {} ({})", name, program.display()),
+            program,
+            input,
+            timeout,
+            failure: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    async fn run(self) {
+        if let Err(e) = self.run_program().await {
+            *self.failure.lock().unwrap() = Some(e.to_string());
+        }
+    }
+
+    async fn run_program(&self) -> io::Result<()> {
+        let mut child = Command::new(&self.program)
+            .env_clear()
+            .env(
+                "PATH",
+                "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+            )
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()?;
+        // a single line fits into the pipe, so this does not block. A hook that does not
+        // care about the event may exit without reading it.
+        if let Some(mut stdin) = child.stdin.take() {
+            match stdin.write_all(self.input.as_bytes()) {
+                Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e),
+                _ => {}
+            }
+        }
+        let exited = Box::pin(await_process(Pid::Pid(child.id())));
+        match select(exited, Timer::after(self.timeout)).await {
+            Either::Left((status, _)) => match status? {
+                0 => {
+                    debug!("{} finished", self.desc);
+                    Ok(())
+                }
+                status => Err(io::Error::other(format!("exited with status {}", status))),
+            },
+            Either::Right(_) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("killed after {:?}", self.timeout),
+                ))
+            }
+        }
+    }
+}
+
+impl Job for RunHookJob {
+    fn desc(&self) -> &str {
+        &self.desc
+    }
+
+    fn execute_after_cancellation(&self) -> bool {
+        false
+    }
+
+    fn create_task(self: &RunHookJob) -> Pin<Box<dyn Future<Output = ()>>> {
+        Box::pin(self.clone().run())
+    }
+
+    fn job_target(&self) -> JobTarget {
+        JobTarget::Host
+    }
+
+    fn failure(&self) -> Option<String> {
+        self.failure.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn run(script: &str, timeout: Duration) -> Option<String> {
+        let job = RunHookJob::new(
+            "on-device-created",
+            PathBuf::from("/bin/sh"),
+            String::new(),
+            timeout,
+        );
+        // the script replaces the event on stdin, which sh would read as its commands
+        let job = RunHookJob {
+            input: format!("{}\n", script),
+            ..job
+        };
+        block_on(job.create_task());
+        job.failure()
+    }
+
+    #[test]
+    fn reports_failing_hooks() {
+        assert_eq!(run("exit 0", Duration::from_secs(5)), None);
+        assert_eq!(
+            run("exit 3", Duration::from_secs(5)).as_deref(),
+            Some("exited with status 3")
+        );
+        assert!(run("sleep 10", Duration::from_millis(100))
+            .unwrap()
+            .starts_with("killed after"));
+    }
+
+    #[test]
+    fn hook_gets_the_event_on_stdin() {
+        let dir = std::env::temp_dir().join(format!("vuinputd-hook-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let hook = dir.join("hook");
+        let output = dir.join("event.json");
+        std::fs::write(&hook, format!("#!/bin/sh\ncat > {}\n", output.display())).unwrap();
+        std::fs::set_permissions(&hook, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        let event = "{\"type\":\"event\",\"time\":0}\n";
+        let job = RunHookJob::new(
+            "on-device-removed",
+            hook,
+            event.to_string(),
+            Duration::from_secs(5),
+        );
+        block_on(job.create_task());
+        assert_eq!(job.failure(), None);
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), event);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,292 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Checks that a new device did not end up on a seat of the host (verify-seat). The udev rules
+// of vuinputd put keyboards, mice, touchpads, tablets and touchscreens on seat_vuinput, which
+// has no master device and never becomes a seat of logind (seat-name), so no compositor of the
+// host picks them up. If the rules are missing, or were not reloaded yet when the device
+// appeared, logind puts the device on seat0 and the desktop of the host gets the input of the
+// container.
+//
+// After the device has been created, logind is asked on which seat it has the device (with
+// loginctl, like session_lock). A keyboard or pointer on another seat is handed to udev again
+// (udevadm trigger --action=change), so that the current rules apply and logind moves it, and
+// checked again. What the check found is shown by vuinputctl list --json. The checks run on
+// the background loop, so the creation of other devices does not wait for udevadm.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_io::{Async, Timer};
+use futures::AsyncReadExt;
+use log::{debug, info, warn};
+
+use crate::control::protocol::SeatStatus;
+use crate::cuse_device::state::VuInputDevice;
+use crate::global_config::{get_reloadable_config, get_simulation_dir};
+use crate::job_engine::job::{Job, JobTarget};
+use crate::job_engine::JOB_DISPATCHER;
+use crate::process_tools::{await_process, Pid};
+
+/// Checks, the ones after the first follow a retrigger
+const ATTEMPTS: u32 = 3;
+/// Time udev and logind get for a device before it is checked
+const SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+/// Properties of input_id that make a device a keyboard or pointer for compositors, also as
+/// renamed by 90-vuinputd-protect.rules
+pub const ISOLATED_CLASSES: [&str; 10] = [
+    "ID_INPUT_KEYBOARD",
+    "ID_INPUT_MOUSE",
+    "ID_INPUT_TOUCHPAD",
+    "ID_INPUT_TABLET",
+    "ID_INPUT_TOUCHSCREEN",
+    "ID_VUINPUT_KEYBOARD",
+    "ID_VUINPUT_MOUSE",
+    "ID_VUINPUT_TOUCHPAD",
+    "ID_VUINPUT_TABLET",
+    "ID_VUINPUT_TOUCHSCREEN",
+];
+
+#[derive(Clone, Debug)]
+pub struct VerifySeatJob {
+    /// e.g. /sys/devices/virtual/input/input99, the device logind assigns to a seat
+    syspath: String,
+    status: Arc<Mutex<Option<SeatStatus>>>,
+    failure: Arc<Mutex<Option<String>>>,
+}
+
+impl VerifySeatJob {
+    pub fn new(device: &VuInputDevice) -> Self {
+        Self {
+            syspath: device.syspath.clone(),
+            status: device.seat.clone(),
+            failure: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    async fn verify(self) {
+        for attempt in 1..=ATTEMPTS {
+            Timer::after(SETTLE_DELAY).await;
+            let status = check(&self.syspath)
+                .await
+                .unwrap_or_else(|e| SeatStatus::Unknown {
+                    error: e.to_string(),
+                });
+            *self.status.lock().unwrap() = Some(status.clone());
+            match status {
+                SeatStatus::Exposed { seat } if attempt < ATTEMPTS => {
+                    info!(
+                        "{} is on {}, asking udev to apply the rules again",
+                        self.syspath, seat
+                    );
+                    let syspath = self.syspath.as_str();
+                    if let Err(e) = run("udevadm", &["trigger", "--action=change", syspath]).await {
+                        warn!("failed to retrigger {}: {}", self.syspath, e);
+                    }
+                }
+                SeatStatus::Exposed { seat } => {
+                    let message = format!(
+                        "{} stays on {}, where the desktop of the host gets its input. Are the \
+                        udev rules of vuinputd installed?",
+                        self.syspath, seat
+                    );
+                    warn!("{}", message);
+                    *self.failure.lock().unwrap() = Some(message);
+                    return;
+                }
+                SeatStatus::Unknown { error } => {
+                    debug!("seat of {} is unknown: {}", self.syspath, error);
+                    *self.failure.lock().unwrap() = Some(error);
+                    return;
+                }
+                status => {
+                    debug!("seat of {}: {:?}", self.syspath, status);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Job for VerifySeatJob {
+    fn desc(&self) -> &str {
+        "verify seat"
+    }
+
+    fn execute_after_cancellation(&self) -> bool {
+        false
+    }
+
+    fn create_task(self: &VerifySeatJob) -> Pin<Box<dyn Future<Output = ()>>> {
+        Box::pin(self.clone().verify())
+    }
+
+    fn job_target(&self) -> JobTarget {
+        JobTarget::BackgroundLoop
+    }
+
+    fn failure(&self) -> Option<String> {
+        self.failure.lock().unwrap().clone()
+    }
+}
+
+/// Queues the check of a new device, if verify-seat is on. Simulated devices have no seat.
+pub fn verify(device: &VuInputDevice) {
+    if !get_reloadable_config().verify_seat || get_simulation_dir().is_some() {
+        return;
+    }
+    *device.seat.lock().unwrap() = None;
+    JOB_DISPATCHER
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .dispatch(Box::new(VerifySeatJob::new(device)));
+}
+
+/// Runs the program and returns its stdout, without blocking the other jobs
+async fn run(program: &str, args: &[&str]) -> io::Result<String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let mut stdout = Async::new(child.stdout.take().unwrap())?;
+    let mut output = String::new();
+    stdout.read_to_string(&mut output).await?;
+    match await_process(Pid::Pid(child.id())).await? {
+        0 => Ok(output),
+        status => Err(io::Error::other(format!(
+            "{} {} exited with status {}",
+            program, args[0], status
+        ))),
+    }
+}
+
+async fn check(syspath: &str) -> io::Result<SeatStatus> {
+    let path = format!("--path={}", syspath);
+    let properties = run("udevadm", &["info", "--query=property", &path]).await?;
+    let seats = run("loginctl", &["list-seats", "--no-legend"]).await?;
+    let mut logind_seat = None;
+    for seat in seats
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+    {
+        let devices = run("loginctl", &["seat-status", "--full", "--no-pager", seat]).await?;
+        if seat_devices(&devices).any(|device| device == syspath) {
+            logind_seat = Some(seat.to_string());
+            break;
+        }
+    }
+    Ok(evaluate(
+        &parse_properties(&properties),
+        logind_seat,
+        &get_reloadable_config().seat_name,
+    ))
+}
+
+/// `udevadm info --query=property`: KEY=value per line
+fn parse_properties(output: &str) -> HashMap<&str, &str> {
+    output
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .collect()
+}
+
+/// The sysfs paths in the device tree of `loginctl seat-status`
+fn seat_devices(output: &str) -> impl Iterator<Item = &str> {
+    output
+        .lines()
+        .filter_map(|line| line.find("/sys/").map(|start| line[start..].trim_end()))
+}
+
+/// A device logind does not list is on the seat of its ID_SEAT, seat0 without one: that is
+/// where libinput looks for it. `vuinput_seat` is the seat of the udev rules of vuinputd.
+fn evaluate(
+    properties: &HashMap<&str, &str>,
+    logind_seat: Option<String>,
+    vuinput_seat: &str,
+) -> SeatStatus {
+    let seat = logind_seat.unwrap_or_else(|| {
+        properties
+            .get("ID_SEAT")
+            .copied()
+            .unwrap_or("seat0")
+            .to_string()
+    });
+    if seat == vuinput_seat {
+        SeatStatus::Isolated { seat }
+    } else if ISOLATED_CLASSES
+        .iter()
+        .any(|class| properties.get(class) == Some(&"1"))
+    {
+        SeatStatus::Exposed { seat }
+    } else {
+        SeatStatus::Shared { seat }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEAT_STATUS: &str = "seat0
+         Devices:
+                  ├─/sys/devices/pci0000:00/0000:00:02.0/drm/card1
+                  │ [MASTER] drm:card1
+                  ├─/sys/devices/virtual/input/input99
+                  │ input:input99 \"vuinputd:4690:Keyboard\"
+                  └─/sys/devices/virtual/input/input990
+                    input:input990 \"Power Button\"
+";
+
+    #[test]
+    fn finds_the_seat_of_a_device() {
+        let devices: Vec<&str> = seat_devices(SEAT_STATUS).collect();
+        assert_eq!(
+            devices,
+            [
+                "/sys/devices/pci0000:00/0000:00:02.0/drm/card1",
+                "/sys/devices/virtual/input/input99",
+                "/sys/devices/virtual/input/input990"
+            ]
+        );
+
+        let keyboard = parse_properties("ID_INPUT=1\nID_INPUT_KEYBOARD=1\n");
+        assert_eq!(
+            evaluate(&keyboard, Some("seat0".to_string()), "seat_vuinput"),
+            SeatStatus::Exposed {
+                seat: "seat0".to_string()
+            }
+        );
+        // without the rules, the keyboard is on seat0 even before logind lists it
+        assert_eq!(
+            evaluate(&keyboard, None, "seat_vuinput"),
+            SeatStatus::Exposed {
+                seat: "seat0".to_string()
+            }
+        );
+        let protected = parse_properties("ID_VUINPUT_KEYBOARD=1\nID_SEAT=seat_vuinput\n");
+        assert_eq!(
+            evaluate(&protected, None, "seat_vuinput"),
+            SeatStatus::Isolated {
+                seat: "seat_vuinput".to_string()
+            }
+        );
+        let gamepad = parse_properties("ID_INPUT=1\nID_INPUT_JOYSTICK=1\n");
+        assert_eq!(
+            evaluate(&gamepad, Some("seat0".to_string()), "seat_vuinput"),
+            SeatStatus::Shared {
+                seat: "seat0".to_string()
+            }
+        );
+    }
+}
@@ -0,0 +1,49 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Pings the systemd watchdog (WatchdogSec=) from the thread of the dispatcher. A job that
+// blocks this thread, e.g. on a container that does not answer, stops the pings as well,
+// so that systemd restarts vuinputd.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use async_io::Timer;
+
+use crate::job_engine::job::{Job, JobTarget};
+use crate::sd_daemon;
+
+pub struct WatchdogBackgroundLoop {
+    interval: Duration,
+}
+
+impl WatchdogBackgroundLoop {
+    /// `timeout` is the WATCHDOG_USEC of systemd, pings are sent twice as often
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            interval: timeout / 2,
+        }
+    }
+}
+
+impl Job for WatchdogBackgroundLoop {
+    fn desc(&self) -> &str {
+        "Ping the systemd watchdog"
+    }
+
+    fn create_task(self: &WatchdogBackgroundLoop) -> Pin<Box<dyn Future<Output = ()>>> {
+        let interval = self.interval;
+        Box::pin(async move {
+            loop {
+                sd_daemon::watchdog();
+                Timer::after(interval).await;
+            }
+        })
+    }
+
+    fn job_target(&self) -> JobTarget {
+        JobTarget::BackgroundLoop
+    }
+}
@@ -13,7 +13,6 @@
 // renaming
 // use in container
 // cancellation token
-// distinguish between cleanup jobs that must not be cancelled and other jobs (especially background jobs)
 // naming: dev_path vs dev_node. I guess I mean the same.
 // Send warning, if udev monitor does not exist
 // Filter out Ctrl+Alt+Fx. "sysrq" keys or the low-level VT switching combos.
@@ -21,24 +20,44 @@
 use ::cuse_lowlevel::*;
 use base64::prelude::BASE64_STANDARD;
 use base64::Engine as _;
-use log::info;
-use std::ffi::CString;
-use std::os::raw::c_char;
+use log::{debug, error, info, warn, LevelFilter};
+use std::ffi::OsStr;
+use std::os::unix::net::UnixListener;
 use std::path::PathBuf;
 use std::sync::atomic::AtomicU64;
 use std::sync::Mutex;
+use std::time::Duration;
 
 pub mod cuse_device;
 
+use crate::config_file::{
+    value_name, ConfigFile, ConfigSource, DeviceNames, Hooks, Limits, StrictGamepad,
+    DEFAULT_CONFIG_FILE,
+};
 use crate::container_runtime::ContainerRuntime;
+use crate::cuse_device::cuse_module::{ensure_cuse_available, ModprobeCuse};
+use crate::cuse_device::device_id::UsbId;
 use crate::cuse_device::evdev_write_watcher::{
     initialize_evdev_write_watcher, EVDEV_WRITE_WATCHER,
 };
+use crate::cuse_device::persistence;
+use crate::cuse_device::policy_script;
+use crate::cuse_device::revoke;
+use crate::cuse_device::session_manager::{self, DeviceNode};
 use crate::cuse_device::state::{initialize_dedup_last_error, initialize_vuinput_state};
+use crate::cuse_device::sysfs_input;
+use crate::cuse_device::uinput_compat::{self, CompatMode};
+use crate::cuse_device::vuhid;
 use crate::cuse_device::vuinput_make_cuse_ops;
 use crate::cuse_device::vuinput_open::VUINPUT_COUNTER;
-use crate::global_config::{DeviceOwner, DevicePolicy, Placement, Scope};
+use crate::global_config::{
+    Approval, CuseNodePermissions, DeviceNamePolicy, DeviceOwner, DevicePolicy, GlobalConfig,
+    IdPolicy, NodeNaming, Placement, ProtocolDump, Scope, SeatMode,
+};
 use crate::jobs::monitor_udev_job::MonitorBackgroundLoop;
+use crate::jobs::node_acl_job::NodeAclBackgroundLoop;
+use crate::jobs::reload_config_job::ReloadConfigJob;
+use crate::jobs::watchdog_job::WatchdogBackgroundLoop;
 
 pub mod process_tools;
 
@@ -47,14 +66,33 @@ use crate::job_engine::{job::*, JOB_DISPATCHER};
 use crate::process_tools::*;
 
 pub mod actions;
+pub mod audit;
 pub mod input_realizer;
 
+pub mod config_file;
 pub mod container_runtime;
+pub mod control;
+pub mod desktop_notification;
+pub mod doctor;
+pub mod evemu;
+pub mod fault_injection;
 pub mod global_config;
+pub mod health;
+pub mod host_root;
+pub mod input_codes;
 pub mod jobs;
+pub mod metrics;
+pub mod replay;
+pub mod sd_daemon;
+pub mod session_lock;
+pub mod signal_handling;
+pub mod simulation;
+pub mod systemd_units;
+pub mod udev_rules;
 pub mod vt_tools;
 
-use clap::Parser;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
 
 const DEV_PREFIX: &str = "/dev/";
 const DEVNAME_MAX_LEN: usize = 128 - DEV_PREFIX.len();
@@ -62,6 +100,13 @@ const DEVNAME_MAX_LEN: usize = 128 - DEV_PREFIX.len();
 #[derive(Debug, Parser)]
 #[command(author, version, about)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Configuration file (TOML), re-read on SIGHUP. Command line options take precedence.
+    #[arg(long, value_name = "FILE", help = format!("Configuration file (TOML), re-read on SIGHUP. Command line options take precedence. [default: {}]", DEFAULT_CONFIG_FILE))]
+    config: Option<PathBuf>,
+
     /// Major device number
     #[arg(long)]
     major: Option<u32>,
@@ -74,6 +119,11 @@ struct Args {
     #[arg(long)]
     devname: Option<String>,
 
+    /// Serve a CUSE node of its own with its own policy, e.g. name=vuinput-gamepad,policy=strict-gamepad.
+    /// kind=uhid serves /dev/uhid instead of /dev/uinput. May be repeated, replaces --devname.
+    #[arg(long = "device", value_name = "SPEC")]
+    devices: Vec<DeviceNode>,
+
     /// Action to execute (JSON encoded). Note that this excludes all other options.
     #[arg(long, value_name = "JSON")]
     pub action: Option<String>,
@@ -130,6 +180,227 @@ struct Args {
     /// Bind to a single named container. If omitted, the daemon watches all running containers (Multi mode).
     #[arg(long, value_name = "CONTAINER_NAME")]
     pub target_container: Option<String>,
+
+    /// Additional option passed to libfuse as "-o OPTION" (e.g. allow_other). May be repeated.
+    #[arg(long = "fuse-option", value_name = "OPTION")]
+    pub fuse_options: Vec<String>,
+
+    /// Maximum number of threads serving the requests of a CUSE node, 1 for a single thread
+    #[arg(long = "cuse-threads", value_name = "N", default_value_t = session_manager::DEFAULT_CUSE_THREADS, value_parser = clap::value_parser!(u32).range(1..))]
+    pub cuse_threads: u32,
+
+    /// Mode of the CUSE node /dev/{devname} in octal (e.g. 0660). Applied after registration.
+    #[arg(long = "cuse-mode", value_name = "MODE")]
+    pub cuse_mode: Option<String>,
+
+    /// Owner (user name or uid) of the CUSE node /dev/{devname}
+    #[arg(long = "cuse-owner", value_name = "USER")]
+    pub cuse_owner: Option<String>,
+
+    /// Group (group name or gid) of the CUSE node /dev/{devname}
+    #[arg(long = "cuse-group", value_name = "GROUP")]
+    pub cuse_group: Option<String>,
+
+    /// Allow all users to open the CUSE node (mode 0666, unless --cuse-mode is given)
+    #[arg(long = "allow-other")]
+    pub allow_other: bool,
+
+    /// Run without CUSE and root: devices are faked in memory, requests are read from stdin
+    /// and the artifacts of the container are written below DIR
+    #[arg(long, value_name = "DIR")]
+    pub simulate: Option<PathBuf>,
+
+    /// Hexdump the buffers exchanged with clients at trace level (RUST_LOG=trace, debug builds only)
+    #[arg(long = "protocol-dump", value_enum, default_value_t)]
+    pub protocol_dump: ProtocolDump,
+
+    /// Never log code and value of keyboard keys, except for events blocked by the device policy
+    #[arg(long = "keystroke-privacy", value_name = "BOOL", default_value_t = true, action = clap::ArgAction::Set)]
+    pub keystroke_privacy: bool,
+
+    /// What to do with device names that contain control characters, invalid UTF-8 or are too long
+    #[arg(long = "device-name-policy", value_enum, default_value_t)]
+    pub device_name_policy: DeviceNamePolicy,
+
+    /// Whether vendor and product id of the clients reach the host or are replaced with 1209:5020
+    #[arg(long = "id-policy", value_enum, default_value_t)]
+    pub id_policy: IdPolicy,
+
+    /// Vendor and product id (e.g. 045e:028e) that --id-policy allowlist keeps. May be repeated.
+    #[arg(long = "passthrough-id", value_name = "VID:PID")]
+    pub passthrough_ids: Vec<UsbId>,
+
+    /// Accept clients that set up their device by writing a struct uinput_user_dev instead of
+    /// calling UI_DEV_SETUP; with false, such a write fails with EINVAL
+    #[arg(long = "legacy-setup", value_name = "BOOL", default_value_t = true, action = clap::ArgAction::Set)]
+    pub legacy_setup: bool,
+
+    /// Seconds the cleanup of the containers may take when vuinputd stops [default: 10]
+    #[arg(long = "shutdown-timeout", value_name = "SECONDS")]
+    pub shutdown_timeout: Option<u64>,
+
+    /// Send a desktop notification to USER (name or uid) when the device policy refuses a request
+    #[arg(long = "notify-user", value_name = "USER")]
+    pub notify_user: Option<String>,
+
+    /// Drop keyboard keys of virtual devices while the session on SEAT (e.g. seat0) is locked
+    #[arg(long = "block-keys-when-locked", value_name = "SEAT")]
+    pub block_keys_when_locked: Option<String>,
+
+    /// Check with logind that new keyboards and pointers did not end up on a seat of the host,
+    /// and have udev apply the rules again if they did
+    #[arg(long = "verify-seat")]
+    pub verify_seat: bool,
+
+    /// Seat the devices are announced on inside the containers
+    #[arg(long = "seat-mode", value_enum, default_value_t)]
+    pub seat_mode: SeatMode,
+
+    /// Seat the udev rules of vuinputd put the devices on, and the seat (or prefix) inside the
+    /// containers [default: seat_vuinput]
+    #[arg(long = "seat-name", value_name = "SEAT")]
+    pub seat_name: Option<String>,
+
+    /// Names of the device nodes inside the containers
+    #[arg(long = "node-naming", value_enum, default_value_t)]
+    pub node_naming: NodeNaming,
+
+    /// Give the device nodes in containers to the input group of the container, with mode 0660
+    #[arg(long = "input-group")]
+    pub input_group: bool,
+
+    /// Gid for --input-group in containers whose /etc/group has no input group. May be repeated,
+    /// the first one the container maps is used.
+    #[arg(long = "input-gid", value_name = "GID")]
+    pub input_gids: Vec<u32>,
+
+    /// Owner of the device nodes in containers, a user name or uid of the container
+    /// [default: root of the container]
+    #[arg(long = "node-owner", value_name = "USER")]
+    pub node_owner: Option<String>,
+
+    /// Group of the device nodes in containers, a group name or gid of the container, e.g.
+    /// input. Takes precedence over --input-group.
+    #[arg(long = "node-group", value_name = "GROUP")]
+    pub node_group: Option<String>,
+
+    /// Mode of the device nodes in containers, e.g. 0660 [default: 0660 with a group, 0666
+    /// otherwise]
+    #[arg(long = "node-mode", value_name = "MODE", value_parser = config_file::parse_mode)]
+    pub node_mode: Option<u32>,
+
+    /// User of the containers that gets rw on the device nodes through an ACL, a user name,
+    /// uid or @active for the user of the active session on the seat. May be repeated.
+    #[arg(long = "node-acl", value_name = "USER")]
+    pub node_acl: Vec<String>,
+
+    /// Whether containers need the approval of the host (vuinputctl approve) for their devices
+    #[arg(long = "approval", value_enum, default_value_t)]
+    pub approval: Approval,
+
+    /// How long an approval or denial is remembered for a container [default: 3600]
+    #[arg(long = "approval-ttl", value_name = "SECONDS")]
+    pub approval_ttl: Option<u64>,
+
+    /// Rhai script that decides for --device-policy script (requires the scripted-policy feature)
+    #[arg(long = "policy-script", value_name = "FILE")]
+    pub policy_script: Option<String>,
+
+    /// Keep the devices of containers across restarts: they are saved to
+    /// /run/vuinputd/{devname}/state.json and created again when vuinputd starts
+    #[arg(long = "persist-devices")]
+    pub persist_devices: bool,
+
+    /// Offer org.vuinputd.Manager on the system bus (requires the dbus feature)
+    #[arg(long = "dbus")]
+    pub dbus: bool,
+
+    /// Let sandboxed apps create gamepads through org.vuinputd.Portal on the system bus
+    /// (requires the dbus feature)
+    #[arg(long = "portal")]
+    pub portal: bool,
+
+    /// Serve Prometheus metrics over HTTP on ADDRESS, e.g. 127.0.0.1:9812
+    #[arg(long = "metrics-listen", value_name = "ADDRESS")]
+    pub metrics_listen: Option<String>,
+
+    /// Write Prometheus metrics into FILE for the textfile collector of node_exporter
+    #[arg(long = "metrics-textfile", value_name = "FILE")]
+    pub metrics_textfile: Option<PathBuf>,
+
+    /// Append created and destroyed devices, refused requests and revoked handles to FILE as
+    /// JSON lines
+    #[arg(long = "audit-log", value_name = "FILE")]
+    pub audit_log: Option<PathBuf>,
+
+    /// Send the audit records to the systemd journal as well
+    #[arg(long = "audit-journal")]
+    pub audit_journal: bool,
+
+    /// Load the cuse kernel module if /dev/cuse is missing
+    #[arg(long = "modprobe-cuse", value_enum, default_value_t)]
+    pub modprobe_cuse: ModprobeCuse,
+
+    /// Directory the /proc, /sys and /run/udev of the host are mounted below, if vuinputd runs in a container
+    #[arg(long = "host-root", value_name = "DIR")]
+    pub host_root: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Render a systemd unit and a tmpfiles.d snippet that start vuinputd with the given options
+    InstallSystemd {
+        /// Directory the unit file is written to
+        #[arg(long, default_value = "/etc/systemd/system")]
+        unit_dir: PathBuf,
+
+        /// Directory the tmpfiles.d snippet is written to
+        #[arg(long, default_value = "/etc/tmpfiles.d")]
+        tmpfiles_dir: PathBuf,
+
+        /// Path of the vuinputd binary in ExecStart (defaults to the running binary)
+        #[arg(long)]
+        binary: Option<PathBuf>,
+
+        /// Print the rendered files instead of writing them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Check whether the host is prepared to run vuinputd
+    Doctor,
+    /// Create a device like the one of an evemu recording and replay its events
+    Replay {
+        /// Description (evemu-describe) and events (evemu-record) of the recording, may be the
+        /// same file
+        #[arg(long, num_args = 2, value_names = ["DESC", "EVENTS"], required = true)]
+        evemu: Vec<PathBuf>,
+
+        /// Root pid of the container (see vuinputctl containers) whose /dev/uinput is used.
+        /// Without it, the device is created on the host.
+        #[arg(long, value_name = "PID")]
+        container: Option<String>,
+    },
+    /// Ask the running instance whether it is ready (exit code 0) or not (1)
+    Health {
+        /// Only check that it is alive, not that clients can create devices yet
+        #[arg(long)]
+        live: bool,
+
+        /// Ask again until it is healthy or SECONDS have passed
+        #[arg(long, value_name = "SECONDS")]
+        wait: Option<u64>,
+    },
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommand {
+    /// Print the effective configuration (defaults < file < environment < command line)
+    Print,
 }
 
 impl Args {
@@ -152,6 +423,279 @@ impl Args {
         self.container_runtime.clone()
     }
 
+    pub fn resolve_cuse_node_permissions(&self) -> Result<CuseNodePermissions, String> {
+        let mode = match &self.cuse_mode {
+            Some(mode) => {
+                let parsed = u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+                    .map_err(|_| format!("--cuse-mode: '{}' is not an octal mode", mode))?;
+                if parsed > 0o7777 {
+                    return Err(format!("--cuse-mode: '{}' is out of range", mode));
+                }
+                Some(parsed)
+            }
+            None if self.allow_other => Some(0o666),
+            None => None,
+        };
+
+        let uid = match &self.cuse_owner {
+            Some(owner) => Some(match owner.parse::<u32>() {
+                Ok(uid) => uid,
+                Err(_) => nix::unistd::User::from_name(owner)
+                    .map_err(|e| format!("--cuse-owner: failed to look up '{}': {}", owner, e))?
+                    .ok_or(format!("--cuse-owner: unknown user '{}'", owner))?
+                    .uid
+                    .as_raw(),
+            }),
+            None => None,
+        };
+
+        let gid = match &self.cuse_group {
+            Some(group) => Some(match group.parse::<u32>() {
+                Ok(gid) => gid,
+                Err(_) => nix::unistd::Group::from_name(group)
+                    .map_err(|e| format!("--cuse-group: failed to look up '{}': {}", group, e))?
+                    .ok_or(format!("--cuse-group: unknown group '{}'", group))?
+                    .gid
+                    .as_raw(),
+            }),
+            None => None,
+        };
+
+        Ok(CuseNodePermissions { mode, uid, gid })
+    }
+
+    /// The options that have been given on the command line, in the shape of the configuration file
+    pub fn command_line_config(&self, matches: &ArgMatches) -> ConfigFile {
+        let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        ConfigFile {
+            devname: self.devname.clone(),
+            placement: self.placement.clone(),
+            container_runtime: given("container_runtime").then(|| self.container_runtime.clone()),
+            target_container: self.target_container.clone(),
+            device_owner: given("device_owner").then(|| self.device_owner.clone()),
+            device_policy: given("device_policy").then_some(self.device_policy),
+            log_level: None,
+            protocol_dump: given("protocol_dump").then_some(self.protocol_dump),
+            keystroke_privacy: given("keystroke_privacy").then_some(self.keystroke_privacy),
+            device_name_policy: given("device_name_policy").then_some(self.device_name_policy),
+            id_policy: given("id_policy").then_some(self.id_policy),
+            passthrough_ids: given("passthrough_ids").then(|| self.passthrough_ids.clone()),
+            legacy_setup: given("legacy_setup").then_some(self.legacy_setup),
+            shutdown_timeout: self.shutdown_timeout,
+            notify_user: self.notify_user.clone(),
+            block_keys_when_locked: self.block_keys_when_locked.clone(),
+            verify_seat: given("verify_seat").then_some(self.verify_seat),
+            seat_mode: given("seat_mode").then_some(self.seat_mode),
+            seat_name: self.seat_name.clone(),
+            node_naming: given("node_naming").then_some(self.node_naming),
+            input_group: given("input_group").then_some(self.input_group),
+            input_gids: given("input_gids").then(|| self.input_gids.clone()),
+            node_owner: self.node_owner.clone(),
+            node_group: self.node_group.clone(),
+            node_mode: self.node_mode,
+            node_acl: given("node_acl").then(|| self.node_acl.clone()),
+            approval: given("approval").then_some(self.approval),
+            approval_ttl: self.approval_ttl,
+            policy_script: self.policy_script.clone(),
+            persist_devices: given("persist_devices").then_some(self.persist_devices),
+            dbus: given("dbus").then_some(self.dbus),
+            portal: given("portal").then_some(self.portal),
+            metrics_listen: self.metrics_listen.clone(),
+            metrics_textfile: self.metrics_textfile.clone(),
+            audit_log: self.audit_log.clone(),
+            audit_journal: given("audit_journal").then_some(self.audit_journal),
+            // rules are only read from the file and the environment
+            container_policy: None,
+            udev_property: None,
+            limits: Limits::default(),
+            hooks: Hooks::default(),
+            strict_gamepad: StrictGamepad::default(),
+            device_names: DeviceNames::default(),
+        }
+    }
+
+    /// Takes over the values of the effective configuration (file and command line merged).
+    pub fn apply_config(&mut self, config: &ConfigFile) {
+        self.devname = config.devname.clone();
+        self.placement = config.placement.clone();
+        self.container_runtime = config.container_runtime.clone().unwrap_or_default();
+        self.target_container = config.target_container.clone();
+        self.device_owner = config.device_owner.clone().unwrap_or_default();
+        self.persist_devices = config.persist_devices.unwrap_or_default();
+        self.dbus = config.dbus.unwrap_or_default();
+        self.portal = config.portal.unwrap_or_default();
+        self.metrics_listen = config.metrics_listen.clone();
+        self.metrics_textfile = config.metrics_textfile.clone();
+        self.audit_log = config.audit_log.clone();
+        self.audit_journal = config.audit_journal.unwrap_or_default();
+    }
+
+    /// The name of the first node, which also names the control socket and /run/vuinputd/{name}
+    pub fn instance_name(&self) -> String {
+        match (&self.devname, self.devices.first()) {
+            (Some(devname), _) => devname.clone(),
+            (None, Some(device)) => device.name.clone(),
+            (None, None) => "vuinput".to_string(),
+        }
+    }
+
+    /// Reconstructs the command line of the daemon from the options, leaving out defaults.
+    pub fn daemon_args(&self) -> Vec<String> {
+        let mut daemon_args = Vec::new();
+        let mut push = |name: &str, value: String| {
+            daemon_args.push(name.to_string());
+            daemon_args.push(value);
+        };
+
+        if let Some(config) = &self.config {
+            push("--config", config.to_string_lossy().into_owned());
+        }
+        if let (Some(major), Some(minor)) = (self.major, self.minor) {
+            push("--major", major.to_string());
+            push("--minor", minor.to_string());
+        }
+        if let Some(devname) = &self.devname {
+            push("--devname", devname.clone());
+        }
+        for device in &self.devices {
+            push("--device", device.to_string());
+        }
+        if self.device_policy != DevicePolicy::default() {
+            push("--device-policy", value_name(&self.device_policy));
+        }
+        let container_runtime = self.resolve_runtime();
+        if container_runtime != ContainerRuntime::Auto {
+            push("--container-runtime", value_name(&container_runtime));
+        }
+        if self.device_owner != DeviceOwner::default() {
+            push("--device-owner", value_name(&self.device_owner));
+        }
+        if let Some(strategy_file) = &self.strategy_file {
+            push(
+                "--strategy-file",
+                strategy_file.to_string_lossy().into_owned(),
+            );
+        }
+        if let Some(target_container) = &self.target_container {
+            push("--target-container", target_container.clone());
+        }
+        for option in &self.fuse_options {
+            push("--fuse-option", option.clone());
+        }
+        if self.cuse_threads != session_manager::DEFAULT_CUSE_THREADS {
+            push("--cuse-threads", self.cuse_threads.to_string());
+        }
+        if let Some(mode) = &self.cuse_mode {
+            push("--cuse-mode", mode.clone());
+        }
+        if let Some(owner) = &self.cuse_owner {
+            push("--cuse-owner", owner.clone());
+        }
+        if let Some(group) = &self.cuse_group {
+            push("--cuse-group", group.clone());
+        }
+        if self.protocol_dump != ProtocolDump::default() {
+            push("--protocol-dump", value_name(&self.protocol_dump));
+        }
+        if !self.keystroke_privacy {
+            push("--keystroke-privacy", "false".to_string());
+        }
+        if self.device_name_policy != DeviceNamePolicy::default() {
+            push("--device-name-policy", value_name(&self.device_name_policy));
+        }
+        if self.id_policy != IdPolicy::default() {
+            push("--id-policy", value_name(&self.id_policy));
+        }
+        for id in &self.passthrough_ids {
+            push("--passthrough-id", id.to_string());
+        }
+        if !self.legacy_setup {
+            push("--legacy-setup", "false".to_string());
+        }
+        if let Some(seconds) = self.shutdown_timeout {
+            push("--shutdown-timeout", seconds.to_string());
+        }
+        if let Some(user) = &self.notify_user {
+            push("--notify-user", user.clone());
+        }
+        if let Some(seat) = &self.block_keys_when_locked {
+            push("--block-keys-when-locked", seat.clone());
+        }
+        if self.seat_mode != SeatMode::default() {
+            push("--seat-mode", value_name(&self.seat_mode));
+        }
+        if let Some(seat) = &self.seat_name {
+            push("--seat-name", seat.clone());
+        }
+        if self.node_naming != NodeNaming::default() {
+            push("--node-naming", value_name(&self.node_naming));
+        }
+        for gid in &self.input_gids {
+            push("--input-gid", gid.to_string());
+        }
+        if let Some(user) = &self.node_owner {
+            push("--node-owner", user.clone());
+        }
+        if let Some(group) = &self.node_group {
+            push("--node-group", group.clone());
+        }
+        if let Some(mode) = self.node_mode {
+            push("--node-mode", format!("{:04o}", mode));
+        }
+        for user in &self.node_acl {
+            push("--node-acl", user.clone());
+        }
+        if self.approval != Approval::default() {
+            push("--approval", value_name(&self.approval));
+        }
+        if let Some(seconds) = self.approval_ttl {
+            push("--approval-ttl", seconds.to_string());
+        }
+        if let Some(script) = &self.policy_script {
+            push("--policy-script", script.clone());
+        }
+        if self.modprobe_cuse != ModprobeCuse::default() {
+            push("--modprobe-cuse", value_name(&self.modprobe_cuse));
+        }
+        if let Some(root) = &self.host_root {
+            push("--host-root", root.to_string_lossy().into_owned());
+        }
+        if let Some(address) = &self.metrics_listen {
+            push("--metrics-listen", address.clone());
+        }
+        if let Some(textfile) = &self.metrics_textfile {
+            push(
+                "--metrics-textfile",
+                textfile.to_string_lossy().into_owned(),
+            );
+        }
+        if let Some(log) = &self.audit_log {
+            push("--audit-log", log.to_string_lossy().into_owned());
+        }
+        if self.allow_other {
+            daemon_args.push("--allow-other".to_string());
+        }
+        if self.persist_devices {
+            daemon_args.push("--persist-devices".to_string());
+        }
+        if self.audit_journal {
+            daemon_args.push("--audit-journal".to_string());
+        }
+        if self.verify_seat {
+            daemon_args.push("--verify-seat".to_string());
+        }
+        if self.input_group {
+            daemon_args.push("--input-group".to_string());
+        }
+        if self.dbus {
+            daemon_args.push("--dbus".to_string());
+        }
+        if self.portal {
+            daemon_args.push("--portal".to_string());
+        }
+        daemon_args
+    }
+
     fn validate_args(&self) -> Result<(), String> {
         if self.placement.is_some() && self.container_runtime != ContainerRuntime::Auto {
             return Err(
@@ -185,6 +729,13 @@ impl Args {
             }
         }
 
+        if self.command.is_some() && (action.is_some() || self.vt_guard) {
+            return Err(
+                "subcommands must not be used together with --action, --action-base64 or --vt-guard"
+                    .into(),
+            );
+        }
+
         // major/minor must appear together
         match (self.major, self.minor) {
             (Some(_), Some(_)) | (None, None) => {}
@@ -203,14 +754,47 @@ impl Args {
             }
         }
 
+        if !self.devices.is_empty() {
+            if self.devname.is_some() {
+                return Err("--devname and --device cannot be used together".into());
+            }
+            // the kernel would refuse to register a second node with the same numbers
+            if self.devices.len() > 1 && self.major.is_some() {
+                return Err("--major and --minor can only be used with a single --device".into());
+            }
+            for (i, device) in self.devices.iter().enumerate() {
+                if device.name.len() >= DEVNAME_MAX_LEN {
+                    return Err(format!(
+                        "the name of --device {} must be shorter than {} bytes",
+                        device, DEVNAME_MAX_LEN
+                    ));
+                }
+                if self.devices[..i].iter().any(|d| d.name == device.name) {
+                    return Err(format!("--device {} is given twice", device.name));
+                }
+            }
+        }
+
         Ok(())
     }
 }
 
+/// Variables that are not valid UTF-8 can't be settings, std::env::vars would panic on them
+fn utf8_env_vars() -> impl Iterator<Item = (String, String)> {
+    std::env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+}
+
 fn main() -> std::io::Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug")).init();
+    // Without RUST_LOG, everything passes env_logger and the level is set by the configuration
+    // (see global_config::apply_log_level), so it can be changed on reload.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("trace")).init();
+    if std::env::var_os("RUST_LOG").is_none() {
+        log::set_max_level(LevelFilter::Debug);
+    }
 
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
     let argv0 = std::env::args_os()
         .next()
         .expect("Couldn't retrieve program name");
@@ -220,6 +804,10 @@ fn main() -> std::io::Result<()> {
         std::process::exit(2);
     }
 
+    if let Some(root) = &args.host_root {
+        host_root::set_host_root(root);
+    }
+
     let action = match (&args.action, &args.action_base64) {
         (Some(json), None) => Some(json.clone()),
         (None, Some(b64)) => {
@@ -248,23 +836,152 @@ fn main() -> std::io::Result<()> {
         std::process::exit(error_code);
     }
 
+    if let Some(Command::InstallSystemd {
+        unit_dir,
+        tmpfiles_dir,
+        binary,
+        dry_run,
+    }) = &args.command
+    {
+        let options = systemd_units::UnitOptions {
+            binary: match binary {
+                Some(binary) => binary.clone(),
+                None => std::env::current_exe()?,
+            },
+            daemon_args: args.daemon_args(),
+            devname: args.instance_name(),
+            uses_run_folder: args.resolve_runtime().uses_run_folder(),
+        };
+        systemd_units::install_systemd(&options, unit_dir, tmpfiles_dir, *dry_run)?;
+        std::process::exit(0);
+    }
+
+    if let Some(Command::Doctor) = &args.command {
+        let passed = doctor::run_doctor();
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
+    if let Some(Command::Replay { evemu, container }) = &args.command {
+        if let Err(e) = replay::run_replay(
+            &evemu[0],
+            &evemu[1],
+            container.as_deref(),
+            &args.device_owner,
+            args.enter_user_namespace,
+        ) {
+            eprintln!("Error: {e}");
+            std::process::exit(1);
+        }
+        std::process::exit(0);
+    }
+
     if args.vt_guard {
         vt_tools::mute_keyboard()?;
         std::process::exit(0);
     }
 
-    check_permissions().expect("failed to read the capabilities of the vuinputd process");
-    vt_tools::check_vt_status();
+    let config_path = args
+        .config
+        .clone()
+        .unwrap_or(PathBuf::from(DEFAULT_CONFIG_FILE));
+    let config_required = args.config.is_some();
+    let command_line_config = args.command_line_config(&matches);
+    let (file_config, env_config) = match ConfigFile::load(&config_path, config_required)
+        .and_then(|file| Ok((file, ConfigFile::from_env(utf8_env_vars())?)))
+    {
+        Ok(layers) => layers,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            std::process::exit(2);
+        }
+    };
+    // the file is re-read on reload, the other layers stay
+    let overrides = env_config.overridden_by(&command_line_config);
+    let effective_config = file_config.overridden_by(&overrides);
 
-    let container_runtime = args.resolve_runtime();
+    if let Some(Command::Config {
+        command: ConfigCommand::Print,
+    }) = &args.command
+    {
+        print!(
+            "{}",
+            config_file::render_effective(&[
+                (ConfigSource::Default, &ConfigFile::defaults()),
+                (ConfigSource::File, &file_config),
+                (ConfigSource::Environment, &env_config),
+                (ConfigSource::CommandLine, &command_line_config),
+            ])
+        );
+        std::process::exit(0);
+    }
+    args.apply_config(&effective_config);
+
+    if let Some(Command::Health { live, wait }) = &args.command {
+        let socket = control::protocol::control_socket_path(&args.instance_name());
+        std::process::exit(health::run_health(
+            &socket,
+            *live,
+            wait.map(Duration::from_secs),
+        ));
+    }
+
+    if let Err(e) = args.validate_args() {
+        eprintln!("Error: {e}");
+        std::process::exit(2);
+    }
+    signal_handling::block_sighup().expect("failed to block SIGHUP");
+    if !args.devices.is_empty() && args.simulate.is_none() {
+        signal_handling::block_shutdown_signals().expect("failed to block SIGINT and SIGTERM");
+    }
+
+    fault_injection::init();
+
+    let simulation_dir = args.simulate.clone();
+    let container_runtime = if simulation_dir.is_some() {
+        ContainerRuntime::Simulated
+    } else {
+        check_permissions().expect("failed to read the capabilities of the vuinputd process");
+        if let Err(e) = ensure_cuse_available(&args.modprobe_cuse) {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        vt_tools::check_vt_status();
+        if let Some(message) = sysfs_input::describe(&sysfs_input::sysfs_status()) {
+            error!("{}", message);
+            warn!("Visit https://github.com/joleuger/vuinputd/blob/main/docs/TROUBLESHOOTING.md for details");
+        }
+        if host_root::is_in_host_pid_namespace() == Some(false) {
+            warn!(
+                "vuinputd does not run in the PID namespace of {}, clients in other containers can't be identified. Run its container with --pid=host",
+                host_root::host_path("/proc")
+            );
+        }
+        args.resolve_runtime()
+    };
     let scope = args.get_scope();
+    let cuse_node = match args.resolve_cuse_node_permissions() {
+        Ok(cuse_node) => cuse_node,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            std::process::exit(2);
+        }
+    };
+    let reloadable_config = effective_config.reloadable_config();
+    if let Err(e) = policy_script::configure(&reloadable_config) {
+        eprintln!("Error: {e}");
+        std::process::exit(2);
+    }
 
     global_config::initialize_global_config(
-        &args.device_policy,
-        &container_runtime,
-        &args.devname,
-        &args.device_owner,
-        &scope,
+        GlobalConfig {
+            container_runtime: container_runtime.clone(),
+            vudevname: args.instance_name(),
+            device_owner: args.device_owner.clone(),
+            scope,
+            cuse_node,
+            simulation_dir: simulation_dir.clone(),
+        },
+        reloadable_config,
     );
     initialize_evdev_write_watcher().expect(
         "failed to initialize the watcher that watches for writes on the created evdev devices",
@@ -280,16 +997,91 @@ fn main() -> std::io::Result<()> {
         .set(get_self_namespace())
         .expect("failed to retrieve the namespaces of the vuinputd process");
     initialize_dedup_last_error();
-    JOB_DISPATCHER
-        .get()
-        .unwrap()
-        .lock()
-        .unwrap()
-        .dispatch(Box::new(MonitorBackgroundLoop::new()));
+    if simulation_dir.is_none() {
+        health::expect_udev_monitor();
+        JOB_DISPATCHER
+            .get()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .dispatch(Box::new(MonitorBackgroundLoop::new()));
+        JOB_DISPATCHER
+            .get()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .dispatch(Box::new(NodeAclBackgroundLoop::new()));
+    }
+    if let Some(timeout) = sd_daemon::watchdog_interval() {
+        JOB_DISPATCHER
+            .get()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .dispatch(Box::new(WatchdogBackgroundLoop::new(timeout)));
+    }
+    signal_handling::spawn_reload_on_sighup(ReloadConfigJob::new(
+        config_path,
+        config_required,
+        overrides,
+        effective_config,
+    ))?;
 
-    info!("Starting vuinputd");
+    let control_socket = control::protocol::control_socket_path(global_config::get_vudevname());
+    // a socket unit may have bound the control socket already
+    let activated_control_socket = sd_daemon::listen_fds().into_iter().next();
+    let socket_activated = activated_control_socket.is_some();
+    let control_server = match activated_control_socket {
+        Some(fd) => control::serve_control_socket(UnixListener::from(fd), &control_socket),
+        None => control::start_control_server(&control_socket),
+    };
+    if let Err(e) = control_server {
+        warn!(
+            "control socket {} is not available: {}",
+            control_socket.display(),
+            e
+        );
+    }
 
-    let cuse_ops = vuinput_make_cuse_ops();
+    if let Err(e) = audit::configure(args.audit_log.as_deref(), args.audit_journal) {
+        eprintln!("Error: audit log: {e}");
+        std::process::exit(2);
+    }
+    if let Err(e) = metrics::start(
+        args.metrics_listen.as_deref(),
+        args.metrics_textfile.as_deref(),
+    ) {
+        warn!("metrics are not exported: {}", e);
+    }
+    if args.dbus || args.portal {
+        if let Err(e) = control::dbus::start(args.dbus, args.portal) {
+            warn!("the system bus is not served: {}", e);
+        }
+    }
+
+    if simulation_dir.is_none() {
+        if let Err(e) = desktop_notification::start() {
+            warn!("desktop notifications are not available: {}", e);
+        }
+        if let Err(e) = session_lock::start() {
+            warn!("the lock screen of the host can't be watched: {}", e);
+        }
+    }
+
+    info!("Starting vuinputd");
+    if simulation_dir.is_none() {
+        match uinput_compat::probe_host() {
+            Ok(features) => {
+                match features.mode() {
+                    CompatMode::Native => info!("{}", features),
+                    CompatMode::LegacySetup => warn!("{}", features),
+                    CompatMode::Unsupported => error!("{}", features),
+                }
+                uinput_compat::initialize(features);
+            }
+            Err(e) => warn!("the version of uinput can't be probed: {}", e),
+        }
+    }
 
     let vuinput_devicename = match &args.devname {
         None => "vuinput",
@@ -297,13 +1089,64 @@ fn main() -> std::io::Result<()> {
     };
 
     container_runtime.initialize();
+    if args.persist_devices && simulation_dir.is_none() {
+        let state_file = persistence::state_file_path(global_config::get_vudevname());
+        if let Err(e) = persistence::start(&state_file) {
+            warn!("devices are not persisted: {}", e);
+        }
+    }
 
-    let vuinput_devicename = CString::new(format!("DEVNAME={}", vuinput_devicename)).unwrap();
+    if let Some(simulation_dir) = &simulation_dir {
+        info!(
+            "Simulating devices, writing into {}",
+            simulation_dir.display()
+        );
+        sd_daemon::ready("Simulating devices");
+        simulation::run(std::io::stdin().lock());
+    } else {
+        sd_daemon::expect_nodes(args.devices.len().max(1));
+        run_cuse_session(&args, &argv0, vuinput_devicename)?;
+    }
+    sd_daemon::stopping();
+    signal_handling::restore_default_shutdown_signals();
+    let shutdown_mode = signal_handling::shutdown_mode();
+    info!("Stopping vuinputd ({:?})", shutdown_mode);
+    persistence::stop();
+    // without the CUSE session, no release requests arrive anymore that would clean up
+    let removed = revoke::revoke_all() + vuhid::handover::retire_all();
+    if removed > 0 {
+        info!("removing {} devices from the containers", removed);
+    }
+    let dispatcher_thread = {
+        let mut dispatcher = JOB_DISPATCHER.get().unwrap().lock().unwrap();
+        dispatcher.close(shutdown_mode);
+        dispatcher.take_thread()
+    };
+    // without the lock of the dispatcher, which the jobs that are left may need
+    if let Some(dispatcher_thread) = dispatcher_thread {
+        dispatcher_thread.wait_until_finished(global_config::get_shutdown_timeout());
+    }
+    EVDEV_WRITE_WATCHER.get().unwrap().lock().unwrap().stop();
+    if !socket_activated {
+        control::remove_control_socket(&control_socket);
+    }
 
-    let mut dev_info_argv: Vec<*const c_char> = vec![
-        vuinput_devicename.as_ptr(), // pointer to the C string
-        std::ptr::null(),            // null terminator, often required by C APIs
-    ];
+    Ok(())
+}
+
+/// Runs the CUSE session until it is ended by a signal or the kernel
+fn run_cuse_session(args: &Args, argv0: &OsStr, vuinput_devicename: &str) -> std::io::Result<()> {
+    info!(
+        "Using libfuse {} (built with {}, FUSE_USE_VERSION {})",
+        compat::fuse_runtime_version(),
+        compat::FUSE_BUILD_VERSION,
+        compat::FUSE_USE_VERSION
+    );
+    if compat::runtime_older_than_build() {
+        warn!(
+            "libfuse is older than the one vuinputd has been built with, please rebuild vuinputd"
+        );
+    }
 
     // setting dev_major and dev_minor to 0 leads to a dynamic assignment of the major and minor, very likely beginning with 234:0
     // see  in https://www.kernel.org/doc/Documentation/admin-guide/devices.txt
@@ -311,49 +1154,49 @@ fn main() -> std::io::Result<()> {
         (Some(major), Some(minor)) => (major, minor),
         _ => (0, 0),
     };
+    if !args.devices.is_empty() {
+        return session_manager::run_sessions(
+            &args.devices,
+            argv0,
+            &args.fuse_options,
+            args.cuse_threads,
+            major,
+            minor,
+        );
+    }
+
+    let cuse_ops = vuinput_make_cuse_ops();
+    let mut dev_info_args = session_manager::dev_info_args(vuinput_devicename)?;
     let ci = cuse_lowlevel::cuse_info {
         dev_major: major,
         dev_minor: minor,
-        dev_info_argc: 1,
-        dev_info_argv: dev_info_argv.as_mut_ptr(),
+        dev_info_argc: dev_info_args.argc() as u32,
+        dev_info_argv: dev_info_args.argv_const(),
         flags: cuse_lowlevel::CUSE_UNRESTRICTED_IOCTL,
     };
 
-    let arg_program_name = CString::new(argv0.as_encoded_bytes()).unwrap();
-    let parg_program_name = arg_program_name.into_raw();
-    let arg_foreground = CString::new("-f").unwrap();
-    let parg_foreground = arg_foreground.into_raw();
-    let arg_singlethreaded = CString::new("-s").unwrap();
-    let parg_singlethreaded = arg_singlethreaded.into_raw();
-    let mut stripped_argv: Vec<*mut c_char> = vec![
-        parg_program_name,
-        parg_foreground,
-        parg_singlethreaded,
-        std::ptr::null_mut(), // null terminator, often required by C APIs
-    ];
-
-    unsafe {
-        cuse_lowlevel::cuse_lowlevel_main(
-            3,
-            stripped_argv.as_mut_ptr(),
+    let mut fuse_args =
+        session_manager::session_args(argv0, &args.fuse_options, args.cuse_threads)?;
+
+    // like cuse_lowlevel_main, but with the limit of threads
+    let mut multithreaded = 0;
+    let se = unsafe {
+        cuse_lowlevel::cuse_lowlevel_setup(
+            fuse_args.argc(),
+            fuse_args.argv(),
             &ci,
             &cuse_ops,
+            &mut multithreaded,
             std::ptr::null_mut(),
-        );
-        let _reclaim_arg_program_name = CString::from_raw(parg_program_name);
-        let _reclaim_arg_foreground = CString::from_raw(parg_foreground);
-        let _reclaim_arg_singlethreaded = CString::from_raw(parg_singlethreaded);
+        )
+    };
+    if se.is_null() {
+        return Err(std::io::Error::other(
+            "the CUSE session could not be set up",
+        ));
     }
-    info!("Stopping vuinputd");
-    JOB_DISPATCHER.get().unwrap().lock().unwrap().close();
-    JOB_DISPATCHER
-        .get()
-        .unwrap()
-        .lock()
-        .unwrap()
-        .wait_until_finished();
-
-    EVDEV_WRITE_WATCHER.get().unwrap().lock().unwrap().stop();
-
+    let res = unsafe { session_manager::session_loop(se, multithreaded != 0, args.cuse_threads) };
+    debug!("session of /dev/{} ended ({})", vuinput_devicename, res);
+    unsafe { cuse_lowlevel::cuse_lowlevel_teardown(se) };
     Ok(())
 }
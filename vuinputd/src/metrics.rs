@@ -0,0 +1,396 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Metrics in the text format of Prometheus, for operators that run vuinputd on many hosts
+// (e.g. game streaming servers): open handles, devices per container, forwarded and dropped
+// events, the depth of the job queues, how long the jobs take and the size of the udev event
+// store. They are served over HTTP (metrics-listen, e.g. "127.0.0.1:9812", any path) and/or
+// written into a file for the textfile collector of node_exporter (metrics-textfile).
+//
+// The counters of a handle are gone when it is closed, so the totals are kept here.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use crate::cuse_device::drop_counters::{DropCause, DropCounters};
+use crate::cuse_device::state::vuinput_states;
+use crate::job_engine::JOB_DISPATCHER;
+use crate::jobs::monitor_udev_job::EVENT_STORE;
+
+/// How often the textfile is written
+const TEXTFILE_INTERVAL: Duration = Duration::from_secs(15);
+/// A scraper that does not send its request and take the answer within this time is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Request line and headers, a scraper sends a few hundred bytes
+const MAX_REQUEST_SIZE: u64 = 8192;
+
+static FORWARDED: AtomicU64 = AtomicU64::new(0);
+static DROPPED_BY_POLICY: AtomicU64 = AtomicU64::new(0);
+static DROPPED_WHILE_LOCKED: AtomicU64 = AtomicU64::new(0);
+static DROPPED_BY_UINPUT: AtomicU64 = AtomicU64::new(0);
+static JOB_DURATIONS: Mutex<BTreeMap<String, JobDurations>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct JobDurations {
+    pub count: u64,
+    pub seconds: f64,
+}
+
+pub fn record_forwarded(events: u64) {
+    FORWARDED.fetch_add(events, Ordering::Relaxed);
+}
+
+pub fn record_drop(cause: DropCause, events: u64) {
+    let counter = match cause {
+        DropCause::Policy => &DROPPED_BY_POLICY,
+        DropCause::SessionLocked => &DROPPED_WHILE_LOCKED,
+        DropCause::Uinput => &DROPPED_BY_UINPUT,
+    };
+    counter.fetch_add(events, Ordering::Relaxed);
+}
+
+/// A job of the dispatcher finished (or failed) after `duration`
+pub fn record_job(desc: &str, duration: Duration) {
+    let mut durations = JOB_DURATIONS.lock().unwrap();
+    let job = durations.entry(desc.to_string()).or_default();
+    job.count += 1;
+    job.seconds += duration.as_secs_f64();
+}
+
+/// The values of all metrics at one point in time
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Snapshot {
+    pub open_handles: usize,
+    /// Created devices by the root pid of their container
+    pub devices: BTreeMap<u32, usize>,
+    /// Root pid of the container and label of the labeled handles, see vuinputctl label
+    pub labels: BTreeMap<u64, (u32, String)>,
+    pub forwarded: u64,
+    pub dropped: DropCounters,
+    /// Queued jobs by target
+    pub job_queues: BTreeMap<String, usize>,
+    pub jobs: BTreeMap<String, JobDurations>,
+    /// None until the udev monitor has been started
+    pub udev_event_store: Option<usize>,
+}
+
+impl Snapshot {
+    pub fn collect() -> Snapshot {
+        let states = vuinput_states();
+        let mut devices = BTreeMap::new();
+        let mut labels = BTreeMap::new();
+        for (fh, state) in &states {
+            let state = state.lock().unwrap();
+            let container = state.requesting_process.pid_requestor_root.as_raw();
+            if state.input_device.is_some() {
+                *devices.entry(container).or_default() += 1;
+            }
+            if let Some(label) = &state.label {
+                labels.insert(*fh, (container, label.clone()));
+            }
+        }
+        #[cfg(feature = "dbus")]
+        for container in crate::control::portal::devices_per_container() {
+            *devices.entry(container).or_default() += 1;
+        }
+        let job_queues = JOB_DISPATCHER
+            .get()
+            .map(|dispatcher| dispatcher.lock().unwrap().queue_status())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(target, status)| (target.to_string(), status.queued))
+            .collect();
+        Snapshot {
+            open_handles: states.len(),
+            devices,
+            labels,
+            forwarded: FORWARDED.load(Ordering::Relaxed),
+            dropped: DropCounters {
+                policy: DROPPED_BY_POLICY.load(Ordering::Relaxed),
+                session_locked: DROPPED_WHILE_LOCKED.load(Ordering::Relaxed),
+                uinput: DROPPED_BY_UINPUT.load(Ordering::Relaxed),
+            },
+            job_queues,
+            jobs: JOB_DURATIONS.lock().unwrap().clone(),
+            udev_event_store: EVENT_STORE.get().map(|store| store.size()),
+        }
+    }
+}
+
+/// The text format of Prometheus (version 0.0.4)
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        header(f, "open_handles", "gauge", "Open handles of /dev/uinput")?;
+        writeln!(f, "vuinputd_open_handles {}", self.open_handles)?;
+
+        header(f, "devices", "gauge", "Created devices per container")?;
+        for (container, devices) in &self.devices {
+            writeln!(
+                f,
+                "vuinputd_devices{{container=\"{}\"}} {}",
+                container, devices
+            )?;
+        }
+
+        if !self.labels.is_empty() {
+            header(
+                f,
+                "handle_label",
+                "gauge",
+                "Labels of the handles, always 1",
+            )?;
+            for (fh, (container, label)) in &self.labels {
+                writeln!(
+                    f,
+                    "vuinputd_handle_label{{fh=\"{}\",container=\"{}\",label=\"{}\"}} 1",
+                    fh,
+                    container,
+                    escape(label)
+                )?;
+            }
+        }
+
+        header(
+            f,
+            "events_forwarded_total",
+            "counter",
+            "Events written to uinput",
+        )?;
+        writeln!(f, "vuinputd_events_forwarded_total {}", self.forwarded)?;
+
+        header(
+            f,
+            "events_dropped_total",
+            "counter",
+            "Events that did not reach their device",
+        )?;
+        for (cause, events) in [
+            ("policy", self.dropped.policy),
+            ("session_locked", self.dropped.session_locked),
+            ("uinput", self.dropped.uinput),
+        ] {
+            writeln!(
+                f,
+                "vuinputd_events_dropped_total{{cause=\"{}\"}} {}",
+                cause, events
+            )?;
+        }
+
+        header(f, "job_queue_depth", "gauge", "Jobs waiting per target")?;
+        for (target, queued) in &self.job_queues {
+            writeln!(
+                f,
+                "vuinputd_job_queue_depth{{target=\"{}\"}} {}",
+                escape(target),
+                queued
+            )?;
+        }
+
+        header(f, "job_duration_seconds", "summary", "Time the jobs took")?;
+        for (job, durations) in &self.jobs {
+            let job = escape(job);
+            writeln!(
+                f,
+                "vuinputd_job_duration_seconds_sum{{job=\"{}\"}} {}",
+                job, durations.seconds
+            )?;
+            writeln!(
+                f,
+                "vuinputd_job_duration_seconds_count{{job=\"{}\"}} {}",
+                job, durations.count
+            )?;
+        }
+
+        if let Some(entries) = self.udev_event_store {
+            header(
+                f,
+                "udev_event_store_entries",
+                "gauge",
+                "Devices the udev event store holds events of",
+            )?;
+            writeln!(f, "vuinputd_udev_event_store_entries {}", entries)?;
+        }
+        Ok(())
+    }
+}
+
+fn header(f: &mut fmt::Formatter<'_>, name: &str, kind: &str, help: &str) -> fmt::Result {
+    writeln!(f, "# HELP vuinputd_{} {}", name, help)?;
+    writeln!(f, "# TYPE vuinputd_{} {}", name, kind)
+}
+
+/// Label values may not contain unescaped backslashes, quotes and newlines
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn render() -> String {
+    let mut text = String::new();
+    let _ = write!(text, "{}", Snapshot::collect());
+    text
+}
+
+/// Starts the exporters that are configured, each in a thread of its own
+pub fn start(listen: Option<&str>, textfile: Option<&Path>) -> io::Result<()> {
+    if let Some(address) = listen {
+        let listener = TcpListener::bind(address)?;
+        debug!("serving metrics on {}", address);
+        thread::Builder::new()
+            .name("metrics-http".to_string())
+            .spawn(move || serve(listener))?;
+    }
+    if let Some(textfile) = textfile {
+        let textfile = textfile.to_path_buf();
+        thread::Builder::new()
+            .name("metrics-file".to_string())
+            .spawn(move || write_textfile_periodically(textfile))?;
+    }
+    Ok(())
+}
+
+/// Every scraper gets its own thread, so one that sends its request slowly does not keep the
+/// others waiting
+fn serve(listener: TcpListener) {
+    for stream in listener.incoming() {
+        let spawned = stream.and_then(|mut stream| {
+            thread::Builder::new()
+                .name("metrics-request".to_string())
+                .spawn(move || {
+                    if let Err(e) = answer(&mut stream) {
+                        debug!("metrics request failed: {}", e);
+                    }
+                })
+                .map(|_| ())
+        });
+        if let Err(e) = spawned {
+            debug!("metrics request failed: {}", e);
+        }
+    }
+}
+
+/// Reads a line, but only until `deadline`
+fn read_line_until(
+    reader: &mut impl BufRead,
+    stream: &TcpStream,
+    deadline: Instant,
+    line: &mut String,
+) -> io::Result<usize> {
+    let left = deadline.saturating_duration_since(Instant::now());
+    if left.is_zero() {
+        return Err(io::ErrorKind::TimedOut.into());
+    }
+    stream.set_read_timeout(Some(left))?;
+    reader.read_line(line)
+}
+
+fn answer(stream: &mut TcpStream) -> io::Result<()> {
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    // a request that does not end within MAX_REQUEST_SIZE reads as if it had ended there
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_REQUEST_SIZE));
+    let mut request_line = String::new();
+    read_line_until(&mut reader, stream, deadline, &mut request_line)?;
+    // the headers are of no interest, but have to be read before answering
+    let mut header = String::new();
+    while read_line_until(&mut reader, stream, deadline, &mut header)? > 0
+        && !header.trim_end().is_empty()
+    {
+        header.clear();
+    }
+    let (status, body) = match request_line.split_whitespace().next() {
+        Some("GET") => ("200 OK", render()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+        Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+fn write_textfile_periodically(textfile: PathBuf) {
+    loop {
+        if let Err(e) = write_textfile(&textfile) {
+            warn!(
+                "failed to write the metrics to {}: {}",
+                textfile.display(),
+                e
+            );
+        }
+        thread::sleep(TEXTFILE_INTERVAL);
+    }
+}
+
+/// The collector must not read a half written file, so it is replaced at once
+fn write_textfile(textfile: &Path) -> io::Result<()> {
+    let mut tmp = textfile.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, render())?;
+    fs::rename(&tmp, textfile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_the_text_format() {
+        let snapshot = Snapshot {
+            open_handles: 3,
+            devices: BTreeMap::from([(4242, 2)]),
+            labels: BTreeMap::from([(5, (4242, "stream of \"alice\"".to_string()))]),
+            forwarded: 1000,
+            dropped: DropCounters {
+                policy: 7,
+                session_locked: 0,
+                uinput: 1,
+            },
+            job_queues: BTreeMap::from([("container (pid \"x\")".to_string(), 4)]),
+            jobs: BTreeMap::from([(
+                "mknod input device".to_string(),
+                JobDurations {
+                    count: 2,
+                    seconds: 0.5,
+                },
+            )]),
+            udev_event_store: None,
+        };
+        let text = snapshot.to_string();
+        assert!(text.contains("# TYPE vuinputd_open_handles gauge\nvuinputd_open_handles 3\n"));
+        assert!(text.contains("vuinputd_devices{container=\"4242\"} 2\n"));
+        assert!(text.contains(
+            "vuinputd_handle_label{fh=\"5\",container=\"4242\",label=\"stream of \\\"alice\\\"\"} 1\n"
+        ));
+        assert!(text.contains("vuinputd_events_forwarded_total 1000\n"));
+        assert!(text.contains("vuinputd_events_dropped_total{cause=\"policy\"} 7\n"));
+        assert!(text.contains("vuinputd_job_queue_depth{target=\"container (pid \\\"x\\\")\"} 4\n"));
+        assert!(text.contains(
+            "vuinputd_job_duration_seconds_sum{job=\"mknod input device\"} 0.5\n\
+            vuinputd_job_duration_seconds_count{job=\"mknod input device\"} 2\n"
+        ));
+        assert!(!text.contains("udev_event_store"));
+    }
+}
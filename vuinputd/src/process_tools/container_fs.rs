@@ -0,0 +1,94 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Files of a container that vuinputd reads, like /etc/group or the seat files of logind. The
+// container decides what they are: a FIFO would block the thread that reads it, and a symlink
+// to /dev/zero or a huge file would fill the memory of vuinputd. So they are opened with
+// openat2 and RESOLVE_IN_ROOT, which keeps symlinks inside the root of the container, without
+// blocking, and only regular files of up to MAX_FILE_SIZE are read. Kernels before 5.6 have
+// no openat2; there, the last component must not be a symlink (O_NOFOLLOW).
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::fs::OpenOptionsExt;
+
+use nix::errno::Errno;
+use nix::fcntl::{openat2, OFlag, OpenHow, ResolveFlag};
+
+use crate::process_tools::Pid;
+
+/// Larger files are refused, an /etc/group has a few kilobytes
+pub const MAX_FILE_SIZE: u64 = 1 << 20;
+
+fn open_in_root(root: Pid, path: &str) -> io::Result<File> {
+    let flags = OFlag::O_RDONLY | OFlag::O_NONBLOCK | OFlag::O_NOCTTY | OFlag::O_CLOEXEC;
+    let root_dir = File::options()
+        .read(true)
+        .custom_flags((OFlag::O_PATH | OFlag::O_DIRECTORY).bits())
+        .open(format!("{}/root", root.path()))?;
+    let how = OpenHow::new()
+        .flags(flags)
+        .resolve(ResolveFlag::RESOLVE_IN_ROOT | ResolveFlag::RESOLVE_NO_MAGICLINKS);
+    match openat2(&root_dir, path, how) {
+        Ok(fd) => Ok(File::from(fd)),
+        Err(Errno::ENOSYS) => File::options()
+            .read(true)
+            .custom_flags((flags | OFlag::O_NOFOLLOW).bits())
+            .open(format!("{}/root{}", root.path(), path)),
+        Err(errno) => Err(errno.into()),
+    }
+}
+
+/// The content of the file at the absolute `path` in the container of `root`
+pub fn read_to_string(root: Pid, path: &str) -> io::Result<String> {
+    let file = open_in_root(root, path)?;
+    if !file.metadata()?.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is no regular file", path),
+        ));
+    }
+    let mut content = String::new();
+    file.take(MAX_FILE_SIZE + 1).read_to_string(&mut content)?;
+    if content.len() as u64 > MAX_FILE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is larger than {} bytes", path, MAX_FILE_SIZE),
+        ));
+    }
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn reads_only_small_regular_files() {
+        let own = Pid::Pid(std::process::id());
+        let dir = std::env::temp_dir().join(format!("vuinputd-container-fs-{}", own.as_raw()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| format!("{}/{}", dir.to_str().unwrap(), name);
+
+        fs::write(path("group"), "input:x:104:\n").unwrap();
+        assert_eq!(
+            read_to_string(own, &path("group")).unwrap(),
+            "input:x:104:\n"
+        );
+
+        // a FIFO nobody writes to is refused instead of blocking
+        nix::unistd::mkfifo(path("fifo").as_str(), nix::sys::stat::Mode::S_IRWXU).unwrap();
+        assert!(read_to_string(own, &path("fifo")).is_err());
+
+        std::os::unix::fs::symlink("/dev/zero", path("zero")).unwrap();
+        assert!(read_to_string(own, &path("zero")).is_err());
+
+        fs::write(path("huge"), vec![b'x'; MAX_FILE_SIZE as usize + 1]).unwrap();
+        assert!(read_to_string(own, &path("huge")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
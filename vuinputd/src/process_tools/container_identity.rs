@@ -0,0 +1,584 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Which container a process belongs to, in the words of its engine. The namespaces tell
+// vuinputd that two processes share a container, but not which one it is, so logs, jobs and
+// vuinputctl name it with what the engine put into the cgroup of the process:
+//
+// - docker: .../docker-<id>.scope (systemd driver) or /docker/<id> (cgroupfs driver)
+// - podman: .../libpod-<id>.scope, .../libpod-<id>.scope/container or /libpod_parent/libpod-<id>
+// - systemd-nspawn: .../machine-<name>.scope, with \x escapes (machine-my\x2dbox.scope)
+// - lxc and incus: /lxc.payload.<name>
+//
+// The outermost of these directories is taken: the cgroups below a container (and below the
+// user@<uid>.service of every user) are delegated, so anybody can create a docker-<id>.scope
+// there. The engine is asked whether the directory is really its container, see verified.
+//
+// The name of docker and podman containers is asked from their API socket (/containers/<id>/json,
+// rootless podman below /run/user/<uid>), together with the labels of its image and run, which
+// the container-policy rules can match, and its pid, which has to be the root pid. nspawn
+// machines are checked against the leader machined has recorded in /run/systemd/machines. lxc
+// has no such record, its cgroup has to be at the top of the hierarchy, where only root of the
+// host creates cgroups. Lookups that fail leave the short id. The result is kept per root pid
+// and mount namespace, so a pid that is reused by another container is resolved again.
+//
+// The lookup runs on a thread of its own, the open of /dev/vuinput that needs it waits at most
+// RESOLVE_TIMEOUT. If the engine takes longer, that open goes on with the unconfirmed identity
+// of the cgroup (like when the engine does not answer at all), and the later opens of the
+// container get the result. Opens of a container that is being looked up wait for that lookup.
+//
+// vuinputd-oci-hook registers containers as they start, with the engine, the id and the
+// annotations of the OCI state. The cgroup is not needed then, which helps runtimes that name
+// their cgroups differently; the name is still asked from the engine.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::host_root::host_path;
+use crate::process_tools::{get_namespace, Pid};
+
+/// How long the API socket of an engine may take to answer, all in all
+const SOCKET_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long an open waits for the lookup of its container
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(1);
+/// An inspected container is a few kilobytes of JSON, more is not read
+const MAX_RESPONSE_SIZE: u64 = 1 << 20;
+
+/// Length of the ids that docker ps and podman ps show
+const SHORT_ID: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Engine {
+    Docker,
+    Podman,
+    Nspawn,
+    Lxc,
+}
+
+impl Engine {
+    pub fn from_name(name: &str) -> Option<Engine> {
+        [Engine::Docker, Engine::Podman, Engine::Nspawn, Engine::Lxc]
+            .into_iter()
+            .find(|engine| engine.as_str() == name)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Engine::Docker => "docker",
+            Engine::Podman => "podman",
+            Engine::Nspawn => "nspawn",
+            Engine::Lxc => "lxc",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ContainerIdentity {
+    pub engine: Engine,
+    /// The full id of docker and podman, the machine or container name otherwise
+    pub id: String,
+    /// The name the engine knows the container by, None if it couldn't be asked
+    pub name: Option<String>,
+    /// Labels of docker and podman containers (Config.Labels), empty for the other engines
+    pub labels: BTreeMap<String, String>,
+    /// Whether the engine (or vuinputd-oci-hook) confirmed that the root pid is this
+    /// container. The cgroup alone does not tell, so only then the container-policy rules
+    /// apply.
+    pub verified: bool,
+}
+
+impl ContainerIdentity {
+    /// The name, else the id as short as the engine shows it
+    pub fn label(&self) -> &str {
+        match &self.name {
+            Some(name) => name,
+            None => self.id.get(..SHORT_ID).unwrap_or(&self.id),
+        }
+    }
+}
+
+/// e.g. "docker steam (3f2a9c1b7d4e)" or "nspawn gamebox"
+impl std::fmt::Display for ContainerIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.engine.as_str(), self.label())?;
+        if self.name.as_deref().is_some_and(|name| name != self.id) {
+            write!(f, " ({})", self.id.get(..SHORT_ID).unwrap_or(&self.id))?;
+        }
+        Ok(())
+    }
+}
+
+/// Root pid and mount namespace of a container
+type ContainerKey = (u32, Option<u64>);
+
+enum Lookup {
+    Running,
+    Done(Option<Arc<ContainerIdentity>>),
+}
+
+/// Resolved identities, shared by the processes of the container
+static IDENTITIES: Mutex<BTreeMap<ContainerKey, Lookup>> = Mutex::new(BTreeMap::new());
+/// Wakes the opens that wait for a lookup
+static RESOLVED: Condvar = Condvar::new();
+
+/// Containers registered by vuinputd-oci-hook, the labels are the annotations of the container
+static REGISTERED: Mutex<BTreeMap<ContainerKey, ContainerIdentity>> = Mutex::new(BTreeMap::new());
+
+/// Takes `identity` for the container with root pid `root` instead of its cgroup
+pub fn register(root: Pid, identity: ContainerIdentity) {
+    let key = (root.as_raw(), get_namespace(root).mnt);
+    debug!("root pid {} registered as {}", root.as_raw(), identity);
+    IDENTITIES.lock().unwrap().remove(&key);
+    let mut registered = REGISTERED.lock().unwrap();
+    registered.retain(|(pid, _), _| fs::metadata(Pid::Pid(*pid).path()).is_ok());
+    registered.insert(key, identity);
+}
+
+/// Forgets the registration of a stopped container, returns how many root pids it had
+pub fn unregister(id: &str) -> usize {
+    IDENTITIES.lock().unwrap().retain(|_, lookup| match lookup {
+        Lookup::Done(Some(identity)) => identity.id != id,
+        _ => true,
+    });
+    let mut registered = REGISTERED.lock().unwrap();
+    let before = registered.len();
+    registered.retain(|_, identity| identity.id != id);
+    before - registered.len()
+}
+
+/// The container of the process with root pid `root`, None for processes of the host and
+/// for engines that are not known
+pub fn identify(root: Pid, mnt: Option<u64>) -> Option<Arc<ContainerIdentity>> {
+    let key = (root.as_raw(), mnt);
+    let deadline = Instant::now() + RESOLVE_TIMEOUT;
+    let mut identities = IDENTITIES.lock().unwrap();
+    match identities.get(&key) {
+        Some(Lookup::Done(identity)) => return identity.clone(),
+        Some(Lookup::Running) => {}
+        None => {
+            identities.insert(key, Lookup::Running);
+            let spawned = thread::Builder::new()
+                .name("identify".to_string())
+                .spawn(move || look_up(root, key));
+            if let Err(e) = spawned {
+                debug!("root pid {} is looked up in place: {}", root.as_raw(), e);
+                drop(identities);
+                look_up(root, key);
+                identities = IDENTITIES.lock().unwrap();
+            }
+        }
+    }
+    loop {
+        if let Some(Lookup::Done(identity)) = identities.get(&key) {
+            return identity.clone();
+        }
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        identities = RESOLVED.wait_timeout(identities, left).unwrap().0;
+    }
+    drop(identities);
+    debug!(
+        "the engine of root pid {} takes too long, going on without it",
+        root.as_raw()
+    );
+    let registered = REGISTERED.lock().unwrap().get(&key).cloned();
+    from_registration_or_cgroup(root, registered).map(Arc::new)
+}
+
+/// Resolves the container and stores it for the opens that wait for it
+fn look_up(root: Pid, key: ContainerKey) {
+    let registered = REGISTERED.lock().unwrap().get(&key).cloned();
+    let identity = resolve(root, registered).map(Arc::new);
+    match &identity {
+        Some(identity) => debug!("root pid {} belongs to {}", root.as_raw(), identity),
+        None => debug!("root pid {} belongs to no known container", root.as_raw()),
+    }
+    let mut identities = IDENTITIES.lock().unwrap();
+    // a registration in the meantime has removed the lookup, it is started again
+    if let Some(lookup @ Lookup::Running) = identities.get_mut(&key) {
+        *lookup = Lookup::Done(identity);
+    }
+    // forget the containers that are gone, their pids may come back
+    identities.retain(|(pid, _), _| fs::metadata(Pid::Pid(*pid).path()).is_ok());
+    RESOLVED.notify_all();
+}
+
+/// What is known without asking the engine
+fn from_registration_or_cgroup(
+    root: Pid,
+    registered: Option<ContainerIdentity>,
+) -> Option<ContainerIdentity> {
+    match registered {
+        Some(identity) => Some(ContainerIdentity {
+            verified: true,
+            ..identity
+        }),
+        None => parse_cgroup(&fs::read_to_string(format!("{}/cgroup", root.path())).ok()?),
+    }
+}
+
+fn resolve(root: Pid, registered: Option<ContainerIdentity>) -> Option<ContainerIdentity> {
+    let mut identity = from_registration_or_cgroup(root, registered)?;
+    let inspected = match identity.engine {
+        Engine::Docker => ask_engine(&[host_path("/run/docker.sock")], &identity.id),
+        Engine::Podman => {
+            let mut sockets = vec![host_path("/run/podman/podman.sock")];
+            if let Ok(metadata) = fs::metadata(root.path()) {
+                sockets.push(host_path(&format!(
+                    "/run/user/{}/podman/podman.sock",
+                    metadata.uid()
+                )));
+            }
+            ask_engine(&sockets, &identity.id)
+        }
+        Engine::Nspawn => is_machine_leader(&identity.id, root).then(|| Inspected {
+            name: identity.id.clone(),
+            labels: BTreeMap::new(),
+            pid: Some(root.as_raw()),
+        }),
+        Engine::Lxc => Some(Inspected {
+            name: identity.id.clone(),
+            labels: BTreeMap::new(),
+            pid: None,
+        }),
+    };
+    if let Some(inspected) = inspected {
+        identity.verified |= inspected.pid == Some(root.as_raw());
+        identity.name = Some(inspected.name);
+        // the labels of the engine win over annotations of the same name
+        identity.labels.extend(inspected.labels);
+    }
+    if !identity.verified {
+        debug!(
+            "root pid {} is not confirmed to be {}, the container-policy rules don't apply",
+            root.as_raw(),
+            identity
+        );
+    }
+    Some(identity)
+}
+
+/// The engine and id of the outermost container in the content of /proc/<pid>/cgroup
+fn parse_cgroup(cgroup: &str) -> Option<ContainerIdentity> {
+    // cgroup v2 has a single line "0::<path>", v1 one per hierarchy, all with the same leaf
+    cgroup.lines().find_map(|line| {
+        let dirs: Vec<&str> = line.splitn(3, ':').nth(2)?.split('/').collect();
+        (0..dirs.len())
+            .find_map(|i| parse_cgroup_dir(dirs[i], i.checked_sub(1).map(|parent| dirs[parent])))
+    })
+}
+
+fn parse_cgroup_dir(dir: &str, parent: Option<&str>) -> Option<ContainerIdentity> {
+    let identity = |engine, id: &str| {
+        Some(ContainerIdentity {
+            engine,
+            id: id.to_string(),
+            name: None,
+            labels: BTreeMap::new(),
+            verified: false,
+        })
+    };
+    if let Some(id) = dir
+        .strip_prefix("docker-")
+        .and_then(|d| d.strip_suffix(".scope"))
+    {
+        return identity(Engine::Docker, id);
+    }
+    if let Some(id) = dir.strip_prefix("libpod-") {
+        let id = id.strip_suffix(".scope").unwrap_or(id);
+        // libpod-conmon-<id>.scope is the monitor of the container, not the container
+        return is_container_id(id).then(|| identity(Engine::Podman, id))?;
+    }
+    if is_container_id(dir) && parent == Some("docker") {
+        // cgroupfs driver of docker
+        return identity(Engine::Docker, dir);
+    }
+    if let Some(name) = dir
+        .strip_prefix("machine-")
+        .and_then(|d| d.strip_suffix(".scope"))
+    {
+        return identity(Engine::Nspawn, &unescape_unit_name(name));
+    }
+    if let Some(name) = dir.strip_prefix("lxc.payload.") {
+        // only root of the host creates cgroups at the top
+        let mut lxc = identity(Engine::Lxc, name)?;
+        lxc.verified = parent == Some("");
+        return Some(lxc);
+    }
+    None
+}
+
+fn is_container_id(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Undoes the escaping of systemd-escape, e.g. my\x2dbox to my-box
+fn unescape_unit_name(name: &str) -> String {
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut rest = name.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = tail
+            .strip_prefix(b"x")
+            .and_then(|hex| hex.get(..2))
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (byte, escaped) {
+            (b'\\', Some(decoded)) => {
+                bytes.push(decoded);
+                rest = &tail[3..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// What the engine tells about a container
+#[derive(Debug, PartialEq, Eq)]
+struct Inspected {
+    name: String,
+    labels: BTreeMap<String, String>,
+    /// The pid of its first process on the host (State.Pid), 0 and None if it is not running
+    pid: Option<u32>,
+}
+
+/// Asks the first API socket that answers for the name of the container
+fn ask_engine(sockets: &[String], id: &str) -> Option<Inspected> {
+    sockets.iter().find_map(|socket| match inspect(socket, id) {
+        Ok(inspected) => inspected,
+        Err(e) => {
+            debug!("no name of container {} from {}: {}", id, socket, e);
+            None
+        }
+    })
+}
+
+fn inspect(socket: &str, id: &str) -> io::Result<Option<Inspected>> {
+    let deadline = Instant::now() + SOCKET_TIMEOUT;
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_write_timeout(Some(SOCKET_TIMEOUT))?;
+    // HTTP/1.0, so that the body is neither chunked nor kept open
+    write!(
+        stream,
+        "GET /containers/{}/json HTTP/1.0\r\nHost: localhost\r\n\r\n",
+        id
+    )?;
+    let response = read_until(&stream, deadline)?;
+    Ok(parse_inspect_response(&response))
+}
+
+/// Reads the whole response, unless it takes longer than until `deadline` or is larger than
+/// MAX_RESPONSE_SIZE
+fn read_until(stream: &UnixStream, deadline: Instant) -> io::Result<Vec<u8>> {
+    let mut response = Vec::new();
+    let mut limited = stream.take(MAX_RESPONSE_SIZE + 1);
+    let mut chunk = [0u8; 8192];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        stream.set_read_timeout(Some(left))?;
+        match limited.read(&mut chunk)? {
+            0 => break,
+            read => response.extend_from_slice(&chunk[..read]),
+        }
+    }
+    if response.len() as u64 > MAX_RESPONSE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("the answer is larger than {} bytes", MAX_RESPONSE_SIZE),
+        ));
+    }
+    Ok(response)
+}
+
+/// The name and labels in the answer to /containers/<id>/json, docker and podman prefix the
+/// name with /
+fn parse_inspect_response(response: &[u8]) -> Option<Inspected> {
+    let response = std::str::from_utf8(response).ok()?;
+    let (head, body) = response.split_once("\r\n\r\n")?;
+    if head.split(' ').nth(1) != Some("200") {
+        return None;
+    }
+    let json: serde_json::Value = serde_json::from_str(body).ok()?;
+    let name = json.get("Name")?.as_str()?.trim_start_matches('/');
+    if name.is_empty() {
+        return None;
+    }
+    // null if the container has no labels
+    let labels = json
+        .pointer("/Config/Labels")
+        .and_then(|labels| labels.as_object())
+        .into_iter()
+        .flatten()
+        .filter_map(|(key, value)| Some((key.clone(), value.as_str()?.to_string())))
+        .collect();
+    let pid = json
+        .pointer("/State/Pid")
+        .and_then(|pid| pid.as_u64())
+        .and_then(|pid| u32::try_from(pid).ok())
+        .filter(|pid| *pid != 0);
+    Some(Inspected {
+        name: name.to_string(),
+        labels,
+        pid,
+    })
+}
+
+/// Whether machined has registered a machine `name` led by `root`
+fn is_machine_leader(name: &str, root: Pid) -> bool {
+    let machine = fs::read_to_string(host_path(&format!("/run/systemd/machines/{}", name)));
+    machine.is_ok_and(|machine| {
+        machine
+            .lines()
+            .filter_map(|line| line.strip_prefix("LEADER="))
+            .any(|leader| leader.trim() == root.to_string_rep())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "3f2a9c1b7d4e8f60a1b2c3d4e5f60718293a4b5c6d7e8f901122334455667788";
+
+    fn parsed(cgroup: &str) -> Option<(Engine, String)> {
+        parse_cgroup(cgroup).map(|identity| (identity.engine, identity.id))
+    }
+
+    #[test]
+    fn finds_the_engine_in_the_cgroup() {
+        let id = ID;
+        assert_eq!(
+            parsed(&format!("0::/system.slice/docker-{}.scope\n", id)),
+            Some((Engine::Docker, id.to_string()))
+        );
+        assert_eq!(
+            parsed(&format!("0::/docker/{}\n", id)),
+            Some((Engine::Docker, id.to_string()))
+        );
+        assert_eq!(
+            parsed(&format!(
+                "0::/user.slice/user-1000.slice/user@1000.service/user.slice/libpod-{}.scope/container\n",
+                id
+            )),
+            Some((Engine::Podman, id.to_string()))
+        );
+        // cgroup v1, the container is the leaf of every hierarchy
+        assert_eq!(
+            parsed(&format!(
+                "12:pids:/machine.slice/libpod-{}.scope\n1:name=systemd:/machine.slice/libpod-{}.scope\n",
+                id, id
+            )),
+            Some((Engine::Podman, id.to_string()))
+        );
+        assert_eq!(
+            parsed("0::/machine.slice/machine-my\\x2dbox.scope/payload\n"),
+            Some((Engine::Nspawn, "my-box".to_string()))
+        );
+        assert_eq!(
+            parsed("0::/lxc.payload.steam/init.scope\n"),
+            Some((Engine::Lxc, "steam".to_string()))
+        );
+        assert_eq!(
+            parsed(&format!("0::/machine.slice/libpod-conmon-{}.scope\n", id)),
+            None
+        );
+        assert_eq!(
+            parsed("0::/user.slice/user-1000.slice/session-2.scope\n"),
+            None
+        );
+
+        // the cgroups below a container are its own, the outermost container counts
+        assert_eq!(
+            parsed(&format!(
+                "0::/machine.slice/machine-gamebox.scope/payload/system.slice/docker-{}.scope\n",
+                id
+            )),
+            Some((Engine::Nspawn, "gamebox".to_string()))
+        );
+        let lxc = parse_cgroup("0::/lxc.payload.steam/init.scope\n").unwrap();
+        assert!(lxc.verified);
+        let nested =
+            parse_cgroup("0::/user.slice/user-1000.slice/user@1000.service/lxc.payload.steam\n")
+                .unwrap();
+        assert!(!nested.verified);
+        // the engine has to confirm these
+        assert!(
+            !parse_cgroup(&format!("0::/system.slice/docker-{}.scope\n", id))
+                .unwrap()
+                .verified
+        );
+    }
+
+    #[test]
+    fn reads_the_name_from_the_engine() {
+        let ok = b"HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n{\"Id\":\"3f2a\",\"Name\":\"/steam\",\"State\":{\"Pid\":4242},\"Config\":{\"Labels\":{\"com.example.trusted\":\"yes\"}}}";
+        assert_eq!(
+            parse_inspect_response(ok),
+            Some(Inspected {
+                name: "steam".to_string(),
+                labels: BTreeMap::from([("com.example.trusted".to_string(), "yes".to_string())]),
+                pid: Some(4242),
+            })
+        );
+        let unlabeled =
+            b"HTTP/1.0 200 OK\r\n\r\n{\"Name\":\"/steam\",\"Config\":{\"Labels\":null}}";
+        assert_eq!(
+            parse_inspect_response(unlabeled).map(|inspected| (inspected.labels, inspected.pid)),
+            Some((BTreeMap::new(), None))
+        );
+        let missing = b"HTTP/1.0 404 Not Found\r\n\r\n{\"message\":\"no such container\"}";
+        assert_eq!(parse_inspect_response(missing), None);
+
+        let identity = ContainerIdentity {
+            engine: Engine::Docker,
+            id: ID.to_string(),
+            name: Some("steam".to_string()),
+            labels: BTreeMap::new(),
+            verified: true,
+        };
+        assert_eq!(identity.to_string(), "docker steam (3f2a9c1b7d4e)");
+        let unnamed = ContainerIdentity {
+            name: None,
+            ..identity
+        };
+        assert_eq!(unnamed.to_string(), "docker 3f2a9c1b7d4e");
+    }
+
+    #[test]
+    fn registered_containers_need_no_cgroup() {
+        let root = Pid::Pid(std::process::id());
+        let mnt = get_namespace(root).mnt;
+        register(
+            root,
+            ContainerIdentity {
+                engine: Engine::Lxc,
+                id: "registered".to_string(),
+                name: None,
+                labels: BTreeMap::from([("org.vuinputd.enable".to_string(), "true".to_string())]),
+                verified: false,
+            },
+        );
+        let identity = identify(root, mnt).unwrap();
+        assert!(identity.verified);
+        assert_eq!(identity.to_string(), "lxc registered");
+        assert_eq!(identity.labels["org.vuinputd.enable"], "true");
+        assert_eq!(unregister("registered"), 1);
+        assert_eq!(unregister("registered"), 0);
+    }
+}
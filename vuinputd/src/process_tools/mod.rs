@@ -15,7 +15,7 @@ use std::{
     },
     path::Path,
     process::Command,
-    sync::OnceLock,
+    sync::{Arc, OnceLock},
 };
 
 use anyhow::anyhow;
@@ -23,10 +23,16 @@ use std::io;
 
 use crate::{
     actions::action::Action,
+    cuse_device::vuinput_write::compat_uses_64bit_time,
     global_config::{get_device_owner, DeviceOwner},
+    host_root::{host_path, host_root},
+    process_tools::container_identity::ContainerIdentity,
 };
 
+pub mod container_fs;
+pub mod container_identity;
 pub mod ns_fscreds;
+pub mod pid_translation;
 
 pub static SELF_NAMESPACES: OnceLock<Namespaces> = OnceLock::new();
 
@@ -36,19 +42,49 @@ pub enum Pid {
 }
 
 impl Pid {
+    pub fn as_raw(&self) -> u32 {
+        let Pid::Pid(val) = self;
+        *val
+    }
+    /// The directory of the process in /proc, everything about a process is read from there
     pub fn path(&self) -> String {
-        match self {
-            Pid::Pid(pid_no) => format!("/proc/{}", pid_no),
-        }
+        host_path(&format!("/proc/{}", self.as_raw()))
     }
     pub fn to_string_rep(&self) -> String {
-        let Pid::Pid(val) = self;
-        val.to_string()
+        self.as_raw().to_string()
     }
 }
-enum PidOrSelf {
-    Pid(u32),
-    SelfPid,
+
+/// A process, told apart from a later one with the same pid by the time it started. State that
+/// vuinputd keeps about a container is keyed by the instance of its root process, so that it
+/// does not pass on to the next container that gets the pid.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+pub struct ProcessInstance {
+    pub pid: u32,
+    /// Field 22 of /proc/<pid>/stat, in clock ticks since boot
+    pub start_time: u64,
+}
+
+impl ProcessInstance {
+    /// None, if the process does not exist (anymore)
+    pub fn of(pid: Pid) -> Option<ProcessInstance> {
+        let stat = fs::read_to_string(format!("{}/stat", pid.path())).ok()?;
+        Some(ProcessInstance {
+            pid: pid.as_raw(),
+            start_time: parse_start_time(&stat)?,
+        })
+    }
+
+    pub fn is_alive(&self) -> bool {
+        ProcessInstance::of(Pid::Pid(self.pid)) == Some(*self)
+    }
+}
+
+/// The comm in parentheses may contain spaces and parentheses itself, so the fields are
+/// counted from the last ')', which is followed by field 3 (state).
+fn parse_start_time(stat: &str) -> Option<u64> {
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
 }
 
 #[derive(Debug, Default, Clone, Eq, PartialEq, Hash)]
@@ -65,33 +101,58 @@ pub struct Namespaces {
     pub time_for_children: Option<u64>,
 }
 
-/// Returns true if the process with `pid` is a 32-bit (compat) process. None, if unsure.
+/// Returns true if the process with `pid` sends and receives input events in the compat
+/// layout, i.e. is a 32-bit process on a 64-bit host. None, if unsure.
+///
+/// The kernel decides per syscall (in_compat_syscall), which follows from the ELF class of the
+/// binary. The personality (e.g. setarch linux32) only changes what uname reports, so it is not
+/// considered.
 pub fn is_compat_process(pid: Pid) -> Option<bool> {
-    match pid {
-        Pid::Pid(pid) => {
-            const EI_CLASS: usize = 4;
-            const ELFCLASS32: u8 = 1;
-            const ELFCLASS64: u8 = 2;
-
-            let exe_path = format!("/proc/{}/exe", pid);
-            let mut buf = [0u8; 5];
-
-            match File::open(&exe_path).and_then(|mut f| f.read_exact(&mut buf)) {
-                Ok(()) => {
-                    // ELF magic check
-                    if &buf[0..4] != b"\x7FELF" {
-                        return None;
-                    }
-                    match buf[EI_CLASS] {
-                        ELFCLASS32 => Some(true),
-                        ELFCLASS64 => Some(false),
-                        _ => None,
-                    }
-                }
-                Err(_) => None,
-            }
-        }
+    if cfg!(target_pointer_width = "32") {
+        // vuinputd uses the 32-bit layout itself, and 64-bit processes can't run
+        return Some(false);
     }
+    let mut header = [0u8; ELF_HEADER_PREFIX];
+    File::open(format!("{}/exe", pid.path()))
+        .and_then(|mut f| f.read_exact(&mut header))
+        .ok()?;
+    uses_compat_layout(&header)
+}
+
+/// Whether a binary with this header uses input_event_compat on a 64-bit host
+fn uses_compat_layout(header: &[u8; ELF_HEADER_PREFIX]) -> Option<bool> {
+    let (is_32bit, e_machine) = parse_elf_header(header)?;
+    Some(is_32bit && !compat_uses_64bit_time(e_machine))
+}
+
+// e_ident (16 bytes), e_type and e_machine (2 bytes each)
+const ELF_HEADER_PREFIX: usize = 20;
+
+/// Returns whether the binary is a 32-bit one, together with its machine (e_machine).
+fn parse_elf_header(header: &[u8; ELF_HEADER_PREFIX]) -> Option<(bool, u16)> {
+    const EI_CLASS: usize = 4;
+    const EI_DATA: usize = 5;
+    const ELFCLASS32: u8 = 1;
+    const ELFCLASS64: u8 = 2;
+    const ELFDATA2LSB: u8 = 1;
+    const ELFDATA2MSB: u8 = 2;
+
+    // ELF magic check
+    if &header[0..4] != b"\x7FELF" {
+        return None;
+    }
+    let is_32bit = match header[EI_CLASS] {
+        ELFCLASS32 => true,
+        ELFCLASS64 => false,
+        _ => return None,
+    };
+    let e_machine = [header[18], header[19]];
+    let e_machine = match header[EI_DATA] {
+        ELFDATA2LSB => u16::from_le_bytes(e_machine),
+        ELFDATA2MSB => u16::from_be_bytes(e_machine),
+        _ => return None,
+    };
+    Some((is_32bit, e_machine))
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
@@ -100,6 +161,8 @@ pub struct RequestingProcess {
     pub pid_requestor_root: Pid,
     pub namespaces: Namespaces,
     pub is_compat: bool,
+    /// The container engine and name, None on the host or if the engine is not known
+    pub identity: Option<Arc<ContainerIdentity>>,
 }
 
 impl Namespaces {
@@ -108,108 +171,122 @@ impl Namespaces {
     }
 }
 
-impl RequestingProcess {
-    pub fn equal_mnt_and_net(&self, other: &RequestingProcess) -> bool {
-        self.namespaces.equal_mnt_and_net(&other.namespaces)
+impl Namespaces {
+    pub fn entries(&self) -> [(&'static str, Option<u64>); 10] {
+        [
+            ("net", self.net),
+            ("uts", self.uts),
+            ("ipc", self.ipc),
+            ("pid", self.pid),
+            ("pid_for_children", self.pid_for_children),
+            ("user", self.user),
+            ("mnt", self.mnt),
+            ("cgroup", self.cgroup),
+            ("time", self.time),
+            ("time_for_children", self.time_for_children),
+        ]
     }
 
-    pub fn equal_mnt_and_net_ns(&self, other: &Namespaces) -> bool {
-        self.namespaces.equal_mnt_and_net(&other)
+    fn set(&mut self, name: &str, inode: u64) {
+        let field = match name {
+            "net" => &mut self.net,
+            "uts" => &mut self.uts,
+            "ipc" => &mut self.ipc,
+            "pid" => &mut self.pid,
+            "pid_for_children" => &mut self.pid_for_children,
+            "user" => &mut self.user,
+            "mnt" => &mut self.mnt,
+            "cgroup" => &mut self.cgroup,
+            "time" => &mut self.time,
+            "time_for_children" => &mut self.time_for_children,
+            _ => return,
+        };
+        *field = Some(inode);
+    }
+}
+
+/// e.g. "net:4026531840, uts:4026531838, ..."
+impl std::fmt::Display for Namespaces {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut first = true;
+        for (name, inode) in self.entries() {
+            if let Some(inode) = inode {
+                if !first {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}:{}", name, inode)?;
+                first = false;
+            }
+        }
+        Ok(())
     }
 }
 
 impl std::fmt::Display for RequestingProcess {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "Namespaces:")?;
-        writeln!(f, "  net:  {:?}", self.namespaces.net)?;
-        writeln!(f, "  uts:  {:?}", self.namespaces.uts)?;
-        writeln!(f, "  ipc:  {:?}", self.namespaces.ipc)?;
-        writeln!(f, "  pid:  {:?}", self.namespaces.pid)?;
-        writeln!(
+        write!(
             f,
-            "  pid_for_children:  {:?}",
-            self.namespaces.pid_for_children
-        )?;
-        writeln!(f, "  user: {:?}", self.namespaces.user)?;
-        writeln!(f, "  mnt:  {:?}", self.namespaces.mnt)?;
-        writeln!(f, "  cgroup:  {:?}", self.namespaces.cgroup)?;
-        writeln!(f, "  time:  {:?}", self.namespaces.time)?;
-        writeln!(
-            f,
-            "  time_for_children:  {:?}",
-            self.namespaces.time_for_children
+            "pid {} (root pid {}{}), namespaces {}",
+            self.pid_requestor.to_string_rep(),
+            self.pid_requestor_root.to_string_rep(),
+            if self.is_compat { ", 32 bit" } else { "" },
+            self.namespaces
         )?;
+        if let Some(identity) = &self.identity {
+            write!(f, ", {}", identity)?;
+        }
         Ok(())
     }
 }
 
 pub fn get_self_namespace() -> Namespaces {
-    get_namespace_of_pid_or_self(PidOrSelf::SelfPid)
+    read_namespaces("/proc/self")
 }
 
 pub fn get_namespace(pid: Pid) -> Namespaces {
-    let Pid::Pid(pid) = pid;
-    get_namespace_of_pid_or_self(PidOrSelf::Pid(pid))
+    read_namespaces(&pid.path())
 }
 
-fn get_namespace_of_pid_or_self(pid_or_self: PidOrSelf) -> Namespaces {
-    let pid: String = match pid_or_self {
-        PidOrSelf::Pid(pid) => pid.to_string(),
-        PidOrSelf::SelfPid => "self".to_string(),
-    };
-    let nspath = format!("/proc/{}/ns", pid);
-
-    let mut ns = Namespaces {
-        net: None,
-        uts: None,
-        ipc: None,
-        pid: None,
-        pid_for_children: None,
-        user: None,
-        mnt: None,
-        cgroup: None,
-        time: None,
-        time_for_children: None,
+/// Namespaces that can't be read (e.g. because the process is gone already) stay None
+fn read_namespaces(proc_dir: &str) -> Namespaces {
+    let mut ns = Namespaces::default();
+    let entries = match fs::read_dir(format!("{}/ns", proc_dir)) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("could not read the namespaces of {}: {}", proc_dir, e);
+            return ns;
+        }
     };
-
-    for entry in fs::read_dir(&nspath).expect("proc not found") {
-        let entry = entry.expect("`msg`");
-        let link = fs::read_link(entry.path()).expect("problem parsing inode");
-        let link_str = link.to_string_lossy();
-        if let (Some(start), Some(end)) = (link_str.find('['), link_str.find(']')) {
-            if let Ok(inode) = link_str[start + 1..end].parse::<u64>() {
-                match entry.file_name().into_string().unwrap_or_default().as_str() {
-                    "net" => ns.net = Some(inode),
-                    "uts" => ns.uts = Some(inode),
-                    "ipc" => ns.ipc = Some(inode),
-                    "pid" => ns.pid = Some(inode),
-                    "pid_for_children" => ns.pid_for_children = Some(inode),
-                    "user" => ns.user = Some(inode),
-                    "mnt" => ns.mnt = Some(inode),
-                    "cgroup" => ns.cgroup = Some(inode),
-                    "time" => ns.time = Some(inode),
-                    "time_for_children" => ns.time_for_children = Some(inode),
-                    _ => (),
+    for entry in entries.flatten() {
+        match fs::read_link(entry.path()) {
+            Ok(link) => {
+                if let Some(inode) = parse_ns_link(&link.to_string_lossy()) {
+                    ns.set(&entry.file_name().to_string_lossy(), inode);
                 }
             }
+            Err(e) => debug!("could not read {}: {}", entry.path().display(), e),
         }
     }
     ns
 }
 
+/// Extracts the inode of a link like "net:[4026531840]"
+fn parse_ns_link(link: &str) -> Option<u64> {
+    let start = link.find('[')?;
+    let end = link.find(']')?;
+    link.get(start + 1..end)?.parse().ok()
+}
+
 fn get_ppid(pid: Pid) -> Option<Pid> {
-    let content = match pid {
-        Pid::Pid(pid) => fs::read_to_string(format!("/proc/{}/status", pid)).ok()?,
-    };
-    let ppid = content
+    let status = fs::read_to_string(format!("{}/status", pid.path())).ok()?;
+    parse_ppid(&status).map(Pid::Pid)
+}
+
+fn parse_ppid(status: &str) -> Option<u32> {
+    status
         .lines()
-        .find(|line| line.starts_with("PPid:"))
-        .and_then(|line| line.split_whitespace().nth(1))
-        .and_then(|ppid| ppid.parse::<u32>().ok());
-    match ppid {
-        None => None,
-        Some(ppid) => Some(Pid::Pid(ppid)),
-    }
+        .find_map(|line| line.strip_prefix("PPid:"))
+        .and_then(|ppid| ppid.trim().parse().ok())
 }
 
 pub fn get_requesting_process(pid: Pid) -> RequestingProcess {
@@ -217,11 +294,14 @@ pub fn get_requesting_process(pid: Pid) -> RequestingProcess {
         Pid::Pid(_) => {
             let is_compat = match is_compat_process(pid) {
                 Some(false) => {
-                    debug!("identified process {} as 64 bit process", pid.path());
+                    debug!("identified process {} as native process", pid.path());
                     false
                 }
                 Some(true) => {
-                    debug!("identified process {} as 32 bit process", pid.path());
+                    debug!(
+                        "identified process {} as 32 bit (compat) process",
+                        pid.path()
+                    );
                     true
                 }
                 None => {
@@ -256,11 +336,19 @@ pub fn get_requesting_process(pid: Pid) -> RequestingProcess {
                 pid.path()
             );
 
+            let in_container = SELF_NAMESPACES
+                .get()
+                .is_some_and(|own| !own.equal_mnt_and_net(&nsinodes));
+            let identity = in_container
+                .then(|| container_identity::identify(ppid, nsinodes.mnt))
+                .flatten();
+
             RequestingProcess {
                 pid_requestor: pid,
                 pid_requestor_root: ppid,
                 namespaces: nsinodes,
                 is_compat: is_compat,
+                identity,
             }
         }
     }
@@ -305,6 +393,9 @@ pub fn start_action(
         if enter_user_ns {
             cmd.arg("--enter-user-namespace");
         }
+        if let Some(root) = host_root() {
+            cmd.arg("--host-root").arg(root);
+        }
         cmd.pre_exec(|| {
             // Last resort, if the parent just is killed.
             libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
@@ -337,7 +428,7 @@ pub fn run_in_net_and_mnt_namespace(
         None
     };
 
-    let nspath = format!("/proc/{}/ns", target_pid);
+    let nspath = host_path(&format!("/proc/{}/ns", target_pid));
     let path: &Path = Path::new(&nspath);
     if !fs::exists(path).unwrap() {
         return Err(anyhow!("the root process of the container whose namespaces we want to enter does not exist anymore"));
@@ -403,3 +494,121 @@ pub fn check_permissions() -> Result<(), std::io::Error> {
         Ok(())
     })
 }
+
+pub const CAP_SYS_MODULE: u32 = 16;
+pub const CAP_SYS_ADMIN: u32 = 21;
+
+/// Checks whether the given capability is in the effective set of the vuinputd process
+pub fn has_effective_capability(capability: u32) -> Result<bool, std::io::Error> {
+    let status_file = fs::read_to_string("/proc/self/status")?;
+    let cap_eff = status_file
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "CapEff not found"))?;
+    let cap_eff = u64::from_str_radix(cap_eff.trim(), 16)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(cap_eff & (1 << capability) != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_start_time() {
+        let stat = "4711 (a) b (c)) S 1 4711 4711 0 -1 4194560 \
+                    1 0 0 0 0 0 0 0 20 0 1 0 123456 1000 100";
+        assert_eq!(parse_start_time(stat), Some(123456));
+        assert_eq!(parse_start_time("4711 (a) S 1"), None);
+
+        let own = ProcessInstance::of(Pid::Pid(std::process::id())).unwrap();
+        assert!(own.is_alive());
+        assert!(!ProcessInstance {
+            start_time: own.start_time + 1,
+            ..own
+        }
+        .is_alive());
+    }
+
+    #[test]
+    fn parses_proc_entries() {
+        assert_eq!(parse_ns_link("net:[4026531840]"), Some(4026531840));
+        assert_eq!(parse_ns_link("net:4026531840"), None);
+        assert_eq!(
+            parse_ppid("Name:\tbash\nPPid:\t4711\nTracerPid:\t0\n"),
+            Some(4711)
+        );
+        assert_eq!(parse_ppid("Name:\tbash\n"), None);
+    }
+
+    fn elf_header(class: u8, data: u8, e_machine: [u8; 2]) -> [u8; ELF_HEADER_PREFIX] {
+        let mut header = [0u8; ELF_HEADER_PREFIX];
+        header[0..4].copy_from_slice(b"\x7FELF");
+        header[4] = class;
+        header[5] = data;
+        header[18..20].copy_from_slice(&e_machine);
+        header
+    }
+
+    #[test]
+    fn parses_elf_headers() {
+        // i386, x86_64, x32 and arm (little endian)
+        assert_eq!(parse_elf_header(&elf_header(1, 1, [3, 0])), Some((true, 3)));
+        assert_eq!(
+            parse_elf_header(&elf_header(2, 1, [62, 0])),
+            Some((false, 62))
+        );
+        assert_eq!(
+            parse_elf_header(&elf_header(1, 1, [62, 0])),
+            Some((true, 62))
+        );
+        assert_eq!(
+            parse_elf_header(&elf_header(1, 1, [40, 0])),
+            Some((true, 40))
+        );
+        // s390 (big endian)
+        assert_eq!(
+            parse_elf_header(&elf_header(1, 2, [0, 22])),
+            Some((true, 22))
+        );
+        assert_eq!(parse_elf_header(&elf_header(3, 1, [3, 0])), None);
+        let mut script = [0u8; ELF_HEADER_PREFIX];
+        script[0..4].copy_from_slice(b"#!/b");
+        assert_eq!(parse_elf_header(&script), None);
+    }
+
+    #[test]
+    fn decides_the_layout_of_clients() {
+        // arm (armhf games on arm64) and i386 use the 16-byte layout
+        assert_eq!(uses_compat_layout(&elf_header(1, 1, [40, 0])), Some(true));
+        assert_eq!(uses_compat_layout(&elf_header(1, 1, [3, 0])), Some(true));
+        // aarch64 and x86_64 are native
+        assert_eq!(uses_compat_layout(&elf_header(2, 1, [183, 0])), Some(false));
+        assert_eq!(uses_compat_layout(&elf_header(2, 1, [62, 0])), Some(false));
+        // x32 only on x86_64 hosts
+        assert_eq!(
+            uses_compat_layout(&elf_header(1, 1, [62, 0])),
+            Some(!cfg!(target_arch = "x86_64"))
+        );
+        assert_eq!(uses_compat_layout(&[0u8; ELF_HEADER_PREFIX]), None);
+    }
+
+    #[test]
+    fn own_process() {
+        let own_pid = Pid::Pid(std::process::id());
+        let requesting_process = get_requesting_process(own_pid);
+        assert_eq!(requesting_process.pid_requestor, own_pid);
+        // also on 32-bit hosts, where vuinputd uses the same layout as its clients
+        assert!(!requesting_process.is_compat);
+    }
+
+    #[test]
+    fn namespaces_display() {
+        let namespaces = Namespaces {
+            net: Some(1),
+            mnt: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(namespaces.to_string(), "net:1, mnt:2");
+    }
+}
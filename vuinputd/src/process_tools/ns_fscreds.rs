@@ -15,8 +15,8 @@ struct IdMapEntry {
     pub length: u64,
 }
 
-fn parse_id_map(pid: u32, map_type: &str) -> io::Result<Vec<IdMapEntry>> {
-    let path = format!("/proc/{}/{}", pid, map_type);
+fn parse_id_map(pid: Pid, map_type: &str) -> io::Result<Vec<IdMapEntry>> {
+    let path = format!("{}/{}", pid.path(), map_type);
     let file = fs::File::open(&path)?;
     let reader = io::BufReader::new(file);
 
@@ -49,20 +49,18 @@ fn to_host_id(entries: &[IdMapEntry], inside_id: u64) -> Option<u64> {
 
 /// Returns the host UID that corresponds to `ns_uid` (e.g. 0) inside the container.
 pub fn get_uid_in_container(pid: Pid, ns_uid: u64) -> anyhow::Result<u32> {
-    let Pid::Pid(pid) = pid;
     let entries = parse_id_map(pid, "uid_map")?;
     to_host_id(&entries, ns_uid)
         .map(|id| id as u32)
-        .ok_or_else(|| anyhow::anyhow!("uid {} is not mapped in /proc/{}/uid_map", ns_uid, pid))
+        .ok_or_else(|| anyhow::anyhow!("uid {} is not mapped in {}/uid_map", ns_uid, pid.path()))
 }
 
 /// Returns the host GID that corresponds to `ns_gid` (e.g. 0) inside the container.
 pub fn get_gid_in_container(pid: Pid, ns_gid: u64) -> anyhow::Result<u32> {
-    let Pid::Pid(pid) = pid;
     let entries = parse_id_map(pid, "gid_map")?;
     to_host_id(&entries, ns_gid)
         .map(|id| id as u32)
-        .ok_or_else(|| anyhow::anyhow!("gid {} is not mapped in /proc/{}/gid_map", ns_gid, pid))
+        .ok_or_else(|| anyhow::anyhow!("gid {} is not mapped in {}/gid_map", ns_gid, pid.path()))
 }
 
 /// Switch filesystem UID/GID to the given host IDs.
@@ -130,8 +128,8 @@ mod tests {
     #[test]
     fn proc_self_uid_is_parseable() {
         let uid = unsafe { libc::getuid() } as u64;
-        let entries =
-            parse_id_map(std::process::id(), "uid_map").expect("failed to read /proc/self/uid_map");
+        let entries = parse_id_map(Pid::Pid(std::process::id()), "uid_map")
+            .expect("failed to read /proc/self/uid_map");
         assert!(
             to_host_id(&entries, uid).is_some(),
             "current uid {} not found in uid_map",
@@ -0,0 +1,194 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// The pid of a request (fuse_req_ctx) is the one in the PID namespace of vuinputd, but
+// everything about the process is read from the /proc below the host root (see host_root). If
+// both belong to different PID namespaces, /proc/<pid> is another process or none at all. The
+// pid is therefore translated into the PID namespace of that /proc:
+//  1. pidfd_open takes a reference to the process itself. The "Pid:" line of the fdinfo of the
+//     pidfd shows its pid in the PID namespace of the procfs the fdinfo is read from (5.6+).
+//  2. Without that line (or pidfd_open), the processes of the /proc are searched for the one
+//     whose "NSpid:" has the pid at the level of the PID namespace of vuinputd. NSpid lists the
+//     pids from the namespace of the procfs down to the one of the process.
+// Processes outside the namespace of vuinputd and its descendants are not visible to vuinputd
+// at all: their pid is 0.
+
+use std::fmt;
+use std::fs;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use log::debug;
+
+use crate::host_root::{host_path, host_root, is_in_host_pid_namespace};
+use crate::process_tools::Pid;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PidTranslationError {
+    /// The process is not in the PID namespace of vuinputd (or below), so the pid is 0
+    NotVisible,
+    /// The process exited before it could be translated
+    Gone(u32),
+    /// The process is not in the PID namespace of the /proc (or below)
+    NotInProc { pid: u32, proc_dir: String },
+}
+
+impl fmt::Display for PidTranslationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PidTranslationError::NotVisible => write!(
+                f,
+                "the process is not visible in the PID namespace of vuinputd, run vuinputd in the PID namespace of the host"
+            ),
+            PidTranslationError::Gone(pid) => write!(f, "process {} exited already", pid),
+            PidTranslationError::NotInProc { pid, proc_dir } => {
+                write!(f, "process {} has no pid in {}", pid, proc_dir)
+            }
+        }
+    }
+}
+
+/// Translates the pid of a request into the one of the /proc of the host
+pub fn translate_pid(pid: u32) -> Result<Pid, PidTranslationError> {
+    if pid == 0 {
+        return Err(PidTranslationError::NotVisible);
+    }
+    if host_root().is_none() || is_in_host_pid_namespace() != Some(false) {
+        return Ok(Pid::Pid(pid));
+    }
+    let host_pid = translate_in(&host_path("/proc"), pid)?;
+    debug!("pid {} is {} on the host", pid, host_pid);
+    Ok(Pid::Pid(host_pid))
+}
+
+/// Translates the pid (of the PID namespace of vuinputd) into the one of the given /proc
+fn translate_in(proc_dir: &str, pid: u32) -> Result<u32, PidTranslationError> {
+    let not_in_proc = || PidTranslationError::NotInProc {
+        pid,
+        proc_dir: proc_dir.to_string(),
+    };
+    let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if pidfd < 0 {
+        let e = std::io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::ESRCH) {
+            return Err(PidTranslationError::Gone(pid));
+        }
+        debug!("pidfd_open of {} failed ({}), searching NSpid", pid, e);
+        return search_nspid(proc_dir, pid).ok_or_else(not_in_proc);
+    }
+    // keeps the pid from being reused while it is translated
+    let pidfd = unsafe { OwnedFd::from_raw_fd(pidfd as i32) };
+    let fdinfo = format!("{}/self/fdinfo/{}", proc_dir, pidfd.as_raw_fd());
+    let fdinfo = fs::read_to_string(&fdinfo).unwrap_or_default();
+    match parse_fdinfo_pid(&fdinfo) {
+        Some(FdinfoPid::Pid(translated)) => Ok(translated),
+        Some(FdinfoPid::NotInNamespace) => Err(not_in_proc()),
+        Some(FdinfoPid::Exited) => Err(PidTranslationError::Gone(pid)),
+        None => {
+            debug!(
+                "no pid in the fdinfo of the pidfd of {}, searching NSpid",
+                pid
+            );
+            search_nspid(proc_dir, pid).ok_or_else(not_in_proc)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum FdinfoPid {
+    Pid(u32),
+    /// 0: the process is not in the PID namespace of the procfs
+    NotInNamespace,
+    /// -1: the process exited
+    Exited,
+}
+
+/// "Pid:\t4711" of the fdinfo of a pidfd
+fn parse_fdinfo_pid(fdinfo: &str) -> Option<FdinfoPid> {
+    let pid: i64 = fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("Pid:"))?
+        .trim()
+        .parse()
+        .ok()?;
+    match pid {
+        -1 => Some(FdinfoPid::Exited),
+        0 => Some(FdinfoPid::NotInNamespace),
+        pid => u32::try_from(pid).ok().map(FdinfoPid::Pid),
+    }
+}
+
+/// "NSpid:\t4711\t12" of /proc/<pid>/status, from the namespace of the procfs downwards
+fn parse_nspid(status: &str) -> Vec<u32> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("NSpid:"))
+        .map(|pids| {
+            pids.split_whitespace()
+                .filter_map(|pid| pid.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Whether NSpid shows `pid` in the PID namespace at `level` (0 is the one of the procfs)
+fn nspid_matches(nspid: &[u32], level: usize, pid: u32) -> bool {
+    nspid.get(level) == Some(&pid)
+}
+
+fn search_nspid(proc_dir: &str, pid: u32) -> Option<u32> {
+    // vuinputd itself is the last entry of its own NSpid
+    let own = parse_nspid(&fs::read_to_string(format!("{}/self/status", proc_dir)).ok()?);
+    let level = own.len().checked_sub(1)?;
+    fs::read_dir(proc_dir).ok()?.flatten().find_map(|entry| {
+        let candidate: u32 = entry.file_name().to_str()?.parse().ok()?;
+        let status = fs::read_to_string(entry.path().join("status")).ok()?;
+        nspid_matches(&parse_nspid(&status), level, pid).then_some(candidate)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn parses_proc_entries() {
+        let fdinfo =
+            "pos:\t0\nflags:\t02000002\nmnt_id:\t15\nino:\t1057\nPid:\t4711\nNSpid:\t4711\t12\n";
+        assert_eq!(parse_fdinfo_pid(fdinfo), Some(FdinfoPid::Pid(4711)));
+        assert_eq!(
+            parse_fdinfo_pid("Pid:\t0\n"),
+            Some(FdinfoPid::NotInNamespace)
+        );
+        assert_eq!(parse_fdinfo_pid("Pid:\t-1\n"), Some(FdinfoPid::Exited));
+        assert_eq!(parse_fdinfo_pid(""), None);
+
+        let status = "Name:\tgame\nPid:\t4711\nNSpid:\t4711\t12\t1\nNSpgid:\t4711\t12\t1\n";
+        assert_eq!(parse_nspid(status), vec![4711, 12, 1]);
+        assert_eq!(parse_nspid("Name:\tgame\n"), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn matches_the_level_of_vuinputd() {
+        // vuinputd in a container one level below the host sees the game as 12
+        let nspid = [4711, 12, 1];
+        assert!(nspid_matches(&nspid, 1, 12));
+        assert!(!nspid_matches(&nspid, 0, 12));
+        assert!(!nspid_matches(&nspid, 3, 12));
+        // the game is not below the namespace of vuinputd
+        assert!(!nspid_matches(&[4711], 1, 12));
+    }
+
+    #[test]
+    fn translates_within_the_own_namespace() {
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let translated = translate_in("/proc", child.id());
+        let searched = search_nspid("/proc", child.id());
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(translated, Ok(child.id()));
+        assert_eq!(searched, Some(child.id()));
+        assert_eq!(translate_pid(0), Err(PidTranslationError::NotVisible));
+    }
+}
@@ -0,0 +1,166 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// "vuinputd replay": creates a device like the one of an evemu recording and replays its
+// events with the original timing, e.g. to reproduce a bug report. With --container, the
+// process enters the namespaces of the container and uses its /dev/uinput, so the device
+// goes through the running vuinputd (device policy, node and udev data in the container) like
+// the one of any other client there.
+//
+// The device is destroyed at the end or on SIGINT/SIGTERM; otherwise its node would stay in
+// the container, as vuinputd removes it from within the namespaces of this process.
+
+use std::ffi::CStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::mem::size_of;
+use std::os::fd::AsRawFd;
+use std::os::raw::c_char;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use libc::{input_event, uinput_abs_setup, uinput_setup};
+use nix::errno::Errno;
+use uinput_ioctls::*;
+
+use crate::cuse_device::device_name::UINPUT_MAX_NAME_SIZE;
+use crate::cuse_device::reconnect::code_bit_ioctl;
+use crate::evemu::{self, Description, RecordedEvent};
+use crate::global_config::DeviceOwner;
+use crate::input_codes::{CodeName, TypeName};
+use crate::process_tools::run_in_net_and_mnt_namespace;
+use crate::signal_handling::{block_shutdown_signals, sleep_unless_shutdown_signal};
+
+const UINPUT_DEVICE: &str = "/dev/uinput";
+/// Gives udev and the applications time to pick the device up before the first event
+const WAIT_AFTER_CREATE: Duration = Duration::from_secs(1);
+
+pub fn run_replay(
+    desc: &Path,
+    events: &Path,
+    container: Option<&str>,
+    device_owner: &DeviceOwner,
+    enter_user_namespace: bool,
+) -> Result<(), String> {
+    let read =
+        |path: &Path| fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e));
+    let description =
+        evemu::parse_description(&read(desc)?).map_err(|e| format!("{}: {}", desc.display(), e))?;
+    let events =
+        evemu::parse_events(&read(events)?).map_err(|e| format!("{}: {}", events.display(), e))?;
+
+    block_shutdown_signals().map_err(|e| format!("failed to block SIGINT and SIGTERM: {}", e))?;
+    if let Some(container) = container {
+        run_in_net_and_mnt_namespace(container, device_owner, enter_user_namespace)
+            .map_err(|e| format!("failed to enter the namespaces of {}: {}", container, e))?;
+    }
+    let mut uinput = OpenOptions::new()
+        .write(true)
+        .open(UINPUT_DEVICE)
+        .map_err(|e| format!("{}: {}", UINPUT_DEVICE, e))?;
+    let sysname = unsafe { create_device(&uinput, &description) }?;
+    eprintln!(
+        "created \"{}\" ({}), replaying {} events",
+        description.name,
+        sysname,
+        events.len()
+    );
+
+    let result = match sleep_unless_shutdown_signal(WAIT_AFTER_CREATE) {
+        true => replay_events(&mut uinput, &events),
+        false => Ok(()),
+    };
+    if let Err(errno) = unsafe { ui_dev_destroy(uinput.as_raw_fd()) } {
+        eprintln!("UI_DEV_DESTROY failed: {}", errno);
+    }
+    result
+}
+
+/// Sets the device up like the description and creates it. Returns the sysname.
+unsafe fn create_device(uinput: &File, description: &Description) -> Result<String, String> {
+    let fd = uinput.as_raw_fd();
+    for ev_type in description.codes(0) {
+        ui_set_evbit(fd, ev_type.into())
+            .map_err(failed("UI_SET_EVBIT", TypeName(ev_type).to_string()))?;
+        let Some((ioctl, set_bit)) = code_bit_ioctl(ev_type) else {
+            continue;
+        };
+        for code in description.codes(ev_type) {
+            set_bit(fd, code.into()).map_err(failed(ioctl, CodeName(ev_type, code).to_string()))?;
+        }
+    }
+    for prop in description.properties() {
+        ui_set_propbit(fd, prop.into()).map_err(failed("UI_SET_PROPBIT", prop.to_string()))?;
+    }
+
+    let mut setup: uinput_setup = std::mem::zeroed();
+    setup.id.bustype = description.bustype;
+    setup.id.vendor = description.vendor;
+    setup.id.product = description.product;
+    setup.id.version = description.version;
+    for (target, byte) in setup
+        .name
+        .iter_mut()
+        .zip(description.name.bytes().take(UINPUT_MAX_NAME_SIZE - 1))
+    {
+        *target = byte as c_char;
+    }
+    ui_dev_setup(fd, &setup).map_err(failed("UI_DEV_SETUP", description.name.clone()))?;
+    for (code, absinfo) in &description.absinfo {
+        let mut abs_setup: uinput_abs_setup = std::mem::zeroed();
+        abs_setup.code = *code;
+        abs_setup.absinfo.minimum = absinfo.minimum;
+        abs_setup.absinfo.maximum = absinfo.maximum;
+        abs_setup.absinfo.fuzz = absinfo.fuzz;
+        abs_setup.absinfo.flat = absinfo.flat;
+        abs_setup.absinfo.resolution = absinfo.resolution;
+        ui_abs_setup(fd, &abs_setup)
+            .map_err(failed("UI_ABS_SETUP", CodeName(0x03, *code).to_string()))?;
+    }
+    ui_dev_create(fd).map_err(failed("UI_DEV_CREATE", description.name.clone()))?;
+
+    let mut sysname: [c_char; 64] = [0; 64];
+    ui_get_sysname(fd, sysname.as_mut_slice()).map_err(failed("UI_GET_SYSNAME", String::new()))?;
+    Ok(CStr::from_ptr(sysname.as_ptr())
+        .to_string_lossy()
+        .into_owned())
+}
+
+fn failed(ioctl: &'static str, what: String) -> impl FnOnce(Errno) -> String {
+    move |errno| format!("{} {} failed: {}", ioctl, what, errno)
+}
+
+/// Writes the events at the times of the recording, relative to the first one
+fn replay_events(uinput: &mut File, events: &[RecordedEvent]) -> Result<(), String> {
+    let Some(first) = events.first() else {
+        return Ok(());
+    };
+    let start = Instant::now();
+    for recorded in events {
+        let due = start + recorded.time.saturating_sub(first.time);
+        let now = Instant::now();
+        if due > now && !sleep_unless_shutdown_signal(due - now) {
+            eprintln!("interrupted, destroying the device");
+            return Ok(());
+        }
+        let mut event: input_event = unsafe { std::mem::zeroed() };
+        event.type_ = recorded.type_;
+        event.code = recorded.code;
+        event.value = recorded.value;
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                &event as *const input_event as *const u8,
+                size_of::<input_event>(),
+            )
+        };
+        uinput.write_all(bytes).map_err(|e| {
+            format!(
+                "writing {} failed: {}",
+                CodeName(event.type_, event.code),
+                e
+            )
+        })?;
+    }
+    Ok(())
+}
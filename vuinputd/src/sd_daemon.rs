@@ -0,0 +1,141 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// The parts of sd-daemon(3) vuinputd needs, without linking libsystemd: readiness and
+// watchdog notifications (sd_notify) and sockets passed by a socket unit (sd_listen_fds).
+// Outside of systemd, the environment variables are missing and all of this does nothing.
+
+use std::env;
+use std::io;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use log::{debug, warn};
+
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Sends a state like "READY=1" to the service manager. Returns false if vuinputd has not
+/// been started by systemd with Type=notify (or NotifyAccess=).
+pub fn notify(state: &str) -> io::Result<bool> {
+    let Some(socket) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    let socket = socket.to_string_lossy();
+    let addr = match socket.strip_prefix('@') {
+        Some(abstract_name) => SocketAddr::from_abstract_name(abstract_name.as_bytes())?,
+        None => SocketAddr::from_pathname(socket.as_ref())?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(true)
+}
+
+fn notify_or_warn(state: &str) {
+    match notify(state) {
+        Ok(true) => debug!("notified systemd: {}", state.replace('\n', " ")),
+        Ok(false) => {}
+        Err(e) => warn!("failed to notify systemd ({}): {}", state, e),
+    }
+}
+
+static PENDING_NODES: AtomicUsize = AtomicUsize::new(1);
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Number of CUSE nodes that have to be registered before vuinputd is ready
+pub fn expect_nodes(count: usize) {
+    PENDING_NODES.store(count, Ordering::SeqCst);
+}
+
+/// Called once the kernel has registered a CUSE node. Notifies systemd after the last one.
+pub fn node_ready(devname: &str) {
+    let previous = PENDING_NODES
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+        .unwrap_or(0);
+    if previous == 1 {
+        ready(&format!("Serving /dev/{}", devname));
+    }
+}
+
+pub fn ready(status: &str) {
+    READY.store(true, Ordering::SeqCst);
+    notify_or_warn(&format!("READY=1\nSTATUS={}", status));
+}
+
+/// Whether READY=1 has been sent (or would have been, outside of systemd) and vuinputd is not
+/// stopping yet
+pub fn is_ready() -> bool {
+    READY.load(Ordering::SeqCst)
+}
+
+pub fn stopping() {
+    READY.store(false, Ordering::SeqCst);
+    notify_or_warn("STOPPING=1\nSTATUS=Stopping");
+}
+
+pub fn watchdog() {
+    notify_or_warn("WATCHDOG=1");
+}
+
+/// The interval in which systemd expects WATCHDOG=1, None if WatchdogSec= is not set.
+pub fn watchdog_interval() -> Option<Duration> {
+    if !for_this_process("WATCHDOG_PID") {
+        return None;
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// A variable like LISTEN_PID or WATCHDOG_PID is either missing or names the process that
+/// is meant to use the rest of the variables, not e.g. a child that inherited them.
+fn for_this_process(pid_variable: &str) -> bool {
+    match env::var(pid_variable) {
+        Ok(pid) => pid.parse() == Ok(std::process::id()),
+        Err(_) => true,
+    }
+}
+
+/// Takes over the sockets passed by a socket unit (LISTEN_FDS), in the order of the unit.
+pub fn listen_fds() -> Vec<OwnedFd> {
+    if env::var_os("LISTEN_PID").is_none() || !for_this_process("LISTEN_PID") {
+        return Vec::new();
+    }
+    let count: RawFd = match env::var("LISTEN_FDS").ok().and_then(|n| n.parse().ok()) {
+        Some(count) => count,
+        None => return Vec::new(),
+    };
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(|fd| {
+            // the actions are run as child processes, which must not inherit the sockets
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            unsafe { OwnedFd::from_raw_fd(fd) }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn notifies_the_socket() {
+        let dir = std::env::temp_dir().join(format!("vuinputd-notify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify.sock");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        // no other test reads NOTIFY_SOCKET
+        env::set_var("NOTIFY_SOCKET", &path);
+        assert!(notify("READY=1\nSTATUS=Serving /dev/vuinput").unwrap());
+        env::remove_var("NOTIFY_SOCKET");
+        assert!(!notify("READY=1").unwrap());
+
+        let mut buffer = [0u8; 64];
+        let len = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"READY=1\nSTATUS=Serving /dev/vuinput");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,167 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Keeps containers from typing into the lock screen of the host (block-keys-when-locked).
+// The LockedHint of the logind sessions on the configured seat is polled with loginctl, like
+// desktop_notification uses notify-send instead of talking D-Bus itself. While the active
+// session of the seat is locked, the writes of keyboard keys are dropped. Releases still pass,
+// so that a key that is held when the screen locks does not stay pressed. Buttons of mice and
+// gamepads are not affected.
+
+use std::io;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use libc::input_event;
+use log::{info, warn};
+
+use crate::cuse_device::keystroke_privacy::is_sensitive;
+use crate::global_config::get_block_keys_when_locked;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+static LOCKED: AtomicBool = AtomicBool::new(false);
+
+/// Polls logind until vuinputd stops. The seat is looked up for each poll, so
+/// block-keys-when-locked can be changed by a reload.
+pub fn start() -> io::Result<()> {
+    thread::Builder::new()
+        .name("session-lock".to_string())
+        .spawn(|| {
+            let mut failing = false;
+            loop {
+                let locked = match get_block_keys_when_locked() {
+                    Some(seat) => match is_seat_locked(&seat) {
+                        Ok(locked) => {
+                            failing = false;
+                            locked
+                        }
+                        Err(e) => {
+                            if !failing {
+                                warn!("failed to query the lock state of {}: {}", seat, e);
+                                failing = true;
+                            }
+                            // keep the last known state until logind answers again
+                            LOCKED.load(Ordering::Relaxed)
+                        }
+                    },
+                    None => false,
+                };
+                if LOCKED.swap(locked, Ordering::Relaxed) != locked {
+                    if locked {
+                        info!(
+                            "the session is locked, keyboard keys of virtual devices are blocked"
+                        );
+                    } else {
+                        info!(
+                            "the session is unlocked, keyboard keys of virtual devices pass again"
+                        );
+                    }
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        })?;
+    Ok(())
+}
+
+/// Whether the event has to be dropped because the session is locked
+pub fn is_blocked(event: &input_event) -> bool {
+    blocks(LOCKED.load(Ordering::Relaxed), event)
+}
+
+/// Presses and repeats of keyboard keys are blocked while the session is `locked`
+fn blocks(locked: bool, event: &input_event) -> bool {
+    locked && event.value != 0 && is_sensitive(event.type_, event.code)
+}
+
+fn is_seat_locked(seat: &str) -> io::Result<bool> {
+    let sessions = loginctl(&["list-sessions", "--no-legend"])?;
+    let ids: Vec<&str> = sessions
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .collect();
+    if ids.is_empty() {
+        return Ok(false);
+    }
+    let mut args = vec![
+        "show-session",
+        "-p",
+        "Seat",
+        "-p",
+        "Active",
+        "-p",
+        "LockedHint",
+    ];
+    args.extend(ids);
+    Ok(is_locked(&loginctl(&args)?, seat))
+}
+
+fn loginctl(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("loginctl")
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "loginctl {} exited with {}",
+            args[0], output.status
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Parses the properties of `loginctl show-session`, one block per session. The seat counts as
+/// locked, if its active session is.
+fn is_locked(properties: &str, seat: &str) -> bool {
+    properties.split("\n\n").any(|session| {
+        let value = |name: &str| {
+            session.lines().find_map(|line| {
+                line.strip_prefix(name)
+                    .and_then(|line| line.strip_prefix('='))
+            })
+        };
+        value("Seat") == Some(seat)
+            && value("Active") == Some("yes")
+            && value("LockedHint") == Some("yes")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SESSIONS: &str = "Seat=seat0\nActive=no\nLockedHint=no\n\n\
+                            Seat=seat0\nActive=yes\nLockedHint=yes\n\n\
+                            Seat=\nActive=yes\nLockedHint=no\n";
+
+    #[test]
+    fn locked_if_the_active_session_of_the_seat_is() {
+        assert!(is_locked(SESSIONS, "seat0"));
+        assert!(!is_locked(SESSIONS, "seat1"));
+        assert!(!is_locked(
+            "Seat=seat0\nActive=no\nLockedHint=yes\n",
+            "seat0"
+        ));
+        assert!(!is_locked("", "seat0"));
+    }
+
+    #[test]
+    fn blocks_key_presses_only() {
+        let mut event: input_event = unsafe { std::mem::zeroed() };
+        event.type_ = 0x01; // EV_KEY
+        event.code = 30; // KEY_A
+        event.value = 1;
+        assert!(!blocks(false, &event));
+
+        assert!(blocks(true, &event));
+        event.value = 0;
+        assert!(!blocks(true, &event));
+        event.value = 1;
+        event.code = 0x110; // BTN_LEFT
+        assert!(!blocks(true, &event));
+    }
+}
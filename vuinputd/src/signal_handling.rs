@@ -0,0 +1,179 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// libfuse installs handlers for SIGHUP, SIGINT and SIGTERM that end the CUSE session. SIGHUP
+// is blocked before any other thread is started (threads inherit the mask), so it never reaches
+// these handlers. Instead, a dedicated thread picks it up with sigwait and schedules a reload.
+//
+// For SIGINT and SIGTERM, the handlers of libfuse stay in place, but get wrapped by one that
+// remembers the signal, so that the jobs can be shut down accordingly afterwards.
+//
+// With several device nodes (--device), there is one CUSE session per node, but libfuse only
+// ends the last one it has been set up with. SIGINT and SIGTERM are then blocked like SIGHUP
+// and the main thread waits for them, see session_manager.
+
+use libc::c_int;
+use log::{error, info};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::job_engine::job::ShutdownMode;
+use crate::job_engine::JOB_DISPATCHER;
+use crate::jobs::reload_config_job::ReloadConfigJob;
+
+fn sighup_set() -> SigSet {
+    let mut set = SigSet::empty();
+    set.add(Signal::SIGHUP);
+    set
+}
+
+/// Must be called before the first thread is spawned.
+pub fn block_sighup() -> nix::Result<()> {
+    sighup_set().thread_block()
+}
+
+pub fn spawn_reload_on_sighup(reload_job: ReloadConfigJob) -> std::io::Result<JoinHandle<()>> {
+    thread::Builder::new()
+        .name("sighup".to_string())
+        .spawn(move || {
+            let set = sighup_set();
+            loop {
+                match set.wait() {
+                    Ok(_) => {
+                        info!("SIGHUP received, reloading the configuration");
+                        JOB_DISPATCHER
+                            .get()
+                            .unwrap()
+                            .lock()
+                            .unwrap()
+                            .dispatch(Box::new(reload_job.clone()));
+                    }
+                    Err(e) => {
+                        error!("waiting for SIGHUP failed, reloading is disabled: {}", e);
+                        return;
+                    }
+                }
+            }
+        })
+}
+
+static SHUTDOWN_SIGNAL: AtomicI32 = AtomicI32::new(0);
+static LIBFUSE_SIGINT_HANDLER: AtomicUsize = AtomicUsize::new(0);
+static LIBFUSE_SIGTERM_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+fn libfuse_handler(signal: c_int) -> &'static AtomicUsize {
+    if signal == libc::SIGINT {
+        &LIBFUSE_SIGINT_HANDLER
+    } else {
+        &LIBFUSE_SIGTERM_HANDLER
+    }
+}
+
+extern "C" fn record_shutdown_signal(signal: c_int) {
+    // only async-signal-safe operations in here
+    SHUTDOWN_SIGNAL.store(signal, Ordering::SeqCst);
+    let handler = libfuse_handler(signal).load(Ordering::SeqCst);
+    if handler != 0 {
+        let handler: extern "C" fn(c_int) = unsafe { std::mem::transmute(handler) };
+        handler(signal);
+    }
+}
+
+/// Wraps the SIGINT and SIGTERM handlers of libfuse. Must be called after libfuse has
+/// installed them, i.e. from within the CUSE session.
+pub fn record_shutdown_signals() {
+    for signal in [Signal::SIGINT, Signal::SIGTERM] {
+        let action = SigAction::new(
+            SigHandler::Handler(record_shutdown_signal),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        match unsafe { sigaction(signal, &action) } {
+            Ok(previous) => {
+                if let SigHandler::Handler(handler) = previous.handler() {
+                    libfuse_handler(signal as c_int).store(handler as usize, Ordering::SeqCst);
+                }
+            }
+            Err(e) => error!("failed to install the handler for {}: {}", signal, e),
+        }
+    }
+}
+
+fn shutdown_set() -> SigSet {
+    let mut set = SigSet::empty();
+    set.add(Signal::SIGINT);
+    set.add(Signal::SIGTERM);
+    set
+}
+
+/// Must be called before the first thread is spawned, like block_sighup.
+pub fn block_shutdown_signals() -> nix::Result<()> {
+    shutdown_set().thread_block()
+}
+
+/// Waits for SIGINT or SIGTERM, which must have been blocked with block_shutdown_signals.
+pub fn wait_for_shutdown_signal() -> nix::Result<Signal> {
+    let signal = shutdown_set().wait()?;
+    SHUTDOWN_SIGNAL.store(signal as c_int, Ordering::SeqCst);
+    Ok(signal)
+}
+
+/// Sleeps like thread::sleep, but returns false as soon as SIGINT or SIGTERM arrive, which
+/// must have been blocked with block_shutdown_signals.
+pub fn sleep_unless_shutdown_signal(duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    let set = shutdown_set();
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let timeout = libc::timespec {
+            tv_sec: remaining.as_secs() as libc::time_t,
+            tv_nsec: remaining.subsec_nanos() as libc::c_long,
+        };
+        let signal = unsafe { libc::sigtimedwait(set.as_ref(), std::ptr::null_mut(), &timeout) };
+        if signal > 0 {
+            SHUTDOWN_SIGNAL.store(signal, Ordering::SeqCst);
+            return false;
+        }
+        match nix::errno::Errno::last() {
+            nix::errno::Errno::EINTR => continue,
+            _ => return true,
+        }
+    }
+}
+
+extern "C" fn interrupt_only(_signal: c_int) {}
+
+/// SIGUSR1 is used to interrupt the blocking read of a session thread. The handler does
+/// nothing, but without SA_RESTART the read returns EINTR and libfuse checks whether the
+/// session has been ended.
+pub fn install_session_interrupt() -> nix::Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(interrupt_only),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    unsafe { sigaction(Signal::SIGUSR1, &action) }.map(|_| ())
+}
+
+/// Called after the session has ended, so that a second signal terminates the process
+/// right away instead of waiting for the remaining jobs.
+pub fn restore_default_shutdown_signals() {
+    let action = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+    for signal in [Signal::SIGINT, Signal::SIGTERM] {
+        if let Err(e) = unsafe { sigaction(signal, &action) } {
+            error!("failed to restore the handler for {}: {}", signal, e);
+        }
+    }
+}
+
+/// SIGTERM (e.g. systemctl stop) lets all queued jobs finish, SIGINT (Ctrl+C) only runs the
+/// cleanup jobs. A session that ended without a signal is drained as well.
+pub fn shutdown_mode() -> ShutdownMode {
+    match SHUTDOWN_SIGNAL.load(Ordering::SeqCst) {
+        libc::SIGINT => ShutdownMode::CancelNonCleanup,
+        _ => ShutdownMode::Drain,
+    }
+}
@@ -0,0 +1,87 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// An in-memory stand-in for the uinput fd of the host. It only does what the kernel does:
+// it records the declared capabilities, refuses them once the device exists and delivers
+// the events of declared codes. The device policy is applied before, by the functions the
+// CUSE node uses as well (see simulation::set_capability and simulation::write).
+
+use libc::{c_int, input_event, EINVAL};
+
+use crate::cuse_device::state::{DeviceDescriptor, VuInputDevice};
+use crate::cuse_device::vuinput_ioctl::{Capability, SYS_INPUT_DIR};
+
+const EV_SYN: u16 = 0x00;
+const INPUT_MAJOR: u64 = 13;
+// minors of /dev/input/event* start at 64
+const EVDEV_MINOR_BASE: u64 = 64;
+
+#[derive(Default)]
+pub struct FakeUinputDevice {
+    pub capabilities: DeviceDescriptor,
+    pub input_device: Option<VuInputDevice>,
+    /// Events that made it to the (fake) evdev device
+    pub events: Vec<input_event>,
+}
+
+impl FakeUinputDevice {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// UI_SET_EVBIT, UI_SET_KEYBIT, UI_SET_PROPBIT, etc.
+    pub fn set_bit(&mut self, capability: Capability, bit: u16) -> Result<(), c_int> {
+        if self.input_device.is_some() {
+            return Err(EINVAL);
+        }
+        capability.record(&mut self.capabilities, bit);
+        Ok(())
+    }
+
+    /// UI_DEV_CREATE. `number` stands in for the counters of the kernel and makes the
+    /// names unique, e.g. input3 and event3.
+    pub fn create(&mut self, number: u64) -> Result<&VuInputDevice, c_int> {
+        if self.input_device.is_some() {
+            return Err(EINVAL);
+        }
+        Ok(self.input_device.insert(VuInputDevice {
+            major: INPUT_MAJOR,
+            minor: EVDEV_MINOR_BASE + number,
+            sysname: format!("input{}", number),
+            syspath: format!("{}input{}", SYS_INPUT_DIR, number),
+            devname: format!("event{}", number),
+            devnode: format!("/dev/input/event{}", number),
+            serial: None,
+            mirrors: Vec::new(),
+            seat: Default::default(),
+            container_devnode: Default::default(),
+        }))
+    }
+
+    /// write() of an event, whether it has been delivered: the kernel drops the events of
+    /// codes the device did not declare
+    pub fn write(&mut self, event: input_event) -> Result<bool, c_int> {
+        if self.input_device.is_none() {
+            return Err(EINVAL);
+        }
+        let declared = event.type_ == EV_SYN
+            || (self.capabilities.event_types.contains(&event.type_)
+                && self
+                    .capabilities
+                    .codes
+                    .get(&event.type_)
+                    .is_some_and(|codes| codes.contains(&event.code)));
+        if declared {
+            self.events.push(event);
+        }
+        Ok(declared)
+    }
+
+    /// UI_DEV_DESTROY, the next device is set up from scratch
+    pub fn destroy(&mut self) -> Option<VuInputDevice> {
+        self.capabilities = DeviceDescriptor::default();
+        self.events.clear();
+        self.input_device.take()
+    }
+}
@@ -0,0 +1,553 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Simulation mode (--simulate DIR) for working on policies and jobs without CUSE and root.
+// Instead of the CUSE session, requests are read as commands from stdin, the uinput device
+// is faked in memory (see fake_device) and the jobs write into DIR instead of a container. The
+// device policy is applied like on the CUSE node:
+//
+//   evbit EV_KEY      UI_SET_EVBIT
+//   bit KEY_A         UI_SET_KEYBIT (ABSBIT, RELBIT, ... follow from the prefix of the name)
+//   prop INPUT_PROP_BUTTONPAD
+//                     UI_SET_PROPBIT
+//   create            UI_DEV_CREATE
+//   write KEY_A 1     write() of a single event
+//   destroy           UI_DEV_DESTROY
+//
+// Every command answers with a line, e.g. "ok", "EPERM" or "blocked by the device policy".
+
+pub mod fake_device;
+
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::time::Duration;
+
+use libc::{c_int, input_event, EINVAL, EPERM};
+use log::error;
+
+use crate::cuse_device::device_policy;
+use crate::cuse_device::device_serial;
+use crate::cuse_device::reconnect::code_bit_ioctl;
+use crate::cuse_device::state::{DeviceDescriptor, KeyTracker, VuInputDevice};
+use crate::cuse_device::vuinput_ioctl::Capability;
+use crate::global_config::{get_container_runtime, get_reloadable_config, ReloadableConfig};
+use crate::input_codes::{code_by_name, prop_by_name, CodeName, PropName, TypeName};
+use crate::input_realizer::runtime_data::set_serial;
+use crate::input_realizer::seat;
+use crate::job_engine::closure_job::job;
+use crate::job_engine::job::JobTarget;
+use crate::job_engine::JOB_DISPATCHER;
+use crate::jobs;
+use crate::jobs::mknod_device_job::MknodDeviceJob;
+use crate::jobs::monitor_udev_job::{EventKind, EventStore, UdevEvent, EVENT_STORE};
+use crate::jobs::remove_device_job::RemoveDeviceJob;
+use crate::process_tools::{get_requesting_process, Pid, RequestingProcess};
+use fake_device::FakeUinputDevice;
+
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const EV_ABS: u16 = 0x03;
+const BTN_LEFT: u16 = 0x110;
+const BTN_TOOL_PEN: u16 = 0x140;
+const BTN_TOOL_FINGER: u16 = 0x145;
+const BTN_TOUCH: u16 = 0x14a;
+const BTN_STYLUS: u16 = 0x14b;
+const INPUT_PROP_DIRECT: u16 = 0x01;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    EvBit(u16),
+    Bit(u16, u16),
+    Prop(u16),
+    Create,
+    Write(u16, u16, i32),
+    Destroy,
+}
+
+fn parse_code(name: &str) -> Result<(u16, u16), String> {
+    code_by_name(name).ok_or_else(|| format!("unknown code '{}'", name))
+}
+
+/// Returns None for empty lines and comments (#)
+pub fn parse_command(line: &str) -> Result<Option<Command>, String> {
+    let line = line.split('#').next().unwrap_or_default();
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let command = match words.as_slice() {
+        [] => return Ok(None),
+        ["evbit", name] => Command::EvBit(
+            (0..0x20)
+                .find(|type_| crate::input_codes::type_name(*type_) == Some(*name))
+                .ok_or_else(|| format!("unknown event type '{}'", name))?,
+        ),
+        ["bit", name] => {
+            let (type_, code) = parse_code(name)?;
+            Command::Bit(type_, code)
+        }
+        ["prop", name] => {
+            Command::Prop(prop_by_name(name).ok_or_else(|| format!("unknown property '{}'", name))?)
+        }
+        ["create"] => Command::Create,
+        ["write", name, value] => {
+            let (type_, code) = parse_code(name)?;
+            let value = value
+                .parse()
+                .map_err(|_| format!("'{}' is not a value", value))?;
+            Command::Write(type_, code, value)
+        }
+        ["destroy"] => Command::Destroy,
+        _ => return Err(format!("unknown command '{}'", line.trim())),
+    };
+    Ok(Some(command))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum WriteOutcome {
+    Forwarded,
+    BlockedByPolicy,
+    /// The kernel drops events of codes the device did not declare
+    NotDeclared,
+}
+
+fn ioctl_name(capability: Capability) -> &'static str {
+    match capability {
+        Capability::EventType => "UI_SET_EVBIT",
+        Capability::Code(type_) => code_bit_ioctl(type_).map_or("UI_SET_*BIT", |(name, _)| name),
+        Capability::Property => "UI_SET_PROPBIT",
+    }
+}
+
+/// UI_SET_*BIT: refused with EPERM and recorded if the device policy does not allow the bit,
+/// otherwise passed to the device
+fn set_capability(
+    device: &mut FakeUinputDevice,
+    config: &ReloadableConfig,
+    capability: Capability,
+    bit: u16,
+) -> Result<(), c_int> {
+    if !capability.is_allowed(&config.policy, &config.gamepad_extra_keys, bit) {
+        device.capabilities.rejected.insert(format!(
+            "{} {}",
+            ioctl_name(capability),
+            capability.bit_name(bit)
+        ));
+        return Err(EPERM);
+    }
+    device.set_bit(capability, bit)
+}
+
+/// write() of an event: filtered by the device policy, then passed to the device
+fn write(
+    device: &mut FakeUinputDevice,
+    keytracker: &mut KeyTracker,
+    config: &ReloadableConfig,
+    mut event: input_event,
+) -> Result<WriteOutcome, c_int> {
+    if device.input_device.is_none() {
+        return Err(EINVAL);
+    }
+    if !device_policy::is_allowed(
+        keytracker,
+        &config.policy,
+        &config.gamepad_extra_keys,
+        &mut event,
+    ) {
+        return Ok(WriteOutcome::BlockedByPolicy);
+    }
+    match device.write(event)? {
+        true => Ok(WriteOutcome::Forwarded),
+        false => Ok(WriteOutcome::NotDeclared),
+    }
+}
+
+fn errno_name(errno: i32) -> String {
+    match errno {
+        EPERM => "EPERM".to_string(),
+        EINVAL => "EINVAL".to_string(),
+        _ => format!("errno {}", errno),
+    }
+}
+
+/// The class of a device with absolute axes, in the order the input_id builtin checks them
+fn absolute_class(capabilities: &DeviceDescriptor) -> &'static str {
+    let has_key = |code| {
+        capabilities
+            .codes
+            .get(&EV_KEY)
+            .is_some_and(|keys| keys.contains(&code))
+    };
+    let direct = capabilities.properties.contains(&INPUT_PROP_DIRECT);
+    if has_key(BTN_TOOL_PEN) || has_key(BTN_STYLUS) {
+        "ID_INPUT_TABLET"
+    } else if has_key(BTN_TOOL_FINGER) && !direct {
+        "ID_INPUT_TOUCHPAD"
+    } else if has_key(BTN_LEFT) {
+        // absolute pointers of streaming clients and virtual machines
+        "ID_INPUT_MOUSE"
+    } else if has_key(BTN_TOUCH) || direct {
+        "ID_INPUT_TOUCHSCREEN"
+    } else {
+        "ID_INPUT_JOYSTICK"
+    }
+}
+
+/// Roughly what the input_id builtin of udev reports for the declared capabilities
+fn runtime_data(capabilities: &DeviceDescriptor) -> String {
+    let mut properties = Vec::new();
+    for (type_, property) in [
+        (EV_KEY, "ID_INPUT_KEY"),
+        (EV_REL, "ID_INPUT_MOUSE"),
+        (EV_ABS, absolute_class(capabilities)),
+    ] {
+        if capabilities.event_types.contains(&type_) && !properties.contains(&property) {
+            properties.push(property);
+        }
+    }
+    let mut data = String::from("E:ID_INPUT=1\n");
+    for property in properties {
+        data.push_str(&format!("E:{}=1\n", property));
+    }
+    data
+}
+
+fn netlink_message(device: &VuInputDevice, action: &str, seqnum: u64) -> HashMap<String, String> {
+    let sysname = &device.sysname;
+    HashMap::from([
+        ("ACTION".to_string(), action.to_string()),
+        (
+            "DEVPATH".to_string(),
+            format!("/devices/virtual/input/{}/{}", sysname, device.devname),
+        ),
+        ("DEVNAME".to_string(), format!("input/{}", device.devname)),
+        ("SUBSYSTEM".to_string(), "input".to_string()),
+        ("MAJOR".to_string(), device.major.to_string()),
+        ("MINOR".to_string(), device.minor.to_string()),
+        ("SEQNUM".to_string(), seqnum.to_string()),
+    ])
+}
+
+/// What the CUSE layer and the udev monitor do on UI_DEV_CREATE, with the fake device
+fn announce_device(
+    requesting_process: &RequestingProcess,
+    device: &VuInputDevice,
+    capabilities: &DeviceDescriptor,
+    seqnum: u64,
+) {
+    // the udev monitor would pick this up from the kernel
+    let mut add_message = netlink_message(device, "add", seqnum);
+    EVENT_STORE.get().unwrap().on_event(UdevEvent {
+        syspath: device.syspath.clone(),
+        seqnum,
+        kind: EventKind::Add,
+        payload: add_message.clone(),
+        parent: false,
+    });
+
+    let mknod_job = MknodDeviceJob::new(
+        requesting_process.clone(),
+        device.devname.clone(),
+        device.syspath.clone(),
+        device.major,
+        device.minor,
+    )
+    .record_node(device.container_devnode.clone());
+    let awaiter = mknod_job.get_awaiter_for_state();
+    JOB_DISPATCHER
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .dispatch(Box::new(mknod_job));
+    awaiter(&jobs::mknod_device_job::State::Finished);
+
+    // EmitUdevEventJob reads the udev data of the host, which doesn't exist for the fake device
+    let requesting_process_in_job = requesting_process.clone();
+    let mut runtime_data = runtime_data(capabilities);
+    if let Some(serial) = device.serial {
+        runtime_data = set_serial(&runtime_data, &serial.to_string());
+        add_message.insert("ID_SERIAL".to_string(), serial.to_string());
+    }
+    if let Some(seat) = seat::container_seat(requesting_process) {
+        runtime_data = seat::set_seat_in_runtime_data(&runtime_data, &seat);
+        seat::set_seat_in_properties(&mut add_message, &seat);
+    }
+    let (major, minor) = (device.major, device.minor);
+    JOB_DISPATCHER.get().unwrap().lock().unwrap().dispatch(job!(
+        "emit simulated udev event",
+        JobTarget::Container(requesting_process.clone()),
+        async move {
+            let injector = get_container_runtime().injection_strategy();
+            let result = match injector
+                .write_udev_runtime_data(&requesting_process_in_job, &runtime_data, major, minor)
+                .await
+            {
+                Ok(()) => {
+                    injector
+                        .emit_netlink_message(&requesting_process_in_job, add_message)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                error!("simulated udev event: {:#}", e);
+            }
+        }
+    ));
+}
+
+fn remove_device(requesting_process: &RequestingProcess, device: VuInputDevice) {
+    if let Some(serial) = &device.serial {
+        device_serial::release(serial);
+    }
+    let remove_job = RemoveDeviceJob::new(
+        requesting_process.clone(),
+        device.devname,
+        device.syspath,
+        device.major,
+        device.minor,
+    );
+    let awaiter = remove_job.get_awaiter_for_state();
+    JOB_DISPATCHER
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .dispatch(Box::new(remove_job));
+    awaiter(&jobs::remove_device_job::State::Finished);
+}
+
+/// Runs the commands until the input ends. A device that is still there is removed, like
+/// on release of the file handle.
+pub fn run(input: impl BufRead) {
+    // takes the place of the udev monitor, which doesn't run in simulation mode
+    let _ = EVENT_STORE.set(EventStore::new(Duration::from_secs(60)));
+    let requesting_process = get_requesting_process(Pid::Pid(std::process::id()));
+    let mut device = FakeUinputDevice::new();
+    let mut keytracker = KeyTracker::new();
+    let mut created: u64 = 0;
+
+    for line in input.lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let command = match parse_command(&line) {
+            Ok(Some(command)) => command,
+            Ok(None) => continue,
+            Err(e) => {
+                println!("error: {}", e);
+                continue;
+            }
+        };
+        let config = get_reloadable_config();
+        let answer = match command {
+            Command::EvBit(type_) => {
+                set_capability(&mut device, &config, Capability::EventType, type_)
+                    .map(|_| format!("ok, declared {}", TypeName(type_)))
+            }
+            Command::Bit(type_, code) => {
+                set_capability(&mut device, &config, Capability::Code(type_), code)
+                    .map(|_| format!("ok, declared {}", CodeName(type_, code)))
+            }
+            Command::Prop(prop) => set_capability(&mut device, &config, Capability::Property, prop)
+                .map(|_| format!("ok, declared {}", PropName(prop))),
+            Command::Create => match device.create(created) {
+                Ok(_) => {
+                    created += 1;
+                    let input_device = device.input_device.as_mut().unwrap();
+                    input_device.serial =
+                        Some(device_serial::allocate(&requesting_process.namespaces));
+                    let input_device = device.input_device.as_ref().unwrap();
+                    announce_device(
+                        &requesting_process,
+                        input_device,
+                        &device.capabilities,
+                        created,
+                    );
+                    Ok(format!(
+                        "ok, created {} with {}",
+                        input_device.devnode, device.capabilities
+                    ))
+                }
+                Err(errno) => Err(errno),
+            },
+            Command::Write(type_, code, value) => {
+                let mut event: input_event = unsafe { std::mem::zeroed() };
+                event.type_ = type_;
+                event.code = code;
+                event.value = value;
+                write(&mut device, &mut keytracker, &config, event).map(|outcome| match outcome {
+                    WriteOutcome::Forwarded => "ok".to_string(),
+                    WriteOutcome::BlockedByPolicy => "blocked by the device policy".to_string(),
+                    WriteOutcome::NotDeclared => {
+                        format!("dropped, {} has not been declared", CodeName(type_, code))
+                    }
+                })
+            }
+            Command::Destroy => {
+                keytracker = KeyTracker::new();
+                if let Some(input_device) = device.destroy() {
+                    remove_device(&requesting_process, input_device);
+                }
+                Ok("ok".to_string())
+            }
+        };
+        match answer {
+            Ok(answer) => println!("{}", answer),
+            Err(errno) => println!("{}", errno_name(errno)),
+        }
+    }
+
+    if let Some(input_device) = device.destroy() {
+        remove_device(&requesting_process, input_device);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::global_config::{DevicePolicy, ReloadableConfig};
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse_command("  # comment"), Ok(None));
+        assert_eq!(
+            parse_command("evbit EV_KEY"),
+            Ok(Some(Command::EvBit(EV_KEY)))
+        );
+        assert_eq!(
+            parse_command("bit ABS_X"),
+            Ok(Some(Command::Bit(EV_ABS, 0)))
+        );
+        assert_eq!(
+            parse_command("write KEY_A 1 # press"),
+            Ok(Some(Command::Write(EV_KEY, 30, 1)))
+        );
+        assert_eq!(
+            parse_command("prop INPUT_PROP_BUTTONPAD"),
+            Ok(Some(Command::Prop(2)))
+        );
+        assert!(parse_command("bit KEY_NOPE").is_err());
+        assert!(parse_command("write KEY_A").is_err());
+    }
+
+    #[test]
+    fn fake_device_follows_policy_and_declaration() {
+        let config = ReloadableConfig {
+            policy: DevicePolicy::StrictGamepad,
+            ..Default::default()
+        };
+        let mut device = FakeUinputDevice::new();
+        let mut keytracker = KeyTracker::new();
+        let mut event: input_event = unsafe { std::mem::zeroed() };
+        event.type_ = EV_KEY;
+        event.code = 0x130; // BTN_SOUTH
+        event.value = 1;
+        let evbit = Capability::EventType;
+        let keybit = Capability::Code(EV_KEY);
+
+        assert_eq!(
+            write(&mut device, &mut keytracker, &config, event),
+            Err(EINVAL)
+        );
+        assert_eq!(set_capability(&mut device, &config, evbit, EV_KEY), Ok(()));
+        assert_eq!(
+            set_capability(&mut device, &config, evbit, EV_REL),
+            Err(EPERM)
+        );
+        assert!(device.capabilities.rejected.contains("UI_SET_EVBIT EV_REL"));
+        assert_eq!(device.create(0).unwrap().devnode, "/dev/input/event0");
+        // bits can't be changed once the device exists
+        assert_eq!(
+            set_capability(&mut device, &config, keybit, 0x130),
+            Err(EINVAL)
+        );
+        assert_eq!(
+            write(&mut device, &mut keytracker, &config, event),
+            Ok(WriteOutcome::NotDeclared)
+        );
+
+        device.destroy();
+        set_capability(&mut device, &config, evbit, EV_KEY).unwrap();
+        set_capability(&mut device, &config, keybit, 0x130).unwrap();
+        device.create(1).unwrap();
+        assert_eq!(
+            write(&mut device, &mut keytracker, &config, event),
+            Ok(WriteOutcome::Forwarded)
+        );
+        // KEY_A is not declared, but strict-gamepad blocks it before
+        event.code = 30;
+        assert_eq!(
+            write(&mut device, &mut keytracker, &config, event),
+            Ok(WriteOutcome::BlockedByPolicy)
+        );
+        assert_eq!(device.events.len(), 1);
+    }
+
+    #[test]
+    fn touchpad_runtime_data() {
+        let config = ReloadableConfig {
+            policy: DevicePolicy::StrictTouchpad,
+            ..Default::default()
+        };
+        let mut device = FakeUinputDevice::new();
+        set_capability(&mut device, &config, Capability::EventType, EV_KEY).unwrap();
+        set_capability(&mut device, &config, Capability::EventType, EV_ABS).unwrap();
+        set_capability(
+            &mut device,
+            &config,
+            Capability::Code(EV_KEY),
+            BTN_TOOL_FINGER,
+        )
+        .unwrap();
+        set_capability(&mut device, &config, Capability::Property, 0x00).unwrap(); // INPUT_PROP_POINTER
+        assert_eq!(
+            set_capability(
+                &mut device,
+                &config,
+                Capability::Property,
+                INPUT_PROP_DIRECT
+            ),
+            Err(EPERM)
+        );
+        assert_eq!(
+            runtime_data(&device.capabilities),
+            "E:ID_INPUT=1\nE:ID_INPUT_KEY=1\nE:ID_INPUT_TOUCHPAD=1\n"
+        );
+    }
+
+    #[test]
+    fn absolute_pointer_runtime_data() {
+        let config = ReloadableConfig {
+            policy: DevicePolicy::StrictTablet,
+            ..Default::default()
+        };
+        let mut device = FakeUinputDevice::new();
+        set_capability(&mut device, &config, Capability::EventType, EV_KEY).unwrap();
+        set_capability(&mut device, &config, Capability::EventType, EV_ABS).unwrap();
+        set_capability(&mut device, &config, Capability::EventType, EV_REL).unwrap();
+        set_capability(&mut device, &config, Capability::Code(EV_KEY), BTN_LEFT).unwrap();
+        set_capability(&mut device, &config, Capability::Code(EV_ABS), 0x00).unwrap(); // ABS_X
+        set_capability(&mut device, &config, Capability::Code(EV_REL), 0x08).unwrap(); // REL_WHEEL
+        assert_eq!(
+            set_capability(&mut device, &config, Capability::Code(EV_REL), 0x00),
+            Err(EPERM)
+        ); // REL_X
+        assert_eq!(
+            set_capability(
+                &mut device,
+                &config,
+                Capability::Property,
+                INPUT_PROP_DIRECT
+            ),
+            Err(EPERM)
+        );
+        assert_eq!(
+            runtime_data(&device.capabilities),
+            "E:ID_INPUT=1\nE:ID_INPUT_KEY=1\nE:ID_INPUT_MOUSE=1\n"
+        );
+
+        // with a pen it becomes a tablet
+        set_capability(&mut device, &config, Capability::Code(EV_KEY), BTN_TOOL_PEN).unwrap();
+        assert!(runtime_data(&device.capabilities).contains("E:ID_INPUT_TABLET=1\n"));
+    }
+}
@@ -0,0 +1,202 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Capabilities vuinputd needs at runtime:
+// - CAP_SYS_ADMIN, CAP_SYS_CHROOT: setns() into the mnt namespace of containers
+// - CAP_SYS_PTRACE: open /proc/<pid>/ns of processes of other users
+// - CAP_MKNOD, CAP_DAC_OVERRIDE, CAP_FOWNER, CAP_CHOWN: create and own device nodes
+// - CAP_SETUID, CAP_SETGID: act as the user of the container when writing its files
+// - CAP_NET_ADMIN: send uevents to the udev monitor netlink group
+const CAPABILITIES: &[&str] = &[
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_CHROOT",
+    "CAP_SYS_PTRACE",
+    "CAP_MKNOD",
+    "CAP_DAC_OVERRIDE",
+    "CAP_FOWNER",
+    "CAP_CHOWN",
+    "CAP_SETUID",
+    "CAP_SETGID",
+    "CAP_NET_ADMIN",
+];
+
+#[derive(Debug)]
+pub struct UnitOptions {
+    /// Path of the vuinputd binary used in ExecStart
+    pub binary: PathBuf,
+    /// Arguments of the daemon, reconstructed from the active configuration
+    pub daemon_args: Vec<String>,
+    pub devname: String,
+    /// Whether the container runtime places device nodes and udev data below /run/vuinputd
+    pub uses_run_folder: bool,
+}
+
+impl UnitOptions {
+    /// The default instance keeps the name of the shipped unit, other instances get their own.
+    pub fn unit_base_name(&self) -> String {
+        if self.devname == "vuinput" {
+            "vuinputd".to_string()
+        } else {
+            format!("vuinputd-{}", self.devname)
+        }
+    }
+}
+
+/// Quotes an argument for ExecStart, see "COMMAND LINES" in systemd.service(5)
+fn quote_exec_arg(arg: &str) -> String {
+    let escaped = arg.replace('%', "%%").replace('$', "$$");
+    if escaped.is_empty()
+        || escaped
+            .chars()
+            .any(|c| c.is_whitespace() || c == '"' || c == '\'' || c == '\\' || c == ';')
+    {
+        format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        escaped
+    }
+}
+
+pub fn render_service_unit(options: &UnitOptions) -> String {
+    let mut exec_start = quote_exec_arg(&options.binary.to_string_lossy());
+    for arg in &options.daemon_args {
+        exec_start.push(' ');
+        exec_start.push_str(&quote_exec_arg(arg));
+    }
+
+    format!(
+        "# Generated by vuinputd install-systemd. Re-run it after changing the options.
+[Unit]
+Description=uinput proxy (/dev/{devname})
+After=systemd-udevd.service modprobe@cuse.service systemd-tmpfiles-setup.service
+Requires=systemd-udevd.service
+Wants=modprobe@cuse.service
+
+[Service]
+# READY=1 is sent once the CUSE node is registered, WATCHDOG=1 by the job dispatcher
+Type=notify
+NotifyAccess=main
+WatchdogSec=30s
+ExecStart={exec_start}
+ExecReload=/bin/kill -HUP $MAINPID
+Restart=on-failure
+
+CapabilityBoundingSet={capabilities}
+# we need the permission to create all sorts of devices
+DeviceAllow=char-* rwm
+
+[Install]
+WantedBy=multi-user.target
+",
+        devname = options.devname,
+        exec_start = exec_start,
+        capabilities = CAPABILITIES.join(" "),
+    )
+}
+
+pub fn render_tmpfiles(options: &UnitOptions) -> String {
+    let mut tmpfiles = String::from(
+        "# Generated by vuinputd install-systemd. See tmpfiles.d(5).\n\
+         d /run/vuinputd 0755 root root -\n",
+    );
+    if options.uses_run_folder {
+        let prefix = format!("/run/vuinputd/{}", options.devname);
+        tmpfiles.push_str(&format!(
+            "# Note: {prefix}/dev-input must be on a mount that allows device nodes (no \"nodev\").\n\
+             d {prefix} 0755 root root -\n\
+             d {prefix}/dev-input 0755 root root -\n\
+             d {prefix}/udev 0755 root root -\n\
+             d {prefix}/udev/data 0755 root root -\n\
+             f {prefix}/udev/control 0644 root root -\n",
+            prefix = prefix
+        ));
+    }
+    tmpfiles
+}
+
+fn write_or_print(path: &Path, content: &str, dry_run: bool) -> io::Result<()> {
+    if dry_run {
+        println!("### {}\n{}", path.display(), content);
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, content)?;
+    println!("wrote {}", path.display());
+    Ok(())
+}
+
+pub fn install_systemd(
+    options: &UnitOptions,
+    unit_dir: &Path,
+    tmpfiles_dir: &Path,
+    dry_run: bool,
+) -> io::Result<()> {
+    let base_name = options.unit_base_name();
+    let unit_path = unit_dir.join(format!("{}.service", base_name));
+    let tmpfiles_path = tmpfiles_dir.join(format!("{}.conf", base_name));
+
+    write_or_print(&unit_path, &render_service_unit(options), dry_run)?;
+    write_or_print(&tmpfiles_path, &render_tmpfiles(options), dry_run)?;
+
+    if !dry_run {
+        println!(
+            "Activate with:\n  systemd-tmpfiles --create {}\n  systemctl daemon-reload\n  systemctl enable --now {}.service",
+            tmpfiles_path.display(),
+            base_name
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(devname: &str, uses_run_folder: bool) -> UnitOptions {
+        UnitOptions {
+            binary: PathBuf::from("/usr/bin/vuinputd"),
+            daemon_args: vec![
+                "--devname".to_string(),
+                devname.to_string(),
+                "--cuse-group".to_string(),
+                "input users".to_string(),
+            ],
+            devname: devname.to_string(),
+            uses_run_folder,
+        }
+    }
+
+    #[test]
+    fn unit_contains_quoted_exec_start() {
+        let unit = render_service_unit(&options("vuinput-a", false));
+        assert!(unit.contains(
+            "ExecStart=/usr/bin/vuinputd --devname vuinput-a --cuse-group \"input users\"\n"
+        ));
+        assert!(unit.contains("Wants=modprobe@cuse.service"));
+        assert!(unit.contains("Type=notify\n"));
+        assert_eq!(
+            options("vuinput-a", false).unit_base_name(),
+            "vuinputd-vuinput-a"
+        );
+        assert_eq!(options("vuinput", false).unit_base_name(), "vuinputd");
+    }
+
+    #[test]
+    fn tmpfiles_only_contain_run_folder_if_used() {
+        assert!(!render_tmpfiles(&options("vuinput", false)).contains("dev-input"));
+        assert!(render_tmpfiles(&options("vuinput", true))
+            .contains("d /run/vuinputd/vuinput/dev-input 0755 root root -\n"));
+    }
+
+    #[test]
+    fn specifiers_are_escaped() {
+        assert_eq!(quote_exec_arg("100%"), "100%%");
+        assert_eq!(quote_exec_arg(""), "\"\"");
+    }
+}
@@ -0,0 +1,288 @@
+// SPDX-License-Identifier: MIT
+//
+// Author: Johannes Leupolz <dev@leupolz.eu>
+
+// Finds udev rules of other packages that undo 90-vuinputd-protect.rules. Rules of e.g.
+// steam-devices or a custom 70-uaccess.rules are harmless as long as they run before the rules of
+// vuinputd, but a rule file whose name sorts after them can set ID_SEAT=seat0 or
+// ID_INPUT_KEYBOARD=1 again, and the desktop of the host gets the input of the container.
+//
+// Two ways to find them:
+//
+//   - `udevadm test` of a device, which logs every assignment with its rule file and line.
+//     This is what happened to the device. The udev monitor runs it when a device of vuinputd
+//     shows up on another seat, see jobs::rules_conflict_job.
+//   - reading the rule files in the order of udev, for vuinputd doctor. This does not know
+//     which rules match the devices of vuinputd, so it only warns.
+//
+// Either way, a conflict is an assignment after the last one of the rules of vuinputd that moves
+// the device to another seat than theirs, or makes it a keyboard or pointer again.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use regex::Regex;
+
+use crate::host_root::host_path;
+
+/// Directories of udev rules; a file shadows those of the same name in later directories
+pub const RULES_DIRS: [&str; 4] = [
+    "/etc/udev/rules.d",
+    "/run/udev/rules.d",
+    "/usr/lib/udev/rules.d",
+    "/lib/udev/rules.d",
+];
+
+/// The rules of vuinputd end with this, whatever number they have been installed with
+const PROTECT_RULES: &str = "vuinputd-protect.rules";
+
+/// Properties that make a keyboard or pointer for compositors, which the rules of vuinputd clear
+const CLEARED_CLASSES: [&str; 5] = [
+    "ID_INPUT_KEYBOARD",
+    "ID_INPUT_MOUSE",
+    "ID_INPUT_TOUCHPAD",
+    "ID_INPUT_TABLET",
+    "ID_INPUT_TOUCHSCREEN",
+];
+
+/// An assignment to a property by a rule, e.g. ENV{ID_SEAT}="seat0"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assignment {
+    pub file: String,
+    pub line: u32,
+    pub key: String,
+    pub value: String,
+}
+
+impl std::fmt::Display for Assignment {
+    /// e.g. "/etc/udev/rules.d/99-steam.rules:12 sets ID_SEAT=seat0"
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.value.as_str() {
+            "" => write!(f, "{}:{} clears {}", self.file, self.line, self.key),
+            value => write!(f, "{}:{} sets {}={}", self.file, self.line, self.key, value),
+        }
+    }
+}
+
+fn is_protect_rules(file: &str) -> bool {
+    file.ends_with(PROTECT_RULES)
+}
+
+/// ENV{key}="value", ENV{key}:="value" or ENV{key}+="value", but not a comparison
+fn env_assignment() -> Regex {
+    Regex::new(r#"ENV\{([^}]+)\}\s*(?:\+=|:=|=)\s*(?:"([^"]*)"|'([^']*)'|([^\s,"']*))"#).unwrap()
+}
+
+/// The assignments of a line of a rule or of the log of udevadm test
+fn assignments_in<'a>(re: &'a Regex, text: &'a str) -> impl Iterator<Item = (String, String)> + 'a {
+    re.captures_iter(text).filter_map(|caps| {
+        let whole = caps.get(0)?.as_str();
+        // the first = of a comparison (==) has been taken for an assignment
+        let op_end = whole.find('=')?;
+        if whole[op_end + 1..].starts_with('=') {
+            return None;
+        }
+        let value = caps
+            .get(2)
+            .or(caps.get(3))
+            .or(caps.get(4))
+            .map_or("", |value| value.as_str());
+        Some((caps[1].to_string(), value.to_string()))
+    })
+}
+
+/// The assignments in the output of `udevadm test`, in the order they were applied. Both
+/// formats of the log are understood: "<device>: <file>:<line> ENV{key}=..." of current
+/// systemd and "ENV{key}=... <file>:<line>" of older versions.
+pub fn parse_udevadm_test(output: &str) -> Vec<Assignment> {
+    let location = Regex::new(r"(/\S+\.rules):(\d+)").unwrap();
+    let env = env_assignment();
+    let mut assignments = Vec::new();
+    for line in output.lines() {
+        let Some(caps) = location.captures(line) else {
+            continue;
+        };
+        let (file, number) = (caps[1].to_string(), caps[2].parse().unwrap_or_default());
+        for (key, value) in assignments_in(&env, line) {
+            assignments.push(Assignment {
+                file: file.clone(),
+                line: number,
+                key,
+                value,
+            });
+        }
+    }
+    assignments
+}
+
+/// The assignments of a rule file, with continued lines joined
+fn parse_rules_file(file: &str, content: &str) -> Vec<Assignment> {
+    let env = env_assignment();
+    let mut assignments = Vec::new();
+    let mut rule = String::new();
+    let mut first_line = 0;
+    for (index, line) in content.lines().enumerate() {
+        if rule.is_empty() {
+            first_line = index as u32 + 1;
+        }
+        let line = line.trim();
+        if let Some(continued) = line.strip_suffix('\\') {
+            rule.push_str(continued);
+            rule.push(' ');
+            continue;
+        }
+        rule.push_str(line);
+        if !rule.trim_start().starts_with('#') {
+            for (key, value) in assignments_in(&env, &rule) {
+                assignments.push(Assignment {
+                    file: file.to_string(),
+                    line: first_line,
+                    key,
+                    value,
+                });
+            }
+        }
+        rule.clear();
+    }
+    assignments
+}
+
+/// The assignments of all rule files of the host, in the order udev applies them: by file name,
+/// a file of an earlier directory of RULES_DIRS shadowing those of later ones
+pub fn read_rules() -> Vec<Assignment> {
+    let mut files = BTreeMap::new();
+    for dir in RULES_DIRS.iter().rev() {
+        let Ok(entries) = fs::read_dir(host_path(dir)) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.ends_with(".rules") {
+                files.insert(name.clone(), (format!("{}/{}", dir, name), entry.path()));
+            }
+        }
+    }
+    files
+        .values()
+        .filter_map(|(file, path)| Some(parse_rules_file(file, &fs::read_to_string(path).ok()?)))
+        .flatten()
+        .collect()
+}
+
+/// Whether the rules of vuinputd are among `assignments`
+pub fn has_protect_rules(assignments: &[Assignment]) -> bool {
+    assignments.iter().any(|a| is_protect_rules(&a.file))
+}
+
+/// The assignments after the last one of the rules of vuinputd that move the device to another
+/// seat than the one of these rules, or make it a keyboard or pointer again
+pub fn conflicts(assignments: &[Assignment]) -> Vec<Assignment> {
+    let Some(last) = assignments.iter().rposition(|a| is_protect_rules(&a.file)) else {
+        return Vec::new();
+    };
+    let vuinput_seat = assignments[..=last]
+        .iter()
+        .rev()
+        .find(|a| is_protect_rules(&a.file) && a.key == "ID_SEAT")
+        .map(|a| a.value.as_str());
+    assignments[last + 1..]
+        .iter()
+        .filter(|a| match a.key.as_str() {
+            "ID_SEAT" => Some(a.value.as_str()) != vuinput_seat,
+            key => CLEARED_CLASSES.contains(&key) && !a.value.is_empty(),
+        })
+        .cloned()
+        .collect()
+}
+
+/// What to do about a conflict
+pub fn remediation(conflict: &Assignment) -> String {
+    let name = Path::new(&conflict.file)
+        .file_name()
+        .map_or(conflict.file.clone(), |name| {
+            name.to_string_lossy().into_owned()
+        });
+    format!(
+        "{} after the rules of vuinputd. Exclude the devices of vuinputd from that rule \
+        (add ENV{{ID_VUINPUT}}!=\"1\" to a copy in /etc/udev/rules.d/{}), or install \
+        90-vuinputd-protect.rules as /etc/udev/rules.d/99-zz-vuinputd-protect.rules, \
+        then run udevadm control --reload",
+        conflict, name
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_log_of_udevadm_test() {
+        // systemd 255
+        let output = "\
+input99: /usr/lib/udev/rules.d/60-input-id.rules:7 Importing properties from results of builtin command 'input_id'
+input99: /usr/lib/udev/rules.d/71-seat.rules:41 TAG+=\"seat\"
+input99: /etc/udev/rules.d/90-vuinputd-protect.rules:47 ENV{ID_VUINPUT_KEYBOARD}=\"1\"
+input99: /etc/udev/rules.d/90-vuinputd-protect.rules:47 ENV{ID_INPUT_KEYBOARD}=\"\"
+input99: /etc/udev/rules.d/90-vuinputd-protect.rules:47 ENV{ID_SEAT}=\"seat_vuinput\"
+input99: /etc/udev/rules.d/99-steam.rules:3 ENV{ID_SEAT}=\"seat0\"
+ID_SEAT=seat0
+";
+        let assignments = parse_udevadm_test(output);
+        assert_eq!(assignments.len(), 4);
+        assert_eq!(
+            conflicts(&assignments),
+            [Assignment {
+                file: "/etc/udev/rules.d/99-steam.rules".to_string(),
+                line: 3,
+                key: "ID_SEAT".to_string(),
+                value: "seat0".to_string(),
+            }]
+        );
+        assert!(remediation(&conflicts(&assignments)[0]).starts_with(
+            "/etc/udev/rules.d/99-steam.rules:3 sets ID_SEAT=seat0 after the rules of vuinputd."
+        ));
+
+        // older versions put the location at the end
+        let output = "\
+ENV{ID_SEAT}='seat_vuinput' /etc/udev/rules.d/90-vuinputd-protect.rules:47
+ENV{ID_INPUT_KEYBOARD}='1' /etc/udev/rules.d/95-keyboard.rules:2
+";
+        let assignments = parse_udevadm_test(output);
+        assert_eq!(conflicts(&assignments).len(), 1);
+        assert_eq!(conflicts(&assignments)[0].key, "ID_INPUT_KEYBOARD");
+    }
+
+    #[test]
+    fn only_rules_after_those_of_vuinputd_conflict() {
+        let rules = "\
+# ENV{ID_SEAT}=\"seat0\" in a comment
+SUBSYSTEM==\"input\", ENV{ID_SEAT}==\"seat0\", ENV{ID_INPUT_MOUSE}=\"1\"
+SUBSYSTEMS==\"input\", ENV{ID_VUINPUT}==\"1\", ENV{ID_INPUT_KEYBOARD}==\"1\" \\
+ENV{ID_VUINPUT_KEYBOARD}=\"1\", ENV{ID_INPUT_KEYBOARD}=\"\", ENV{ID_SEAT}=\"seat_vuinput\"
+";
+        let early = parse_rules_file("/usr/lib/udev/rules.d/70-uaccess.rules", rules);
+        assert_eq!(early.len(), 4);
+        assert_eq!(early[0].key, "ID_INPUT_MOUSE");
+        assert_eq!(early[1].line, 3);
+
+        let protect = parse_rules_file("/etc/udev/rules.d/90-vuinputd-protect.rules", rules);
+        let late = parse_rules_file(
+            "/etc/udev/rules.d/99-steam.rules",
+            "ENV{ID_SEAT}=\"seat_vuinput\"\nENV{ID_INPUT_MOUSE}:=\"1\"\n",
+        );
+        let ordered: Vec<Assignment> = [early.clone(), protect.clone(), late]
+            .into_iter()
+            .flatten()
+            .collect();
+        assert!(has_protect_rules(&ordered));
+        // the seat of vuinputd is fine, the mouse is not
+        let found = conflicts(&ordered);
+        assert_eq!(found.len(), 1);
+        assert_eq!(
+            found[0].to_string(),
+            "/etc/udev/rules.d/99-steam.rules:2 sets ID_INPUT_MOUSE=1"
+        );
+        assert!(conflicts(&[early, protect].concat()).is_empty());
+    }
+}